@@ -0,0 +1,168 @@
+//! Counts heap allocations made by helpers to exchange messages over TCP, and to multiply
+//! batches of values with and without an arena for scratch buffers. Run with
+//! `cargo bench --bench allocations`.
+use rand::thread_rng;
+use raw_ipa::arena::Arena;
+use raw_ipa::field::Fp31;
+use raw_ipa::helpers::ring::{HelperAddr, Identity, Message, Ring};
+use raw_ipa::helpers::tcp::{TcpRing, TcpRingConfig};
+use raw_ipa::prss::{Participant, ParticipantSetup};
+use raw_ipa::replicated_secret_sharing::ReplicatedSecretSharing;
+use raw_ipa::securemul::ProtocolContext;
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::TcpListener;
+
+struct CountingAlloc;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Pair(u128, u128);
+
+impl Message for Pair {
+    const NAME: &'static str = "bench.pair";
+}
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const MESSAGES: u64 = 10_000;
+
+async fn make_three() -> [TcpRing; 3] {
+    let listeners = [
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs = listeners
+        .iter()
+        .map(|l| l.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    let [l0, l1, l2] = listeners;
+
+    // every helper is on this host, so peers are not authenticated
+    let config = |listen: usize, left: usize, right: usize| TcpRingConfig {
+        allow_unauthenticated: true,
+        ..TcpRingConfig::new(addrs[listen], addrs[left], addrs[right])
+    };
+    let configs = [config(0, 2, 1), config(1, 0, 2), config(2, 1, 0)];
+
+    let (h0, h1, h2) = futures::try_join!(
+        TcpRing::connect(l0, &configs[0]),
+        TcpRing::connect(l1, &configs[1]),
+        TcpRing::connect(l2, &configs[2]),
+    )
+    .unwrap();
+
+    [h0, h1, h2]
+}
+
+fn make_participants() -> [Participant; 3] {
+    let mut r = thread_rng();
+    let setup = [(); 3].map(|()| ParticipantSetup::new(&mut r));
+    let pk = [0, 1, 2].map(|i| setup[i].public_keys());
+    let mut i = 0;
+    setup.map(|s| {
+        let (left, right) = ((i + 2) % 3, (i + 1) % 3);
+        i += 1;
+        s.setup(&pk[left].1, &pk[right].0)
+    })
+}
+
+/// Average number of allocations it takes to run `f` once.
+async fn allocations<F, Fut>(f: F) -> f64
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    // warm up, so buffers that are allocated once are not counted
+    for _ in 0..100 {
+        f().await;
+    }
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..MESSAGES {
+        f().await;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let per_message = (ALLOCATIONS.load(Ordering::Relaxed) - start) as f64 / MESSAGES as f64;
+    per_message
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let ring = make_three().await;
+    let values = (0..64_u128).map(Fp31::from).collect::<Vec<_>>();
+
+    let message = allocations(|| async {
+        ring[0].send(HelperAddr::Right, Pair(1, 2)).await.unwrap();
+        ring[1].receive::<Pair>(HelperAddr::Left).await.unwrap();
+    })
+    .await;
+    let fields = allocations(|| async {
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        ring[1]
+            .receive_fields::<Fp31>(HelperAddr::Left)
+            .await
+            .unwrap();
+    })
+    .await;
+
+    let participants = make_participants();
+    let arenas = [(); 3].map(|()| Arena::new());
+    let shares = values
+        .iter()
+        .map(|&v| ReplicatedSecretSharing::share(v, &mut thread_rng()))
+        .collect::<Vec<_>>();
+    let shares = [0, 1, 2].map(|i| shares.iter().map(|s| s[i]).collect::<Vec<_>>());
+    let mut multiply = [0.0; 2];
+    for (with_arena, allocs) in [false, true].into_iter().zip(&mut multiply) {
+        let ctx = [0, 1, 2].map(|i| {
+            let ctx = ProtocolContext::new(Identity::ALL[i], &participants[i], &ring[i]);
+            if with_arena {
+                ctx.with_arena(&arenas[i])
+            } else {
+                ctx
+            }
+        });
+        let index = Cell::new(0_u128);
+        *allocs = allocations(|| async {
+            let first = index.replace(index.get() + 64);
+            futures::try_join!(
+                ctx[0].multiply_batch(first, &shares[0], &shares[0]),
+                ctx[1].multiply_batch(first, &shares[1], &shares[1]),
+                ctx[2].multiply_batch(first, &shares[2], &shares[2]),
+            )
+            .unwrap();
+        })
+        .await;
+    }
+
+    println!("allocations per send and receive");
+    println!("{:<24} {message:>8.2}", "message");
+    println!("{:<24} {fields:>8.2}", "64 field values");
+    println!("allocations per batch of 64 multiplications by three helpers");
+    println!("{:<24} {:>8.2}", "without arena", multiply[0]);
+    println!("{:<24} {:>8.2}", "with arena", multiply[1]);
+}
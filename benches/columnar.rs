@@ -0,0 +1,80 @@
+//! Compares the row-oriented and the columnar layout of converted shares for the access pattern
+//! of a radix sort, which reads one bit of every row per pass, and measures the cost of getting
+//! shares into the columnar layout. Run with `cargo bench --bench columnar`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::thread_rng;
+use raw_ipa::columnar::BitColumns;
+use raw_ipa::field::{Field, Fp31};
+use raw_ipa::replicated_secret_sharing::ReplicatedSecretSharing;
+
+const BITS: usize = 32;
+const ROW_COUNTS: [usize; 2] = [1 << 10, 1 << 16];
+
+type Share = ReplicatedSecretSharing<Fp31>;
+
+fn make_rows(count: usize) -> Vec<Vec<Share>> {
+    let mut rng = thread_rng();
+    (0..count)
+        .map(|i| {
+            (0..BITS)
+                .map(|bit| Share::share(Fp31::from(((i >> bit) & 1) as u128), &mut rng)[0])
+                .collect()
+        })
+        .collect()
+}
+
+/// Every pass of the sort adds up the shares of one bit of every row.
+fn sort_passes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sort_passes");
+    for count in ROW_COUNTS {
+        let rows = make_rows(count);
+        let columns = BitColumns::from_rows(&rows).unwrap();
+        let zero = Share::new(Fp31::ZERO, Fp31::ZERO);
+        group.throughput(Throughput::Elements((count * BITS) as u64));
+
+        group.bench_with_input(BenchmarkId::new("rows", count), &rows, |b, rows| {
+            b.iter(|| {
+                (0..BITS)
+                    .map(|bit| rows.iter().fold(zero, |acc, row| acc + row[bit]))
+                    .collect::<Vec<_>>()
+            });
+        });
+        group.bench_with_input(
+            BenchmarkId::new("columns", count),
+            &columns,
+            |b, columns| {
+                b.iter(|| {
+                    (0..BITS)
+                        .map(|bit| columns.column(bit).iter().fold(zero, |acc, s| acc + *s))
+                        .collect::<Vec<_>>()
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Scattering converted rows into columns and persisting them.
+fn conversion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("conversion");
+    for count in ROW_COUNTS {
+        let rows = make_rows(count);
+        let columns = BitColumns::from_rows(&rows).unwrap();
+        group.throughput(Throughput::Elements(count as u64));
+
+        group.bench_with_input(BenchmarkId::new("push_rows", count), &rows, |b, rows| {
+            b.iter(|| BitColumns::from_rows(rows).unwrap());
+        });
+        group.bench_with_input(BenchmarkId::new("write", count), &columns, |b, columns| {
+            b.iter(|| {
+                let mut bytes = Vec::with_capacity(columns.encoded_len());
+                columns.write_to(&mut bytes);
+                bytes
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sort_passes, conversion);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+//! Throughput of MPC primitives executed by three helpers inside one process, communicating over
+//! the in-memory ring. Run with `cargo bench --features test-fixture --bench mpc`.
+//!
+//! Benchmarks are generic over the field, so new fields can be measured by adding a line to
+//! `field_benches`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::thread_rng;
+use raw_ipa::field::{Field, Fp31};
+use raw_ipa::helpers::codec::Bincode;
+use raw_ipa::helpers::ring::mock::make_three_with_codec;
+use raw_ipa::helpers::ring::Identity;
+use raw_ipa::prss::{Participant, ParticipantSetup};
+use raw_ipa::replicated_secret_sharing::ReplicatedSecretSharing;
+use raw_ipa::securemul::{ProtocolContext, SecureMul};
+use tokio::runtime::Runtime;
+
+const BATCH_SIZES: [u64; 3] = [1, 16, 256];
+
+fn make_participants() -> [Participant; 3] {
+    let mut r = thread_rng();
+    let setup = [(); 3].map(|()| ParticipantSetup::new(&mut r));
+    let pk = [0, 1, 2].map(|i| setup[i].public_keys());
+    let mut i = 0;
+    setup.map(|s| {
+        let (left, right) = ((i + 2) % 3, (i + 1) % 3);
+        i += 1;
+        s.setup(&pk[left].1, &pk[right].0)
+    })
+}
+
+/// Sequential multiplications of a batch of secret-shared values.
+fn securemul<F: Field + 'static>(c: &mut Criterion, field: &str) {
+    let rt = Runtime::new().unwrap();
+    let ring = rt.block_on(async { make_three_with_codec::<Bincode>() });
+    let participants = make_participants();
+    let ctx = [0, 1, 2].map(|i| ProtocolContext::new(Identity::ALL[i], &participants[i], &ring[i]));
+    let a = ReplicatedSecretSharing::share(F::from(5), &mut thread_rng());
+    let b = ReplicatedSecretSharing::share(F::from(6), &mut thread_rng());
+
+    let mut group = c.benchmark_group(format!("securemul/{field}"));
+    for batch in BATCH_SIZES {
+        group.throughput(Throughput::Elements(batch));
+        group.bench_function(BenchmarkId::from_parameter(batch), |bench| {
+            bench.to_async(&rt).iter(|| async {
+                for index in 0..u128::from(batch) {
+                    futures::try_join!(
+                        SecureMul::new(index, a[0], b[0]).execute(&ctx[0]),
+                        SecureMul::new(index, a[1], b[1]).execute(&ctx[1]),
+                        SecureMul::new(index, a[2], b[2]).execute(&ctx[2]),
+                    )
+                    .unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Generation of pairs of random field values shared with both peers.
+fn prss<F: Field>(c: &mut Criterion, field: &str) {
+    let [p, _, _] = make_participants();
+
+    let mut group = c.benchmark_group(format!("prss/{field}"));
+    for batch in BATCH_SIZES {
+        group.throughput(Throughput::Elements(batch));
+        group.bench_function(BenchmarkId::from_parameter(batch), |bench| {
+            bench.iter(|| {
+                (0..u128::from(batch))
+                    .map(|index| p.generate_fields::<F>(index))
+                    .fold(F::ZERO, |acc, (l, r)| acc + l + r)
+            });
+        });
+    }
+    group.finish();
+}
+
+fn field_benches(c: &mut Criterion) {
+    securemul::<Fp31>(c, "fp31");
+    prss::<Fp31>(c, "fp31");
+}
+
+criterion_group!(benches, field_benches);
+criterion_main!(benches);
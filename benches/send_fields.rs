@@ -0,0 +1,102 @@
+//! Compares sending shares one message per value with sending them in bulk with `send_fields`,
+//! over TCP connections between helpers running on the loopback interface, and sending many
+//! messages at once with and without batching them.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use raw_ipa::field::Fp31;
+use raw_ipa::helpers::batching::FixedBatch;
+use raw_ipa::helpers::ring::{HelperAddr, Ring};
+use raw_ipa::helpers::tcp::{TcpRing, TcpRingConfig};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+async fn make_three() -> [TcpRing; 3] {
+    let listeners = [
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let addrs = listeners
+        .iter()
+        .map(|l| l.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    let [l0, l1, l2] = listeners;
+
+    // every helper is on this host, so peers are not authenticated
+    let config = |listen: usize, left: usize, right: usize| TcpRingConfig {
+        allow_unauthenticated: true,
+        ..TcpRingConfig::new(addrs[listen], addrs[left], addrs[right])
+    };
+    let configs = [config(0, 2, 1), config(1, 0, 2), config(2, 1, 0)];
+
+    let (h0, h1, h2) = futures::try_join!(
+        TcpRing::connect(l0, &configs[0]),
+        TcpRing::connect(l1, &configs[1]),
+        TcpRing::connect(l2, &configs[2]),
+    )
+    .unwrap();
+
+    [h0, h1, h2]
+}
+
+fn send_fields(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let ring = rt.block_on(make_three());
+
+    let mut group = c.benchmark_group("send");
+    for size in [1_u128, 64, 4096] {
+        let values = (0..size).map(Fp31::from).collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(u64::try_from(size).unwrap()));
+
+        group.bench_with_input(BenchmarkId::new("message", size), &values, |b, values| {
+            b.to_async(&rt).iter(|| async {
+                for v in values {
+                    ring[0]
+                        .send(HelperAddr::Right, u128::from(u8::from(*v)))
+                        .await
+                        .unwrap();
+                    ring[1].receive::<u128>(HelperAddr::Left).await.unwrap();
+                }
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("fields", size), &values, |b, values| {
+            b.to_async(&rt).iter(|| async {
+                ring[0]
+                    .send_fields(HelperAddr::Right, values)
+                    .await
+                    .unwrap();
+                ring[1]
+                    .receive_fields::<Fp31>(HelperAddr::Left)
+                    .await
+                    .unwrap();
+            });
+        });
+    }
+    group.finish();
+}
+
+fn batching(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let adaptive = rt.block_on(make_three());
+    let unbatched = rt
+        .block_on(make_three())
+        .map(|ring| ring.with_batch_policy(|| FixedBatch(0)));
+
+    let mut group = c.benchmark_group("batching");
+    let messages = 1024_u32;
+    group.throughput(Throughput::Elements(u64::from(messages)));
+    for (name, ring) in [("adaptive", &adaptive), ("unbatched", &unbatched)] {
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| async {
+                let sends = (0..messages).map(|v| ring[0].send(HelperAddr::Right, v));
+                futures::future::try_join_all(sends).await.unwrap();
+                for _ in 0..messages {
+                    ring[1].receive::<u32>(HelperAddr::Left).await.unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, send_fields, batching);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raw_ipa::report::EncryptedMatchkeys;
+
+fuzz_target!(|data: &[u8]| {
+    // only canonical encodings decode, so they must encode to the same bytes
+    if let Ok(match_keys) = EncryptedMatchkeys::from_bytes(data) {
+        assert_eq!(data, &match_keys.to_bytes().unwrap()[..]);
+    }
+});
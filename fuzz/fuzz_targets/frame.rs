@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raw_ipa::helpers::codec::{Bincode, Codec};
+use raw_ipa::helpers::tcp::{parse_frame, Frame};
+
+fuzz_target!(|frame: &[u8]| {
+    // payloads of data messages received from peers are decoded with the codec next
+    if let Ok(Frame::Data { payload, .. }) = parse_frame(frame) {
+        let _ = Bincode::decode::<(u128, u128)>(payload);
+        let _ = Bincode::decode::<String>(payload);
+    }
+});
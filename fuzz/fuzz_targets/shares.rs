@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raw_ipa::field::Fp31;
+use raw_ipa::helpers::codec::{read_shares, write_shares};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(shares) = read_shares::<Fp31>(data) {
+        let mut encoded = Vec::new();
+        write_shares(&shares, &mut encoded);
+        assert_eq!(data, &encoded[..]);
+    }
+});
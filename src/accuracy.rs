@@ -0,0 +1,161 @@
+//! Estimates how accurate query results are for given noise parameters.
+//!
+//! Query issuers pick the privacy budget and the contribution cap before they see the result.
+//! Given a histogram they expect to get, this draws the Laplace noise helpers would add to it
+//! many times over and reports the error in every bucket, so the parameters can be compared
+//! before any budget is spent.
+//!
+//! The cap is only used as the sensitivity of the noise. Capping also drops contributions of
+//! users that exceed it, which this cannot model from an aggregated histogram.
+use rand::Rng;
+use rand_distr::{Distribution, Exp1};
+use std::fmt::{Display, Formatter};
+
+/// Noise parameters to evaluate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    /// Privacy budget spent on the query. Infinite epsilon means there is no noise.
+    pub epsilon: f64,
+    /// Maximum contribution of a single user to any bucket.
+    pub cap: f64,
+}
+
+impl Candidate {
+    /// Scale of the Laplace noise added to every bucket.
+    #[must_use]
+    pub fn scale(&self) -> f64 {
+        if self.epsilon.is_infinite() {
+            0.0
+        } else {
+            self.cap / self.epsilon
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        // difference of two exponential variables is Laplace distributed
+        let e1: f64 = Exp1.sample(rng);
+        let e2: f64 = Exp1.sample(rng);
+        self.scale() * (e1 - e2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketAccuracy {
+    pub expected: u32,
+    /// Root mean square error of the noisy value.
+    pub rmse: f64,
+    /// Mean absolute error relative to the expected value. Not defined for empty buckets.
+    pub relative_error: Option<f64>,
+}
+
+/// Accuracy of a single candidate, one entry per bucket.
+#[derive(Debug)]
+pub struct Simulation {
+    pub candidate: Candidate,
+    pub samples: usize,
+    pub buckets: Vec<BucketAccuracy>,
+}
+
+/// Adds noise described by `candidate` to every bucket of `expected` `samples` times and
+/// measures the error.
+///
+/// ## Panics
+/// If `samples` is zero.
+#[must_use]
+pub fn simulate<R: Rng>(
+    expected: &[u32],
+    candidate: Candidate,
+    samples: usize,
+    rng: &mut R,
+) -> Simulation {
+    assert!(samples > 0, "at least one sample is required");
+    #[allow(clippy::cast_precision_loss)]
+    let n = samples as f64;
+
+    let buckets = expected
+        .iter()
+        .map(|&expected| {
+            let (mut squared, mut absolute) = (0.0, 0.0);
+            for _ in 0..samples {
+                let error = candidate.sample(rng);
+                squared += error * error;
+                absolute += error.abs();
+            }
+            BucketAccuracy {
+                expected,
+                rmse: (squared / n).sqrt(),
+                relative_error: (expected > 0).then(|| absolute / n / f64::from(expected)),
+            }
+        })
+        .collect();
+
+    Simulation {
+        candidate,
+        samples,
+        buckets,
+    }
+}
+
+impl Display for Simulation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "epsilon: {}, cap: {}, samples: {}",
+            self.candidate.epsilon, self.candidate.cap, self.samples
+        )?;
+        writeln!(
+            f,
+            "{:>6} {:>10} {:>10} {:>10}",
+            "bucket", "expected", "rmse", "rel error"
+        )?;
+        for (i, b) in self.buckets.iter().enumerate() {
+            let relative = b
+                .relative_error
+                .map_or_else(|| "-".to_owned(), |e| format!("{:.2}%", e * 100.0));
+            writeln!(
+                f,
+                "{i:>6} {:>10} {:>10.2} {relative:>10}",
+                b.expected, b.rmse
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accuracy::{simulate, Candidate};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn laplace_error() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let candidate = Candidate {
+            epsilon: 0.5,
+            cap: 2.0,
+        };
+        let sim = simulate(&[100, 0], candidate, 20_000, &mut rng);
+
+        // Laplace noise with scale b has standard deviation b * sqrt(2) and mean absolute
+        // value b, here b = 4
+        let b = &sim.buckets[0];
+        assert!((b.rmse - 4.0 * 2_f64.sqrt()).abs() < 0.2, "{}", b.rmse);
+        assert!((b.relative_error.unwrap() - 0.04).abs() < 0.002);
+        assert_eq!(None, sim.buckets[1].relative_error);
+    }
+
+    #[test]
+    fn no_noise() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let candidate = Candidate {
+            epsilon: f64::INFINITY,
+            cap: 1.0,
+        };
+        let sim = simulate(&[5, 7], candidate, 10, &mut rng);
+        assert!(sim
+            .buckets
+            .iter()
+            .all(|b| b.rmse == 0.0 && b.relative_error == Some(0.0)));
+    }
+}
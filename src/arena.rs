@@ -0,0 +1,210 @@
+//!
+//! Scratch buffers for the protocols of a query. Steps like multiplication and conditional swaps
+//! collect what they compute for a batch of records in vectors that are dropped as soon as the
+//! batch is exchanged with peers, and a large sort runs thousands of such batches. Allocating
+//! and freeing all of them puts pressure on the allocator and fragments the heap of a helper
+//! that runs many queries at once.
+//!
+//! An [`Arena`] keeps the buffers a query used instead, and hands them out again to the next
+//! step that asks for [`Scratch`] space of the same type. Buffers are cleared before they are
+//! reused, and only freed all at once when the arena is [reset](Arena::reset), which helpers do
+//! at stage boundaries, or dropped with the query. Protocols get scratch space through
+//! [`ProtocolContext::scratch`](crate::securemul::ProtocolContext::scratch), which allocates
+//! as usual if the query has no arena.
+//!
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Buffers that are not in use, by the type of their elements.
+type FreeLists = HashMap<TypeId, Vec<Box<dyn Any + Send>>>;
+
+/// How an arena served requests for scratch space.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaStats {
+    /// Buffers that had to be allocated.
+    pub allocated: u64,
+    /// Buffers that were reused.
+    pub reused: u64,
+    /// Buffers kept for reuse right now.
+    pub retained: u64,
+}
+
+/// Buffers of a single query that are reused until the arena is reset.
+#[derive(Debug, Default)]
+pub struct Arena {
+    free: Mutex<FreeLists>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl Arena {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Empty buffer with room for at least `capacity` elements, which goes back to the arena
+    /// when dropped.
+    ///
+    /// ## Panics
+    /// If another thread panicked while it returned a buffer.
+    #[must_use]
+    pub fn vec<T: Send + 'static>(&self, capacity: usize) -> Scratch<'_, T> {
+        let reused = self
+            .free
+            .lock()
+            .unwrap()
+            .get_mut(&TypeId::of::<Vec<T>>())
+            .and_then(Vec::pop);
+        let buf = if let Some(buf) = reused {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            let mut buf = buf.downcast::<Vec<T>>().unwrap();
+            buf.reserve(capacity);
+            buf
+        } else {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Box::new(Vec::with_capacity(capacity))
+        };
+        Scratch {
+            buf: Some(buf),
+            arena: Some(self),
+        }
+    }
+
+    /// Frees every buffer the arena keeps. Buffers that are in use when it is reset go back to
+    /// the arena as usual once dropped.
+    ///
+    /// ## Panics
+    /// If another thread panicked while it returned a buffer.
+    pub fn reset(&self) {
+        // buffers are freed outside of the lock
+        let free = mem::take(&mut *self.free.lock().unwrap());
+        drop(free);
+    }
+
+    /// ## Panics
+    /// If another thread panicked while it returned a buffer.
+    #[must_use]
+    pub fn stats(&self) -> ArenaStats {
+        let retained = self
+            .free
+            .lock()
+            .unwrap()
+            .values()
+            .map(Vec::len)
+            .sum::<usize>();
+        ArenaStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            retained: retained as u64,
+        }
+    }
+
+    fn give_back(&self, buf: Box<dyn Any + Send>) {
+        // a buffer that cannot go back is freed instead, which is never wrong
+        if let Ok(mut free) = self.free.lock() {
+            free.entry((*buf).type_id()).or_default().push(buf);
+        }
+    }
+}
+
+/// Vector of scratch space, empty when it is handed out. Derefs to the vector.
+#[derive(Debug)]
+pub struct Scratch<'a, T: Send + 'static> {
+    /// Only taken when dropped. Boxed so that it goes back to the arena as is, without
+    /// allocating.
+    #[allow(clippy::box_collection)]
+    buf: Option<Box<Vec<T>>>,
+    arena: Option<&'a Arena>,
+}
+
+impl<T: Send + 'static> Scratch<'_, T> {
+    /// Scratch space that is not taken from an arena, and is freed when dropped.
+    #[must_use]
+    pub fn unpooled(capacity: usize) -> Self {
+        Self {
+            buf: Some(Box::new(Vec::with_capacity(capacity))),
+            arena: None,
+        }
+    }
+}
+
+impl<T: Send + 'static> Deref for Scratch<'_, T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl<T: Send + 'static> DerefMut for Scratch<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl<T: Send + 'static> Drop for Scratch<'_, T> {
+    fn drop(&mut self) {
+        if let (Some(arena), Some(mut buf)) = (self.arena, self.buf.take()) {
+            buf.clear();
+            arena.give_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arena::{Arena, ArenaStats, Scratch};
+
+    #[test]
+    fn reuses_buffers() {
+        let arena = Arena::new();
+        let mut a = arena.vec::<u32>(100);
+        a.extend(0..100);
+        let ptr = a.as_ptr();
+        drop(a);
+
+        let b = arena.vec::<u32>(50);
+        assert!(b.is_empty());
+        assert!(b.capacity() >= 100);
+        assert_eq!(ptr, b.as_ptr());
+        // buffers of other types are not shared
+        let c = arena.vec::<u64>(10);
+        assert_eq!(
+            ArenaStats {
+                allocated: 2,
+                reused: 1,
+                retained: 0,
+            },
+            arena.stats()
+        );
+        drop((b, c));
+        assert_eq!(2, arena.stats().retained);
+    }
+
+    #[test]
+    fn reset() {
+        let arena = Arena::new();
+        let in_use = arena.vec::<u8>(10);
+        drop(arena.vec::<u8>(10));
+        arena.reset();
+        assert_eq!(0, arena.stats().retained);
+
+        // buffers in use come back after the reset
+        drop(in_use);
+        assert_eq!(1, arena.stats().retained);
+        drop(arena.vec::<u8>(10));
+        assert_eq!(1, arena.stats().reused);
+    }
+
+    #[test]
+    fn unpooled() {
+        let mut scratch = Scratch::unpooled(4);
+        scratch.push(1_u8);
+        assert_eq!(&[1], &scratch[..]);
+    }
+}
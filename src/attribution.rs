@@ -0,0 +1,251 @@
+//!
+//! Models that split the credit of a conversion between the source events it is attributed to.
+//! Every touch of a conversion gets a share of its credit that depends only on public data: how
+//! many touches there are and how long before the conversion each of them happened. Helpers
+//! compute these [`credits`](Model::credits) in the clear and [`weigh`] the secret-shared
+//! contributions of the touches with them, which is a multiplication by a public constant and
+//! needs no communication.
+//!
+//! Credits are fixed-point numbers with [`Model::scale`] units to one conversion, so results of
+//! fractional models are that many times larger than the number of conversions or their value.
+//! Rounding never moves credit between conversions: the most recent touch gets whatever is left
+//! after the others got theirs rounded down, so every conversion gives out exactly one.
+//!
+//! Measurement logic that is none of the models can be tried out with [`Hooks`], which change
+//! values before they are capped, the credits of every conversion and the histograms helpers
+//! reveal, while the rest of the query stays as it is. Hooks only ever see data in the clear
+//! that whoever runs them has anyway, so they cannot leak anything the query keeps secret.
+//!
+use crate::field::Field;
+use crate::helpers::models::Aggregate;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// Units of credit a conversion gives out in fractional models: fixed point with three bits
+/// after the point. Fields as small as [`crate::field::Fp31`] leave little room for more.
+pub const CREDIT_SCALE: u32 = 8;
+
+/// How a conversion credits the source events attributed to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum Model {
+    /// All of it goes to the most recent touch.
+    #[default]
+    LastTouch,
+    /// Every touch gets the same share.
+    EqualCredit,
+    /// Touches get shares that halve with every `half_life_seconds` between them and the
+    /// conversion.
+    TimeDecay { half_life_seconds: u32 },
+}
+
+impl Model {
+    /// Units of credit to one conversion. Last touch gives out whole conversions only, so its
+    /// results are the same as without attribution credit.
+    #[must_use]
+    pub fn scale(self) -> u32 {
+        match self {
+            Self::LastTouch => 1,
+            Self::EqualCredit | Self::TimeDecay { .. } => CREDIT_SCALE,
+        }
+    }
+
+    /// Credit of every touch of a conversion, in units of [`scale`](Self::scale).
+    /// `elapsed_seconds` has the time between every touch and the conversion, in any order.
+    /// Credits add up to the scale, unless there are no touches.
+    #[must_use]
+    pub fn credits(self, elapsed_seconds: &[u32]) -> Vec<u32> {
+        let mut credits = vec![0; elapsed_seconds.len()];
+        // ties go to the touch that comes first
+        let latest = match (0..elapsed_seconds.len()).min_by_key(|&i| elapsed_seconds[i]) {
+            Some(latest) => latest,
+            None => return credits,
+        };
+        let scale = self.scale();
+        match self {
+            Self::LastTouch => {}
+            Self::EqualCredit => {
+                let share = scale / u32::try_from(credits.len()).unwrap_or(u32::MAX);
+                credits.fill(share);
+            }
+            Self::TimeDecay { half_life_seconds } => {
+                // relative to the latest touch, so that old conversions do not underflow
+                let weights = elapsed_seconds
+                    .iter()
+                    .map(|&e| {
+                        let age = f64::from(e - elapsed_seconds[latest]);
+                        (-age / f64::from(half_life_seconds)).exp2()
+                    })
+                    .collect::<Vec<_>>();
+                let total = weights.iter().sum::<f64>();
+                for (credit, weight) in credits.iter_mut().zip(weights) {
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    let units = (f64::from(scale) * weight / total).floor() as u32;
+                    *credit = units;
+                }
+            }
+        }
+        credits[latest] = 0;
+        credits[latest] = scale - credits.iter().sum::<u32>();
+        credits
+    }
+
+    /// Credits of every touch of a conversion as [`credits`](Self::credits) has them, after
+    /// `hooks` changed them.
+    #[must_use]
+    pub fn credits_with(self, hooks: &dyn Hooks, elapsed_seconds: &[u32]) -> Vec<u32> {
+        let mut credits = self.credits(elapsed_seconds);
+        hooks.transform_credits(elapsed_seconds, &mut credits);
+        credits
+    }
+}
+
+/// Extension points of the attribution stage. Every hook does nothing unless implemented.
+pub trait Hooks: Debug + Send + Sync {
+    /// Fixed-point value of a conversion before it is capped to the value scale of the query,
+    /// see [`crate::query::ValueScale::cap`].
+    fn before_capping(&self, value: u32) -> u32 {
+        value
+    }
+
+    /// Changes the `credits` the model gave the touches of a single conversion, which happened
+    /// `elapsed_seconds` before it. Credits that do not add up to the [scale](Model::scale)
+    /// give out more or less than the conversion.
+    fn transform_credits(&self, _elapsed_seconds: &[u32], _credits: &mut [u32]) {}
+
+    /// Changes the revealed `histogram` of `aggregate`, one value per bucket. Values are in
+    /// units of credit, and wrap around the prime of the field.
+    fn after_aggregation(&self, _aggregate: Aggregate, _histogram: &mut [u128]) {}
+}
+
+/// Hooks that leave the attribution stage as it is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+impl FromStr for Model {
+    type Err = String;
+
+    /// Parses `last-touch`, `equal-credit` or `time-decay:<half life in seconds>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        match s.split_once(':') {
+            None if s == "last-touch" => Ok(Self::LastTouch),
+            None if s == "equal-credit" => Ok(Self::EqualCredit),
+            Some(("time-decay", half_life)) => half_life
+                .parse()
+                .map(|half_life_seconds| Self::TimeDecay { half_life_seconds })
+                .map_err(|e| format!("invalid half life {half_life}: {e}")),
+            _ => Err(format!("unknown attribution model: {s}")),
+        }
+    }
+}
+
+/// Contributions of touches to the histogram, each one `credits` times the contribution of the
+/// conversion it belongs to.
+///
+/// ## Panics
+/// If there are not as many credits as contributions.
+#[must_use]
+pub fn weigh<F: Field>(
+    contributions: &[ReplicatedSecretSharing<F>],
+    credits: &[u32],
+) -> Vec<ReplicatedSecretSharing<F>> {
+    assert_eq!(contributions.len(), credits.len());
+    contributions
+        .iter()
+        .zip(credits)
+        .map(|(&c, &credit)| c * F::from(u128::from(credit)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribution::{weigh, Hooks, Model, NoHooks, CREDIT_SCALE};
+    use crate::field::Fp31;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use rand::thread_rng;
+
+    #[test]
+    fn credits() {
+        let elapsed = [3600, 60, 7200];
+        assert_eq!(vec![0, 1, 0], Model::LastTouch.credits(&elapsed));
+        // eighths do not split into thirds, the latest touch gets the rest
+        assert_eq!(CREDIT_SCALE, Model::EqualCredit.scale());
+        assert_eq!(vec![2, 4, 2], Model::EqualCredit.credits(&elapsed));
+        let decay = Model::TimeDecay {
+            half_life_seconds: 3540,
+        };
+        // weights of about 1/2, 1 and 1/4
+        assert_eq!(vec![2, 5, 1], decay.credits(&elapsed));
+
+        for model in [Model::LastTouch, Model::EqualCredit, decay] {
+            assert!(model.credits(&[]).is_empty());
+            assert_eq!(vec![model.scale()], model.credits(&[100]));
+            assert_eq!(model.scale(), model.credits(&elapsed).iter().sum::<u32>());
+        }
+        // ties go to the first touch
+        assert_eq!(vec![1, 0], Model::LastTouch.credits(&[5, 5]));
+        assert_eq!(vec![4, 4], decay.credits(&[5, 5]));
+    }
+
+    #[test]
+    fn hooked_credits() {
+        /// Gives all credit to the first touch instead.
+        #[derive(Debug)]
+        struct FirstTouch;
+
+        impl Hooks for FirstTouch {
+            fn transform_credits(&self, elapsed_seconds: &[u32], credits: &mut [u32]) {
+                let total = credits.iter().sum();
+                credits.fill(0);
+                if let Some(first) = (0..credits.len()).max_by_key(|&i| elapsed_seconds[i]) {
+                    credits[first] = total;
+                }
+            }
+        }
+
+        let elapsed = [3600, 60, 7200];
+        assert_eq!(
+            Model::EqualCredit.credits(&elapsed),
+            Model::EqualCredit.credits_with(&NoHooks, &elapsed)
+        );
+        assert_eq!(
+            vec![0, 0, CREDIT_SCALE],
+            Model::EqualCredit.credits_with(&FirstTouch, &elapsed)
+        );
+        assert!(Model::LastTouch.credits_with(&FirstTouch, &[]).is_empty());
+        assert_eq!(5, NoHooks.before_capping(5));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Model::LastTouch), "last-touch".parse());
+        assert_eq!(Ok(Model::EqualCredit), "Equal-Credit".parse());
+        assert_eq!(
+            Ok(Model::TimeDecay {
+                half_life_seconds: 86400
+            }),
+            "time-decay:86400".parse()
+        );
+        for invalid in ["first-touch", "time-decay", "time-decay:-1", "last-touch:1"] {
+            assert!(invalid.parse::<Model>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn weighs() {
+        let mut rng = thread_rng();
+        let [a, b] = [5_u128, 7].map(|v| ReplicatedSecretSharing::share(Fp31::from(v), &mut rng));
+        let weighed = [0, 1, 2].map(|i| weigh(&[a[i], b[i]], &[3, 0]));
+        let open = |j: usize| {
+            ReplicatedSecretSharing::reconstruct(&[0, 1, 2].map(|i| weighed[i][j])).unwrap()
+        };
+        assert_eq!(Fp31::from(15_u128), open(0));
+        assert_eq!(Fp31::from(0_u128), open(1));
+    }
+}
@@ -0,0 +1,174 @@
+use raw_ipa::cli::Verbosity;
+use raw_ipa::conformance::{self, Report};
+use raw_ipa::helpers::codec::Bincode;
+use raw_ipa::helpers::ring::Identity;
+use raw_ipa::helpers::tcp::{TcpRing, TcpRingConfig};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::net::TcpListener;
+use tracing::info;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "conformance",
+    about = "Runs two helpers against a helper implemented independently of this crate, and reports which stages of the test vector queries it interoperates in"
+)]
+struct Args {
+    #[structopt(flatten)]
+    logging: Verbosity,
+
+    /// Identity of the helper under test: H1, H2 or H3
+    #[structopt(long, parse(try_from_str = parse_identity))]
+    external: Identity,
+
+    /// Address the helper under test listens on
+    #[structopt(long)]
+    external_addr: SocketAddr,
+
+    /// Address the helper on the right of the one under test listens on
+    #[structopt(long, default_value = "127.0.0.1:0")]
+    right_listen: SocketAddr,
+
+    /// Address the helper on the left of the one under test listens on
+    #[structopt(long, default_value = "127.0.0.1:0")]
+    left_listen: SocketAddr,
+
+    /// Milliseconds to wait for connections, and for every stage to finish
+    #[structopt(long, default_value = "10000")]
+    timeout_ms: u64,
+
+    /// Print the report as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+fn parse_identity(s: &str) -> Result<Identity, String> {
+    Identity::ALL
+        .into_iter()
+        .find(|i| i.to_string().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown helper identity {s}"))
+}
+
+/// Connects the helpers on the right and on the left of the helper under test, listening on
+/// `right` and `left`, and runs them against it.
+async fn run(args: &Args, right: TcpListener, left: TcpListener) -> Result<Report, Box<dyn Error>> {
+    let (right_addr, left_addr) = (right.local_addr()?, left.local_addr()?);
+    info!(
+        "helpers listen on {right_addr} and {left_addr}, {} is at {}",
+        args.external, args.external_addr
+    );
+    let timeout = Duration::from_millis(args.timeout_ms);
+    // the helper under test is expected not to authenticate its peers either
+    let config = |listen, left, right| TcpRingConfig {
+        allow_unauthenticated: true,
+        ..TcpRingConfig::new(listen, left, right)
+    };
+    let right_config = config(right_addr, args.external_addr, left_addr);
+    let left_config = config(left_addr, right_addr, args.external_addr);
+    let connect = async {
+        futures::try_join!(
+            TcpRing::<Bincode>::connect(right, &right_config),
+            TcpRing::<Bincode>::connect(left, &left_config),
+        )
+    };
+    let (right, left) = match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(rings)) => rings,
+        Ok(Err(e)) => return Ok(Report::unconnected(args.external, e.to_string())),
+        Err(_) => {
+            let reason = format!("did not connect in {timeout:?}");
+            return Ok(Report::unconnected(args.external, reason));
+        }
+    };
+    Ok(conformance::check(args.external, &right, &left, timeout).await)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args();
+    args.logging.setup_logging();
+
+    let right = TcpListener::bind(args.right_listen).await?;
+    let left = TcpListener::bind(args.left_listen).await?;
+    let report = run(&args, right, left).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+    if !report.passed() {
+        return Err(format!("{} does not conform", args.external).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Args};
+    use raw_ipa::conformance::{run_helper, Outcome};
+    use raw_ipa::helpers::codec::Bincode;
+    use raw_ipa::helpers::ring::Identity;
+    use raw_ipa::helpers::tcp::{TcpRing, TcpRingConfig};
+    use std::net::SocketAddr;
+    use structopt::StructOpt;
+    use tokio::net::TcpListener;
+
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        let (external, external_addr) = listen().await;
+        let (right, right_addr) = listen().await;
+        let (left, left_addr) = listen().await;
+        let addr = external_addr.to_string();
+        let args = Args::from_iter(["conformance", "--external", "h2", "--external-addr", &addr]);
+
+        // the helper under test is this crate as well
+        let helper = async {
+            let config = TcpRingConfig {
+                allow_unauthenticated: true,
+                ..TcpRingConfig::new(external_addr, left_addr, right_addr)
+            };
+            let ring = TcpRing::<Bincode>::connect(external, &config)
+                .await
+                .unwrap();
+            run_helper(Identity::H2, &ring).await
+        };
+        let (report, helper) = futures::join!(run(&args, right, left), helper);
+        helper.unwrap();
+        let report = report.unwrap();
+        assert!(report.passed(), "{report}");
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // nothing listens there once the listener is dropped
+        let (external, external_addr) = listen().await;
+        drop(external);
+        let addr = external_addr.to_string();
+        let args = Args::from_iter([
+            "conformance",
+            "--external",
+            "H3",
+            "--external-addr",
+            &addr,
+            "--timeout-ms",
+            "200",
+        ]);
+        let (right, _) = listen().await;
+        let (left, _) = listen().await;
+
+        let report = run(&args, right, left).await.unwrap();
+        assert!(!report.passed());
+        assert!(matches!(report.connect, Outcome::Failed(_)));
+        assert!(report.stages.iter().all(|s| s.outcome == Outcome::Skipped));
+    }
+}
@@ -1,14 +1,71 @@
 use std::error::Error;
 
+use futures::future::{select, Either};
 use hyper::http::uri::Scheme;
-use raw_ipa::cli::Verbosity;
-use raw_ipa::net::{bind_mpc_helper_server, BindTarget};
+use raw_ipa::capabilities::Capabilities;
+use raw_ipa::cli::{KeygenArgs, Verbosity};
+use raw_ipa::helpers::quota::Quotas;
+use raw_ipa::helpers::tcp::PROTOCOL_VERSION;
+use raw_ipa::net::auth::Tokens;
+use raw_ipa::net::{
+    capabilities_router, mpc_helper_router, serve_mpc_helper_until, status_router, BindTarget,
+};
+use raw_ipa::telemetry::status::Status;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
-use tracing::info;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tracing::{info, warn};
+
+/// How often to check whether running queries have finished once the helper was asked to stop.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long queries that are running when the helper is asked to stop have to checkpoint.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Completes once the process gets SIGTERM from `terminate`, or SIGINT.
+async fn terminated(mut terminate: Signal) {
+    let terminate = Box::pin(terminate.recv());
+    if let Either::Right((Err(e), terminate)) =
+        select(terminate, Box::pin(tokio::signal::ctrl_c())).await
+    {
+        warn!("cannot listen for SIGINT, stopping on SIGTERM only: {e}");
+        terminate.await;
+    }
+}
+
+/// Stops taking new queries once the process is asked to terminate and pauses the ones that are
+/// running, so they resume from a checkpoint when the helper is back. Completes when all of them
+/// have finished or suspended, or [`DRAIN_TIMEOUT`] has passed.
+async fn drain(status: Arc<Status>, terminate: Signal) {
+    terminated(terminate).await;
+    info!("shutting down, pausing running queries");
+    status.start_draining();
+    status.pause_all();
+    let wait = async {
+        while !status.drained() {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    };
+    if tokio::time::timeout(DRAIN_TIMEOUT, wait).await.is_err() {
+        warn!(
+            "{} queries did not suspend in {DRAIN_TIMEOUT:?}",
+            status.snapshot().queries.len()
+        );
+    }
+}
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "mpc-helper", about = "CLI to start an MPC helper endpoint")]
+#[structopt(
+    name = "mpc-helper",
+    about = "CLI to start an MPC helper endpoint",
+    long_about = "CLI to start an MPC helper endpoint.\n\n\
+        This helper does not run queries yet: nothing starts them, so nothing is admitted to its \
+        status. /status, /events, /pause and /cancel stay empty and --quotas is not applied until \
+        a query driver that admits queries is wired in."
+)]
 struct Args {
     /// Configure logging.
     #[structopt(flatten)]
@@ -21,6 +78,40 @@ struct Args {
     /// Indicates whether to start HTTP or HTTPS endpoint
     #[structopt(short = "-s", long = "scheme", default_value = "http")]
     scheme: Scheme,
+
+    /// Largest number of records to take in a single query, advertised on /capabilities
+    #[structopt(long = "max-records")]
+    max_records: Option<u64>,
+
+    /// Keep coarse statistics of the queries that stopped running in the last this many seconds,
+    /// reported on /status
+    #[structopt(long = "summary-window")]
+    summary_window: Option<u64>,
+
+    /// JSON file with the SHA-256 digests of the bearer tokens of collectors, peers and operators,
+    /// which /status, /events, /pause and /cancel take. Without it, they take nobody
+    #[structopt(long = "tokens", parse(from_os_str))]
+    tokens: Option<PathBuf>,
+
+    /// JSON file with the quotas of collectors, which queries they submit are admitted against.
+    /// Without it, collectors are not limited. Not applied yet, as this helper admits no queries
+    #[structopt(long = "quotas", parse(from_os_str))]
+    quotas: Option<PathBuf>,
+
+    /// Expose metrics in Prometheus format on the /metrics endpoint
+    #[cfg(feature = "prometheus")]
+    #[structopt(long = "metrics")]
+    metrics: bool,
+
+    /// Run a helper management command instead of starting the endpoint
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Generate key material for a helper
+    Keygen(KeygenArgs),
 }
 
 #[tokio::main]
@@ -28,6 +119,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::from_args();
     args.logging.setup_logging();
 
+    if let Some(Command::Keygen(keygen)) = &args.command {
+        keygen.execute()?;
+        return Ok(());
+    }
+
     // decide what protocol we're going to use here
     let addr = SocketAddr::from(([127, 0, 0, 1], args.port.unwrap_or(0)));
     let target = match args.scheme.as_str() {
@@ -42,10 +138,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     };
 
-    // start server
-    let (addr, server_handle) = bind_mpc_helper_server(target).await;
+    let tokens = match &args.tokens {
+        Some(path) => Tokens::from_json(&std::fs::read(path)?)?,
+        None => Tokens::default(),
+    };
+    if tokens.is_empty() {
+        warn!("no tokens configured, query management endpoints take nobody");
+    }
+
+    let mut status = args.summary_window.map_or_else(Status::default, |secs| {
+        Status::with_summary(Duration::from_secs(secs))
+    });
+    if let Some(path) = &args.quotas {
+        let quotas: Quotas = serde_json::from_slice(&std::fs::read(path)?)?;
+        status = status.with_quotas(quotas);
+    }
+    let status = Arc::new(status);
+    #[allow(unused_mut)]
+    let mut router = mpc_helper_router()
+        .merge(status_router(Arc::clone(&status), tokens))
+        .merge(capabilities_router(
+            Capabilities::new(PROTOCOL_VERSION).with_max_records(args.max_records),
+        ));
+    #[cfg(feature = "prometheus")]
+    if args.metrics {
+        let handle = raw_ipa::telemetry::install_prometheus_recorder()?;
+        router = router.merge(raw_ipa::net::metrics_router(handle));
+    }
+
+    // start server, which drains and stops on SIGTERM or SIGINT
+    let terminate = signal(SignalKind::terminate())?;
+    let (addr, server_handle) =
+        serve_mpc_helper_until(target, router, drain(status, terminate)).await;
     info!("listening to {}://{}", args.scheme, addr);
     server_handle.await?;
+    info!("stopped");
 
     Ok(())
 }
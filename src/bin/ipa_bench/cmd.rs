@@ -1,18 +1,70 @@
-use crate::sample::Sample;
+use crate::config::Config;
+use crate::sample::{distributions, EventDistributions};
 
-use super::gen_events::generate_events;
+use super::fit::{fit, Aggregates};
+use super::gen_events::{gen_population, generate_events, generate_query, OutputFormat, Splitter};
+use super::secret_share::secret_share;
 
 use log::{debug, error, info};
 use rand::rngs::StdRng;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use raw_ipa::accuracy::{simulate, Candidate};
 use raw_ipa::cli::Verbosity;
+use raw_ipa::estimate::{estimate, Network};
+use raw_ipa::export::{Format, QueryResult};
+use raw_ipa::net::object_store::{Error as ObjectStoreError, ObjectStore};
+use raw_ipa::query::IpaQueryConfig;
+use raw_ipa::verify::{reconstruct, verify, NoiseParams};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::{io, process};
+use std::process;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 const DEFAULT_EVENT_GEN_COUNT: u32 = 100_000;
 
+/// Machine-readable description of a `gen-events` run.
+#[derive(Debug, Serialize)]
+struct Summary {
+    seed: u64,
+    /// SHA-256 of the configuration file, as read.
+    config_sha256: String,
+    source_events: u32,
+    trigger_events: u32,
+    /// File the events were written to. Not set if they were written to stdout.
+    output: Option<PathBuf>,
+    output_bytes: u64,
+    elapsed_secs: f64,
+}
+
+/// Counts bytes written to the inner writer.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct CommonArgs {
     #[structopt(flatten)]
@@ -34,21 +86,24 @@ pub struct CommonArgs {
 impl CommonArgs {
     fn get_output(&self) -> Result<Box<dyn io::Write>, io::Error> {
         match self.output_file {
-            Some(ref path) => {
-                let mut file = File::options();
+            Some(ref path) => self
+                .open_output(path)
+                .map(|f| Box::new(f) as Box<dyn io::Write>),
+            None => Ok(Box::new(io::stdout())),
+        }
+    }
 
-                if self.overwrite {
-                    file.truncate(true).create(true);
-                } else {
-                    file.create_new(true);
-                }
+    /// Opens `path` for writing, refusing to replace an existing file unless `--overwrite` is set.
+    fn open_output(&self, path: &Path) -> Result<File, io::Error> {
+        let mut file = File::options();
 
-                file.write(true)
-                    .open(path)
-                    .map(|f| Box::new(f) as Box<dyn io::Write>)
-            }
-            None => Ok(Box::new(io::stdout())),
+        if self.overwrite {
+            file.truncate(true).create(true);
+        } else {
+            file.create_new(true);
         }
+
+        file.write(true).open(path)
     }
 }
 
@@ -93,6 +148,112 @@ pub enum Command {
         #[structopt(long, help = "Output secret shared values")]
         secret_share: bool,
 
+        #[structopt(
+            long,
+            help = "Output secret shared events in the report schema of the helpers, one source or trigger event per record. Implies --secret-share."
+        )]
+        models: bool,
+
+        #[structopt(
+            long,
+            help = "Split secret shared values with random bytes drawn in blocks, which is faster for large runs. Shares differ from those generated without it with the same seed, values do not."
+        )]
+        batch_shares: bool,
+
+        #[structopt(
+            long,
+            help = "Sample with integer arithmetic only, so the same seed generates the same events on every platform."
+        )]
+        deterministic: bool,
+
+        #[structopt(
+            long,
+            parse(try_from_str = parse_range),
+            help = "Generate only ads in this range, e.g. 100..200. With the same seed, events of these ads are the same as in a run that generates all of them."
+        )]
+        ads: Option<Range<u32>>,
+
+        #[structopt(
+            long,
+            help = "Write a JSON summary of the run to the file: event counts, output size, seed and the hash of the configuration.",
+            parse(from_os_str)
+        )]
+        summary: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            help = "Configuration file containing distributions data.",
+            parse(from_os_str)
+        )]
+        config_file: PathBuf,
+    },
+
+    #[structopt(
+        about = "Generate events of several queries of different advertisers that reach the same users, with the expected result of every query."
+    )]
+    GenQueries {
+        #[structopt(long, default_value = "2", help = "Number of queries to generate.")]
+        queries: usize,
+
+        #[structopt(
+            long,
+            default_value = "10",
+            help = "Number of ads run by every advertiser."
+        )]
+        ads: usize,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "10000",
+            help = "Number of users shared by all queries. Ads of a query stop reaching new users once all of them are reached."
+        )]
+        population: usize,
+
+        #[structopt(
+            short,
+            long,
+            help = "Random generator seed. Setting the seed allows reproduction of the synthetic data exactly."
+        )]
+        random_seed: Option<u64>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "0",
+            help = "First epoch in which ads are created."
+        )]
+        epoch: u8,
+
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "Number of consecutive epochs in which ads of every query are created, so queries overlap in all of them. Impressions and conversions may happen in the epochs after them."
+        )]
+        epochs: u8,
+
+        #[structopt(long, help = "Output secret shared values")]
+        secret_share: bool,
+
+        #[structopt(
+            long,
+            help = "Output secret shared events in the report schema of the helpers, one source or trigger event per record. Implies --secret-share."
+        )]
+        models: bool,
+
+        #[structopt(
+            long,
+            help = "Split secret shared values with random bytes drawn in blocks, which is faster for large runs. Shares differ from those generated without it with the same seed, values do not."
+        )]
+        batch_shares: bool,
+
+        #[structopt(
+            long,
+            help = "Sample with integer arithmetic only, so the same seed generates the same events on every platform."
+        )]
+        deterministic: bool,
+
         #[structopt(
             short,
             long,
@@ -100,10 +261,229 @@ pub enum Command {
             parse(from_os_str)
         )]
         config_file: PathBuf,
+
+        #[structopt(
+            short = "d",
+            long,
+            help = "Directory to write query<N>.events and query<N>.expected.json of every query to.",
+            parse(from_os_str)
+        )]
+        output_dir: PathBuf,
+    },
+
+    #[structopt(
+        about = "Fit the distributions of a configuration to aggregate statistics of real traffic, so generated events match them."
+    )]
+    Fit {
+        #[structopt(
+            short,
+            long,
+            help = "JSON file with reach and CVR histograms and conversion value quantiles. Reads from stdin if not specified.",
+            parse(from_os_str)
+        )]
+        input_file: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            help = "Configuration file whose distributions not described by the statistics are kept.",
+            parse(from_os_str)
+        )]
+        config_file: PathBuf,
+    },
+
+    #[structopt(
+        about = "Secret share cleartext events produced by gen-events into inputs for three helpers."
+    )]
+    SecretShare {
+        #[structopt(
+            short,
+            long,
+            help = "File containing cleartext events. Reads from stdin if not specified.",
+            parse(from_os_str)
+        )]
+        input_file: Option<PathBuf>,
+
+        #[structopt(
+            short = "d",
+            long,
+            help = "Directory to write helper1.bin, helper2.bin and helper3.bin to. With --overwrite, they may be named pipes that upload reads from, so shares are never stored.",
+            parse(from_os_str)
+        )]
+        output_dir: PathBuf,
+
+        #[structopt(
+            short,
+            long,
+            help = "Random generator seed. Setting the seed allows reproduction of the shares exactly."
+        )]
+        random_seed: Option<u64>,
+    },
+
+    #[structopt(
+        about = "Upload a file, or whatever is piped to stdin, to an object store by a pre-signed URL."
+    )]
+    Upload {
+        #[structopt(
+            short,
+            long,
+            help = "File to upload. Reads from stdin if not specified.",
+            parse(from_os_str)
+        )]
+        input_file: Option<PathBuf>,
+
+        #[structopt(long, help = "Pre-signed URL to upload to.")]
+        url: String,
+    },
+
+    #[structopt(about = "Compare the result revealed by helpers to the expected histogram.")]
+    Verify {
+        #[structopt(
+            short,
+            long,
+            help = "JSON file with the expected value of every bucket, computed in the clear.",
+            parse(from_os_str)
+        )]
+        expected: PathBuf,
+
+        #[structopt(
+            short,
+            long,
+            number_of_values = 3,
+            required = true,
+            help = "JSON files with the shares of the result revealed by each of the three helpers.",
+            parse(from_os_str)
+        )]
+        shares: Vec<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Privacy budget used by the query. If not set, the result must match exactly."
+        )]
+        epsilon: Option<f64>,
+
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "Maximum contribution of a single user to any bucket."
+        )]
+        sensitivity: f64,
+
+        #[structopt(
+            long,
+            default_value = "0.99",
+            help = "Probability with which noise in a bucket must be within the reported tolerance."
+        )]
+        confidence: f64,
+    },
+
+    #[structopt(
+        about = "Reconstruct the result revealed by helpers and write it with the configuration of the query, for analyses to read."
+    )]
+    Export {
+        #[structopt(
+            short,
+            long,
+            number_of_values = 3,
+            required = true,
+            help = "JSON files with the shares of the result revealed by each of the three helpers.",
+            parse(from_os_str)
+        )]
+        shares: Vec<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "JSON file with the configuration the query was run with.",
+            parse(from_os_str)
+        )]
+        query_config: PathBuf,
+
+        #[structopt(
+            long,
+            default_value = "json",
+            help = "Format of the result: json, csv or, if built with the arrow feature, arrow."
+        )]
+        format: Format,
+    },
+
+    #[structopt(
+        about = "Estimate the error DP noise adds to every bucket of a histogram for candidate privacy budgets and contribution caps."
+    )]
+    SimulateNoise {
+        #[structopt(
+            short,
+            long,
+            help = "JSON file with the expected value of every bucket.",
+            parse(from_os_str)
+        )]
+        expected: PathBuf,
+
+        #[structopt(
+            long,
+            required = true,
+            help = "Privacy budgets to evaluate. Every one is combined with every cap."
+        )]
+        epsilon: Vec<f64>,
+
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "Maximum contributions of a single user to any bucket to evaluate."
+        )]
+        cap: Vec<f64>,
+
+        #[structopt(
+            long,
+            default_value = "1000",
+            help = "Number of times noise is drawn for every bucket."
+        )]
+        samples: usize,
+
+        #[structopt(
+            short,
+            long,
+            help = "Random generator seed. Setting the seed allows reproduction of the estimates exactly."
+        )]
+        random_seed: Option<u64>,
+    },
+
+    #[structopt(
+        about = "Estimate the rounds, traffic between helpers, memory and time a query takes before it runs."
+    )]
+    Estimate {
+        #[structopt(
+            long,
+            help = "JSON file with the configuration of the query. The default configuration if not set.",
+            parse(from_os_str)
+        )]
+        query_config: Option<PathBuf>,
+
+        #[structopt(
+            short = "n",
+            long,
+            help = "Number of input rows, source and trigger events together."
+        )]
+        records: u64,
+
+        #[structopt(long, help = "Time in milliseconds a message takes between helpers.")]
+        latency_ms: Option<u64>,
+
+        #[structopt(
+            long,
+            help = "Megabits per second a helper sends to each of its peers."
+        )]
+        bandwidth_mbps: Option<u64>,
+
+        #[structopt(
+            long,
+            help = "Time in nanoseconds a helper takes to compute its part of a multiplication."
+        )]
+        multiplication_ns: Option<u64>,
     },
 }
 
 impl Command {
+    #[allow(clippy::too_many_lines)]
     pub fn dispatch(&self, common: &CommonArgs) {
         info!("Command {:?}", self);
 
@@ -113,6 +493,11 @@ impl Command {
                 random_seed,
                 epoch,
                 secret_share,
+                models,
+                batch_shares,
+                deterministic,
+                ads,
+                summary,
                 config_file,
             } => {
                 Command::gen_events(
@@ -120,53 +505,162 @@ impl Command {
                     *scale_factor,
                     random_seed,
                     *epoch,
-                    *secret_share,
+                    OutputFormat::from_flags(*secret_share, *models),
+                    *batch_shares,
+                    *deterministic,
+                    ads.clone().unwrap_or(0..u32::MAX),
+                    config_file,
+                    summary.as_deref(),
+                );
+            }
+            Self::GenQueries {
+                queries,
+                ads,
+                population,
+                random_seed,
+                epoch,
+                epochs,
+                secret_share,
+                models,
+                batch_shares,
+                deterministic,
+                config_file,
+                output_dir,
+            } => {
+                Command::gen_queries(
+                    common,
+                    *queries,
+                    *ads,
+                    *population,
+                    random_seed,
+                    *epoch..epoch.saturating_add(*epochs),
+                    OutputFormat::from_flags(*secret_share, *models),
+                    *batch_shares,
+                    *deterministic,
                     config_file,
+                    output_dir,
                 );
             }
+            Self::Fit {
+                input_file,
+                config_file,
+            } => {
+                Command::fit(common, input_file, config_file);
+            }
+            Self::SecretShare {
+                input_file,
+                output_dir,
+                random_seed,
+            } => {
+                Command::secret_share(common, input_file, output_dir, random_seed);
+            }
+            Self::Upload { input_file, url } => {
+                Command::upload(input_file, url);
+            }
+            Self::Verify {
+                expected,
+                shares,
+                epsilon,
+                sensitivity,
+                confidence,
+            } => {
+                let noise = epsilon.map_or(NoiseParams::NONE, |epsilon| NoiseParams {
+                    epsilon,
+                    sensitivity: *sensitivity,
+                    confidence: *confidence,
+                });
+                Command::verify(common, expected, shares, &noise);
+            }
+            Self::Export {
+                shares,
+                query_config,
+                format,
+            } => {
+                Command::export(common, shares, query_config, *format);
+            }
+            Self::SimulateNoise {
+                expected,
+                epsilon,
+                cap,
+                samples,
+                random_seed,
+            } => {
+                Command::simulate_noise(common, expected, epsilon, cap, *samples, random_seed);
+            }
+            Self::Estimate {
+                query_config,
+                records,
+                latency_ms,
+                bandwidth_mbps,
+                multiplication_ns,
+            } => {
+                let default = Network::default();
+                let network = Network {
+                    latency: latency_ms.map_or(default.latency, Duration::from_millis),
+                    bandwidth: bandwidth_mbps.map_or(default.bandwidth, |mbps| mbps * 125_000),
+                    multiplication: multiplication_ns
+                        .map_or(default.multiplication, Duration::from_nanos),
+                };
+                Command::estimate(common, query_config.as_deref(), *records, &network);
+            }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn gen_events(
         common: &CommonArgs,
         scale_factor: u32,
         random_seed: &Option<u64>,
         epoch: u8,
-        secret_share: bool,
+        format: OutputFormat,
+        batch_shares: bool,
+        deterministic: bool,
+        ads: Range<u32>,
         config_file: &Path,
+        summary: Option<&Path>,
     ) {
+        let start = Instant::now();
         let mut input = Command::get_input(&Some(config_file.to_path_buf())).unwrap_or_else(|e| {
             error!("Failed to open the input file. {}", e);
             process::exit(1);
         });
 
-        let mut out = common.get_output().unwrap_or_else(|e| {
-            error!("Failed to open the output file. {}", e);
-            process::exit(1);
-        });
+        // events are written a few bytes at a time
+        let mut out =
+            CountingWriter::new(BufWriter::new(common.get_output().unwrap_or_else(|e| {
+                error!("Failed to open the output file. {}", e);
+                process::exit(1);
+            })));
 
+        // log the seed of unseeded runs as well, so they can be reproduced
+        let seed = random_seed.unwrap_or_else(|| StdRng::from_entropy().gen());
         info!(
-            "scale: {}, seed: {:?}, epoch: {}",
-            scale_factor, random_seed, epoch
+            "scale: {}, seed: {}, epoch: {}, ads: {:?}",
+            scale_factor, seed, epoch, ads
         );
         debug!(
             "Total number of events to generate: {}",
             DEFAULT_EVENT_GEN_COUNT * scale_factor
         );
 
-        let config = serde_json::from_reader(&mut input).unwrap();
-        let sample = Sample::new(&config);
-
-        let mut rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
-        let mut ss_rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
+        let mut config = Vec::new();
+        input.read_to_end(&mut config).unwrap_or_else(|e| {
+            error!("Failed to read the input file. {}", e);
+            process::exit(1);
+        });
+        let config_sha256 = format!("{:x}", Sha256::digest(&config));
+        let config: Config = serde_json::from_slice(&config).unwrap();
+        info!("frequency cap: {:?}", config.frequency_cap);
+        let sample = Command::distributions(&config, deterministic);
 
         let (s_count, t_count) = generate_events(
-            &sample,
+            sample.as_ref(),
             DEFAULT_EVENT_GEN_COUNT * scale_factor,
             epoch,
-            secret_share,
-            &mut rng,
-            &mut ss_rng,
+            format,
+            batch_shares,
+            seed,
+            ads,
             &mut out,
         );
 
@@ -176,6 +670,271 @@ impl Command {
             "trigger/source ratio: {}",
             f64::from(t_count) / f64::from(s_count)
         );
+
+        out.flush().unwrap();
+        if let Some(path) = summary {
+            let summary = Summary {
+                seed,
+                config_sha256,
+                source_events: s_count,
+                trigger_events: t_count,
+                output: common.output_file.clone(),
+                output_bytes: out.count,
+                elapsed_secs: start.elapsed().as_secs_f64(),
+            };
+            let file = common.open_output(path).unwrap_or_else(|e| {
+                error!("Failed to open {}. {}", path.display(), e);
+                process::exit(1);
+            });
+            serde_json::to_writer_pretty(file, &summary).unwrap();
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn gen_queries(
+        common: &CommonArgs,
+        queries: usize,
+        ads: usize,
+        population: usize,
+        random_seed: &Option<u64>,
+        epochs: Range<u8>,
+        format: OutputFormat,
+        batch_shares: bool,
+        deterministic: bool,
+        config_file: &Path,
+        output_dir: &Path,
+    ) {
+        if epochs.is_empty() {
+            error!("At least one epoch is required");
+            process::exit(1);
+        }
+        info!(
+            "queries: {}, seed: {:?}, epochs: {:?}",
+            queries, random_seed, epochs
+        );
+
+        let config: Config = Command::load_json(config_file);
+        info!("frequency cap: {:?}", config.frequency_cap);
+        let sample = Command::distributions(&config, deterministic);
+
+        let mut rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
+        let mut ss_rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
+        let mut shares = Splitter::new(&mut ss_rng, batch_shares);
+
+        let population = gen_population(sample.as_ref(), population, &mut rng);
+        for q in 0..queries {
+            let path = output_dir.join(format!("query{q}.events"));
+            let mut out = BufWriter::new(common.open_output(&path).unwrap_or_else(|e| {
+                error!("Failed to open {}. {}", path.display(), e);
+                process::exit(1);
+            }));
+            let expected = generate_query(
+                sample.as_ref(),
+                &population,
+                ads,
+                epochs.clone(),
+                format,
+                &mut rng,
+                &mut shares,
+                &mut out,
+            );
+            out.flush().unwrap();
+
+            let path = output_dir.join(format!("query{q}.expected.json"));
+            let out = common.open_output(&path).unwrap_or_else(|e| {
+                error!("Failed to open {}. {}", path.display(), e);
+                process::exit(1);
+            });
+            serde_json::to_writer(out, &expected).unwrap();
+            info!("query {}: expected {:?}", q, expected);
+        }
+    }
+
+    fn secret_share(
+        common: &CommonArgs,
+        input_file: &Option<PathBuf>,
+        output_dir: &Path,
+        random_seed: &Option<u64>,
+    ) {
+        let input = Command::get_input(input_file).unwrap_or_else(|e| {
+            error!("Failed to open the input file. {}", e);
+            process::exit(1);
+        });
+
+        let mut outputs = [1, 2, 3].map(|i| {
+            let path = output_dir.join(format!("helper{i}.bin"));
+            let file = common.open_output(&path).unwrap_or_else(|e| {
+                error!("Failed to open {}. {}", path.display(), e);
+                process::exit(1);
+            });
+            BufWriter::new(file)
+        });
+
+        let mut rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
+        let count = secret_share(BufReader::new(input), &mut outputs, &mut rng)
+            .and_then(|count| {
+                for out in &mut outputs {
+                    out.flush()?;
+                }
+                Ok(count)
+            })
+            .unwrap_or_else(|e| {
+                error!("Failed to secret share events. {}", e);
+                process::exit(1);
+            });
+
+        info!("{} events secret shared", count);
+    }
+
+    fn upload(input_file: &Option<PathBuf>, url: &str) {
+        let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+            error!("Failed to start the runtime. {}", e);
+            process::exit(1);
+        });
+        let store = ObjectStore::default();
+        let written = runtime
+            .block_on(async {
+                match input_file {
+                    Some(path) => {
+                        let file = tokio::fs::File::open(path).await?;
+                        let len = file.metadata().await?.len();
+                        store.put_reader(url, Some(len), file).await
+                    }
+                    None => store.put_reader(url, None, tokio::io::stdin()).await,
+                }
+            })
+            .unwrap_or_else(|e: ObjectStoreError| {
+                error!("Failed to upload. {}", e);
+                process::exit(1);
+            });
+
+        info!("{} bytes uploaded", written);
+    }
+
+    fn verify(common: &CommonArgs, expected: &Path, shares: &[PathBuf], noise: &NoiseParams) {
+        let expected: Vec<u32> = Command::load_json(expected);
+        let shares = [0, 1, 2].map(|i| Command::load_json(&shares[i]));
+
+        let report = verify(&expected, &shares, noise).unwrap_or_else(|e| {
+            error!("Failed to reconstruct the result. {}", e);
+            process::exit(1);
+        });
+
+        let mut out = common.get_output().unwrap_or_else(|e| {
+            error!("Failed to open the output file. {}", e);
+            process::exit(1);
+        });
+        writeln!(out, "{report}").unwrap();
+
+        if !report.is_consistent() {
+            error!("Result is not consistent with the configured noise");
+            process::exit(1);
+        }
+    }
+
+    fn export(common: &CommonArgs, shares: &[PathBuf], query_config: &Path, format: Format) {
+        let shares = [0, 1, 2].map(|i| Command::load_json(&shares[i]));
+        let histogram = reconstruct(&shares).unwrap_or_else(|e| {
+            error!("Failed to reconstruct the result. {}", e);
+            process::exit(1);
+        });
+        let result = QueryResult::new(Command::load_json(query_config), histogram);
+
+        let out = common.get_output().unwrap_or_else(|e| {
+            error!("Failed to open the output file. {}", e);
+            process::exit(1);
+        });
+        result
+            .write(format, BufWriter::new(out))
+            .unwrap_or_else(|e| {
+                error!("Failed to export the result. {}", e);
+                process::exit(1);
+            });
+    }
+
+    fn simulate_noise(
+        common: &CommonArgs,
+        expected: &Path,
+        epsilon: &[f64],
+        cap: &[f64],
+        samples: usize,
+        random_seed: &Option<u64>,
+    ) {
+        let expected: Vec<u32> = Command::load_json(expected);
+        if samples == 0 {
+            error!("At least one sample is required");
+            process::exit(1);
+        }
+
+        let mut out = common.get_output().unwrap_or_else(|e| {
+            error!("Failed to open the output file. {}", e);
+            process::exit(1);
+        });
+
+        let mut rng = random_seed.map_or(StdRng::from_entropy(), StdRng::seed_from_u64);
+        for &epsilon in epsilon {
+            for &cap in cap {
+                let sim = simulate(&expected, Candidate { epsilon, cap }, samples, &mut rng);
+                writeln!(out, "{sim}").unwrap();
+            }
+        }
+    }
+
+    fn estimate(common: &CommonArgs, query_config: Option<&Path>, records: u64, network: &Network) {
+        let config: IpaQueryConfig =
+            query_config.map_or_else(IpaQueryConfig::default, Command::load_json);
+        config.validate().unwrap_or_else(|e| {
+            error!("Invalid query configuration. {}", e);
+            process::exit(1);
+        });
+
+        let mut out = common.get_output().unwrap_or_else(|e| {
+            error!("Failed to open the output file. {}", e);
+            process::exit(1);
+        });
+        write!(out, "{}", estimate(&config, records, network)).unwrap();
+    }
+
+    fn fit(common: &CommonArgs, input_file: &Option<PathBuf>, config_file: &Path) {
+        let input = Command::get_input(input_file).unwrap_or_else(|e| {
+            error!("Failed to open the input file. {}", e);
+            process::exit(1);
+        });
+        let aggregates: Aggregates = serde_json::from_reader(input).unwrap_or_else(|e| {
+            error!("Failed to parse the input file. {}", e);
+            process::exit(1);
+        });
+        let config = fit(&aggregates, Command::load_json(config_file)).unwrap_or_else(|e| {
+            error!("Failed to fit the distributions. {}", e);
+            process::exit(1);
+        });
+
+        let out = common.get_output().unwrap_or_else(|e| {
+            error!("Failed to open the output file. {}", e);
+            process::exit(1);
+        });
+        serde_json::to_writer_pretty(out, &config).unwrap();
+    }
+
+    fn distributions(config: &Config, deterministic: bool) -> Box<dyn EventDistributions + '_> {
+        distributions(config, deterministic).unwrap_or_else(|| {
+            error!(
+                "Unknown distributions {:?}",
+                config.distributions.as_deref().unwrap_or_default()
+            );
+            process::exit(1);
+        })
+    }
+
+    fn load_json<T: serde::de::DeserializeOwned>(path: &Path) -> T {
+        let input = Command::get_input(&Some(path.to_path_buf())).unwrap_or_else(|e| {
+            error!("Failed to open {}. {}", path.display(), e);
+            process::exit(1);
+        });
+        serde_json::from_reader(input).unwrap_or_else(|e| {
+            error!("Failed to parse {}. {}", path.display(), e);
+            process::exit(1);
+        })
     }
 
     fn get_input(path: &Option<PathBuf>) -> Result<Box<dyn io::Read>, io::Error> {
@@ -185,3 +944,13 @@ impl Command {
         }
     }
 }
+
+/// Parses a range of numbers written as `start..end`.
+fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("{s} is not a range, expected start..end"))?;
+    let start = start.parse::<u32>().map_err(|e| e.to_string())?;
+    let end = end.parse::<u32>().map_err(|e| e.to_string())?;
+    Ok(start..end)
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::ops::Range;
+use std::time::Duration;
 
 #[cfg(feature = "enable-serde")]
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,4 +20,54 @@ pub struct Config {
     pub conversion_per_user: Vec<WeightedIndex<u8>>,
     pub impression_impression_duration: Vec<WeightedIndex<Range<f64>>>,
     pub impression_conversion_duration: Vec<WeightedIndex<Range<u32>>>,
+    /// Limits how many impressions a single user sees. No limit if not set.
+    #[serde(default)]
+    pub frequency_cap: Option<FrequencyCap>,
+    /// Errors in the timestamps reported by devices. Timestamps are exact if not set.
+    #[serde(default)]
+    pub clock_skew: Option<ClockSkew>,
+    /// Name of the implementation that samples events, out of the ones in
+    /// `sample::DISTRIBUTIONS`. The distributions above are sampled directly if not set.
+    #[serde(default)]
+    pub distributions: Option<String>,
+}
+
+/// Every device of a user has its own clock that is off by an offset drawn from `device_offset`,
+/// in hours, and every timestamp it reports is off by up to `jitter` seconds more, either way.
+/// Events are reported by a random device of the user, so a conversion may be reported with an
+/// earlier timestamp than the impression that led to it.
+#[cfg(feature = "enable-serde")]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClockSkew {
+    pub device_offset: Vec<WeightedIndex<Range<f64>>>,
+    #[serde(default)]
+    pub jitter: u32,
+}
+
+/// At most `impressions` impressions are shown to a user within any `days` long window.
+/// Impressions that would exceed the cap are delayed until the window has room for them.
+#[cfg(feature = "enable-serde")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FrequencyCap {
+    pub impressions: u8,
+    pub days: u32,
+}
+
+#[cfg(feature = "enable-serde")]
+impl FrequencyCap {
+    fn window(self) -> Duration {
+        Duration::from_secs(u64::from(self.days) * 24 * 60 * 60)
+    }
+
+    /// Earliest time at or after `t` that an impression can be shown to a user who has seen
+    /// impressions at `shown`, which must be sorted.
+    #[must_use]
+    pub fn next_allowed(self, shown: &[Duration], t: Duration) -> Duration {
+        let cap = usize::from(self.impressions);
+        if shown.len() < cap {
+            return t;
+        }
+        // the oldest of the last `cap` impressions must drop out of the window first
+        t.max(shown[shown.len() - cap] + self.window())
+    }
 }
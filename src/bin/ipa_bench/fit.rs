@@ -0,0 +1,210 @@
+use crate::config::{Config, WeightedIndex};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::ops::Range;
+
+/// Anonymized aggregate statistics of real traffic. Every one of them is optional, distributions
+/// of the generator config that none of them describe are kept as they are.
+#[derive(Deserialize, Debug, Default)]
+pub struct Aggregates {
+    /// Number of ads by the number of unique users they reached.
+    #[serde(default)]
+    pub reach: Vec<Bucket<u32>>,
+    /// Number of ads by their conversion rate.
+    #[serde(default)]
+    pub cvr: Vec<Bucket<f64>>,
+    /// Values of conversions at increasing quantiles, from 0 to 1.
+    #[serde(default)]
+    pub conversion_value_quantiles: Vec<Quantile>,
+}
+
+/// Number of observations in `start..end`.
+#[derive(Deserialize, Debug)]
+pub struct Bucket<T> {
+    pub start: T,
+    pub end: T,
+    pub count: u64,
+}
+
+/// `quantile` of the observations are less than or equal to `value`.
+#[derive(Deserialize, Debug)]
+pub struct Quantile {
+    pub quantile: f64,
+    pub value: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0} has no observations")]
+    NoObservations(&'static str),
+    #[error("{0} has an empty bucket {1}")]
+    EmptyBucket(&'static str, String),
+    #[error("conversion value quantiles {0}")]
+    Quantiles(&'static str),
+}
+
+/// Replaces the distributions of `base` with ones that generate events matching `aggregates`.
+///
+/// Events are generated by picking a bucket and then a value in it uniformly, so a histogram is
+/// matched by weighting every bucket by its share of observations, and quantiles by a bucket
+/// between every two of them, weighted by the difference of the quantiles.
+///
+/// ## Errors
+/// If a histogram has no observations or an empty bucket, or quantiles are not increasing from 0
+/// to 1 with values that never decrease.
+pub fn fit(aggregates: &Aggregates, mut base: Config) -> Result<Config, Error> {
+    if !aggregates.reach.is_empty() {
+        base.reach_per_ad = histogram("reach", &aggregates.reach)?;
+    }
+    if !aggregates.cvr.is_empty() {
+        base.cvr_per_ad = histogram("cvr", &aggregates.cvr)?;
+    }
+    if !aggregates.conversion_value_quantiles.is_empty() {
+        base.conversion_value_per_user = quantiles(&aggregates.conversion_value_quantiles)?;
+    }
+    Ok(base)
+}
+
+fn histogram<T: PartialOrd + Display + Copy>(
+    name: &'static str,
+    buckets: &[Bucket<T>],
+) -> Result<Vec<WeightedIndex<Range<T>>>, Error> {
+    if let Some(b) = buckets.iter().find(|b| b.start >= b.end) {
+        return Err(Error::EmptyBucket(name, format!("{}..{}", b.start, b.end)));
+    }
+    let total: u64 = buckets.iter().map(|b| b.count).sum();
+    if total == 0 {
+        return Err(Error::NoObservations(name));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let weights = buckets
+        .iter()
+        .map(|b| WeightedIndex {
+            index: b.start..b.end,
+            weight: b.count as f64 / total as f64,
+        })
+        .collect();
+    Ok(weights)
+}
+
+fn quantiles(quantiles: &[Quantile]) -> Result<Vec<WeightedIndex<Range<u32>>>, Error> {
+    if quantiles.len() < 2 {
+        return Err(Error::Quantiles("need at least two points"));
+    }
+    let first = quantiles[0].quantile;
+    let last = quantiles[quantiles.len() - 1].quantile;
+    if first.abs() > f64::EPSILON || (last - 1.0).abs() > f64::EPSILON {
+        return Err(Error::Quantiles("must start at 0 and end at 1"));
+    }
+
+    quantiles
+        .windows(2)
+        .map(|w| {
+            let (lo, hi) = (&w[0], &w[1]);
+            if hi.quantile <= lo.quantile {
+                return Err(Error::Quantiles("must be increasing"));
+            }
+            if hi.value < lo.value {
+                return Err(Error::Quantiles("must not decrease in value"));
+            }
+            Ok(WeightedIndex {
+                // values are drawn from a half-open range, which must not be empty
+                index: lo.value..hi.value.max(lo.value + 1),
+                weight: hi.quantile - lo.quantile,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit, Aggregates, Bucket, Error, Quantile};
+    use crate::config::Config;
+    use crate::sample::{EventDistributions, Sample};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn base() -> Config {
+        serde_json::from_str(include_str!("config.example.json")).unwrap()
+    }
+
+    fn quantile(quantile: f64, value: u32) -> Quantile {
+        Quantile { quantile, value }
+    }
+
+    #[test]
+    fn sampled_values_match_aggregates() {
+        let aggregates = Aggregates {
+            reach: vec![
+                Bucket {
+                    start: 1,
+                    end: 10,
+                    count: 300,
+                },
+                Bucket {
+                    start: 10,
+                    end: 100,
+                    count: 100,
+                },
+            ],
+            conversion_value_quantiles: vec![
+                quantile(0.0, 0),
+                quantile(0.5, 20),
+                quantile(0.9, 100),
+                quantile(1.0, 1000),
+            ],
+            ..Aggregates::default()
+        };
+        let config = fit(&aggregates, base()).unwrap();
+        let sample = Sample::new_deterministic(&config);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let n = 10_000;
+        let small_reach = (0..n)
+            .filter(|_| sample.reach_per_ad(&mut rng) < 10)
+            .count();
+        assert!((7300..7700).contains(&small_reach), "{small_reach}");
+
+        let mut values = (0..n)
+            .map(|_| sample.conversion_value_per_ad(&mut rng))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        assert!((18..=22).contains(&values[n / 2]), "{}", values[n / 2]);
+        assert!(
+            (90..=110).contains(&values[n * 9 / 10]),
+            "{}",
+            values[n * 9 / 10]
+        );
+
+        // not described by the aggregates
+        assert_eq!(base().cvr_per_ad.len(), config.cvr_per_ad.len());
+    }
+
+    #[test]
+    fn rejects_invalid_aggregates() {
+        let empty = Aggregates {
+            cvr: vec![Bucket {
+                start: 0.01,
+                end: 0.02,
+                count: 0,
+            }],
+            ..Aggregates::default()
+        };
+        assert!(matches!(
+            fit(&empty, base()),
+            Err(Error::NoObservations("cvr"))
+        ));
+
+        let unsorted = Aggregates {
+            conversion_value_quantiles: vec![
+                quantile(0.0, 0),
+                quantile(0.7, 10),
+                quantile(0.5, 20),
+                quantile(1.0, 30),
+            ],
+            ..Aggregates::default()
+        };
+        assert!(matches!(fit(&unsorted, base()), Err(Error::Quantiles(_))));
+    }
+}
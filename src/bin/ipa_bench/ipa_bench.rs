@@ -1,7 +1,9 @@
 mod cmd;
 mod config;
+mod fit;
 mod gen_events;
 mod sample;
+mod secret_share;
 
 use structopt::StructOpt;
 
@@ -1,28 +1,169 @@
 use rand::distributions::WeightedIndex;
-use rand::{CryptoRng, Rng, RngCore};
-use rand_distr::{num_traits::ToPrimitive, Distribution};
+use rand::seq::index;
+use rand::{Rng, RngCore};
+use rand_distr::{num_traits::ToPrimitive, Bernoulli, Distribution};
+use std::ops::Range;
 use std::time::Duration;
 
-use crate::config::Config;
+use crate::config::{Config, FrequencyCap};
+
+/// Scale of fixed-point probabilities and weights used by deterministic sampling.
+const FIXED_ONE: f64 = 4_294_967_296.0;
+
+/// Picks an index with probability proportional to its weight.
+enum Distr {
+    Float(WeightedIndex<f64>),
+    /// Cumulative weights, in fixed point. Drawing from it needs nothing but integer arithmetic.
+    Fixed(Vec<u64>),
+}
+
+impl Distr {
+    fn new<I: IntoIterator<Item = f64>>(weights: I, deterministic: bool) -> Self {
+        if !deterministic {
+            return Self::Float(WeightedIndex::new(weights).unwrap());
+        }
+
+        // weights are converted to fixed point once, with operations that are exact or
+        // correctly rounded, so every platform ends up with the same table
+        let weights = weights.into_iter().collect::<Vec<_>>();
+        let total: f64 = weights.iter().sum();
+        assert!(total > 0.0, "weights must not all be zero");
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut acc = 0;
+        for w in weights {
+            acc += (w / total * FIXED_ONE).round().to_u64().unwrap();
+            cdf.push(acc);
+        }
+        Self::Fixed(cdf)
+    }
+
+    fn sample<R: RngCore + ?Sized>(&self, rng: &mut R) -> usize {
+        match self {
+            Self::Float(distr) => distr.sample(rng),
+            Self::Fixed(cdf) => {
+                let x = below(rng, *cdf.last().unwrap());
+                cdf.partition_point(|&c| c <= x)
+            }
+        }
+    }
+}
+
+/// Uniformly distributed integer in `0..n`, computed the same way on every platform and by
+/// every version of `rand`.
+fn below<R: RngCore + ?Sized>(rng: &mut R, n: u64) -> u64 {
+    ((u128::from(rng.next_u64()) * u128::from(n)) >> 64)
+        .to_u64()
+        .unwrap()
+}
+
+/// Same as [`below`], for an integer in `r`.
+fn between<R: RngCore + ?Sized>(rng: &mut R, r: Range<i64>) -> i64 {
+    let span = u64::try_from(r.end - r.start).unwrap();
+    r.start + i64::try_from(below(rng, span)).unwrap()
+}
+
+/// Number of seconds in `hours`, rounded to the closest one.
+fn to_seconds(hours: f64) -> i64 {
+    (hours * 60.0 * 60.0).round().to_i64().unwrap()
+}
+
+/// Everything the event generator draws at random. [`Sample`], which draws from the
+/// distributions of the configuration, is the default; an implementation that models traffic
+/// of its own can be selected by the `distributions` field of the configuration once it is
+/// listed in [`DISTRIBUTIONS`].
+pub trait EventDistributions {
+    /// Uniformly distributed integer in `r`.
+    fn uniform(&self, rng: &mut dyn RngCore, r: Range<u64>) -> u64;
+
+    /// Indices `0..n` in random order.
+    fn shuffled(&self, rng: &mut dyn RngCore, n: usize) -> Vec<usize>;
+
+    fn reach_per_ad(&self, rng: &mut dyn RngCore) -> u32;
+
+    fn devices_per_user(&self, rng: &mut dyn RngCore) -> u8;
+
+    fn cvr_per_ad_account(&self, rng: &mut dyn RngCore) -> f64;
+
+    /// Whether a user converts, given the conversion rate of the ad they saw.
+    fn converts(&self, cvr: f64, rng: &mut dyn RngCore) -> bool;
+
+    fn impression_per_user(&self, rng: &mut dyn RngCore) -> u8;
+
+    fn conversion_per_user(&self, rng: &mut dyn RngCore) -> u8;
+
+    fn conversion_value_per_ad(&self, rng: &mut dyn RngCore) -> u32;
+
+    fn impressions_time_diff(&self, rng: &mut dyn RngCore) -> Duration;
+
+    fn conversions_time_diff(&self, rng: &mut dyn RngCore) -> Duration;
+
+    fn frequency_cap(&self) -> Option<FrequencyCap> {
+        None
+    }
+
+    /// Offset of the clock of a device from the real time, in seconds. `None` if clocks are not
+    /// skewed, and then nothing is drawn from `rng`.
+    fn device_clock_offset(&self, _rng: &mut dyn RngCore) -> Option<i64> {
+        None
+    }
+
+    /// Error of a single reported timestamp, in seconds. Zero if there is no jitter, and then
+    /// nothing is drawn from `rng`.
+    fn timestamp_jitter(&self, _rng: &mut dyn RngCore) -> i64 {
+        0
+    }
+}
+
+/// Creates the distributions of a configuration, sampling with integer arithmetic only if the
+/// second argument is set.
+pub type Factory = for<'a> fn(&'a Config, bool) -> Box<dyn EventDistributions + 'a>;
+
+/// Implementations of [`EventDistributions`] by the name a configuration selects them with.
+pub const DISTRIBUTIONS: &[(&str, Factory)] = &[("default", default_distributions)];
+
+fn default_distributions(config: &Config, deterministic: bool) -> Box<dyn EventDistributions + '_> {
+    if deterministic {
+        Box::new(Sample::new_deterministic(config))
+    } else {
+        Box::new(Sample::new(config))
+    }
+}
+
+/// Distributions selected by `config`, [`Sample`] if it does not select any. `None` if there is
+/// no implementation with the selected name.
+pub fn distributions(
+    config: &Config,
+    deterministic: bool,
+) -> Option<Box<dyn EventDistributions + '_>> {
+    let name = config.distributions.as_deref().unwrap_or("default");
+    DISTRIBUTIONS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, factory)| factory(config, deterministic))
+}
 
 pub struct Sample<'a> {
     config: &'a Config,
+    deterministic: bool,
 
     // Event Count
-    reach_per_ad_distr: WeightedIndex<f64>,
-    cvr_per_adaccount_distr: WeightedIndex<f64>,
-    ad_impression_per_user_distr: WeightedIndex<f64>,
-    ad_conversion_per_user_distr: WeightedIndex<f64>,
+    reach_per_ad_distr: Distr,
+    cvr_per_adaccount_distr: Distr,
+    ad_impression_per_user_distr: Distr,
+    ad_conversion_per_user_distr: Distr,
 
     // Match key
-    devices_per_user_distr: WeightedIndex<f64>,
+    devices_per_user_distr: Distr,
 
     // Time
-    conversions_duration_distr: WeightedIndex<f64>,
-    frequency_cap_distr: WeightedIndex<f64>,
+    conversions_duration_distr: Distr,
+    frequency_cap_distr: Distr,
 
     // Trigger value
-    trigger_value_distr: WeightedIndex<f64>,
+    trigger_value_distr: Distr,
+
+    // Clock skew
+    device_offset_distr: Option<Distr>,
 }
 
 impl<'a> Sample<'a> {
@@ -30,104 +171,255 @@ impl<'a> Sample<'a> {
     // # of events per day = impressions/day + conversions/day
     // impressions per day = devices * impression/device/day
     pub fn new(config: &'a Config) -> Self {
+        Self::with_sampling(config, false)
+    }
+
+    /// Samples the distributions with integer arithmetic only, so that a seed produces the same
+    /// events on every platform and with every version of `rand`. Events differ from the ones
+    /// produced by [`Sample::new`] for the same seed.
+    pub fn new_deterministic(config: &'a Config) -> Self {
+        Self::with_sampling(config, true)
+    }
+
+    fn with_sampling(config: &'a Config, deterministic: bool) -> Self {
+        if let Some(cap) = config.frequency_cap {
+            assert!(
+                cap.impressions > 0 && cap.days > 0,
+                "frequency cap must allow at least one impression in a non-empty window"
+            );
+        }
+
         Self {
             config,
+            deterministic,
 
-            reach_per_ad_distr: WeightedIndex::new(config.reach_per_ad.iter().map(|i| i.weight))
-                .unwrap(),
-            cvr_per_adaccount_distr: WeightedIndex::new(config.cvr_per_ad.iter().map(|i| i.weight))
-                .unwrap(),
-            ad_impression_per_user_distr: WeightedIndex::new(
+            reach_per_ad_distr: Distr::new(
+                config.reach_per_ad.iter().map(|i| i.weight),
+                deterministic,
+            ),
+            cvr_per_adaccount_distr: Distr::new(
+                config.cvr_per_ad.iter().map(|i| i.weight),
+                deterministic,
+            ),
+            ad_impression_per_user_distr: Distr::new(
                 config.impression_per_user.iter().map(|i| i.weight),
-            )
-            .unwrap(),
-            ad_conversion_per_user_distr: WeightedIndex::new(
+                deterministic,
+            ),
+            ad_conversion_per_user_distr: Distr::new(
                 config.conversion_per_user.iter().map(|i| i.weight),
-            )
-            .unwrap(),
+                deterministic,
+            ),
 
-            devices_per_user_distr: WeightedIndex::new(
+            devices_per_user_distr: Distr::new(
                 config.devices_per_user.iter().map(|i| i.weight),
-            )
-            .unwrap(),
+                deterministic,
+            ),
 
-            conversions_duration_distr: WeightedIndex::new(
+            conversions_duration_distr: Distr::new(
                 config
                     .impression_conversion_duration
                     .iter()
                     .map(|i| i.weight),
-            )
-            .unwrap(),
+                deterministic,
+            ),
 
-            frequency_cap_distr: WeightedIndex::new(
+            frequency_cap_distr: Distr::new(
                 config
                     .impression_impression_duration
                     .iter()
                     .map(|i| i.weight),
-            )
-            .unwrap(),
+                deterministic,
+            ),
 
             // TODO: Need data
-            trigger_value_distr: WeightedIndex::new(
+            trigger_value_distr: Distr::new(
                 config.conversion_value_per_user.iter().map(|i| i.weight),
-            )
-            .unwrap(),
+                deterministic,
+            ),
+
+            device_offset_distr: config
+                .clock_skew
+                .as_ref()
+                .map(|skew| Distr::new(skew.device_offset.iter().map(|i| i.weight), deterministic)),
         }
     }
+}
 
-    pub fn reach_per_ad<R: RngCore + CryptoRng>(&self, rng: &mut R) -> u32 {
+impl EventDistributions for Sample<'_> {
+    fn uniform(&self, rng: &mut dyn RngCore, r: Range<u64>) -> u64 {
+        if self.deterministic {
+            r.start + below(rng, r.end - r.start)
+        } else {
+            rng.gen_range(r)
+        }
+    }
+
+    fn shuffled(&self, rng: &mut dyn RngCore, n: usize) -> Vec<usize> {
+        if self.deterministic {
+            // Fisher-Yates
+            let mut indices = (0..n).collect::<Vec<_>>();
+            for i in (1..n).rev() {
+                let j = below(rng, i as u64 + 1).to_usize().unwrap();
+                indices.swap(i, j);
+            }
+            indices
+        } else {
+            index::sample(rng, n, n).into_vec()
+        }
+    }
+
+    fn reach_per_ad(&self, rng: &mut dyn RngCore) -> u32 {
         let r = self.config.reach_per_ad[self.reach_per_ad_distr.sample(rng)]
             .index
             .clone();
-        rng.gen_range(r)
+        if self.deterministic {
+            let r = u64::from(r.start)..u64::from(r.end);
+            self.uniform(rng, r).to_u32().unwrap()
+        } else {
+            rng.gen_range(r)
+        }
     }
 
-    pub fn devices_per_user<R: RngCore + CryptoRng>(&self, rng: &mut R) -> u8 {
+    fn devices_per_user(&self, rng: &mut dyn RngCore) -> u8 {
         self.config.devices_per_user[self.devices_per_user_distr.sample(rng)].index
     }
 
-    pub fn cvr_per_ad_account<R: RngCore + CryptoRng>(&self, rng: &mut R) -> f64 {
+    fn cvr_per_ad_account(&self, rng: &mut dyn RngCore) -> f64 {
         let r = self.config.cvr_per_ad[self.cvr_per_adaccount_distr.sample(rng)]
             .index
             .clone();
-        rng.gen_range(r)
+        if self.deterministic {
+            // fixed-point probability, which converts back to the same float everywhere
+            let start = (r.start * FIXED_ONE).round().to_u64().unwrap();
+            let end = (r.end * FIXED_ONE).round().to_u64().unwrap();
+            self.uniform(rng, start..end).to_f64().unwrap() / FIXED_ONE
+        } else {
+            rng.gen_range(r)
+        }
     }
 
-    pub fn impression_per_user<R: RngCore + CryptoRng>(&self, rng: &mut R) -> u8 {
+    fn converts(&self, cvr: f64, rng: &mut dyn RngCore) -> bool {
+        if self.deterministic {
+            let threshold = (cvr * FIXED_ONE).round().to_u64().unwrap();
+            below(rng, 1 << 32) < threshold
+        } else {
+            Bernoulli::new(cvr).unwrap().sample(rng)
+        }
+    }
+
+    fn impression_per_user(&self, rng: &mut dyn RngCore) -> u8 {
         self.config.impression_per_user[self.ad_impression_per_user_distr.sample(rng)].index
     }
 
-    pub fn conversion_per_user<R: RngCore + CryptoRng>(&self, rng: &mut R) -> u8 {
+    fn conversion_per_user(&self, rng: &mut dyn RngCore) -> u8 {
         self.config.conversion_per_user[self.ad_conversion_per_user_distr.sample(rng)].index
     }
 
-    pub fn conversion_value_per_ad<R: RngCore + CryptoRng>(&self, rng: &mut R) -> u32 {
+    fn conversion_value_per_ad(&self, rng: &mut dyn RngCore) -> u32 {
         let r = self.config.conversion_value_per_user[self.trigger_value_distr.sample(rng)]
             .index
             .clone();
-        rng.gen_range(r)
+        if self.deterministic {
+            let r = u64::from(r.start)..u64::from(r.end);
+            self.uniform(rng, r).to_u32().unwrap()
+        } else {
+            rng.gen_range(r)
+        }
     }
 
-    pub fn impressions_time_diff<R: RngCore + CryptoRng>(&self, rng: &mut R) -> Duration {
+    fn impressions_time_diff(&self, rng: &mut dyn RngCore) -> Duration {
         let r = self.config.impression_impression_duration[self.frequency_cap_distr.sample(rng)]
             .index
             .clone();
+        if self.deterministic {
+            let r = to_seconds(r.start)..to_seconds(r.end);
+            return Duration::new(between(rng, r).to_u64().unwrap(), 0);
+        }
         let diff = rng.gen_range(r);
         Duration::new((diff * 60.0 * 60.0).floor().to_u64().unwrap(), 0)
     }
 
-    pub fn conversions_time_diff<R: RngCore + CryptoRng>(&self, rng: &mut R) -> Duration {
+    fn frequency_cap(&self) -> Option<FrequencyCap> {
+        self.config.frequency_cap
+    }
+
+    fn device_clock_offset(&self, rng: &mut dyn RngCore) -> Option<i64> {
+        let (skew, distr) = self
+            .config
+            .clock_skew
+            .as_ref()
+            .zip(self.device_offset_distr.as_ref())?;
+        let r = skew.device_offset[distr.sample(rng)].index.clone();
+        if self.deterministic {
+            return Some(between(rng, to_seconds(r.start)..to_seconds(r.end)));
+        }
+        let hours = rng.gen_range(r);
+        Some((hours * 60.0 * 60.0).floor().to_i64().unwrap())
+    }
+
+    fn timestamp_jitter(&self, rng: &mut dyn RngCore) -> i64 {
+        match self.config.clock_skew {
+            Some(ref skew) if skew.jitter > 0 => {
+                let jitter = i64::from(skew.jitter);
+                if self.deterministic {
+                    between(rng, -jitter..jitter + 1)
+                } else {
+                    rng.gen_range(-jitter..=jitter)
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn conversions_time_diff(&self, rng: &mut dyn RngCore) -> Duration {
         let days = self.config.impression_conversion_duration
             [self.conversions_duration_distr.sample(rng)]
         .index
         .clone();
-        let diff = rng.gen_range(days);
+        let diff = if self.deterministic {
+            self.uniform(rng, u64::from(days.start)..u64::from(days.end))
+        } else {
+            u64::from(rng.gen_range(days))
+        };
 
         // Since [diff] is a range of days, randomly choose hours and seconds for the given range.
         // E.g. return [1..3) days + y hours + z seconds
-        Duration::new(diff.to_u64().unwrap() * 24 * 60 * 60, 0)
-            + Duration::new(rng.gen_range(0..23) * 60 * 60, 0)
-            + Duration::new(rng.gen_range(0..59) * 60, 0)
-            + Duration::new(rng.gen_range(0..59), 0)
+        Duration::new(diff * 24 * 60 * 60, 0)
+            + Duration::new(self.uniform(rng, 0..23) * 60 * 60, 0)
+            + Duration::new(self.uniform(rng, 0..59) * 60, 0)
+            + Duration::new(self.uniform(rng, 0..59), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distributions, Distr};
+    use crate::config::Config;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn fixed_point_weights() {
+        let distr = Distr::new([0.0, 0.25, 0.75], true);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = [0; 3];
+        for _ in 0..10_000 {
+            counts[distr.sample(&mut rng)] += 1;
+        }
+        assert_eq!(0, counts[0]);
+        assert!((2300..2700).contains(&counts[1]), "{counts:?}");
+        assert_eq!(10_000, counts[1] + counts[2]);
+    }
+
+    #[test]
+    fn select_distributions_by_name() {
+        let mut config: Config = serde_json::from_str(include_str!("config.example.json")).unwrap();
+        assert!(distributions(&config, false).is_some());
+
+        config.distributions = Some("default".into());
+        assert!(distributions(&config, true).is_some());
+
+        config.distributions = Some("nonexistent".into());
+        assert!(distributions(&config, false).is_none());
     }
 }
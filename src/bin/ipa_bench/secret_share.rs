@@ -0,0 +1,160 @@
+use super::gen_events::{Event, EventBase, RECORD_SEPARATOR};
+use log::{info, warn};
+use rand::{CryptoRng, RngCore};
+use raw_ipa::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+use std::io::{self, BufRead, Write};
+
+/// Reads cleartext events produced by `gen-events` and writes replicated secret shares of them
+/// to `outputs`, one per helper. Every output is a sequence of bincode-encoded [`SharedEvent`]s,
+/// which helpers read with [`raw_ipa::helpers::share_file`].
+///
+/// Returns the number of events written.
+///
+/// ## Errors
+/// If input cannot be read or parsed or outputs cannot be written.
+pub fn secret_share<R, I, W>(input: I, outputs: &mut [W; 3], rng: &mut R) -> io::Result<u32>
+where
+    R: RngCore + CryptoRng,
+    I: BufRead,
+    W: Write,
+{
+    let mut count = 0;
+
+    for record in input.split(RECORD_SEPARATOR) {
+        let record = record?;
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let event: Event = serde_json::from_slice(&record)?;
+
+        let shares = match event {
+            Event::Source(e) => {
+                let breakdown_key = e.breakdown_key.parse::<u32>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("breakdown key {} is not a number", e.breakdown_key),
+                    )
+                })?;
+                share_event(&e.event, rng, |rng| {
+                    ReplicatedShare::share(breakdown_key, rng)
+                        .map(|breakdown_key| SharedEventKind::Source { breakdown_key })
+                })
+            }
+            Event::Trigger(e) => share_event(&e.event, rng, |rng| {
+                ReplicatedShare::share(e.value, rng).map(|value| SharedEventKind::Trigger { value })
+            }),
+            Event::EncryptedSource(_) | Event::EncryptedTrigger(_) => {
+                warn!("Skipping event that is already secret shared");
+                continue;
+            }
+        };
+
+        for (output, share) in outputs.iter_mut().zip(shares) {
+            bincode::serialize_into(output, &share)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+
+        count += 1;
+        if count % 10000 == 0 {
+            info!("{}", count);
+        }
+    }
+
+    Ok(count)
+}
+
+fn share_event<R, F>(event: &EventBase, rng: &mut R, kind: F) -> [SharedEvent; 3]
+where
+    R: RngCore + CryptoRng,
+    F: FnOnce(&mut R) -> [SharedEventKind; 3],
+{
+    let mut matchkeys = [Vec::new(), Vec::new(), Vec::new()];
+    for mk in &event.matchkeys {
+        for (helper, share) in matchkeys.iter_mut().zip(ReplicatedShare::share(*mk, rng)) {
+            helper.push(share);
+        }
+    }
+    let timestamps = ReplicatedShare::share(event.timestamp, rng);
+    let kinds = kind(rng);
+
+    let mut i = 0;
+    matchkeys.map(|matchkeys| {
+        let e = SharedEvent {
+            matchkeys,
+            epoch: event.epoch,
+            timestamp: timestamps[i],
+            kind: kinds[i],
+        };
+        i += 1;
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::secret_share;
+    use crate::gen_events::{Event, EventBase, SourceEvent, TriggerEvent, RECORD_SEPARATOR};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use raw_ipa::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+    use std::io::Cursor;
+
+    #[test]
+    fn shares_reconstruct_to_input() {
+        let base = EventBase {
+            matchkeys: vec![1, 2],
+            epoch: 3,
+            timestamp: 4,
+        };
+        let mut input = Vec::new();
+        for e in [
+            Event::Source(SourceEvent {
+                event: base.clone(),
+                breakdown_key: "5".into(),
+            }),
+            Event::Trigger(TriggerEvent {
+                event: base,
+                value: 6,
+                zkp: "zkp".into(),
+            }),
+        ] {
+            input.push(RECORD_SEPARATOR);
+            input.extend(serde_json::to_vec(&e).unwrap());
+        }
+
+        let mut outputs = [Vec::new(), Vec::new(), Vec::new()];
+        let count = secret_share(
+            Cursor::new(input),
+            &mut outputs,
+            &mut StdRng::seed_from_u64(0),
+        )
+        .unwrap();
+        assert_eq!(2, count);
+
+        let mut readers = outputs.map(Cursor::new);
+        let mut next = || -> [SharedEvent; 3] {
+            let mut events = readers
+                .iter_mut()
+                .map(|r| bincode::deserialize_from(r).unwrap());
+            [(); 3].map(|()| events.next().unwrap())
+        };
+
+        let source = next();
+        assert!(source.iter().all(|e| e.epoch == 3));
+        let mk = [0, 1, 2].map(|i| source[i].matchkeys[1]);
+        assert_eq!(2, ReplicatedShare::reconstruct(&mk).unwrap());
+        let ts = [0, 1, 2].map(|i| source[i].timestamp);
+        assert_eq!(4, ReplicatedShare::reconstruct(&ts).unwrap());
+        let bk = source.map(|e| match e.kind {
+            SharedEventKind::Source { breakdown_key } => breakdown_key,
+            SharedEventKind::Trigger { .. } => panic!("expected source event"),
+        });
+        assert_eq!(5, ReplicatedShare::reconstruct(&bk).unwrap());
+
+        let value = next().map(|e| match e.kind {
+            SharedEventKind::Trigger { value } => value,
+            SharedEventKind::Source { .. } => panic!("expected trigger event"),
+        });
+        assert_eq!(6, ReplicatedShare::reconstruct(&value).unwrap());
+    }
+}
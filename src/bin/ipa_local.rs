@@ -0,0 +1,930 @@
+use rand::Rng;
+use raw_ipa::arena::Arena;
+use raw_ipa::attribution::{self, Hooks, Model, NoHooks};
+use raw_ipa::cli::Verbosity;
+use raw_ipa::commitment::InputCommitment;
+use raw_ipa::entropy::Entropy;
+use raw_ipa::error::Res;
+use raw_ipa::estimate::{measure_latency, DeadlinePolicy, Network};
+use raw_ipa::field::{Field, Fp31, Fp32BitPrime};
+use raw_ipa::helpers::models::Aggregate;
+use raw_ipa::helpers::ring::{Identity, Ring};
+use raw_ipa::helpers::tcp::{TcpRing, TcpRingConfig};
+use raw_ipa::parallelism::{Parallelism, ParallelismConfig};
+use raw_ipa::prss::{Participant, ParticipantSetup};
+use raw_ipa::query::{FieldType, IpaQueryConfig, RunCost, Sampling, SecurityMode, Stage};
+use raw_ipa::replicated_secret_sharing::ReplicatedSecretSharing;
+use raw_ipa::reveal::reveal_vec;
+use raw_ipa::roles::check_roles;
+use raw_ipa::securemul::{ProtocolContext, SecureMul};
+use raw_ipa::telemetry::rounds::{self, RoundCounter};
+use raw_ipa::transcript::{check_transcript, TranscriptRing};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Instant;
+use structopt::StructOpt;
+use tokio::net::TcpListener;
+use tracing::info;
+
+type Share<F> = ReplicatedSecretSharing<F>;
+
+/// Messages every helper sends around the ring to measure the latency between them.
+const LATENCY_ROUNDS: u32 = 10;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "ipa_local",
+    about = "Runs three MPC helpers inside one process and computes breakdown histograms of attributed trigger values"
+)]
+struct Args {
+    #[structopt(flatten)]
+    logging: Verbosity,
+
+    /// Number of records to generate
+    #[structopt(short = "n", long, default_value = "20")]
+    records: usize,
+
+    /// Number of breakdown keys
+    #[structopt(short, long, default_value = "4")]
+    buckets: u32,
+
+    /// Parameters of the query, as JSON. The number of breakdown keys is taken from there
+    /// instead, if set
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Number of consecutive breakdown keys that are counted in the same bucket of the
+    /// histogram. Records keep their keys, they are mapped to buckets by the helpers
+    #[structopt(long, default_value = "1")]
+    bucket_width: usize,
+
+    /// Aggregates computed for every bucket: `sum` of attributed values, `count` of attributed
+    /// records, or both. The average value is the sum divided by the count
+    #[structopt(long, default_value = "sum")]
+    aggregate: Vec<Aggregate>,
+
+    /// How conversions credit the source events they are attributed to: `last-touch`,
+    /// `equal-credit` or `time-decay:<half life in seconds>`. Overrides the model of the query
+    /// config, if set
+    #[structopt(long)]
+    attribution: Option<Model>,
+
+    /// Field shares and histograms are in: `fp31` or `fp32-bit-prime`. Overrides the field of the
+    /// query config, if set
+    #[structopt(long)]
+    field: Option<FieldType>,
+
+    /// Number of source events every conversion is attributed to at most
+    #[structopt(long, default_value = "1")]
+    touches: usize,
+
+    /// Random generator seed. Setting the seed allows reproduction of the input exactly
+    #[structopt(short, long)]
+    random_seed: Option<u64>,
+
+    /// Maximum number of bytes every helper may buffer for the query
+    #[structopt(long)]
+    memory_limit: Option<usize>,
+
+    /// Number of records generated, shared and processed at a time. Only one chunk of shared
+    /// input is held in memory, so this bounds the memory used by the run
+    #[structopt(long, default_value = "1024")]
+    chunk_size: usize,
+
+    /// Number of records every step of the protocol exchanges with peers at a time, all of
+    /// them if not set
+    #[structopt(long)]
+    max_records_per_step: Option<usize>,
+
+    /// Most records a helper may get ahead of the slowest of its peers before it waits for them
+    /// to catch up, any number if not set
+    #[structopt(long)]
+    max_lead: Option<u64>,
+
+    /// Number of protocol steps every helper runs at the same time, any number if not set
+    #[structopt(long)]
+    max_concurrent_steps: Option<usize>,
+
+    /// Dry run on a uniform sample of the records, every one of which is in it with this
+    /// probability. Prints how long the run on all records would take and how much helpers would
+    /// send to each other
+    #[structopt(long)]
+    sample: Option<f64>,
+
+    /// Sets the deadlines of stages the query config leaves open to their estimate times this,
+    /// on the latency measured between the helpers once they are connected
+    #[structopt(long)]
+    deadline_slack: Option<f64>,
+
+    /// Writes the steps of the first helper as a tree, with their rounds, multiplications and
+    /// bytes sent, to this file: in the DOT language of Graphviz if it ends with `.dot`, as
+    /// JSON otherwise
+    #[structopt(long)]
+    step_tree: Option<PathBuf>,
+}
+
+/// Source event a conversion is attributed to.
+#[derive(Debug)]
+struct Touch {
+    breakdown_key: usize,
+    /// Time between the source event and the conversion, which is public.
+    elapsed_seconds: u32,
+}
+
+/// Cleartext input record: a conversion and the source events it is attributed to. The value
+/// counts towards the buckets of their breakdown keys, split between them by the attribution
+/// model, only if the record is attributed.
+#[derive(Debug)]
+struct Record {
+    touches: Vec<Touch>,
+    attributed: bool,
+    /// Fixed-point value, see [`raw_ipa::query::ValueScale`].
+    value: u32,
+}
+
+/// Secret shares of a record, one per helper. Breakdown keys are shared as one-hot vectors, so
+/// helpers cannot tell which bucket a record contributes to.
+struct SharedRecord<F: Field> {
+    breakdown_keys: Vec<Vec<[Share<F>; 3]>>,
+    elapsed_seconds: Vec<u32>,
+    attributed: [Share<F>; 3],
+    value: [Share<F>; 3],
+}
+
+impl<F: Field> SharedRecord<F> {
+    fn new<R: Rng>(record: &Record, buckets: usize, rng: &mut R) -> Self {
+        let bit = |b: bool| F::from(u128::from(b));
+        Self {
+            breakdown_keys: record
+                .touches
+                .iter()
+                .map(|t| {
+                    (0..buckets)
+                        .map(|i| Share::share(bit(i == t.breakdown_key), rng))
+                        .collect()
+                })
+                .collect(),
+            elapsed_seconds: record.touches.iter().map(|t| t.elapsed_seconds).collect(),
+            attributed: Share::share(bit(record.attributed), rng),
+            value: Share::share(F::from(u128::from(record.value)), rng),
+        }
+    }
+}
+
+/// Each helper multiplies its shares of `a` and `b`. All three run concurrently and this
+/// function returns once every one of them has its share of the product.
+async fn multiply<F: Field, R: Ring>(
+    ctx: &[ProtocolContext<'_, R>; 3],
+    index: u128,
+    a: [Share<F>; 3],
+    b: [Share<F>; 3],
+) -> Res<[Share<F>; 3]> {
+    let (r0, r1, r2) = futures::try_join!(
+        SecureMul::new(index, a[0], b[0]).execute(&ctx[0]),
+        SecureMul::new(index, a[1], b[1]).execute(&ctx[1]),
+        SecureMul::new(index, a[2], b[2]).execute(&ctx[2]),
+    )?;
+    Ok([r0, r1, r2])
+}
+
+/// Adds the contribution of every record in `chunk` to the buckets of the breakdown keys of its
+/// touches, in the histogram of every one of `aggregates`: `attributed * value` to the sum and
+/// `attributed` to the count, weighed by the credit `model` and `hooks` give every touch. Every
+/// bucket counts `width` consecutive breakdown keys. `index` is the index of the last
+/// multiplication done so far, it is advanced so it remains unique across chunks.
+#[allow(clippy::too_many_arguments)]
+async fn add_to_histograms<F: Field, R: Ring>(
+    ctx: &[ProtocolContext<'_, R>; 3],
+    chunk: &[SharedRecord<F>],
+    model: Model,
+    hooks: &dyn Hooks,
+    aggregates: &[Aggregate],
+    histograms: &mut [Vec<[Share<F>; 3]>],
+    width: usize,
+    index: &mut u128,
+) -> Res<()> {
+    let mut next_index = || {
+        *index += 1;
+        *index
+    };
+
+    // credits are public, every helper computes the same ones
+    let credits = chunk
+        .iter()
+        .map(|r| model.credits_with(hooks, &r.elapsed_seconds))
+        .collect::<Vec<_>>();
+    let mut contributions = Vec::with_capacity(aggregates.len());
+    for aggregate in aggregates {
+        let mut shares = [(); 3].map(|()| Vec::with_capacity(chunk.len()));
+        for (record, credits) in chunk.iter().zip(&credits) {
+            let contribution = match aggregate {
+                Aggregate::Sum => {
+                    multiply(ctx, next_index(), record.attributed, record.value).await?
+                }
+                // the bit already counts the record
+                Aggregate::Count => record.attributed,
+            };
+            // every touch gets its part of the contribution
+            for (helper, share) in shares.iter_mut().zip(contribution) {
+                helper.extend(attribution::weigh(&vec![share; credits.len()], credits));
+            }
+        }
+        contributions.push(shares);
+    }
+
+    // bucket gets the sum of contributions multiplied by the bit that tells whether the
+    // breakdown key is in its range, which takes one round per bucket no matter how many
+    // touches there are. Keys are one-hot, so that bit is just the sum of the bits of the keys
+    // in the range and helpers compute it without talking to each other.
+    for bucket in 0..histograms.first().map_or(0, Vec::len) {
+        let keys = [0, 1, 2].map(|i| {
+            chunk
+                .iter()
+                .flat_map(|r| &r.breakdown_keys)
+                .map(|key| {
+                    key.iter()
+                        .skip(bucket * width)
+                        .take(width)
+                        .fold(Share::new(F::ZERO, F::ZERO), |acc, bit| acc + bit[i])
+                })
+                .collect::<Vec<_>>()
+        });
+        for (histogram, contributions) in histograms.iter_mut().zip(&contributions) {
+            let index = next_index();
+            let (s0, s1, s2) = futures::try_join!(
+                ctx[0].sum_of_products(index, &keys[0], &contributions[0]),
+                ctx[1].sum_of_products(index, &keys[1], &contributions[1]),
+                ctx[2].sum_of_products(index, &keys[2], &contributions[2]),
+            )?;
+            for (acc, share) in histogram[bucket].iter_mut().zip([s0, s1, s2]) {
+                *acc = *acc + share;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn make_participants<R: Rng + rand::CryptoRng>(rng: &mut R) -> [Participant; 3] {
+    let setup = [(); 3].map(|()| ParticipantSetup::new(rng));
+    let pk = [0, 1, 2].map(|i| setup[i].public_keys());
+    let mut i = 0;
+    setup.map(|s| {
+        let (left, right) = ((i + 2) % 3, (i + 1) % 3);
+        i += 1;
+        s.setup(&pk[left].1, &pk[right].0)
+    })
+}
+
+async fn make_ring(memory_limit: Option<usize>) -> Res<[TcpRing; 3]> {
+    let mut listeners = Vec::with_capacity(3);
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?);
+    }
+    let addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<Result<Vec<_>, _>>()?;
+    let [l0, l1, l2]: [TcpListener; 3] = listeners.try_into().unwrap();
+
+    // every helper is on this host, so peers are not authenticated
+    let config = |listen: usize, left: usize, right: usize| TcpRingConfig {
+        allow_unauthenticated: true,
+        memory_limit,
+        ..TcpRingConfig::new(addrs[listen], addrs[left], addrs[right])
+    };
+    let configs = [config(0, 2, 1), config(1, 0, 2), config(2, 1, 0)];
+
+    let (h0, h1, h2) = futures::try_join!(
+        TcpRing::connect(l0, &configs[0]),
+        TcpRing::connect(l1, &configs[1]),
+        TcpRing::connect(l2, &configs[2]),
+    )?;
+    Ok([h0, h1, h2])
+}
+
+/// Result of the local run. Histograms of all aggregates are one after another, in the order
+/// they were requested.
+struct Outcome {
+    /// Field the query ran in, which histograms are reduced modulo.
+    field: FieldType,
+    /// Histograms revealed by the helpers.
+    actual: Vec<u128>,
+    /// Histograms computed in the clear.
+    expected: Vec<u128>,
+    /// Rounds and multiplications done by the first helper. Others do the same amount of work.
+    rounds: rounds::Report,
+    /// What the run on all records would take, if this was a dry run on a sample of them.
+    estimate: Option<RunCost>,
+    /// Units of credit every conversion gives out, see [`Model::scale`].
+    credit_scale: u32,
+}
+
+/// Parameters of the query from the config file, or from the arguments if there is none.
+fn query_config(args: &Args) -> Result<IpaQueryConfig, Box<dyn Error>> {
+    let mut config: IpaQueryConfig = match &args.config {
+        Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        None => IpaQueryConfig {
+            max_breakdown_key: args.buckets,
+            ..IpaQueryConfig::default()
+        },
+    };
+    if let Some(model) = args.attribution {
+        config.attribution = model;
+    }
+    if let Some(field) = args.field {
+        config.field = field;
+    }
+    if let Some(rate) = args.sample {
+        config.sampling = Some(Sampling {
+            rate,
+            seed: args.random_seed.unwrap_or_default(),
+        });
+    }
+    config.validate()?;
+    if config.security != SecurityMode::SemiHonest {
+        return Err("only semi-honest queries can be run locally".into());
+    }
+    Ok(config)
+}
+
+/// Generates the input and runs the query on three helpers, in the field the query config
+/// asks for.
+async fn run(args: &Args) -> Result<Outcome, Box<dyn Error>> {
+    run_with_hooks(args, &NoHooks).await
+}
+
+/// Same as [`run`], with the attribution stage changed by `hooks`. Expected histograms are
+/// computed with the same hooks.
+async fn run_with_hooks(args: &Args, hooks: &dyn Hooks) -> Result<Outcome, Box<dyn Error>> {
+    let config = query_config(args)?;
+    match config.field {
+        FieldType::Fp31 => run_field::<Fp31>(args, config, hooks).await,
+        FieldType::Fp32BitPrime => run_field::<Fp32BitPrime>(args, config, hooks).await,
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run_field<F: Field>(
+    args: &Args,
+    mut config: IpaQueryConfig,
+    hooks: &dyn Hooks,
+) -> Result<Outcome, Box<dyn Error>> {
+    let entropy = args.random_seed.map_or_else(Entropy::os, Entropy::seeded);
+    let mut rng = entropy.rng();
+
+    if args.chunk_size == 0 {
+        return Err("chunk size must be positive".into());
+    }
+    if args.bucket_width == 0 {
+        return Err("bucket width must be positive".into());
+    }
+    if args.touches == 0 {
+        return Err("conversions must be attributed to at least one source event".into());
+    }
+    let keys = config.max_breakdown_key as usize;
+    let buckets = (keys + args.bucket_width - 1) / args.bucket_width;
+    let aggregates = &args.aggregate;
+    if aggregates
+        .iter()
+        .enumerate()
+        .any(|(i, a)| aggregates[..i].contains(a))
+    {
+        return Err("every aggregate can be requested once".into());
+    }
+
+    // PRSS state is large, keep it off the stack of the future
+    let participants = Box::new(make_participants(&mut rng));
+    let helpers = make_ring(args.memory_limit)
+        .await?
+        .map(|h| h.with_max_lead(args.max_lead));
+    info!("helpers are connected");
+    if let Some(slack) = args.deadline_slack {
+        if !(slack > 0.0 && slack.is_finite()) {
+            return Err("deadline slack must be positive".into());
+        }
+        let (l0, l1, l2) = futures::try_join!(
+            measure_latency(&helpers[0], LATENCY_ROUNDS),
+            measure_latency(&helpers[1], LATENCY_ROUNDS),
+            measure_latency(&helpers[2], LATENCY_ROUNDS),
+        )?;
+        let network = Network {
+            latency: l0.max(l1).max(l2),
+            ..Network::default()
+        };
+        let policy = DeadlinePolicy {
+            slack,
+            ..DeadlinePolicy::default()
+        };
+        config.deadlines = policy.deadlines(&config, args.records as u64, &network);
+        info!(
+            "latency between helpers is {:?}, deadlines are {:?}",
+            network.latency, config.deadlines
+        );
+    }
+    let counters = [(); 3].map(|()| RoundCounter::default());
+    let parallelism = [(); 3].map(|()| {
+        Parallelism::new(ParallelismConfig {
+            max_records_per_step: args.max_records_per_step,
+            max_concurrent_steps: args.max_concurrent_steps,
+            task_budget: None,
+        })
+    });
+    let arenas = [(); 3].map(|()| Arena::new());
+    let rings = [0, 1, 2].map(|i| TranscriptRing::new(&helpers[i]));
+    let ctx = [0, 1, 2].map(|i| {
+        ProtocolContext::new(Identity::ALL[i], &participants[i], &rings[i])
+            .with_rounds(&counters[i])
+            .with_entropy(&entropy)
+            .with_parallelism(&parallelism[i])
+            .with_transcript(rings[i].transcript())
+            .with_arena(&arenas[i])
+    });
+
+    let zero = Share::new(F::ZERO, F::ZERO);
+    let mut shares = vec![vec![[zero; 3]; buckets]; aggregates.len()];
+    let mut expected = vec![vec![F::ZERO; buckets]; aggregates.len()];
+    let mut index = 0;
+    let mut remaining = args.records;
+    // records that were generated and of those, the ones in the sample
+    let (mut generated, mut sampled) = (0_u64, 0_u64);
+    let started = Instant::now();
+    let progress = helpers[0].progress();
+    progress.set_expected_records(args.records as u64);
+    // every helper enforces the deadline on its own, the first one is enough here: aborting
+    // the query fails whatever the others wait for
+    let stage = async {
+        // helpers that mixed up their identities would attribute with each other's randomness
+        let name = Stage::Aggregation.name();
+        futures::try_join!(
+            check_roles(&ctx[0], name),
+            check_roles(&ctx[1], name),
+            check_roles(&ctx[2], name),
+        )?;
+        while remaining > 0 {
+            let chunk_size = remaining.min(args.chunk_size);
+            remaining -= chunk_size;
+
+            // all records are generated, so the input does not depend on the sample
+            let first = generated;
+            generated += chunk_size as u64;
+            // helpers that are ahead wait for the others before they start on the chunk
+            futures::try_join!(
+                helpers[0].pace(Stage::Aggregation, generated),
+                helpers[1].pace(Stage::Aggregation, generated),
+                helpers[2].pace(Stage::Aggregation, generated),
+            )?;
+            let records = (first..generated)
+                .map(|i| {
+                    let touches = (0..rng.gen_range(1..=args.touches))
+                        .map(|_| Touch {
+                            breakdown_key: rng.gen_range(0..keys),
+                            elapsed_seconds: rng.gen_range(0..config.attribution_window_seconds),
+                        })
+                        .collect();
+                    let record = Record {
+                        touches,
+                        attributed: rng.gen_bool(0.5),
+                        // devices cap values before they share them
+                        value: config
+                            .value_scale
+                            .cap(hooks.before_capping(rng.gen_range(1..=5))),
+                    };
+                    (i, record)
+                })
+                .filter(|(i, _)| config.sampling.map_or(true, |s| s.includes(*i)))
+                .map(|(_, record)| record)
+                .collect::<Vec<_>>();
+            sampled += records.len() as u64;
+            if records.is_empty() {
+                progress.records_processed(chunk_size as u64);
+                continue;
+            }
+            for r in records.iter().filter(|r| r.attributed) {
+                let elapsed = r
+                    .touches
+                    .iter()
+                    .map(|t| t.elapsed_seconds)
+                    .collect::<Vec<_>>();
+                let credits = config.attribution.credits_with(hooks, &elapsed);
+                for (touch, credit) in r.touches.iter().zip(credits) {
+                    for (aggregate, histogram) in aggregates.iter().zip(&mut expected) {
+                        let contribution = match aggregate {
+                            Aggregate::Sum => u128::from(r.value),
+                            Aggregate::Count => 1,
+                        };
+                        histogram[touch.breakdown_key / args.bucket_width] +=
+                            F::from(contribution * u128::from(credit));
+                    }
+                }
+            }
+            let chunk = records
+                .iter()
+                .map(|r| SharedRecord::new(r, keys, &mut rng))
+                .collect::<Vec<_>>();
+
+            // helpers make sure they got the same input before they work on it
+            let [c0, c1, c2] = [0, 1, 2].map(|i| {
+                let mut commitment = InputCommitment::default();
+                for r in &chunk {
+                    for key in &r.breakdown_keys {
+                        commitment.add(&key.iter().map(|s| s[i]).collect::<Vec<_>>());
+                    }
+                    commitment.add(&[r.attributed[i], r.value[i]]);
+                }
+                commitment
+            });
+            futures::try_join!(c0.check(&ctx[0]), c1.check(&ctx[1]), c2.check(&ctx[2]))?;
+
+            add_to_histograms(
+                &ctx,
+                &chunk,
+                config.attribution,
+                hooks,
+                aggregates,
+                &mut shares,
+                args.bucket_width,
+                &mut index,
+            )
+            .await?;
+            progress.records_processed(chunk_size as u64);
+        }
+        Ok::<_, raw_ipa::error::Error>(())
+    };
+    config
+        .deadlines
+        .run(Stage::Aggregation, &helpers[0], Some(&progress), stage)
+        .await?;
+    // scratch buffers of the stage are not needed any more
+    for arena in &arenas {
+        let stats = arena.stats();
+        info!(
+            "{} scratch buffers allocated, {} reused",
+            stats.allocated, stats.reused
+        );
+        arena.reset();
+    }
+
+    // every helper opens all histograms in one round
+    let [s0, s1, s2] = [0, 1, 2].map(|i| shares.iter().flatten().map(|s| s[i]).collect::<Vec<_>>());
+    let (actual, r1, r2) = futures::try_join!(
+        reveal_vec(&ctx[0], index + 1, &s0),
+        reveal_vec(&ctx[1], index + 1, &s1),
+        reveal_vec(&ctx[2], index + 1, &s2),
+    )?;
+    if actual != r1 || actual != r2 {
+        return Err("helpers revealed different histograms".into());
+    }
+    futures::try_join!(
+        check_transcript(&ctx[0], rings[0].transcript()),
+        check_transcript(&ctx[1], rings[1].transcript()),
+        check_transcript(&ctx[2], rings[2].transcript()),
+    )?;
+    for (identity, ring) in Identity::ALL.iter().zip(&rings) {
+        info!(
+            "transcript of {identity} is {:x}",
+            ring.transcript().digest()
+        );
+    }
+
+    let estimate = config.sampling.map(|sampling| {
+        let bytes = helpers
+            .iter()
+            .map(|h| {
+                let snapshot = h.progress().snapshot("dry run");
+                snapshot.left.bytes_sent + snapshot.right.bytes_sent
+            })
+            .sum();
+        sampling.extrapolate(&RunCost {
+            records: sampled,
+            elapsed: started.elapsed(),
+            bytes,
+        })
+    });
+
+    let to_u128 = |v: F| -> u128 { Into::<F::Integer>::into(v).into() };
+    let mut actual = actual.into_iter().map(to_u128).collect::<Vec<_>>();
+    let mut expected = expected
+        .concat()
+        .into_iter()
+        .map(to_u128)
+        .collect::<Vec<_>>();
+    for (&aggregate, (actual, expected)) in aggregates
+        .iter()
+        .zip(actual.chunks_mut(buckets).zip(expected.chunks_mut(buckets)))
+    {
+        hooks.after_aggregation(aggregate, actual);
+        hooks.after_aggregation(aggregate, expected);
+    }
+    Ok(Outcome {
+        field: config.field,
+        actual,
+        expected,
+        rounds: counters[0].report(),
+        estimate,
+        credit_scale: config.attribution.scale(),
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args();
+    args.logging.setup_logging();
+
+    let Outcome {
+        field,
+        actual,
+        expected,
+        rounds,
+        estimate,
+        credit_scale,
+    } = run(&args).await?;
+
+    let buckets = actual.len() / args.aggregate.len();
+    for (aggregate, (actual, expected)) in args
+        .aggregate
+        .iter()
+        .zip(actual.chunks(buckets).zip(expected.chunks(buckets)))
+    {
+        let name = match aggregate {
+            Aggregate::Sum => "Sum of attributed values",
+            Aggregate::Count => "Number of attributed records",
+        };
+        if credit_scale == 1 {
+            println!("{name} (mod {}):", field.prime());
+        } else {
+            println!(
+                "{name}, in 1/{credit_scale} of a conversion (mod {}):",
+                field.prime()
+            );
+        }
+        println!("{:>6} {:>6} {:>8}", "bucket", "mpc", "expected");
+        for (bucket, (a, e)) in actual.iter().zip(expected).enumerate() {
+            let mark = if a == e { "" } else { " <- mismatch" };
+            println!("{bucket:>6} {a:>6} {e:>8}{mark}");
+        }
+        println!();
+    }
+
+    print!("{rounds}");
+    if let Some(path) = &args.step_tree {
+        let tree = rounds.tree(field.size());
+        let out = if path.extension().map_or(false, |e| e == "dot") {
+            tree.to_dot()
+        } else {
+            serde_json::to_string_pretty(&tree)?
+        };
+        std::fs::write(path, out)?;
+    }
+    if let Some(RunCost { elapsed, bytes, .. }) = estimate {
+        println!(
+            "Dry run on a sample of the {} records. Running on all of them would take about \
+             {elapsed:.1?}, with {bytes} bytes sent between helpers",
+            args.records
+        );
+    }
+
+    if actual == expected {
+        Ok(())
+    } else {
+        Err("MPC result does not match the expected histogram".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, run_with_hooks, Args};
+    use raw_ipa::attribution::Hooks;
+    use raw_ipa::helpers::models::Aggregate;
+    use raw_ipa::query::FieldType;
+    use structopt::StructOpt;
+
+    #[tokio::test]
+    async fn matches_expected() {
+        let args = Args::from_iter(["ipa_local", "-n", "5", "-b", "3", "-r", "1"]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(3, outcome.actual.len());
+        assert_eq!(outcome.expected, outcome.actual);
+
+        // one multiplication for the contribution of every record, sequential
+        let stats = outcome.rounds.step("securemul");
+        assert_eq!(5, stats.multiplications);
+        assert_eq!(5, stats.rounds);
+        // one product per record and bucket, but a single round per bucket
+        let stats = outcome.rounds.step("sum_of_products");
+        assert_eq!(15, stats.multiplications);
+        assert_eq!(3, stats.rounds);
+        assert_eq!(1, outcome.rounds.step("reveal").rounds);
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let args = Args::from_iter([
+            "ipa_local",
+            "-n",
+            "5",
+            "-b",
+            "3",
+            "--chunk-size",
+            "2",
+            "--max-lead",
+            "2",
+        ]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(outcome.expected, outcome.actual);
+        // every chunk needs a round per bucket
+        let stats = outcome.rounds.step("sum_of_products");
+        assert_eq!(15, stats.multiplications);
+        assert_eq!(9, stats.rounds);
+
+        let args = Args::from_iter(["ipa_local", "--chunk-size", "0"]);
+        assert!(run(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bucket_width() {
+        // keys 0-2, 3-5 and 6
+        let args = Args::from_iter(["ipa_local", "-n", "20", "-b", "7", "--bucket-width", "3"]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(3, outcome.actual.len());
+        assert_eq!(outcome.expected, outcome.actual);
+        // one round per bucket of the histogram, not per breakdown key
+        assert_eq!(3, outcome.rounds.step("sum_of_products").rounds);
+
+        let args = Args::from_iter(["ipa_local", "--bucket-width", "0"]);
+        assert!(run(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deadline_slack() {
+        let args = Args::from_iter(["ipa_local", "-n", "5", "--deadline-slack", "2"]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(outcome.expected, outcome.actual);
+
+        let args = Args::from_iter(["ipa_local", "--deadline-slack", "0"]);
+        assert!(run(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let args = Args::from_iter([
+            "ipa_local",
+            "-n",
+            "200",
+            "-b",
+            "3",
+            "-r",
+            "1",
+            "--sample",
+            "0.1",
+        ]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(outcome.expected, outcome.actual);
+        let estimate = outcome.estimate.unwrap();
+        // extrapolated from about 20 records
+        assert!((100..300).contains(&estimate.records), "{estimate:?}");
+        assert!(estimate.bytes > 0);
+
+        let args = Args::from_iter(["ipa_local", "--sample", "0"]);
+        assert!(run(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_query() {
+        // breakdown keys must fit into the field
+        for keys in ["0", "32"] {
+            let args = Args::from_iter(["ipa_local", "-b", keys]);
+            assert!(run(&args).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn wide_field() {
+        // more breakdown keys and bigger sums than fit into the default field
+        let args = Args::from_iter([
+            "ipa_local",
+            "-n",
+            "40",
+            "-b",
+            "40",
+            "-r",
+            "1",
+            "--field",
+            "fp32-bit-prime",
+        ]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(FieldType::Fp32BitPrime, outcome.field);
+        assert_eq!(outcome.expected, outcome.actual);
+    }
+
+    #[tokio::test]
+    async fn aggregates() {
+        let args = Args::from_iter([
+            "ipa_local",
+            "-n",
+            "20",
+            "-b",
+            "3",
+            "--aggregate",
+            "count",
+            "sum",
+        ]);
+        let outcome = run(&args).await.unwrap();
+        assert_eq!(6, outcome.actual.len());
+        assert_eq!(outcome.expected, outcome.actual);
+        // counting takes no multiplication per record, only summing does
+        assert_eq!(20, outcome.rounds.step("securemul").multiplications);
+        // a round per bucket of every histogram
+        assert_eq!(6, outcome.rounds.step("sum_of_products").rounds);
+        assert_eq!(1, outcome.rounds.step("reveal").rounds);
+
+        let args = Args::from_iter(["ipa_local", "--aggregate", "sum", "sum"]);
+        assert!(run(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn attribution() {
+        for model in ["last-touch", "equal-credit", "time-decay:86400"] {
+            let args = Args::from_iter([
+                "ipa_local",
+                "-n",
+                "10",
+                "-b",
+                "3",
+                "--touches",
+                "3",
+                "--attribution",
+                model,
+                "--aggregate",
+                "count",
+                "sum",
+            ]);
+            let outcome = run(&args).await.unwrap();
+            assert_eq!(outcome.expected, outcome.actual, "{model}");
+            // credit is weighed locally, so touches only add products to the buckets
+            assert_eq!(10, outcome.rounds.step("securemul").multiplications);
+            assert_eq!(6, outcome.rounds.step("sum_of_products").rounds);
+        }
+
+        for args in [
+            vec!["ipa_local", "--touches", "0"],
+            vec!["ipa_local", "--attribution", "time-decay:0"],
+            vec!["ipa_local", "--attribution", "first-touch"],
+        ] {
+            let result = Args::from_iter_safe(args);
+            assert!(result.is_err() || run(&result.unwrap()).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks() {
+        /// Values of zero, first touch instead of the model, and counts that start at 100.
+        #[derive(Debug)]
+        struct Research;
+
+        impl Hooks for Research {
+            fn before_capping(&self, _value: u32) -> u32 {
+                0
+            }
+
+            fn transform_credits(&self, elapsed_seconds: &[u32], credits: &mut [u32]) {
+                let total = credits.iter().sum();
+                credits.fill(0);
+                if let Some(first) = (0..credits.len()).max_by_key(|&i| elapsed_seconds[i]) {
+                    credits[first] = total;
+                }
+            }
+
+            fn after_aggregation(&self, aggregate: Aggregate, histogram: &mut [u128]) {
+                if aggregate == Aggregate::Count {
+                    for v in histogram {
+                        *v += 100;
+                    }
+                }
+            }
+        }
+
+        let args = Args::from_iter([
+            "ipa_local",
+            "-n",
+            "10",
+            "-b",
+            "3",
+            "--touches",
+            "3",
+            "--attribution",
+            "equal-credit",
+            "--aggregate",
+            "count",
+            "sum",
+        ]);
+        let outcome = run_with_hooks(&args, &Research).await.unwrap();
+        assert_eq!(outcome.expected, outcome.actual);
+        let (counts, sums) = outcome.actual.split_at(3);
+        assert!(counts.iter().all(|&c| c >= 100), "{counts:?}");
+        assert_eq!(&[0; 3], sums);
+        // the rest of the query stays as it is
+        assert_eq!(10, outcome.rounds.step("securemul").multiplications);
+    }
+}
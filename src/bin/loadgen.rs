@@ -0,0 +1,295 @@
+use futures::StreamExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use raw_ipa::cli::Verbosity;
+use raw_ipa::collector::Collector;
+use raw_ipa::field::Fp31;
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use tracing::{debug, info};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "loadgen",
+    about = "Drives the HTTP endpoints of three MPC helpers with synthetic load and reports latency percentiles and error rates"
+)]
+struct Args {
+    #[structopt(flatten)]
+    logging: Verbosity,
+
+    /// Base URLs of the three helpers, such as `http://localhost:3000`
+    #[structopt(short = "u", long = "helper", number_of_values = 3, required = true)]
+    helpers: Vec<String>,
+
+    /// Number of requests in flight at the same time
+    #[structopt(short, long, default_value = "16")]
+    concurrency: usize,
+
+    /// Number of requests to send in total
+    #[structopt(short = "n", long, default_value = "1000")]
+    requests: usize,
+
+    /// Bearer token helpers know the collector by, which the status of queries needs
+    #[structopt(long)]
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    workload: Workload,
+}
+
+#[derive(Debug, StructOpt)]
+enum Workload {
+    /// Flood of report submissions, sent to the helpers in turn. Helpers do not take reports
+    /// over HTTP yet, so every report goes to `/echo` as a query parameter of the given size,
+    /// which exercises the same server path
+    Reports {
+        /// Size of every report in bytes
+        #[structopt(long, default_value = "256")]
+        size: usize,
+    },
+    /// Many small queries, every one of which checks the capabilities of all helpers and then
+    /// the status of the query on all of them, as report collectors do
+    Queries,
+    /// One huge query, the shares of which are uploaded to the pre-signed URLs of the helpers in
+    /// a single request each. The number of requests is ignored
+    Huge {
+        /// Number of records of the query
+        #[structopt(long, default_value = "1000000")]
+        records: usize,
+
+        /// Pre-signed URLs of the objects the helpers read their shares from
+        #[structopt(long = "url", number_of_values = 3, required = true)]
+        urls: Vec<String>,
+    },
+}
+
+/// Latencies of the requests sent, and how many of them failed.
+#[derive(Debug, Default)]
+struct Summary {
+    latencies: Vec<Duration>,
+    errors: usize,
+    elapsed: Duration,
+}
+
+impl Summary {
+    fn requests(&self) -> usize {
+        self.latencies.len()
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn error_rate(&self) -> f64 {
+        if self.latencies.is_empty() {
+            0.0
+        } else {
+            self.errors as f64 / self.latencies.len() as f64
+        }
+    }
+
+    /// Latency that `p` percent of the requests did not exceed, by nearest rank.
+    fn percentile(&self, p: u32) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * p as usize + 99) / 100;
+        sorted
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn print(&self) {
+        println!(
+            "{} requests in {:?}, {} failed ({:.2}%)",
+            self.requests(),
+            self.elapsed,
+            self.errors,
+            self.error_rate() * 100.0
+        );
+        println!(
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.percentile(100)
+        );
+    }
+}
+
+/// Runs `requests` instances of `request`, at most `concurrency` of them at a time, and measures
+/// how long every one takes.
+async fn drive<F, Fut>(requests: usize, concurrency: usize, request: F) -> Summary
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    let start = Instant::now();
+    let outcomes = futures::stream::iter(0..requests)
+        .map(|i| {
+            let request = request(i);
+            async move {
+                let start = Instant::now();
+                let outcome = request.await;
+                (start.elapsed(), outcome)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut summary = Summary {
+        elapsed: start.elapsed(),
+        ..Summary::default()
+    };
+    for (latency, outcome) in outcomes {
+        summary.latencies.push(latency);
+        if let Err(e) = outcome {
+            debug!("request failed: {e}");
+            summary.errors += 1;
+        }
+    }
+    summary
+}
+
+async fn submit(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    helper: &str,
+    report: &str,
+) -> Result<(), Box<dyn Error>> {
+    let uri: Uri = format!("{}/echo?report={report}", helper.trim_end_matches('/')).parse()?;
+    let response = client.get(uri).await?;
+    if !response.status().is_success() {
+        return Err(format!("{helper} responded with {}", response.status()).into());
+    }
+    hyper::body::to_bytes(response.into_body()).await?;
+    Ok(())
+}
+
+async fn run(args: &Args) -> Result<Summary, Box<dyn Error>> {
+    let mut collector = Collector::new([&args.helpers[0], &args.helpers[1], &args.helpers[2]])?;
+    if let Some(token) = &args.token {
+        collector = collector.with_token(token);
+    }
+    let summary = match &args.workload {
+        Workload::Reports { size } => {
+            let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+            let report: String = thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(*size)
+                .map(char::from)
+                .collect();
+            drive(args.requests, args.concurrency, |i| {
+                submit(&client, &args.helpers[i % 3], &report)
+            })
+            .await
+        }
+        Workload::Queries => {
+            drive(args.requests, args.concurrency, |i| {
+                let collector = &collector;
+                async move {
+                    collector.capabilities().await?;
+                    collector.status(&format!("loadgen-{i}")).await?;
+                    Ok(())
+                }
+            })
+            .await
+        }
+        Workload::Huge { records, urls } => {
+            let mut rng = thread_rng();
+            let values = (0..*records)
+                .map(|_| Fp31::from(rng.gen::<u32>()))
+                .collect::<Vec<_>>();
+            let shares = Collector::split(&values, &mut rng);
+            info!("uploading {records} records");
+            drive(1, 1, |_| async {
+                collector
+                    .upload([&urls[0], &urls[1], &urls[2]], &shares)
+                    .await?;
+                Ok(())
+            })
+            .await
+        }
+    };
+    Ok(summary)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args();
+    args.logging.setup_logging();
+
+    run(&args).await?.print();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Args, Summary};
+    use raw_ipa::capabilities::Capabilities;
+    use raw_ipa::net::auth::{Caller, Role, Tokens};
+    use raw_ipa::net::{
+        capabilities_router, mpc_helper_router, serve_mpc_helper, status_router, BindTarget,
+    };
+    use raw_ipa::telemetry::status::Status;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    #[test]
+    fn percentiles() {
+        let summary = Summary {
+            latencies: (1..=200).rev().map(Duration::from_millis).collect(),
+            errors: 3,
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(Duration::from_millis(100), summary.percentile(50));
+        assert_eq!(Duration::from_millis(198), summary.percentile(99));
+        assert_eq!(Duration::from_millis(200), summary.percentile(100));
+        assert!((summary.error_rate() - 0.015).abs() < f64::EPSILON);
+        assert_eq!(Duration::ZERO, Summary::default().percentile(50));
+    }
+
+    async fn helpers() -> Vec<String> {
+        let mut urls = Vec::new();
+        for _ in 0..3 {
+            let tokens =
+                Tokens::default().with_token("token", Caller::new("loadgen", Role::Collector));
+            let router = mpc_helper_router()
+                .merge(status_router(Arc::new(Status::default()), tokens))
+                .merge(capabilities_router(Capabilities::new(1)));
+            let (addr, _) =
+                serve_mpc_helper(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+            urls.push(format!("http://{addr}"));
+        }
+        urls
+    }
+
+    #[tokio::test]
+    async fn workloads() {
+        let urls = helpers().await;
+        for workload in [&["reports", "--size", "64"][..], &["queries"]] {
+            let mut argv = vec!["loadgen", "-n", "20", "-c", "4", "--token", "token", "-u"];
+            argv.extend(urls.iter().map(String::as_str));
+            argv.extend(workload);
+            let summary = run(&Args::from_iter(argv)).await.unwrap();
+            assert_eq!(20, summary.requests());
+            assert_eq!(0, summary.errors);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_errors() {
+        // nothing listens on the last helper, so every third report fails
+        let mut urls = helpers().await;
+        urls[2] = "http://127.0.0.1:1".to_owned();
+        let mut argv = vec!["loadgen", "-n", "9", "-u"];
+        argv.extend(urls.iter().map(String::as_str));
+        argv.push("reports");
+        let summary = run(&Args::from_iter(argv)).await.unwrap();
+        assert_eq!(3, summary.errors);
+    }
+}
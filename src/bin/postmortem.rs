@@ -0,0 +1,173 @@
+use raw_ipa::cli::{HexArg, Verbosity};
+use raw_ipa::storage::{DiskStore, QueryStore, StorageKey};
+use raw_ipa::telemetry::status::QuerySnapshot;
+use raw_ipa::telemetry::timeline::Timeline;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "postmortem",
+    about = "Puts together the timeline of a query from the records a helper kept of it, without changing any of them"
+)]
+struct Args {
+    #[structopt(flatten)]
+    logging: Verbosity,
+
+    /// Directory the helper keeps its state in
+    #[structopt(long, parse(from_os_str))]
+    state: PathBuf,
+
+    /// Storage key of the helper, in hex
+    #[structopt(long)]
+    storage_key: HexArg<32>,
+
+    /// File of snapshots of the helper status taken while the query ran, one `/status`
+    /// response per line
+    #[structopt(long, parse(from_os_str))]
+    snapshots: Option<PathBuf>,
+
+    /// Print the timeline as JSON
+    #[structopt(long)]
+    json: bool,
+
+    /// Id of the query
+    query: String,
+}
+
+/// What of a `/status` response matters for the timeline.
+#[derive(Deserialize)]
+struct StatusLine {
+    queries: Vec<QuerySnapshot>,
+}
+
+fn read_snapshots(path: &Path) -> Result<Vec<QuerySnapshot>, Box<dyn Error>> {
+    let mut snapshots = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let status: StatusLine = serde_json::from_str(line)
+            .map_err(|e| format!("line {} of {}: {e}", i + 1, path.display()))?;
+        snapshots.extend(status.queries);
+    }
+    Ok(snapshots)
+}
+
+fn run(args: &Args) -> Result<String, Box<dyn Error>> {
+    let store = DiskStore::open_existing(
+        &args.state,
+        &StorageKey::from_bytes(*args.storage_key.as_ref()),
+    )?;
+    let query = store
+        .query(&args.query)?
+        .ok_or_else(|| format!("{} has no query {}", args.state.display(), args.query))?;
+    let snapshots = match &args.snapshots {
+        Some(path) => read_snapshots(path)?,
+        None => Vec::new(),
+    };
+    info!("{} snapshots of all queries", snapshots.len());
+
+    let timeline = Timeline::new(
+        query,
+        &store.audit_log()?,
+        &snapshots,
+        store.checkpoint(&args.query)?.as_deref(),
+    );
+    if args.json {
+        Ok(serde_json::to_string_pretty(&timeline)?)
+    } else {
+        Ok(timeline.to_string())
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args();
+    args.logging.setup_logging();
+
+    print!("{}", run(&args)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Args};
+    use rand::{thread_rng, RngCore};
+    use raw_ipa::storage::{
+        AuditEntry, DiskStore, QueryMetadata, QueryState, QueryStore, StorageKey,
+    };
+    use std::fs;
+    use structopt::StructOpt;
+
+    #[test]
+    fn postmortem() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-postmortem-{}", std::process::id()));
+        let mut key = [0; 32];
+        thread_rng().fill_bytes(&mut key);
+        let hex_key = hex::encode(key);
+        let state = root.to_str().unwrap();
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["postmortem", "--state", state, "--storage-key", &hex_key];
+            argv.extend(extra);
+            Args::from_iter(argv)
+        };
+
+        // nothing is created where there is no state
+        assert!(run(&args(&["q1"])).is_err());
+        assert!(!root.exists());
+
+        let store = DiskStore::open(&root, &StorageKey::from_bytes(key)).unwrap();
+        store
+            .put_query(&QueryMetadata {
+                id: "q1".to_owned(),
+                collector: "c".to_owned(),
+                epoch: 1,
+                rows: 10,
+                submitted: 100,
+                state: QueryState::Suspended,
+            })
+            .unwrap();
+        store.put_checkpoint("q1", &[1, 2, 3]).unwrap();
+        store
+            .audit(AuditEntry {
+                at: 105,
+                collector: "c".to_owned(),
+                event: "paused q1".to_owned(),
+            })
+            .unwrap();
+        let snapshots = root.join("snapshots.jsonl");
+        let line = |stage: &str, elapsed: f64| {
+            format!(
+                r#"{{"queries":[{{"id":"q1","stage":"{stage}","elapsed_secs":{elapsed},"records_processed":5,"left":{{"bytes_sent":0,"bytes_received":0}},"right":{{"bytes_sent":0,"bytes_received":0}},"buffer_depth":0,"dead_letters":0,"eta_secs":null}}],"suspended":[]}}"#
+            )
+        };
+        fs::write(
+            &snapshots,
+            [line("sort", 1.0), line("attribution", 4.0)].join("\n"),
+        )
+        .unwrap();
+        let snapshots = snapshots.to_str().unwrap();
+
+        let report = run(&args(&["--snapshots", snapshots, "q1"])).unwrap();
+        assert!(report.contains("Suspended"), "{report}");
+        assert!(report.contains("audit at +5s: paused q1"), "{report}");
+        assert!(report.contains("checkpoint of 3 bytes"), "{report}");
+        let json: serde_json::Value =
+            serde_json::from_str(&run(&args(&["--json", "--snapshots", snapshots, "q1"])).unwrap())
+                .unwrap();
+        assert_eq!(2, json["stages"].as_array().unwrap().len());
+        let duration = json["stages"][0]["duration_secs"].as_f64().unwrap();
+        assert!((duration - 3.0).abs() < f64::EPSILON);
+
+        assert!(run(&args(&["q2"])).is_err());
+
+        fs::remove_file(root.join("snapshots.jsonl")).unwrap();
+        store.destroy().unwrap();
+        fs::remove_dir(root).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use raw_ipa::cli::Verbosity;
+use raw_ipa::scenarios::{self, Report};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "scenarios",
+    about = "Runs benchmark scenarios defined in a TOML file and reports how long they took as JSON"
+)]
+struct Args {
+    #[structopt(flatten)]
+    logging: Verbosity,
+
+    /// File that defines the scenarios
+    scenarios: PathBuf,
+
+    /// Names of the scenarios to run, all of them if not set
+    #[structopt(long)]
+    only: Vec<String>,
+
+    /// File the report is written to, standard output if not set
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args();
+    args.logging.setup_logging();
+
+    let defined = scenarios::parse(&std::fs::read_to_string(&args.scenarios)?)?;
+    if let Some(unknown) = args
+        .only
+        .iter()
+        .find(|name| defined.iter().all(|s| &s.name != *name))
+    {
+        return Err(format!("no scenario is named {unknown}").into());
+    }
+
+    let mut reports: Vec<Report> = Vec::new();
+    for scenario in defined
+        .iter()
+        .filter(|s| args.only.is_empty() || args.only.contains(&s.name))
+    {
+        info!(scenario = %scenario.name, "running");
+        reports.push(scenarios::run(scenario).await?);
+    }
+
+    match &args.output {
+        Some(path) => serde_json::to_writer_pretty(File::create(path)?, &reports)?,
+        None => serde_json::to_writer_pretty(io::stdout().lock(), &reports)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+//!
+//! What a helper can run, so that report collectors can pick query parameters that all three
+//! helpers of a query support before they submit it, rather than finding out when it fails.
+//! Helpers serve their [`Capabilities`] without authentication: none of it is secret, and
+//! collectors need it before they have anything to authenticate with.
+//!
+//! Collectors combine what they get from the three helpers with [`Capabilities::common`], and
+//! check a query against the result with [`Capabilities::supports`].
+//!
+use crate::helpers::models::MODEL_VERSION;
+use crate::query::{FieldType, IpaQueryConfig, SecurityMode};
+use rust_elgamal::EncryptionKey;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("there are no helpers to combine capabilities of")]
+    NoHelpers,
+    #[error("helpers speak different versions of the protocol: {0:?}")]
+    ProtocolVersionMismatch(Vec<u16>),
+    #[error("not all helpers support {0:?}")]
+    UnsupportedField(FieldType),
+    #[error("not all helpers support {0:?}")]
+    UnsupportedSecurityMode(SecurityMode),
+    #[error("query has {records} records, but helpers take {max} at most")]
+    TooManyRecords { records: u64, max: u64 },
+}
+
+/// Everything a helper tells report collectors about itself.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct Capabilities {
+    /// Version of the protocol helpers speak to each other, which must be the same on all three.
+    pub protocol_version: u16,
+    /// Newest version of report models the helper reads. Older ones are read too.
+    pub model_version: u8,
+    pub fields: Vec<FieldType>,
+    pub security_modes: Vec<SecurityMode>,
+    /// Largest number of records the helper takes in a single query, if it limits them.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_records: Option<u64>,
+    /// Keys that match keys are encrypted to, one for every event helper whose key is known.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub public_keys: Vec<EncryptionKey>,
+}
+
+impl Capabilities {
+    /// Capabilities of a helper of this build, which speaks `protocol_version` to its peers.
+    #[must_use]
+    pub fn new(protocol_version: u16) -> Self {
+        Self {
+            protocol_version,
+            model_version: MODEL_VERSION,
+            fields: vec![FieldType::Fp31, FieldType::Fp32BitPrime],
+            security_modes: vec![SecurityMode::SemiHonest, SecurityMode::Malicious],
+            max_records: None,
+            public_keys: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_max_records(mut self, max_records: Option<u64>) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    #[must_use]
+    pub fn with_public_key(mut self, key: EncryptionKey) -> Self {
+        self.public_keys.push(key);
+        self
+    }
+
+    /// What all of `helpers` can run: fields and security modes they all support, the oldest
+    /// model version, the smallest limit on records and all of their keys.
+    ///
+    /// ## Errors
+    /// If helpers speak different versions of the protocol, which means they cannot run any
+    /// query together.
+    pub fn common(helpers: &[Self]) -> Result<Self, Error> {
+        let mut versions = helpers
+            .iter()
+            .map(|h| h.protocol_version)
+            .collect::<Vec<_>>();
+        versions.sort_unstable();
+        versions.dedup();
+        let (first, rest) = helpers.split_first().ok_or(Error::NoHelpers)?;
+        if versions.len() > 1 {
+            return Err(Error::ProtocolVersionMismatch(versions));
+        }
+
+        let mut common = first.clone();
+        for helper in rest {
+            common.model_version = common.model_version.min(helper.model_version);
+            common.fields.retain(|f| helper.fields.contains(f));
+            common
+                .security_modes
+                .retain(|m| helper.security_modes.contains(m));
+            common.max_records = match (common.max_records, helper.max_records) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            common.public_keys.extend(&helper.public_keys);
+        }
+        Ok(common)
+    }
+
+    /// Checks that a query with `config` and `records` records can run.
+    ///
+    /// ## Errors
+    /// If the field or the security mode of the query is not supported, or it has too many
+    /// records.
+    pub fn supports(&self, config: &IpaQueryConfig, records: u64) -> Result<(), Error> {
+        if !self.fields.contains(&config.field) {
+            return Err(Error::UnsupportedField(config.field));
+        }
+        if !self.security_modes.contains(&config.security) {
+            return Err(Error::UnsupportedSecurityMode(config.security));
+        }
+        match self.max_records {
+            Some(max) if records > max => Err(Error::TooManyRecords { records, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capabilities::{Capabilities, Error};
+    use crate::query::{IpaQueryConfig, SecurityMode};
+
+    #[test]
+    fn common() {
+        let a = Capabilities::new(1).with_max_records(Some(100));
+        let mut b = Capabilities::new(1).with_max_records(Some(50));
+        b.security_modes = vec![SecurityMode::SemiHonest];
+        b.model_version = 1;
+        let c = Capabilities::new(1);
+
+        let common = Capabilities::common(&[a.clone(), b, c.clone()]).unwrap();
+        assert_eq!(Some(50), common.max_records);
+        assert_eq!(vec![SecurityMode::SemiHonest], common.security_modes);
+        assert_eq!(1, common.model_version);
+        assert_eq!(
+            Some(100),
+            Capabilities::common(&[c, a]).unwrap().max_records
+        );
+
+        assert_eq!(
+            Error::ProtocolVersionMismatch(vec![1, 2]),
+            Capabilities::common(&[Capabilities::new(2), Capabilities::new(1)]).unwrap_err()
+        );
+        assert_eq!(Error::NoHelpers, Capabilities::common(&[]).unwrap_err());
+    }
+
+    #[test]
+    fn supports() {
+        let config = IpaQueryConfig::default();
+        let mut capabilities = Capabilities::new(1).with_max_records(Some(10));
+        assert_eq!(Ok(()), capabilities.supports(&config, 10));
+        assert_eq!(
+            Err(Error::TooManyRecords {
+                records: 11,
+                max: 10
+            }),
+            capabilities.supports(&config, 11)
+        );
+
+        capabilities.security_modes.clear();
+        assert_eq!(
+            Err(Error::UnsupportedSecurityMode(SecurityMode::SemiHonest)),
+            capabilities.supports(&config, 1)
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::error::BoxError;
+use crate::error::Error;
 use futures::{ready, Stream};
 use pin_project::pin_project;
 use std::future::Future;
@@ -44,7 +44,7 @@ where
     St: Stream,
     St::Item: Clone,
     F: FnMut(Vec<St::Item>) -> Fut,
-    Fut: Future<Output = Result<St::Item, BoxError>>,
+    Fut: Future<Output = Result<St::Item, Error>>,
 {
     type Item = St::Item;
 
@@ -59,7 +59,7 @@ where
 
                 if let Err(e) = item {
                     // TODO (alex): we should propagate errors back to caller
-                    error!({ e }, "An error occurred computing next stream element");
+                    error!(error = %e, "An error occurred computing next stream element");
                     return Poll::Ready(None);
                 }
                 let item = item.unwrap();
@@ -88,7 +88,7 @@ impl<St, F, Fut> ChunkScan<St, F, Fut>
 where
     St: Stream,
     F: FnMut(Vec<St::Item>) -> Fut,
-    Fut: Future<Output = Result<St::Item, BoxError>>,
+    Fut: Future<Output = Result<St::Item, Error>>,
 {
     pub fn new(stream: St, capacity: usize, f: F) -> Self {
         Self {
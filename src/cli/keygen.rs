@@ -0,0 +1,93 @@
+use crate::error::{Error, Res};
+use crate::helpers::{AggregationHelper, EventHelper, Helpers, Role as HelperRole};
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tracing::info;
+
+/// Generates key material for a single helper and writes it to the helper directory.
+#[derive(Debug, StructOpt)]
+pub struct KeygenArgs {
+    /// The role of the helper to generate keys for (seh, teh, ah1, ah2).
+    #[structopt(long)]
+    role: HelperRole,
+
+    /// The directory to write helper files to.
+    #[structopt(long)]
+    dir: PathBuf,
+
+    /// Host names of this helper to put in the TLS certificate.
+    #[structopt(long = "hostname", default_value = "localhost")]
+    hostnames: Vec<String>,
+
+    /// Overwrite key material if it already exists.
+    #[structopt(long)]
+    force: bool,
+}
+
+impl KeygenArgs {
+    const TLS_KEY: &'static str = "key.pem";
+    const TLS_CERT: &'static str = "cert.pem";
+    const TLS_CSR: &'static str = "csr.pem";
+
+    /// Writes the following files to the helper directory:
+    /// * `public.json` and `private.json` with the helper configuration and keys, in the format
+    ///   expected by `Helpers::load`. For event helpers this includes their threshold key share.
+    /// * `key.pem` with the private key for the helper TLS endpoint, along with `cert.pem`,
+    ///   a self-signed certificate for it, and `csr.pem`, a certificate signing request to send
+    ///   to a CA.
+    ///
+    /// # Errors
+    /// If helper files already exist and `force` is not set, or if they cannot be written.
+    pub fn execute(&self) -> Res<()> {
+        if !self.force && Helpers::filename(&self.dir, false).exists() {
+            return Err(Error::AlreadyExists);
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        match self.role {
+            HelperRole::Event(role) => EventHelper::new(role).save(&self.dir)?,
+            HelperRole::Aggregation(role) => AggregationHelper::new(role).save(&self.dir)?,
+        }
+        self.write_tls(&self.dir)?;
+
+        info!("Keys for {} written to {}", self.role, self.dir.display());
+        Ok(())
+    }
+
+    fn write_tls(&self, dir: &Path) -> Res<()> {
+        let cert = rcgen::generate_simple_self_signed(self.hostnames.clone())?;
+        fs::write(dir.join(Self::TLS_KEY), cert.serialize_private_key_pem())?;
+        fs::write(dir.join(Self::TLS_CERT), cert.serialize_pem()?)?;
+        fs::write(dir.join(Self::TLS_CSR), cert.serialize_request_pem()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::keygen::KeygenArgs;
+    use crate::error::Error;
+    use crate::helpers::{EventHelper, EventHelperRole};
+    use structopt::StructOpt;
+
+    #[test]
+    fn keygen() {
+        let dir = std::env::temp_dir().join(format!("raw-ipa-keygen-{}", std::process::id()));
+        let dir_arg = dir.to_str().unwrap();
+        let args = KeygenArgs::from_iter(["keygen", "--role", "seh", "--dir", dir_arg]);
+
+        args.execute().unwrap();
+        assert!(EventHelper::load(&dir, EventHelperRole::Source).is_ok());
+        for f in ["public.json", "key.pem", "cert.pem", "csr.pem"] {
+            assert!(dir.join(f).is_file(), "{f} is missing");
+        }
+
+        assert!(matches!(args.execute(), Err(Error::AlreadyExists)));
+        KeygenArgs::from_iter(["keygen", "--role", "seh", "--dir", dir_arg, "--force"])
+            .execute()
+            .unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
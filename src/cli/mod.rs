@@ -1,9 +1,11 @@
 mod helper;
 mod hexarg;
+mod keygen;
 mod stringn;
 mod verbosity;
 
 pub use helper::HelperArgs;
 pub use hexarg::HexArg;
+pub use keygen::KeygenArgs;
 pub use stringn::StringN;
 pub use verbosity::Verbosity;
@@ -1,8 +1,12 @@
 use std::io::stderr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
 use tracing::metadata::LevelFilter;
 use tracing::{info, Level};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 #[derive(Debug, StructOpt)]
 pub struct Verbosity {
@@ -13,20 +17,135 @@ pub struct Verbosity {
     /// Verbose mode (-v, -vv, -vvv, etc)
     #[structopt(short = "v", long = "verbose", global = true, parse(from_occurrences))]
     verbose: usize,
+
+    /// Format of log records: human readable (pretty) or one JSON object per line (json)
+    #[structopt(long = "log-format", global = true, default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// Write logs to this file instead of stderr
+    #[structopt(long = "log-file", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// How often to start a new log file (never, hourly, daily). Rotated files get the
+    /// date appended to their name. Only used together with --log-file
+    #[structopt(long = "log-rotation", global = true, default_value = "never")]
+    log_rotation: LogRotation,
+
+    /// Address of the OTLP collector to export tracing spans to
+    #[cfg(feature = "otlp")]
+    #[structopt(long = "otlp-endpoint", global = true)]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            _ => Err(format!("unknown log rotation: {s}")),
+        }
+    }
+}
+
+impl LogRotation {
+    fn appender(self, path: &Path) -> RollingFileAppender {
+        let rotation = match self {
+            Self::Never => Rotation::NEVER,
+            Self::Hourly => Rotation::HOURLY,
+            Self::Daily => Rotation::DAILY,
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let file_name = path.file_name().expect("log file must not be a directory");
+
+        RollingFileAppender::new(rotation, dir, file_name)
+    }
 }
 
 impl Verbosity {
     pub fn setup_logging(&self) {
         let filter_layer = self.level_filter();
-        let fmt_layer = fmt::layer().without_time().with_writer(stderr);
+        let writer = match &self.log_file {
+            Some(path) => BoxMakeWriter::new(self.log_rotation.appender(path)),
+            None => BoxMakeWriter::new(stderr),
+        };
+        let fmt_layer = fmt::layer().with_writer(writer);
+        let fmt_layer = match self.log_format {
+            LogFormat::Pretty => fmt_layer.without_time().boxed(),
+            // span fields (step, record, peer, etc.) are emitted alongside every record, so log
+            // aggregators can filter on them
+            LogFormat::Json => fmt_layer
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed(),
+        };
 
-        tracing_subscriber::registry()
+        let registry = tracing_subscriber::registry()
             .with(self.level_filter())
-            .with(fmt_layer)
-            .init();
+            .with(fmt_layer);
+        #[cfg(feature = "otlp")]
+        let registry = registry.with(self.otlp_layer());
+        registry.init();
         info!("Logging setup at level {}", filter_layer);
     }
 
+    /// Layer that exports spans to the OTLP collector, if one is configured. Spans are exported
+    /// in batches by a background task, so it requires Tokio runtime to be running.
+    #[cfg(feature = "otlp")]
+    fn otlp_layer<S>(
+        &self,
+    ) -> Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let endpoint = self.otlp_endpoint.as_ref()?;
+        if tokio::runtime::Handle::try_current().is_err() {
+            eprintln!("OTLP export requires Tokio runtime, spans will not be exported");
+            return None;
+        }
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .expect("Failed to set up OTLP exporter");
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
     fn level_filter(&self) -> LevelFilter {
         if self.quiet {
             LevelFilter::OFF
@@ -0,0 +1,23 @@
+//!
+//! What clients need to take part in IPA: field arithmetic, splitting values into replicated
+//! shares, encrypting match keys for the helpers and encoding reports and shares. Devices and
+//! report collectors that run on constrained platforms, or test rigs that only produce input,
+//! build the crate with the `client-core` feature alone:
+//!
+//! ```toml
+//! raw-ipa = { version = "0.1", default-features = false, features = ["client-core"] }
+//! ```
+//!
+//! which leaves out everything helpers need to run queries, the async runtime, the HTTP stack and
+//! the stores among them, but encodes everything exactly the same way helpers read it.
+//!
+//! Match keys are encrypted with the threshold encryption key of the helpers, see
+//! [`EncryptionKey`]; there is no HPKE in this crate.
+//!
+pub use crate::encoding::{field_size, read_fields, read_shares, write_fields, write_shares};
+pub use crate::entropy::Entropy;
+pub use crate::field::{Field, Fp31, Int};
+pub use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+pub use crate::report::{EncryptedMatchkeys, Error as ReportError, EventReport};
+pub use crate::threshold::EncryptionKey;
+pub use crate::user::User;
@@ -0,0 +1,171 @@
+//!
+//! Check that helpers agree on the epoch of a query and roughly on the time, before it starts.
+//! Helpers pick the keys they decrypt match keys with and charge privacy budgets by epoch, so
+//! a helper that runs the query for a different epoch than its peers would quietly use the wrong
+//! keys and spend the wrong budget. A helper whose clock is off does the same once it decides
+//! which epoch it is on its own.
+//!
+//! Every helper sends its epoch and the time on its clock to both peers, in a single round, and
+//! compares what it gets with its own. The time a message takes to get across counts towards the
+//! skew, so the limit must leave room for it.
+//!
+use crate::error::Res;
+use crate::helpers::ring::{message, HelperAddr, Ring};
+use crate::securemul::ProtocolContext;
+use crate::step;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const STEP: &str = step::CLOCK_CHECK;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error(
+        "helper on the {peer:?} runs the query for epoch {theirs}, but this helper for {ours}"
+    )]
+    EpochMismatch {
+        peer: HelperAddr,
+        ours: u8,
+        theirs: u8,
+    },
+    #[error("clock of the helper on the {peer:?} is {skew:?} off, more than {limit:?}")]
+    Skew {
+        peer: HelperAddr,
+        skew: Duration,
+        limit: Duration,
+    },
+}
+
+/// What a helper tells its peers about the query it is about to run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClockMessage {
+    epoch: u8,
+    /// Milliseconds since the Unix epoch.
+    time_ms: u64,
+}
+
+message!(ClockMessage => "clock.clock");
+
+fn millis(time: SystemTime) -> u64 {
+    // a clock before 1970 is as far off as it gets
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Checks that both peers run the query for `epoch` and that their clocks are within
+/// `max_skew` of `now`, the time on the clock of this helper. All three helpers must call this
+/// at the same point of the query, before any of them uses the epoch.
+///
+/// ## Errors
+/// If communication with peers fails, a peer runs the query for another epoch or its clock is
+/// too far off.
+pub async fn check_clocks<R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    epoch: u8,
+    now: SystemTime,
+    max_skew: Duration,
+) -> Res<()> {
+    let mine = ClockMessage {
+        epoch,
+        time_ms: millis(now),
+    };
+
+    let _round = ctx.rounds.map(|rounds| rounds.wait(STEP));
+    let ((), (left, right)) = futures::try_join!(
+        ctx.helper_ring.broadcast(mine.clone()),
+        ctx.helper_ring.receive_from_both::<ClockMessage>(),
+    )?;
+
+    for (peer, theirs) in [(HelperAddr::Left, left), (HelperAddr::Right, right)] {
+        if theirs.epoch != epoch {
+            return Err(Error::EpochMismatch {
+                peer,
+                ours: epoch,
+                theirs: theirs.epoch,
+            }
+            .into());
+        }
+        let skew = Duration::from_millis(theirs.time_ms.abs_diff(mine.time_ms));
+        if skew > max_skew {
+            return Err(Error::Skew {
+                peer,
+                skew,
+                limit: max_skew,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{check_clocks, Error as ClockError};
+    use crate::error::Error;
+    use crate::helpers::ring::HelperAddr;
+    use crate::test_fixture::TestWorld;
+    use std::time::{Duration, SystemTime};
+
+    /// Every helper checks with its epoch and the time on its clock.
+    async fn check(epochs: [u8; 3], offsets: [Duration; 3]) -> [Result<(), Error>; 3] {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let now = SystemTime::now();
+        let limit = Duration::from_secs(5);
+        let (r0, r1, r2) = futures::join!(
+            check_clocks(&ctx[0], epochs[0], now + offsets[0], limit),
+            check_clocks(&ctx[1], epochs[1], now + offsets[1], limit),
+            check_clocks(&ctx[2], epochs[2], now + offsets[2], limit),
+        );
+        [r0, r1, r2]
+    }
+
+    #[tokio::test]
+    async fn agree() {
+        let offsets = [0, 2, 4].map(Duration::from_secs);
+        assert!(check([3; 3], offsets).await.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn epoch_mismatch() {
+        // helper 2 is on the right of helper 1 and on the left of helper 3
+        let [r0, _, r2] = check([3, 4, 3], [Duration::ZERO; 3]).await;
+        assert!(matches!(
+            r0,
+            Err(Error::Clock(ClockError::EpochMismatch {
+                peer: HelperAddr::Right,
+                ours: 3,
+                theirs: 4,
+            }))
+        ));
+        assert!(matches!(
+            r2,
+            Err(Error::Clock(ClockError::EpochMismatch {
+                peer: HelperAddr::Left,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn skew() {
+        let offsets = [0, 0, 6].map(Duration::from_secs);
+        let [r0, r1, _] = check([3; 3], offsets).await;
+        assert!(matches!(
+            r0,
+            Err(Error::Clock(ClockError::Skew {
+                peer: HelperAddr::Left,
+                skew,
+                ..
+            })) if skew == Duration::from_secs(6)
+        ));
+        assert!(matches!(
+            r1,
+            Err(Error::Clock(ClockError::Skew {
+                peer: HelperAddr::Right,
+                ..
+            }))
+        ));
+    }
+}
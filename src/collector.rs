@@ -0,0 +1,463 @@
+//!
+//! What a report collector does around a query, for services that submit queries themselves
+//! rather than through the command line tools. A [`Collector`] knows the three helpers of a
+//! query and where their input and output go in the object store, see
+//! [`object_store`](crate::net::object_store):
+//!
+//! 1. [`Collector::capabilities`] tells what all three helpers can run.
+//! 2. [`Collector::split`] splits input values into a share for every helper, and
+//!    [`Collector::upload`] writes them to the object store.
+//! 3. [`Collector::status`] and [`Collector::wait`] follow the query while helpers run it, and
+//!    [`Collector::cancel`] stops it. The status of every helper tells which reports it
+//!    rejected, see [`QuerySnapshot::rejections`].
+//! 4. [`Collector::download`] reads the shares of the result helpers wrote, and
+//!    [`Collector::reconstruct`] or [`Collector::verify`] turns them into the result.
+//!
+//! Helpers and shares are always in the order of the helpers: the first share is for the first
+//! helper, and so on. Nothing here keeps state between calls, so a collector can be shared by
+//! any number of queries.
+//!
+use crate::capabilities::{self, Capabilities};
+use crate::field::Field;
+use crate::net::object_store::{self, ObjectStore};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::telemetry::status::QuerySnapshot;
+use crate::verify::{Bucket, NoiseParams, Report};
+use hyper::client::HttpConnector;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::time::Duration;
+use thiserror::Error;
+
+type Share<F> = ReplicatedSecretSharing<F>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid URL of helper {helper}")]
+    InvalidUrl {
+        helper: usize,
+        #[source]
+        inner: hyper::http::uri::InvalidUri,
+    },
+    #[error("request to helper {helper} failed")]
+    Network {
+        helper: usize,
+        #[source]
+        inner: hyper::Error,
+    },
+    #[error("helper {helper} responded with {status}")]
+    Status { helper: usize, status: StatusCode },
+    #[error("response of helper {helper} does not parse")]
+    Malformed {
+        helper: usize,
+        #[source]
+        inner: serde_json::Error,
+    },
+    #[error(transparent)]
+    ObjectStore(#[from] object_store::Error),
+    #[error(transparent)]
+    Capabilities(#[from] capabilities::Error),
+    #[error("helpers have {0:?} shares, which must be the same number")]
+    LengthMismatch([usize; 3]),
+    #[error("shares of value {0} are not consistent with each other")]
+    Inconsistent(usize),
+    #[error("expected {expected} buckets, but the result has {actual}")]
+    BucketMismatch { expected: usize, actual: usize },
+    #[error("value of bucket {0} does not fit in 32 bits")]
+    Overflow(usize),
+}
+
+/// What a single helper says about a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryStatus {
+    Running(Box<QuerySnapshot>),
+    /// Query was checkpointed and waits to be resumed.
+    Suspended,
+    /// Query finished, failed or never started, which helpers do not tell apart.
+    NotRunning,
+}
+
+/// The part of the status of a helper collectors read.
+#[derive(Deserialize)]
+struct StatusResponse {
+    queries: Vec<QuerySnapshot>,
+    suspended: Vec<String>,
+}
+
+/// Report collector of queries run by three helpers.
+#[derive(Debug, Clone)]
+pub struct Collector {
+    /// Base URLs of the helpers, without a trailing slash.
+    helpers: [String; 3],
+    client: Client<HttpsConnector<HttpConnector>>,
+    store: ObjectStore,
+    /// Bearer token helpers know this collector by, see [`crate::net::auth`].
+    token: Option<String>,
+}
+
+impl Collector {
+    /// Collector of queries run by the helpers at the given base URLs, such as
+    /// `https://helper1.example:443`.
+    ///
+    /// ## Errors
+    /// If one of the URLs does not parse.
+    pub fn new(helpers: [&str; 3]) -> Result<Self, Error> {
+        let mut bases = <[String; 3]>::default();
+        for (helper, (url, base)) in helpers.iter().zip(&mut bases).enumerate() {
+            let url = url.trim_end_matches('/');
+            url.parse::<Uri>()
+                .map_err(|inner| Error::InvalidUrl { helper, inner })?;
+            *base = url.to_owned();
+        }
+        Ok(Self {
+            helpers: bases,
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            store: ObjectStore::default(),
+            token: None,
+        })
+    }
+
+    /// Authenticates to helpers with `token`, which they need to tell the status of queries
+    /// and to cancel them.
+    #[must_use]
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    /// Uses `store` for input and output, to limit the size of results read for example.
+    #[must_use]
+    pub fn with_object_store(mut self, store: ObjectStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// What all three helpers can run, see [`Capabilities::common`].
+    ///
+    /// ## Errors
+    /// If a helper cannot be reached or its capabilities do not parse, or helpers speak
+    /// different versions of the protocol.
+    pub async fn capabilities(&self) -> Result<Capabilities, Error> {
+        let (c1, c2, c3) = futures::try_join!(
+            self.get::<Capabilities>(0, "/capabilities"),
+            self.get::<Capabilities>(1, "/capabilities"),
+            self.get::<Capabilities>(2, "/capabilities"),
+        )?;
+        Ok(Capabilities::common(&[c1, c2, c3])?)
+    }
+
+    /// Splits every one of `values` into replicated shares, a list of them for every helper.
+    #[must_use]
+    pub fn split<F: Field, R: RngCore>(values: &[F], rng: &mut R) -> [Vec<Share<F>>; 3] {
+        let mut shares = [
+            Vec::with_capacity(values.len()),
+            Vec::with_capacity(values.len()),
+            Vec::with_capacity(values.len()),
+        ];
+        for &v in values {
+            for (helper, share) in shares.iter_mut().zip(Share::share(v, rng)) {
+                helper.push(share);
+            }
+        }
+        shares
+    }
+
+    /// Writes the shares of every helper to the object at the pre-signed URL of that helper.
+    ///
+    /// ## Errors
+    /// If any of the objects cannot be written.
+    pub async fn upload<F: Field>(
+        &self,
+        urls: [&str; 3],
+        shares: &[Vec<Share<F>>; 3],
+    ) -> Result<(), Error> {
+        futures::try_join!(
+            self.store.put_shares(urls[0], &shares[0]),
+            self.store.put_shares(urls[1], &shares[1]),
+            self.store.put_shares(urls[2], &shares[2]),
+        )?;
+        Ok(())
+    }
+
+    /// What every helper says about the query with the given id.
+    ///
+    /// ## Errors
+    /// If a helper cannot be reached or its status does not parse.
+    pub async fn status(&self, id: &str) -> Result<[QueryStatus; 3], Error> {
+        let (s1, s2, s3) = futures::try_join!(
+            self.get::<StatusResponse>(0, "/status"),
+            self.get::<StatusResponse>(1, "/status"),
+            self.get::<StatusResponse>(2, "/status"),
+        )?;
+        Ok([s1, s2, s3].map(|status| {
+            if let Some(query) = status.queries.into_iter().find(|q| q.id == id) {
+                QueryStatus::Running(Box::new(query))
+            } else if status.suspended.iter().any(|s| s == id) {
+                QueryStatus::Suspended
+            } else {
+                QueryStatus::NotRunning
+            }
+        }))
+    }
+
+    /// Polls the status of the query every `interval` until no helper runs it anymore, and
+    /// returns what they say then.
+    ///
+    /// ## Errors
+    /// If a helper cannot be reached or its status does not parse.
+    pub async fn wait(&self, id: &str, interval: Duration) -> Result<[QueryStatus; 3], Error> {
+        loop {
+            let status = self.status(id).await?;
+            if !status.iter().any(|s| matches!(s, QueryStatus::Running(_))) {
+                return Ok(status);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Reads the shares of the result every helper wrote to the object at its pre-signed URL.
+    ///
+    /// ## Errors
+    /// If any of the objects cannot be read or does not hold shares of `F`.
+    pub async fn download<F: Field>(&self, urls: [&str; 3]) -> Result<[Vec<Share<F>>; 3], Error> {
+        let (s1, s2, s3) = futures::try_join!(
+            self.store.get_shares(urls[0]),
+            self.store.get_shares(urls[1]),
+            self.store.get_shares(urls[2]),
+        )?;
+        Ok([s1, s2, s3])
+    }
+
+    /// Reconstructs every value from the shares of all three helpers.
+    ///
+    /// ## Errors
+    /// If helpers have different numbers of shares, or the shares of a value do not agree.
+    pub fn reconstruct<F: Field>(shares: &[Vec<Share<F>>; 3]) -> Result<Vec<F>, Error> {
+        let lengths = [0, 1, 2].map(|i| shares[i].len());
+        if lengths.iter().any(|&len| len != lengths[0]) {
+            return Err(Error::LengthMismatch(lengths));
+        }
+        (0..lengths[0])
+            .map(|i| {
+                Share::reconstruct(&[shares[0][i], shares[1][i], shares[2][i]])
+                    .ok_or(Error::Inconsistent(i))
+            })
+            .collect()
+    }
+
+    /// Reconstructs a histogram from the shares of all three helpers and compares it to the
+    /// `expected` one, allowing for the noise helpers add, see [`crate::verify`].
+    ///
+    /// ## Errors
+    /// If the histogram does not reconstruct, has a different number of buckets than expected,
+    /// or a bucket does not fit in 32 bits.
+    pub fn verify<F: Field>(
+        expected: &[u32],
+        shares: &[Vec<Share<F>>; 3],
+        noise: &NoiseParams,
+    ) -> Result<Report, Error> {
+        let values = Self::reconstruct(shares)?;
+        if values.len() != expected.len() {
+            return Err(Error::BucketMismatch {
+                expected: expected.len(),
+                actual: values.len(),
+            });
+        }
+        let buckets = expected
+            .iter()
+            .zip(values)
+            .enumerate()
+            .map(|(i, (&expected, v))| {
+                let actual: u128 = Into::<F::Integer>::into(v).into();
+                let actual = u32::try_from(actual).map_err(|_| Error::Overflow(i))?;
+                Ok(Bucket { expected, actual })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Report {
+            buckets,
+            tolerance: noise.tolerance(),
+        })
+    }
+
+    /// Cancels the query with the given id. Helpers abort it, which fails it on all of them, so
+    /// a single helper that still runs it is enough. Returns whether any of them did.
+    ///
+    /// ## Errors
+    /// If a helper cannot be reached, or responds with anything but success or not found.
+    pub async fn cancel(&self, id: &str) -> Result<bool, Error> {
+        let path = format!("/cancel/{id}");
+        let (c1, c2, c3) = futures::try_join!(
+            self.post(0, &path),
+            self.post(1, &path),
+            self.post(2, &path),
+        )?;
+        Ok(c1 || c2 || c3)
+    }
+
+    /// Sends an empty `POST` to `path` of `helper`. Returns false if the helper responds with
+    /// not found.
+    async fn post(&self, helper: usize, path: &str) -> Result<bool, Error> {
+        let uri: Uri = format!("{}{path}", self.helpers[helper])
+            .parse()
+            .map_err(|inner| Error::InvalidUrl { helper, inner })?;
+        let response = self
+            .client
+            .request(self.request(Method::POST, uri))
+            .await
+            .map_err(|inner| Error::Network { helper, inner })?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(Error::Status { helper, status }),
+        }
+    }
+
+    /// Empty request to `uri`, with the token of the collector if it has one.
+    fn request(&self, method: Method, uri: Uri) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    /// Reads the JSON at `path` of a helper.
+    async fn get<T: DeserializeOwned>(&self, helper: usize, path: &str) -> Result<T, Error> {
+        let uri = format!("{}{path}", self.helpers[helper])
+            .parse()
+            .map_err(|inner| Error::InvalidUrl { helper, inner })?;
+        let network = |inner| Error::Network { helper, inner };
+        let response = self
+            .client
+            .request(self.request(Method::GET, uri))
+            .await
+            .map_err(network)?;
+        if !response.status().is_success() {
+            return Err(Error::Status {
+                helper,
+                status: response.status(),
+            });
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(network)?;
+        serde_json::from_slice(&body).map_err(|inner| Error::Malformed { helper, inner })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capabilities::Capabilities;
+    use crate::collector::{Collector, Error, QueryStatus};
+    use crate::field::Fp31;
+    use crate::ingest::{RejectionReason, Rejections};
+    use crate::net::auth::{Caller, Role, Tokens};
+    use crate::net::{capabilities_router, serve_mpc_helper, status_router, BindTarget};
+    use crate::telemetry::status::{QueryProgress, Status};
+    use crate::verify::NoiseParams;
+    use hyper::StatusCode;
+    use rand::thread_rng;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn follows_query() {
+        let mut statuses = Vec::new();
+        let mut urls = Vec::new();
+        for max_records in [100, 50, 200] {
+            let status = Arc::new(Status::default());
+            let tokens =
+                Tokens::default().with_token("token", Caller::new("acme", Role::Collector));
+            let router = status_router(Arc::clone(&status), tokens).merge(capabilities_router(
+                Capabilities::new(1).with_max_records(Some(max_records)),
+            ));
+            let (addr, _) =
+                serve_mpc_helper(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+            statuses.push(status);
+            urls.push(format!("http://{addr}/"));
+        }
+        let collector = Collector::new([&urls[0], &urls[1], &urls[2]]).unwrap();
+        assert!(matches!(
+            collector.status("q1").await,
+            Err(Error::Status {
+                status: StatusCode::UNAUTHORIZED,
+                ..
+            })
+        ));
+        let collector = collector.with_token("token");
+
+        assert_eq!(
+            Some(50),
+            collector.capabilities().await.unwrap().max_records
+        );
+
+        let progress = Arc::new(QueryProgress::default());
+        progress.set_stage("sort");
+        let rejections = Rejections {
+            rejected: 2,
+            reasons: [(RejectionReason::Width, 2)].into_iter().collect(),
+            sample: vec![3, 8],
+        };
+        progress.set_rejections(rejections.clone());
+        let running = statuses[0]
+            .admit("acme", "q1", 1, 0, Arc::clone(&progress))
+            .unwrap();
+        statuses[1]
+            .admit("acme", "q1", 1, 0, Arc::default())
+            .unwrap()
+            .suspend();
+        let status = collector.status("q1").await.unwrap();
+        assert!(matches!(&status[0], QueryStatus::Running(q) if q.stage == "sort"));
+        assert!(matches!(&status[0], QueryStatus::Running(q) if q.rejections == Some(rejections)));
+        assert_eq!(
+            [QueryStatus::Suspended, QueryStatus::NotRunning],
+            status[1..]
+        );
+
+        let (status, ()) = tokio::join!(collector.wait("q1", Duration::from_millis(10)), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(running);
+        });
+        assert_eq!(QueryStatus::NotRunning, status.unwrap()[0]);
+
+        let progress = Arc::new(QueryProgress::default());
+        let _running = statuses[2]
+            .admit("acme", "q2", 1, 0, Arc::clone(&progress))
+            .unwrap();
+        assert!(collector.cancel("q2").await.unwrap());
+        assert!(progress.cancel_requested());
+        assert!(!collector.cancel("q3").await.unwrap());
+    }
+
+    #[test]
+    fn split_and_verify() {
+        let values = [3_u128, 0, 17].map(Fp31::from);
+        let mut shares = Collector::split(&values, &mut thread_rng());
+        assert_eq!(values.to_vec(), Collector::reconstruct(&shares).unwrap());
+
+        let report = Collector::verify(&[3, 0, 17], &shares, &NoiseParams::NONE).unwrap();
+        assert!(report.is_consistent());
+        assert!(matches!(
+            Collector::verify(&[3, 0], &shares, &NoiseParams::NONE),
+            Err(Error::BucketMismatch {
+                expected: 2,
+                actual: 3
+            })
+        ));
+
+        shares[1][2] = shares[1][1];
+        assert!(matches!(
+            Collector::reconstruct(&shares),
+            Err(Error::Inconsistent(2))
+        ));
+        shares[2].pop();
+        assert!(matches!(
+            Collector::reconstruct(&shares),
+            Err(Error::LengthMismatch([3, 3, 2]))
+        ));
+    }
+}
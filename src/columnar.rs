@@ -0,0 +1,212 @@
+//!
+//! Columnar layout of bit-decomposed shares, as they come out of modulus conversion and go into
+//! the sort. Every pass of a radix sort looks at one bit of every row, which in a row-oriented
+//! layout means jumping from row to row and touching a cache line for one share each time. Here
+//! the shares of bit 0 of all rows are stored together, followed by those of bit 1 and so on, so
+//! that a pass is a sequential scan of a single column.
+//!
+//! Conversion produces rows one at a time, which [`BitColumns::push_row`] scatters into the
+//! columns. Columns can be persisted between the two with [`BitColumns::write_to`], which keeps
+//! the same order on disk.
+//!
+use crate::error::BoxError;
+use crate::field::Field;
+use crate::helpers::codec::{field_size, read_shares, write_shares};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use bytes::{Buf, BufMut};
+use thiserror::Error;
+
+/// Bytes before the columns in the encoding of [`BitColumns::write_to`]: the number of bits as a
+/// `u32` and the number of rows as a `u64`, both little-endian.
+const HEADER_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("expected rows of {expected} bits, but got one with {actual}")]
+    Width { expected: usize, actual: usize },
+}
+
+/// Shares of the bits of a number of rows, stored column by column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitColumns<F> {
+    columns: Vec<Vec<ReplicatedSecretSharing<F>>>,
+    rows: usize,
+}
+
+impl<F: Field> BitColumns<F> {
+    /// Empty columns for rows of `bits` bits, with room for `rows` of them.
+    #[must_use]
+    pub fn with_capacity(bits: usize, rows: usize) -> Self {
+        Self {
+            columns: (0..bits).map(|_| Vec::with_capacity(rows)).collect(),
+            rows: 0,
+        }
+    }
+
+    /// Columns of `rows`, each of which holds the shares of its bits, least significant first.
+    ///
+    /// ## Errors
+    /// If rows have different numbers of bits.
+    pub fn from_rows<R: AsRef<[ReplicatedSecretSharing<F>]>>(rows: &[R]) -> Result<Self, Error> {
+        let bits = rows.first().map_or(0, |r| r.as_ref().len());
+        let mut columns = Self::with_capacity(bits, rows.len());
+        for row in rows {
+            columns.push_row(row.as_ref())?;
+        }
+        Ok(columns)
+    }
+
+    /// Appends the shares of the bits of a row, least significant first.
+    ///
+    /// ## Errors
+    /// If the row has a different number of bits than the columns.
+    pub fn push_row(&mut self, bits: &[ReplicatedSecretSharing<F>]) -> Result<(), Error> {
+        if bits.len() != self.columns.len() {
+            return Err(Error::Width {
+                expected: self.columns.len(),
+                actual: bits.len(),
+            });
+        }
+        for (column, bit) in self.columns.iter_mut().zip(bits) {
+            column.push(*bit);
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn bits(&self) -> usize {
+        self.columns.len()
+    }
+
+    #[must_use]
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Shares of `bit` of every row, in row order.
+    ///
+    /// ## Panics
+    /// If `bit` is out of range.
+    #[must_use]
+    pub fn column(&self, bit: usize) -> &[ReplicatedSecretSharing<F>] {
+        &self.columns[bit]
+    }
+
+    /// Shares of the bits of row `index`, gathered from all columns.
+    ///
+    /// ## Panics
+    /// If `index` is out of range.
+    #[must_use]
+    pub fn row(&self, index: usize) -> Vec<ReplicatedSecretSharing<F>> {
+        self.columns.iter().map(|c| c[index]).collect()
+    }
+
+    /// Number of bytes [`Self::write_to`] writes.
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.bits() * self.rows * 2 * field_size::<F>()
+    }
+
+    /// Writes the columns to the end of `out`, one after the other, encoded the same way as
+    /// [`write_shares`] does.
+    ///
+    /// ## Panics
+    /// If there are more than `u32::MAX` bits.
+    pub fn write_to<B: BufMut>(&self, out: &mut B) {
+        out.put_u32_le(u32::try_from(self.bits()).unwrap());
+        out.put_u64_le(self.rows as u64);
+        for column in &self.columns {
+            write_shares(column, out);
+        }
+    }
+
+    /// Reads columns written by [`Self::write_to`].
+    ///
+    /// ## Errors
+    /// If `bytes` are shorter or longer than the header says, or some value is not an element
+    /// of the field.
+    pub fn read_from(bytes: &[u8]) -> Result<Self, BoxError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(format!("{} bytes do not hold a header", bytes.len()).into());
+        }
+        let (mut header, body) = bytes.split_at(HEADER_LEN);
+        let bits = header.get_u32_le() as usize;
+        let rows = usize::try_from(header.get_u64_le())?;
+        let column_len = rows
+            .checked_mul(2 * field_size::<F>())
+            .ok_or("too many rows")?;
+        if Some(body.len()) != bits.checked_mul(column_len) {
+            return Err(format!(
+                "{} bytes do not hold {bits} columns of {rows} shares",
+                body.len()
+            )
+            .into());
+        }
+        let columns = if column_len == 0 {
+            vec![Vec::new(); bits]
+        } else {
+            body.chunks_exact(column_len)
+                .map(read_shares)
+                .collect::<Result<_, _>>()?
+        };
+        Ok(Self { columns, rows })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::columnar::{BitColumns, Error};
+    use crate::field::{Field, Fp31};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+
+    fn share(v: u128) -> ReplicatedSecretSharing<Fp31> {
+        ReplicatedSecretSharing::new(Fp31::from(v), Fp31::ZERO)
+    }
+
+    fn rows() -> Vec<Vec<ReplicatedSecretSharing<Fp31>>> {
+        [[1, 0, 1], [0, 0, 1], [1, 1, 0], [0, 1, 1]]
+            .iter()
+            .map(|r| r.iter().map(|b| share(*b)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn columns() {
+        let rows = rows();
+        let columns = BitColumns::from_rows(&rows).unwrap();
+        assert_eq!((3, 4), (columns.bits(), columns.rows()));
+        assert_eq!(&[share(1), share(0), share(1), share(0)], columns.column(0));
+        assert_eq!(&[share(1), share(1), share(0), share(1)], columns.column(2));
+        assert_eq!(rows[2], columns.row(2));
+
+        let mut columns = columns;
+        assert!(matches!(
+            columns.push_row(&rows[0][..2]),
+            Err(Error::Width {
+                expected: 3,
+                actual: 2
+            })
+        ));
+        assert_eq!(4, columns.rows());
+    }
+
+    #[test]
+    fn round_trip() {
+        let columns = BitColumns::from_rows(&rows()).unwrap();
+        let mut bytes = Vec::new();
+        columns.write_to(&mut bytes);
+        assert_eq!(columns.encoded_len(), bytes.len());
+        // bit 0 of every row comes first, after the header
+        assert_eq!(&[1, 0, 0, 0, 1, 0, 0, 0], &bytes[12..20]);
+        assert_eq!(columns, BitColumns::read_from(&bytes).unwrap());
+
+        assert!(BitColumns::<Fp31>::read_from(&bytes[..bytes.len() - 1]).is_err());
+        assert!(BitColumns::<Fp31>::read_from(&bytes[..5]).is_err());
+
+        let empty = BitColumns::<Fp31>::with_capacity(4, 0);
+        let mut bytes = Vec::new();
+        empty.write_to(&mut bytes);
+        assert_eq!(empty, BitColumns::read_from(&bytes).unwrap());
+    }
+}
@@ -0,0 +1,185 @@
+//!
+//! Commitments to the input shares of a query, which helpers compare before the protocol
+//! starts. An upload that was truncated or mixed up on the way to one of the helpers would
+//! otherwise go unnoticed and produce a wrong aggregate.
+//!
+//! Every helper holds two of the three parts of a value, and each of them is also held by one
+//! of its neighbours: the right part of a helper is the left part of the helper on its right.
+//! Helpers hash their left and right parts separately, along with the number of values, and
+//! send the digest of their right parts to the helper on the right, which compares it with the
+//! digest of its own left parts. Input may be committed to in chunks, as it arrives, and it is
+//! checked once in a single round.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::codec::write_fields;
+use crate::helpers::ring::{message, HelperAddr, Ring};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::step;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Debug, Formatter};
+use thiserror::Error;
+
+const STEP: &str = step::INPUT_COMMITMENT;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("helper on the left has {actual} input values, but this helper has {expected}")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("helper on the left has different input shares than this helper")]
+    Mismatch,
+}
+
+/// Digest of the parts of input values that a helper shares with the helper on its right.
+#[derive(Debug, Serialize, Deserialize)]
+struct InputDigest {
+    count: u64,
+    digest: [u8; 32],
+}
+
+message!(InputDigest => "commitment.input-digest");
+
+/// Commitment of a helper to its input shares.
+#[derive(Clone, Default)]
+pub struct InputCommitment {
+    count: u64,
+    left: Sha256,
+    right: Sha256,
+}
+
+impl Debug for InputCommitment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InputCommitment")
+            .field("count", &self.count)
+            .finish_non_exhaustive()
+    }
+}
+
+impl InputCommitment {
+    /// Adds the next chunk of input `shares` to the commitment.
+    pub fn add<F: Field>(&mut self, shares: &[ReplicatedSecretSharing<F>]) {
+        let (left, right): (Vec<F>, Vec<F>) =
+            shares.iter().map(ReplicatedSecretSharing::as_tuple).unzip();
+        let mut bytes = Vec::new();
+        write_fields(&left, &mut bytes);
+        self.left.update(&bytes);
+        bytes.clear();
+        write_fields(&right, &mut bytes);
+        self.right.update(&bytes);
+        self.count += shares.len() as u64;
+    }
+
+    /// Number of values committed to so far.
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Checks that the helper on the left committed to the same left parts as this one, and
+    /// lets the helper on the right check the right parts. All three helpers must call this at
+    /// the same point of the query.
+    ///
+    /// ## Errors
+    /// If communication with peers fails, or the helper on the left has a different number of
+    /// values or different shares of them.
+    pub async fn check<R: Ring>(self, ctx: &ProtocolContext<'_, R>) -> Res<()> {
+        let mine = InputDigest {
+            count: self.count,
+            digest: self.right.finalize().into(),
+        };
+        let expected_left: [u8; 32] = self.left.finalize().into();
+
+        let _round = ctx.rounds.map(|rounds| rounds.wait(STEP));
+        let ((), theirs) = futures::try_join!(
+            ctx.helper_ring.send(HelperAddr::Right, mine),
+            ctx.helper_ring.receive::<InputDigest>(HelperAddr::Left),
+        )?;
+
+        if theirs.count != self.count {
+            return Err(Error::Truncated {
+                expected: self.count,
+                actual: theirs.count,
+            }
+            .into());
+        }
+        if theirs.digest != expected_left {
+            return Err(Error::Mismatch.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commitment::{Error as CommitmentError, InputCommitment};
+    use crate::error::Error;
+    use crate::field::Fp31;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::test_fixture::TestWorld;
+    use rand::rngs::mock::StepRng;
+
+    fn shares(values: &[u128]) -> [Vec<ReplicatedSecretSharing<Fp31>>; 3] {
+        let mut rand = StepRng::new(1, 7);
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for v in values {
+            let shared = ReplicatedSecretSharing::share(Fp31::from(*v), &mut rand);
+            for (helper, share) in shares.iter_mut().zip(shared) {
+                helper.push(share);
+            }
+        }
+        shares
+    }
+
+    /// Every helper commits to its input in two chunks and checks the commitment.
+    async fn check(input: [Vec<ReplicatedSecretSharing<Fp31>>; 3]) -> [Result<(), Error>; 3] {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let commit = |shares: &[ReplicatedSecretSharing<Fp31>]| {
+            let mut commitment = InputCommitment::default();
+            let (first, second) = shares.split_at(shares.len() / 2);
+            commitment.add(first);
+            commitment.add(second);
+            commitment
+        };
+        let (r0, r1, r2) = futures::join!(
+            commit(&input[0]).check(&ctx[0]),
+            commit(&input[1]).check(&ctx[1]),
+            commit(&input[2]).check(&ctx[2]),
+        );
+        [r0, r1, r2]
+    }
+
+    #[tokio::test]
+    async fn consistent() {
+        let input = shares(&[1, 2, 3, 4, 5]);
+        assert!(check(input).await.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let mut input = shares(&[1, 2, 3, 4, 5]);
+        input[0].pop();
+        // helper 1 is on the left of helper 2, which notices
+        let [_, r1, _] = check(input).await;
+        assert!(matches!(
+            r1,
+            Err(Error::Commitment(CommitmentError::Truncated {
+                expected: 5,
+                actual: 4
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn mismatch() {
+        let mut input = shares(&[1, 2, 3, 4, 5]);
+        input[2].swap(0, 1);
+        let [r0, _, _] = check(input).await;
+        assert!(matches!(
+            r0,
+            Err(Error::Commitment(CommitmentError::Mismatch))
+        ));
+    }
+}
@@ -0,0 +1,410 @@
+//!
+//! Conformance of helpers implemented independently of this crate. A conformance run connects
+//! two helpers of this crate with the helper under test, over the same TCP transport helpers use
+//! for queries (see [`crate::helpers::tcp`]), and runs the query of every vector in
+//! [`crate::test_vectors::ipa`] on the three of them, one [`Stage`] at a time. The [`Report`]
+//! says which stages the helper under test interoperates in.
+//!
+//! The two helpers of this crate sit next to each other in the ring, so together they hold all
+//! three parts of every value they share: the outputs of a stage reconstruct without the helper
+//! under test, and are checked against the digests of the vector. Both of them must also agree
+//! on the part they share. Stages that compute the wrong values fail, and the run goes on with
+//! the next one. A stage that fails to communicate, or does not finish in time, fails every
+//! stage after it, as the helpers are no longer in step.
+//!
+//! For every vector in [`VECTORS`](crate::test_vectors::ipa::VECTORS), in order, all three
+//! helpers go through every stage in [`Stage::ALL`], as [`run_helper`] does:
+//!
+//! * `prss`: every helper sets up PRSS for the vector with fresh keys, and sends its peers the
+//!   [`PrssKey`] of the pair, the one for the left of its [`ParticipantSetup::public_keys`] to
+//!   the helper on its left and the other one to the helper on its right. There is no other key
+//!   exchange between helpers in this crate yet.
+//! * `roles`: [`check_roles`] for stage `conformance`.
+//! * `ingest`, `contributions`, `histogram` and `reveal`: the stages of
+//!   [`VectorQuery`], on the shares of the vector the helper gets.
+//!
+use crate::error::Res;
+use crate::field::Fp31;
+use crate::helpers::ring::{message, HelperAddr, Identity, Ring};
+use crate::prss::{Participant, ParticipantSetup};
+use crate::roles::check_roles;
+use crate::securemul::ProtocolContext;
+use crate::test_vectors::ipa::{digest, Share, Vector, VectorQuery};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use x25519_dalek::PublicKey;
+
+/// Stage of the query of a vector, in the order helpers run them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Prss,
+    Roles,
+    Ingest,
+    Contributions,
+    Histogram,
+    Reveal,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Prss,
+        Stage::Roles,
+        Stage::Ingest,
+        Stage::Contributions,
+        Stage::Histogram,
+        Stage::Reveal,
+    ];
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Prss => "prss",
+            Self::Roles => "roles",
+            Self::Ingest => "ingest",
+            Self::Contributions => "contributions",
+            Self::Histogram => "histogram",
+            Self::Reveal => "reveal",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Public key a helper sends a peer to set up the PRSS they share.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PrssKey(pub [u8; 32]);
+
+message!(PrssKey => "conformance.prss-key");
+
+/// How a stage went for the helper under test.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "outcome", content = "reason")]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// An earlier stage failed to communicate.
+    Skipped,
+}
+
+/// Outcome of a stage of the query of a single vector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageResult {
+    pub vector: String,
+    pub stage: Stage,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// Stages the helper under test interoperates in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    /// Identity of the helper under test.
+    pub external: Identity,
+    /// Whether the helpers could connect to it at all.
+    pub connect: Outcome,
+    pub stages: Vec<StageResult>,
+}
+
+impl Report {
+    /// Report of a helper that could not be connected to, for `reason`.
+    #[must_use]
+    pub fn unconnected(external: Identity, reason: String) -> Self {
+        let mut report = Self {
+            external,
+            connect: Outcome::Failed(reason),
+            stages: Vec::new(),
+        };
+        report.skip_rest();
+        report
+    }
+
+    /// Whether the helper under test passed every stage.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.connect == Outcome::Passed && self.stages.iter().all(|s| s.outcome == Outcome::Passed)
+    }
+
+    /// Skips every stage that has no outcome yet.
+    fn skip_rest(&mut self) {
+        for (name, _) in Vector::all() {
+            for stage in Stage::ALL {
+                let done = self
+                    .stages
+                    .iter()
+                    .any(|s| s.vector == name && s.stage == stage);
+                if !done {
+                    self.stages.push(StageResult {
+                        vector: name.to_owned(),
+                        stage,
+                        outcome: Outcome::Skipped,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "helper under test: {}", self.external)?;
+        let line = |f: &mut Formatter<'_>, name: &str, outcome: &Outcome| match outcome {
+            Outcome::Passed => writeln!(f, "{name:<32} passed"),
+            Outcome::Failed(reason) => writeln!(f, "{name:<32} FAILED: {reason}"),
+            Outcome::Skipped => writeln!(f, "{name:<32} skipped"),
+        };
+        line(f, "connect", &self.connect)?;
+        for s in &self.stages {
+            line(f, &format!("{} {}", s.vector, s.stage), &s.outcome)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a stage computes on a single helper.
+#[derive(Debug)]
+enum Output {
+    Nothing,
+    Shares(Vec<Share>),
+    Revealed(Vec<Fp31>),
+}
+
+/// A helper of a conformance run, with the PRSS of the vector it is at.
+struct Helper<'a, R> {
+    identity: Identity,
+    ring: &'a R,
+    participant: Option<Participant>,
+}
+
+impl<'a, R: Ring> Helper<'a, R> {
+    fn new(identity: Identity, ring: &'a R) -> Self {
+        Self {
+            identity,
+            ring,
+            participant: None,
+        }
+    }
+
+    async fn exchange_keys(&self) -> Res<Participant> {
+        let setup = ParticipantSetup::new(&mut thread_rng());
+        let (left, right) = setup.public_keys();
+        self.ring
+            .send(HelperAddr::Left, PrssKey(left.to_bytes()))
+            .await?;
+        self.ring
+            .send(HelperAddr::Right, PrssKey(right.to_bytes()))
+            .await?;
+        let left = self.ring.receive::<PrssKey>(HelperAddr::Left).await?;
+        let right = self.ring.receive::<PrssKey>(HelperAddr::Right).await?;
+        Ok(setup.setup(&PublicKey::from(left.0), &PublicKey::from(right.0)))
+    }
+
+    async fn run(&mut self, stage: Stage, query: &mut VectorQuery<'_>) -> Res<Output> {
+        if stage == Stage::Prss {
+            self.participant = Some(self.exchange_keys().await?);
+            return Ok(Output::Nothing);
+        }
+        let ctx =
+            ProtocolContext::new(self.identity, self.participant.as_ref().unwrap(), self.ring);
+        Ok(match stage {
+            Stage::Prss => unreachable!(),
+            Stage::Roles => {
+                check_roles(&ctx, "conformance").await?;
+                Output::Nothing
+            }
+            Stage::Ingest => Output::Shares(query.ingest(&ctx).await?),
+            Stage::Contributions => Output::Shares(query.contributions(&ctx).await?),
+            Stage::Histogram => Output::Shares(query.histogram(&ctx).await?),
+            Stage::Reveal => Output::Revealed(query.reveal(&ctx).await?),
+        })
+    }
+}
+
+/// Runs a single helper of a conformance run, `identity` on `ring`, the same way this crate
+/// expects the helper under test to.
+///
+/// ## Errors
+/// If communication with peers fails.
+pub async fn run_helper<R: Ring>(identity: Identity, ring: &R) -> Res<()> {
+    let mut helper = Helper::new(identity, ring);
+    for (_, vector) in Vector::all() {
+        let mut query = VectorQuery::new(&vector, identity);
+        for stage in Stage::ALL {
+            helper.run(stage, &mut query).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks what the outputs of both helpers of this crate reconstruct to. `a` is the helper on
+/// the left of `b`.
+fn verify(vector: &Vector, stage: Stage, a: Output, b: Output) -> Result<(), String> {
+    let expected = match stage {
+        Stage::Ingest => &vector.stages.ingest,
+        Stage::Contributions => &vector.stages.contributions,
+        Stage::Histogram => &vector.stages.histogram,
+        _ => "",
+    };
+    match (a, b) {
+        (Output::Nothing, Output::Nothing) => Ok(()),
+        (Output::Shares(a), Output::Shares(b)) => {
+            if a.len() != b.len() {
+                return Err(format!("helpers have {} and {} shares", a.len(), b.len()));
+            }
+            let parts = a
+                .iter()
+                .zip(&b)
+                .map(|(a, b)| (a.as_tuple(), b.as_tuple()))
+                .collect::<Vec<_>>();
+            if let Some(i) = parts.iter().position(|((_, ar), (bl, _))| ar != bl) {
+                return Err(format!(
+                    "helpers disagree on the part of value {i} they share"
+                ));
+            }
+            let values = parts
+                .iter()
+                .map(|&((al, ar), (_, br))| al + ar + br)
+                .collect::<Vec<_>>();
+            let actual = digest(&values);
+            if actual == *expected {
+                Ok(())
+            } else {
+                Err(format!("values have digest {actual}, expected {expected}"))
+            }
+        }
+        (Output::Revealed(a), Output::Revealed(b)) => {
+            let expected = vector
+                .histogram
+                .iter()
+                .map(|&v| Fp31::from(v))
+                .collect::<Vec<_>>();
+            if a == expected && b == expected {
+                Ok(())
+            } else {
+                Err(format!("revealed {a:?} and {b:?}, expected {expected:?}"))
+            }
+        }
+        (a, b) => Err(format!("helpers computed {a:?} and {b:?}")),
+    }
+}
+
+/// Runs the helpers of this crate against the helper under test, `external`, and reports which
+/// stages it passed. `right` and `left` are the rings of the helpers on the right and on the
+/// left of it, connected to it and to each other. Every stage that does not finish within
+/// `stage_timeout` fails.
+pub async fn check<R: Ring>(
+    external: Identity,
+    right: &R,
+    left: &R,
+    stage_timeout: Duration,
+) -> Report {
+    let mut report = Report {
+        external,
+        connect: Outcome::Passed,
+        stages: Vec::new(),
+    };
+    // `a` is on the left of `b`, and `b` on the left of the helper under test
+    let mut a = Helper::new(external.peer(HelperAddr::Right), right);
+    let mut b = Helper::new(external.peer(HelperAddr::Left), left);
+    for (name, vector) in Vector::all() {
+        let mut qa = VectorQuery::new(&vector, a.identity);
+        let mut qb = VectorQuery::new(&vector, b.identity);
+        for stage in Stage::ALL {
+            let run = async { futures::try_join!(a.run(stage, &mut qa), b.run(stage, &mut qb)) };
+            let (outcome, in_step) = match tokio::time::timeout(stage_timeout, run).await {
+                Ok(Ok((oa, ob))) => match verify(&vector, stage, oa, ob) {
+                    Ok(()) => (Outcome::Passed, true),
+                    Err(reason) => (Outcome::Failed(reason), true),
+                },
+                Ok(Err(e)) => (Outcome::Failed(e.to_string()), false),
+                Err(_) => (
+                    Outcome::Failed(format!("did not finish in {stage_timeout:?}")),
+                    false,
+                ),
+            };
+            report.stages.push(StageResult {
+                vector: name.to_owned(),
+                stage,
+                outcome,
+            });
+            if !in_step {
+                report.skip_rest();
+                return report;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance::{check, run_helper, Outcome, Report, Stage};
+    use crate::helpers::ring::mock::make_three;
+    use crate::helpers::ring::Identity;
+    use crate::test_vectors::ipa::Vector;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn conforming_helper() {
+        for (e, external) in Identity::ALL.into_iter().enumerate() {
+            let rings = make_three();
+            let (right, left) = ((e + 1) % 3, (e + 2) % 3);
+            let (report, helper) = futures::join!(
+                check(external, &rings[right], &rings[left], TIMEOUT),
+                run_helper(external, &rings[e]),
+            );
+            helper.unwrap();
+            assert!(report.passed(), "{report}");
+            assert_eq!(Vector::all().len() * Stage::ALL.len(), report.stages.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_helper() {
+        let rings = make_three();
+        // the helper under test never sends its keys
+        let report = check(
+            Identity::H1,
+            &rings[1],
+            &rings[2],
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(!report.passed());
+        assert!(matches!(report.stages[0].outcome, Outcome::Failed(_)));
+        assert!(report.stages[1..]
+            .iter()
+            .all(|s| s.outcome == Outcome::Skipped));
+        assert_eq!(Vector::all().len() * Stage::ALL.len(), report.stages.len());
+    }
+
+    #[tokio::test]
+    async fn wrong_identity() {
+        let rings = make_three();
+        // H1 of the ring runs as if it were H3
+        let (report, helper) = futures::join!(
+            check(Identity::H1, &rings[1], &rings[2], TIMEOUT),
+            run_helper(Identity::H3, &rings[0]),
+        );
+        assert!(helper.is_err());
+        assert_eq!(Outcome::Passed, report.stages[0].outcome);
+        assert_eq!(Stage::Roles, report.stages[1].stage);
+        assert!(matches!(report.stages[1].outcome, Outcome::Failed(_)));
+        assert_eq!(Outcome::Skipped, report.stages[2].outcome);
+    }
+
+    #[test]
+    fn unconnected() {
+        let report = Report::unconnected(Identity::H2, "refused".to_owned());
+        assert!(!report.passed());
+        assert!(report.to_string().contains("connect"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!("failed", json["connect"]["outcome"]);
+        assert_eq!("skipped", json["stages"][0]["outcome"]);
+        assert_eq!("prss", json["stages"][0]["stage"]);
+    }
+}
@@ -0,0 +1,426 @@
+//!
+//! Contexts that protocols are written against, so that the same code runs with either semi-honest
+//! or malicious security. A protocol that takes `C: Context<F>` computes on `C::Share` values and
+//! multiplies them with the context, and is oblivious to how shares are protected.
+//!
+//! [`SemiHonestContext`] is the plain [`ProtocolContext`], whose shares are replicated sharings.
+//! [`MaliciousContext`] wraps one and protects every value with a MAC: a sharing of the value
+//! multiplied by a secret key `r` that no helper knows. Inputs are moved into it with
+//! [`MaliciousContext::upgrade`]. Every multiplication is done on both the value and its MAC, and
+//! [`MaliciousContext::validate`] checks all of them at once before any output is handed back:
+//! a helper that adds an error to a product cannot add the matching error to its MAC without
+//! knowing `r`.
+//!
+//! The check is a random linear combination of all products, repeated often enough to catch an
+//! error with probability `1 - 2^-40` whatever the key is. It still misses errors with
+//! probability `1/p` over the choice of the key itself, which is significant in small fields.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::{Identity, Ring};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::reveal::{reveal, reveal_vec};
+use crate::securemul::ProtocolContext;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Probability with which [`MaliciousContext::validate`] may miss an error for a given key is
+/// `2^-SECURITY_BITS` at most.
+const SECURITY_BITS: u32 = 40;
+
+/// Number of times [`MaliciousContext::validate`] checks the products in a field with `prime`
+/// elements.
+pub(crate) fn mac_checks(prime: u128) -> u32 {
+    // floor(log2(p)) bits of security per check
+    let bits = u128::BITS - prime.leading_zeros() - 1;
+    (SECURITY_BITS + bits - 1) / bits
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("MAC check failed, some helper did not follow the protocol")]
+    MacCheck,
+}
+
+/// Computation available to protocols in both security modes.
+#[async_trait]
+pub trait Context<F: Field>: Sync {
+    /// Share of a value, in the form this context protects it.
+    type Share: Copy
+        + Debug
+        + Send
+        + Sync
+        + Add<Output = Self::Share>
+        + Sub<Output = Self::Share>
+        + Neg<Output = Self::Share>
+        + Mul<F, Output = Self::Share>;
+
+    /// Helper that executes the protocol.
+    fn identity(&self) -> Identity;
+
+    /// Share of `value`, which is known to every helper.
+    fn share_known(&self, value: F) -> Self::Share;
+
+    /// Multiplies every `a[i]` by `b[i]` in one round. Product `i` uses index `index + i`, none
+    /// of these may be reused.
+    ///
+    /// ## Errors
+    /// If `a` and `b` have different lengths, or communication with peers fails.
+    async fn multiply_batch(
+        &self,
+        index: u128,
+        a: &[Self::Share],
+        b: &[Self::Share],
+    ) -> Res<Vec<Self::Share>>;
+}
+
+/// Context with semi-honest security, which trusts helpers to follow the protocol.
+pub type SemiHonestContext<'a, R> = ProtocolContext<'a, R>;
+
+#[async_trait]
+impl<F: Field, R: Ring> Context<F> for ProtocolContext<'_, R> {
+    type Share = ReplicatedSecretSharing<F>;
+
+    fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    fn share_known(&self, value: F) -> Self::Share {
+        ProtocolContext::share_known(self, value)
+    }
+
+    async fn multiply_batch(
+        &self,
+        index: u128,
+        a: &[Self::Share],
+        b: &[Self::Share],
+    ) -> Res<Vec<Self::Share>> {
+        ProtocolContext::multiply_batch(self, index, a, b).await
+    }
+}
+
+/// Share of a value together with a share of its MAC, `r * x`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaliciousReplicated<F> {
+    x: ReplicatedSecretSharing<F>,
+    rx: ReplicatedSecretSharing<F>,
+}
+
+impl<F: Field> Add for MaliciousReplicated<F> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            x: self.x + rhs.x,
+            rx: self.rx + rhs.rx,
+        }
+    }
+}
+
+impl<F: Field> Sub for MaliciousReplicated<F> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            rx: self.rx - rhs.rx,
+        }
+    }
+}
+
+impl<F: Field> Neg for MaliciousReplicated<F> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            x: -self.x,
+            rx: -self.rx,
+        }
+    }
+}
+
+impl<F: Field> Mul<F> for MaliciousReplicated<F> {
+    type Output = Self;
+
+    fn mul(self, rhs: F) -> Self {
+        Self {
+            x: self.x * rhs,
+            rx: self.rx * rhs,
+        }
+    }
+}
+
+/// A value and its MAC that came out of a multiplication and must be checked, along with the
+/// first PRSS index of the coefficients it gets in the check.
+#[derive(Debug)]
+struct Product<F> {
+    value: MaliciousReplicated<F>,
+    coefficients: u128,
+    stride: u128,
+}
+
+/// Context with security against a malicious helper, which aborts the protocol when it
+/// deviates from it. See the module documentation.
+///
+/// Every index of a malicious context stands for several PRSS indices of the context it wraps,
+/// two for the multiplication and one for every repetition of the check, starting right after
+/// the index of the key. A malicious context created with `index` therefore
+/// takes all PRSS indices from `index` on, and the context it wraps must use lower ones only.
+#[derive(Debug)]
+pub struct MaliciousContext<'a, F, R> {
+    inner: &'a ProtocolContext<'a, R>,
+    r: ReplicatedSecretSharing<F>,
+    base: u128,
+    products: Mutex<Vec<Product<F>>>,
+}
+
+impl<'a, F: Field, R: Ring> MaliciousContext<'a, F, R> {
+    /// Number of times the linear combination of all products is checked.
+    fn checks() -> u128 {
+        u128::from(mac_checks(F::PRIME.into()))
+    }
+
+    /// PRSS indices of the wrapped context per index of this one.
+    fn slots() -> u128 {
+        2 + Self::checks()
+    }
+
+    /// Draws the key at PRSS index `index` of `inner`, see [`MaliciousContext`] for the indices
+    /// it takes.
+    #[must_use]
+    pub fn new(inner: &'a ProtocolContext<'a, R>, index: u128) -> Self {
+        Self {
+            inner,
+            r: inner.prss_random_share(index),
+            base: index + 1,
+            products: Mutex::default(),
+        }
+    }
+
+    fn inner_index(&self, index: u128) -> u128 {
+        self.base + index * Self::slots()
+    }
+
+    fn record(&self, start: u128, values: &[MaliciousReplicated<F>]) {
+        let n = values.len() as u128;
+        let mut products = self.products.lock().unwrap();
+        products.extend(values.iter().zip(0..).map(|(&value, i)| Product {
+            value,
+            // coefficients follow the two multiplications of the batch
+            coefficients: start + 2 * n + i,
+            stride: n,
+        }));
+    }
+
+    /// Moves semi-honest `shares` into this context by computing their MACs. Takes `index` up to
+    /// `index + shares.len()`, like a multiplication.
+    ///
+    /// ## Errors
+    /// If multiplication fails.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub async fn upgrade(
+        &self,
+        index: u128,
+        shares: &[ReplicatedSecretSharing<F>],
+    ) -> Res<Vec<MaliciousReplicated<F>>> {
+        let start = self.inner_index(index);
+        let keys = vec![self.r; shares.len()];
+        let macs = self.inner.multiply_batch(start, &keys, shares).await?;
+        let upgraded = shares
+            .iter()
+            .zip(macs)
+            .map(|(&x, rx)| MaliciousReplicated { x, rx })
+            .collect::<Vec<_>>();
+        self.record(start, &upgraded);
+        Ok(upgraded)
+    }
+
+    /// Checks every multiplication done so far and, if all of them are correct, returns the
+    /// semi-honest shares of `outputs`. Takes indices `index` and `index + 1`.
+    ///
+    /// ## Errors
+    /// If the check fails, which means that some helper did not follow the protocol, or
+    /// communication with peers fails.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub async fn validate(
+        self,
+        index: u128,
+        outputs: &[MaliciousReplicated<F>],
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let start = self.inner_index(index);
+        let products = self.products.into_inner().unwrap();
+        let (values, macs): (Vec<_>, Vec<_>) =
+            products.iter().map(|p| (p.value.x, p.value.rx)).unzip();
+
+        let mut checks = Vec::new();
+        for k in 0..Self::checks() {
+            let coefficients = products
+                .iter()
+                .map(|p| self.inner.prss_random_share(p.coefficients + k * p.stride))
+                .collect::<Vec<_>>();
+            let u = self
+                .inner
+                .sum_of_products(start + 2 * k, &coefficients, &macs)
+                .await?;
+            let w = self
+                .inner
+                .sum_of_products(start + 2 * k + 1, &coefficients, &values)
+                .await?;
+            checks.push((u, w));
+        }
+
+        // the key is not needed anymore once every product is fixed
+        let r = reveal(self.inner, start, self.r).await?;
+        let checks = checks
+            .into_iter()
+            .map(|(u, w)| u - w * r)
+            .collect::<Vec<_>>();
+        let revealed = reveal_vec(self.inner, start + 1, &checks).await?;
+        if revealed.iter().any(|c| *c != F::ZERO) {
+            return Err(Error::MacCheck.into());
+        }
+
+        Ok(outputs.iter().map(|o| o.x).collect())
+    }
+}
+
+#[async_trait]
+impl<F: Field, R: Ring> Context<F> for MaliciousContext<'_, F, R> {
+    type Share = MaliciousReplicated<F>;
+
+    fn identity(&self) -> Identity {
+        self.inner.identity
+    }
+
+    fn share_known(&self, value: F) -> Self::Share {
+        let x = self.inner.share_known(value);
+        MaliciousReplicated {
+            x,
+            rx: self.r * value,
+        }
+    }
+
+    async fn multiply_batch(
+        &self,
+        index: u128,
+        a: &[Self::Share],
+        b: &[Self::Share],
+    ) -> Res<Vec<Self::Share>> {
+        // the value and the MAC of every product are computed in the same round
+        let start = self.inner_index(index);
+        let lhs = a.iter().map(|s| s.x).chain(a.iter().map(|s| s.rx));
+        let rhs = b.iter().map(|s| s.x).cycle().take(2 * b.len());
+        let products = self
+            .inner
+            .multiply_batch(start, &lhs.collect::<Vec<_>>(), &rhs.collect::<Vec<_>>())
+            .await?;
+        let (x, rx) = products.split_at(products.len() / 2);
+        let products = x
+            .iter()
+            .zip(rx)
+            .map(|(&x, &rx)| MaliciousReplicated { x, rx })
+            .collect::<Vec<_>>();
+        self.record(start, &products);
+        Ok(products)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::{Context, MaliciousContext};
+    use crate::error::Res;
+    use crate::field::{Field, Fp31};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use futures::future::FutureExt;
+    use rand::rngs::mock::StepRng;
+
+    type Share = ReplicatedSecretSharing<Fp31>;
+
+    /// Written once, for both security modes.
+    async fn sum_of_squares<F: Field, C: Context<F>>(
+        ctx: &C,
+        index: u128,
+        values: &[C::Share],
+    ) -> Res<C::Share> {
+        let squares = ctx.multiply_batch(index, values, values).await?;
+        let zero = ctx.share_known(F::ZERO);
+        Ok(squares.into_iter().fold(zero, |acc, s| acc + s))
+    }
+
+    fn shares(values: &[u128]) -> [Vec<Share>; 3] {
+        let mut rng = StepRng::new(1, 7);
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for &v in values {
+            for (i, s) in Share::share(Fp31::from(v), &mut rng)
+                .into_iter()
+                .enumerate()
+            {
+                shares[i].push(s);
+            }
+        }
+        shares
+    }
+
+    #[tokio::test]
+    async fn semi_honest() {
+        let world = TestWorld::new();
+        let result = world
+            .run(shares(&[1, 2, 3]), |ctx, input| {
+                async move { vec![sum_of_squares(&ctx, 0, &input).await.unwrap()] }.boxed()
+            })
+            .await;
+        assert_eq!(vec![Fp31::from(14_u128)], reconstruct(&result));
+    }
+
+    #[tokio::test]
+    async fn malicious() {
+        let world = TestWorld::new();
+        let result = world
+            .run(shares(&[1, 2, 3]), |ctx, input| {
+                async move {
+                    let m = MaliciousContext::new(&ctx, 1000);
+                    let input = m.upgrade(0, &input).await.unwrap();
+                    let sum = sum_of_squares(&m, 3, &input).await.unwrap();
+                    m.validate(6, &[sum]).await.unwrap()
+                }
+                .boxed()
+            })
+            .await;
+        assert_eq!(vec![Fp31::from(14_u128)], reconstruct(&result));
+    }
+
+    /// A helper adds an error to a value, but cannot add the matching one to its MAC. The key
+    /// is zero with probability 1/31, in which case the error goes unnoticed, so several
+    /// queries are run.
+    #[tokio::test]
+    async fn malicious_catches_errors() {
+        let mut caught = 0;
+        for _ in 0..5 {
+            let world = TestWorld::new();
+            let result = world
+                .run(shares(&[1, 2, 3]), |ctx, input| {
+                    async move {
+                        let m = MaliciousContext::new(&ctx, 1000);
+                        let mut input = m.upgrade(0, &input).await.unwrap();
+                        input[0].x = input[0].x + ctx.share_known(Fp31::ONE);
+                        let sum = sum_of_squares(&m, 3, &input).await.unwrap();
+                        m.validate(6, &[sum]).await
+                    }
+                    .boxed()
+                })
+                .await;
+            if result.iter().all(Result::is_err) {
+                caught += 1;
+            }
+        }
+        assert!(caught > 0);
+    }
+}
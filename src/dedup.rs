@@ -0,0 +1,223 @@
+//!
+//! Removal of duplicate records inside MPC, after they are sorted. Ingest rejects reports it saw
+//! before, but a record that was submitted twice in different batches, or under different
+//! report ids, still gets to the query and counts twice. Once records are sorted by the
+//! columns that identify them, copies of the same record are next to each other, and every
+//! record that is identical to the one before it has its contribution zeroed.
+//!
+//! Helpers learn neither which records are duplicates nor how many there are. Zeroed records
+//! stay in place, so later stages see the same number of rows as without this one.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::Ring;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::sorting_network::Row;
+use std::future::Future;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Records must be compared on at least one column")]
+    NoKeyColumns,
+    #[error("Expected {expected} equality bits, but got {actual}")]
+    EqualityBitCount { expected: usize, actual: usize },
+}
+
+/// Zeroes the `value_columns` of every one of `rows` that has the same `key_columns` as the row
+/// before it, for example match key, timestamp and breakdown key. Rows must be sorted so that
+/// identical keys are adjacent. Returns shares of one for rows that are kept and of zero for
+/// duplicates, so that callers can discard anything else they derived from them.
+///
+/// `equal` gets pairs of shares and must return, for every pair, a share of one if they are
+/// equal and a share of zero otherwise. It reserves indices from `next_index` the same way as
+/// the comparator of [`sort`](crate::sorting_network::sort). Takes one call to `equal` and as
+/// many rounds of multiplication as there are key columns.
+///
+/// ## Errors
+/// If there are no key columns, comparison or multiplication fails, or `equal` returns the
+/// wrong number of bits.
+pub async fn zero_duplicates<F, R, E, EFut>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    rows: &mut [Row<F>],
+    key_columns: &[usize],
+    value_columns: &[usize],
+    mut equal: E,
+) -> Res<Vec<ReplicatedSecretSharing<F>>>
+where
+    F: Field,
+    R: Ring,
+    E: FnMut(&mut u128, Vec<(ReplicatedSecretSharing<F>, ReplicatedSecretSharing<F>)>) -> EFut,
+    EFut: Future<Output = Res<Vec<ReplicatedSecretSharing<F>>>>,
+{
+    if key_columns.is_empty() {
+        return Err(Error::NoKeyColumns.into());
+    }
+    let one = ctx.share_known(F::ONE);
+    if rows.len() < 2 {
+        return Ok(vec![one; rows.len()]);
+    }
+
+    // all columns of all adjacent pairs are compared at once
+    let pairs = rows
+        .windows(2)
+        .flat_map(|w| key_columns.iter().map(|&c| (w[0][c], w[1][c])))
+        .collect::<Vec<_>>();
+    let expected = pairs.len();
+    let same = equal(next_index, pairs).await?;
+    if same.len() != expected {
+        return Err(Error::EqualityBitCount {
+            expected,
+            actual: same.len(),
+        }
+        .into());
+    }
+
+    // a row is a duplicate if all of its key columns are the same
+    let mut duplicate = same
+        .chunks(key_columns.len())
+        .map(|s| s[0])
+        .collect::<Vec<_>>();
+    for c in 1..key_columns.len() {
+        let column = same
+            .chunks(key_columns.len())
+            .map(|s| s[c])
+            .collect::<Vec<_>>();
+        let index = *next_index;
+        *next_index += duplicate.len() as u128;
+        duplicate = ctx.multiply_batch(index, &duplicate, &column).await?;
+    }
+    let keep = std::iter::once(one)
+        .chain(duplicate.into_iter().map(|d| one - d))
+        .collect::<Vec<_>>();
+
+    // the first row is always kept, every value of the others is zeroed in the same round
+    let (values, bits): (Vec<_>, Vec<_>) = rows[1..]
+        .iter()
+        .zip(&keep[1..])
+        .flat_map(|(row, keep)| value_columns.iter().map(move |&c| (row[c], *keep)))
+        .unzip();
+    let index = *next_index;
+    *next_index += values.len() as u128;
+    let kept = ctx.multiply_batch(index, &values, &bits).await?;
+    for (row, kept) in rows[1..]
+        .iter_mut()
+        .zip(kept.chunks(value_columns.len().max(1)))
+    {
+        for (&c, value) in value_columns.iter().zip(kept) {
+            row[c] = *value;
+        }
+    }
+
+    Ok(keep)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::{zero_duplicates, Error as DedupError};
+    use crate::error::{Error, Res};
+    use crate::field::Fp31;
+    use crate::helpers::ring::mock::TestHelper;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::reveal::reveal_vec;
+    use crate::securemul::ProtocolContext;
+    use crate::sorting_network::Row;
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use rand::rngs::mock::StepRng;
+
+    type Share = ReplicatedSecretSharing<Fp31>;
+
+    /// Reveals both sides of every pair to compare them, which is only good enough to test
+    /// what is done with the result.
+    async fn in_the_clear(
+        ctx: &ProtocolContext<'_, TestHelper>,
+        index: u128,
+        pairs: Vec<(Share, Share)>,
+    ) -> Res<Vec<Share>> {
+        let (a, b): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        let a = reveal_vec(ctx, index, &a).await?;
+        let b = reveal_vec(ctx, index, &b).await?;
+        Ok(a.iter()
+            .zip(&b)
+            .map(|(a, b)| ctx.share_known(Fp31::from(u128::from(a == b))))
+            .collect())
+    }
+
+    fn share_rows(input: &[[u128; 4]]) -> [Vec<Row<Fp31>>; 3] {
+        let mut rand = StepRng::new(1, 7);
+        let mut rows = [Vec::new(), Vec::new(), Vec::new()];
+        for record in input {
+            let shared = record.map(|v| Share::share(Fp31::from(v), &mut rand));
+            for (i, helper) in rows.iter_mut().enumerate() {
+                helper.push(shared.iter().map(|s| s[i]).collect());
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn zeroes_duplicates() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+
+        // (match key, timestamp, breakdown key, value), sorted
+        let input = [
+            [3, 10, 1, 5],
+            [3, 10, 1, 5],
+            [3, 10, 2, 4],
+            [3, 11, 2, 3],
+            [7, 11, 2, 2],
+            [7, 11, 2, 2],
+            [7, 11, 2, 2],
+        ];
+        let [mut r0, mut r1, mut r2] = share_rows(&input);
+        let equal = |ctx| {
+            move |next: &mut u128, pairs: Vec<(Share, Share)>| {
+                let index = *next;
+                *next += 1;
+                in_the_clear(ctx, index, pairs)
+            }
+        };
+        let keys = [0, 1, 2];
+        let mut next_index = [1; 3];
+        let [n0, n1, n2] = &mut next_index;
+        let (k0, k1, k2) = futures::try_join!(
+            zero_duplicates(&ctx[0], n0, &mut r0, &keys, &[3], equal(&ctx[0])),
+            zero_duplicates(&ctx[1], n1, &mut r1, &keys, &[3], equal(&ctx[1])),
+            zero_duplicates(&ctx[2], n2, &mut r2, &keys, &[3], equal(&ctx[2])),
+        )
+        .unwrap();
+
+        let keep = [1_u128, 0, 1, 1, 1, 0, 0].map(Fp31::from);
+        assert_eq!(keep.to_vec(), reconstruct(&[k0, k1, k2]));
+        let values = |rows: &[Row<Fp31>]| rows.iter().map(|r| r[3]).collect::<Vec<_>>();
+        let expected = [5_u128, 0, 4, 3, 2, 0, 0].map(Fp31::from);
+        assert_eq!(
+            expected.to_vec(),
+            reconstruct(&[values(&r0), values(&r1), values(&r2)])
+        );
+        // keys are left alone
+        let key = |rows: &[Row<Fp31>]| rows.iter().map(|r| r[0]).collect::<Vec<_>>();
+        assert_eq!(
+            input.map(|r| Fp31::from(r[0])).to_vec(),
+            reconstruct(&[key(&r0), key(&r1), key(&r2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn no_key_columns() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let [mut rows, _, _] = share_rows(&[[1, 2, 3, 4]]);
+        let result = zero_duplicates(&ctx[0], &mut 1, &mut rows, &[], &[3], |_, _| async {
+            Ok(Vec::new())
+        })
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::Dedup(DedupError::NoKeyColumns))
+        ));
+    }
+}
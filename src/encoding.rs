@@ -0,0 +1,85 @@
+//!
+//! Encoding of field values and replicated shares as bytes, the same everywhere they are stored
+//! or sent: between helpers, in share files and in the object store. Clients that share their
+//! input use it as well, see [`crate::client_core`].
+//!
+//! Every value is written as its integer representation in little-endian order, using the
+//! [`field_size`] of its field, and every share as its two values, in order.
+//!
+use crate::field::{Field, Int};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use bytes::BufMut;
+
+/// Same as [`crate::error::BoxError`], which is not available to clients.
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Number of bytes every value of field `F` takes in the encoding used by [`write_fields`].
+#[must_use]
+pub fn field_size<F: Field>() -> usize {
+    (F::Integer::BITS / 8) as usize
+}
+
+/// Writes `values` to the end of `out`, each as its integer representation in little-endian
+/// order, using [`field_size`] bytes. Unlike the message codecs of helpers, this does not need an intermediate
+/// buffer, so values can be written straight into the buffer handed to the transport.
+pub fn write_fields<F: Field, B: BufMut>(values: &[F], out: &mut B) {
+    let size = field_size::<F>();
+    for &v in values {
+        let v: u128 = Into::<F::Integer>::into(v).into();
+        out.put_slice(&v.to_le_bytes()[..size]);
+    }
+}
+
+/// Reads values written by [`write_fields`].
+///
+/// ## Errors
+/// If the length of `bytes` is not a multiple of the value size or some value is not
+/// an element of the field.
+pub fn read_fields<F: Field>(bytes: &[u8]) -> Result<Vec<F>, BoxError> {
+    let size = field_size::<F>();
+    if bytes.len() % size != 0 {
+        return Err(format!(
+            "{} bytes do not hold whole values of {size} bytes",
+            bytes.len()
+        )
+        .into());
+    }
+    let prime: u128 = F::PRIME.into();
+    bytes
+        .chunks_exact(size)
+        .map(|chunk| {
+            let mut buf = [0_u8; 16];
+            buf[..size].copy_from_slice(chunk);
+            let v = u128::from_le_bytes(buf);
+            if v < prime {
+                Ok(F::from(v))
+            } else {
+                Err(format!("{v} is not an element of the field with prime {prime}").into())
+            }
+        })
+        .collect()
+}
+
+/// Writes `shares` to the end of `out`, each as its two values, in order, encoded the same way as
+/// [`write_fields`] does.
+pub fn write_shares<F: Field, B: BufMut>(shares: &[ReplicatedSecretSharing<F>], out: &mut B) {
+    for share in shares {
+        let (a, b) = share.as_tuple();
+        write_fields(&[a, b], out);
+    }
+}
+
+/// Reads shares written by [`write_shares`].
+///
+/// ## Errors
+/// If `bytes` do not hold whole shares or some value is not an element of the field.
+pub fn read_shares<F: Field>(bytes: &[u8]) -> Result<Vec<ReplicatedSecretSharing<F>>, BoxError> {
+    let values = read_fields::<F>(bytes)?;
+    if values.len() % 2 != 0 {
+        return Err(format!("{} values do not make whole shares", values.len()).into());
+    }
+    Ok(values
+        .chunks_exact(2)
+        .map(|v| ReplicatedSecretSharing::new(v[0], v[1]))
+        .collect())
+}
@@ -0,0 +1,100 @@
+//!
+//! Randomness that a helper draws on its own, as opposed to the correlated randomness it gets
+//! from PRSS: keys it generates, ciphertexts it rerandomizes and the like. Code that needs such
+//! randomness takes an [`Entropy`], usually through [`ProtocolContext::rng`], rather than calling
+//! `thread_rng` directly.
+//!
+//! In production, every generator is seeded from the operating system. Tests and the replay
+//! harness use [`Entropy::seeded`] instead, which hands out generators seeded from a single
+//! seed, so that a run can be reproduced exactly as long as generators are asked for in the
+//! same order.
+//!
+//! [`ProtocolContext::rng`]: crate::securemul::ProtocolContext::rng
+//!
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::{Arc, Mutex};
+
+/// Source of random generators. Clones share the same source.
+#[derive(Debug, Clone, Default)]
+pub struct Entropy {
+    /// Generator that seeds all others, if this is deterministic.
+    seeded: Option<Arc<Mutex<StdRng>>>,
+}
+
+impl Entropy {
+    /// Entropy from the operating system.
+    #[must_use]
+    pub fn os() -> Self {
+        Self::default()
+    }
+
+    /// Deterministic entropy derived from `seed`.
+    #[must_use]
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seeded: Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+        }
+    }
+
+    #[must_use]
+    pub fn is_deterministic(&self) -> bool {
+        self.seeded.is_some()
+    }
+
+    /// A new cryptographically secure generator.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn rng(&self) -> StdRng {
+        match &self.seeded {
+            Some(seeds) => StdRng::from_seed(seeds.lock().unwrap().gen()),
+            None => StdRng::from_entropy(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entropy::Entropy;
+    use crate::field::Fp31;
+    use crate::test_fixture::TestWorld;
+    use rand::Rng;
+
+    #[test]
+    fn seeded() {
+        let draw = |entropy: &Entropy| [entropy.rng().gen::<u128>(), entropy.rng().gen()];
+        let (a, b) = (Entropy::seeded(1), Entropy::seeded(1));
+        assert_eq!(draw(&a), draw(&b));
+        // every generator is different, but clones share the sequence of them
+        let [first, second] = draw(&a);
+        assert_ne!(first, second);
+        assert_ne!(draw(&a.clone()), draw(&b));
+        assert_ne!(draw(&Entropy::seeded(2)), draw(&Entropy::seeded(1)));
+    }
+
+    #[test]
+    fn os() {
+        let entropy = Entropy::os();
+        assert!(!entropy.is_deterministic());
+        assert_ne!(entropy.rng().gen::<u128>(), entropy.rng().gen::<u128>());
+    }
+
+    #[tokio::test]
+    async fn seeded_world() {
+        let draw = |world: &TestWorld| {
+            let ctx = world.contexts();
+            (
+                ctx[0].rng().gen::<u128>(),
+                ctx[1].prss_random_share::<Fp31>(1),
+            )
+        };
+        let (a, b) = (
+            draw(&TestWorld::with_seed(7)),
+            draw(&TestWorld::with_seed(7)),
+        );
+        assert_eq!(a.0, b.0);
+        assert!(a.1 == b.1);
+    }
+}
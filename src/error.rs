@@ -1,3 +1,5 @@
+use crate::helpers::error::{is_transient, Error as HelperError};
+use crate::helpers::ring::Identity;
 use std::fmt::Debug;
 use thiserror::Error;
 
@@ -24,9 +26,75 @@ pub enum Error {
     #[error("thread died: {0}")]
     DeadThread(#[from] std::sync::mpsc::SendError<crate::net::Message>),
 
+    #[error(transparent)]
+    Helper(#[from] HelperError),
+    #[error(transparent)]
+    SecureMul(#[from] crate::securemul::Error),
+    #[error(transparent)]
+    Reveal(#[from] crate::reveal::Error),
+    #[error(transparent)]
+    Context(#[from] crate::context::Error),
+    #[error(transparent)]
+    Clock(#[from] crate::clock::Error),
+    #[error(transparent)]
+    Roles(#[from] crate::roles::Error),
+    #[error(transparent)]
+    Transcript(#[from] crate::transcript::Error),
+    #[error(transparent)]
+    Commitment(#[from] crate::commitment::Error),
+    #[error(transparent)]
+    Sort(#[from] crate::sorting_network::Error),
+    #[error(transparent)]
+    Columnar(#[from] crate::columnar::Error),
+    #[error(transparent)]
+    Ingest(#[from] crate::ingest::Error),
+    #[cfg(feature = "enable-serde")]
+    #[error(transparent)]
+    ReportSource(#[from] crate::helpers::report_source::Error),
+    #[error(transparent)]
+    TopK(#[from] crate::top_k::Error),
+    #[error(transparent)]
+    Reach(#[from] crate::reach::Error),
+    #[error(transparent)]
+    Dedup(#[from] crate::dedup::Error),
+    #[error(transparent)]
+    Report(#[from] crate::report::Error),
+    #[error(transparent)]
+    Quota(#[from] crate::helpers::quota::Error),
+    #[error(transparent)]
+    Query(#[from] crate::query::Error),
+    #[error(transparent)]
+    #[cfg(feature = "enable-serde")]
+    Capabilities(#[from] crate::capabilities::Error),
+    #[error(transparent)]
+    #[cfg(feature = "enable-serde")]
+    Collector(#[from] crate::collector::Error),
+    #[error(transparent)]
+    #[cfg(feature = "enable-serde")]
+    Export(#[from] crate::export::Error),
+    #[error(transparent)]
+    Storage(#[from] crate::storage::Error),
+    #[error(transparent)]
+    #[cfg(feature = "scenarios")]
+    Scenarios(#[from] crate::scenarios::Error),
+    #[error("step {step} failed to process record {record} on {identity}")]
+    Step {
+        identity: Identity,
+        step: &'static str,
+        record: u128,
+        #[source]
+        inner: Box<Error>,
+    },
+
+    #[error("expected {expected} buckets, but helper output has {actual}")]
+    BucketMismatch { expected: usize, actual: usize },
+
     #[error("failed to decode hex: {0}")]
     #[cfg(feature = "cli")]
     Hex(#[from] hex::FromHexError),
+    #[error("failed to generate certificate: {0}")]
+    #[cfg(feature = "cli")]
+    Certificate(#[from] rcgen::RcgenError),
     #[error("problem during IO: {0}")]
     Io(#[from] std::io::Error),
     #[error("failed to parse json: {0}")]
@@ -34,7 +102,196 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
 }
 
+impl Error {
+    /// Attaches the helper, the name of the protocol step and the record it was processing when
+    /// this error occurred. The original error is available via `source`.
+    #[must_use]
+    pub fn in_step(self, identity: Identity, step: &'static str, record: u128) -> Self {
+        Self::Step {
+            identity,
+            step,
+            record,
+            inner: Box::new(self),
+        }
+    }
+
+    /// Whether the operation that failed with this error may succeed if attempted again. Only
+    /// transport errors are retryable: I/O with peers, Redis or anything else that was
+    /// interrupted, timed out, or lost its connection, see [`is_transient`]. Errors caused by bad
+    /// input, peers that misbehave or internal inconsistencies are not.
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Step { inner, .. } => inner.is_retryable(),
+            Self::Helper(e) => e.is_retryable(),
+            Self::RedisError(e) => {
+                e.is_timeout() || e.is_connection_dropped() || e.is_connection_refusal()
+            }
+            Self::Io(e) => is_transient(e),
+            _ => false,
+        }
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 pub type Res<T> = Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::helpers::error::Error as HelperError;
+    use crate::helpers::memory::LimitExceeded;
+    use crate::helpers::ring::{HelperAddr, Identity};
+    use crate::ingest::Error as IngestError;
+    use crate::securemul::Error as SecureMulError;
+    use std::error::Error as _;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn step_context() {
+        let e = Error::from(SecureMulError::IndexMismatch {
+            my_index: 1,
+            their_index: 2,
+        })
+        .in_step(Identity::H2, "securemul", 1);
+
+        assert_eq!(
+            "step securemul failed to process record 1 on H2",
+            e.to_string()
+        );
+        assert_eq!(
+            "Shares calculated by peer used different index 2 than expected 1",
+            e.source().unwrap().to_string()
+        );
+        assert!(!e.is_retryable());
+    }
+
+    #[test]
+    fn peer_context() {
+        let e = HelperError::SendError {
+            dest: HelperAddr::Right,
+            inner: io::Error::from(io::ErrorKind::ConnectionReset).into(),
+        }
+        .with_peer(([127, 0, 0, 1], 1234).into());
+
+        assert_eq!("connection to 127.0.0.1:1234 failed", e.to_string());
+        assert_eq!(
+            "An error occurred while sending data to Right",
+            e.source().unwrap().to_string()
+        );
+        assert!(Error::from(e).is_retryable());
+    }
+
+    #[test]
+    fn retryable() {
+        let reset = || io::Error::from(io::ErrorKind::ConnectionReset);
+        let e = Error::from(HelperError::SendError {
+            dest: HelperAddr::Left,
+            inner: reset().into(),
+        });
+        assert!(e.in_step(Identity::H1, "securemul", 1).is_retryable());
+
+        assert!(Error::from(io::Error::from(io::ErrorKind::TimedOut)).is_retryable());
+        assert!(!Error::from(io::Error::from(io::ErrorKind::NotFound)).is_retryable());
+        assert!(Error::from(redis::RedisError::from(io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        )))
+        .is_retryable());
+        assert!(!Error::InvalidId.is_retryable());
+        assert!(!Error::from(IngestError::Replayed { report: 1 }).is_retryable());
+    }
+
+    /// Every variant of helper errors, and whether it is retryable.
+    #[test]
+    fn retryable_helper() {
+        let reset = || io::Error::from(io::ErrorKind::ConnectionReset);
+        let cases: Vec<(HelperError, bool)> = vec![
+            (
+                HelperError::SendError {
+                    dest: HelperAddr::Left,
+                    inner: reset().into(),
+                },
+                true,
+            ),
+            (
+                HelperError::SendError {
+                    dest: HelperAddr::Left,
+                    inner: io::Error::from(io::ErrorKind::InvalidData).into(),
+                },
+                false,
+            ),
+            // failed to encode, which happens again
+            (
+                HelperError::SendError {
+                    dest: HelperAddr::Left,
+                    inner: "cannot encode".into(),
+                },
+                false,
+            ),
+            (
+                HelperError::ReceiveError {
+                    source: HelperAddr::Right,
+                    inner: io::Error::from(io::ErrorKind::TimedOut).into(),
+                },
+                true,
+            ),
+            (
+                HelperError::ReceiveError {
+                    source: HelperAddr::Right,
+                    inner: "malformed".into(),
+                },
+                false,
+            ),
+            (
+                HelperError::MemoryLimitExceeded(LimitExceeded {
+                    limit: 1,
+                    used: 1,
+                    requested: 1,
+                }),
+                false,
+            ),
+            (
+                HelperError::Aborted {
+                    by: None,
+                    reason: "bad input".into(),
+                },
+                false,
+            ),
+            (
+                HelperError::MessageTooBig {
+                    by: HelperAddr::Left,
+                    size: 2,
+                    limit: 1,
+                },
+                false,
+            ),
+            (HelperError::Spill("disk full".into()), false),
+            (
+                HelperError::PeerGone {
+                    peer: HelperAddr::Left,
+                    silent: Duration::from_secs(30),
+                },
+                false,
+            ),
+            (
+                HelperError::Closed {
+                    by: HelperAddr::Left,
+                    message: "m",
+                },
+                false,
+            ),
+        ];
+        let addr = ([127, 0, 0, 1], 1234).into();
+        for (e, retryable) in cases {
+            let text = e.to_string();
+            assert_eq!(retryable, e.is_retryable(), "{text}");
+            // the same with the address of the peer, at any depth
+            let e = e.with_peer(addr).with_peer(addr);
+            assert_eq!(retryable, e.is_retryable(), "{text}");
+            assert_eq!(retryable, Error::from(e).is_retryable(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,482 @@
+//!
+//! Estimates of what a query costs, before it runs. Collectors want to know how long a query
+//! takes and helpers how much traffic and memory it needs, and the IPA protocol has costs that
+//! follow from the size of the input and the parameters of the query alone: which rows are
+//! compared and multiplied does not depend on the data, or helpers would learn from it.
+//!
+//! The estimate counts the primitives every stage uses, the same way [`RoundCounter`] accounts
+//! for them in a run: rounds, multiplications and values that are revealed. Every
+//! multiplication sends one field value from every helper to the helper on its right, and so
+//! does every revealed value, so every pair of helpers exchanges one value for each of them.
+//! Time follows from the [`Network`]: every round waits for a message to get across, every
+//! value sent takes its share of the bandwidth and every multiplication some time to compute.
+//!
+//! Stages are modelled after the IPA protocol, on rows that hold a match key of
+//! [`MATCHKEY_BITS`] bits, one bit for every breakdown key, the trigger bit and the value:
+//!
+//! * conversion turns match keys into bits with [`crate::ingest`], after a check of the input
+//!   commitment,
+//! * sort orders rows by match key with the [odd-even merge network] of comparators that each
+//!   compare two keys bit by bit and swap two rows,
+//! * attribution compares the match keys of neighbouring rows, spreads the result to the rows
+//!   of a user by doubling, caps the conversions of every user and multiplies the trigger bit
+//!   by the value,
+//! * aggregation sums the products of breakdown key bits and contributions and reveals them.
+//!
+//! The crate does not implement all of these yet, so some counts, such as those of the
+//! comparison, are what a straightforward implementation takes. Networks for inputs that are
+//! not a power of two in size are estimated as the next power of two, which is an upper bound.
+//!
+//! Estimates also set the deadlines of stages with a [`DeadlinePolicy`], on the latency that
+//! [`measure_latency`] finds between the helpers before the query starts. Small queries then fail
+//! as soon as a peer stops responding, and large ones get the time they need.
+//!
+//! [`RoundCounter`]: crate::telemetry::rounds::RoundCounter
+//! [odd-even merge network]: crate::sorting_network::odd_even_merge_layers
+//!
+use crate::context::mac_checks;
+use crate::helpers::error::Error;
+use crate::helpers::ring::{HelperAddr, Ring};
+use crate::ingest::MATCHKEY_BITS;
+use crate::query::{IpaQueryConfig, SecurityMode, Stage, StageDeadlines};
+use crate::telemetry::rounds::StepStats;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Bytes of the digest of input commitments and revealed values, and the count that goes with
+/// it.
+const DIGEST_LEN: u64 = 32 + 8;
+
+/// What the network between helpers and the helpers themselves are capable of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    /// Time a message takes from one helper to another.
+    pub latency: Duration,
+    /// Bytes per second a helper sends to each of its peers.
+    pub bandwidth: u64,
+    /// Time a helper takes to compute its part of a single multiplication.
+    pub multiplication: Duration,
+}
+
+impl Default for Network {
+    /// Helpers in different regions of a cloud, with 1 Gbit/s between them.
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(20),
+            bandwidth: 125_000_000,
+            multiplication: Duration::from_nanos(500),
+        }
+    }
+}
+
+/// Estimated cost of a stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageEstimate {
+    pub stage: Stage,
+    /// Rounds and multiplications, the same as a [`RoundCounter`] counts them.
+    ///
+    /// [`RoundCounter`]: crate::telemetry::rounds::RoundCounter
+    pub stats: StepStats,
+    /// Bytes every pair of helpers exchanges, in both directions.
+    pub bytes: u64,
+    /// Bytes of shares a helper holds at once.
+    pub memory: u64,
+    pub elapsed: Duration,
+}
+
+/// Estimated cost of a query, stage by stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    /// Records the query runs on, after sampling.
+    pub records: u64,
+    pub stages: Vec<StageEstimate>,
+}
+
+impl Estimate {
+    /// Rounds and multiplications of all stages.
+    #[must_use]
+    pub fn stats(&self) -> StepStats {
+        self.stages
+            .iter()
+            .fold(StepStats::default(), |acc, s| StepStats {
+                rounds: acc.rounds + s.stats.rounds,
+                multiplications: acc.multiplications + s.stats.multiplications,
+            })
+    }
+
+    /// Bytes every pair of helpers exchanges over the whole query.
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.stages.iter().map(|s| s.bytes).sum()
+    }
+
+    /// Bytes of shares a helper holds at once, in the stage that needs the most.
+    #[must_use]
+    pub fn peak_memory(&self) -> u64 {
+        self.stages.iter().map(|s| s.memory).max().unwrap_or(0)
+    }
+
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.stages.iter().map(|s| s.elapsed).sum()
+    }
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>16} {:>16} {:>16} {:>12}",
+            "stage", "rounds", "multiplications", "bytes per pair", "peak memory", "time"
+        )?;
+        let mut row = |name: &str, stats: StepStats, bytes, memory, elapsed: Duration| {
+            writeln!(
+                f,
+                "{name:<12} {:>8} {:>16} {bytes:>16} {memory:>16} {:>11.1}s",
+                stats.rounds,
+                stats.multiplications,
+                elapsed.as_secs_f64()
+            )
+        };
+        for s in &self.stages {
+            row(s.stage.name(), s.stats, s.bytes, s.memory, s.elapsed)?;
+        }
+        row(
+            "total",
+            self.stats(),
+            self.bytes(),
+            self.peak_memory(),
+            self.elapsed(),
+        )
+    }
+}
+
+/// Primitives a stage uses, counted for one helper.
+#[derive(Debug, Default, Clone, Copy)]
+struct Work {
+    rounds: u64,
+    multiplications: u64,
+    revealed: u64,
+    digests: u64,
+    /// Shares held at once.
+    shares: u64,
+}
+
+impl Work {
+    fn estimate(self, stage: Stage, field_size: u64, network: &Network) -> StageEstimate {
+        let bytes = (self.multiplications + self.revealed) * field_size + self.digests * DIGEST_LEN;
+        // both helpers of a pair send half of the bytes, at the same time
+        #[allow(clippy::cast_precision_loss)]
+        let (transfer, compute) = (
+            Duration::from_secs_f64(bytes as f64 / 2.0 / network.bandwidth as f64),
+            network.multiplication.mul_f64(self.multiplications as f64),
+        );
+        StageEstimate {
+            stage,
+            stats: StepStats {
+                rounds: self.rounds,
+                multiplications: self.multiplications,
+            },
+            bytes,
+            memory: self.shares * 2 * field_size,
+            elapsed: network.latency * u32::try_from(self.rounds).unwrap_or(u32::MAX)
+                + transfer
+                + compute,
+        }
+    }
+}
+
+/// Comparators and layers of the odd-even merge network for `n` rows, or for the next power of
+/// two if `n` is not one.
+fn sorting_network(n: u64) -> (u64, u64) {
+    if n <= 1 {
+        return (0, 0);
+    }
+    let m = u64::from(64 - (n - 1).leading_zeros());
+    (((m * m - m + 4) << m) / 4 - 1, m * (m + 1) / 2)
+}
+
+/// `ceil(log2(n))`, zero for `n` up to one.
+fn log2(n: u64) -> u64 {
+    u64::from(64 - n.saturating_sub(1).leading_zeros())
+}
+
+/// Estimated cost of running the query of `config` on `records` input rows, source and trigger
+/// events together. Queries that are dry runs on a sample of the input are estimated for the
+/// rows of the sample.
+#[must_use]
+pub fn estimate(config: &IpaQueryConfig, records: u64, network: &Network) -> Estimate {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let n = config
+        .sampling
+        .map_or(records, |s| (records as f64 * s.rate).ceil() as u64);
+    let bits = u64::from(MATCHKEY_BITS);
+    let keys = u64::from(config.max_breakdown_key);
+    // match key, one-hot breakdown key, trigger bit and value
+    let width = bits + keys + 2;
+
+    let conversion = Work {
+        // a round for the commitment, then two for the XOR of the three parts of every bit
+        rounds: 3,
+        multiplications: 2 * bits * n,
+        digests: 1,
+        shares: 4 * bits * n,
+        ..Work::default()
+    };
+
+    let (comparators, layers) = sorting_network(n);
+    // a comparison of two keys bit by bit is a carry chain, evaluated as a tree
+    let compare = (2 * bits, log2(bits) + 1);
+    let sort = Work {
+        rounds: layers * (compare.1 + 1),
+        multiplications: comparators * (compare.0 + width),
+        shares: n * width,
+        ..Work::default()
+    };
+
+    let cap = u64::from(config.per_user_cap);
+    let attribution = Work {
+        rounds: log2(bits) + log2(n) + 2,
+        multiplications: n * (bits - 1 + 2 * log2(n) + cap + 1),
+        shares: n * width,
+        ..Work::default()
+    };
+
+    let aggregation = Work {
+        rounds: 2,
+        multiplications: keys,
+        revealed: keys,
+        digests: 1,
+        shares: n * keys + keys,
+    };
+
+    let mut stages = [conversion, sort, attribution, aggregation];
+    if config.security == SecurityMode::Malicious {
+        // every product is computed for the MAC as well
+        for work in &mut stages {
+            work.multiplications *= 2;
+            work.shares *= 2;
+        }
+        let [_, sort, _, aggregation] = &mut stages;
+        // rows are upgraded before the sort
+        sort.rounds += 1;
+        sort.multiplications += n * width;
+        // checks are sums of products, one after another, then the key and the checks are
+        // revealed
+        let checks = u64::from(mac_checks(config.field.prime()));
+        aggregation.rounds += 2 * checks + 2;
+        aggregation.multiplications += 2 * checks;
+        aggregation.revealed += 1 + checks;
+        aggregation.digests += 2;
+    }
+
+    let field_size = config.field.size() as u64;
+    Estimate {
+        records: n,
+        stages: Stage::ALL
+            .iter()
+            .zip(stages)
+            .map(|(&stage, work)| work.estimate(stage, field_size, network))
+            .collect(),
+    }
+}
+
+/// How the deadline of every stage follows from its estimate: the estimate times the slack, but
+/// never less than the floor, so that queries of a few records do not fail on a hiccup of the
+/// network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadlinePolicy {
+    pub slack: f64,
+    pub floor: Duration,
+}
+
+impl Default for DeadlinePolicy {
+    fn default() -> Self {
+        Self {
+            slack: 4.0,
+            floor: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DeadlinePolicy {
+    /// Deadlines of the query of `config` on `records` input rows. Stages that have a deadline
+    /// in `config` keep it.
+    #[must_use]
+    pub fn deadlines(
+        &self,
+        config: &IpaQueryConfig,
+        records: u64,
+        network: &Network,
+    ) -> StageDeadlines {
+        let estimate = estimate(config, records, network);
+        let ms = |stage: Stage| {
+            let elapsed = estimate
+                .stages
+                .iter()
+                .find(|s| s.stage == stage)
+                .map_or(Duration::ZERO, |s| s.elapsed);
+            let limit = elapsed.mul_f64(self.slack).max(self.floor);
+            Some(u64::try_from(limit.as_millis()).unwrap_or(u64::MAX))
+        };
+        let set = &config.deadlines;
+        StageDeadlines {
+            conversion_ms: set.conversion_ms.or_else(|| ms(Stage::Conversion)),
+            sort_ms: set.sort_ms.or_else(|| ms(Stage::Sort)),
+            attribution_ms: set.attribution_ms.or_else(|| ms(Stage::Attribution)),
+            aggregation_ms: set.aggregation_ms.or_else(|| ms(Stage::Aggregation)),
+        }
+    }
+}
+
+/// Time a message takes from one helper to another, averaged over `rounds` messages that every
+/// helper sends to the helper on its right, each after it got the previous one from the left.
+/// All three helpers have to measure at the same time, before the query starts.
+///
+/// ## Errors
+/// If a message cannot be sent or received.
+pub async fn measure_latency<R: Ring>(ring: &R, rounds: u32) -> Result<Duration, Error> {
+    let start = Instant::now();
+    for round in 0..rounds {
+        ring.send(HelperAddr::Right, round).await?;
+        ring.receive::<u32>(HelperAddr::Left).await?;
+    }
+    Ok(start.elapsed() / rounds.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::estimate::{estimate, measure_latency, sorting_network, DeadlinePolicy, Network};
+    use crate::helpers::ring::mock::make_three;
+    use crate::helpers::ring::{HelperAddr, Ring};
+    use crate::query::{IpaQueryConfig, Sampling, SecurityMode, Stage, StageDeadlines};
+    use crate::sorting_network::odd_even_merge_layers;
+    use std::time::Duration;
+
+    #[test]
+    fn sorting_network_size() {
+        for n in 0..=64_u64 {
+            let layers = odd_even_merge_layers(usize::try_from(n).unwrap());
+            let comparators = layers.iter().map(Vec::len).sum::<usize>() as u64;
+            let (c, l) = sorting_network(n);
+            if n.is_power_of_two() || n == 0 {
+                assert_eq!((comparators, layers.len() as u64), (c, l), "{n}");
+            } else {
+                assert!(comparators <= c && layers.len() as u64 <= l, "{n}");
+            }
+        }
+    }
+
+    #[test]
+    fn stages() {
+        let config = IpaQueryConfig::default();
+        let network = Network::default();
+        let small = estimate(&config, 1000, &network);
+        let large = estimate(&config, 1_000_000, &network);
+
+        assert_eq!(
+            Stage::ALL.to_vec(),
+            small.stages.iter().map(|s| s.stage).collect::<Vec<_>>()
+        );
+        // conversion is linear and takes the same rounds
+        let (s, l) = (&small.stages[0], &large.stages[0]);
+        assert_eq!(s.stats.rounds, l.stats.rounds);
+        assert_eq!(1000 * s.stats.multiplications, l.stats.multiplications);
+        assert_eq!(80_000, s.stats.multiplications);
+        // the sort is not
+        assert!(large.stages[1].stats.rounds > small.stages[1].stats.rounds);
+        assert!(large.elapsed() > small.elapsed());
+        assert!(large.peak_memory() > small.peak_memory());
+        assert_eq!(
+            small.bytes(),
+            small.stages.iter().map(|s| s.bytes).sum::<u64>()
+        );
+        assert!(small
+            .to_string()
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("total"));
+    }
+
+    #[test]
+    fn malicious_and_sampled() {
+        let network = Network::default();
+        let semi_honest = estimate(&IpaQueryConfig::default(), 1000, &network);
+        let malicious = estimate(
+            &IpaQueryConfig {
+                security: SecurityMode::Malicious,
+                ..IpaQueryConfig::default()
+            },
+            1000,
+            &network,
+        );
+        assert!(malicious.stats().multiplications > 2 * semi_honest.stats().multiplications);
+        assert!(malicious.stats().rounds > semi_honest.stats().rounds);
+        assert!(malicious.bytes() > semi_honest.bytes());
+
+        let sampled = estimate(
+            &IpaQueryConfig {
+                sampling: Some(Sampling { rate: 0.1, seed: 0 }),
+                ..IpaQueryConfig::default()
+            },
+            1000,
+            &network,
+        );
+        assert_eq!(100, sampled.records);
+        assert_eq!(estimate(&IpaQueryConfig::default(), 100, &network), sampled);
+    }
+
+    #[test]
+    fn deadlines() {
+        let policy = DeadlinePolicy::default();
+        let network = Network::default();
+        let config = IpaQueryConfig {
+            deadlines: StageDeadlines {
+                conversion_ms: Some(1),
+                ..StageDeadlines::default()
+            },
+            ..IpaQueryConfig::default()
+        };
+        let small = policy.deadlines(&config, 10, &network);
+        assert_eq!(Some(1), small.conversion_ms);
+        assert_eq!(Some(Duration::from_secs(5)), small.get(Stage::Aggregation));
+
+        let large = policy.deadlines(&config, 10_000_000, &network);
+        assert_eq!(Some(1), large.conversion_ms);
+        let sort = estimate(&config, 10_000_000, &network).stages[1].elapsed;
+        assert!(large.get(Stage::Sort).unwrap() + Duration::from_millis(1) >= sort * 4);
+
+        // slower networks get more time
+        let slow = policy.deadlines(
+            &config,
+            10_000_000,
+            &Network {
+                latency: Duration::from_millis(200),
+                ..network
+            },
+        );
+        assert!(slow.sort_ms > large.sort_ms);
+    }
+
+    #[tokio::test]
+    async fn latency() {
+        let [h1, h2, h3] = make_three();
+        let (l1, l2, l3) = futures::try_join!(
+            measure_latency(&h1, 5),
+            measure_latency(&h2, 5),
+            measure_latency(&h3, 5)
+        )
+        .unwrap();
+        for latency in [l1, l2, l3] {
+            assert!(latency < Duration::from_secs(1), "{latency:?}");
+        }
+        // nothing is left over for the query
+        h1.send(HelperAddr::Right, 7_u8).await.unwrap();
+        assert_eq!(7_u8, h2.receive::<u8>(HelperAddr::Left).await.unwrap());
+    }
+}
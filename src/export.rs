@@ -0,0 +1,232 @@
+//!
+//! Results of queries as report collectors hand them on to whatever analyses them. Once the
+//! histogram is reconstructed from the shares helpers revealed, [`QueryResult::write`] writes it
+//! together with what is needed to interpret it: the configuration of the query, the privacy
+//! budget it spent and the signatures of helpers over their shares.
+//!
+//! JSON puts all of it in a single document. CSV has a row for every breakdown key, after the
+//! metadata in `#` comment lines, which most CSV readers can be told to skip. Arrow IPC, with the
+//! `arrow` feature, keeps the metadata in the schema of the file.
+//!
+use crate::helpers::ring::Identity;
+use crate::query::IpaQueryConfig;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to write the result: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to serialize the result: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "arrow")]
+    #[error("failed to write the result as Arrow: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(Self::Arrow),
+            _ => Err(format!("unknown result format: {s}")),
+        }
+    }
+}
+
+/// Signature of a helper over the shares of the result it revealed, as the helper sent it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelperSignature {
+    pub helper: Identity,
+    pub signature: String,
+}
+
+/// Reconstructed result of a query, one value per breakdown key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub config: IpaQueryConfig,
+    pub signatures: Vec<HelperSignature>,
+    pub histogram: Vec<u32>,
+}
+
+/// Everything that is written as JSON, in this order. Privacy budget is part of the
+/// configuration, but it is repeated at the top so that readers need not know where to look.
+#[derive(Serialize)]
+struct Document<'a> {
+    epsilon: Option<f64>,
+    config: &'a IpaQueryConfig,
+    signatures: &'a [HelperSignature],
+    histogram: &'a [u32],
+}
+
+impl QueryResult {
+    #[must_use]
+    pub fn new(config: IpaQueryConfig, histogram: Vec<u32>) -> Self {
+        Self {
+            config,
+            signatures: Vec::new(),
+            histogram,
+        }
+    }
+
+    #[must_use]
+    pub fn with_signature(mut self, helper: Identity, signature: String) -> Self {
+        self.signatures.push(HelperSignature { helper, signature });
+        self
+    }
+
+    fn document(&self) -> Document<'_> {
+        Document {
+            epsilon: self.config.epsilon,
+            config: &self.config,
+            signatures: &self.signatures,
+            histogram: &self.histogram,
+        }
+    }
+
+    /// Writes the result to `out` in `format`.
+    ///
+    /// ## Errors
+    /// If writing to `out` fails.
+    pub fn write<W: io::Write>(&self, format: Format, out: W) -> Result<(), Error> {
+        match format {
+            Format::Json => Ok(serde_json::to_writer_pretty(out, &self.document())?),
+            Format::Csv => self.write_csv(out),
+            #[cfg(feature = "arrow")]
+            Format::Arrow => self.write_arrow(out),
+        }
+    }
+
+    fn write_csv<W: io::Write>(&self, mut out: W) -> Result<(), Error> {
+        if let Some(epsilon) = self.config.epsilon {
+            writeln!(out, "# epsilon: {epsilon}")?;
+        }
+        writeln!(out, "# config: {}", serde_json::to_string(&self.config)?)?;
+        for s in &self.signatures {
+            writeln!(out, "# signature {:?}: {}", s.helper, s.signature)?;
+        }
+        writeln!(out, "breakdown_key,value")?;
+        for (breakdown_key, value) in self.histogram.iter().enumerate() {
+            writeln!(out, "{breakdown_key},{value}")?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "arrow")]
+    fn write_arrow<W: io::Write>(&self, out: W) -> Result<(), Error> {
+        use arrow_array::{RecordBatch, UInt32Array};
+        use arrow_ipc::writer::FileWriter;
+        use arrow_schema::{DataType, Field, Schema};
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        let mut metadata = HashMap::from([
+            ("config".to_owned(), serde_json::to_string(&self.config)?),
+            (
+                "signatures".to_owned(),
+                serde_json::to_string(&self.signatures)?,
+            ),
+        ]);
+        if let Some(epsilon) = self.config.epsilon {
+            metadata.insert("epsilon".to_owned(), epsilon.to_string());
+        }
+        let schema = Arc::new(
+            Schema::new(vec![
+                Field::new("breakdown_key", DataType::UInt32, false),
+                Field::new("value", DataType::UInt32, false),
+            ])
+            .with_metadata(metadata),
+        );
+        let breakdown_keys = (0..self.histogram.len())
+            .map(|k| u32::try_from(k).unwrap())
+            .collect::<UInt32Array>();
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![
+                Arc::new(breakdown_keys),
+                Arc::new(UInt32Array::from(self.histogram.clone())),
+            ],
+        )?;
+
+        let mut writer = FileWriter::try_new(out, &schema)?;
+        writer.write(&batch)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{Format, QueryResult};
+    use crate::helpers::ring::Identity;
+    use crate::query::IpaQueryConfig;
+
+    fn result() -> QueryResult {
+        let config = IpaQueryConfig {
+            epsilon: Some(1.5),
+            ..IpaQueryConfig::default()
+        };
+        QueryResult::new(config, vec![10, 0, 7]).with_signature(Identity::H2, "c2lnbg".into())
+    }
+
+    #[test]
+    fn json() {
+        let mut out = Vec::new();
+        result().write(Format::Json, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!("1.5", json["epsilon"].to_string());
+        assert_eq!(4, json["config"]["max_breakdown_key"]);
+        assert_eq!("H2", json["signatures"][0]["helper"]);
+        assert_eq!(serde_json::json!([10, 0, 7]), json["histogram"]);
+    }
+
+    #[test]
+    fn csv() {
+        let mut out = Vec::new();
+        result().write(Format::Csv, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!("# epsilon: 1.5", lines[0]);
+        assert!(lines[1].starts_with("# config: {"));
+        assert_eq!("# signature H2: c2lnbg", lines[2]);
+        assert_eq!(["breakdown_key,value", "0,10", "1,0", "2,7"], lines[3..]);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn arrow() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::UInt32Type;
+        use arrow_ipc::reader::FileReader;
+        use std::io::Cursor;
+
+        let mut out = Vec::new();
+        result().write(Format::Arrow, &mut out).unwrap();
+        let reader = FileReader::try_new(Cursor::new(out), None).unwrap();
+        assert_eq!("1.5", reader.schema().metadata()["epsilon"]);
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(1, batches.len());
+        let values = batches[0].column(1).as_primitive::<UInt32Type>();
+        assert_eq!(&[10, 0, 7], values.values().as_ref());
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Ok(Format::Csv), "CSV".parse());
+        assert!("parquet".parse::<Format>().is_err());
+    }
+}
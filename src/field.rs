@@ -24,6 +24,10 @@ impl Int for u8 {
     const BITS: u32 = u8::BITS;
 }
 
+impl Int for u32 {
+    const BITS: u32 = u32::BITS;
+}
+
 pub trait Field:
     Add<Output = Self>
     + AddAssign
@@ -38,7 +42,10 @@ pub trait Field:
     + Copy
     + PartialEq
     + Debug
+    + Send
+    + Sync
     + Sized
+    + 'static
 {
     type Integer: Int;
 
@@ -176,12 +183,103 @@ impl Debug for Fp31 {
     }
 }
 
+/// Field of the largest prime that fits into 32 bits, for trigger values and aggregates that do
+/// not fit into [`Fp31`].
+#[derive(Clone, Copy, PartialEq)]
+pub struct Fp32BitPrime(<Self as Field>::Integer);
+
+impl From<Fp32BitPrime> for u32 {
+    fn from(v: Fp32BitPrime) -> Self {
+        v.0
+    }
+}
+
+impl Field for Fp32BitPrime {
+    type Integer = u32;
+    const PRIME: Self::Integer = 4_294_967_291;
+    const ZERO: Self = Fp32BitPrime(0);
+    const ONE: Self = Fp32BitPrime(1);
+}
+
+impl Add for Fp32BitPrime {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let c = u64::from;
+        #[allow(clippy::cast_possible_truncation)]
+        Self(((c(self.0) + c(rhs.0)) % c(Self::PRIME)) as <Self as Field>::Integer)
+    }
+}
+
+impl AddAssign for Fp32BitPrime {
+    #[allow(clippy::assign_op_pattern)]
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl Neg for Fp32BitPrime {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self((Self::PRIME - self.0) % Self::PRIME)
+    }
+}
+
+impl Sub for Fp32BitPrime {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        let c = u64::from;
+        #[allow(clippy::cast_possible_truncation)]
+        Self(((c(Self::PRIME) + c(self.0) - c(rhs.0)) % c(Self::PRIME)) as <Self as Field>::Integer)
+    }
+}
+
+impl SubAssign for Fp32BitPrime {
+    #[allow(clippy::assign_op_pattern)]
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl Mul for Fp32BitPrime {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let c = u64::from;
+        #[allow(clippy::cast_possible_truncation)]
+        Self(((c(self.0) * c(rhs.0)) % c(Self::PRIME)) as <Self as Field>::Integer)
+    }
+}
+
+impl MulAssign for Fp32BitPrime {
+    #[allow(clippy::assign_op_pattern)]
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+/// Same as the conversion into [`Fp31`].
+impl<T: Into<u128>> From<T> for Fp32BitPrime {
+    fn from(v: T) -> Self {
+        #[allow(clippy::cast_possible_truncation)]
+        Self((v.into() % u128::from(Self::PRIME)) as <Self as Field>::Integer)
+    }
+}
+
+impl Debug for Fp32BitPrime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_mod{}", self.0, Self::PRIME)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::field::Field;
     use std::ops::Mul;
 
-    use super::Fp31;
+    use super::{Fp31, Fp32BitPrime};
 
     #[test]
     fn fp31() {
@@ -242,6 +340,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn fp32_bit_prime() {
+        let max = Fp32BitPrime(Fp32BitPrime::PRIME - 1);
+        assert_eq!(Fp32BitPrime(1), max + Fp32BitPrime(2));
+        assert_eq!(max, Fp32BitPrime(1) - Fp32BitPrime(2));
+        assert_eq!(Fp32BitPrime(1), max * max);
+        assert_eq!(max, -Fp32BitPrime::ONE);
+        assert_eq!(Fp32BitPrime(4), Fp32BitPrime::from(u128::from(u32::MAX)));
+        assert_eq!(
+            Fp32BitPrime::ONE,
+            Fp32BitPrime(123_456_789).invert() * Fp32BitPrime(123_456_789)
+        );
+    }
+
     #[test]
     #[cfg(debug_assertions)]
     #[should_panic]
@@ -0,0 +1,169 @@
+//!
+//! How many bytes of messages a helper writes to a peer at once. Steps of a query send their
+//! messages concurrently, and a transport that writes every one of them on its own pays for a
+//! system call, and often a packet, per message. Instead, messages that queue up while a write
+//! is in progress are written together with the next one, up to the limit set by a
+//! [`BatchPolicy`].
+//!
+//! Small batches keep latency low while there is little to send, big ones get more throughput
+//! out of a busy connection. [`AdaptiveBatch`] starts small and grows its batches for as long as
+//! messages keep queueing up behind them and throughput does not drop, and shrinks them again
+//! once writes slow down. [`FixedBatch`] never changes the limit, which is what benchmarks
+//! compare the adaptive policy with.
+//!
+//! Every connection has its own policy, which sees every write to it: how many bytes it carried,
+//! how long it took and whether messages were left waiting for the next one. Writes only take
+//! long if the peer does not read fast enough and the connection backs up, so the time they take
+//! stands in for the latency and throughput of the channel.
+//!
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Smallest limit [`AdaptiveBatch`] uses by default, and the one it starts with.
+pub const DEFAULT_MIN_BATCH: usize = 16 * 1024;
+
+/// Largest limit [`AdaptiveBatch`] uses by default.
+pub const DEFAULT_MAX_BATCH: usize = 1024 * 1024;
+
+/// Throughput of a batch may drop by this much against the previous one and still count as
+/// stable, as measurements of writes that take microseconds are noisy.
+const THROUGHPUT_TOLERANCE: f64 = 0.9;
+
+/// Writes that take this many times longer than usual mean the connection backs up.
+const SLOW_WRITE: u32 = 4;
+
+/// Decides how many bytes of messages go into a single write to a peer.
+pub trait BatchPolicy: Debug + Send {
+    /// Most bytes the next write may carry. A message bigger than that is still written, on its
+    /// own.
+    fn limit(&self) -> usize;
+
+    /// Called after every write with the number of bytes it carried and the time it took.
+    /// `backlog` is set if there were messages left that did not fit into the limit.
+    fn observe(&mut self, bytes: usize, elapsed: Duration, backlog: bool);
+}
+
+/// Batches of a fixed size. A limit of zero writes every message on its own.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedBatch(pub usize);
+
+impl BatchPolicy for FixedBatch {
+    fn limit(&self) -> usize {
+        self.0
+    }
+
+    fn observe(&mut self, _bytes: usize, _elapsed: Duration, _backlog: bool) {}
+}
+
+/// Batches that grow from `min` to `max` bytes while messages keep queueing up and throughput
+/// holds, doubling at a time, and halve whenever a write takes much longer than usual.
+#[derive(Debug, Clone)]
+pub struct AdaptiveBatch {
+    min: usize,
+    max: usize,
+    limit: usize,
+    /// Smoothed time of writes.
+    latency: Option<Duration>,
+    /// Bytes per second of the last write that was held back by the limit.
+    throughput: Option<f64>,
+}
+
+impl AdaptiveBatch {
+    #[must_use]
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        Self {
+            min,
+            max: max.max(min),
+            limit: min,
+            latency: None,
+            throughput: None,
+        }
+    }
+}
+
+impl Default for AdaptiveBatch {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_BATCH, DEFAULT_MAX_BATCH)
+    }
+}
+
+impl BatchPolicy for AdaptiveBatch {
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn observe(&mut self, bytes: usize, elapsed: Duration, backlog: bool) {
+        let usual = self.latency.unwrap_or(elapsed);
+        self.latency = Some((usual * 7 + elapsed) / 8);
+
+        if elapsed > usual * SLOW_WRITE {
+            self.limit = (self.limit / 2).max(self.min);
+            self.throughput = None;
+        } else if backlog {
+            #[allow(clippy::cast_precision_loss)]
+            let throughput = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            if self
+                .throughput
+                .map_or(true, |last| throughput >= last * THROUGHPUT_TOLERANCE)
+            {
+                self.limit = self.limit.saturating_mul(2).min(self.max);
+            }
+            self.throughput = Some(throughput);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::batching::{AdaptiveBatch, BatchPolicy, FixedBatch};
+    use std::time::Duration;
+
+    #[test]
+    fn fixed() {
+        let mut policy = FixedBatch(100);
+        policy.observe(100, Duration::from_secs(10), true);
+        assert_eq!(100, policy.limit());
+    }
+
+    #[test]
+    fn grows_with_backlog() {
+        let mut policy = AdaptiveBatch::new(10, 50);
+        assert_eq!(10, policy.limit());
+
+        // nothing waits behind the batch, so a bigger one would not help
+        policy.observe(10, Duration::from_millis(1), false);
+        assert_eq!(10, policy.limit());
+
+        for (bytes, limit) in [(10, 20), (20, 40), (40, 50), (50, 50)] {
+            policy.observe(bytes, Duration::from_millis(1), true);
+            assert_eq!(limit, policy.limit());
+        }
+    }
+
+    #[test]
+    fn holds_when_throughput_drops() {
+        let mut policy = AdaptiveBatch::new(10, 1000);
+        policy.observe(10, Duration::from_millis(1), true);
+        assert_eq!(20, policy.limit());
+        // twice the bytes in three times the time
+        policy.observe(20, Duration::from_millis(3), true);
+        assert_eq!(20, policy.limit());
+    }
+
+    #[test]
+    fn shrinks_when_slow() {
+        let mut policy = AdaptiveBatch::new(10, 1000);
+        for _ in 0..3 {
+            policy.observe(policy.limit(), Duration::from_millis(1), true);
+        }
+        assert_eq!(80, policy.limit());
+
+        policy.observe(80, Duration::from_millis(10), true);
+        assert_eq!(40, policy.limit());
+        for _ in 0..5 {
+            policy.observe(10, Duration::from_secs(1), false);
+        }
+        assert_eq!(10, policy.limit());
+    }
+}
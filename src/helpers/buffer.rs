@@ -0,0 +1,1143 @@
+//!
+//! Buffer for messages that arrived to a helper but have not been received by the protocol yet.
+//! Messages may arrive before or after the protocol asks for them: in the former case the payload
+//! is stored until somebody receives it, in the latter the receiver registers a waiter and gets
+//! woken up as soon as the message arrives.
+//!
+//! Stored messages count towards the memory limit of the query, if the buffer was created
+//! [`with_memory`](MessageBuffer::with_memory). Once a message does not fit, or the query is
+//! aborted, the buffer fails: it drops all messages it holds and every pending and future `take`
+//! returns the [`Failure`].
+//!
+//! Helpers that run ahead of this one may send a message with the same key as one that is still
+//! in the buffer, the message of the next step of the same kind. Such messages are queued behind
+//! it and received in the order they arrived, which is the order they were sent in, as long as
+//! the transport delivers messages from every peer in order.
+//!
+//! A message may be received by more than one consumer, with
+//! [`take_shared`](MessageBuffer::take_shared). Every consumer gets its own copy of the payload,
+//! and the message stays in the buffer until the last of them has received it.
+//!
+//! Payloads are [`Bytes`], so they may share the allocation with other messages that were read
+//! from the network together with them. Only the size of the payload itself is accounted for.
+//!
+//! Messages that nobody receives would otherwise stay in the buffer for as long as it lives.
+//! [`MessageBuffer::expire`] gives up on the ones that have waited longer than a time to live,
+//! and failing the buffer gives up on all of them. Either way, the payload is dropped and a
+//! [`DeadLetter`] describing the message is kept for postmortem analysis, up to
+//! [`MAX_DEAD_LETTERS`] of them.
+//!
+//! Peers may run ahead of this helper and send messages for a stage it has not reached yet. A
+//! buffer with a [`window`](MessageBuffer::window) holds only that many bytes of messages from
+//! every peer in memory. Messages that arrive once the window of their peer is full are spilled
+//! to a [`BlobStore`], sealed with its data key, and read back when they are received. Without a
+//! store, the window is [full](MessageBuffer::window_full) instead, and the transport is
+//! expected to stop reading from the peer until enough of its messages are received.
+//!
+//! The buffer itself never touches the disk, so that transports can keep it behind a lock
+//! without holding that lock for the duration of file I/O. They ask the buffer whether a message
+//! has to be [spilled](MessageBuffer::spill_to), [write](Spilled::write) it with the lock
+//! released and [put](MessageBuffer::put_spilled) it in the buffer. Receivers of a spilled
+//! message get [`Take::Spilled`] and [read](Spilled::read) it back the same way. A message that
+//! changed on disk fails to open, and the transport fails the buffer with [`Failure::Spill`]
+//! instead of feeding the protocol whatever the file holds now.
+//!
+//! Senders may [close](MessageBuffer::close) the channel of a key once they have nothing more to
+//! send on it, for example when a protocol finishes early. The buffer keeps that in order with the
+//! messages: the messages sent before it are received first, and the receive that comes after
+//! them gets [`Take::Closed`] instead of waiting for a message that is never going to arrive,
+//! and so do receivers that already wait for it. The channel is forgotten after that, and a later
+//! step may use the same key again.
+//!
+//! [`MessageBuffer::pending`] lists every message the buffer holds or has receivers waiting for,
+//! which is the first thing to look at when a query does not make progress.
+//!
+use crate::helpers::memory::{LimitExceeded, MemoryTracker, Reservation};
+use crate::helpers::ring::HelperAddr;
+use crate::storage::{self, BlobStore};
+use crate::telemetry;
+use bytes::Bytes;
+use rand::thread_rng;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{trace, warn};
+
+/// Number of dead letters a buffer keeps. Later ones are only counted.
+pub const MAX_DEAD_LETTERS: usize = 1024;
+
+/// Number the next spilled message is named after, so that buffers may share a store.
+static SPILL_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// An entry in the message buffer. Either the message arrived before all of its consumers
+/// asked for it, or some of them are already waiting for it to arrive.
+#[derive(Debug)]
+enum BufItem {
+    /// Messages with the same key that arrived, in the order they did. Never empty, and only the
+    /// first of them may have been received by some of its consumers.
+    Payload(VecDeque<Stored>),
+    /// Pending `receive` calls that will be woken up as soon as the message arrives, along with
+    /// the number of consumers the message has.
+    Waiters(Vec<oneshot::Sender<Delivery>>, usize),
+}
+
+/// Message payload that has not been received by all of its consumers yet.
+#[derive(Debug)]
+struct Stored {
+    payload: Payload,
+    /// Memory the payload holds, released once the last consumer has received it.
+    #[allow(dead_code)]
+    reservation: Option<Reservation>,
+    arrived: Instant,
+    /// Number of consumers that have not received the message yet. Not known until the first
+    /// of them asks for it.
+    remaining: Option<usize>,
+}
+
+impl Stored {
+    /// Marker of a closed channel, which `remaining` consumers have not seen yet.
+    fn closed(remaining: Option<usize>) -> Self {
+        Self {
+            payload: Payload::Closed,
+            reservation: None,
+            arrived: Instant::now(),
+            remaining,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Payload {
+    Memory(Bytes),
+    Spilled(Arc<Spilled>),
+    /// Not a message, the sender closed the channel after the messages before this one.
+    Closed,
+}
+
+impl Payload {
+    fn len(&self) -> usize {
+        match self {
+            Self::Memory(bytes) => bytes.len(),
+            Self::Spilled(spilled) => spilled.size,
+            Self::Closed => 0,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self, Self::Closed)
+    }
+}
+
+/// Payload of a message spilled to a [`BlobStore`]. The blob is removed when this is dropped.
+#[derive(Debug)]
+pub struct Spilled {
+    store: Arc<BlobStore>,
+    name: String,
+    size: usize,
+}
+
+impl Spilled {
+    /// Seals `payload` and writes it to `store`. Callers that keep the buffer behind a lock
+    /// should not hold it while they do.
+    ///
+    /// ## Errors
+    /// If the message cannot be written to the store.
+    pub fn write(store: Arc<BlobStore>, payload: &[u8]) -> Result<Self, storage::Error> {
+        let name = format!("spill-{}", SPILL_SEQ.fetch_add(1, Ordering::Relaxed));
+        store.put(&name, payload, &mut thread_rng())?;
+        Ok(Self {
+            store,
+            name,
+            size: payload.len(),
+        })
+    }
+
+    /// Reads the message back from the store. Same as for [`Self::write`], callers should not
+    /// hold a lock on the buffer while they do.
+    ///
+    /// ## Errors
+    /// If the message cannot be read, or it changed since it was written.
+    pub fn read(&self) -> Result<Bytes, storage::Error> {
+        self.store.get(&self.name).map(Bytes::from)
+    }
+}
+
+impl Drop for Spilled {
+    fn drop(&mut self) {
+        // nothing to do about it, blobs in the store are never read again anyway
+        let _ = self.store.remove(&self.name);
+    }
+}
+
+/// Reason the buffer stopped working.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Messages that arrived do not fit into the memory limit of the query.
+    MemoryLimitExceeded(LimitExceeded),
+    /// Query was aborted by the helper at `by`, or by this helper if it is not set.
+    Aborted {
+        by: Option<HelperAddr>,
+        reason: String,
+    },
+    /// Helper at `by` sent a message of `size` bytes, which is more than the `limit` this
+    /// helper accepts.
+    MessageTooBig {
+        by: HelperAddr,
+        size: usize,
+        limit: usize,
+    },
+    /// A message could not be written to or read back from the spill directory.
+    Spill(String),
+    /// Nothing arrived from the helper at `peer` for `silent`, not even heartbeats.
+    PeerGone { peer: HelperAddr, silent: Duration },
+}
+
+/// Why a message was given up on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Orphaned {
+    /// Nobody received it within the time to live.
+    Expired { ttl: Duration },
+    /// The buffer failed before anybody received it.
+    Failed(Failure),
+}
+
+/// Message that arrived but was never received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub source: HelperAddr,
+    /// Key of the message, as it is formatted with `Debug`.
+    pub key: String,
+    /// Size of the payload in bytes.
+    pub size: usize,
+    /// How long the message waited in the buffer before it was given up on.
+    pub waited: Duration,
+    pub reason: Orphaned,
+}
+
+impl Display for DeadLetter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {} ({} bytes) after {:?}: {:?}",
+            self.source, self.key, self.size, self.waited, self.reason
+        )
+    }
+}
+
+/// Message that is in the buffer, or that somebody waits for, at the time it was listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pending {
+    pub source: HelperAddr,
+    /// Key of the message, as it is formatted with `Debug`.
+    pub key: String,
+    pub state: PendingState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingState {
+    /// Message arrived `waited` ago and has not been received by all of its consumers yet, or by
+    /// any of them if `remaining` is not set.
+    Arrived {
+        size: usize,
+        waited: Duration,
+        remaining: Option<usize>,
+    },
+    /// Message has not arrived yet, `receivers` of its `consumers` wait for it.
+    Awaited { receivers: usize, consumers: usize },
+    /// Sender closed the channel `waited` ago, after the messages listed before this.
+    Closed { waited: Duration },
+}
+
+impl Display for Pending {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}: ", self.source, self.key)?;
+        match &self.state {
+            PendingState::Arrived {
+                size,
+                waited,
+                remaining,
+            } => {
+                write!(f, "arrived {waited:?} ago ({size} bytes)")?;
+                match remaining {
+                    Some(remaining) => write!(f, ", {remaining} consumers left"),
+                    None => write!(f, ", not received yet"),
+                }
+            }
+            PendingState::Awaited {
+                receivers,
+                consumers,
+            } => write!(
+                f,
+                "not arrived, {receivers} of {consumers} consumers waiting"
+            ),
+            PendingState::Closed { waited } => write!(f, "closed {waited:?} ago"),
+        }
+    }
+}
+
+/// Counters describing how the message buffer of a helper was used.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufStats {
+    /// Number of messages that arrived to this helper.
+    pub writes: u64,
+    /// Number of messages taken off the buffer by `receive`.
+    pub removes: u64,
+    /// Number of times `receive` was called before the message arrived.
+    pub misses: u64,
+    /// Number of messages that were given up on, including those that did not fit into the
+    /// dead-letter list.
+    pub dead_letters: u64,
+    /// Number of messages that did not fit into the window of their peer and were spilled to
+    /// disk.
+    pub spills: u64,
+}
+
+/// What a receiver that waits for a message gets: its payload, or `None` if the sender closed the
+/// channel instead of sending it.
+pub type Delivery = Result<Option<Bytes>, Failure>;
+
+/// Result of an attempt to take a message off the buffer.
+pub enum Take {
+    /// Message was already there.
+    Ready(Bytes),
+    /// Message was already there, but it was spilled to disk. Receivers [read](Spilled::read) it
+    /// and [fail](MessageBuffer::spill_failed) the buffer if they cannot.
+    Spilled(Arc<Spilled>),
+    /// Sender closed the channel and there are no more messages on it.
+    Closed,
+    /// Message has not arrived yet, this receiver resolves when it does, when the channel is
+    /// closed or when the buffer fails.
+    Wait(oneshot::Receiver<Delivery>),
+}
+
+/// Messages are addressable by the helper that sent them and a key `K` that identifies
+/// the message itself.
+#[derive(Debug)]
+pub struct MessageBuffer<K> {
+    items: HashMap<(HelperAddr, K), BufItem>,
+    stats: BufStats,
+    memory: Option<Arc<MemoryTracker>>,
+    failed: Option<Failure>,
+    dead_letters: Vec<DeadLetter>,
+    /// Bytes of messages from every peer this buffer holds in memory at most.
+    window: Option<usize>,
+    /// Bytes of messages from the helper on the left and on the right it holds now.
+    held: [usize; 2],
+    spill: Option<Arc<BlobStore>>,
+}
+
+impl<K> Default for MessageBuffer<K> {
+    fn default() -> Self {
+        Self {
+            items: HashMap::new(),
+            stats: BufStats::default(),
+            memory: None,
+            failed: None,
+            dead_letters: Vec::new(),
+            window: None,
+            held: [0; 2],
+            spill: None,
+        }
+    }
+}
+
+impl<K> MessageBuffer<K> {
+    /// Creates a buffer that accounts messages it stores in `memory`.
+    #[must_use]
+    pub fn with_memory(memory: Arc<MemoryTracker>) -> Self {
+        Self {
+            memory: Some(memory),
+            ..Self::default()
+        }
+    }
+
+    /// Holds at most `window` bytes of messages from every peer in memory, or any amount if it
+    /// is not set. Messages that do not fit are spilled to `spill`, if it is set.
+    #[must_use]
+    pub fn window(self, window: Option<usize>, spill: Option<Arc<BlobStore>>) -> Self {
+        Self {
+            window,
+            spill,
+            ..self
+        }
+    }
+
+    /// Store a message of `size` bytes from `source` has to be spilled to, because it does not
+    /// fit into the window of `source`, if there is one. The caller [writes](Spilled::write)
+    /// the message there and puts it in the buffer with [`put_spilled`](Self::put_spilled).
+    #[must_use]
+    pub fn spill_to(&self, source: HelperAddr, size: usize) -> Option<Arc<BlobStore>> {
+        let fits = self
+            .window
+            .map_or(true, |window| self.held[index(source)] + size <= window);
+        match &self.spill {
+            Some(store) if !fits && self.failed.is_none() => Some(Arc::clone(store)),
+            _ => None,
+        }
+    }
+
+    /// Whether the window of `source` is full and there is nowhere to spill to, so no more
+    /// messages should be read from it until some of them are received. Never true once the
+    /// buffer has failed, since there is nothing to wait for then.
+    #[must_use]
+    pub fn window_full(&self, source: HelperAddr) -> bool {
+        self.failed.is_none()
+            && self.spill.is_none()
+            && self
+                .window
+                .map_or(false, |window| self.held[index(source)] >= window)
+    }
+
+    /// Bytes of messages from `source` held in memory.
+    #[must_use]
+    pub fn held(&self, source: HelperAddr) -> usize {
+        self.held[index(source)]
+    }
+
+    /// Forgets about a stored message from `source` that is no longer held.
+    fn release(held: &mut [usize; 2], source: HelperAddr, stored: &Stored) {
+        if let Payload::Memory(bytes) = &stored.payload {
+            held[index(source)] -= bytes.len();
+        }
+    }
+}
+
+/// Hands `delivery` to every receiver that still waits, and returns how many of them there
+/// were. Receivers are gone if their receive was cancelled, for example by a timeout or because a
+/// receive it was joined with failed.
+fn deliver(waiters: Vec<oneshot::Sender<Delivery>>, delivery: &Delivery) -> usize {
+    let mut delivered = 0;
+    for waiter in waiters {
+        if waiter.send(delivery.clone()).is_ok() {
+            delivered += 1;
+        }
+    }
+    delivered
+}
+
+fn index(source: HelperAddr) -> usize {
+    match source {
+        HelperAddr::Left => 0,
+        HelperAddr::Right => 1,
+    }
+}
+
+impl<K: Hash + Eq + Debug> MessageBuffer<K> {
+    /// Stores the message that arrived from `source` or hands it over to the receiver that is
+    /// already waiting for it.
+    ///
+    /// ## Errors
+    /// If storing the message would exceed the memory limit of the query, or the buffer failed
+    /// already.
+    pub fn put(&mut self, source: HelperAddr, key: K, payload: Bytes) -> Result<(), Failure> {
+        self.insert(source, key, payload, None)
+    }
+
+    /// Same as [`put`](Self::put), for a message that [`spill_to`](Self::spill_to) said has to be
+    /// spilled and that was written to the store as `spilled`. Receivers that wait for it get
+    /// `payload` and the spilled copy is dropped.
+    ///
+    /// ## Errors
+    /// If the buffer failed already.
+    pub fn put_spilled(
+        &mut self,
+        source: HelperAddr,
+        key: K,
+        payload: Bytes,
+        spilled: Spilled,
+    ) -> Result<(), Failure> {
+        self.insert(source, key, payload, Some(spilled))
+    }
+
+    fn insert(
+        &mut self,
+        source: HelperAddr,
+        key: K,
+        payload: Bytes,
+        spilled: Option<Spilled>,
+    ) -> Result<(), Failure> {
+        if let Some(e) = &self.failed {
+            return Err(e.clone());
+        }
+        self.stats.writes += 1;
+        trace!(?source, ?key, size = payload.len(), "message arrived");
+        let key = (source, key);
+        if matches!(self.items.get(&key), Some(BufItem::Payload(_))) {
+            trace!("message with the same key is in the buffer already, queueing it");
+            let (payload, reservation) = self.store(source, payload, spilled)?;
+            if let Some(BufItem::Payload(queue)) = self.items.get_mut(&key) {
+                queue.push_back(Stored {
+                    payload,
+                    reservation,
+                    arrived: Instant::now(),
+                    remaining: None,
+                });
+            }
+            telemetry::buffer_depth(self.depth());
+            return Ok(());
+        }
+        let remaining = match self.items.remove(&key) {
+            None => None,
+            Some(BufItem::Waiters(waiters, consumers)) => {
+                let delivered = deliver(waiters, &Ok(Some(payload.clone())));
+                if delivered == consumers {
+                    self.stats.removes += 1;
+                    telemetry::buffer_depth(self.depth());
+                    return Ok(());
+                }
+                // message waits for the consumers that have not asked for it yet, or gave up
+                // waiting for it and may ask again
+                Some(consumers - delivered)
+            }
+            Some(BufItem::Payload(_)) => unreachable!("queued above"),
+        };
+        let (payload, reservation) = self.store(source, payload, spilled)?;
+        self.items.insert(
+            key,
+            BufItem::Payload(VecDeque::from([Stored {
+                payload,
+                reservation,
+                arrived: Instant::now(),
+                remaining,
+            }])),
+        );
+        telemetry::buffer_depth(self.depth());
+        Ok(())
+    }
+
+    /// Closes the channel of `key` from `source`: receivers that wait for a message on it, and
+    /// the first receiver after the messages that are in the buffer already, get
+    /// [`Take::Closed`]. Does nothing if the buffer has failed.
+    pub fn close(&mut self, source: HelperAddr, key: K) {
+        if self.failed.is_some() {
+            return;
+        }
+        trace!(?source, ?key, "channel closed");
+        let key = (source, key);
+        let remaining = match self.items.remove(&key) {
+            None => None,
+            Some(BufItem::Payload(mut queue)) => {
+                queue.push_back(Stored::closed(None));
+                self.items.insert(key, BufItem::Payload(queue));
+                return;
+            }
+            Some(BufItem::Waiters(waiters, consumers)) => {
+                let delivered = deliver(waiters, &Ok(None));
+                if delivered == consumers {
+                    return;
+                }
+                Some(consumers - delivered)
+            }
+        };
+        self.items.insert(
+            key,
+            BufItem::Payload(VecDeque::from([Stored::closed(remaining)])),
+        );
+    }
+
+    /// Keeps `payload` in memory, unless it was spilled.
+    fn store(
+        &mut self,
+        source: HelperAddr,
+        payload: Bytes,
+        spilled: Option<Spilled>,
+    ) -> Result<(Payload, Option<Reservation>), Failure> {
+        if let Some(spilled) = spilled {
+            trace!(
+                ?source,
+                size = spilled.size,
+                "window is full, message was spilled"
+            );
+            self.stats.spills += 1;
+            return Ok((Payload::Spilled(Arc::new(spilled)), None));
+        }
+        let reservation = self.reserve(payload.len())?;
+        self.held[index(source)] += payload.len();
+        Ok((Payload::Memory(payload), reservation))
+    }
+
+    /// Takes the message off the buffer if it is there, otherwise registers a waiter for it.
+    ///
+    /// ## Errors
+    /// If the buffer failed because the query ran out of memory or was aborted.
+    ///
+    /// ## Panics
+    /// If there is somebody else waiting for the same message.
+    pub fn take(&mut self, source: HelperAddr, key: K) -> Result<Take, Failure> {
+        self.take_shared(source, key, 1)
+    }
+
+    /// Same as [`take`](Self::take), for a message that is received by `consumers` consumers,
+    /// every one of which calls this with the same number. Each of them gets the payload, which
+    /// stays in the buffer until all of them have.
+    ///
+    /// ## Errors
+    /// If the buffer failed because the query ran out of memory or was aborted.
+    ///
+    /// ## Panics
+    /// If `consumers` is zero, or the message is received more times than it has consumers.
+    pub fn take_shared(
+        &mut self,
+        source: HelperAddr,
+        key: K,
+        consumers: usize,
+    ) -> Result<Take, Failure> {
+        assert!(consumers > 0, "Messages must have at least one consumer");
+        if let Some(e) = &self.failed {
+            return Err(e.clone());
+        }
+        Ok(match self.items.entry((source, key)) {
+            Entry::Occupied(entry) => match entry.remove_entry() {
+                (key, BufItem::Payload(mut queue)) => {
+                    let stored = queue.front_mut().expect("queues are never empty");
+                    let remaining = stored.remaining.unwrap_or(consumers) - 1;
+                    let take = match &stored.payload {
+                        Payload::Memory(bytes) => Take::Ready(bytes.clone()),
+                        Payload::Spilled(spilled) => Take::Spilled(Arc::clone(spilled)),
+                        Payload::Closed => Take::Closed,
+                    };
+                    if remaining > 0 {
+                        stored.remaining = Some(remaining);
+                    } else {
+                        // the receivers own the message from now on
+                        let stored = queue.pop_front().unwrap();
+                        Self::release(&mut self.held, key.0, &stored);
+                        if !stored.payload.is_closed() {
+                            self.stats.removes += 1;
+                            telemetry::buffer_depth(self.depth());
+                        }
+                    }
+                    if !queue.is_empty() {
+                        self.items.insert(key, BufItem::Payload(queue));
+                    }
+                    if let Take::Closed = take {
+                        trace!("channel is closed");
+                    } else {
+                        trace!("message taken from the buffer");
+                    }
+                    take
+                }
+                ((source, key), BufItem::Waiters(mut waiters, expected)) => {
+                    // receivers whose receive was cancelled may ask again
+                    waiters.retain(|waiter| !waiter.is_closed());
+                    assert!(
+                        expected == consumers && waiters.len() < consumers,
+                        "Duplicated receive for {key:?} from {source:?}"
+                    );
+                    self.stats.misses += 1;
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    self.items
+                        .insert((source, key), BufItem::Waiters(waiters, expected));
+                    Take::Wait(rx)
+                }
+            },
+            Entry::Vacant(entry) => {
+                // message is not here yet, register interest and sleep until it arrives
+                self.stats.misses += 1;
+                trace!("message is not in the buffer yet, waiting for it");
+                let (tx, rx) = oneshot::channel();
+                entry.insert(BufItem::Waiters(vec![tx], consumers));
+                Take::Wait(rx)
+            }
+        })
+    }
+
+    /// Accounts `size` bytes of a stored message in the memory of the query, and fails the
+    /// buffer if they do not fit.
+    fn reserve(&mut self, size: usize) -> Result<Option<Reservation>, Failure> {
+        match self.memory.as_ref().map(|m| m.reserve(size)) {
+            Some(Err(e)) => {
+                let failure = Failure::MemoryLimitExceeded(e);
+                self.fail(failure.clone());
+                Err(failure)
+            }
+            Some(Ok(r)) => Ok(Some(r)),
+            None => Ok(None),
+        }
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> BufStats {
+        self.stats
+    }
+
+    /// Gives up on messages that arrived more than `ttl` before `now` and have not been received
+    /// since, and moves them to the dead-letter list. Returns how many there were.
+    pub fn expire(&mut self, now: Instant, ttl: Duration) -> usize {
+        let mut expired = 0;
+        let (stats, dead_letters, held) = (&mut self.stats, &mut self.dead_letters, &mut self.held);
+        self.items.retain(|(source, key), item| match item {
+            // messages queued behind the first one cannot be received before it, so they are
+            // given up on together
+            BufItem::Payload(queue) if now.saturating_duration_since(queue[0].arrived) > ttl => {
+                warn!(?source, ?key, "message was not received in {ttl:?}");
+                for stored in queue.drain(..).filter(|stored| !stored.payload.is_closed()) {
+                    expired += 1;
+                    Self::release(held, *source, &stored);
+                    Self::bury(
+                        stats,
+                        dead_letters,
+                        DeadLetter {
+                            source: *source,
+                            key: format!("{key:?}"),
+                            size: stored.payload.len(),
+                            waited: now.saturating_duration_since(stored.arrived),
+                            reason: Orphaned::Expired { ttl },
+                        },
+                    );
+                }
+                false
+            }
+            _ => true,
+        });
+        if expired > 0 {
+            telemetry::buffer_depth(self.depth());
+        }
+        expired
+    }
+
+    /// Messages that are in the buffer or have receivers waiting for them at `now`, ordered by
+    /// source and key.
+    #[must_use]
+    pub fn pending(&self, now: Instant) -> Vec<Pending> {
+        let mut pending = Vec::with_capacity(self.items.len());
+        for ((source, key), item) in &self.items {
+            let pending_item = |state| Pending {
+                source: *source,
+                key: format!("{key:?}"),
+                state,
+            };
+            match item {
+                BufItem::Payload(queue) => {
+                    pending.extend(queue.iter().map(|stored| {
+                        let waited = now.saturating_duration_since(stored.arrived);
+                        pending_item(if stored.payload.is_closed() {
+                            PendingState::Closed { waited }
+                        } else {
+                            PendingState::Arrived {
+                                size: stored.payload.len(),
+                                waited,
+                                remaining: stored.remaining,
+                            }
+                        })
+                    }));
+                }
+                BufItem::Waiters(waiters, consumers) => {
+                    pending.push(pending_item(PendingState::Awaited {
+                        receivers: waiters.len(),
+                        consumers: *consumers,
+                    }));
+                }
+            }
+        }
+        // sorting is stable, queued messages stay in the order they arrived
+        pending.sort_by(|a, b| (index(a.source), &a.key).cmp(&(index(b.source), &b.key)));
+        pending
+    }
+
+    /// Messages that were given up on, in the order they were, up to [`MAX_DEAD_LETTERS`].
+    #[must_use]
+    pub fn dead_letters(&self) -> &[DeadLetter] {
+        &self.dead_letters
+    }
+
+    /// Fails the buffer because the query was aborted by the helper at `by` (or this one, if
+    /// `None`). Does nothing if the buffer has failed already, so the original reason is kept.
+    pub fn abort(&mut self, by: Option<HelperAddr>, reason: String) {
+        if self.failed.is_none() {
+            self.fail(Failure::Aborted { by, reason });
+        }
+    }
+
+    /// Fails the buffer because the helper at `by` sent a message bigger than this helper
+    /// accepts. Does nothing if the buffer has failed already.
+    pub fn reject_too_big(&mut self, by: HelperAddr, size: usize, limit: usize) {
+        if self.failed.is_none() {
+            self.fail(Failure::MessageTooBig { by, size, limit });
+        }
+    }
+
+    /// Fails the buffer because the helper at `peer` is gone, see [`crate::helpers::liveness`].
+    /// Does nothing if the buffer has failed already.
+    pub fn peer_gone(&mut self, peer: HelperAddr, silent: Duration) {
+        if self.failed.is_none() {
+            self.fail(Failure::PeerGone { peer, silent });
+        }
+    }
+
+    /// Fails the buffer because a message could not be spilled or read back, for the `reason`.
+    /// Does nothing if the buffer has failed already.
+    pub fn spill_failed(&mut self, reason: String) {
+        if self.failed.is_none() {
+            self.fail(Failure::Spill(reason));
+        }
+    }
+
+    /// Reason this buffer failed, if it did.
+    #[must_use]
+    pub fn failure(&self) -> Option<&Failure> {
+        self.failed.as_ref()
+    }
+
+    fn fail(&mut self, failure: Failure) {
+        let now = Instant::now();
+        for ((source, key), item) in self.items.drain() {
+            match item {
+                BufItem::Waiters(waiters, _) => {
+                    for waiter in waiters {
+                        // receiver may have given up already, nothing to tell it then
+                        let _ = waiter.send(Err(failure.clone()));
+                    }
+                }
+                BufItem::Payload(queue) => {
+                    for stored in queue.into_iter().filter(|s| !s.payload.is_closed()) {
+                        Self::release(&mut self.held, source, &stored);
+                        let letter = DeadLetter {
+                            source,
+                            key: format!("{key:?}"),
+                            size: stored.payload.len(),
+                            waited: now.saturating_duration_since(stored.arrived),
+                            reason: Orphaned::Failed(failure.clone()),
+                        };
+                        Self::bury(&mut self.stats, &mut self.dead_letters, letter);
+                    }
+                }
+            }
+        }
+        self.failed = Some(failure);
+    }
+
+    fn bury(stats: &mut BufStats, dead_letters: &mut Vec<DeadLetter>, letter: DeadLetter) {
+        stats.dead_letters += 1;
+        if dead_letters.len() < MAX_DEAD_LETTERS {
+            dead_letters.push(letter);
+        }
+    }
+
+    /// Number of messages that arrived but have not been received or given up on yet.
+    #[must_use]
+    pub fn depth(&self) -> u64 {
+        self.stats.writes - self.stats.removes - self.stats.dead_letters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::buffer::{Failure, MessageBuffer, Orphaned, PendingState, Spilled, Take};
+    use crate::helpers::ring::HelperAddr;
+    use crate::storage::{self, BlobStore};
+    use bytes::Bytes;
+    use rand::thread_rng;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn spill(buf: &mut MessageBuffer<i32>, source: HelperAddr, key: i32, payload: &'static [u8]) {
+        let payload = Bytes::from_static(payload);
+        match buf.spill_to(source, payload.len()) {
+            Some(store) => {
+                let spilled = Spilled::write(store, &payload).unwrap();
+                buf.put_spilled(source, key, payload, spilled).unwrap();
+            }
+            None => buf.put(source, key, payload).unwrap(),
+        }
+    }
+
+    #[test]
+    fn spills() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-spill-{}", std::process::id()));
+        let store = Arc::new(BlobStore::ephemeral(&root, &mut thread_rng()).unwrap());
+        let mut buf = MessageBuffer::default().window(Some(8), Some(Arc::clone(&store)));
+        spill(&mut buf, HelperAddr::Left, 1, b"first");
+        spill(&mut buf, HelperAddr::Left, 2, b"second");
+        // window is per peer
+        spill(&mut buf, HelperAddr::Right, 1, b"third");
+        assert_eq!(1, buf.stats().spills);
+        assert_eq!(5, buf.held(HelperAddr::Left));
+        assert!(!buf.window_full(HelperAddr::Left));
+        assert_eq!(1, store.names().unwrap().len());
+
+        let second = match buf.take(HelperAddr::Left, 2) {
+            Ok(Take::Spilled(spilled)) => spilled,
+            _ => panic!("message was not spilled"),
+        };
+        assert_eq!(b"second"[..], second.read().unwrap());
+        assert!(matches!(
+            buf.take(HelperAddr::Left, 1),
+            Ok(Take::Ready(p)) if p == b"first"[..]
+        ));
+        assert_eq!(0, buf.held(HelperAddr::Left));
+        drop(second);
+        assert!(store.names().unwrap().is_empty());
+        drop((buf, store));
+        std::fs::remove_dir(root).unwrap();
+    }
+
+    #[test]
+    fn corrupted_spill() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-corrupt-{}", std::process::id()));
+        let store = Arc::new(BlobStore::ephemeral(&root, &mut thread_rng()).unwrap());
+        let mut buf = MessageBuffer::default().window(Some(0), Some(store));
+        spill(&mut buf, HelperAddr::Left, 1, b"payload");
+        let dir = std::fs::read_dir(&root).unwrap().next().unwrap().unwrap();
+        let file = std::fs::read_dir(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        std::fs::write(file.path(), b"paYload").unwrap();
+
+        let spilled = match buf.take(HelperAddr::Left, 1) {
+            Ok(Take::Spilled(spilled)) => spilled,
+            _ => panic!("message was not spilled"),
+        };
+        let err = spilled.read().unwrap_err();
+        assert!(matches!(err, storage::Error::Corrupted(_)), "{err}");
+        buf.spill_failed(err.to_string());
+        assert!(matches!(buf.failure(), Some(Failure::Spill(_))));
+        drop((spilled, buf));
+        std::fs::remove_dir(root).unwrap();
+    }
+
+    #[test]
+    fn window_full() {
+        let mut buf = MessageBuffer::default().window(Some(4), None);
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"full"))
+            .unwrap();
+        assert!(buf.window_full(HelperAddr::Left));
+        assert!(!buf.window_full(HelperAddr::Right));
+        assert!(matches!(buf.take(HelperAddr::Left, 1), Ok(Take::Ready(_))));
+        assert!(!buf.window_full(HelperAddr::Left));
+    }
+
+    #[test]
+    fn expire() {
+        let mut buf = MessageBuffer::default();
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"old"))
+            .unwrap();
+        let later = Instant::now() + Duration::from_secs(10);
+        buf.put(HelperAddr::Right, 2, Bytes::from_static(b"new"))
+            .unwrap();
+        assert!(matches!(buf.take(HelperAddr::Left, 3), Ok(Take::Wait(_))));
+
+        assert_eq!(0, buf.expire(later, Duration::from_secs(20)));
+        // both messages are old by now, but waiters never expire
+        assert_eq!(2, buf.expire(later, Duration::from_secs(5)));
+        assert_eq!(0, buf.depth());
+        assert_eq!(2, buf.stats().dead_letters);
+
+        let mut letters = buf.dead_letters().to_vec();
+        letters.sort_by_key(|l| l.key.clone());
+        assert_eq!(
+            (HelperAddr::Left, "1", 3),
+            (letters[0].source, &*letters[0].key, letters[0].size)
+        );
+        assert_eq!(
+            Orphaned::Expired {
+                ttl: Duration::from_secs(5)
+            },
+            letters[1].reason
+        );
+
+        // the same message may arrive again, and be received then
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"again"))
+            .unwrap();
+        assert!(matches!(buf.take(HelperAddr::Left, 1), Ok(Take::Ready(_))));
+    }
+
+    #[test]
+    fn shared() {
+        let mut buf = MessageBuffer::default();
+        let waiting = match buf.take_shared(HelperAddr::Left, 1, 2).unwrap() {
+            Take::Wait(rx) => rx,
+            Take::Ready(_) | Take::Spilled(_) | Take::Closed => {
+                panic!("message has not arrived yet")
+            }
+        };
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"shared"))
+            .unwrap();
+        let received = waiting.blocking_recv().unwrap().unwrap().unwrap();
+        assert_eq!(b"shared", &received[..]);
+        // kept for the other consumer
+        assert_eq!(1, buf.depth());
+        assert!(matches!(
+            buf.take_shared(HelperAddr::Left, 1, 2),
+            Ok(Take::Ready(p)) if p == b"shared"[..]
+        ));
+        assert_eq!(0, buf.depth());
+        assert!(matches!(buf.take(HelperAddr::Left, 1), Ok(Take::Wait(_))));
+    }
+
+    #[test]
+    fn queued() {
+        let mut buf = MessageBuffer::default();
+        for payload in [&b"first"[..], b"second", b"third"] {
+            buf.put(HelperAddr::Left, 1, Bytes::from_static(payload))
+                .unwrap();
+        }
+        assert_eq!(3, buf.depth());
+        assert_eq!(3, buf.pending(Instant::now()).len());
+
+        // the first message is kept until all of its consumers have it
+        for _ in 0..2 {
+            assert!(matches!(
+                buf.take_shared(HelperAddr::Left, 1, 2),
+                Ok(Take::Ready(p)) if p == b"first"[..]
+            ));
+        }
+        assert!(matches!(
+            buf.take(HelperAddr::Left, 1),
+            Ok(Take::Ready(p)) if p == b"second"[..]
+        ));
+        assert_eq!(1, buf.depth());
+
+        buf.abort(None, "stop".into());
+        assert_eq!(1, buf.dead_letters().len());
+        assert_eq!(5, buf.dead_letters()[0].size);
+    }
+
+    #[test]
+    fn close() {
+        let mut buf = MessageBuffer::default();
+        let waiting = match buf.take_shared(HelperAddr::Left, 1, 2).unwrap() {
+            Take::Wait(rx) => rx,
+            Take::Ready(_) | Take::Spilled(_) | Take::Closed => panic!("channel is not closed yet"),
+        };
+        buf.close(HelperAddr::Left, 1);
+        assert_eq!(None, waiting.blocking_recv().unwrap().unwrap());
+        // the other consumer finds out when it asks
+        assert!(matches!(
+            buf.take_shared(HelperAddr::Left, 1, 2),
+            Ok(Take::Closed)
+        ));
+
+        // messages sent before the channel was closed are received first
+        buf.put(HelperAddr::Right, 1, Bytes::from_static(b"last"))
+            .unwrap();
+        buf.close(HelperAddr::Right, 1);
+        buf.put(HelperAddr::Right, 1, Bytes::from_static(b"next step"))
+            .unwrap();
+        let pending = buf.pending(Instant::now());
+        assert!(matches!(pending[1].state, PendingState::Closed { .. }));
+        assert!(pending[1].to_string().starts_with("Right 1: closed"));
+        assert!(matches!(
+            buf.take(HelperAddr::Right, 1),
+            Ok(Take::Ready(p)) if p == b"last"[..]
+        ));
+        assert!(matches!(buf.take(HelperAddr::Right, 1), Ok(Take::Closed)));
+        assert!(matches!(
+            buf.take(HelperAddr::Right, 1),
+            Ok(Take::Ready(p)) if p == b"next step"[..]
+        ));
+        assert!(buf.pending(Instant::now()).is_empty());
+        assert_eq!(0, buf.depth());
+        assert_eq!((2, 2), (buf.stats().writes, buf.stats().removes));
+
+        // closed channels are not dead letters
+        buf.close(HelperAddr::Left, 2);
+        buf.abort(None, "stop".into());
+        assert!(buf.dead_letters().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicated receive")]
+    fn too_many_consumers() {
+        let mut buf = MessageBuffer::<u32>::default();
+        let _first = buf.take_shared(HelperAddr::Left, 1, 2).unwrap();
+        let _second = buf.take_shared(HelperAddr::Left, 1, 2).unwrap();
+        buf.take_shared(HelperAddr::Left, 1, 2).unwrap();
+    }
+
+    #[test]
+    fn cancelled_receive() {
+        let mut buf = MessageBuffer::default();
+        // receive is dropped before the message arrives, and asks again after it did
+        drop(buf.take(HelperAddr::Left, 1).unwrap());
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"late"))
+            .unwrap();
+        assert_eq!(1, buf.depth());
+        assert!(matches!(
+            buf.take(HelperAddr::Left, 1),
+            Ok(Take::Ready(p)) if p == b"late"[..]
+        ));
+
+        // a consumer of a shared message gives up, the other one still gets it
+        drop(buf.take_shared(HelperAddr::Left, 2, 2).unwrap());
+        let waiting = match buf.take_shared(HelperAddr::Left, 2, 2).unwrap() {
+            Take::Wait(rx) => rx,
+            Take::Ready(_) | Take::Spilled(_) | Take::Closed => {
+                panic!("message has not arrived yet")
+            }
+        };
+        buf.put(HelperAddr::Left, 2, Bytes::from_static(b"shared"))
+            .unwrap();
+        assert!(waiting.blocking_recv().unwrap().is_ok());
+        assert!(matches!(
+            buf.take_shared(HelperAddr::Left, 2, 2),
+            Ok(Take::Ready(_))
+        ));
+        assert_eq!(0, buf.depth());
+
+        drop(buf.take(HelperAddr::Right, 1).unwrap());
+        buf.close(HelperAddr::Right, 1);
+        assert!(matches!(buf.take(HelperAddr::Right, 1), Ok(Take::Closed)));
+    }
+
+    #[test]
+    fn pending() {
+        let mut buf = MessageBuffer::default();
+        let now = Instant::now();
+        buf.put(HelperAddr::Right, 1, Bytes::from_static(b"early"))
+            .unwrap();
+        assert!(matches!(
+            buf.take_shared(HelperAddr::Left, 2, 3),
+            Ok(Take::Wait(_))
+        ));
+
+        let pending = buf.pending(now + Duration::from_secs(3));
+        assert_eq!(2, pending.len());
+        assert_eq!(
+            (HelperAddr::Left, "2"),
+            (pending[0].source, &*pending[0].key)
+        );
+        assert_eq!(
+            PendingState::Awaited {
+                receivers: 1,
+                consumers: 3
+            },
+            pending[0].state
+        );
+        assert!(matches!(
+            pending[1].state,
+            PendingState::Arrived { size: 5, waited, remaining: None } if waited <= Duration::from_secs(3)
+        ));
+        assert_eq!(
+            "Left 2: not arrived, 1 of 3 consumers waiting",
+            pending[0].to_string()
+        );
+
+        assert!(matches!(buf.take(HelperAddr::Right, 1), Ok(Take::Ready(_))));
+        assert_eq!(1, buf.pending(now).len());
+    }
+
+    #[test]
+    fn abort() {
+        let mut buf = MessageBuffer::default();
+        buf.put(HelperAddr::Left, 1, Bytes::from_static(b"orphan"))
+            .unwrap();
+        buf.abort(None, "test".to_owned());
+
+        let failure = Failure::Aborted {
+            by: None,
+            reason: "test".to_owned(),
+        };
+        assert_eq!(1, buf.dead_letters().len());
+        assert_eq!(Orphaned::Failed(failure), buf.dead_letters()[0].reason);
+        assert_eq!(0, buf.depth());
+    }
+}
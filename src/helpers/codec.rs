@@ -0,0 +1,143 @@
+//!
+//! Encoding of messages exchanged between helpers. The transport only moves bytes around, so it is
+//! up to the codec to decide how compact those bytes are. Control messages are rare and benefit
+//! from being readable, while shares exchanged during the protocol execution are numerous and
+//! should be encoded as tightly as possible.
+//!
+use crate::error::BoxError;
+use crate::helpers::ring::Message;
+use bytes::{BufMut, BytesMut};
+use std::fmt::Debug;
+
+pub use crate::encoding::{field_size, read_fields, read_shares, write_fields, write_shares};
+
+/// Converts messages into bytes and back.
+pub trait Codec: Debug + Send + Sync + 'static {
+    /// Name of the codec, which helpers compare when they connect.
+    const NAME: &'static str;
+
+    /// Encodes the given message into a byte buffer that can be handed to the transport.
+    ///
+    /// ## Errors
+    /// If message cannot be represented using this codec.
+    fn encode<T: Message>(msg: &T) -> Result<Box<[u8]>, BoxError>;
+
+    /// Same as `encode`, but appends the message to `out`, which avoids allocating a buffer for
+    /// every message if `out` is reused.
+    ///
+    /// ## Errors
+    /// If message cannot be represented using this codec.
+    fn encode_into<T: Message>(msg: &T, out: &mut BytesMut) -> Result<(), BoxError> {
+        out.extend_from_slice(&Self::encode(msg)?);
+        Ok(())
+    }
+
+    /// Decodes the message of type `T` from the given bytes.
+    ///
+    /// ## Errors
+    /// If bytes do not represent a valid message of type `T`.
+    fn decode<T: Message>(bytes: &[u8]) -> Result<T, BoxError>;
+}
+
+/// JSON encoding. Easy to inspect, but verbose.
+#[cfg(feature = "enable-serde")]
+#[derive(Debug)]
+pub struct Json;
+
+#[cfg(feature = "enable-serde")]
+impl Codec for Json {
+    const NAME: &'static str = "json";
+
+    fn encode<T: Message>(msg: &T) -> Result<Box<[u8]>, BoxError> {
+        Ok(serde_json::to_vec(msg)?.into_boxed_slice())
+    }
+
+    fn encode_into<T: Message>(msg: &T, out: &mut BytesMut) -> Result<(), BoxError> {
+        Ok(serde_json::to_writer(out.writer(), msg)?)
+    }
+
+    fn decode<T: Message>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Compact binary encoding that writes integers in their fixed-size little-endian form.
+/// This is the preferred choice for the high-volume share traffic.
+#[derive(Debug)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const NAME: &'static str = "bincode";
+
+    fn encode<T: Message>(msg: &T) -> Result<Box<[u8]>, BoxError> {
+        Ok(bincode::serialize(msg)?.into_boxed_slice())
+    }
+
+    fn encode_into<T: Message>(msg: &T, out: &mut BytesMut) -> Result<(), BoxError> {
+        Ok(bincode::serialize_into(out.writer(), msg)?)
+    }
+
+    fn decode<T: Message>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::helpers::codec::{read_fields, write_fields, Bincode, Codec, Json};
+    use crate::helpers::ring::message;
+    use bytes::BytesMut;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Msg {
+        index: u128,
+        d: u128,
+    }
+
+    message!(Msg => "test.msg");
+
+    fn round_trip<C: Codec>() -> usize {
+        let msg = Msg {
+            index: 1 << 100,
+            d: 7,
+        };
+        let bytes = C::encode(&msg).unwrap();
+        assert_eq!(msg, C::decode::<Msg>(&bytes).unwrap());
+
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        C::encode_into(&msg, &mut buf).unwrap();
+        assert_eq!(&bytes[..], &buf[6..]);
+
+        bytes.len()
+    }
+
+    #[test]
+    fn json() {
+        round_trip::<Json>();
+    }
+
+    #[test]
+    fn bincode() {
+        // two fixed-size 16 byte integers
+        assert_eq!(32, round_trip::<Bincode>());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Json::decode::<Msg>(b"not a message").is_err());
+        assert!(Bincode::decode::<Msg>(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn fields() {
+        let values = [0_u128, 1, 30].map(Fp31::from);
+        let mut bytes = vec![42];
+        write_fields(&values, &mut bytes);
+        assert_eq!(&[42, 0, 1, 30], &bytes[..]);
+        assert_eq!(&values[..], read_fields::<Fp31>(&bytes[1..]).unwrap());
+
+        assert!(read_fields::<Fp31>(&[31]).is_err());
+    }
+}
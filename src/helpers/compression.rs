@@ -0,0 +1,174 @@
+//!
+//! Compression of data messages between helpers. Helpers offer the [`Compression`]s they are
+//! configured with to every peer they connect to, in order of preference, and the peer picks the
+//! first one it is configured with as well (see [`crate::helpers::tcp`]). Every connection is
+//! compressed on its own, so a helper may compress what it sends to one peer and not to the other.
+//!
+//! Payloads are compressed one message at a time, while message names and trace context stay as
+//! they are, so that the ring can match messages, and route them to their channel, without
+//! decompressing them. Shares are highly structured, bit shares after modulus conversion most of
+//! all, and compress well. Payloads smaller than [`MIN_COMPRESSED_SIZE`], and those that do not
+//! get any smaller, go out as they are. How well payloads compress is reported with
+//! [`telemetry::compressed`](crate::telemetry::compressed).
+//!
+use bytes::BytesMut;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Payloads smaller than this many bytes are not compressed, as they have little to gain.
+pub const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Algorithm that payloads of data messages are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "enable-serde", serde(rename_all = "lowercase"))]
+pub enum Compression {
+    /// LZ4, which is fast enough to keep up with the links between helpers.
+    Lz4,
+    /// Zstandard, which compresses better than LZ4 at a higher cost.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Identifies the algorithm in hellos and handshakes. Zero stands for no compression.
+    #[must_use]
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Lz4 => 1,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 2,
+        }
+    }
+
+    /// Algorithm identified by `id`, if this helper knows it.
+    #[must_use]
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            #[cfg(feature = "zstd")]
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Name of the algorithm that metrics are labelled with.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Appends `input` to `out`, compressed.
+    ///
+    /// ## Errors
+    /// If the algorithm fails to compress it.
+    pub fn compress(self, input: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        let start = out.len();
+        match self {
+            Self::Lz4 => {
+                out.resize(
+                    start + lz4_flex::block::get_maximum_output_size(input.len()),
+                    0,
+                );
+                let len = lz4_flex::block::compress_into(input, &mut out[start..])
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                out.truncate(start + len);
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                out.resize(start + zstd::zstd_safe::compress_bound(input.len()), 0);
+                let len = zstd::bulk::compress_to_buffer(
+                    input,
+                    &mut out[start..],
+                    zstd::DEFAULT_COMPRESSION_LEVEL,
+                )?;
+                out.truncate(start + len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends `input`, which was compressed from `size` bytes, to `out` as it was before.
+    ///
+    /// ## Errors
+    /// If `input` is not compressed with this algorithm, or does not decompress to `size` bytes.
+    pub fn decompress(self, input: &[u8], size: usize, out: &mut BytesMut) -> io::Result<()> {
+        let start = out.len();
+        out.resize(start + size, 0);
+        let len = match self {
+            Self::Lz4 => lz4_flex::block::decompress_into(input, &mut out[start..])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::decompress_to_buffer(input, &mut out[start..])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        if len != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("payload decompressed to {len} bytes instead of {size}"),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::compression::Compression;
+    use bytes::BytesMut;
+
+    fn all() -> Vec<Compression> {
+        vec![
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        // bit shares are mostly zeroes and ones
+        let input = (0..4096_u32)
+            .map(|i| u8::from(i % 7 == 0))
+            .collect::<Vec<_>>();
+        for compression in all() {
+            let mut compressed = BytesMut::from(&b"header"[..]);
+            compression.compress(&input, &mut compressed).unwrap();
+            assert_eq!(b"header", &compressed[..6]);
+            assert!(compressed.len() < input.len() / 4, "{compression:?}");
+
+            let mut out = BytesMut::from(&b"header"[..]);
+            compression
+                .decompress(&compressed[6..], input.len(), &mut out)
+                .unwrap();
+            assert_eq!(b"header", &out[..6]);
+            assert_eq!(input, &out[6..]);
+
+            assert_eq!(Some(compression), Compression::from_id(compression.id()));
+        }
+        assert_eq!(None, Compression::from_id(0));
+    }
+
+    #[test]
+    fn wrong_size() {
+        let input = vec![1; 1000];
+        for compression in all() {
+            let mut compressed = BytesMut::new();
+            compression.compress(&input, &mut compressed).unwrap();
+            for size in [999, 1001] {
+                let mut out = BytesMut::new();
+                assert!(compression.decompress(&compressed, size, &mut out).is_err());
+            }
+            assert!(compression
+                .decompress(&[0xff; 16], 1000, &mut BytesMut::new())
+                .is_err());
+        }
+    }
+}
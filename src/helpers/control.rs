@@ -0,0 +1,253 @@
+//!
+//! Control messages helpers send each other about the query itself, as opposed to the data
+//! messages of the protocol that runs it. Data messages are matched with the `receive` call that
+//! waits for them, while control messages are acted on by the transport as soon as they arrive.
+//!
+//! Control messages are encoded the same way whatever codec data messages use, so that helpers
+//! can tell each other to stop even if they disagree about everything else:
+//!
+//! ```text
+//! | version (u8) | message type (u8) | field | field | ... |
+//! ```
+//!
+//! where every field is
+//!
+//! ```text
+//! | tag (u8) | length (u16 LE) | value |
+//! ```
+//!
+//! Version is [`CONTROL_VERSION`] of the sender. Newer versions may add message types and fields,
+//! but not change the meaning of existing ones, so receivers skip fields with tags they do not
+//! know and ignore messages of types they do not know, instead of failing the query.
+//!
+use crate::helpers::pacing::Position;
+use bytes::{Buf, BufMut};
+use std::io;
+
+/// Version of control messages this helper sends.
+pub const CONTROL_VERSION: u8 = 1;
+
+const ABORT: u8 = 1;
+const CLOSE: u8 = 2;
+const PROGRESS: u8 = 3;
+const HEARTBEAT: u8 = 4;
+
+/// Tag of the reason an [`ControlMessage::Abort`] is sent for.
+const REASON: u8 = 1;
+/// Tag of the name of the messages a [`ControlMessage::Close`] closes the channel of.
+const NAME: u8 = 1;
+/// Tags of the stage (u32 LE) and records (u64 LE) of a [`ControlMessage::Progress`].
+const STAGE: u8 = 1;
+const RECORDS: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Sender aborted the query, receivers fail it as well.
+    Abort { reason: String },
+    /// Sender is not going to send more messages called `name` in the current step. Unlike
+    /// other control messages, it takes effect in order with the data messages sent before it.
+    Close { name: String },
+    /// Sender got to `position` in the query, see [`crate::helpers::pacing`].
+    Progress { position: Position },
+    /// Sender is still there, see [`crate::helpers::liveness`]. Has no fields.
+    Heartbeat,
+}
+
+impl ControlMessage {
+    /// Appends the encoded message to `out`.
+    ///
+    /// ## Errors
+    /// If a field is longer than `u16::MAX` bytes.
+    pub fn encode<B: BufMut>(&self, out: &mut B) -> io::Result<()> {
+        out.put_u8(CONTROL_VERSION);
+        match self {
+            Self::Abort { reason } => {
+                out.put_u8(ABORT);
+                put_field(out, REASON, reason.as_bytes())
+            }
+            Self::Close { name } => {
+                out.put_u8(CLOSE);
+                put_field(out, NAME, name.as_bytes())
+            }
+            Self::Progress { position } => {
+                out.put_u8(PROGRESS);
+                put_field(out, STAGE, &position.stage.to_le_bytes())?;
+                put_field(out, RECORDS, &position.records.to_le_bytes())
+            }
+            Self::Heartbeat => {
+                out.put_u8(HEARTBEAT);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decodes a message, or returns `None` if it is of a type this helper does not know.
+    ///
+    /// ## Errors
+    /// If the message is malformed or a field it must have is missing.
+    pub fn decode(mut bytes: &[u8]) -> io::Result<Option<Self>> {
+        if bytes.remaining() < 2 {
+            return Err(bad_message("truncated header"));
+        }
+        // nothing depends on the version yet, newer messages are read the same way
+        let _version = bytes.get_u8();
+        let kind = bytes.get_u8();
+
+        let (mut reason, mut name) = (None, None);
+        let (mut stage, mut records) = (None, None);
+        while bytes.has_remaining() {
+            let (tag, value) = take_field(&mut bytes)?;
+            match (kind, tag) {
+                (ABORT, REASON) => reason = Some(String::from_utf8_lossy(value).into_owned()),
+                (CLOSE, NAME) => {
+                    let value = std::str::from_utf8(value)
+                        .map_err(|_| bad_message("name of closed messages is not UTF-8"))?;
+                    name = Some(value.to_owned());
+                }
+                (PROGRESS, STAGE) => {
+                    let value = value
+                        .try_into()
+                        .map_err(|_| bad_message("stage is not 4 bytes"))?;
+                    stage = Some(u32::from_le_bytes(value));
+                }
+                (PROGRESS, RECORDS) => {
+                    let value = value
+                        .try_into()
+                        .map_err(|_| bad_message("records are not 8 bytes"))?;
+                    records = Some(u64::from_le_bytes(value));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(match kind {
+            ABORT => Some(Self::Abort {
+                reason: reason.ok_or_else(|| bad_message("abort without a reason"))?,
+            }),
+            CLOSE => Some(Self::Close {
+                name: name.ok_or_else(|| bad_message("close without a name"))?,
+            }),
+            PROGRESS => Some(Self::Progress {
+                position: Position {
+                    stage: stage.ok_or_else(|| bad_message("progress without a stage"))?,
+                    records: records.ok_or_else(|| bad_message("progress without records"))?,
+                },
+            }),
+            HEARTBEAT => Some(Self::Heartbeat),
+            _ => None,
+        })
+    }
+}
+
+fn put_field<B: BufMut>(out: &mut B, tag: u8, value: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "control field is too long"))?;
+    out.put_u8(tag);
+    out.put_u16_le(len);
+    out.put_slice(value);
+    Ok(())
+}
+
+fn take_field<'a>(bytes: &mut &'a [u8]) -> io::Result<(u8, &'a [u8])> {
+    if bytes.remaining() < 3 {
+        return Err(bad_message("truncated field"));
+    }
+    let tag = bytes.get_u8();
+    let len = usize::from(bytes.get_u16_le());
+    if bytes.remaining() < len {
+        return Err(bad_message("truncated field"));
+    }
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok((tag, value))
+}
+
+fn bad_message(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed control message: {what}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::control::{ControlMessage, CONTROL_VERSION};
+    use crate::helpers::pacing::Position;
+
+    #[test]
+    fn round_trip() {
+        let abort = ControlMessage::Abort {
+            reason: "out of disk space".into(),
+        };
+        let mut bytes = Vec::new();
+        abort.encode(&mut bytes).unwrap();
+        assert_eq!(&[CONTROL_VERSION, 1, 1, 17, 0], &bytes[..5]);
+        assert_eq!(Some(abort), ControlMessage::decode(&bytes).unwrap());
+
+        assert!(ControlMessage::decode(&bytes[..1]).is_err());
+        assert!(ControlMessage::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ControlMessage::decode(&[CONTROL_VERSION, 1]).is_err());
+    }
+
+    #[test]
+    fn close() {
+        let close = ControlMessage::Close { name: "u8".into() };
+        let mut bytes = Vec::new();
+        close.encode(&mut bytes).unwrap();
+        assert_eq!(&[CONTROL_VERSION, 2, 1, 2, 0, b'u', b'8'], &bytes[..]);
+        assert_eq!(Some(close), ControlMessage::decode(&bytes).unwrap());
+
+        assert!(ControlMessage::decode(&[CONTROL_VERSION, 2]).is_err());
+        assert!(ControlMessage::decode(&[CONTROL_VERSION, 2, 1, 1, 0, 0xff]).is_err());
+    }
+
+    #[test]
+    fn progress() {
+        let progress = ControlMessage::Progress {
+            position: Position {
+                stage: 2,
+                records: 1000,
+            },
+        };
+        let mut bytes = Vec::new();
+        progress.encode(&mut bytes).unwrap();
+        assert_eq!(
+            &[CONTROL_VERSION, 3, 1, 4, 0, 2, 0, 0, 0, 2, 8, 0],
+            &bytes[..12]
+        );
+        assert_eq!(Some(progress), ControlMessage::decode(&bytes).unwrap());
+
+        assert!(ControlMessage::decode(&bytes[..9]).is_err());
+        assert!(ControlMessage::decode(&[CONTROL_VERSION, 3, 1, 1, 0, 2]).is_err());
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut bytes = Vec::new();
+        ControlMessage::Heartbeat.encode(&mut bytes).unwrap();
+        assert_eq!(&[CONTROL_VERSION, 4], &bytes[..]);
+        assert_eq!(
+            Some(ControlMessage::Heartbeat),
+            ControlMessage::decode(&bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn newer_versions() {
+        // a newer helper may add fields to existing messages, and new messages
+        let header = [CONTROL_VERSION + 1, 1];
+        let unknown_field = [9, 2, 0, 0xde, 0xad];
+        let reason = [1, 3, 0, b'b', b'y', b'e'];
+        let newer = [&header[..], &unknown_field, &reason].concat();
+        assert_eq!(
+            Some(ControlMessage::Abort {
+                reason: "bye".into()
+            }),
+            ControlMessage::decode(&newer).unwrap()
+        );
+        assert_eq!(
+            None,
+            ControlMessage::decode(&[CONTROL_VERSION + 1, 200, 1, 0, 0]).unwrap()
+        );
+    }
+}
@@ -1,5 +1,10 @@
 use crate::error::BoxError;
+use crate::helpers::buffer::Failure;
+use crate::helpers::memory::LimitExceeded;
 use crate::helpers::ring::HelperAddr;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,4 +22,92 @@ pub enum Error {
         #[source]
         inner: BoxError,
     },
+    #[error(transparent)]
+    MemoryLimitExceeded(#[from] LimitExceeded),
+    #[error("query was aborted by {}: {reason}", .by.map_or("this helper".to_owned(), |s| format!("{s:?} peer")))]
+    Aborted {
+        by: Option<HelperAddr>,
+        reason: String,
+    },
+    #[error(
+        "{by:?} peer sent a message of {size} bytes, which exceeds the limit of {limit} bytes"
+    )]
+    MessageTooBig {
+        by: HelperAddr,
+        size: usize,
+        limit: usize,
+    },
+    #[error("{0}")]
+    Spill(String),
+    #[error("{peer:?} peer is gone, nothing arrived from it for {silent:?}")]
+    PeerGone { peer: HelperAddr, silent: Duration },
+    #[error("{by:?} peer closed the channel of {message}")]
+    Closed {
+        by: HelperAddr,
+        message: &'static str,
+    },
+    #[error("connection to {peer} failed")]
+    Peer {
+        peer: SocketAddr,
+        #[source]
+        inner: Box<Error>,
+    },
+}
+
+impl From<Failure> for Error {
+    fn from(f: Failure) -> Self {
+        match f {
+            Failure::MemoryLimitExceeded(e) => Self::MemoryLimitExceeded(e),
+            Failure::Aborted { by, reason } => Self::Aborted { by, reason },
+            Failure::MessageTooBig { by, size, limit } => Self::MessageTooBig { by, size, limit },
+            Failure::Spill(reason) => Self::Spill(reason),
+            Failure::PeerGone { peer, silent } => Self::PeerGone { peer, silent },
+        }
+    }
+}
+
+impl Error {
+    /// Attaches the network address of the peer this error occurred with.
+    #[must_use]
+    pub fn with_peer(self, peer: SocketAddr) -> Self {
+        Self::Peer {
+            peer,
+            inner: Box::new(self),
+        }
+    }
+
+    /// Whether the send or receive that failed with this error may succeed if attempted again,
+    /// which is only if the connection to the peer failed with a [transient](is_transient)
+    /// I/O error. Peers that misbehave, abort the query or stay silent for too long fail it for
+    /// good.
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::SendError { inner, .. } | Self::ReceiveError { inner, .. } => inner
+                .downcast_ref::<io::Error>()
+                .map_or(false, is_transient),
+            Self::Peer { inner, .. } => inner.is_retryable(),
+            Self::MemoryLimitExceeded(_)
+            | Self::Aborted { .. }
+            | Self::MessageTooBig { .. }
+            | Self::Spill(_)
+            | Self::PeerGone { .. }
+            | Self::Closed { .. } => false,
+        }
+    }
+}
+
+/// Whether an I/O operation that failed with `e` may succeed if attempted again: it was
+/// interrupted, timed out, or the connection it used was refused or cut.
+#[must_use]
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
 }
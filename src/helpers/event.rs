@@ -1,10 +1,10 @@
+use crate::entropy::Entropy;
 #[cfg(feature = "enable-serde")]
 use crate::error::{Error, Res};
 #[cfg(feature = "enable-serde")]
 use crate::helpers::Helpers;
 use crate::report::{DecryptedEventReport, DecryptedMatchkeys, EncryptedMatchkeys, EventReport};
 use crate::threshold::DecryptionKey as ThresholdDecryptionKey;
-use rand::thread_rng;
 use rust_elgamal::EncryptionKey;
 #[cfg(feature = "enable-serde")]
 use serde::{Deserialize, Serialize};
@@ -47,7 +47,13 @@ pub struct Helper {
 impl Helper {
     #[must_use]
     pub fn new(role: Role) -> Self {
-        let matchkey_decrypt = ThresholdDecryptionKey::new(&mut thread_rng());
+        Self::with_entropy(role, &Entropy::os())
+    }
+
+    /// Same as [`new`](Self::new), with the key generated from `entropy`.
+    #[must_use]
+    pub fn with_entropy(role: Role, entropy: &Entropy) -> Self {
+        let matchkey_decrypt = ThresholdDecryptionKey::new(&mut entropy.rng());
         Self {
             public: PublicHelper {
                 role,
@@ -116,8 +122,9 @@ impl DerefMut for Helper {
 #[cfg(test)]
 mod tests {
     use super::{Helper, Role};
-    use crate::threshold::EncryptionKey as ThresholdEncryptionKey;
+    use crate::threshold::{BlindingKey, EncryptionKey as ThresholdEncryptionKey};
     use crate::user::User;
+    use rand::thread_rng;
 
     #[test]
     fn test_the_basics() {
@@ -176,4 +183,40 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn blind_matching() {
+        let h_source = Helper::new(Role::Source);
+        let h_trigger = Helper::new(Role::Trigger);
+        let tek = ThresholdEncryptionKey::new([
+            h_source.public.matchkey_encrypt,
+            h_trigger.public.matchkey_encrypt,
+        ]);
+
+        let mut u1 = User::new(1, tek);
+        u1.set_matchkey("a.example", "12345678");
+        u1.set_matchkey("b.example", "23456789");
+        let mut u2 = User::new(2, tek);
+        u2.set_matchkey("a.example", "12345678");
+        u2.set_matchkey("b.example", "does_not_match");
+        let r1 = u1.generate_event_report(&["a.example", "b.example"]);
+        let r2 = u2.generate_event_report(&["a.example", "b.example"]);
+
+        // every helper blinds all match keys with its own exponent before decryption
+        let mut rng = thread_rng();
+        let (b_source, b_trigger) = (BlindingKey::new(&mut rng), BlindingKey::new(&mut rng));
+        let blind = |r: &crate::report::EventReport| {
+            r.matchkeys()
+                .blind(&b_source)
+                .threshold_decrypt(&h_source.matchkey_decrypt)
+                .blind(&b_trigger)
+                .decrypt(&h_trigger.matchkey_decrypt)
+        };
+        let (blinded_1, blinded_2) = (blind(&r1), blind(&r2));
+        assert_eq!(1, blinded_1.count_matches(&blinded_2));
+
+        // blinded match keys have nothing in common with the points they are blinding
+        let decrypted_1 = h_trigger.decrypt_event(&h_source.threshold_decrypt_event(&r1));
+        assert_eq!(0, blinded_1.count_matches(decrypted_1.matchkeys()));
+    }
 }
@@ -0,0 +1,171 @@
+//!
+//! Liveness of the peers of a helper. A peer that sends nothing for a while may be slow, busy
+//! with a part of the query that does not need this helper, or gone, and a helper that only
+//! waits for its messages cannot tell which. Query traffic is no help either, as it stops
+//! whenever the protocol waits for something else.
+//!
+//! Helpers that enable heartbeats send their peers a heartbeat control message every
+//! [`HeartbeatConfig::interval_ms`], whatever the query does, so that something arrives from a
+//! peer that is there at least that often. A peer that sent nothing, heartbeats or query
+//! messages, for longer than [`HeartbeatConfig::timeout_ms`] is gone, and the query fails right
+//! away instead of when its deadline runs out. A peer that still sends heartbeats is only slow,
+//! and the query keeps waiting for it.
+//!
+//! Helpers that stop reading from a peer, because its window is full or it sends too fast, do
+//! not see its heartbeats either, so a peer does not count as silent while they do not read.
+//!
+use crate::helpers::ring::HelperAddr;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How often helpers send heartbeats, and how long they wait for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    /// Milliseconds after which a peer that sent nothing is gone. Must be a few intervals, and
+    /// more than it takes to send the largest message, or peers that are there count as gone.
+    pub timeout_ms: u64,
+}
+
+impl HeartbeatConfig {
+    #[must_use]
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// When something last arrived from a peer.
+#[derive(Debug, Clone, Copy)]
+struct Heard {
+    at: Instant,
+    /// This helper does not read from the peer.
+    paused: bool,
+}
+
+/// What this helper heard from its peers.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    left: Heard,
+    right: Heard,
+}
+
+impl Liveness {
+    /// Liveness of peers that were heard from at `now`, such as when they just connected.
+    #[must_use]
+    pub fn new(now: Instant) -> Self {
+        let heard = Heard {
+            at: now,
+            paused: false,
+        };
+        Self {
+            left: heard,
+            right: heard,
+        }
+    }
+
+    fn peer(&mut self, peer: HelperAddr) -> &mut Heard {
+        match peer {
+            HelperAddr::Left => &mut self.left,
+            HelperAddr::Right => &mut self.right,
+        }
+    }
+
+    /// Something arrived from `peer` at `now`.
+    pub fn heard(&mut self, peer: HelperAddr, now: Instant) {
+        self.peer(peer).at = now;
+    }
+
+    /// This helper stops reading from `peer`, which does not count as silent until it reads
+    /// again.
+    pub fn pause(&mut self, peer: HelperAddr) {
+        self.peer(peer).paused = true;
+    }
+
+    /// This helper reads from `peer` again at `now`.
+    pub fn resume(&mut self, peer: HelperAddr, now: Instant) {
+        *self.peer(peer) = Heard {
+            at: now,
+            paused: false,
+        };
+    }
+
+    /// How long nothing arrived from `peer` at `now`, zero while this helper does not read from
+    /// it.
+    #[must_use]
+    pub fn silence(&self, peer: HelperAddr, now: Instant) -> Duration {
+        let heard = match peer {
+            HelperAddr::Left => self.left,
+            HelperAddr::Right => self.right,
+        };
+        if heard.paused {
+            Duration::ZERO
+        } else {
+            now.saturating_duration_since(heard.at)
+        }
+    }
+
+    /// Peer that has been silent for longer than `timeout` at `now`, if there is one.
+    #[must_use]
+    pub fn gone(&self, now: Instant, timeout: Duration) -> Option<(HelperAddr, Duration)> {
+        [HelperAddr::Left, HelperAddr::Right]
+            .into_iter()
+            .map(|peer| (peer, self.silence(peer, now)))
+            .find(|&(_, silent)| silent > timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::liveness::Liveness;
+    use crate::helpers::ring::HelperAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn silence() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let timeout = Duration::from_millis(100);
+        let mut liveness = Liveness::new(start);
+        assert_eq!(None, liveness.gone(at(100), timeout));
+
+        liveness.heard(HelperAddr::Left, at(80));
+        assert_eq!(
+            Duration::from_millis(20),
+            liveness.silence(HelperAddr::Left, at(100))
+        );
+        assert_eq!(
+            Some((HelperAddr::Right, Duration::from_millis(150))),
+            liveness.gone(at(150), timeout)
+        );
+        liveness.heard(HelperAddr::Right, at(150));
+        assert_eq!(None, liveness.gone(at(150), timeout));
+        assert_eq!(
+            Some((HelperAddr::Left, Duration::from_millis(120))),
+            liveness.gone(at(200), timeout)
+        );
+    }
+
+    #[test]
+    fn paused() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let timeout = Duration::from_millis(100);
+        let mut liveness = Liveness::new(start);
+
+        // a peer this helper does not read from is never silent
+        liveness.pause(HelperAddr::Left);
+        liveness.heard(HelperAddr::Right, at(1000));
+        assert_eq!(None, liveness.gone(at(1000), timeout));
+
+        liveness.resume(HelperAddr::Left, at(1000));
+        assert_eq!(None, liveness.gone(at(1100), timeout));
+        assert!(liveness.gone(at(1101), timeout).is_some());
+    }
+}
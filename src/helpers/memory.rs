@@ -0,0 +1,109 @@
+//!
+//! Accounting of memory held on behalf of a single query. Components that keep data around for
+//! a query (currently the message buffer) reserve the memory they hold in the query's
+//! [`MemoryTracker`] and release it when the data is gone. If the query configured a limit and a
+//! reservation would exceed it, the reservation fails and the query is expected to fail with
+//! [`LimitExceeded`] rather than keep growing until the helper is killed.
+//!
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("query holds {used} bytes and cannot take {requested} more without exceeding its memory limit of {limit} bytes")]
+pub struct LimitExceeded {
+    pub limit: usize,
+    pub used: usize,
+    pub requested: usize,
+}
+
+/// Tracks bytes held by a single query.
+#[derive(Debug, Default)]
+pub struct MemoryTracker {
+    used: AtomicUsize,
+    limit: Option<usize>,
+}
+
+/// Memory reserved in a [`MemoryTracker`]. It is released when this value is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    tracker: Arc<MemoryTracker>,
+    bytes: usize,
+}
+
+impl MemoryTracker {
+    /// Creates a tracker for a query that may hold at most `limit` bytes, or any amount if
+    /// `limit` is not set.
+    #[must_use]
+    pub fn new(limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            used: AtomicUsize::new(0),
+            limit,
+        })
+    }
+
+    /// Reserves `bytes` for the query.
+    ///
+    /// ## Errors
+    /// If the query would hold more memory than its limit allows.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Result<Reservation, LimitExceeded> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .map_err(|used| LimitExceeded {
+                limit,
+                used,
+                requested: bytes,
+            })?;
+
+        Ok(Reservation {
+            tracker: Arc::clone(self),
+            bytes,
+        })
+    }
+
+    /// Number of bytes currently held by the query.
+    #[must_use]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.tracker.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::memory::{LimitExceeded, MemoryTracker};
+
+    #[test]
+    fn limit() {
+        let tracker = MemoryTracker::new(Some(10));
+        let a = tracker.reserve(6).unwrap();
+        assert_eq!(
+            LimitExceeded {
+                limit: 10,
+                used: 6,
+                requested: 5
+            },
+            tracker.reserve(5).unwrap_err()
+        );
+        let b = tracker.reserve(4).unwrap();
+        assert_eq!(10, tracker.used());
+
+        drop((a, b));
+        assert_eq!(0, tracker.used());
+    }
+
+    #[test]
+    fn unlimited() {
+        let tracker = MemoryTracker::new(None);
+        let _r = tracker.reserve(usize::MAX).unwrap();
+        assert!(tracker.reserve(1).is_err());
+    }
+}
@@ -1,9 +1,34 @@
 pub mod aggregation;
+pub mod batching;
+pub mod buffer;
+pub mod codec;
+#[cfg(feature = "web-app")]
+pub mod compression;
+pub mod control;
 pub mod error;
 pub mod event;
+pub mod liveness;
+pub mod memory;
 pub mod models;
+pub mod pacing;
+pub mod pool;
 pub mod privacy_budget;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod quota;
+#[cfg(feature = "enable-serde")]
+pub mod replay;
+#[cfg(feature = "enable-serde")]
+pub mod report_source;
+pub mod result_cache;
 pub mod ring;
+pub mod share_file;
+#[cfg(feature = "web-app")]
+pub mod tcp;
+#[cfg(feature = "web-app")]
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 pub use aggregation::{
     Helper as AggregationHelper, PublicHelper as PublicAggregationHelper,
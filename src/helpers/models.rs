@@ -1,56 +1,211 @@
+use crate::field::Field;
+use crate::helpers::result_cache::CacheControl;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
 use rand::{CryptoRng, Rng, RngCore};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
-use std::ops::Range;
+use std::ops::{BitXor, Range};
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
 
 // Type aliases to indicate whether the parameter should be encrypted, secret shared, etc.
 // Underlying types are temporalily assigned for PoC.
 pub type CipherText = Vec<u8>;
 type PlainText = String;
 
-#[derive(Debug, Clone)]
+/// How a value is split into the three shares of a [`SecretShare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum Sharing {
+    /// Shares XOR to the value. Protocols in the boolean domain take these as they are.
+    #[default]
+    Xor,
+    /// Shares add up to the value, modulo 2 to the power of its width in bits.
+    Additive,
+}
+
+#[cfg(feature = "enable-serde")]
+impl Sharing {
+    #[allow(clippy::trivially_copy_pass_by_ref)] // `skip_serializing_if` passes a reference
+    fn is_xor(&self) -> bool {
+        *self == Self::Xor
+    }
+}
+
+#[derive(Clone)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct SecretShare {
     ss: [CipherText; 3],
+    #[cfg_attr(
+        feature = "enable-serde",
+        serde(default, skip_serializing_if = "Sharing::is_xor")
+    )]
+    sharing: Sharing,
+}
+
+/// Shows how the value is shared and how long it is, but none of the shares: all three of them
+/// together are the value.
+impl Debug for SecretShare {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretShare")
+            .field("sharing", &self.sharing)
+            .field("len", &self.ss[0].len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for SecretShare {
+    fn drop(&mut self) {
+        self.ss.iter_mut().for_each(Zeroize::zeroize);
+    }
 }
 
 impl SecretShare {
-    fn combine(&self) -> Vec<u8> {
-        let mut result = Vec::new();
+    #[must_use]
+    pub fn sharing(&self) -> Sharing {
+        self.sharing
+    }
 
+    fn combine(&self) -> Vec<u8> {
         assert!(self.ss[0].len() == self.ss[1].len());
         assert!(self.ss[0].len() == self.ss[2].len());
 
-        for i in 0..self.ss[0].len() {
-            result.push(self.ss[0][i] ^ self.ss[1][i] ^ self.ss[2][i]);
+        match self.sharing {
+            Sharing::Xor => (0..self.ss[0].len())
+                .map(|i| self.ss[0][i] ^ self.ss[1][i] ^ self.ss[2][i])
+                .collect(),
+            Sharing::Additive => add_be(&add_be(&self.ss[0], &self.ss[1]), &self.ss[2]),
         }
-
-        result
     }
 
     // TODO: Add Shamir's SS
 
-    fn xor<R: RngCore + CryptoRng>(data: &[u8], rng: &mut R) -> Self {
+    fn split<R: RngCore + CryptoRng>(data: &[u8], sharing: Sharing, rng: &mut R) -> Self {
         let mut ss = [Vec::new(), Vec::new(), Vec::new()];
 
         for x in data {
             let ss1 = rng.gen::<u8>();
             let ss2 = rng.gen::<u8>();
-            let ss3 = ss1 ^ ss2 ^ x;
 
             ss[0].push(ss1);
             ss[1].push(ss2);
-            ss[2].push(ss3);
+            ss[2].push(ss1 ^ ss2 ^ x);
+        }
+        if sharing == Sharing::Additive {
+            ss[2] = sub_be(&sub_be(data, &ss[0]), &ss[1]);
         }
 
-        SecretShare { ss }
+        SecretShare { ss, sharing }
+    }
+
+    /// Same as [`split`](Self::split), with the first two shares taken from `random`, which has
+    /// twice as many bytes as `data`.
+    fn split_random(data: &[u8], sharing: Sharing, random: &[u8]) -> Self {
+        let (ss1, ss2) = random.split_at(data.len());
+        let ss3 = match sharing {
+            Sharing::Xor => data
+                .iter()
+                .zip(ss1.iter().zip(ss2))
+                .map(|(x, (a, b))| x ^ a ^ b)
+                .collect(),
+            Sharing::Additive => sub_be(&sub_be(data, ss1), ss2),
+        };
+        SecretShare {
+            ss: [ss1.to_vec(), ss2.to_vec(), ss3],
+            sharing,
+        }
     }
 }
 
+/// Splits many values into secret shares, with random bytes drawn from the generator a block at
+/// a time rather than with a call of the generator for every byte of every share, as
+/// [`SecretSharable::split`] does. Shares are just as random, but not the same as those that
+/// `split` makes with the generator in the same state.
+pub struct ShareBatch<R> {
+    rng: R,
+    random: Vec<u8>,
+    /// Bytes of `random` that were handed out already.
+    used: usize,
+}
+
+impl<R: RngCore + CryptoRng> ShareBatch<R> {
+    /// Random bytes drawn from the generator at a time.
+    pub const BLOCK: usize = 4096;
+
+    pub fn new(rng: R) -> Self {
+        Self {
+            rng,
+            random: vec![0; Self::BLOCK],
+            used: Self::BLOCK,
+        }
+    }
+
+    /// Splits `value` into shares of the given kind.
+    pub fn split<T: SecretSharable>(&mut self, value: &T, sharing: Sharing) -> SecretShare {
+        value.split_batched(sharing, self)
+    }
+
+    /// Next `len` random bytes, drawing a new block if there are not as many left in this one.
+    /// Together with a share that is made with them, they are the value, so the block is erased
+    /// once the batch is dropped.
+    fn random(&mut self, len: usize) -> &[u8] {
+        debug_assert!(len <= Self::BLOCK);
+        if self.random.len() - self.used < len {
+            self.rng.fill_bytes(&mut self.random);
+            self.used = 0;
+        }
+        self.used += len;
+        &self.random[self.used - len..self.used]
+    }
+}
+
+impl<R> Drop for ShareBatch<R> {
+    fn drop(&mut self) {
+        self.random.zeroize();
+    }
+}
+
+/// Sum of two big-endian integers of the same width, wrapping around.
+fn add_be(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut sum = vec![0; a.len()];
+    let mut carry = 0;
+    for i in (0..a.len()).rev() {
+        let [high, low] = (u16::from(a[i]) + u16::from(b[i]) + carry).to_be_bytes();
+        sum[i] = low;
+        carry = u16::from(high);
+    }
+    sum
+}
+
+/// Difference of two big-endian integers of the same width, wrapping around.
+fn sub_be(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut diff = vec![0; a.len()];
+    let mut borrow = false;
+    for i in (0..a.len()).rev() {
+        let (d, b1) = a[i].overflowing_sub(b[i]);
+        let (d, b2) = d.overflowing_sub(u8::from(borrow));
+        diff[i] = d;
+        borrow = b1 || b2;
+    }
+    diff
+}
+
 pub trait SecretSharable {
-    /// Splits the number into secret shares
-    fn xor_split<R: RngCore + CryptoRng>(&self, rng: &mut R) -> SecretShare;
+    /// Splits the number into secret shares of the given kind
+    fn split<R: RngCore + CryptoRng>(&self, sharing: Sharing, rng: &mut R) -> SecretShare;
+
+    /// Same as [`split`](Self::split), with random bytes taken from `batch`
+    fn split_batched<R: RngCore + CryptoRng>(
+        &self,
+        sharing: Sharing,
+        batch: &mut ShareBatch<R>,
+    ) -> SecretShare;
+
+    /// Splits the number into secret shares that XOR to it
+    fn xor_split<R: RngCore + CryptoRng>(&self, rng: &mut R) -> SecretShare {
+        self.split(Sharing::Xor, rng)
+    }
 
     /// Combines the given secret shares back to [Self]
     /// # Errors
@@ -58,56 +213,200 @@ pub trait SecretSharable {
     fn combine(data: &SecretShare) -> Result<Self, IoError>
     where
         Self: Sized;
+
+    /// Splits the number into replicated shares in `F` for three helpers, the way protocols
+    /// take their inputs: helper `i` gets shares `i` and `i + 1`, which add up to the number with
+    /// the third share.
+    /// # Errors
+    /// if the number is not smaller than the prime of `F`
+    fn replicate<F: Field, R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+    ) -> Result<[ReplicatedSecretSharing<F>; 3], IoError>
+    where
+        Self: Copy + Into<u128>,
+    {
+        let value: u128 = (*self).into();
+        if value >= F::PRIME.into() {
+            return Err(IoError::from(IoErrorKind::InvalidData));
+        }
+        Ok(ReplicatedSecretSharing::share(F::from(value), rng))
+    }
 }
 
-impl SecretSharable for u32 {
-    fn xor_split<R: RngCore + CryptoRng>(&self, rng: &mut R) -> SecretShare {
-        SecretShare::xor(&self.to_be_bytes(), rng)
+/// Big-endian bytes of a value that is `N` bytes long, combined from `data`. Shares may be
+/// longer than that, as long as the extra high-order bytes are zero, or shorter.
+fn combine_be<const N: usize>(data: &SecretShare) -> Result<[u8; N], IoError> {
+    let ss = Zeroizing::new(data.combine());
+
+    let split = ss.len().saturating_sub(N);
+    if ss[..split].iter().any(|x| *x != 0) {
+        return Err(IoError::from(IoErrorKind::InvalidData));
     }
 
-    fn combine(data: &SecretShare) -> Result<Self, IoError> {
-        let ss = data.combine();
+    let mut bytes = [0u8; N];
+    bytes[N - (ss.len() - split)..].copy_from_slice(&ss[split..]);
+    Ok(bytes)
+}
 
-        let mut high = ss[0..ss.len() - 4].to_vec();
-        high.retain(|x| *x != 0);
+macro_rules! secret_sharable_uint {
+    ($($t:ty),*) => {
+        $(
+            impl SecretSharable for $t {
+                fn split<R: RngCore + CryptoRng>(
+                    &self,
+                    sharing: Sharing,
+                    rng: &mut R,
+                ) -> SecretShare {
+                    SecretShare::split(&self.to_be_bytes(), sharing, rng)
+                }
+
+                fn split_batched<R: RngCore + CryptoRng>(
+                    &self,
+                    sharing: Sharing,
+                    batch: &mut ShareBatch<R>,
+                ) -> SecretShare {
+                    let bytes = self.to_be_bytes();
+                    SecretShare::split_random(&bytes, sharing, batch.random(2 * bytes.len()))
+                }
+
+                fn combine(data: &SecretShare) -> Result<Self, IoError> {
+                    Ok(<$t>::from_be_bytes(combine_be(data)?))
+                }
+            }
+        )*
+    };
+}
 
-        if ss.len() > 4 && !high.is_empty() {
-            return Err(IoError::from(IoErrorKind::InvalidData));
-        }
+secret_sharable_uint!(u32, u64, u128);
 
-        let mut bytes = [0u8; 4];
-        for (i, v) in ss[ss.len() - 4..].iter().enumerate() {
-            bytes[i] = *v;
-        }
+/// Unsigned integer that is `BITS` bits wide, up to 128. It is secret shared as the smallest
+/// number of bytes that hold it, so narrow values such as 40-bit match keys are not padded to
+/// the next primitive type.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct Uint<const BITS: u32>(u128);
 
-        Ok(u32::from_be_bytes(bytes))
+/// Match keys are values of this type, so the value is not shown.
+impl<const BITS: u32> Debug for Uint<BITS> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Uint<{BITS}>(..)")
     }
 }
 
-impl SecretSharable for u64 {
-    fn xor_split<R: RngCore + CryptoRng>(&self, rng: &mut R) -> SecretShare {
-        SecretShare::xor(&self.to_be_bytes(), rng)
+/// 40-bit match key.
+pub type U40 = Uint<40>;
+
+impl<const BITS: u32> Uint<BITS> {
+    const BYTES: usize = (BITS as usize + 7) / 8;
+
+    /// `None` if `value` does not fit in `BITS` bits.
+    ///
+    /// # Panics
+    /// If `BITS` is zero or more than 128.
+    #[must_use]
+    pub fn new(value: u128) -> Option<Self> {
+        assert!(
+            0 < BITS && BITS <= u128::BITS,
+            "{BITS} bits are not supported"
+        );
+        value
+            .checked_shr(BITS)
+            .map_or(true, |high| high == 0)
+            .then_some(Self(value))
+    }
+
+    #[must_use]
+    pub fn value(self) -> u128 {
+        self.0
+    }
+}
+
+impl<const BITS: u32> From<Uint<BITS>> for u128 {
+    fn from(v: Uint<BITS>) -> Self {
+        v.0
+    }
+}
+
+impl<const BITS: u32> SecretSharable for Uint<BITS> {
+    fn split<R: RngCore + CryptoRng>(&self, sharing: Sharing, rng: &mut R) -> SecretShare {
+        let bytes = self.0.to_be_bytes();
+        SecretShare::split(&bytes[bytes.len() - Self::BYTES..], sharing, rng)
+    }
+
+    fn split_batched<R: RngCore + CryptoRng>(
+        &self,
+        sharing: Sharing,
+        batch: &mut ShareBatch<R>,
+    ) -> SecretShare {
+        let bytes = self.0.to_be_bytes();
+        let random = batch.random(2 * Self::BYTES);
+        SecretShare::split_random(&bytes[bytes.len() - Self::BYTES..], sharing, random)
     }
 
     fn combine(data: &SecretShare) -> Result<Self, IoError> {
-        let ss = data.combine();
+        Self::new(u128::from_be_bytes(combine_be(data)?))
+            .ok_or_else(|| IoError::from(IoErrorKind::InvalidData))
+    }
+}
 
-        let mut high = ss[0..ss.len() - 8].to_vec();
-        high.retain(|x| *x != 0);
+/// Replicated XOR share of a value. The value is split into three random shares that XOR to it,
+/// and every helper holds two of them: helper `i` gets shares `i` and `i + 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct ReplicatedShare<T>(pub T, pub T);
+
+impl<T> ReplicatedShare<T>
+where
+    T: BitXor<Output = T> + Copy,
+{
+    /// Splits `value` into replicated shares for three helpers.
+    pub fn share<R: RngCore + CryptoRng>(value: T, rng: &mut R) -> [Self; 3]
+    where
+        rand::distributions::Standard: rand::distributions::Distribution<T>,
+    {
+        let x1 = rng.gen::<T>();
+        let x2 = rng.gen::<T>();
+        let x3 = value ^ x1 ^ x2;
 
-        if ss.len() > 8 && !high.is_empty() {
-            return Err(IoError::from(IoErrorKind::InvalidData));
-        }
+        [Self(x1, x2), Self(x2, x3), Self(x3, x1)]
+    }
 
-        let mut bytes = [0u8; 8];
-        for (i, v) in ss[ss.len() - 8..].iter().enumerate() {
-            bytes[i] = *v;
+    /// Reconstructs the value from the shares held by three helpers.
+    ///
+    /// # Errors
+    /// If shares are inconsistent, i.e. helpers disagree on the share they have in common.
+    pub fn reconstruct(shares: &[Self; 3]) -> Result<T, IoError>
+    where
+        T: PartialEq,
+    {
+        let [Self(x1, x2), Self(y2, x3), Self(y3, y1)] = *shares;
+        if x1 == y1 && x2 == y2 && x3 == y3 {
+            Ok(x1 ^ x2 ^ x3)
+        } else {
+            Err(IoError::from(IoErrorKind::InvalidData))
         }
-
-        Ok(u64::from_be_bytes(bytes))
     }
 }
 
+/// An event as it is seen by a single helper: epoch is in the clear, everything else is
+/// replicated secret shared.
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedEvent {
+    pub matchkeys: Vec<ReplicatedShare<u64>>,
+    pub epoch: u8,
+    pub timestamp: ReplicatedShare<u32>,
+    pub kind: SharedEventKind,
+}
+
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedEventKind {
+    Source { breakdown_key: ReplicatedShare<u32> },
+    Trigger { value: ReplicatedShare<u32> },
+}
+
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Event {
     /// Secret shared and then encrypted match keys.
@@ -163,10 +462,57 @@ impl Debug for TriggerEvent {
     }
 }
 
+/// Aggregate computed for every breakdown key. The average value of conversions is the sum
+/// divided by the count, so a query that needs it asks for both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum Aggregate {
+    /// Number of attributed conversions.
+    Count,
+    /// Sum of the values of attributed conversions.
+    Sum,
+}
+
+impl Aggregate {
+    /// How much a single user can change the aggregate of a breakdown key, if they contribute
+    /// at most `cap` conversions with values less than `value_range.end`.
+    #[must_use]
+    pub fn sensitivity(self, cap: u8, value_range: &Range<u32>) -> f64 {
+        match self {
+            Self::Count => f64::from(cap),
+            Self::Sum => f64::from(cap) * f64::from(value_range.end.saturating_sub(1)),
+        }
+    }
+
+    /// Privacy budget left for every one of `count` aggregates of a query that spends `epsilon`.
+    /// Noise is added to every aggregate separately, so their budgets add up to that of the
+    /// query.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn epsilon(epsilon: f64, count: usize) -> f64 {
+        epsilon / count.max(1) as f64
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "count" => Ok(Self::Count),
+            "sum" => Ok(Self::Sum),
+            _ => Err(format!("unknown aggregate: {s}")),
+        }
+    }
+}
+
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 enum QueryType {
     SourceFanout,
     TriggerFanout,
+    /// Number of distinct users exposed to every breakdown key, from source events only. See
+    /// [`crate::reach`].
+    Reach,
 }
 
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
@@ -184,7 +530,9 @@ struct IPAQuery {
     /// Initial MPC helper node to send the data to.
     leader_node: Node,
 
-    /// List of match key providers to be used by the source and trigger events during an epoch.
+    /// List of match key providers to be used by the source and trigger events during an epoch,
+    /// in order of priority: events are matched by the first provider both of them have a match
+    /// key of. See [`crate::report::DecryptedMatchkeys::matches_by_priority`].
     mk_providers: Vec<String>,
 
     /// Source-fanout or Trigger-fanout.
@@ -193,6 +541,16 @@ struct IPAQuery {
     /// Percentage of epoch-level privacy budget this query should consume. Likely 1-100.
     privacy_budget: u8,
 
+    /// Aggregates to compute for every breakdown key, the budget of the query is split between
+    /// them. Sum of conversion values if there are none.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    aggregates: Vec<Aggregate>,
+
+    /// Whether the result may be taken from, and stored in, the result cache of helpers. See
+    /// [`crate::helpers::result_cache`].
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    cache_control: CacheControl,
+
     /// A collection of source events. At least 100 (TBD) unique source events must be provided.
     source_events: Vec<SourceEvent>,
 
@@ -241,3 +599,408 @@ impl Debug for TriggerFanoutQuery {
         )
     }
 }
+
+#[cfg(feature = "enable-serde")]
+pub use versioned::{Versioned, MODEL_VERSION};
+
+/// Serialized forms of report models, prefixed by a byte with the version that wrote them.
+/// Every version that was ever written has a form here, and blobs in older forms are upgraded as
+/// they are read, so helpers and clients can be rolled out at different times.
+#[cfg(feature = "enable-serde")]
+mod versioned {
+    use super::{CipherText, Event, PlainText, SecretShare, Sharing, SourceEvent, TriggerEvent};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+    /// Version of the forms written by [`Versioned::to_versioned_bytes`].
+    ///
+    /// 1. secret shares always XOR to the value
+    /// 2. secret shares record the [`Sharing`] they were split with
+    pub const MODEL_VERSION: u8 = 2;
+
+    pub trait Versioned: Sized {
+        /// Serializes `self` in the form of [`MODEL_VERSION`].
+        fn to_versioned_bytes(&self) -> Vec<u8>;
+
+        /// Deserializes a blob written by any version up to [`MODEL_VERSION`].
+        ///
+        /// # Errors
+        /// If the blob is empty or malformed, or it was written by a newer version.
+        fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, IoError>;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ShareV1 {
+        ss: [CipherText; 3],
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ShareV2 {
+        ss: [CipherText; 3],
+        sharing: Sharing,
+    }
+
+    impl From<ShareV1> for SecretShare {
+        fn from(s: ShareV1) -> Self {
+            Self {
+                ss: s.ss,
+                sharing: Sharing::Xor,
+            }
+        }
+    }
+
+    impl From<ShareV2> for SecretShare {
+        fn from(s: ShareV2) -> Self {
+            Self {
+                ss: s.ss,
+                sharing: s.sharing,
+            }
+        }
+    }
+
+    impl From<&SecretShare> for ShareV2 {
+        fn from(s: &SecretShare) -> Self {
+            Self {
+                ss: s.ss.clone(),
+                sharing: s.sharing,
+            }
+        }
+    }
+
+    /// Forms of events do not change between versions by themselves, only their shares do.
+    #[derive(Serialize, Deserialize)]
+    struct EventForm<S> {
+        matchkeys: Vec<S>,
+        epoch: u8,
+        timestamp: S,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct SourceForm<S> {
+        event: EventForm<S>,
+        breakdown_key: PlainText,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct TriggerForm<S> {
+        event: EventForm<S>,
+        value: S,
+        zkp: PlainText,
+    }
+
+    impl<S: Into<SecretShare>> From<EventForm<S>> for Event {
+        fn from(e: EventForm<S>) -> Self {
+            Self {
+                matchkeys: e.matchkeys.into_iter().map(Into::into).collect(),
+                epoch: e.epoch,
+                timestamp: e.timestamp.into(),
+            }
+        }
+    }
+
+    impl From<&Event> for EventForm<ShareV2> {
+        fn from(e: &Event) -> Self {
+            Self {
+                matchkeys: e.matchkeys.iter().map(Into::into).collect(),
+                epoch: e.epoch,
+                timestamp: (&e.timestamp).into(),
+            }
+        }
+    }
+
+    impl<S: Into<SecretShare>> From<SourceForm<S>> for SourceEvent {
+        fn from(e: SourceForm<S>) -> Self {
+            Self {
+                event: e.event.into(),
+                breakdown_key: e.breakdown_key,
+            }
+        }
+    }
+
+    impl From<&SourceEvent> for SourceForm<ShareV2> {
+        fn from(e: &SourceEvent) -> Self {
+            Self {
+                event: (&e.event).into(),
+                breakdown_key: e.breakdown_key.clone(),
+            }
+        }
+    }
+
+    impl<S: Into<SecretShare>> From<TriggerForm<S>> for TriggerEvent {
+        fn from(e: TriggerForm<S>) -> Self {
+            Self {
+                event: e.event.into(),
+                value: e.value.into(),
+                zkp: e.zkp,
+            }
+        }
+    }
+
+    impl From<&TriggerEvent> for TriggerForm<ShareV2> {
+        fn from(e: &TriggerEvent) -> Self {
+            Self {
+                event: (&e.event).into(),
+                value: (&e.value).into(),
+                zkp: e.zkp.clone(),
+            }
+        }
+    }
+
+    fn write<T: Serialize>(version: u8, form: &T) -> Vec<u8> {
+        let mut bytes = vec![version];
+        bincode::serialize_into(&mut bytes, form).unwrap();
+        bytes
+    }
+
+    /// Reads a blob in the form `V1` or `V2`, depending on its version.
+    fn read<V1, V2, T>(bytes: &[u8]) -> Result<T, IoError>
+    where
+        V1: DeserializeOwned + Into<T>,
+        V2: DeserializeOwned + Into<T>,
+    {
+        fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, IoError> {
+            bincode::deserialize(bytes).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+        }
+
+        match bytes.split_first() {
+            Some((1, form)) => decode::<V1>(form).map(Into::into),
+            Some((2, form)) => decode::<V2>(form).map(Into::into),
+            Some((version, _)) => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("model version {version} is newer than {MODEL_VERSION}"),
+            )),
+            None => Err(IoError::from(IoErrorKind::UnexpectedEof)),
+        }
+    }
+
+    impl Versioned for SecretShare {
+        fn to_versioned_bytes(&self) -> Vec<u8> {
+            write(MODEL_VERSION, &ShareV2::from(self))
+        }
+
+        fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, IoError> {
+            read::<ShareV1, ShareV2, _>(bytes)
+        }
+    }
+
+    impl Versioned for SourceEvent {
+        fn to_versioned_bytes(&self) -> Vec<u8> {
+            write(MODEL_VERSION, &SourceForm::from(self))
+        }
+
+        fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, IoError> {
+            read::<SourceForm<ShareV1>, SourceForm<ShareV2>, _>(bytes)
+        }
+    }
+
+    impl Versioned for TriggerEvent {
+        fn to_versioned_bytes(&self) -> Vec<u8> {
+            write(MODEL_VERSION, &TriggerForm::from(self))
+        }
+
+        fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, IoError> {
+            read::<TriggerForm<ShareV1>, TriggerForm<ShareV2>, _>(bytes)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{write, EventForm, ShareV1, SourceForm, TriggerForm, Versioned, MODEL_VERSION};
+        use crate::helpers::models::{
+            SecretSharable, SecretShare, Sharing, SourceEvent, TriggerEvent,
+        };
+        use rand::thread_rng;
+
+        fn share_v1(value: u32) -> ShareV1 {
+            ShareV1 {
+                ss: value.xor_split(&mut thread_rng()).ss.clone(),
+            }
+        }
+
+        fn event_v1() -> EventForm<ShareV1> {
+            EventForm {
+                matchkeys: vec![share_v1(1), share_v1(2)],
+                epoch: 3,
+                timestamp: share_v1(4),
+            }
+        }
+
+        #[test]
+        fn v1_events_are_upgraded() {
+            let blob = write(
+                1,
+                &SourceForm {
+                    event: event_v1(),
+                    breakdown_key: "5".into(),
+                },
+            );
+            let source = SourceEvent::from_versioned_bytes(&blob).unwrap();
+            assert_eq!(3, source.event.epoch);
+            assert_eq!("5", source.breakdown_key);
+            assert_eq!(Sharing::Xor, source.event.timestamp.sharing());
+            assert_eq!(4, u32::combine(&source.event.timestamp).unwrap());
+            let matchkeys = source
+                .event
+                .matchkeys
+                .iter()
+                .map(|mk| u32::combine(mk).unwrap());
+            assert_eq!(vec![1, 2], matchkeys.collect::<Vec<_>>());
+
+            let blob = write(
+                1,
+                &TriggerForm {
+                    event: event_v1(),
+                    value: share_v1(6),
+                    zkp: String::new(),
+                },
+            );
+            let trigger = TriggerEvent::from_versioned_bytes(&blob).unwrap();
+            assert_eq!(6, u32::combine(&trigger.value).unwrap());
+        }
+
+        #[test]
+        fn round_trip() {
+            let share = 7_u32.split(Sharing::Additive, &mut thread_rng());
+            let blob = share.to_versioned_bytes();
+            assert_eq!(MODEL_VERSION, blob[0]);
+
+            let share = SecretShare::from_versioned_bytes(&blob).unwrap();
+            assert_eq!(Sharing::Additive, share.sharing());
+            assert_eq!(7, u32::combine(&share).unwrap());
+        }
+
+        #[test]
+        fn unknown_versions_are_rejected() {
+            let mut blob = 7_u32.xor_split(&mut thread_rng()).to_versioned_bytes();
+            blob[0] = MODEL_VERSION + 1;
+            assert!(SecretShare::from_versioned_bytes(&blob).is_err());
+            assert!(SecretShare::from_versioned_bytes(&[]).is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::helpers::models::{
+        Aggregate, ReplicatedShare, SecretSharable, SecretShare, ShareBatch, Sharing, Uint, U40,
+    };
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use rand::thread_rng;
+
+    #[test]
+    fn aggregate_calibration() {
+        assert!((2.0 - Aggregate::Count.sensitivity(2, &(1..100))).abs() < f64::EPSILON);
+        assert!((198.0 - Aggregate::Sum.sensitivity(2, &(1..100))).abs() < f64::EPSILON);
+        assert!((0.5 - Aggregate::epsilon(1.0, 2)).abs() < f64::EPSILON);
+        assert_eq!(Ok(Aggregate::Count), "COUNT".parse());
+        assert!("avg".parse::<Aggregate>().is_err());
+    }
+
+    #[test]
+    fn replicate_into_field() {
+        let shares = 17_u32.replicate::<Fp31, _>(&mut thread_rng()).unwrap();
+        assert_eq!(
+            Some(Fp31::from(17_u128)),
+            ReplicatedSecretSharing::reconstruct(&shares)
+        );
+
+        let shares = U40::new(30)
+            .unwrap()
+            .replicate::<Fp31, _>(&mut thread_rng());
+        assert!(shares.is_ok());
+        assert!(31_u64.replicate::<Fp31, _>(&mut thread_rng()).is_err());
+    }
+
+    #[test]
+    fn additive_sharing() {
+        for value in [0, 1, 0x00ff_ffff, u32::MAX] {
+            let shares = value.split(Sharing::Additive, &mut thread_rng());
+            assert_eq!(Sharing::Additive, shares.sharing());
+            let sum = shares.ss.iter().fold(0_u32, |acc, s| {
+                acc.wrapping_add(u32::from_be_bytes(s.as_slice().try_into().unwrap()))
+            });
+            assert_eq!(value, sum);
+            assert_eq!(value, u32::combine(&shares).unwrap());
+        }
+
+        let value = U40::new(0xab_cdef_0123).unwrap();
+        let shares = value.split(Sharing::Additive, &mut thread_rng());
+        assert_eq!(value, U40::combine(&shares).unwrap());
+    }
+
+    #[test]
+    fn secret_share_u128() {
+        let value = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210_u128;
+        let shares = value.xor_split(&mut thread_rng());
+        assert_eq!(value, u128::combine(&shares).unwrap());
+
+        // narrower types combine from wider shares as long as the value fits
+        let shares = u128::from(u32::MAX).xor_split(&mut thread_rng());
+        assert_eq!(u32::MAX, u32::combine(&shares).unwrap());
+        assert!(u32::combine(&value.xor_split(&mut thread_rng())).is_err());
+    }
+
+    #[test]
+    fn secret_share_u40() {
+        let value = U40::new((1 << 40) - 1).unwrap();
+        let shares = value.xor_split(&mut thread_rng());
+        assert_eq!(5, shares.ss[0].len());
+        assert_eq!(value, U40::combine(&shares).unwrap());
+        assert_eq!(value.value(), u128::from(u64::combine(&shares).unwrap()));
+
+        assert_eq!(None, U40::new(1 << 40));
+        let wide: SecretShare = (1_u64 << 40).xor_split(&mut thread_rng());
+        assert!(U40::combine(&wide).is_err());
+
+        let full = Uint::<128>::new(u128::MAX).unwrap();
+        assert_eq!(
+            full,
+            Uint::combine(&full.xor_split(&mut thread_rng())).unwrap()
+        );
+    }
+
+    #[test]
+    fn batched_shares() {
+        let mut batch = ShareBatch::new(thread_rng());
+        // enough of them to draw a few blocks
+        for i in 0..1000_u64 {
+            let value = i.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            for sharing in [Sharing::Xor, Sharing::Additive] {
+                let shares = batch.split(&value, sharing);
+                assert_eq!(sharing, shares.sharing());
+                assert_eq!(value, u64::combine(&shares).unwrap());
+            }
+            let key = U40::new(u128::from(i) << 30).unwrap();
+            let shares = batch.split(&key, Sharing::Xor);
+            assert_eq!(5, shares.ss[0].len());
+            assert_eq!(key, U40::combine(&shares).unwrap());
+        }
+
+        // every value gets fresh random bytes
+        let [a, b] = [(); 2].map(|()| batch.split(&0_u128, Sharing::Xor));
+        assert_ne!(a.ss[0], b.ss[0]);
+        assert_ne!(a.ss[0], a.ss[1]);
+    }
+
+    #[test]
+    fn redacted_debug() {
+        let value = U40::new(0xab_cdef_0123).unwrap();
+        assert_eq!("Uint<40>(..)", format!("{value:?}"));
+        let shares = value.xor_split(&mut thread_rng());
+        assert_eq!(
+            "SecretShare { sharing: Xor, len: 5, .. }",
+            format!("{shares:?}")
+        );
+    }
+
+    #[test]
+    fn replicated_share() {
+        let shares = ReplicatedShare::share(0xdead_beef_u32, &mut thread_rng());
+        assert_eq!(0xdead_beef, ReplicatedShare::reconstruct(&shares).unwrap());
+
+        let mut bad = shares;
+        bad[1].0 ^= 1;
+        assert!(ReplicatedShare::reconstruct(&bad).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//!
+//! Pacing of a helper that is ahead of its peers. Helpers run the same stages of a query on the
+//! same records, but not at the same speed: one of them may be on a slower machine, or share it
+//! with other queries. Peers that are ahead keep sending messages for records the slow helper
+//! has not got to yet, which pile up in its buffer until its window fills and it stops reading,
+//! or its memory runs out and the query fails.
+//!
+//! Instead, every helper tells its peers where it is in the query whenever it starts on more
+//! records, and a helper that is more than [`Pacer::max_lead`] records ahead of the slowest of
+//! them waits before it starts on any more, so that it sends no faster than that peer can take
+//! its messages. A helper is ahead of a peer by any number of records once it is in a later
+//! stage, so helpers that pace also wait for each other before every stage. The slowest helper
+//! never waits, which is why pacing cannot stop the query.
+//!
+use crate::helpers::ring::HelperAddr;
+use crate::query::Stage;
+use std::cmp::Ordering;
+
+/// Where a helper is in the query.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    /// Number of the stage in [`Stage::ALL`].
+    pub stage: u32,
+    /// Records of the stage the helper has started on.
+    pub records: u64,
+}
+
+impl Position {
+    #[must_use]
+    pub fn new(stage: Stage, records: u64) -> Self {
+        let index = Stage::ALL.iter().position(|&s| s == stage).unwrap_or(0);
+        Self {
+            stage: u32::try_from(index).unwrap_or(u32::MAX),
+            records,
+        }
+    }
+
+    /// Records this is ahead of `other` by.
+    fn lead(self, other: Self) -> u64 {
+        match self.stage.cmp(&other.stage) {
+            Ordering::Less => 0,
+            Ordering::Equal => self.records.saturating_sub(other.records),
+            Ordering::Greater => u64::MAX,
+        }
+    }
+}
+
+/// Positions of a helper and its peers, as far as they told it.
+#[derive(Debug, Default, Clone)]
+pub struct Pacer {
+    max_lead: Option<u64>,
+    ours: Position,
+    /// Peers that did not say where they are yet are at the start of the query.
+    left: Position,
+    right: Position,
+}
+
+impl Pacer {
+    /// Pacer that lets a helper get at most `max_lead` records ahead of its slowest peer. It
+    /// never waits for peers if that is not set.
+    #[must_use]
+    pub fn new(max_lead: Option<u64>) -> Self {
+        Self {
+            max_lead,
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn max_lead(&self) -> Option<u64> {
+        self.max_lead
+    }
+
+    /// Changes the lead this helper may have, keeping the positions it knows of.
+    pub fn set_max_lead(&mut self, max_lead: Option<u64>) {
+        self.max_lead = max_lead;
+    }
+
+    /// Moves this helper to `position`. Returns whether that is further than it was, in which
+    /// case peers should be told.
+    pub fn advance(&mut self, position: Position) -> bool {
+        if position > self.ours {
+            self.ours = position;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes note of where `peer` says it is. Positions arrive in order, but a peer never goes
+    /// back, so older ones are ignored all the same.
+    pub fn observe(&mut self, peer: HelperAddr, position: Position) {
+        let theirs = match peer {
+            HelperAddr::Left => &mut self.left,
+            HelperAddr::Right => &mut self.right,
+        };
+        *theirs = position.max(*theirs);
+    }
+
+    #[must_use]
+    pub fn position(&self, peer: Option<HelperAddr>) -> Position {
+        match peer {
+            None => self.ours,
+            Some(HelperAddr::Left) => self.left,
+            Some(HelperAddr::Right) => self.right,
+        }
+    }
+
+    /// Records this helper is ahead of the slowest of its peers by.
+    #[must_use]
+    pub fn lead(&self) -> u64 {
+        self.ours.lead(self.left.min(self.right))
+    }
+
+    /// Whether this helper may start on the records up to its position.
+    #[must_use]
+    pub fn may_proceed(&self) -> bool {
+        self.max_lead.map_or(true, |max| self.lead() <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::pacing::{Pacer, Position};
+    use crate::helpers::ring::HelperAddr;
+    use crate::query::Stage;
+
+    #[test]
+    fn waits_for_slowest() {
+        let mut pacer = Pacer::new(Some(10));
+        assert!(pacer.advance(Position::new(Stage::Sort, 10)));
+        // peers that did not say anything yet are at the start of the query
+        assert!(!pacer.may_proceed());
+        pacer.observe(HelperAddr::Left, Position::new(Stage::Sort, 0));
+        pacer.observe(HelperAddr::Right, Position::new(Stage::Sort, 0));
+        assert!(!pacer.advance(Position::new(Stage::Sort, 5)));
+        assert!(pacer.may_proceed());
+
+        assert!(pacer.advance(Position::new(Stage::Sort, 30)));
+        assert_eq!(30, pacer.lead());
+        assert!(!pacer.may_proceed());
+
+        pacer.observe(HelperAddr::Left, Position::new(Stage::Sort, 25));
+        assert!(!pacer.may_proceed());
+        pacer.observe(HelperAddr::Right, Position::new(Stage::Sort, 20));
+        assert_eq!(10, pacer.lead());
+        assert!(pacer.may_proceed());
+        // late news of an older position
+        pacer.observe(HelperAddr::Right, Position::new(Stage::Sort, 0));
+        assert!(pacer.may_proceed());
+    }
+
+    #[test]
+    fn stage_barrier() {
+        let mut pacer = Pacer::new(Some(1000));
+        pacer.observe(HelperAddr::Left, Position::new(Stage::Sort, 500));
+        pacer.observe(HelperAddr::Right, Position::new(Stage::Sort, 600));
+        pacer.advance(Position::new(Stage::Attribution, 0));
+        assert!(!pacer.may_proceed());
+
+        pacer.observe(HelperAddr::Left, Position::new(Stage::Attribution, 0));
+        pacer.observe(HelperAddr::Right, Position::new(Stage::Attribution, 0));
+        assert!(pacer.may_proceed());
+
+        // a peer that is ahead does not hold this helper back
+        pacer.observe(HelperAddr::Left, Position::new(Stage::Aggregation, 0));
+        assert_eq!(0, pacer.lead());
+    }
+
+    #[test]
+    fn unpaced() {
+        let mut pacer = Pacer::new(None);
+        pacer.advance(Position::new(Stage::Aggregation, 1_000_000));
+        assert!(pacer.may_proceed());
+    }
+}
@@ -0,0 +1,76 @@
+//!
+//! Pool of buffers used to build messages before they are handed over to the transport. Every
+//! message needs a buffer only for as long as it takes to write it out, so instead of allocating
+//! a fresh one for each of them, helpers take one from the pool and return it once the message is
+//! sent.
+//!
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+/// Initial capacity of buffers created by the pool. Buffers grow if a message does not fit and
+/// keep their capacity when they return to the pool.
+const BUFFER_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps at most `max_buffers` buffers that are not in use. Buffers
+    /// returned to a full pool are freed.
+    #[must_use]
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }
+    }
+
+    /// Takes an empty buffer off the pool, or allocates a new one if there are none.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn get(&self) -> BytesMut {
+        self.free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(BUFFER_CAPACITY))
+    }
+
+    /// Returns the buffer to the pool, so it can be used for another message.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_buffers {
+            free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::pool::BufferPool;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(1);
+        let mut a = pool.get();
+        a.extend_from_slice(&[1; 10_000]);
+        let ptr = a.as_ptr();
+        let b = pool.get();
+        pool.put(a);
+        pool.put(b);
+
+        let c = pool.get();
+        assert!(c.is_empty());
+        assert!(c.capacity() >= 10_000);
+        assert_eq!(ptr, c.as_ptr());
+    }
+}
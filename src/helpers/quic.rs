@@ -0,0 +1,206 @@
+//!
+//! QUIC endpoints and streams for the [`Quic`] transport. Every helper runs one endpoint, which
+//! accepts connections from its peers and connects to them, so that connections to a peer come
+//! from the address this helper listens on, the same as on the other transports.
+//!
+//! Every endpoint has a self-signed certificate of its own, which peers take at face value:
+//! helpers do not know the certificates of their peers up front, and QUIC is there for its
+//! streams rather than to authenticate peers. Helpers that share [`PeerKeys`] authenticate each
+//! other the same as on the other transports, and [bind](binding) their tags to the TLS session of
+//! the connection, so that whoever relays the handshake cannot take over the connection after it.
+//!
+//! [`Quic`]: crate::helpers::transport::Transport::Quic
+//! [`PeerKeys`]: crate::helpers::tcp::PeerKeys
+//!
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, PrivateKey, ServerName};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Name helpers present in their certificates, and ask for when they connect.
+const SERVER_NAME: &str = "raw-ipa";
+
+/// Label of the keying material exported for [`binding`].
+const BINDING_LABEL: &[u8] = b"raw-ipa peer binding";
+
+/// Size of the keying material exported for [`binding`].
+const BINDING_SIZE: usize = 32;
+
+/// Most channels a peer may have open on a connection at once. Every message name is a channel,
+/// and they stay open for as long as the connection does.
+const MAX_CHANNELS: u32 = 1024;
+
+/// How often to send something on a connection that has nothing to carry, so that it does not
+/// time out while the query is busy elsewhere.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Starts an endpoint on `addr` that accepts connections from peers and connects to them.
+///
+/// ## Errors
+/// If it fails to bind to `addr`, or to create its certificate.
+pub fn endpoint(addr: SocketAddr) -> io::Result<Endpoint> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()]).map_err(other)?;
+    let chain = vec![Certificate(cert.serialize_der().map_err(other)?)];
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_concurrent_uni_streams(VarInt::from_u32(MAX_CHANNELS))
+        .max_concurrent_bidi_streams(VarInt::from_u32(1))
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    let transport = Arc::new(transport);
+
+    let mut server = ServerConfig::with_single_cert(chain, key).map_err(other)?;
+    server.transport_config(Arc::clone(&transport));
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+        .with_no_client_auth();
+    let mut client = ClientConfig::new(Arc::new(crypto));
+    client.transport_config(transport);
+
+    let mut endpoint = Endpoint::server(server, addr)?;
+    endpoint.set_default_client_config(client);
+    Ok(endpoint)
+}
+
+/// Connects `endpoint` to the peer at `addr`.
+///
+/// ## Errors
+/// If the connection cannot be established.
+pub async fn connect(endpoint: &Endpoint, addr: SocketAddr) -> io::Result<Connection> {
+    let connecting = endpoint.connect(addr, SERVER_NAME).map_err(other)?;
+    Ok(connecting.await?)
+}
+
+/// Secret that both ends of `connection` and nobody else know, which is different for every
+/// connection.
+///
+/// ## Errors
+/// If it cannot be exported from the TLS session.
+pub fn binding(connection: &Connection) -> io::Result<Vec<u8>> {
+    let mut binding = vec![0; BINDING_SIZE];
+    connection
+        .export_keying_material(&mut binding, BINDING_LABEL, &[])
+        .map_err(|_| other("failed to export keying material"))?;
+    Ok(binding)
+}
+
+fn other<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Verifier that takes any certificate, see the module documentation.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Both directions of a bidirectional stream.
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    #[must_use]
+    pub fn new((send, recv): (SendStream, RecvStream)) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    /// Finishes the sending side, once the peer has everything written to it.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::quic::{binding, connect, endpoint, QuicStream};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn streams() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let (server, client) = (endpoint(addr).unwrap(), endpoint(addr).unwrap());
+        let server_addr = server.local_addr().unwrap();
+
+        let accept = async {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let mut stream = QuicStream::new(connection.accept_bi().await.unwrap());
+            let mut hello = [0; 5];
+            stream.read_exact(&mut hello).await.unwrap();
+            stream.write_all(b"hi").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let uni = connection.accept_uni().await.unwrap();
+            let data = uni.read_to_end(16).await.unwrap();
+            (connection, hello, data)
+        };
+        let dial = async {
+            let connection = connect(&client, server_addr).await.unwrap();
+            let mut stream = QuicStream::new(connection.open_bi().await.unwrap());
+            stream.write_all(b"hello").await.unwrap();
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).await.unwrap();
+            let mut uni = connection.open_uni().await.unwrap();
+            uni.write_all(&[1, 2, 3]).await.unwrap();
+            uni.finish().await.unwrap();
+            (connection, answer)
+        };
+        let ((accepted, hello, data), (dialed, answer)) = tokio::join!(accept, dial);
+        assert_eq!(b"hello", &hello);
+        assert_eq!(b"hi", &answer[..]);
+        assert_eq!(vec![1, 2, 3], data);
+
+        // both ends know the binding, and it is not the same for other connections
+        let bound = binding(&dialed).unwrap();
+        assert_eq!(bound, binding(&accepted).unwrap());
+        let (other, _) = tokio::join!(connect(&client, server_addr), async {
+            server.accept().await.unwrap().await.unwrap()
+        });
+        assert_ne!(bound, binding(&other.unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+//!
+//! Quotas of report collectors, so that a single one of them cannot take up all the capacity of
+//! a helper, or spend privacy budgets with a flood of queries.
+//!
+//! Every collector is limited in the number of queries it submits per day (UTC) and the number
+//! of input rows it submits for every epoch, across all of its queries. Queries are checked with
+//! [`QuotaTracker::admit`] once the collector is authenticated and before anything is allocated
+//! for them, which [`Status::admit`] does for every query it admits. Every decision is written to
+//! the audit log, which is the [`AUDIT_TARGET`] of `tracing`.
+//!
+//! [`Status::admit`]: crate::telemetry::status::Status::admit
+//!
+#[cfg(feature = "enable-serde")]
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// `tracing` target of events that make up the audit log.
+pub const AUDIT_TARGET: &str = "audit";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("collector {collector} has submitted {limit} queries today already")]
+    QueriesPerDay { collector: String, limit: u32 },
+    #[error("{requested} more rows of {collector} exceed its quota of {limit} for epoch {epoch}")]
+    RowsPerEpoch {
+        collector: String,
+        epoch: u8,
+        requested: u64,
+        limit: u64,
+    },
+}
+
+/// Limits of a single collector. Limits that are not set are not enforced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Deserialize))]
+pub struct Quota {
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub queries_per_day: Option<u32>,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub rows_per_epoch: Option<u64>,
+}
+
+/// Quotas of all collectors. Collectors that are not listed get the default one.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "enable-serde", derive(Deserialize))]
+pub struct Quotas {
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub default: Quota,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub collectors: HashMap<String, Quota>,
+}
+
+impl Quotas {
+    #[must_use]
+    pub fn of(&self, collector: &str) -> Quota {
+        self.collectors
+            .get(collector)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// What a collector has used up so far.
+#[derive(Debug, Default)]
+struct Usage {
+    day: u64,
+    queries: u32,
+    rows: HashMap<u8, u64>,
+}
+
+/// Enforces [`Quotas`] on queries as they are submitted.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quotas: Quotas,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl QuotaTracker {
+    #[must_use]
+    pub fn new(quotas: Quotas) -> Self {
+        Self {
+            quotas,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Counts a query of `collector` with `rows` input rows for `epoch`, submitted at `now`,
+    /// against its quota. `collector` must be the authenticated identity of the caller. Nothing
+    /// is counted for a query that is rejected.
+    ///
+    /// ## Errors
+    /// If the query would exceed either limit of the collector.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn admit(
+        &self,
+        collector: &str,
+        epoch: u8,
+        rows: u64,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let result = self.check_and_count(collector, epoch, rows, now);
+        match &result {
+            Ok(()) => info!(target: AUDIT_TARGET, collector, epoch, rows, "query admitted"),
+            Err(e) => warn!(target: AUDIT_TARGET, collector, epoch, rows, "query rejected: {e}"),
+        }
+        result
+    }
+
+    fn check_and_count(
+        &self,
+        collector: &str,
+        epoch: u8,
+        rows: u64,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let quota = self.quotas.of(collector);
+        // a clock before 1970 counts as the first day
+        let day = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / SECONDS_PER_DAY);
+
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(collector.to_owned()).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.queries = 0;
+        }
+
+        if let Some(limit) = quota.queries_per_day {
+            if usage.queries >= limit {
+                return Err(Error::QueriesPerDay {
+                    collector: collector.to_owned(),
+                    limit,
+                });
+            }
+        }
+        let used = usage.rows.get(&epoch).copied().unwrap_or(0);
+        if let Some(limit) = quota.rows_per_epoch {
+            if used.saturating_add(rows) > limit {
+                return Err(Error::RowsPerEpoch {
+                    collector: collector.to_owned(),
+                    epoch,
+                    requested: rows,
+                    limit,
+                });
+            }
+        }
+
+        usage.queries += 1;
+        usage.rows.insert(epoch, used.saturating_add(rows));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::quota::{Error, Quota, QuotaTracker, Quotas, SECONDS_PER_DAY};
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn day(n: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(n * SECONDS_PER_DAY + 1)
+    }
+
+    fn tracker() -> QuotaTracker {
+        QuotaTracker::new(Quotas {
+            default: Quota {
+                queries_per_day: Some(2),
+                rows_per_epoch: Some(100),
+            },
+            collectors: HashMap::from([("big".to_owned(), Quota::default())]),
+        })
+    }
+
+    #[test]
+    fn queries_per_day() {
+        let quotas = tracker();
+        quotas.admit("small", 1, 1, day(1)).unwrap();
+        quotas.admit("small", 1, 1, day(1)).unwrap();
+        assert_eq!(
+            Err(Error::QueriesPerDay {
+                collector: "small".to_owned(),
+                limit: 2
+            }),
+            quotas.admit("small", 1, 1, day(1))
+        );
+        // other collectors have quotas of their own
+        quotas.admit("other", 1, 1, day(1)).unwrap();
+        // and a new day starts over
+        quotas.admit("small", 1, 1, day(2)).unwrap();
+    }
+
+    #[test]
+    fn rows_per_epoch() {
+        let quotas = tracker();
+        quotas.admit("small", 1, 60, day(1)).unwrap();
+        assert!(matches!(
+            quotas.admit("small", 1, 50, day(2)),
+            Err(Error::RowsPerEpoch { epoch: 1, .. })
+        ));
+        // the rejected query did not count
+        quotas.admit("small", 1, 40, day(2)).unwrap();
+        quotas.admit("small", 2, 100, day(2)).unwrap();
+    }
+
+    #[test]
+    fn unlimited() {
+        let quotas = tracker();
+        for _ in 0..10 {
+            quotas.admit("big", 1, 1000, day(1)).unwrap();
+        }
+    }
+}
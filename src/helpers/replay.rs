@@ -0,0 +1,342 @@
+//!
+//! Capture and replay of a single helper's view of a protocol execution. Failures that depend on
+//! how messages from three helpers interleave are hard to reproduce, so instead of running all
+//! three again, the failing helper can be re-executed alone against what it saw the first time.
+//!
+//! [`Recorder`] wraps the ring a helper uses and writes down every message it sends and
+//! receives. Together with the helper's PRSS [`Seeds`], that makes a [`Capture`], which can be
+//! saved to disk. [`Replayer`] is a ring that serves received messages from the capture instead
+//! of the network, so the helper's protocol code can be executed deterministically, for example
+//! under a debugger. It also checks that the helper sends exactly what it sent originally and
+//! fails the first send that differs.
+//!
+//! Capture files contain PRSS seeds, so they must be handled with the same care as the private
+//! keys of the helper.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::buffer::Pending;
+use crate::helpers::codec::{read_fields, write_fields, Bincode, Codec};
+use crate::helpers::error::Error;
+use crate::helpers::ring::{FieldValues, HelperAddr, Message, Ring};
+use crate::prss::{Participant, Seeds};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A message sent to or received from the helper at `peer`. Payload is encoded with [`Bincode`],
+/// or with [`write_fields`] for field values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedMessage {
+    pub peer: HelperAddr,
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+/// Everything needed to replay the protocol execution of one helper.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Capture {
+    pub seeds: Seeds,
+    /// Messages received by the helper, in the order receives completed.
+    pub received: Vec<CapturedMessage>,
+    /// Messages sent by the helper, in the order they were sent.
+    pub sent: Vec<CapturedMessage>,
+}
+
+impl Capture {
+    /// PRSS participant that generates the same randomness as the captured helper did.
+    #[must_use]
+    pub fn participant(&self) -> Participant {
+        Participant::from(&self.seeds)
+    }
+
+    /// ## Errors
+    /// If the file cannot be written.
+    pub fn save(&self, path: &Path) -> Res<()> {
+        let file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(file, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(())
+    }
+
+    /// ## Errors
+    /// If the file is missing or does not contain a capture.
+    pub fn load(path: &Path) -> Res<Self> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(bincode::deserialize_from(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    }
+}
+
+/// Ring that records all messages going through the ring `R`.
+#[derive(Debug)]
+pub struct Recorder<R> {
+    inner: R,
+    received: Mutex<Vec<CapturedMessage>>,
+    sent: Mutex<Vec<CapturedMessage>>,
+}
+
+impl<R> Recorder<R> {
+    #[must_use]
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            received: Mutex::default(),
+            sent: Mutex::default(),
+        }
+    }
+
+    /// Finishes recording. `seeds` must be the ones the helper used to set up its PRSS
+    /// participant.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn into_capture(self, seeds: Seeds) -> Capture {
+        Capture {
+            seeds,
+            received: self.received.into_inner().unwrap(),
+            sent: self.sent.into_inner().unwrap(),
+        }
+    }
+}
+
+fn capture<T: Message>(
+    peer: HelperAddr,
+    msg: &T,
+) -> Result<CapturedMessage, crate::error::BoxError> {
+    Ok(CapturedMessage {
+        peer,
+        name: T::NAME.to_owned(),
+        payload: Bincode::encode(msg)?.into_vec(),
+    })
+}
+
+fn capture_fields<F: Field>(peer: HelperAddr, values: &[F]) -> CapturedMessage {
+    let mut payload = Vec::new();
+    write_fields(values, &mut payload);
+    CapturedMessage {
+        peer,
+        name: FieldValues::NAME.to_owned(),
+        payload,
+    }
+}
+
+#[async_trait]
+impl<R: Ring> Ring for Recorder<R> {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        let captured = capture(dest, &msg).map_err(|inner| Error::SendError { dest, inner })?;
+        self.inner.send(dest, msg).await?;
+        self.sent.lock().unwrap().push(captured);
+        Ok(())
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
+        self.receive_shared(source, 1).await
+    }
+
+    /// Every consumer records the message it got, so a replay hands each of them a copy.
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, Error> {
+        let msg = self.inner.receive_shared::<T>(source, consumers).await?;
+        let captured =
+            capture(source, &msg).map_err(|inner| Error::ReceiveError { source, inner })?;
+        self.received.lock().unwrap().push(captured);
+        Ok(msg)
+    }
+
+    async fn send_fields<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Result<(), Error> {
+        self.inner.send_fields(dest, values).await?;
+        self.sent.lock().unwrap().push(capture_fields(dest, values));
+        Ok(())
+    }
+
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, Error> {
+        let values = self.inner.receive_fields(source).await?;
+        self.received
+            .lock()
+            .unwrap()
+            .push(capture_fields(source, &values));
+        Ok(values)
+    }
+
+    async fn abort(&self, reason: &str) -> Result<(), Error> {
+        self.inner.abort(reason).await
+    }
+
+    fn pending(&self) -> Vec<Pending> {
+        self.inner.pending()
+    }
+
+    async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), Error> {
+        self.inner.close::<T>(dest).await
+    }
+}
+
+type Queues = HashMap<(HelperAddr, String), VecDeque<Vec<u8>>>;
+
+/// Ring that plays back a [`Capture`]. Messages of the same type from the same peer are
+/// delivered in the order they were captured.
+#[derive(Debug)]
+pub struct Replayer {
+    received: Mutex<Queues>,
+    sent: Mutex<Queues>,
+}
+
+impl Replayer {
+    #[must_use]
+    pub fn new(capture: &Capture) -> Self {
+        let queues = |messages: &[CapturedMessage]| {
+            let mut queues = Queues::new();
+            for m in messages {
+                queues
+                    .entry((m.peer, m.name.clone()))
+                    .or_default()
+                    .push_back(m.payload.clone());
+            }
+            Mutex::new(queues)
+        };
+
+        Self {
+            received: queues(&capture.received),
+            sent: queues(&capture.sent),
+        }
+    }
+
+    fn next(queues: &Mutex<Queues>, peer: HelperAddr, name: &str) -> Option<Vec<u8>> {
+        queues
+            .lock()
+            .unwrap()
+            .get_mut(&(peer, name.to_owned()))
+            .and_then(VecDeque::pop_front)
+    }
+
+    /// Checks that the message called `name` was sent to `dest` in the captured execution.
+    fn check_sent(&self, dest: HelperAddr, name: &str, payload: &[u8]) -> Result<(), Error> {
+        let inner = match Self::next(&self.sent, dest, name) {
+            Some(captured) if captured == payload => return Ok(()),
+            Some(_) => format!("{name} sent to {dest:?} differs from the captured one"),
+            None => format!("{name} was not sent to {dest:?} in the captured execution"),
+        };
+        Err(Error::SendError {
+            dest,
+            inner: inner.into(),
+        })
+    }
+
+    fn next_received(&self, source: HelperAddr, name: &str) -> Result<Vec<u8>, Error> {
+        Self::next(&self.received, source, name).ok_or_else(|| Error::ReceiveError {
+            source,
+            inner: format!("no more {name} from {source:?} in the capture").into(),
+        })
+    }
+}
+
+#[async_trait]
+impl Ring for Replayer {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        let payload = Bincode::encode(&msg).map_err(|inner| Error::SendError { dest, inner })?;
+        self.check_sent(dest, T::NAME, &payload)
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
+        let payload = self.next_received(source, T::NAME)?;
+        Bincode::decode(&payload).map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    /// Consumers of a shared message were captured one by one, so they are replayed that way.
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        _consumers: usize,
+    ) -> Result<T, Error> {
+        self.receive(source).await
+    }
+
+    async fn send_fields<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Result<(), Error> {
+        let mut payload = Vec::new();
+        write_fields(values, &mut payload);
+        self.check_sent(dest, FieldValues::NAME, &payload)
+    }
+
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, Error> {
+        let payload = self.next_received(source, FieldValues::NAME)?;
+        read_fields(&payload).map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    async fn abort(&self, _reason: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::helpers::error::Error;
+    use crate::helpers::replay::{Capture, Recorder, Replayer};
+    use crate::helpers::ring::{mock, Identity};
+    use crate::prss::{Participant, ParticipantSetup};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::securemul::{ProtocolContext, SecureMul};
+    use rand::thread_rng;
+
+    #[tokio::test]
+    async fn replay_securemul() {
+        let mut r = thread_rng();
+        let setup = [(); 3].map(|()| ParticipantSetup::new(&mut r));
+        let pk = [0, 1, 2].map(|i| setup[i].public_keys());
+        let [s1, s2, s3] = setup;
+        let seeds = s1.setup_seeds(&pk[2].1, &pk[1].0);
+        let p = [
+            Participant::from(&seeds),
+            s2.setup(&pk[0].1, &pk[2].0),
+            s3.setup(&pk[1].1, &pk[0].0),
+        ];
+
+        let a = ReplicatedSecretSharing::share(Fp31::from(5_u128), &mut r);
+        let b = ReplicatedSecretSharing::share(Fp31::from(6_u128), &mut r);
+        let [h1, h2, h3] = mock::make_three();
+        let h1 = Recorder::new(h1);
+        let expected = {
+            let ctx1 = ProtocolContext::new(Identity::H1, &p[0], &h1);
+            let ctx2 = ProtocolContext::new(Identity::H2, &p[1], &h2);
+            let ctx3 = ProtocolContext::new(Identity::H3, &p[2], &h3);
+
+            tokio::try_join!(
+                SecureMul::new(1, a[0], b[0]).execute(&ctx1),
+                SecureMul::new(1, a[1], b[1]).execute(&ctx2),
+                SecureMul::new(1, a[2], b[2]).execute(&ctx3),
+            )
+            .unwrap()
+            .0
+        };
+
+        let path = std::env::temp_dir().join(format!("raw-ipa-capture-{}", std::process::id()));
+        h1.into_capture(seeds).save(&path).unwrap();
+        let capture = Capture::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // replay helper 1 alone
+        let participant = capture.participant();
+        let replayer = Replayer::new(&capture);
+        let ctx = ProtocolContext::new(Identity::H1, &participant, &replayer);
+        let actual = SecureMul::new(1, a[0], b[0]).execute(&ctx).await.unwrap();
+        assert_eq!(expected, actual);
+
+        // different input makes the helper send something else
+        let replayer = Replayer::new(&capture);
+        let ctx = ProtocolContext::new(Identity::H1, &participant, &replayer);
+        let diverged = SecureMul::new(1, a[1], b[0]).execute(&ctx).await;
+        assert!(matches!(
+            diverged,
+            Err(crate::error::Error::Step { inner, .. })
+                if matches!(*inner, crate::error::Error::Helper(Error::SendError { .. }))
+        ));
+    }
+}
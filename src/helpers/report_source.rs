@@ -0,0 +1,317 @@
+//!
+//! Reports that a helper takes from a message queue, rather than from a file of shares. A
+//! [`ReportSource`] delivers reports one at a time, encoded the same way as in share files (see
+//! [`crate::helpers::share_file`]), and forgets about them once they are acknowledged. Sources
+//! that wrap a Kafka topic, or any other queue, implement it. [`StreamSource`] turns any stream
+//! of deliveries into one.
+//!
+//! Delivery is at least once: a helper that fails before it acknowledged a report gets it again
+//! when it reads from the source the next time, and so may a helper that did not fail, if the
+//! queue redelivers. [`SourceReader`] acknowledges reports only when asked to, which callers do
+//! once they stored the reports they read, and drops every report that went through its
+//! [`ReplayFilter`] before. The filter recognizes reports by the digest of their encoding, and
+//! can be kept for as long as reports of the same epoch may arrive, so that neither a
+//! redelivery nor a report submitted again counts twice. [`Ingest::push_report`] checks reports
+//! against the same filter wherever they come from.
+//!
+//! [`Ingest::push_report`]: crate::ingest::Ingest::push_report
+//!
+use crate::error::BoxError;
+use crate::helpers::models::SharedEvent;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to read from the report source: {0}")]
+    Source(BoxError),
+    #[error("failed to acknowledge reports up to {token}: {inner}")]
+    Ack { token: u64, inner: BoxError },
+    #[error("report {token} is malformed: {inner}")]
+    Malformed { token: u64, inner: bincode::Error },
+}
+
+/// Report as a source delivers it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Position of the report in the source, like the offset in a Kafka partition. Tokens of
+    /// later deliveries are greater.
+    pub token: u64,
+    /// [`SharedEvent`] encoded with bincode.
+    pub payload: Vec<u8>,
+}
+
+/// Queue that delivers reports to a helper.
+#[async_trait]
+pub trait ReportSource: Send {
+    /// Waits for the next report, or returns `None` if there are no more for the query.
+    async fn next(&mut self) -> Result<Option<Delivery>, BoxError>;
+
+    /// Acknowledges the report with `token` and every one before it, which the source does not
+    /// need to deliver again.
+    async fn ack(&mut self, token: u64) -> Result<(), BoxError>;
+}
+
+/// Source that takes reports from a stream, and only remembers how far they are acknowledged.
+#[derive(Debug)]
+pub struct StreamSource<S> {
+    stream: S,
+    acked: Option<u64>,
+}
+
+impl<S> StreamSource<S> {
+    #[must_use]
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            acked: None,
+        }
+    }
+
+    /// Token of the last report acknowledged, if any.
+    #[must_use]
+    pub fn acked(&self) -> Option<u64> {
+        self.acked
+    }
+}
+
+#[async_trait]
+impl<S> ReportSource for StreamSource<S>
+where
+    S: Stream<Item = Delivery> + Send + Unpin,
+{
+    async fn next(&mut self) -> Result<Option<Delivery>, BoxError> {
+        Ok(self.stream.next().await)
+    }
+
+    async fn ack(&mut self, token: u64) -> Result<(), BoxError> {
+        self.acked = Some(token);
+        Ok(())
+    }
+}
+
+/// Digests of the reports that were read before.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayFilter {
+    seen: HashSet<[u8; 32]>,
+}
+
+/// Digest a report is recognized by in a [`ReplayFilter`].
+fn digest(payload: &[u8]) -> [u8; 32] {
+    Sha256::digest(payload).into()
+}
+
+impl ReplayFilter {
+    /// Whether the report encoded as `payload` was seen before.
+    #[must_use]
+    pub fn contains(&self, payload: &[u8]) -> bool {
+        self.seen.contains(&digest(payload))
+    }
+
+    /// Remembers the report encoded as `payload`. Returns whether it was not seen before.
+    pub fn insert(&mut self, payload: &[u8]) -> bool {
+        self.seen.insert(digest(payload))
+    }
+
+    /// Digests of all reports seen, to keep the filter across restarts of the helper.
+    pub fn digests(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.seen.iter()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+impl FromIterator<[u8; 32]> for ReplayFilter {
+    fn from_iter<I: IntoIterator<Item = [u8; 32]>>(digests: I) -> Self {
+        Self {
+            seen: digests.into_iter().collect(),
+        }
+    }
+}
+
+/// Reads reports from a source, without those it read before.
+#[derive(Debug)]
+pub struct SourceReader<S> {
+    source: S,
+    filter: ReplayFilter,
+    /// Digests of the reports read and not acknowledged yet. They join the filter only once they
+    /// are, as the source delivers them again otherwise.
+    pending: HashSet<[u8; 32]>,
+    /// Token of the last report read and not acknowledged yet.
+    unacked: Option<u64>,
+    replayed: u64,
+}
+
+impl<S: ReportSource> SourceReader<S> {
+    #[must_use]
+    pub fn new(source: S, filter: ReplayFilter) -> Self {
+        Self {
+            source,
+            filter,
+            pending: HashSet::new(),
+            unacked: None,
+            replayed: 0,
+        }
+    }
+
+    /// Reads up to `max` reports that were not read before, fewer only if the source has no
+    /// more. Reports are not acknowledged until [`Self::commit`] is called.
+    ///
+    /// ## Errors
+    /// If the source fails, or delivers a report that cannot be decoded. Reports read up to that
+    /// point are lost, but the source delivers them again as they were not acknowledged.
+    pub async fn read(&mut self, max: usize) -> Result<Vec<SharedEvent>, Error> {
+        let mut reports = Vec::new();
+        let mut digests = Vec::new();
+        while reports.len() < max {
+            let delivery = match self.source.next().await.map_err(Error::Source)? {
+                Some(delivery) => delivery,
+                None => break,
+            };
+            self.unacked = Some(delivery.token);
+            let digest = digest(&delivery.payload);
+            if self.filter.seen.contains(&digest)
+                || self.pending.contains(&digest)
+                || digests.contains(&digest)
+            {
+                self.replayed += 1;
+                continue;
+            }
+            let report =
+                bincode::deserialize(&delivery.payload).map_err(|inner| Error::Malformed {
+                    token: delivery.token,
+                    inner,
+                })?;
+            reports.push(report);
+            digests.push(digest);
+        }
+        self.pending.extend(digests);
+        Ok(reports)
+    }
+
+    /// Acknowledges every report read so far. Callers do that once the reports are stored, so
+    /// that the source delivers them again if the helper fails before.
+    ///
+    /// ## Errors
+    /// If the source fails to take the acknowledgement.
+    pub async fn commit(&mut self) -> Result<(), Error> {
+        if let Some(token) = self.unacked {
+            self.source
+                .ack(token)
+                .await
+                .map_err(|inner| Error::Ack { token, inner })?;
+            self.unacked = None;
+            self.filter.seen.extend(self.pending.drain());
+        }
+        Ok(())
+    }
+
+    /// Number of reports dropped because they were read before.
+    #[must_use]
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    #[must_use]
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Gives the source and the filter back, to keep the latter for the next query. The filter
+    /// has the reports that were acknowledged only.
+    #[must_use]
+    pub fn into_parts(self) -> (S, ReplayFilter) {
+        (self.source, self.filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+    use crate::helpers::report_source::{
+        Delivery, Error, ReplayFilter, SourceReader, StreamSource,
+    };
+
+    fn event(value: u32) -> SharedEvent {
+        SharedEvent {
+            matchkeys: vec![ReplicatedShare(1, 2)],
+            epoch: 3,
+            timestamp: ReplicatedShare(4, 5),
+            kind: SharedEventKind::Trigger {
+                value: ReplicatedShare(value, value),
+            },
+        }
+    }
+
+    fn delivery(token: u64, value: u32) -> Delivery {
+        Delivery {
+            token,
+            payload: bincode::serialize(&event(value)).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_replays() {
+        // source redelivers the first report after the third
+        let deliveries = [(0, 1), (1, 2), (2, 3), (3, 1), (4, 4)].map(|(t, v)| delivery(t, v));
+        let source = StreamSource::new(futures::stream::iter(deliveries));
+        let mut reader = SourceReader::new(source, ReplayFilter::default());
+
+        assert_eq!(vec![event(1), event(2)], reader.read(2).await.unwrap());
+        assert_eq!(None, reader.source().acked());
+        reader.commit().await.unwrap();
+        assert_eq!(Some(1), reader.source().acked());
+
+        assert_eq!(vec![event(3), event(4)], reader.read(10).await.unwrap());
+        assert_eq!(1, reader.replayed());
+        reader.commit().await.unwrap();
+        let (source, filter) = reader.into_parts();
+        assert_eq!(Some(4), source.acked());
+        assert_eq!(4, filter.len());
+
+        // next query with the same filter drops reports submitted again
+        let source = StreamSource::new(futures::stream::iter([delivery(5, 2), delivery(6, 5)]));
+        let mut reader = SourceReader::new(source, filter.digests().copied().collect());
+        assert_eq!(vec![event(5)], reader.read(10).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn redelivered_after_failure() {
+        let source = StreamSource::new(futures::stream::iter([delivery(0, 1), delivery(1, 2)]));
+        let mut reader = SourceReader::new(source, ReplayFilter::default());
+        assert_eq!(2, reader.read(10).await.unwrap().len());
+
+        // helper failed before it stored the reports, so they were not acknowledged
+        let (_, filter) = reader.into_parts();
+        assert!(filter.is_empty());
+        let source = StreamSource::new(futures::stream::iter([delivery(0, 1), delivery(1, 2)]));
+        let mut reader = SourceReader::new(source, filter);
+        assert_eq!(vec![event(1), event(2)], reader.read(10).await.unwrap());
+        assert_eq!(0, reader.replayed());
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        let garbage = Delivery {
+            token: 7,
+            payload: vec![1, 2],
+        };
+        let source = StreamSource::new(futures::stream::iter([delivery(6, 1), garbage]));
+        let mut reader = SourceReader::new(source, ReplayFilter::default());
+        assert!(matches!(
+            reader.read(10).await,
+            Err(Error::Malformed { token: 7, .. })
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+//!
+//! Cache of query results, so that a query that is submitted again with the same input and the
+//! same parameters gets the result shares it got the first time. Recomputing them would not
+//! only waste the work, it would spend the privacy budget of the input again, and reveal fresh
+//! noise that can be averaged with the first.
+//!
+//! Results are keyed by a [`Fingerprint`] of the commitment to the input and the
+//! [`IpaQueryConfig`]. Every query says how it uses the cache with [`CacheControl`]. Helpers
+//! cache on their own, so they must all be asked the same way, and a query that is answered
+//! from the cache must not spend any budget.
+//!
+use crate::attribution::Model;
+use crate::query::{FieldType, IpaQueryConfig, SecurityMode};
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identifies a query by what it computes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    /// Fingerprint of a query with the input that `input_commitment` commits to, run with
+    /// `config`.
+    #[must_use]
+    pub fn new(input_commitment: &[u8], config: &IpaQueryConfig) -> Self {
+        let mut hash = Sha256::new();
+        // length-prefixed, so that no commitment is a prefix of another
+        hash.update((input_commitment.len() as u64).to_le_bytes());
+        hash.update(input_commitment);
+        hash.update(config.max_breakdown_key.to_le_bytes());
+        hash.update(config.attribution_window_seconds.to_le_bytes());
+        hash.update([config.per_user_cap]);
+        match config.attribution {
+            Model::LastTouch => hash.update([0_u8]),
+            Model::EqualCredit => hash.update([1_u8]),
+            Model::TimeDecay { half_life_seconds } => {
+                hash.update([2_u8]);
+                hash.update(half_life_seconds.to_le_bytes());
+            }
+        }
+        hash.update([config.value_scale.decimals]);
+        match config.value_scale.max_value {
+            Some(max_value) => {
+                hash.update([1_u8]);
+                hash.update(max_value.to_le_bytes());
+            }
+            None => hash.update([0_u8]),
+        }
+        match config.epsilon {
+            Some(epsilon) => {
+                hash.update([1_u8]);
+                hash.update(epsilon.to_le_bytes());
+            }
+            None => hash.update([0_u8]),
+        }
+        hash.update([match config.field {
+            FieldType::Fp31 => 0_u8,
+            FieldType::Fp32BitPrime => 1,
+        }]);
+        hash.update([match config.security {
+            SecurityMode::SemiHonest => 0_u8,
+            SecurityMode::Malicious => 1,
+        }]);
+        // a dry run computes something else than the query it samples
+        match config.sampling {
+            Some(sampling) => {
+                hash.update([1_u8]);
+                hash.update(sampling.rate.to_le_bytes());
+                hash.update(sampling.seed.to_le_bytes());
+            }
+            None => hash.update([0_u8]),
+        }
+        Self(hash.finalize().into())
+    }
+}
+
+/// How a query uses the result cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum CacheControl {
+    /// Cached result is returned if there is one, otherwise the result is computed and cached.
+    #[default]
+    Use,
+    /// Result is computed again and replaces the cached one.
+    Refresh,
+    /// Result is computed and not cached. Cached results are left alone.
+    Bypass,
+}
+
+#[derive(Debug)]
+struct Entry {
+    shares: Arc<Vec<u8>>,
+    stored: Instant,
+}
+
+/// Result shares of queries this helper ran, up to `capacity` of them and for `max_age` at
+/// most. The oldest result is evicted when the cache is full.
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    max_age: Duration,
+    entries: Mutex<HashMap<Fingerprint, Entry>>,
+}
+
+impl ResultCache {
+    #[must_use]
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            capacity,
+            max_age,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Result shares of the query with `fingerprint`, as of `now`, if the query may be
+    /// answered from the cache.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn get(
+        &self,
+        fingerprint: &Fingerprint,
+        control: CacheControl,
+        now: Instant,
+    ) -> Option<Arc<Vec<u8>>> {
+        if control != CacheControl::Use {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(fingerprint) {
+            Some(entry) if now.saturating_duration_since(entry.stored) <= self.max_age => {
+                Some(Arc::clone(&entry.shares))
+            }
+            Some(_) => {
+                entries.remove(fingerprint);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Stores the result shares of the query with `fingerprint`, computed at `now`, unless the
+    /// query bypasses the cache.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn insert(
+        &self,
+        fingerprint: Fingerprint,
+        control: CacheControl,
+        shares: Vec<u8>,
+        now: Instant,
+    ) {
+        if control == CacheControl::Bypass || self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&fingerprint) && entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.stored)
+                .map(|(f, _)| *f);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            fingerprint,
+            Entry {
+                shares: Arc::new(shares),
+                stored: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribution::Model;
+    use crate::helpers::result_cache::{CacheControl, Fingerprint, ResultCache};
+    use crate::query::IpaQueryConfig;
+    use std::time::{Duration, Instant};
+
+    fn fingerprint(commitment: &[u8]) -> Fingerprint {
+        Fingerprint::new(commitment, &IpaQueryConfig::default())
+    }
+
+    #[test]
+    fn fingerprints() {
+        let config = IpaQueryConfig::default();
+        assert_eq!(fingerprint(b"input"), fingerprint(b"input"));
+        assert_ne!(fingerprint(b"input"), fingerprint(b"other input"));
+        let other = IpaQueryConfig {
+            epsilon: Some(1.0),
+            ..config
+        };
+        assert_ne!(fingerprint(b"input"), Fingerprint::new(b"input", &other));
+        let other = IpaQueryConfig {
+            attribution: Model::EqualCredit,
+            ..config
+        };
+        assert_ne!(fingerprint(b"input"), Fingerprint::new(b"input", &other));
+    }
+
+    #[test]
+    fn cache_control() {
+        let cache = ResultCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let query = fingerprint(b"input");
+
+        assert_eq!(None, cache.get(&query, CacheControl::Use, now));
+        cache.insert(query, CacheControl::Bypass, vec![1], now);
+        assert_eq!(None, cache.get(&query, CacheControl::Use, now));
+
+        cache.insert(query, CacheControl::Use, vec![2], now);
+        assert_eq!(vec![2], *cache.get(&query, CacheControl::Use, now).unwrap());
+        assert_eq!(None, cache.get(&query, CacheControl::Refresh, now));
+        cache.insert(query, CacheControl::Refresh, vec![3], now);
+        assert_eq!(vec![3], *cache.get(&query, CacheControl::Use, now).unwrap());
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(None, cache.get(&query, CacheControl::Use, later));
+    }
+
+    #[test]
+    fn evicts_oldest() {
+        let cache = ResultCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let queries = ["a", "b", "c"].map(|c| fingerprint(c.as_bytes()));
+        for (i, query) in queries.iter().enumerate() {
+            let at = now + Duration::from_secs(i as u64);
+            cache.insert(*query, CacheControl::Use, vec![], at);
+        }
+
+        assert_eq!(None, cache.get(&queries[0], CacheControl::Use, now));
+        assert!(cache.get(&queries[1], CacheControl::Use, now).is_some());
+        assert!(cache.get(&queries[2], CacheControl::Use, now).is_some());
+    }
+}
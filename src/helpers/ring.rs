@@ -7,67 +7,320 @@
 //! corresponding helper without needing to know the exact location - this is what this module
 //! enables MPC helper service to do.
 //!
+//! Protocols that address a helper by its identity rather than by its position, or go over both
+//! peers in turn, use a [`PeerChannel`] per peer instead of the ring directly.
+//!
+use crate::field::Field;
+use crate::helpers::buffer::Pending;
+use crate::helpers::codec::{field_size, read_fields, write_fields};
 use crate::helpers::error::Error;
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::fmt::Debug;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display, Formatter};
+
+/// Trait for messages sent between helpers. Peers match messages by their name, which is part of
+/// the wire protocol: it must be unique among the messages of the protocol and must not change
+/// between versions of this crate, unlike type names, which are up to the compiler.
+pub trait Message: Debug + Send + Serialize + DeserializeOwned + 'static {
+    /// Name of the message on the wire.
+    const NAME: &'static str;
+}
+
+/// Implements [`Message`] for each of the types, with the name it has on the wire.
+macro_rules! message {
+    ($($t:ty => $name:literal),* $(,)?) => {
+        $(
+            impl $crate::helpers::ring::Message for $t {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+pub(crate) use message;
+
+message! {
+    u8 => "u8",
+    u16 => "u16",
+    u32 => "u32",
+    u64 => "u64",
+    u128 => "u128",
+    bool => "bool",
+    String => "string",
+    Vec<u8> => "bytes",
+    Vec<u32> => "u32s",
+    Vec<u64> => "u64s",
+    Abort => "abort",
+    FieldValues => "field-values",
+}
 
-/// Trait for messages sent between helpers
-pub trait Message: Debug + Send + Serialize + DeserializeOwned + 'static {}
+/// Control message sent to peers when a helper aborts the query. Rings recognize it by its type
+/// and fail their message buffer instead of storing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(not(any(test, feature = "test-fixture")), allow(dead_code))]
+pub(crate) struct Abort(pub String);
 
-impl<T> Message for T where T: Debug + Send + Serialize + DeserializeOwned + 'static {}
+/// Field values sent with [`Ring::send_fields`] by rings that do not write them to the transport
+/// directly. Payload is encoded with [`write_fields`].
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FieldValues(pub Vec<u8>);
 
 /// Destination. Currently we only support Left and Right, but we could support the exact address
 /// too
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum HelperAddr {
     Left,
     Right,
 }
 
+/// Position of a helper in the ring. Helper on the right of `H1` is `H2`, helper on the right
+/// of `H3` is `H1`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum Identity {
+    H1,
+    H2,
+    H3,
+}
+
+impl Identity {
+    pub const ALL: [Identity; 3] = [Identity::H1, Identity::H2, Identity::H3];
+
+    /// Identity of the helper at `addr` relative to this one.
+    #[must_use]
+    pub fn peer(self, addr: HelperAddr) -> Self {
+        match (self, addr) {
+            (Self::H1, HelperAddr::Right) | (Self::H3, HelperAddr::Left) => Self::H2,
+            (Self::H2, HelperAddr::Right) | (Self::H1, HelperAddr::Left) => Self::H3,
+            (Self::H3, HelperAddr::Right) | (Self::H2, HelperAddr::Left) => Self::H1,
+        }
+    }
+
+    /// Position of `peer` relative to this helper, or `None` if it is this helper.
+    #[must_use]
+    pub fn addr_of(self, peer: Identity) -> Option<HelperAddr> {
+        [HelperAddr::Left, HelperAddr::Right]
+            .into_iter()
+            .find(|&addr| self.peer(addr) == peer)
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
 /// Trait for MPC helpers to communicate with each other. Helpers can send messages and
-/// receive messages from a specific helper.
+/// receive messages from a specific helper. Rings are shared between tasks running on different
+/// threads, so they must be `Sync`.
 #[async_trait]
-pub trait Ring {
+pub trait Ring: Sync {
     /// Send message to the destination. Implementations are free to choose whether it is required
     /// to wait until `dest` acknowledges message or simply put it to a outgoing queue
     async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error>;
     async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error>;
+
+    /// Receives a message that `consumers` tasks of this helper wait for, every one of them with
+    /// this call and the same number of consumers. Each of them gets the message, which the ring
+    /// keeps until all of them have. This lets independent steps share a message they all
+    /// depend on. Rings that wrap other rings must forward this call.
+    ///
+    /// The default implementation supports a single consumer only.
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, Error> {
+        if consumers == 1 {
+            self.receive(source).await
+        } else {
+            Err(Error::ReceiveError {
+                source,
+                inner: format!("ring does not support {consumers} consumers of a message").into(),
+            })
+        }
+    }
+
+    /// Aborts the query after this helper hit a fatal error: every pending and future `receive`
+    /// on this helper and both of its peers fails with [`Error::Aborted`] carrying `reason`, and
+    /// messages buffered for the query are dropped.
+    async fn abort(&self, reason: &str) -> Result<(), Error>;
+
+    /// Closes the channel of messages of type `T` to `dest`, once this helper is not going to send
+    /// any more of them in the current step, for example because the protocol finished early.
+    /// `dest` receives the messages sent before as usual, but the receive after them fails with
+    /// [`Error::Closed`] rather than waiting for the rest of the query, and `dest` forgets about
+    /// the channel. Messages of the same type sent afterwards open it again, for the next step.
+    /// Channels of field values are closed with `T` being [`FieldValues`]. Rings that wrap other
+    /// rings must forward this call.
+    ///
+    /// The default implementation does not tell `dest`, which keeps waiting.
+    async fn close<T: Message>(&self, _dest: HelperAddr) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Messages that arrived to this helper but were not received yet, and those that it waits
+    /// for, to tell what a query that does not make progress is stuck on. Rings that wrap other
+    /// rings must forward this call.
+    ///
+    /// The default implementation knows of none.
+    fn pending(&self) -> Vec<Pending> {
+        Vec::new()
+    }
+
+    /// Sends field values to `dest`. They bypass the codec and are encoded with
+    /// [`write_fields`], which lets rings write them directly into the buffers handed to the
+    /// transport. This is the preferred way to send shares in bulk. Values must be received with
+    /// `receive_fields`, and rings that wrap other rings must forward both calls.
+    async fn send_fields<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(values.len() * field_size::<F>());
+        write_fields(values, &mut bytes);
+        self.send(dest, FieldValues(bytes)).await
+    }
+
+    /// Receives field values sent with `send_fields`.
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, Error> {
+        let FieldValues(bytes) = self.receive(source).await?;
+        read_fields(&bytes).map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    /// Send the same message to both peers.
+    async fn broadcast<T: Message + Clone>(&self, msg: T) -> Result<(), Error> {
+        futures::try_join!(
+            self.send(HelperAddr::Left, msg.clone()),
+            self.send(HelperAddr::Right, msg)
+        )?;
+        Ok(())
+    }
+
+    /// Receive a message of the same type from both peers. Returns the message received from the
+    /// helper on the left side first.
+    async fn receive_from_both<T: Message>(&self) -> Result<(T, T), Error> {
+        futures::try_join!(
+            self.receive(HelperAddr::Left),
+            self.receive(HelperAddr::Right)
+        )
+    }
+}
+
+/// Channel to one specific peer of a helper. Protocols that talk to a helper by its identity, or
+/// to each peer in turn, hold one of these rather than working out where the peer sits in the
+/// ring. Channels are cheap to make, every one of them borrows the ring.
+#[derive(Debug)]
+pub struct PeerChannel<'a, R> {
+    ring: &'a R,
+    peer: Identity,
+    addr: HelperAddr,
+}
+
+impl<R> Clone for PeerChannel<'_, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R> Copy for PeerChannel<'_, R> {}
+
+impl<'a, R: Ring> PeerChannel<'a, R> {
+    /// Channel from the helper with identity `me` to `peer`, or `None` if they are the same.
+    #[must_use]
+    pub fn new(ring: &'a R, me: Identity, peer: Identity) -> Option<Self> {
+        me.addr_of(peer).map(|addr| Self { ring, peer, addr })
+    }
+
+    /// Channels to both peers of the helper with identity `me`, the one on the left first.
+    #[must_use]
+    pub fn both(ring: &'a R, me: Identity) -> [Self; 2] {
+        [HelperAddr::Left, HelperAddr::Right].map(|addr| Self {
+            ring,
+            peer: me.peer(addr),
+            addr,
+        })
+    }
+
+    /// Identity of the helper at the other end.
+    #[must_use]
+    pub fn peer(&self) -> Identity {
+        self.peer
+    }
+
+    /// Position of the peer in the ring.
+    #[must_use]
+    pub fn addr(&self) -> HelperAddr {
+        self.addr
+    }
+
+    /// ## Errors
+    /// Same as [`Ring::send`].
+    pub async fn send<T: Message>(&self, msg: T) -> Result<(), Error> {
+        self.ring.send(self.addr, msg).await
+    }
+
+    /// ## Errors
+    /// Same as [`Ring::receive`].
+    pub async fn receive<T: Message>(&self) -> Result<T, Error> {
+        self.ring.receive(self.addr).await
+    }
+
+    /// ## Errors
+    /// Same as [`Ring::send_fields`].
+    pub async fn send_fields<F: Field>(&self, values: &[F]) -> Result<(), Error> {
+        self.ring.send_fields(self.addr, values).await
+    }
+
+    /// ## Errors
+    /// Same as [`Ring::receive_fields`].
+    pub async fn receive_fields<F: Field>(&self) -> Result<Vec<F>, Error> {
+        self.ring.receive_fields(self.addr).await
+    }
 }
 
-#[cfg(test)]
+/// In-memory ring for tests and benchmarks. Enabled outside of unit tests by the `test-fixture`
+/// feature.
+#[cfg(any(test, feature = "test-fixture"))]
 pub mod mock {
+    use crate::helpers::buffer::{MessageBuffer, Pending, Take};
+    use crate::helpers::codec::{Codec, Json};
     use crate::helpers::error::Error;
-    use crate::helpers::ring::{HelperAddr, Message, Ring};
+    use crate::helpers::ring::{Abort, HelperAddr, Message, Ring};
+    use crate::telemetry::status::QueryProgress;
     use async_trait::async_trait;
+    use bytes::Bytes;
     use std::any::TypeId;
-    use std::collections::hash_map::Entry;
-    use std::collections::HashMap;
+    use std::marker::PhantomData;
     use std::sync::{Arc, Mutex};
+    use std::time::Instant;
     use tokio::sync::mpsc::{channel, Sender};
+    use tracing::Instrument;
+
+    pub use crate::helpers::buffer::BufStats;
 
     /// Internally we represent all messages to be a sequence of bytes and store them inside
     /// a hashmap where each element is addressable by message type id and destination (i.e. who
     /// is the intended receiver of this message).
-    type MessageBuf = HashMap<(HelperAddr, TypeId), Box<[u8]>>;
+    type MessageBuf = MessageBuffer<TypeId>;
 
     /// Each message is packed inside an envelope with some meta information about it.
     #[derive(Debug)]
     struct MessageEnvelope {
         source: HelperAddr,
         type_id: TypeId,
-        payload: Box<[u8]>,
+        payload: Bytes,
+        /// Envelope carries no message, but closes the channel of `type_id`.
+        close: bool,
     }
 
     /// A mock implementation of `Ring` trait to be used in unit tests where all helpers are running
-    /// inside the same process. Provides simple and inefficient implementation by buffering all messages
-    /// on `send` and polling the buffer on `receive`. Message is determined to be the same if it has
+    /// inside the same process. Provides simple implementation by buffering all messages
+    /// on `send`. `receive` either takes the message from the buffer or registers itself as a waiter
+    /// and gets notified when the message arrives. Message is determined to be the same if it has
     /// the same `TypeId` and helper address matches the destination. For example, message `Foo` sent
     /// to Helper 2 will be received and removed from the local buffer only when Helper 2 attempts to
     /// receive it.
+    ///
+    /// Messages are serialized using codec `C` before they are put on the wire.
     #[derive(Debug)]
-    pub struct TestHelper {
+    pub struct TestHelper<C = Json> {
         // A handle to send message to this helper
         input_queue: Sender<MessageEnvelope>,
 
@@ -79,34 +332,44 @@ pub mod mock {
 
         // buffer for messages sent to this helper
         buf: Arc<Mutex<MessageBuf>>,
+
+        // bytes exchanged with peers, counted the same way `TcpRing` counts them
+        progress: Arc<QueryProgress>,
+
+        codec: PhantomData<C>,
     }
 
-    impl TestHelper {
+    impl<C: Codec> TestHelper<C> {
         /// Constructs a new instance of test helper using the specified `buf_capacity` buffer
         /// capacity for the internally used channel.
         ///
         /// ## Panics
-        /// Panics if Mutex used internally for synchronization is poisoned or if there are more
-        /// than one message with the same type id and destination address arriving via `send` call.
+        /// Panics if Mutex used internally for synchronization is poisoned.
+        /// Panics if the helper waiting for the message went away before it arrived.
         #[must_use]
         pub fn new(buf_capacity: usize) -> Self {
             let (tx, mut rx) = channel::<MessageEnvelope>(buf_capacity);
-            let buf = Arc::new(Mutex::new(HashMap::new()));
+            let buf = Arc::new(Mutex::new(MessageBuf::default()));
+            let progress = Arc::new(QueryProgress::default());
 
             tokio::spawn({
                 let buf = Arc::clone(&buf);
+                let progress = Arc::clone(&progress);
                 async move {
+                    let mut batch = Vec::with_capacity(buf_capacity);
                     while let Some(item) = rx.recv().await {
-                        // obtain an exclusive lock on the shared buffer
-                        // and store the received message there. If there is already a message
-                        // with the same type and destination, we simply panic and abort this task
-                        let buf = &mut *buf.lock().unwrap();
-                        match buf.entry((item.source, item.type_id)) {
-                            Entry::Occupied(_entry) => {
-                                panic!("Duplicated message {item:?}")
-                            }
-                            Entry::Vacant(entry) => entry.insert(item.payload),
-                        };
+                        // take whatever else arrived in the meantime too, so that a burst of
+                        // messages is stored under a single lock and with a single wakeup
+                        batch.push(item);
+                        while let Ok(item) = rx.try_recv() {
+                            batch.push(item);
+                        }
+                        for item in &batch {
+                            progress.bytes_received(item.source, item.payload.len());
+                        }
+                        if !Self::store(&buf, batch.drain(..)) {
+                            break;
+                        }
                     }
                 }
             });
@@ -116,9 +379,53 @@ pub mod mock {
                 left: None,
                 right: None,
                 buf,
+                progress,
+                codec: PhantomData,
             }
         }
 
+        /// Stores messages in the buffer, or hands them over to receivers that wait for them.
+        /// Returns `false` if the buffer failed or the query was aborted, and no more messages
+        /// should be accepted.
+        fn store(buf: &Mutex<MessageBuf>, batch: impl Iterator<Item = MessageEnvelope>) -> bool {
+            let mut buf = buf.lock().unwrap();
+            for item in batch {
+                if item.type_id == TypeId::of::<Abort>() {
+                    let reason = C::decode::<Abort>(&item.payload)
+                        .map_or_else(|e| format!("malformed abort message: {e}"), |a| a.0);
+                    buf.abort(Some(item.source), reason);
+                    return false;
+                }
+
+                if item.close {
+                    buf.close(item.source, item.type_id);
+                    continue;
+                }
+
+                // If the buffer failed, receivers already got the error and there is no point in
+                // accepting more messages
+                if buf.put(item.source, item.type_id, item.payload).is_err() {
+                    return false;
+                }
+            }
+            true
+        }
+
+        /// Returns a snapshot of the counters for the message buffer of this helper.
+        ///
+        /// ## Panics
+        /// Panics if Mutex used internally for synchronization is poisoned.
+        #[must_use]
+        pub fn stats(&self) -> BufStats {
+            self.buf.lock().unwrap().stats()
+        }
+
+        /// Progress of the query this helper runs, with the bytes it exchanged with its peers.
+        #[must_use]
+        pub fn progress(&self) -> Arc<QueryProgress> {
+            Arc::clone(&self.progress)
+        }
+
         fn set_left(&mut self, left: Sender<MessageEnvelope>) {
             self.left = Some(left);
         }
@@ -126,11 +433,15 @@ pub mod mock {
         fn set_right(&mut self, right: Sender<MessageEnvelope>) {
             self.right = Some(right);
         }
-    }
 
-    #[async_trait]
-    impl Ring for TestHelper {
-        async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        /// Puts `payload` of the message with `type_id` into an envelope and sends it to `dest`.
+        async fn deliver(
+            &self,
+            dest: HelperAddr,
+            type_id: TypeId,
+            payload: Bytes,
+            close: bool,
+        ) -> Result<(), Error> {
             assert!(self.left.is_some());
             assert!(self.right.is_some());
 
@@ -142,52 +453,94 @@ pub mod mock {
                 HelperAddr::Left => (self.left.as_ref().unwrap(), HelperAddr::Right),
                 HelperAddr::Right => (self.right.as_ref().unwrap(), HelperAddr::Left),
             };
-
-            let bytes = serde_json::to_vec(&msg).unwrap().into_boxed_slice();
             let envelope = MessageEnvelope {
-                type_id: TypeId::of::<T>(),
                 source,
-                payload: bytes,
+                type_id,
+                payload,
+                close,
             };
 
             target.send(envelope).await.map_err(|e| Error::SendError {
                 dest,
                 inner: Box::new(e) as _,
-            })?;
-            Ok(())
+            })
+        }
+    }
+
+    #[async_trait]
+    impl<C: Codec> Ring for TestHelper<C> {
+        async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+            let bytes = C::encode(&msg).map_err(|inner| Error::SendError { dest, inner })?;
+            let span = tracing::trace_span!("send", ?dest, message = T::NAME, size = bytes.len());
+            self.progress.bytes_sent(dest, bytes.len());
+            self.deliver(dest, TypeId::of::<T>(), bytes.into(), false)
+                .instrument(span)
+                .await
         }
 
         async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
-            let buf = Arc::clone(&self.buf);
-
-            let res = tokio::spawn(async move {
-                loop {
-                    {
-                        let buf = &mut *buf.lock().unwrap();
-                        let key = (source, TypeId::of::<T>());
-                        if let Entry::Occupied(entry) = buf.entry(key) {
-                            let payload = entry.remove();
-                            let obj: T = serde_json::from_slice(&payload).unwrap();
-
-                            return obj;
-                        }
-                    }
+            self.receive_shared(source, 1).await
+        }
 
-                    tokio::task::yield_now().await;
+        async fn receive_shared<T: Message>(
+            &self,
+            source: HelperAddr,
+            consumers: usize,
+        ) -> Result<T, Error> {
+            let span = tracing::trace_span!("receive", ?source, message = T::NAME);
+            let take = {
+                let _guard = span.enter();
+                self.buf
+                    .lock()
+                    .unwrap()
+                    .take_shared(source, TypeId::of::<T>(), consumers)?
+            };
+            let delivered = match take {
+                Take::Ready(payload) => Some(payload),
+                Take::Closed => None,
+                Take::Spilled(_) => unreachable!("buffers without a window never spill"),
+                Take::Wait(rx) => {
+                    rx.instrument(span)
+                        .await
+                        .map_err(|e| Error::ReceiveError {
+                            source,
+                            inner: Box::new(e) as _,
+                        })??
                 }
-            })
-            .await
-            .map_err(|e| Error::ReceiveError {
-                source,
-                inner: Box::new(e) as _,
-            });
-            res
+            };
+
+            let payload = delivered.ok_or(Error::Closed {
+                by: source,
+                message: T::NAME,
+            })?;
+            C::decode(&payload).map_err(|inner| Error::ReceiveError { source, inner })
+        }
+
+        async fn abort(&self, reason: &str) -> Result<(), Error> {
+            self.buf.lock().unwrap().abort(None, reason.to_owned());
+            self.broadcast(Abort(reason.to_owned())).await
+        }
+
+        async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), Error> {
+            self.deliver(dest, TypeId::of::<T>(), Bytes::new(), true)
+                .await
+        }
+
+        fn pending(&self) -> Vec<Pending> {
+            self.buf.lock().unwrap().pending(Instant::now())
         }
     }
 
     /// Creates 3 test helper instances and orchestrates them into a ring.
     #[must_use]
     pub fn make_three() -> [TestHelper; 3] {
+        make_three_with_codec()
+    }
+
+    /// Creates 3 test helper instances that use codec `C` to encode messages
+    /// and orchestrates them into a ring.
+    #[must_use]
+    pub fn make_three_with_codec<C: Codec>() -> [TestHelper<C>; 3] {
         let buf_capacity = 10;
         let mut helpers = [
             TestHelper::new(buf_capacity),
@@ -205,4 +558,206 @@ pub mod mock {
 
         helpers
     }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::field::Fp31;
+        use crate::helpers::codec::Bincode;
+        use crate::helpers::error::Error;
+        use crate::helpers::ring::mock::{make_three, make_three_with_codec, BufStats};
+        use crate::helpers::ring::{HelperAddr, Identity, PeerChannel, Ring};
+
+        message!((u8, u128) => "test.u8-u128");
+
+        #[tokio::test]
+        async fn counts_buffer_usage() {
+            let ring = make_three();
+
+            // receive before the message is sent, then send and receive the other way around
+            let (_, received) = tokio::join!(
+                ring[0].send(HelperAddr::Right, 1_u32),
+                ring[1].receive::<u32>(HelperAddr::Left),
+            );
+            assert_eq!(1, received.unwrap());
+
+            ring[1].send(HelperAddr::Left, 2_u32).await.unwrap();
+            tokio::task::yield_now().await;
+            let received = ring[0].receive::<u32>(HelperAddr::Right).await.unwrap();
+            assert_eq!(2, received);
+
+            for helper in &ring {
+                let BufStats {
+                    writes,
+                    removes,
+                    misses,
+                    dead_letters,
+                    spills,
+                } = helper.stats();
+                assert_eq!(writes, removes);
+                assert_eq!(0, dead_letters);
+                assert_eq!(0, spills);
+                assert!(misses <= removes);
+            }
+            assert_eq!(1, ring[0].stats().writes);
+            assert_eq!(1, ring[1].stats().writes);
+            assert_eq!(BufStats::default(), ring[2].stats());
+
+            // helper 0 has helper 1 on the right, which has it on the left
+            let (h0, h1) = (
+                ring[0].progress().snapshot(""),
+                ring[1].progress().snapshot(""),
+            );
+            assert!(h0.right.bytes_sent > 0);
+            assert_eq!(h0.right.bytes_sent, h1.left.bytes_received);
+            assert_eq!(h1.left.bytes_sent, h0.right.bytes_received);
+        }
+
+        #[tokio::test]
+        async fn shared_receive() {
+            let ring = make_three();
+
+            // one consumer waits for the message, the others ask for it after it arrived
+            let (first, _) = tokio::join!(
+                ring[1].receive_shared::<u32>(HelperAddr::Left, 3),
+                ring[0].send(HelperAddr::Right, 7_u32),
+            );
+            assert_eq!(7, first.unwrap());
+            for _ in 0..2 {
+                let received = ring[1].receive_shared::<u32>(HelperAddr::Left, 3).await;
+                assert_eq!(7, received.unwrap());
+            }
+            assert_eq!((1, 1), (ring[1].stats().writes, ring[1].stats().removes));
+
+            // once all consumers have it, the next message of the same type can arrive
+            ring[0].send(HelperAddr::Right, 8_u32).await.unwrap();
+            assert_eq!(8, ring[1].receive::<u32>(HelperAddr::Left).await.unwrap());
+        }
+
+        #[tokio::test]
+        async fn broadcast() {
+            let ring = make_three();
+
+            ring[0].broadcast(5_u8).await.unwrap();
+            ring[1].send(HelperAddr::Right, 6_u8).await.unwrap();
+
+            // helper 1 has helper 0 on the left, helper 2 has it on the right
+            assert_eq!(5, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+            assert_eq!((6, 5), ring[2].receive_from_both::<u8>().await.unwrap());
+        }
+
+        #[test]
+        fn identity() {
+            assert_eq!(Identity::H3, Identity::H1.peer(HelperAddr::Left));
+            assert_eq!(Identity::H1, Identity::H3.peer(HelperAddr::Right));
+            assert_eq!(Some(HelperAddr::Left), Identity::H1.addr_of(Identity::H3));
+            assert_eq!(Some(HelperAddr::Right), Identity::H3.addr_of(Identity::H1));
+            assert_eq!(None, Identity::H2.addr_of(Identity::H2));
+            assert_eq!("H2", Identity::H2.to_string());
+        }
+
+        #[tokio::test]
+        async fn peer_channels() {
+            let ring = make_three();
+            assert!(PeerChannel::new(&ring[0], Identity::H1, Identity::H1).is_none());
+            let [left, right] = PeerChannel::both(&ring[0], Identity::H1);
+            assert_eq!((Identity::H3, HelperAddr::Left), (left.peer(), left.addr()));
+            assert_eq!(Identity::H2, right.peer());
+
+            left.send(1_u8).await.unwrap();
+            right.send_fields(&[Fp31::from(2_u128)]).await.unwrap();
+            let h1 = PeerChannel::new(&ring[2], Identity::H3, Identity::H1).unwrap();
+            assert_eq!(1, h1.receive::<u8>().await.unwrap());
+            let h1 = PeerChannel::new(&ring[1], Identity::H2, Identity::H1).unwrap();
+            assert_eq!(
+                vec![Fp31::from(2_u128)],
+                h1.receive_fields::<Fp31>().await.unwrap()
+            );
+        }
+
+        #[tokio::test]
+        async fn abort() {
+            let ring = make_three();
+
+            let pending = ring[1].receive::<u8>(HelperAddr::Right);
+            let (pending, aborted) = tokio::join!(pending, ring[0].abort("bad input"));
+            aborted.unwrap();
+
+            // helper 1 has helper 0 on the left, helper 2 has it on the right
+            assert!(matches!(
+                pending,
+                Err(Error::Aborted { by: Some(HelperAddr::Left), reason }) if reason == "bad input"
+            ));
+            assert!(matches!(
+                ring[2].receive::<u8>(HelperAddr::Left).await,
+                Err(Error::Aborted {
+                    by: Some(HelperAddr::Right),
+                    ..
+                })
+            ));
+            assert!(matches!(
+                ring[0].receive::<u8>(HelperAddr::Left).await,
+                Err(Error::Aborted { by: None, .. })
+            ));
+        }
+
+        #[tokio::test]
+        async fn close() {
+            let ring = make_three();
+
+            // helper 1 waits for more than helper 0 sends before it finishes early
+            ring[0].send(HelperAddr::Right, 1_u8).await.unwrap();
+            let (received, closed) = tokio::join!(
+                async {
+                    let first = ring[1].receive::<u8>(HelperAddr::Left).await;
+                    (first, ring[1].receive::<u8>(HelperAddr::Left).await)
+                },
+                ring[0].close::<u8>(HelperAddr::Right),
+            );
+            closed.unwrap();
+            assert_eq!(1, received.0.unwrap());
+            assert!(matches!(
+                received.1,
+                Err(Error::Closed {
+                    by: HelperAddr::Left,
+                    ..
+                })
+            ));
+
+            // channel is open again for the next step, and nothing is left behind
+            ring[0].send(HelperAddr::Right, 2_u8).await.unwrap();
+            assert_eq!(2, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+            assert!(ring[1].pending().is_empty());
+        }
+
+        #[tokio::test]
+        async fn bincode_ring() {
+            let ring = make_three_with_codec::<Bincode>();
+
+            ring[2]
+                .send(HelperAddr::Right, (3_u8, 4_u128))
+                .await
+                .unwrap();
+            let received = ring[0]
+                .receive::<(u8, u128)>(HelperAddr::Left)
+                .await
+                .unwrap();
+            assert_eq!((3, 4), received);
+        }
+
+        #[tokio::test]
+        async fn fields() {
+            let ring = make_three();
+            let values = [3_u128, 17, 0].map(Fp31::from);
+
+            ring[1]
+                .send_fields(HelperAddr::Left, &values)
+                .await
+                .unwrap();
+            let received = ring[0]
+                .receive_fields::<Fp31>(HelperAddr::Right)
+                .await
+                .unwrap();
+            assert_eq!(&values[..], received);
+        }
+    }
 }
@@ -0,0 +1,285 @@
+//!
+//! Reader for the files of shares that `ipa_bench secret-share` writes for every helper. A file
+//! is a sequence of bincode-encoded [`SharedEvent`]s, and inputs of real queries run into
+//! hundreds of gigabytes, too much to parse into events up front. [`ShareFile`] maps the file
+//! into memory instead, and [`Records`] walks it one event at a time, handing out
+//! [`SharedEventRef`]s that read their fields straight from the mapped bytes. Nothing is copied
+//! or allocated until the protocol asks for it, and the operating system pages the file in and
+//! out as it is read.
+//!
+//! Events are laid out the way bincode encodes them with its default options:
+//!
+//! ```text
+//! | match key count (u64 LE) | match keys | epoch (u8) | timestamp | kind (u32 LE) | value |
+//! ```
+//!
+//! where every match key is a pair of u64 LE, and the timestamp and the value (breakdown key of
+//! source events, trigger value of trigger events) are pairs of u32 LE. Every event is checked
+//! to be complete and of a known kind before it is handed out, so its accessors cannot fail.
+//!
+use crate::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+const MATCHKEY_LEN: usize = 16;
+/// Bytes that follow the match keys: epoch, timestamp, kind and value.
+const TAIL_LEN: usize = 1 + 8 + 4 + 8;
+
+const SOURCE: u32 = 0;
+const TRIGGER: u32 = 1;
+
+/// File of shares mapped into memory.
+#[derive(Debug)]
+pub struct ShareFile {
+    map: Mmap,
+}
+
+impl ShareFile {
+    /// Maps the file at `path` into memory. The file must not be modified while it is mapped:
+    /// events read after a change may be anything, including ones that fail to parse.
+    ///
+    /// ## Errors
+    /// If the file cannot be opened or mapped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: share files are written once by the secret sharing tool and only read after,
+        // and every event is validated before any of it is read
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map })
+    }
+
+    /// Events in the file, in the order they were written.
+    #[must_use]
+    pub fn records(&self) -> Records<'_> {
+        Records::new(&self.map)
+    }
+}
+
+impl Deref for ShareFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// Iterator over the events encoded in a byte slice. Stops after the first malformed event.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+    /// Offset of the next event, for errors.
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    #[must_use]
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn next_event(&mut self) -> io::Result<SharedEventRef<'a>> {
+        let malformed = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed event at offset {}: {what}", self.offset),
+            )
+        };
+        let count = self
+            .bytes
+            .get(..8)
+            .map(|header| u64_at(header, 0))
+            .ok_or_else(|| malformed("truncated header"))?;
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(MATCHKEY_LEN))
+            .and_then(|keys| keys.checked_add(8 + TAIL_LEN))
+            .filter(|&len| len <= self.bytes.len())
+            .ok_or_else(|| malformed("truncated event"))?;
+        let (bytes, rest) = self.bytes.split_at(len);
+        let event = SharedEventRef { bytes };
+        match event.kind_tag() {
+            SOURCE | TRIGGER => {}
+            tag => return Err(malformed(&format!("unknown kind {tag}"))),
+        }
+        self.bytes = rest;
+        self.offset += len;
+        Ok(event)
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = io::Result<SharedEventRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let event = self.next_event();
+        if event.is_err() {
+            // nothing after a malformed event can be trusted to start where it seems to
+            self.bytes = &[];
+        }
+        Some(event)
+    }
+}
+
+/// [`SharedEvent`] that is read from the bytes it is encoded in as its fields are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedEventRef<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SharedEventRef<'a> {
+    /// Encoded event, as it is in the file.
+    #[must_use]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    #[must_use]
+    pub fn matchkeys(&self) -> impl ExactSizeIterator<Item = ReplicatedShare<u64>> + 'a {
+        self.bytes[8..self.tail()]
+            .chunks_exact(MATCHKEY_LEN)
+            .map(|key| ReplicatedShare(u64_at(key, 0), u64_at(key, 8)))
+    }
+
+    #[must_use]
+    pub fn epoch(&self) -> u8 {
+        self.bytes[self.tail()]
+    }
+
+    #[must_use]
+    pub fn timestamp(&self) -> ReplicatedShare<u32> {
+        self.u32_pair(self.tail() + 1)
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> SharedEventKind {
+        let value = self.u32_pair(self.tail() + 13);
+        match self.kind_tag() {
+            SOURCE => SharedEventKind::Source {
+                breakdown_key: value,
+            },
+            _ => SharedEventKind::Trigger { value },
+        }
+    }
+
+    /// Parses the whole event.
+    #[must_use]
+    pub fn to_event(&self) -> SharedEvent {
+        SharedEvent {
+            matchkeys: self.matchkeys().collect(),
+            epoch: self.epoch(),
+            timestamp: self.timestamp(),
+            kind: self.kind(),
+        }
+    }
+
+    /// Offset of the fields that follow the match keys.
+    fn tail(&self) -> usize {
+        self.bytes.len() - TAIL_LEN
+    }
+
+    fn kind_tag(&self) -> u32 {
+        u32_at(self.bytes, self.tail() + 9)
+    }
+
+    fn u32_pair(&self, offset: usize) -> ReplicatedShare<u32> {
+        ReplicatedShare(u32_at(self.bytes, offset), u32_at(self.bytes, offset + 4))
+    }
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(all(test, feature = "enable-serde"))]
+mod tests {
+    use crate::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+    use crate::helpers::share_file::{Records, ShareFile};
+
+    fn events() -> Vec<SharedEvent> {
+        vec![
+            SharedEvent {
+                matchkeys: vec![ReplicatedShare(1, 2), ReplicatedShare(u64::MAX, 3)],
+                epoch: 7,
+                timestamp: ReplicatedShare(4, 5),
+                kind: SharedEventKind::Source {
+                    breakdown_key: ReplicatedShare(6, 7),
+                },
+            },
+            SharedEvent {
+                matchkeys: Vec::new(),
+                epoch: 0,
+                timestamp: ReplicatedShare(8, 9),
+                kind: SharedEventKind::Trigger {
+                    value: ReplicatedShare(10, u32::MAX),
+                },
+            },
+        ]
+    }
+
+    fn encode(events: &[SharedEvent]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for e in events {
+            bincode::serialize_into(&mut bytes, e).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_bincode() {
+        let events = events();
+        let bytes = encode(&events);
+        let read = Records::new(&bytes)
+            .map(|e| e.unwrap().to_event())
+            .collect::<Vec<_>>();
+        assert_eq!(events, read);
+
+        let first = Records::new(&bytes).next().unwrap().unwrap();
+        assert_eq!(2, first.matchkeys().len());
+        assert_eq!(7, first.epoch());
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = encode(&events());
+        let truncated = Records::new(&bytes[..bytes.len() - 1]).collect::<Vec<_>>();
+        assert_eq!(2, truncated.len());
+        assert!(truncated[0].is_ok());
+        assert!(truncated[1].is_err());
+
+        let mut unknown = encode(&events()[..1]);
+        let kind = unknown.len() - 12;
+        unknown[kind] = 2;
+        assert!(Records::new(&unknown).next().unwrap().is_err());
+
+        // a huge count must not overflow
+        let huge = u64::MAX.to_le_bytes();
+        assert!(Records::new(&huge).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn mapped_file() {
+        let path = std::env::temp_dir().join(format!("raw-ipa-shares-{}", std::process::id()));
+        std::fs::write(&path, encode(&events())).unwrap();
+        let file = ShareFile::open(&path).unwrap();
+        let read = file
+            .records()
+            .map(|e| e.unwrap().to_event())
+            .collect::<Vec<_>>();
+        drop(file);
+        assert_eq!(events(), read);
+
+        std::fs::write(&path, []).unwrap();
+        assert_eq!(0, ShareFile::open(&path).unwrap().records().count());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
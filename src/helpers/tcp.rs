@@ -0,0 +1,2952 @@
+//!
+//! `Ring` implementation that connects helpers running in different processes via plain TCP
+//! connections, or any other [`Transport`]. Every helper listens for connections from its peers
+//! and opens one outbound connection to each of them, so there are two connections between every
+//! pair of helpers, one per direction. Connections carry the same bytes whatever the transport,
+//! see [`crate::helpers::transport`].
+//!
+//! Each connection starts with a single byte that tells the receiving side where the connecting
+//! helper sits relative to it (left or right), followed by a hello that says what the connecting
+//! helper speaks:
+//!
+//! ```text
+//! | protocol version (u16 LE) | codec length (u8) | codec | authenticated (u8) |
+//! | compressions (u8) | compression ids (u8 each) |
+//! ```
+//!
+//! The receiving side answers with a single byte, zero if it speaks the same protocol version
+//! and message codec, and one followed by the reason (length-prefixed with u16 LE) if it does
+//! not, or if the connection does not come from the address of the peer it claims to be. The
+//! connecting helper then fails, instead of misreading messages in the middle of a query, while
+//! the receiving one ignores the connection and waits for its actual peers.
+//!
+//! Helpers that share [`PeerKeys`] with their peers authenticate them. The connecting helper
+//! sends a random nonce after its hello, and the receiving side answers with a nonce of its own
+//! and an HMAC-SHA256 tag over both nonces and the claimed position, under the key the two
+//! helpers share. The connecting helper checks it and answers with a tag of its own, and the
+//! receiving side accepts or rejects it as above. Neither side learns anything about the key
+//! from a peer that does not know it. On transports with a secret of their own for every
+//! connection, such as QUIC, the tags cover that as well.
+//!
+//! The hello lists the [`Compression`]s the connecting helper offers, in order of preference. The
+//! receiving side picks the first one it is configured with as well, and follows its last
+//! acceptance with the id of that compression, or zero if there is none. After that, the
+//! connection carries a sequence of length-prefixed frames:
+//!
+//! ```text
+//! | frame length (u32 LE) | frame kind (u8) | body |
+//! ```
+//!
+//! where the frame length covers everything that follows it. Frames of kind 1 carry a
+//! [`ControlMessage`] about the query itself, encoded as described in [`crate::helpers::control`].
+//! Frames of kind 0 carry a data message of the protocol, and their body is
+//!
+//! ```text
+//! | name length (u16 LE) | message name | context length (u8) | trace context | payload |
+//! ```
+//!
+//! The message name is the [`Message::NAME`] of the message type, so the receiving helper can
+//! match it with the corresponding `receive` call. Frames of other kinds are skipped, so that
+//! newer helpers can send them to peers that may not understand them. Frames of kind 2 carry a
+//! data message whose payload is compressed, on connections with a compression, and their body is
+//!
+//! ```text
+//! | name length (u16 LE) | message name | context length (u8) | trace context |
+//! | payload length (u32 LE) | compressed payload |
+//! ```
+//!
+//! where the payload length is that of the payload before compression, which is held to
+//! [`ReceiveLimits::max_message_size`] before it is decompressed. On transports with
+//! [channels](crate::helpers::transport::Channels), data frames and the close of their channel go
+//! on the channel of their message name instead, the same frames as on the connection.
+//! Trace context is the W3C `traceparent` of the span that sent the message, or empty if trace
+//! propagation is not enabled (see [`crate::telemetry::trace`]). Field values sent with
+//! [`Ring::send_fields`] are not passed through the codec: their payload is written with
+//! [`write_fields`] directly into the frame.
+//!
+//! A helper that aborts the query sends an abort control message with the reason to both peers.
+//! A helper that receives it fails all pending and future receives and stops reading from the
+//! connection it came from. A helper that [closes](Ring::close) the channel of a message sends a
+//! close control message with its name, after the last data message called that.
+//!
+//! Helpers can limit what their peers send them with [`ReceiveLimits`]. A frame that is bigger
+//! than allowed fails the query. A peer that sends faster than allowed is throttled: the helper
+//! stops reading from its connection for a while, and TCP flow control slows the peer down
+//! without affecting the other connection. Messages that are not received within
+//! [`ReceiveLimits::message_ttl_ms`] are given up on and kept as dead letters, which can be
+//! persisted with [`TcpRing::persist_dead_letters`] for postmortem analysis.
+//!
+//! Messages from a peer that is ahead of this helper are held in memory up to
+//! [`ReceiveLimits::window`] bytes. Once the window of a peer is full, further messages from it
+//! are sealed and spilled to a [`BlobStore`] (see [`crate::helpers::buffer`]), or, if there is
+//! none, the helper stops reading
+//! from its connection until the protocol receives some of them, same as for a peer that sends
+//! too fast. The latter blocks the peer, and the query, if the protocol waits for a message
+//! that is behind the ones that filled the window.
+//!
+//! Frames are written to a peer by a task of its own, so that a send that is cancelled never
+//! leaves half a frame on the connection. Frames sent to a peer while a write to it is in progress
+//! queue up and go out together with the next write, as many as the [`BatchPolicy`] of the
+//! connection allows. By default that is an
+//! [`AdaptiveBatch`] that grows up to [`TcpRingConfig::max_batch_size`], see
+//! [`crate::helpers::batching`]. Peers read the same frames either way.
+//!
+//! Code that runs a query tells the ring where it is with [`TcpRing::pace`], which sends a
+//! progress control message to both peers. A helper that is more than
+//! [`TcpRingConfig::max_lead`] records ahead of the slowest peer waits there until that peer
+//! catches up, see [`crate::helpers::pacing`].
+//!
+//! Rings with [`TcpRingConfig::max_send_rate`] set hold back data messages so that they send
+//! no more than that to both peers together, and a query with a lot of data does not take up
+//! all of the links between helpers. Control messages are sent right away regardless.
+//!
+//! Rings with [`TcpRingConfig::heartbeat`] set send both peers a heartbeat control message at a
+//! fixed interval, whatever the query does, and fail the query once a peer sent nothing for
+//! longer than the timeout, see [`crate::helpers::liveness`]. What the ring heard from its peers
+//! is part of the [`QueryProgress`] of the query.
+//!
+use crate::error::BoxError;
+use crate::field::Field;
+use crate::helpers::batching::{AdaptiveBatch, BatchPolicy, DEFAULT_MIN_BATCH};
+use crate::helpers::buffer::{DeadLetter, Failure, MessageBuffer, Pending, Spilled, Take};
+use crate::helpers::codec::{read_fields, write_fields, Bincode, Codec};
+use crate::helpers::compression::{Compression, MIN_COMPRESSED_SIZE};
+use crate::helpers::control::ControlMessage;
+use crate::helpers::error::Error;
+use crate::helpers::liveness::{HeartbeatConfig, Liveness};
+use crate::helpers::memory::MemoryTracker;
+use crate::helpers::pacing::{Pacer, Position};
+use crate::helpers::pool::BufferPool;
+use crate::helpers::ring::{FieldValues, HelperAddr, Message, Ring};
+use crate::helpers::transport::{ChannelWriter, Channels, Connection, Link, Listener, Transport};
+use crate::query::Stage;
+use crate::storage::{self, BlobStore};
+use crate::telemetry;
+use crate::telemetry::status::{PeerHealth, QueryProgress};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use rand::{thread_rng, CryptoRng, RngCore};
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::any::type_name;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+#[cfg(feature = "enable-serde")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::{debug, error, warn, Instrument};
+use zeroize::Zeroize;
+
+/// Version of the wire protocol. It must be bumped whenever framing or encoding of messages
+/// changes in a way that helpers running older versions cannot read.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Kind of frames that carry data messages of the protocol.
+const DATA_FRAME: u8 = 0;
+/// Kind of frames that carry a [`ControlMessage`].
+const CONTROL_FRAME: u8 = 1;
+/// Kind of frames that carry data messages with a compressed payload.
+const COMPRESSED_FRAME: u8 = 2;
+
+/// How long to wait before trying to connect to a peer that is not listening yet.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a connection may take to say which peer it is, before it is given up on.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the keys peers share, and of the nonces and tags they authenticate each other with.
+const AUTH_SIZE: usize = 32;
+
+/// Number of buffers for outgoing frames kept around to be reused.
+const MAX_POOLED_BUFFERS: usize = 32;
+
+/// Initial size of the buffer incoming frames are read into. Frames are split off this buffer
+/// without copying, and it is reused once all messages read into it are received.
+const READ_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Burst a throttled connection may carry at once, as the time it takes to carry it at the
+/// limited rate.
+const THROTTLE_BURST: Duration = Duration::from_secs(1);
+
+/// Name of the blob dead letters are persisted as, see [`TcpRing::persist_dead_letters`].
+pub const DEAD_LETTERS_BLOB: &str = "dead-letters";
+
+/// Largest frame a peer may send by default, see [`ReceiveLimits::max_message_size`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Limits on what every peer may send to this helper.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct ReceiveLimits {
+    /// Largest frame a peer may send, in bytes. [`DEFAULT_MAX_MESSAGE_SIZE`] if it is not
+    /// configured, and unlimited only if it is set to `None` (`null`) explicitly.
+    #[cfg_attr(
+        feature = "enable-serde",
+        serde(default = "ReceiveLimits::default_max_message_size")
+    )]
+    pub max_message_size: Option<usize>,
+    /// Number of bytes per second a peer may send on average. Unlimited if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_ingest_rate: Option<u64>,
+    /// Milliseconds a message may wait to be received before it is given up on. Messages wait
+    /// for as long as the query runs if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub message_ttl_ms: Option<u64>,
+    /// Bytes of messages from a peer that are held in memory until they are received. Any
+    /// amount, up to the memory limit, if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub window: Option<usize>,
+}
+
+impl ReceiveLimits {
+    #[allow(clippy::unnecessary_wraps)] // for serde
+    fn default_max_message_size() -> Option<usize> {
+        Some(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl Default for ReceiveLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: Self::default_max_message_size(),
+            max_ingest_rate: None,
+            message_ttl_ms: None,
+            window: None,
+        }
+    }
+}
+
+/// Keys this helper shares with its peers, one for each of them. The left key of a helper is the
+/// right key of the helper on its left.
+#[derive(Clone)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct PeerKeys {
+    /// Key shared with the helper on the left side, hex-encoded in the configuration.
+    #[cfg_attr(feature = "enable-serde", serde(with = "hex::serde"))]
+    pub left: [u8; AUTH_SIZE],
+    /// Key shared with the helper on the right side, hex-encoded in the configuration.
+    #[cfg_attr(feature = "enable-serde", serde(with = "hex::serde"))]
+    pub right: [u8; AUTH_SIZE],
+}
+
+impl PeerKeys {
+    fn of(&self, peer: HelperAddr) -> &[u8; AUTH_SIZE] {
+        match peer {
+            HelperAddr::Left => &self.left,
+            HelperAddr::Right => &self.right,
+        }
+    }
+}
+
+impl Debug for PeerKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("PeerKeys { .. }")
+    }
+}
+
+impl Drop for PeerKeys {
+    fn drop(&mut self) {
+        self.left.zeroize();
+        self.right.zeroize();
+    }
+}
+
+/// Addresses required to join the ring.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct TcpRingConfig {
+    /// Address this helper accepts connections from its peers on.
+    pub listen: SocketAddr,
+    /// Address of the helper on the left side.
+    pub left: SocketAddr,
+    /// Address of the helper on the right side.
+    pub right: SocketAddr,
+    /// How helpers connect to each other, plain TCP if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub transport: Transport,
+    /// Keys that peers are authenticated with. Required, unless `allow_unauthenticated` is set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub keys: Option<PeerKeys>,
+    /// Accepts peers that do not authenticate, if there are no `keys`. Anything that can reach
+    /// the listening address from one of the peer addresses can then pose as that peer.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub allow_unauthenticated: bool,
+    /// Maximum number of bytes of messages that arrived but have not been received yet. If peers
+    /// send more than that, the query fails. Unlimited if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub memory_limit: Option<usize>,
+    #[cfg_attr(feature = "enable-serde", serde(default, flatten))]
+    pub limits: ReceiveLimits,
+    /// Directory messages that do not fit into the window of their peer are spilled to, in an
+    /// [ephemeral](BlobStore::ephemeral) store of the ring. Not used if there is no window.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub spill_dir: Option<PathBuf>,
+    /// Most bytes of frames written to a peer at once. [`DEFAULT_MAX_BATCH`] if not set.
+    ///
+    /// [`DEFAULT_MAX_BATCH`]: crate::helpers::batching::DEFAULT_MAX_BATCH
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_batch_size: Option<usize>,
+    /// Most records this helper may get ahead of the slowest peer, see [`TcpRing::pace`]. It
+    /// does not wait for peers if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_lead: Option<u64>,
+    /// Heartbeats this helper sends its peers and expects from them, see
+    /// [`TcpRing::with_heartbeat`]. No heartbeats are sent if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Number of bytes of data messages per second this helper may send to both peers together
+    /// on average, see [`TcpRing::with_send_rate`]. Unlimited if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_send_rate: Option<u64>,
+    /// Compressions this helper offers its peers for what it sends them, in order of preference,
+    /// and accepts for what they send it, see [`crate::helpers::compression`]. Nothing is
+    /// compressed if empty.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub compression: Vec<Compression>,
+}
+
+impl TcpRingConfig {
+    /// Configuration of a helper that listens on `listen` for the helpers at `left` and `right`,
+    /// over TCP and with nothing else set. Peers are not authenticated, so either `keys` or
+    /// `allow_unauthenticated` needs to be set before the helper can connect.
+    #[must_use]
+    pub fn new(listen: SocketAddr, left: SocketAddr, right: SocketAddr) -> Self {
+        Self {
+            listen,
+            left,
+            right,
+            transport: Transport::default(),
+            keys: None,
+            allow_unauthenticated: false,
+            memory_limit: None,
+            limits: ReceiveLimits::default(),
+            spill_dir: None,
+            max_batch_size: None,
+            max_lead: None,
+            heartbeat: None,
+            max_send_rate: None,
+            compression: Vec::new(),
+        }
+    }
+
+    /// Binds the listening address for [`TcpRing::connect`], over the configured transport.
+    ///
+    /// ## Errors
+    /// If it fails to bind to the listening address.
+    pub async fn bind(&self) -> io::Result<Listener> {
+        self.transport.bind(self.listen).await
+    }
+}
+
+#[cfg(feature = "enable-serde")]
+impl TcpRingConfig {
+    /// # Errors
+    /// If the file is missing or badly formatted.
+    pub fn load(path: &Path) -> crate::error::Res<Self> {
+        let s = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+}
+
+/// Helper ring over TCP connections. Messages are encoded using codec `C`.
+#[derive(Debug)]
+pub struct TcpRing<C = Bincode> {
+    left: Arc<Outbox>,
+    right: Arc<Outbox>,
+    left_addr: SocketAddr,
+    right_addr: SocketAddr,
+    /// Messages are keyed by their name. Names of messages that arrived point into the frames
+    /// they arrived in, and names of messages that are received are static, so neither needs
+    /// an allocation.
+    buf: Arc<Mutex<MessageBuffer<Bytes>>>,
+    /// Notified whenever messages leave the buffer, so that readers of peers whose window is
+    /// full can carry on.
+    drained: Arc<Notify>,
+    pool: BufferPool,
+    progress: Arc<QueryProgress>,
+    pacing: Arc<Pacing>,
+    liveness: Arc<Mutex<Liveness>>,
+    /// Data messages sent to both peers, if their rate is limited.
+    send_throttle: Option<Mutex<Throttle>>,
+    codec: PhantomData<C>,
+}
+
+/// Where this helper and its peers are in the query.
+#[derive(Debug, Default)]
+struct Pacing {
+    pacer: Mutex<Pacer>,
+    /// Notified whenever a peer says where it is, or the query fails.
+    changed: Notify,
+}
+
+impl<C: Codec> TcpRing<C> {
+    /// Establishes connections with both peers, and accepts theirs on `listener`, typically bound
+    /// with [`TcpRingConfig::bind`]. This function returns only after both peers are connected,
+    /// so all three helpers must be started for it to complete.
+    ///
+    /// ## Errors
+    /// If there are no keys to authenticate peers with and unauthenticated peers are not
+    /// allowed, if the spill directory cannot be created, or if a peer rejects this helper or
+    /// does not know the key it shares with this helper.
+    pub async fn connect<L: Into<Listener>>(
+        listener: L,
+        config: &TcpRingConfig,
+    ) -> io::Result<Self> {
+        if config.keys.is_none() && !config.allow_unauthenticated {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "peers must be authenticated, unless unauthenticated peers are allowed explicitly",
+            ));
+        }
+        let listener = listener.into();
+        let memory = MemoryTracker::new(config.memory_limit);
+        let spill = match &config.spill_dir {
+            Some(dir) => Some(Arc::new(
+                BlobStore::ephemeral(dir, &mut thread_rng())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            )),
+            None => None,
+        };
+        let (left, right, limits) = (config.left, config.right, config.limits);
+        let compression = &config.compression[..];
+        let buf = MessageBuffer::with_memory(memory).window(limits.window, spill);
+        let buf = Arc::new(Mutex::new(buf));
+        let drained = Arc::new(Notify::new());
+        let progress = Arc::new(QueryProgress::default());
+        let pacing = Arc::new(Pacing::default());
+        let liveness = Arc::new(Mutex::new(Liveness::new(Instant::now())));
+        if let Some(ttl) = limits.message_ttl_ms {
+            let ttl = Duration::from_millis(ttl);
+            tokio::spawn(Self::expire_messages(
+                Arc::downgrade(&buf),
+                Arc::clone(&drained),
+                Arc::clone(&progress),
+                ttl,
+            ));
+        }
+
+        // when this helper connects to the helper on its left, it is the right one for that peer
+        let peers = Peers {
+            left,
+            right,
+            keys: config.keys.clone(),
+            compression: compression.to_vec(),
+        };
+        // boxed, so that futures waiting for the ring to connect stay small
+        let (left_link, right_link, ()) = futures::try_join!(
+            Box::pin(Self::dial(
+                &listener,
+                left,
+                HelperAddr::Right,
+                peers.key(HelperAddr::Left),
+                compression,
+            )),
+            Box::pin(Self::dial(
+                &listener,
+                right,
+                HelperAddr::Left,
+                peers.key(HelperAddr::Right),
+                compression,
+            )),
+            Box::pin(Self::accept_peers(
+                &listener, &peers, &buf, &drained, &progress, &pacing, &liveness, limits
+            )),
+        )?;
+
+        let ring = Self {
+            left: Arc::new(Outbox::new(left_link)),
+            right: Arc::new(Outbox::new(right_link)),
+            left_addr: left,
+            right_addr: right,
+            buf,
+            drained,
+            pool: BufferPool::new(MAX_POOLED_BUFFERS),
+            progress,
+            pacing,
+            liveness,
+            send_throttle: None,
+            codec: PhantomData,
+        }
+        .with_max_lead(config.max_lead)
+        .with_heartbeat(config.heartbeat)
+        .with_send_rate(config.max_send_rate);
+        Ok(match config.max_batch_size {
+            Some(max) => ring.with_batch_policy(|| AdaptiveBatch::new(DEFAULT_MIN_BATCH, max)),
+            None => ring,
+        })
+    }
+
+    /// Batches frames written to each peer as `make` decides, instead of with the default
+    /// [`AdaptiveBatch`]. Every connection gets a policy of its own.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn with_batch_policy<P, F>(self, make: F) -> Self
+    where
+        P: BatchPolicy + 'static,
+        F: Fn() -> P,
+    {
+        for outbox in [&self.left, &self.right] {
+            *outbox.policy.lock().unwrap() = Box::new(make());
+        }
+        self
+    }
+
+    /// Lets this helper get at most `max_lead` records ahead of the slowest peer, see
+    /// [`Self::pace`].
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn with_max_lead(self, max_lead: Option<u64>) -> Self {
+        self.pacing.pacer.lock().unwrap().set_max_lead(max_lead);
+        self
+    }
+
+    /// Sends data messages to both peers together at no more than `rate` bytes per second on
+    /// average, after a burst of a second worth of them. Sends wait until the rate allows them.
+    /// Control messages are never held back, so that peers still hear about the query while
+    /// its data is throttled. Sends are not limited if `rate` is not set.
+    #[must_use]
+    pub fn with_send_rate(mut self, rate: Option<u64>) -> Self {
+        self.send_throttle = rate.map(|rate| Mutex::new(Throttle::new(rate, Instant::now())));
+        self
+    }
+
+    /// Sends both peers a heartbeat every [`HeartbeatConfig::interval_ms`], and fails the query
+    /// with [`Error::PeerGone`] once a peer sent nothing for longer than
+    /// [`HeartbeatConfig::timeout_ms`], see [`crate::helpers::liveness`]. Peers must send
+    /// heartbeats at least as often, or they count as gone. Does nothing if `config` is not set.
+    #[must_use]
+    pub fn with_heartbeat(self, config: Option<HeartbeatConfig>) -> Self {
+        if let Some(config) = config {
+            let interval = config.interval().max(Duration::from_millis(1));
+            tokio::spawn(Self::send_heartbeats(
+                [
+                    (HelperAddr::Left, Arc::downgrade(&self.left)),
+                    (HelperAddr::Right, Arc::downgrade(&self.right)),
+                ],
+                Arc::downgrade(&self.buf),
+                interval,
+            ));
+            tokio::spawn(Self::watch_peers(
+                Arc::downgrade(&self.buf),
+                Arc::clone(&self.liveness),
+                Arc::clone(&self.drained),
+                Arc::clone(&self.progress),
+                Arc::clone(&self.pacing),
+                interval,
+                config.timeout(),
+            ));
+        }
+        self
+    }
+
+    /// Tells both peers that this helper starts on the records of `stage` up to `records`, and
+    /// waits until it is no more than [`TcpRingConfig::max_lead`] records ahead of the slowest
+    /// of them. Code that runs a query calls this before it starts on every chunk of records,
+    /// so that a helper that is ahead does not send messages its peers cannot take yet.
+    ///
+    /// ## Errors
+    /// If the progress cannot be sent to peers, or the query fails while this helper waits.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub async fn pace(&self, stage: Stage, records: u64) -> Result<(), Error> {
+        let position = Position::new(stage, records);
+        if self.pacing.pacer.lock().unwrap().advance(position) {
+            let progress = ControlMessage::Progress { position };
+            let left = self.make_control_frame(HelperAddr::Left, &progress)?;
+            let right = self.make_control_frame(HelperAddr::Right, &progress)?;
+            futures::try_join!(
+                self.write_frame(HelperAddr::Left, left),
+                self.write_frame(HelperAddr::Right, right)
+            )?;
+        }
+        loop {
+            // registered before checking, so that news in between is not missed
+            let changed = self.pacing.changed.notified();
+            if let Some(failure) = self.failure() {
+                return Err(failure.into());
+            }
+            let lead = {
+                let pacer = self.pacing.pacer.lock().unwrap();
+                if pacer.may_proceed() {
+                    return Ok(());
+                }
+                pacer.lead()
+            };
+            debug!("{lead} records ahead of the slowest peer, waiting for it to catch up");
+            changed.await;
+        }
+    }
+
+    /// Connects to the peer at `addr` over the transport of `listener`, for which this helper is
+    /// the `me` peer, and proves that it knows `key` if there is one. Returns the connection
+    /// along with the one of `compression` the peer picked.
+    async fn dial(
+        listener: &Listener,
+        addr: SocketAddr,
+        me: HelperAddr,
+        key: Option<&[u8; AUTH_SIZE]>,
+        compression: &[Compression],
+    ) -> io::Result<(Link, Option<Compression>)> {
+        loop {
+            match listener.dial(addr).await {
+                Ok(mut link) => {
+                    let stream = &mut link.stream;
+                    stream.write_u8(me.into()).await?;
+                    let hello = Hello::of::<C>(key.is_some()).offering(compression);
+                    hello.write(stream).await?;
+                    if let Some(key) = key {
+                        let ours = nonce();
+                        stream.write_all(&ours).await?;
+                        stream.flush().await?;
+                        read_answer(stream, addr).await?;
+                        let mut theirs = [0; AUTH_SIZE];
+                        let mut tag = [0; AUTH_SIZE];
+                        stream.read_exact(&mut theirs).await?;
+                        stream.read_exact(&mut tag).await?;
+                        auth_mac(key, ACCEPTOR_TAG, &link.binding, me, &theirs, &ours)
+                            .verify(&tag)
+                            .map_err(|_| {
+                                io::Error::new(
+                                    io::ErrorKind::PermissionDenied,
+                                    format!("{addr} does not know the key shared with it"),
+                                )
+                            })?;
+                        let tag =
+                            auth_mac(key, DIALER_TAG, &link.binding, me, &ours, &theirs).finalize();
+                        stream.write_all(&tag.into_bytes()).await?;
+                    }
+                    stream.flush().await?;
+                    read_answer(stream, addr).await?;
+                    let picked = read_compression(stream, compression).await?;
+                    debug!("connected to {addr} as its {me:?} peer, compressed with {picked:?}");
+                    return Ok((link, picked));
+                }
+                Err(e) => {
+                    debug!("{addr} is not available yet: {e}");
+                    tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Accepts connections until both peers are connected, and reads frames from them. Connections
+    /// that fail the handshake are ignored.
+    #[allow(clippy::too_many_arguments)]
+    async fn accept_peers(
+        listener: &Listener,
+        peers: &Peers,
+        buf: &Arc<Mutex<MessageBuffer<Bytes>>>,
+        drained: &Arc<Notify>,
+        progress: &Arc<QueryProgress>,
+        pacing: &Arc<Pacing>,
+        liveness: &Arc<Mutex<Liveness>>,
+        limits: ReceiveLimits,
+    ) -> io::Result<()> {
+        // handshakes run alongside each other, so that a connection that stalls does not keep
+        // actual peers out
+        let mut handshakes = FuturesUnordered::new();
+        let mut seen = Vec::with_capacity(2);
+        while seen.len() < 2 {
+            let next = if handshakes.is_empty() {
+                Either::Left(listener.accept().await)
+            } else {
+                match future::select(Box::pin(listener.accept()), handshakes.next()).await {
+                    Either::Left((accepted, _)) => Either::Left(accepted),
+                    Either::Right((done, _)) => Either::Right(done.unwrap()), // not empty
+                }
+            };
+            let (mut link, addr, (source, compression)) = match next {
+                Either::Left(accepted) => {
+                    let (incoming, addr) = accepted?;
+                    handshakes.push(async move {
+                        let handshake = async {
+                            let mut link = incoming.open().await?;
+                            let peer = Self::handshake(&mut link, addr, peers).await?;
+                            Ok::<_, io::Error>((link, peer))
+                        };
+                        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake);
+                        (addr, handshake.await)
+                    });
+                    continue;
+                }
+                Either::Right((addr, Ok(Ok((link, peer))))) => (link, addr, peer),
+                Either::Right((addr, Ok(Err(e)))) => {
+                    warn!("ignored connection from {addr}: {e}");
+                    continue;
+                }
+                Either::Right((addr, Err(_))) => {
+                    warn!("ignored connection from {addr}: no handshake in {HANDSHAKE_TIMEOUT:?}");
+                    continue;
+                }
+            };
+            if seen.contains(&source) {
+                let reason = format!("{source:?} peer is already connected");
+                warn!("ignored connection from {addr}: {reason}");
+                let _ = write_rejection(&mut link.stream, &reason).await;
+                continue;
+            }
+            if let Err(e) = write_acceptance(&mut link.stream, compression).await {
+                warn!("ignored connection from {addr}: {e}");
+                continue;
+            }
+            seen.push(source);
+            debug!("accepted connection from {addr} as {source:?} peer");
+
+            let inbound = Inbound {
+                source,
+                buf: Arc::clone(buf),
+                drained: Arc::clone(drained),
+                progress: Arc::clone(progress),
+                pacing: Arc::clone(pacing),
+                liveness: Arc::clone(liveness),
+                limits,
+                throttle: limits
+                    .max_ingest_rate
+                    .map(|rate| Arc::new(Mutex::new(Throttle::new(rate, Instant::now())))),
+                compression,
+            };
+            if let Some(channels) = link.channels {
+                tokio::spawn(Self::accept_channels(channels, inbound.clone()));
+            }
+            Self::spawn_reader(link.stream, inbound);
+        }
+
+        Ok(())
+    }
+
+    /// Reads frames from `stream` until the peer is done with it.
+    fn spawn_reader<R: AsyncRead + Send + Unpin + 'static>(stream: R, inbound: Inbound) {
+        let source = inbound.source;
+        tokio::spawn(
+            async move {
+                if let Err(e) = Self::read_frames(stream, &inbound).await {
+                    error!("connection to {source:?} peer is broken: {e}");
+                }
+            }
+            .instrument(tracing::debug_span!("read", ?source)),
+        );
+    }
+
+    /// Reads frames from every channel the peer opens, until it closes the connection.
+    async fn accept_channels(channels: Channels, inbound: Inbound) {
+        loop {
+            match channels.accept().await {
+                Ok(Some(channel)) => Self::spawn_reader(channel, inbound.clone()),
+                Ok(None) => return,
+                Err(e) => {
+                    let source = inbound.source;
+                    error!("connection to {source:?} peer is broken: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Finds out which peer connected from `addr`, and checks that it is that peer. Rejects the
+    /// connection if not, and returns the reason. Returns the peer along with the compression
+    /// picked for what it sends.
+    async fn handshake(
+        link: &mut Link,
+        addr: SocketAddr,
+        peers: &Peers,
+    ) -> io::Result<(HelperAddr, Option<Compression>)> {
+        let stream = &mut link.stream;
+        let source = HelperAddr::try_from(stream.read_u8().await?)?;
+        let hello = Hello::read(stream).await?;
+        let checked = if addr.ip() == peers.addr(source).ip() {
+            Hello::of::<C>(peers.keys.is_some()).check(&hello)
+        } else {
+            Err(format!("{addr} is not the address of the {source:?} peer"))
+        };
+        if let Err(reason) = checked {
+            // the peer may be gone already, this helper ignores it either way
+            let _ = write_rejection(stream, &reason).await;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        }
+
+        if let Some(key) = peers.key(source) {
+            let mut theirs = [0; AUTH_SIZE];
+            stream.read_exact(&mut theirs).await?;
+            let ours = nonce();
+            let tag = auth_mac(key, ACCEPTOR_TAG, &link.binding, source, &ours, &theirs).finalize();
+            let mut challenge = BytesMut::with_capacity(1 + 2 * AUTH_SIZE);
+            challenge.put_u8(HELLO_ACCEPTED);
+            challenge.put_slice(&ours);
+            challenge.put_slice(&tag.into_bytes());
+            stream.write_all(&challenge).await?;
+            stream.flush().await?;
+
+            let mut tag = [0; AUTH_SIZE];
+            stream.read_exact(&mut tag).await?;
+            if auth_mac(key, DIALER_TAG, &link.binding, source, &theirs, &ours)
+                .verify(&tag)
+                .is_err()
+            {
+                let reason = format!("{source:?} peer does not know the key shared with it");
+                let _ = write_rejection(stream, &reason).await;
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+            }
+        }
+
+        Ok((source, hello.pick(&peers.compression)))
+    }
+
+    /// Gives up on messages that waited longer than `ttl`, checking twice per `ttl` for as long
+    /// as the ring is around.
+    async fn expire_messages(
+        buf: Weak<Mutex<MessageBuffer<Bytes>>>,
+        drained: Arc<Notify>,
+        progress: Arc<QueryProgress>,
+        ttl: Duration,
+    ) {
+        let mut interval = tokio::time::interval(ttl / 2);
+        loop {
+            interval.tick().await;
+            let buf = match buf.upgrade() {
+                Some(buf) => buf,
+                None => return,
+            };
+            let mut buf = buf.lock().unwrap();
+            if buf.expire(Instant::now(), ttl) > 0 {
+                update_progress(&buf, &progress);
+                drained.notify_waiters();
+            }
+        }
+    }
+
+    /// Sends a heartbeat to every peer once per `interval`, until the ring is dropped or the
+    /// query fails.
+    async fn send_heartbeats(
+        outboxes: [(HelperAddr, Weak<Outbox>); 2],
+        buf: Weak<Mutex<MessageBuffer<Bytes>>>,
+        interval: Duration,
+    ) {
+        let pool = BufferPool::new(outboxes.len());
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if buf
+                .upgrade()
+                .map_or(true, |buf| buf.lock().unwrap().failure().is_some())
+            {
+                return;
+            }
+            // a peer that does not read blocks its write, but not those to the other peer
+            let writes = outboxes.iter().map(|(peer, outbox)| {
+                let pool = &pool;
+                async move {
+                    let outbox = outbox.upgrade()?;
+                    let frame = encode_control(pool.get(), &ControlMessage::Heartbeat).ok()?;
+                    if let Err(e) = outbox.write(frame, pool).await {
+                        debug!("failed to send a heartbeat to {peer:?} peer: {e}");
+                    }
+                    Some(())
+                }
+            });
+            if futures::future::join_all(writes).await.contains(&None) {
+                return;
+            }
+        }
+    }
+
+    /// Checks once per `interval` whether a peer was silent for longer than `timeout`, and fails
+    /// the query if so. Keeps what it heard from peers in `progress` until then.
+    async fn watch_peers(
+        buf: Weak<Mutex<MessageBuffer<Bytes>>>,
+        liveness: Arc<Mutex<Liveness>>,
+        drained: Arc<Notify>,
+        progress: Arc<QueryProgress>,
+        pacing: Arc<Pacing>,
+        interval: Duration,
+        timeout: Duration,
+    ) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let buf = match buf.upgrade() {
+                Some(buf) => buf,
+                None => return,
+            };
+            let now = Instant::now();
+            let (gone, silence) = {
+                let liveness = liveness.lock().unwrap();
+                let silence = [HelperAddr::Left, HelperAddr::Right]
+                    .map(|peer| (peer, liveness.silence(peer, now)));
+                (liveness.gone(now, timeout), silence)
+            };
+            for (peer, silent) in silence {
+                let silent_ms = u64::try_from(silent.as_millis()).unwrap_or(u64::MAX);
+                progress.set_peer_health(
+                    peer,
+                    PeerHealth {
+                        silent_ms,
+                        gone: gone.map_or(false, |(p, _)| p == peer),
+                    },
+                );
+            }
+            let mut buf = buf.lock().unwrap();
+            if buf.failure().is_some() {
+                return;
+            }
+            if let Some((peer, silent)) = gone {
+                error!("{peer:?} peer is gone, nothing arrived from it for {silent:?}");
+                buf.peer_gone(peer, silent);
+                update_progress(&buf, &progress);
+                drained.notify_waiters();
+                pacing.changed.notify_waiters();
+                return;
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn read_frames<R: AsyncRead + Unpin>(mut stream: R, inbound: &Inbound) -> io::Result<()> {
+        let Inbound {
+            source,
+            buf,
+            drained,
+            progress,
+            pacing,
+            liveness,
+            limits,
+            throttle,
+            compression,
+        } = inbound;
+        let (source, progress) = (*source, &**progress);
+        let mut input = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+        loop {
+            let mut paused = false;
+            loop {
+                // registered before checking, so that a drain in between is not missed
+                let notified = drained.notified();
+                if !buf.lock().unwrap().window_full(source) {
+                    break;
+                }
+                debug!("window of {source:?} peer is full, pausing until messages are received");
+                liveness.lock().unwrap().pause(source);
+                paused = true;
+                notified.await;
+            }
+            if paused {
+                liveness.lock().unwrap().resume(source, Instant::now());
+            }
+            if !read_at_least(&mut stream, &mut input, 4).await? {
+                return Ok(());
+            }
+            let len = input.get_u32_le() as usize;
+            if let Some(limit) = limits.max_message_size.filter(|&limit| len > limit) {
+                buf.lock().unwrap().reject_too_big(source, len, limit);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame of {len} bytes exceeds the limit of {limit} bytes"),
+                ));
+            }
+            if let Some(throttle) = throttle {
+                // the peer is throttled over all of its channels together
+                let delay = throttle.lock().unwrap().delay(Instant::now(), 4 + len);
+                if !delay.is_zero() {
+                    debug!("{source:?} peer sends too fast, pausing for {delay:?}");
+                    liveness.lock().unwrap().pause(source);
+                    tokio::time::sleep(delay).await;
+                    liveness.lock().unwrap().resume(source, Instant::now());
+                }
+            }
+            if !read_at_least(&mut stream, &mut input, len).await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let frame = input.split_to(len).freeze();
+            liveness.lock().unwrap().heard(source, Instant::now());
+            telemetry::bytes_received(source, 4 + len);
+            progress.bytes_received(source, 4 + len);
+            let (&kind, data) = frame.split_first().ok_or_else(bad_frame)?;
+            match kind {
+                DATA_FRAME | COMPRESSED_FRAME => {}
+                CONTROL_FRAME => match ControlMessage::decode(data)? {
+                    Some(ControlMessage::Abort { reason }) => {
+                        warn!("{source:?} peer aborted the query: {reason}");
+                        let mut buf = buf.lock().unwrap();
+                        buf.abort(Some(source), reason);
+                        update_progress(&buf, progress);
+                        drained.notify_waiters();
+                        pacing.changed.notify_waiters();
+                        return Ok(());
+                    }
+                    Some(ControlMessage::Close { name }) => {
+                        debug!("{source:?} peer closed the channel of {name}");
+                        let mut buf = buf.lock().unwrap();
+                        buf.close(source, Bytes::from(name));
+                        update_progress(&buf, progress);
+                        continue;
+                    }
+                    Some(ControlMessage::Progress { position }) => {
+                        pacing.pacer.lock().unwrap().observe(source, position);
+                        pacing.changed.notify_waiters();
+                        continue;
+                    }
+                    // only tells that the peer is there, which any frame does
+                    Some(ControlMessage::Heartbeat) => continue,
+                    None => {
+                        warn!("ignoring control message of unknown type from {source:?} peer");
+                        continue;
+                    }
+                },
+                _ => {
+                    warn!("ignoring frame of unknown kind {kind} from {source:?} peer");
+                    continue;
+                }
+            }
+            let (name, body) = split_frame(data)?;
+            let name = frame.slice_ref(name.as_bytes());
+            let body = if kind == COMPRESSED_FRAME {
+                let (header, size, payload) = split_compressed(body)?;
+                if let Some(limit) = limits.max_message_size.filter(|&limit| size > limit) {
+                    buf.lock().unwrap().reject_too_big(source, size, limit);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("payload of {size} bytes exceeds the limit of {limit} bytes"),
+                    ));
+                }
+                let compression = compression.ok_or_else(|| {
+                    let reason = "compressed frame on a connection without compression";
+                    io::Error::new(io::ErrorKind::InvalidData, reason)
+                })?;
+                let mut decompressed = BytesMut::with_capacity(header.len() + size);
+                decompressed.extend_from_slice(header);
+                compression.decompress(payload, size, &mut decompressed)?;
+                decompressed.freeze()
+            } else {
+                frame.slice_ref(body)
+            };
+
+            // written with the buffer unlocked, so that receives do not wait for the disk
+            let spill = buf.lock().unwrap().spill_to(source, body.len());
+            let spilled = match spill.map(|store| Spilled::write(store, &body)).transpose() {
+                Ok(spilled) => spilled,
+                Err(e) => {
+                    let reason = format!("failed to spill a message: {e}");
+                    buf.lock().unwrap().spill_failed(reason.clone());
+                    let e = Error::from(Failure::Spill(reason));
+                    return Err(io::Error::new(io::ErrorKind::Other, e));
+                }
+            };
+            let put = {
+                let mut buf = buf.lock().unwrap();
+                let put = match spilled {
+                    Some(spilled) => buf.put_spilled(source, name, body, spilled),
+                    None => buf.put(source, name, body),
+                };
+                update_progress(&buf, progress);
+                put
+            };
+            match put {
+                Ok(()) => {}
+                // whoever failed the query has reported why, nothing else to do here
+                Err(
+                    Failure::Aborted { .. }
+                    | Failure::MessageTooBig { .. }
+                    | Failure::PeerGone { .. },
+                ) => return Ok(()),
+                Err(e @ Failure::MemoryLimitExceeded(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::OutOfMemory, Error::from(e)))
+                }
+                Err(e @ Failure::Spill(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, Error::from(e)))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Codec> Ring for TcpRing<C> {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        let span = tracing::debug_span!("send", ?dest, message = T::NAME);
+        let frame =
+            span.in_scope(|| self.make_frame(dest, T::NAME, |out| C::encode_into(&msg, out)))?;
+        self.throttle_send(dest, frame.len()).await;
+        self.write_frame(dest, frame).instrument(span).await
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
+        self.receive_shared(source, 1).await
+    }
+
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, Error> {
+        let body = self.take_body(source, T::NAME, consumers).await?;
+        let (context, payload) = split_context(&body).map_err(|e| Error::ReceiveError {
+            source,
+            inner: e.into(),
+        })?;
+
+        let span = tracing::debug_span!("receive", ?source, message = T::NAME);
+        telemetry::trace::set_remote_parent(&span, context);
+        span.in_scope(|| C::decode(payload))
+            .map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    /// Writes values straight into the frame, skipping the codec and the copy of its output.
+    async fn send_fields<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Result<(), Error> {
+        let span = tracing::debug_span!(
+            "send",
+            ?dest,
+            message = type_name::<[F]>(),
+            values = values.len()
+        );
+        let frame = span.in_scope(|| {
+            self.make_frame(dest, FieldValues::NAME, |out| {
+                write_fields(values, out);
+                Ok(())
+            })
+        })?;
+        self.throttle_send(dest, frame.len()).await;
+        self.write_frame(dest, frame).instrument(span).await
+    }
+
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, Error> {
+        let body = self.take_body(source, FieldValues::NAME, 1).await?;
+        let (context, payload) = split_context(&body).map_err(|e| Error::ReceiveError {
+            source,
+            inner: e.into(),
+        })?;
+
+        let span = tracing::debug_span!("receive", ?source, message = type_name::<[F]>());
+        telemetry::trace::set_remote_parent(&span, context);
+        span.in_scope(|| read_fields(payload))
+            .map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    async fn abort(&self, reason: &str) -> Result<(), Error> {
+        error!("aborting the query: {reason}");
+        {
+            let mut buf = self.buf.lock().unwrap();
+            buf.abort(None, reason.to_owned());
+            update_progress(&buf, &self.progress);
+        }
+        self.drained.notify_waiters();
+        self.pacing.changed.notify_waiters();
+
+        let abort = ControlMessage::Abort {
+            reason: reason.to_owned(),
+        };
+        let left = self.make_control_frame(HelperAddr::Left, &abort)?;
+        let right = self.make_control_frame(HelperAddr::Right, &abort)?;
+        futures::try_join!(
+            self.write_frame(HelperAddr::Left, left),
+            self.write_frame(HelperAddr::Right, right)
+        )?;
+        Ok(())
+    }
+
+    async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), Error> {
+        let close = ControlMessage::Close {
+            name: T::NAME.to_owned(),
+        };
+        let frame = self.make_control_frame(dest, &close)?;
+        self.write_frame(dest, frame).await
+    }
+
+    fn pending(&self) -> Vec<Pending> {
+        self.buf.lock().unwrap().pending(Instant::now())
+    }
+}
+
+impl<C> TcpRing<C> {
+    /// Reason the query failed on this helper, if it did: it was aborted by this helper or one of
+    /// its peers, or ran out of memory.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn failure(&self) -> Option<Failure> {
+        self.buf.lock().unwrap().failure().cloned()
+    }
+
+    /// Messages that arrived to this helper and were given up on, because nobody received them
+    /// in time or the query failed.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.buf.lock().unwrap().dead_letters().to_vec()
+    }
+
+    /// Stores the [`dead_letters`](Self::dead_letters) of this helper in `store` as
+    /// [`DEAD_LETTERS_BLOB`], one per line, so they outlive the query. Does nothing if there are
+    /// none.
+    ///
+    /// ## Errors
+    /// If the blob cannot be written.
+    pub fn persist_dead_letters<R: RngCore + CryptoRng>(
+        &self,
+        store: &BlobStore,
+        rng: &mut R,
+    ) -> Result<(), storage::Error> {
+        let letters = self.dead_letters();
+        if letters.is_empty() {
+            return Ok(());
+        }
+        let report = letters.iter().map(|l| format!("{l}\n")).collect::<String>();
+        store.put(DEAD_LETTERS_BLOB, report.as_bytes(), rng)
+    }
+
+    /// Where this helper and its peers are in the query, as far as they told it with
+    /// [`TcpRing::pace`].
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn pacer(&self) -> Pacer {
+        self.pacing.pacer.lock().unwrap().clone()
+    }
+
+    /// Progress of the query running on this ring. Bytes exchanged with peers and the number of
+    /// messages waiting to be received or given up on are kept up to date by the ring,
+    /// everything else is up to the code that runs the query.
+    #[must_use]
+    pub fn progress(&self) -> Arc<QueryProgress> {
+        Arc::clone(&self.progress)
+    }
+
+    /// Builds a frame for the message called `name` in a buffer taken from the pool.
+    /// `write_payload` appends the message payload to it, which is compressed if the connection
+    /// to `dest` has a compression.
+    fn make_frame<W>(
+        &self,
+        dest: HelperAddr,
+        name: &str,
+        write_payload: W,
+    ) -> Result<BytesMut, Error>
+    where
+        W: FnOnce(&mut BytesMut) -> Result<(), BoxError>,
+    {
+        let mut frame = self.pool.get();
+        let context = telemetry::trace::current_context();
+        let mut payload = 0;
+        start_frame(&mut frame, name, &context)
+            .map_err(BoxError::from)
+            .and_then(|()| {
+                payload = frame.len();
+                write_payload(&mut frame)
+            })
+            .map_err(|inner| Error::SendError { dest, inner })?;
+        if let Some(compression) = self.outbox(dest).compression {
+            frame = self.compress_payload(dest, frame, payload, compression);
+        }
+        finish_frame(&mut frame).map_err(|e| Error::SendError {
+            dest,
+            inner: e.into(),
+        })?;
+
+        Ok(frame)
+    }
+
+    /// Turns `frame`, whose payload starts at `payload`, into a [`COMPRESSED_FRAME`], unless the
+    /// payload is too small to gain from it or does not get any smaller.
+    fn compress_payload(
+        &self,
+        dest: HelperAddr,
+        frame: BytesMut,
+        payload: usize,
+        compression: Compression,
+    ) -> BytesMut {
+        let size = frame.len() - payload;
+        if size < MIN_COMPRESSED_SIZE {
+            return frame;
+        }
+        let size_prefix = match u32::try_from(size) {
+            Ok(size) => size,
+            // fails when the frame is finished
+            Err(_) => return frame,
+        };
+        let mut compressed = self.pool.get();
+        compressed.extend_from_slice(&frame[..payload]);
+        compressed[4] = COMPRESSED_FRAME;
+        compressed.put_u32_le(size_prefix);
+        if let Err(e) = compression.compress(&frame[payload..], &mut compressed) {
+            debug!("sending the payload uncompressed, failed to compress it: {e}");
+            self.pool.put(compressed);
+            return frame;
+        }
+        let compressed_size = compressed.len() - payload - 4;
+        telemetry::compressed(dest, compression.name(), size, compressed_size.min(size));
+        if compressed.len() < frame.len() {
+            self.pool.put(frame);
+            compressed
+        } else {
+            self.pool.put(compressed);
+            frame
+        }
+    }
+
+    fn outbox(&self, dest: HelperAddr) -> &Outbox {
+        match dest {
+            HelperAddr::Left => &self.left,
+            HelperAddr::Right => &self.right,
+        }
+    }
+
+    /// Builds a frame for the control message `msg` in a buffer taken from the pool.
+    fn make_control_frame(
+        &self,
+        dest: HelperAddr,
+        msg: &ControlMessage,
+    ) -> Result<BytesMut, Error> {
+        encode_control(self.pool.get(), msg).map_err(|e| Error::SendError {
+            dest,
+            inner: e.into(),
+        })
+    }
+
+    /// Waits until `bytes` more of data can be sent to `dest` without exceeding the send rate.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    async fn throttle_send(&self, dest: HelperAddr, bytes: usize) {
+        let delay = match &self.send_throttle {
+            Some(throttle) => throttle.lock().unwrap().delay(Instant::now(), bytes),
+            None => return,
+        };
+        if !delay.is_zero() {
+            debug!("sending too fast to {dest:?} peer, pausing for {delay:?}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Writes the frame to the connection with `dest` and returns its buffer to the pool.
+    async fn write_frame(&self, dest: HelperAddr, frame: BytesMut) -> Result<(), Error> {
+        let len = frame.len();
+        self.outbox(dest)
+            .write(frame, &self.pool)
+            .instrument(tracing::trace_span!("write", size = len))
+            .await
+            .map_err(|e| {
+                Error::SendError {
+                    dest,
+                    inner: e.into(),
+                }
+                .with_peer(self.peer_addr(dest))
+            })?;
+        telemetry::bytes_sent(dest, len);
+        self.progress.bytes_sent(dest, len);
+
+        Ok(())
+    }
+
+    /// Waits for the message called `name` to arrive from `source` and returns its frame body.
+    /// The message is kept until all of its `consumers` have taken it. Fails if `source` closed
+    /// the channel instead.
+    async fn take_body(
+        &self,
+        source: HelperAddr,
+        name: &'static str,
+        consumers: usize,
+    ) -> Result<Bytes, Error> {
+        let take = {
+            let mut buf = self.buf.lock().unwrap();
+            let take = buf.take_shared(source, Bytes::from_static(name.as_bytes()), consumers);
+            update_progress(&buf, &self.progress);
+            // message left the buffer, or the buffer failed
+            if !matches!(take, Ok(Take::Wait(_))) {
+                self.drained.notify_waiters();
+            }
+            take?
+        };
+        let body = match take {
+            Take::Ready(body) => Some(body),
+            Take::Closed => None,
+            // read with the buffer unlocked, so that other receives and peers do not wait for it
+            Take::Spilled(spilled) => match spilled.read() {
+                Ok(body) => Some(body),
+                Err(e) => {
+                    let reason = format!("failed to read a spilled message back: {e}");
+                    self.buf.lock().unwrap().spill_failed(reason.clone());
+                    return Err(Failure::Spill(reason).into());
+                }
+            },
+            Take::Wait(rx) => rx.await.map_err(|e| {
+                Error::ReceiveError {
+                    source,
+                    inner: e.into(),
+                }
+                .with_peer(self.peer_addr(source))
+            })??,
+        };
+        body.ok_or(Error::Closed {
+            by: source,
+            message: name,
+        })
+    }
+
+    fn peer_addr(&self, addr: HelperAddr) -> SocketAddr {
+        match addr {
+            HelperAddr::Left => self.left_addr,
+            HelperAddr::Right => self.right_addr,
+        }
+    }
+}
+
+/// Where frames read from a peer go, shared by all readers of the connection from that peer.
+#[derive(Clone)]
+struct Inbound {
+    source: HelperAddr,
+    buf: Arc<Mutex<MessageBuffer<Bytes>>>,
+    drained: Arc<Notify>,
+    progress: Arc<QueryProgress>,
+    pacing: Arc<Pacing>,
+    liveness: Arc<Mutex<Liveness>>,
+    limits: ReceiveLimits,
+    throttle: Option<Arc<Mutex<Throttle>>>,
+    compression: Option<Compression>,
+}
+
+/// Frame waiting to be written to a peer, and where to tell the sender how that went. The
+/// buffer of the frame goes back to the sender along with the result.
+#[derive(Debug)]
+struct Queued {
+    frame: BytesMut,
+    done: oneshot::Sender<(io::Result<()>, BytesMut)>,
+}
+
+/// Connection to a peer that frames are written to, in batches. The connection is owned by a
+/// writer task, so a sender that gives up on its frame never interrupts a write half way through
+/// and leaves the peer with a broken frame. The task stops, and shuts the connection down, once
+/// the outbox is dropped.
+#[derive(Debug)]
+struct Outbox {
+    queue: mpsc::UnboundedSender<Queued>,
+    policy: Arc<Mutex<Box<dyn BatchPolicy>>>,
+    /// What payloads of data messages are compressed with, if anything.
+    compression: Option<Compression>,
+}
+
+impl Outbox {
+    fn new((link, compression): (Link, Option<Compression>)) -> Self {
+        let (queue, frames) = mpsc::unbounded_channel();
+        let policy: Arc<Mutex<Box<dyn BatchPolicy>>> =
+            Arc::new(Mutex::new(Box::new(AdaptiveBatch::default())));
+        let write = Self::write_frames(link.stream, link.channels, frames, Arc::clone(&policy));
+        tokio::spawn(write);
+        Self {
+            queue,
+            policy,
+            compression,
+        }
+    }
+
+    /// Writes the frame, along with others that queued up before it, and returns its buffer to
+    /// the pool. Frames are written in the order they are queued. The frame is written even if
+    /// the returned future is dropped.
+    async fn write(&self, frame: BytesMut, pool: &BufferPool) -> io::Result<()> {
+        let (done, written) = oneshot::channel();
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "connection is closed");
+        self.queue
+            .send(Queued { frame, done })
+            .map_err(|_| closed())?;
+        let (res, frame) = written.await.map_err(|_| closed())?;
+        pool.put(frame);
+        res
+    }
+
+    /// Writes frames as they are queued, as many at once as the policy allows, until the
+    /// outbox is dropped. Frames go to their channel if the connection has channels, see
+    /// [`channel_of`].
+    async fn write_frames(
+        mut stream: Connection,
+        channels: Option<Channels>,
+        mut frames: mpsc::UnboundedReceiver<Queued>,
+        policy: Arc<Mutex<Box<dyn BatchPolicy>>>,
+    ) {
+        let mut batch = BytesMut::new();
+        // channels opened so far, by the name of their messages
+        let mut open = HashMap::new();
+        let mut next = None;
+        loop {
+            let first = match next.take() {
+                Some(first) => first,
+                None => match frames.recv().await {
+                    Some(first) => first,
+                    None => return Self::shut_down(stream, open).await,
+                },
+            };
+            let limit = policy.lock().unwrap().limit();
+            let mut bytes = first.frame.len();
+            let mut taken = vec![first];
+            while let Ok(queued) = frames.try_recv() {
+                if bytes + queued.frame.len() > limit {
+                    next = Some(queued);
+                    break;
+                }
+                bytes += queued.frame.len();
+                taken.push(queued);
+            }
+
+            let start = Instant::now();
+            let written: Vec<_> = taken.iter().map(|queued| &queued.frame).collect();
+            let res = match &channels {
+                None => write_batch(&mut stream, &written, &mut batch).await,
+                Some(channels) => {
+                    let to = (&mut stream, channels, &mut open);
+                    write_channels(to, &written, &mut batch).await
+                }
+            };
+            policy
+                .lock()
+                .unwrap()
+                .observe(bytes, start.elapsed(), next.is_some());
+
+            for queued in taken {
+                // io errors cannot be cloned, every frame of the batch gets one of its own
+                let res = match &res {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                // sender may have given up on the frame
+                let _ = queued.done.send((res, queued.frame));
+            }
+        }
+    }
+
+    /// Tells the peer that no more frames follow, on the connection and on every channel. It may
+    /// be gone, or may not read any more, and has all frames that made it either way.
+    async fn shut_down(mut stream: Connection, open: HashMap<String, ChannelWriter>) {
+        let channels = open.into_values().map(|mut channel| async move {
+            let _ = channel.shutdown().await;
+        });
+        let all = future::join(stream.shutdown(), future::join_all(channels));
+        let _ = tokio::time::timeout(HANDSHAKE_TIMEOUT, all).await;
+    }
+}
+
+/// Writes `frames` to `stream` at once, and flushes it.
+async fn write_batch<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    frames: &[&BytesMut],
+    batch: &mut BytesMut,
+) -> io::Result<()> {
+    if let [single] = frames {
+        stream.write_all(single).await?;
+    } else {
+        batch.clear();
+        for frame in frames {
+            batch.extend_from_slice(frame);
+        }
+        stream.write_all(batch).await?;
+    }
+    stream.flush().await
+}
+
+/// Writes each of `frames` to its channel, opening channels as needed, and the rest to the
+/// connection. Frames of a channel go out at once, in the order of the first frame of each.
+async fn write_channels(
+    (stream, channels, open): (
+        &mut Connection,
+        &Channels,
+        &mut HashMap<String, ChannelWriter>,
+    ),
+    frames: &[&BytesMut],
+    batch: &mut BytesMut,
+) -> io::Result<()> {
+    let mut groups: Vec<(Option<Cow<'_, str>>, Vec<&BytesMut>)> = Vec::new();
+    for &frame in frames {
+        let channel = channel_of(frame)?;
+        match groups.iter_mut().find(|(to, _)| *to == channel) {
+            Some((_, group)) => group.push(frame),
+            None => groups.push((channel, vec![frame])),
+        }
+    }
+    for (channel, frames) in groups {
+        if let Some(name) = channel {
+            if !open.contains_key(&*name) {
+                let writer = channels.open().await?;
+                open.insert(name.clone().into_owned(), writer);
+            }
+            let writer = open.get_mut(&*name).unwrap(); // opened above
+            write_batch(writer, &frames, batch).await?;
+        } else {
+            write_batch(stream, &frames, batch).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the channel `frame` goes on, or `None` if it goes on the connection itself. Data
+/// messages go on the channel of their name, and so does the close of that channel, so that it
+/// arrives after the last message. Everything else goes on the connection.
+fn channel_of(frame: &[u8]) -> io::Result<Option<Cow<'_, str>>> {
+    let body = frame.get(4..).ok_or_else(bad_frame)?; // past the length
+    let (&kind, body) = body.split_first().ok_or_else(bad_frame)?;
+    Ok(match kind {
+        DATA_FRAME | COMPRESSED_FRAME => Some(Cow::Borrowed(split_frame(body)?.0)),
+        CONTROL_FRAME => match ControlMessage::decode(body)? {
+            Some(ControlMessage::Close { name }) => Some(Cow::Owned(name)),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Paces traffic on connections so that it does not exceed `rate` bytes per second on average,
+/// after an initial burst of [`THROTTLE_BURST`] worth of data.
+#[derive(Debug)]
+struct Throttle {
+    rate: u64,
+    /// Time at which everything read so far would have been read at the limited rate.
+    caught_up: Instant,
+}
+
+impl Throttle {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate.max(1),
+            caught_up: now,
+        }
+    }
+
+    /// How long to pause after `bytes` were read or written at `now`.
+    fn delay(&mut self, now: Instant, bytes: usize) -> Duration {
+        let nanos = bytes as u128 * 1_000_000_000 / u128::from(self.rate);
+        let cost = Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+        self.caught_up = self.caught_up.max(now) + cost;
+        self.caught_up
+            .saturating_duration_since(now)
+            .saturating_sub(THROTTLE_BURST)
+    }
+}
+
+/// Answer to a hello that the receiving helper speaks the same protocol as the connecting one.
+const HELLO_ACCEPTED: u8 = 0;
+/// Answer to a hello that the receiving helper does not speak the protocol of the connecting one.
+const HELLO_REJECTED: u8 = 1;
+
+/// Label of the tag the receiving side of a connection authenticates itself with.
+const ACCEPTOR_TAG: &[u8] = b"raw-ipa peer acceptor";
+/// Label of the tag the connecting side of a connection authenticates itself with.
+const DIALER_TAG: &[u8] = b"raw-ipa peer dialer";
+
+/// Addresses of the peers of a helper, the keys it shares with them and the compressions it
+/// offers them.
+struct Peers {
+    left: SocketAddr,
+    right: SocketAddr,
+    keys: Option<PeerKeys>,
+    compression: Vec<Compression>,
+}
+
+impl Peers {
+    fn addr(&self, peer: HelperAddr) -> SocketAddr {
+        match peer {
+            HelperAddr::Left => self.left,
+            HelperAddr::Right => self.right,
+        }
+    }
+
+    fn key(&self, peer: HelperAddr) -> Option<&[u8; AUTH_SIZE]> {
+        self.keys.as_ref().map(|keys| keys.of(peer))
+    }
+}
+
+fn nonce() -> [u8; AUTH_SIZE] {
+    let mut nonce = [0; AUTH_SIZE];
+    thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// MAC of one side of a connection, over the [binding](Link::binding) of the connection, the
+/// position the connecting helper claimed and the nonces of both sides, the one of that side
+/// first.
+fn auth_mac(
+    key: &[u8; AUTH_SIZE],
+    label: &[u8],
+    binding: &[u8],
+    source: HelperAddr,
+    first: &[u8],
+    second: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap(); // any key size works
+    mac.update(label);
+    mac.update(binding);
+    mac.update(&[source.into()]);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+/// What a helper speaks, sent to every peer it connects to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hello {
+    version: u16,
+    codec: String,
+    authenticated: bool,
+    /// Ids of the compressions offered, including those the receiving helper may not know.
+    compressions: Vec<u8>,
+}
+
+impl Hello {
+    /// Hello of this helper, when it encodes messages with codec `C`, and authenticates its peers
+    /// if `authenticated` is set.
+    fn of<C: Codec>(authenticated: bool) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            codec: C::NAME.to_owned(),
+            authenticated,
+            compressions: Vec::new(),
+        }
+    }
+
+    /// Same hello, offering `compressions` in that order of preference.
+    fn offering(mut self, compressions: &[Compression]) -> Self {
+        self.compressions = compressions.iter().map(|c| c.id()).collect();
+        self
+    }
+
+    /// First of the offered compressions that is among `ours`.
+    fn pick(&self, ours: &[Compression]) -> Option<Compression> {
+        self.compressions
+            .iter()
+            .filter_map(|&id| Compression::from_id(id))
+            .find(|compression| ours.contains(compression))
+    }
+
+    /// Whether this helper can talk to a peer that sent `theirs`. If not, returns the reason.
+    fn check(&self, theirs: &Self) -> Result<(), String> {
+        if theirs.version != self.version {
+            return Err(format!(
+                "peer speaks protocol version {}, but this helper speaks version {}",
+                theirs.version, self.version
+            ));
+        }
+        if theirs.codec != self.codec {
+            return Err(format!(
+                "peer encodes messages with {}, but this helper uses {}",
+                theirs.codec, self.codec
+            ));
+        }
+        if theirs.authenticated != self.authenticated {
+            let (peer, this) = if theirs.authenticated {
+                ("does", "does not")
+            } else {
+                ("does not", "does")
+            };
+            return Err(format!("peer {peer} authenticate, but this helper {this}"));
+        }
+        Ok(())
+    }
+
+    async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> io::Result<()> {
+        let codec_len = u8::try_from(self.codec.len()).map_err(too_big)?;
+        let compressions = u8::try_from(self.compressions.len()).map_err(too_big)?;
+        let mut hello = BytesMut::with_capacity(5 + self.codec.len() + self.compressions.len());
+        hello.put_u16_le(self.version);
+        hello.put_u8(codec_len);
+        hello.put_slice(self.codec.as_bytes());
+        hello.put_u8(self.authenticated.into());
+        hello.put_u8(compressions);
+        hello.put_slice(&self.compressions);
+        stream.write_all(&hello).await
+    }
+
+    async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Self> {
+        let version = stream.read_u16_le().await?;
+        let mut codec = vec![0; usize::from(stream.read_u8().await?)];
+        stream.read_exact(&mut codec).await?;
+        let codec = String::from_utf8(codec)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed hello"))?;
+        let authenticated = stream.read_u8().await? != 0;
+        let mut compressions = vec![0; usize::from(stream.read_u8().await?)];
+        stream.read_exact(&mut compressions).await?;
+        Ok(Self {
+            version,
+            codec,
+            authenticated,
+            compressions,
+        })
+    }
+}
+
+/// Reads whether the peer at `addr` accepted what this helper sent it last.
+async fn read_answer<S: AsyncRead + Unpin>(stream: &mut S, addr: SocketAddr) -> io::Result<()> {
+    if stream.read_u8().await? == HELLO_ACCEPTED {
+        return Ok(());
+    }
+    let reason = read_reason(stream).await?;
+    Err(io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!("{addr} rejected the connection: {reason}"),
+    ))
+}
+
+/// Accepts the connection, and tells the peer what it compresses what it sends with.
+async fn write_acceptance<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    compression: Option<Compression>,
+) -> io::Result<()> {
+    stream
+        .write_all(&[HELLO_ACCEPTED, compression.map_or(0, Compression::id)])
+        .await?;
+    stream.flush().await
+}
+
+/// Reads the compression the peer picked out of those offered, see [`write_acceptance`].
+async fn read_compression<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    offered: &[Compression],
+) -> io::Result<Option<Compression>> {
+    match stream.read_u8().await? {
+        0 => Ok(None),
+        id => match Compression::from_id(id).filter(|picked| offered.contains(picked)) {
+            Some(picked) => Ok(Some(picked)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("peer picked compression {id}, which this helper did not offer"),
+            )),
+        },
+    }
+}
+
+async fn write_rejection<S: AsyncWrite + Unpin>(stream: &mut S, reason: &str) -> io::Result<()> {
+    let reason_len = u16::try_from(reason.len()).map_err(too_big)?;
+    let mut rejection = BytesMut::with_capacity(3 + reason.len());
+    rejection.put_u8(HELLO_REJECTED);
+    rejection.put_u16_le(reason_len);
+    rejection.put_slice(reason.as_bytes());
+    stream.write_all(&rejection).await?;
+    stream.flush().await
+}
+
+async fn read_reason<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut reason = vec![0; usize::from(stream.read_u16_le().await?)];
+    stream.read_exact(&mut reason).await?;
+    Ok(String::from_utf8_lossy(&reason).into_owned())
+}
+
+fn too_big<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "message is too big")
+}
+
+/// Writes everything that precedes the payload to `frame`, which must be empty. Frame length is
+/// not known until the payload is written, so it is left blank until [`finish_frame`].
+fn start_frame(frame: &mut BytesMut, name: &str, context: &str) -> io::Result<()> {
+    let name_len = u16::try_from(name.len()).map_err(too_big)?;
+    let context_len = u8::try_from(context.len()).map_err(too_big)?;
+    frame.put_u32_le(0);
+    frame.put_u8(DATA_FRAME);
+    frame.put_u16_le(name_len);
+    frame.put_slice(name.as_bytes());
+    frame.put_u8(context_len);
+    frame.put_slice(context.as_bytes());
+
+    Ok(())
+}
+
+/// Encodes the control message `msg` as a frame into the empty buffer `frame`.
+fn encode_control(mut frame: BytesMut, msg: &ControlMessage) -> io::Result<BytesMut> {
+    frame.put_u32_le(0);
+    frame.put_u8(CONTROL_FRAME);
+    msg.encode(&mut frame)?;
+    finish_frame(&mut frame)?;
+    Ok(frame)
+}
+
+/// Fills in the length of the frame after the payload has been written.
+fn finish_frame(frame: &mut BytesMut) -> io::Result<()> {
+    let frame_len = u32::try_from(frame.len() - 4).map_err(too_big)?;
+    frame[..4].copy_from_slice(&frame_len.to_le_bytes());
+
+    Ok(())
+}
+
+/// Reads from the stream until `input` holds at least `len` bytes. Returns `false` if the peer
+/// closed the connection before sending anything.
+async fn read_at_least<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    input: &mut BytesMut,
+    len: usize,
+) -> io::Result<bool> {
+    while input.len() < len {
+        // reuses the space taken by messages that were received already, if there are no more
+        // references to it
+        input.reserve(len - input.len());
+        if stream.read_buf(input).await? == 0 {
+            return if input.is_empty() {
+                Ok(false)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+    }
+
+    Ok(true)
+}
+
+fn bad_frame() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed frame")
+}
+
+/// Frame as the receiving helper reads it, see [`parse_frame`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Data {
+        name: &'a str,
+        context: &'a str,
+        payload: &'a [u8],
+    },
+    /// Data message whose payload is compressed from `size` bytes.
+    Compressed {
+        name: &'a str,
+        context: &'a str,
+        size: usize,
+        payload: &'a [u8],
+    },
+    /// Control message, or `None` if it is of a type this helper does not know.
+    Control(Option<ControlMessage>),
+    /// Frame of a kind this helper does not know, which it skips.
+    Unknown(u8),
+}
+
+/// Splits a frame, without its length, into its parts the same way the receiving helper does.
+/// Frames come from peers, which makes this a fuzzing target.
+///
+/// ## Errors
+/// If the frame is malformed.
+pub fn parse_frame(frame: &[u8]) -> io::Result<Frame<'_>> {
+    let (&kind, body) = frame.split_first().ok_or_else(bad_frame)?;
+    match kind {
+        DATA_FRAME => {
+            let (name, body) = split_frame(body)?;
+            let (context, payload) = split_context(body)?;
+            Ok(Frame::Data {
+                name,
+                context,
+                payload,
+            })
+        }
+        COMPRESSED_FRAME => {
+            let (name, body) = split_frame(body)?;
+            let (header, size, payload) = split_compressed(body)?;
+            let (context, _) = split_context(header)?;
+            Ok(Frame::Compressed {
+                name,
+                context,
+                size,
+                payload,
+            })
+        }
+        CONTROL_FRAME => Ok(Frame::Control(ControlMessage::decode(body)?)),
+        _ => Ok(Frame::Unknown(kind)),
+    }
+}
+
+fn update_progress(buf: &MessageBuffer<Bytes>, progress: &QueryProgress) {
+    progress.set_buffer_depth(buf.depth());
+    progress.set_dead_letters(buf.stats().dead_letters);
+}
+
+/// Splits the body of a data frame into the message name and the rest of it, which is kept in
+/// the buffer until the message is received.
+fn split_frame(frame: &[u8]) -> io::Result<(&str, &[u8])> {
+    if frame.len() < 2 {
+        return Err(bad_frame());
+    }
+    let (name_len, rest) = frame.split_at(2);
+    let name_len = usize::from(u16::from_le_bytes([name_len[0], name_len[1]]));
+    if rest.len() < name_len {
+        return Err(bad_frame());
+    }
+    let (name, body) = rest.split_at(name_len);
+    let name = std::str::from_utf8(name).map_err(|_| bad_frame())?;
+
+    Ok((name, body))
+}
+
+/// Splits the frame body into trace context and message payload.
+fn split_context(body: &[u8]) -> io::Result<(&str, &[u8])> {
+    let (&context_len, rest) = body.split_first().ok_or_else(bad_frame)?;
+    let context_len = usize::from(context_len);
+    if rest.len() < context_len {
+        return Err(bad_frame());
+    }
+    let (context, payload) = rest.split_at(context_len);
+    let context = std::str::from_utf8(context).map_err(|_| bad_frame())?;
+
+    Ok((context, payload))
+}
+
+/// Splits the body of a compressed data frame into the trace context along with its length,
+/// which is kept as it is, the size of the payload before compression and the compressed payload.
+fn split_compressed(body: &[u8]) -> io::Result<(&[u8], usize, &[u8])> {
+    let context_len = usize::from(*body.first().ok_or_else(bad_frame)?);
+    if body.len() < 1 + context_len + 4 {
+        return Err(bad_frame());
+    }
+    let (header, rest) = body.split_at(1 + context_len);
+    let (size, payload) = rest.split_at(4);
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+
+    Ok((header, size as usize, payload))
+}
+
+impl From<HelperAddr> for u8 {
+    fn from(addr: HelperAddr) -> Self {
+        match addr {
+            HelperAddr::Left => 0,
+            HelperAddr::Right => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for HelperAddr {
+    type Error = io::Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(HelperAddr::Left),
+            1 => Ok(HelperAddr::Right),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{v} is not a valid peer position"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::{Field, Fp31};
+    use crate::helpers::batching::{BatchPolicy, FixedBatch};
+    use crate::helpers::buffer::{Failure, Orphaned};
+    use crate::helpers::codec::{Bincode, Json};
+    use crate::helpers::compression::Compression;
+    use crate::helpers::control::ControlMessage;
+    use crate::helpers::error::Error;
+    use crate::helpers::liveness::HeartbeatConfig;
+    use crate::helpers::pacing::Position;
+    use crate::helpers::ring::{message, FieldValues, HelperAddr, Ring};
+    use crate::helpers::tcp::{
+        finish_frame, parse_frame, read_answer, split_context, split_frame, start_frame, Frame,
+        Hello, PeerKeys, ReceiveLimits, TcpRing, TcpRingConfig, Throttle, AUTH_SIZE,
+        DEAD_LETTERS_BLOB, DEFAULT_MAX_MESSAGE_SIZE, PROTOCOL_VERSION,
+    };
+    use crate::helpers::transport::Listener;
+    #[cfg(feature = "quic")]
+    use crate::helpers::transport::Transport;
+    use crate::query::Stage;
+    use crate::storage::{BlobStore, StorageKey};
+    use bytes::BytesMut;
+    use rand::thread_rng;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+    message! {
+        (u128, u128) => "test.u128-u128",
+        [u8; 16] => "test.u8x16",
+        [u16; 16] => "test.u16x16",
+    }
+
+    async fn make_three() -> [TcpRing; 3] {
+        make_three_with(None, ReceiveLimits::default()).await
+    }
+
+    async fn make_three_with_limit(memory_limit: Option<usize>) -> [TcpRing; 3] {
+        make_three_with(memory_limit, ReceiveLimits::default()).await
+    }
+
+    async fn make_three_with(memory_limit: Option<usize>, limits: ReceiveLimits) -> [TcpRing; 3] {
+        make_three_configured(|_, config| TcpRingConfig {
+            memory_limit,
+            limits,
+            ..config
+        })
+        .await
+    }
+
+    /// Configuration of helper `i` of the three listening on `addrs`, which does not
+    /// authenticate its peers.
+    fn config_of(addrs: [SocketAddr; 3], i: usize) -> TcpRingConfig {
+        TcpRingConfig {
+            allow_unauthenticated: true,
+            ..TcpRingConfig::new(addrs[i], addrs[(i + 2) % 3], addrs[(i + 1) % 3])
+        }
+    }
+
+    /// Connects three helpers, with the configuration of helper `i` from [`config_of`] changed
+    /// by `configure`.
+    async fn make_three_configured<F>(configure: F) -> [TcpRing; 3]
+    where
+        F: Fn(usize, TcpRingConfig) -> TcpRingConfig,
+    {
+        let (listeners, addrs) = bind_three().await;
+        let [l0, l1, l2] = listeners;
+        let connect = |listener: TcpListener, i: usize| {
+            let config = configure(i, config_of(addrs, i));
+            async move { TcpRing::connect(listener, &config).await }
+        };
+        let (h0, h1, h2) =
+            tokio::try_join!(connect(l0, 0), connect(l1, 1), connect(l2, 2)).unwrap();
+
+        [h0, h1, h2]
+    }
+
+    #[test]
+    fn frame() {
+        let mut frame = BytesMut::new();
+        start_frame(&mut frame, "foo", "ctx").unwrap();
+        frame.extend_from_slice(&[1, 2, 3]);
+        finish_frame(&mut frame).unwrap();
+        assert_eq!(&[13, 0, 0, 0, 0], &frame[..5]);
+        let (name, body) = split_frame(&frame[5..]).unwrap();
+        assert_eq!("foo", name);
+        assert_eq!(("ctx", &[1_u8, 2, 3][..]), split_context(body).unwrap());
+        assert_eq!(
+            Frame::Data {
+                name: "foo",
+                context: "ctx",
+                payload: &[1, 2, 3]
+            },
+            parse_frame(&frame[4..]).unwrap()
+        );
+
+        let abort = ControlMessage::Abort {
+            reason: "bye".into(),
+        };
+        let mut frame = vec![1];
+        abort.encode(&mut frame).unwrap();
+        assert_eq!(Frame::Control(Some(abort)), parse_frame(&frame).unwrap());
+        assert_eq!(Frame::Unknown(7), parse_frame(&[7, 1, 2]).unwrap());
+
+        let compressed = [2, 3, 0, b'f', b'o', b'o', 1, b'c', 9, 0, 0, 0, 5, 6];
+        assert_eq!(
+            Frame::Compressed {
+                name: "foo",
+                context: "c",
+                size: 9,
+                payload: &[5, 6]
+            },
+            parse_frame(&compressed).unwrap()
+        );
+        assert!(parse_frame(&compressed[..10]).is_err());
+        assert!(parse_frame(&[]).is_err());
+
+        assert!(split_frame(&[1]).is_err());
+        assert!(split_frame(&[4, 0, b'f']).is_err());
+        assert!(split_context(&[]).is_err());
+        assert!(split_context(&[2, b'c']).is_err());
+    }
+
+    #[tokio::test]
+    async fn send_receive() {
+        let ring = make_three().await;
+
+        ring[0].send(HelperAddr::Right, 7_u32).await.unwrap();
+        ring[0].broadcast((1_u128, 2_u128)).await.unwrap();
+        ring[2].send(HelperAddr::Left, false).await.unwrap();
+
+        assert_eq!(7, ring[1].receive::<u32>(HelperAddr::Left).await.unwrap());
+        assert_eq!(
+            (1, 2),
+            ring[1]
+                .receive::<(u128, u128)>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            (1, 2),
+            ring[2]
+                .receive::<(u128, u128)>(HelperAddr::Right)
+                .await
+                .unwrap()
+        );
+        assert!(!ring[1].receive::<bool>(HelperAddr::Right).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn progress() {
+        let ring = make_three().await;
+        ring[0].send(HelperAddr::Right, 7_u32).await.unwrap();
+        ring[0].send(HelperAddr::Right, 8_u64).await.unwrap();
+        // frames arrive in order, so the first one is waiting in the buffer once this returns
+        assert_eq!(8, ring[1].receive::<u64>(HelperAddr::Left).await.unwrap());
+
+        let sent = ring[0].progress().snapshot("q").right.bytes_sent;
+        assert!(sent > 12, "{sent}");
+        let received = ring[1].progress().snapshot("q");
+        assert_eq!(sent, received.left.bytes_received);
+        assert_eq!(1, received.buffer_depth);
+    }
+
+    #[tokio::test]
+    async fn send_fields() {
+        let ring = make_three().await;
+        let values = (0..100_u128).map(Fp31::from).collect::<Vec<_>>();
+
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        ring[0].send(HelperAddr::Right, 7_u8).await.unwrap();
+
+        assert_eq!(7, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+        assert_eq!(
+            values,
+            ring[1]
+                .receive_fields::<Fp31>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let ring = make_three_with_limit(Some(64)).await;
+
+        // helper 1 does not receive, so messages pile up in its buffer until they don't fit
+        ring[0].send(HelperAddr::Right, [1_u8; 16]).await.unwrap();
+        ring[0]
+            .send(HelperAddr::Right, vec![2_u8; 64])
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let err = ring[1].receive::<[u8; 16]>(HelperAddr::Left).await;
+        assert!(matches!(err, Err(Error::MemoryLimitExceeded(_))));
+
+        // other helpers are not affected
+        ring[1].send(HelperAddr::Right, 3_u8).await.unwrap();
+        assert_eq!(3, ring[2].receive::<u8>(HelperAddr::Left).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn message_size_limit() {
+        let limits = ReceiveLimits {
+            max_message_size: Some(256),
+            ..ReceiveLimits::default()
+        };
+        let ring = make_three_with(None, limits).await;
+
+        let values = (0..100_u128).map(Fp31::from).collect::<Vec<_>>();
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        assert_eq!(
+            values,
+            ring[1]
+                .receive_fields::<Fp31>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+
+        let values = vec![Fp31::from(1_u128); 1000];
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        let err = ring[1].receive_fields::<Fp31>(HelperAddr::Left).await;
+        assert!(matches!(
+            err,
+            Err(Error::MessageTooBig {
+                by: HelperAddr::Left,
+                limit: 256,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn hello_mismatch() {
+        let ours = Hello::of::<Bincode>(false);
+        assert_eq!(Ok(()), ours.check(&Hello::of::<Bincode>(false)));
+        assert!(ours.check(&Hello::of::<Json>(false)).is_err());
+
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::of::<Bincode>(false)
+        };
+        let reason = ours.check(&newer).unwrap_err();
+        assert!(reason.contains("protocol version"), "{reason}");
+
+        let reason = ours.check(&Hello::of::<Bincode>(true)).unwrap_err();
+        assert!(reason.contains("authenticate"), "{reason}");
+    }
+
+    #[tokio::test]
+    async fn codec_mismatch() {
+        let (listeners, addrs) = bind_three().await;
+        let [l0, l1, l2] = listeners;
+        let [c0, c1, c2] = [0, 1, 2].map(|i| config_of(addrs, i));
+
+        // helper 0 speaks JSON, so both of its peers reject it and it fails to connect to them,
+        // while they keep waiting for a helper 0 they can talk to
+        let wait = Duration::from_millis(500);
+        let (h0, h1, h2) = tokio::join!(
+            TcpRing::<Json>::connect(l0, &c0),
+            tokio::time::timeout(wait, TcpRing::<Bincode>::connect(l1, &c1)),
+            tokio::time::timeout(wait, TcpRing::<Bincode>::connect(l2, &c2)),
+        );
+        let err = h0.unwrap_err();
+        assert!(err.to_string().contains("encodes messages with"), "{err}");
+        assert!(!matches!(h1, Ok(Ok(_))) && !matches!(h2, Ok(Ok(_))));
+    }
+
+    async fn bind_three() -> ([TcpListener; 3], [SocketAddr; 3]) {
+        let listeners = [
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let addrs = [0, 1, 2].map(|i| listeners[i].local_addr().unwrap());
+        (listeners, addrs)
+    }
+
+    /// Keys of three helpers, every pair of which shares a key.
+    fn pair_keys() -> [PeerKeys; 3] {
+        let pair = [[1; 32], [2; 32], [3; 32]];
+        [0, 1, 2].map(|i| PeerKeys {
+            left: pair[(i + 2) % 3],
+            right: pair[i],
+        })
+    }
+
+    /// Connects helpers with `keys`, running `stray` alongside. Helpers that do not connect
+    /// within a few seconds fail with [`io::ErrorKind::TimedOut`].
+    async fn connect_keyed<L: Into<Listener>, F: Future<Output = ()>>(
+        listeners: [L; 3],
+        addrs: [SocketAddr; 3],
+        keys: [PeerKeys; 3],
+        stray: F,
+    ) -> [io::Result<TcpRing>; 3] {
+        let [l0, l1, l2] = listeners;
+        let [k0, k1, k2] = keys;
+        let connect = |listener: L, i: usize, keys| async move {
+            let config = TcpRingConfig {
+                keys: Some(keys),
+                ..TcpRingConfig::new(addrs[i], addrs[(i + 2) % 3], addrs[(i + 1) % 3])
+            };
+            let connect = TcpRing::connect(listener, &config);
+            tokio::time::timeout(Duration::from_secs(5), connect)
+                .await
+                .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::TimedOut, e)))
+        };
+        let (h0, h1, h2, ()) = tokio::join!(
+            connect(l0, 0, k0),
+            connect(l1, 1, k1),
+            connect(l2, 2, k2),
+            stray
+        );
+        [h0, h1, h2]
+    }
+
+    #[tokio::test]
+    async fn authenticated() {
+        let (listeners, addrs) = bind_three().await;
+        let ring = connect_keyed(listeners, addrs, pair_keys(), async {})
+            .await
+            .map(Result::unwrap);
+
+        ring[0].send(HelperAddr::Right, 5_u32).await.unwrap();
+        assert_eq!(5, ring[1].receive::<u32>(HelperAddr::Left).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        // helper 1 has the wrong key for helper 2, and they fail to connect either way
+        let (listeners, addrs) = bind_three().await;
+        let mut keys = pair_keys();
+        keys[1].right = [4; 32];
+        let [_, h1, h2] = connect_keyed(listeners, addrs, keys, async {}).await;
+        for err in [h1.unwrap_err(), h2.unwrap_err()] {
+            assert!(err.to_string().contains("does not know the key"), "{err}");
+        }
+
+        // and neither does a stray connection that pretends to be helper 0
+        let (listeners, addrs) = bind_three().await;
+        let impostor = async {
+            let mut stream = TcpStream::connect(addrs[1]).await.unwrap();
+            stream.write_u8(HelperAddr::Left.into()).await.unwrap();
+            Hello::of::<Bincode>(true).write(&mut stream).await.unwrap();
+            stream.write_all(&[0; AUTH_SIZE]).await.unwrap();
+            read_answer(&mut stream, addrs[1]).await.unwrap();
+            let mut challenge = [0; 2 * AUTH_SIZE];
+            stream.read_exact(&mut challenge).await.unwrap();
+            stream.write_all(&[0; AUTH_SIZE]).await.unwrap();
+            let err = read_answer(&mut stream, addrs[1]).await.unwrap_err();
+            assert!(err.to_string().contains("does not know the key"), "{err}");
+        };
+        let ring = connect_keyed(listeners, addrs, pair_keys(), impostor)
+            .await
+            .map(Result::unwrap);
+        ring[0].send(HelperAddr::Right, 5_u32).await.unwrap();
+        assert_eq!(5, ring[1].receive::<u32>(HelperAddr::Left).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn strays() {
+        // connections that say nothing, or do not come from a peer, do not keep peers out
+        let (listeners, addrs) = bind_three().await;
+        let strays = async {
+            let _silent = TcpStream::connect(addrs[1]).await.unwrap();
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.bind(SocketAddr::from(([127, 0, 0, 2], 0))).unwrap();
+            let mut stream = socket.connect(addrs[1]).await.unwrap();
+            stream.write_u8(HelperAddr::Left.into()).await.unwrap();
+            Hello::of::<Bincode>(true).write(&mut stream).await.unwrap();
+            let err = read_answer(&mut stream, addrs[1]).await.unwrap_err();
+            assert!(err.to_string().contains("is not the address"), "{err}");
+        };
+        let ring = connect_keyed(listeners, addrs, pair_keys(), strays)
+            .await
+            .map(Result::unwrap);
+        ring[2].send(HelperAddr::Left, 6_u32).await.unwrap();
+        assert_eq!(6, ring[1].receive::<u32>(HelperAddr::Right).await.unwrap());
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket() {
+        let (listeners, addrs) = bind_three().await;
+        let ring = connect_keyed(
+            listeners.map(Listener::WebSocket),
+            addrs,
+            pair_keys(),
+            async {},
+        )
+        .await
+        .map(Result::unwrap);
+        exchange(&ring).await;
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn quic() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(Transport::Quic.bind(addr).await.unwrap());
+        }
+        let addrs = [0, 1, 2].map(|i| listeners[i].local_addr().unwrap());
+        let listeners: [Listener; 3] = listeners.try_into().unwrap();
+        let ring = connect_keyed(listeners, addrs, pair_keys(), async {})
+            .await
+            .map(Result::unwrap);
+        exchange(&ring).await;
+
+        // a message that takes many packets, and one on a channel of its own after it
+        let large = vec![Fp31::from(1_u128); 200_000];
+        ring[0]
+            .send_fields(HelperAddr::Right, &large)
+            .await
+            .unwrap();
+        ring[0].send(HelperAddr::Right, 9_u16).await.unwrap();
+        assert_eq!(9, ring[1].receive::<u16>(HelperAddr::Left).await.unwrap());
+        assert_eq!(
+            large,
+            ring[1]
+                .receive_fields::<Fp31>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+    }
+
+    /// Sends data, fields and a close between helpers of `ring`, over whatever transport.
+    #[cfg(any(feature = "websocket", feature = "quic"))]
+    async fn exchange(ring: &[TcpRing; 3]) {
+        let values = (0..100_u128).map(Fp31::from).collect::<Vec<_>>();
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        ring[0].send(HelperAddr::Right, 7_u8).await.unwrap();
+        ring[0].close::<u8>(HelperAddr::Right).await.unwrap();
+        ring[2].send(HelperAddr::Left, 8_u32).await.unwrap();
+
+        assert_eq!(7, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+        assert_eq!(
+            values,
+            ring[1]
+                .receive_fields::<Fp31>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+        assert!(matches!(
+            ring[1].receive::<u8>(HelperAddr::Left).await,
+            Err(Error::Closed { .. })
+        ));
+        assert_eq!(8, ring[1].receive::<u32>(HelperAddr::Right).await.unwrap());
+    }
+
+    /// Connects helpers that offer `compression`, and hold their peers to `limits`.
+    async fn make_three_compressed(
+        compression: [&[Compression]; 3],
+        limits: ReceiveLimits,
+    ) -> [TcpRing; 3] {
+        make_three_configured(|i, config| TcpRingConfig {
+            limits,
+            compression: compression[i].to_vec(),
+            ..config
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        // helper 2 does not compress, so only what helpers 0 and 1 send each other is compressed
+        let lz4: &[Compression] = &[Compression::Lz4];
+        let ring = make_three_compressed([lz4, lz4, &[]], ReceiveLimits::default()).await;
+
+        let values = vec![Fp31::from(1_u128); 1000];
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        ring[0].send(HelperAddr::Right, 7_u8).await.unwrap();
+        ring[1]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+
+        assert_eq!(7, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+        for receiver in &ring[1..] {
+            assert_eq!(
+                values,
+                receiver
+                    .receive_fields::<Fp31>(HelperAddr::Left)
+                    .await
+                    .unwrap()
+            );
+        }
+        let compressed = ring[1].progress().snapshot("q").left.bytes_received;
+        let uncompressed = ring[2].progress().snapshot("q").left.bytes_received;
+        assert!(
+            compressed * 10 < uncompressed,
+            "{compressed} {uncompressed}"
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_size_limit() {
+        // payloads are held to the limit as they were before compression
+        let limits = ReceiveLimits {
+            max_message_size: Some(256),
+            ..ReceiveLimits::default()
+        };
+        let ring = make_three_compressed([&[Compression::Lz4]; 3], limits).await;
+
+        let values = vec![Fp31::from(1_u128); 1000];
+        ring[0]
+            .send_fields(HelperAddr::Right, &values)
+            .await
+            .unwrap();
+        let err = ring[1].receive_fields::<Fp31>(HelperAddr::Left).await;
+        assert!(matches!(
+            err,
+            Err(Error::MessageTooBig {
+                by: HelperAddr::Left,
+                limit: 256,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn hello_compression() {
+        let lz4 = [Compression::Lz4];
+        let hello = Hello::of::<Bincode>(false).offering(&lz4);
+        assert_eq!(Some(Compression::Lz4), hello.pick(&lz4));
+        assert_eq!(None, hello.pick(&[]));
+        assert_eq!(None, Hello::of::<Bincode>(false).pick(&lz4));
+
+        // compressions this helper does not know are passed over
+        let newer = Hello {
+            compressions: vec![99, Compression::Lz4.id()],
+            ..Hello::of::<Bincode>(false)
+        };
+        assert_eq!(Some(Compression::Lz4), newer.pick(&lz4));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_config() {
+        let (_, addrs) = bind_three().await;
+        let config = TcpRingConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)), addrs[0], addrs[1]);
+        let err = TcpRing::<Bincode>::connect(config.bind().await.unwrap(), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn default_limits() {
+        let limits: ReceiveLimits = serde_json::from_str("{}").unwrap();
+        assert_eq!(Some(DEFAULT_MAX_MESSAGE_SIZE), limits.max_message_size);
+        assert_eq!(
+            Some(DEFAULT_MAX_MESSAGE_SIZE),
+            ReceiveLimits::default().max_message_size
+        );
+
+        // turned off only when asked to
+        let limits: ReceiveLimits = serde_json::from_str(r#"{"max_message_size":null}"#).unwrap();
+        assert_eq!(None, limits.max_message_size);
+    }
+
+    #[test]
+    fn throttle() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(1000, start);
+
+        // a second worth of data goes through at once
+        assert_eq!(Duration::ZERO, throttle.delay(start, 600));
+        assert_eq!(Duration::ZERO, throttle.delay(start, 400));
+        assert_eq!(Duration::from_millis(500), throttle.delay(start, 500));
+
+        // budget recovers over time
+        let later = start + Duration::from_secs(3);
+        assert_eq!(Duration::ZERO, throttle.delay(later, 1000));
+        assert_eq!(Duration::from_millis(1), throttle.delay(later, 1));
+    }
+
+    #[tokio::test]
+    async fn abort() {
+        let ring = make_three().await;
+
+        ring[1].send(HelperAddr::Left, 1_u8).await.unwrap();
+        ring[2].abort("out of disk space").await.unwrap();
+
+        // abort travels on its own connection, so it may arrive after the message from helper 1
+        while ring[0].failure().is_none() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // helper 0 has helper 2 on the left, helper 1 has it on the right
+        let err = ring[0].receive::<u8>(HelperAddr::Right).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Aborted {
+                by: Some(HelperAddr::Left),
+                ..
+            }
+        ));
+        assert_eq!(
+            "query was aborted by Left peer: out of disk space",
+            err.to_string()
+        );
+        assert!(ring[1].receive::<u8>(HelperAddr::Left).await.is_err());
+        assert!(matches!(
+            ring[2].failure(),
+            Some(Failure::Aborted { by: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let ring = make_three().await;
+
+        ring[0].send(HelperAddr::Right, 1_u8).await.unwrap();
+        ring[0].close::<u8>(HelperAddr::Right).await.unwrap();
+        ring[0]
+            .send_fields(HelperAddr::Right, &[Fp31::ONE])
+            .await
+            .unwrap();
+        ring[0]
+            .close::<FieldValues>(HelperAddr::Right)
+            .await
+            .unwrap();
+
+        // close arrives on the same connection as the messages before it, and after them
+        assert_eq!(1, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+        let err = ring[1].receive::<u8>(HelperAddr::Left).await.unwrap_err();
+        assert_eq!("Left peer closed the channel of u8", err.to_string());
+        assert_eq!(
+            vec![Fp31::ONE],
+            ring[1]
+                .receive_fields::<Fp31>(HelperAddr::Left)
+                .await
+                .unwrap()
+        );
+        assert!(matches!(
+            ring[1].receive_fields::<Fp31>(HelperAddr::Left).await,
+            Err(Error::Closed {
+                by: HelperAddr::Left,
+                ..
+            })
+        ));
+        assert!(ring[1].pending().is_empty());
+        assert_eq!(0, ring[1].progress().snapshot("q").buffer_depth);
+    }
+
+    #[tokio::test]
+    async fn dead_letters() {
+        let limits = ReceiveLimits {
+            message_ttl_ms: Some(20),
+            ..ReceiveLimits::default()
+        };
+        let ring = make_three_with(None, limits).await;
+
+        // helper 1 never receives this one
+        ring[0].send(HelperAddr::Right, 1_u8).await.unwrap();
+        while ring[1].dead_letters().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let letters = ring[1].dead_letters();
+        assert_eq!(1, letters.len());
+        assert_eq!(HelperAddr::Left, letters[0].source);
+        assert!(letters[0].waited > Duration::from_millis(20));
+        assert_eq!(
+            Orphaned::Expired {
+                ttl: Duration::from_millis(20)
+            },
+            letters[0].reason
+        );
+        let progress = ring[1].progress().snapshot("q");
+        assert_eq!((0, 1), (progress.buffer_depth, progress.dead_letters));
+
+        // messages that are received in time are not affected
+        ring[0].send(HelperAddr::Right, 2_u16).await.unwrap();
+        assert_eq!(2, ring[1].receive::<u16>(HelperAddr::Left).await.unwrap());
+        assert_eq!(1, ring[1].dead_letters().len());
+
+        let root =
+            std::env::temp_dir().join(format!("raw-ipa-dead-letters-{}", std::process::id()));
+        let mut rng = thread_rng();
+        let store = BlobStore::create(&root, "q", &StorageKey::new(&mut rng), &mut rng).unwrap();
+        ring[1].persist_dead_letters(&store, &mut rng).unwrap();
+        let report = String::from_utf8(store.get(DEAD_LETTERS_BLOB).unwrap()).unwrap();
+        assert_eq!(format!("{}\n", letters[0]), report);
+        store.destroy().unwrap();
+    }
+
+    /// Waits until `count` messages arrived to `ring` and wait to be received.
+    async fn wait_for_depth(ring: &TcpRing, count: u64) {
+        while ring.progress().snapshot("q").buffer_depth < count {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn spill() {
+        let limits = ReceiveLimits {
+            window: Some(8),
+            ..ReceiveLimits::default()
+        };
+        // every ring spills to a store of its own in there, which removes itself once the ring
+        // is gone
+        let dir = std::env::temp_dir().join(format!("raw-ipa-spill-{}", std::process::id()));
+        let ring = make_three_configured(|_, config| TcpRingConfig {
+            limits,
+            spill_dir: Some(dir.clone()),
+            ..config
+        })
+        .await;
+        let spilled = || {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|store| std::fs::read_dir(store.unwrap().path()).unwrap().count())
+                .sum::<usize>()
+        };
+
+        ring[0].send(HelperAddr::Right, 1_u8).await.unwrap();
+        ring[0].send(HelperAddr::Right, [2_u8; 16]).await.unwrap();
+        ring[0].send(HelperAddr::Right, [3_u16; 16]).await.unwrap();
+        wait_for_depth(&ring[1], 3).await;
+        // only the first one fits into the window
+        assert_eq!(2, spilled());
+
+        let left = HelperAddr::Left;
+        assert_eq!([3; 16], ring[1].receive::<[u16; 16]>(left).await.unwrap());
+        assert_eq!([2; 16], ring[1].receive::<[u8; 16]>(left).await.unwrap());
+        assert_eq!(1, ring[1].receive::<u8>(left).await.unwrap());
+        assert_eq!(0, spilled());
+        drop(ring);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_window() {
+        let limits = ReceiveLimits {
+            window: Some(2),
+            ..ReceiveLimits::default()
+        };
+        let ring = make_three_with(None, limits).await;
+
+        ring[0].send(HelperAddr::Right, 1_u8).await.unwrap();
+        ring[0].send(HelperAddr::Right, 2_u16).await.unwrap();
+        wait_for_depth(&ring[1], 1).await;
+        // second message stays with the peer until the first one is received
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(1, ring[1].progress().snapshot("q").buffer_depth);
+
+        assert_eq!(1, ring[1].receive::<u8>(HelperAddr::Left).await.unwrap());
+        assert_eq!(2, ring[1].receive::<u16>(HelperAddr::Left).await.unwrap());
+    }
+
+    /// Writes every frame that is queued at once, and remembers how many bytes every write
+    /// carried.
+    #[derive(Debug, Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<usize>>>);
+
+    impl BatchPolicy for Recorder {
+        fn limit(&self) -> usize {
+            usize::MAX
+        }
+
+        fn observe(&mut self, bytes: usize, _elapsed: Duration, backlog: bool) {
+            assert!(!backlog);
+            self.0.lock().unwrap().push(bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let recorder = Recorder::default();
+        let [r0, r1, _r2] = make_three().await;
+        let r0 = r0.with_batch_policy(|| recorder.clone());
+        let r1 = r1.with_batch_policy(|| FixedBatch(0));
+
+        futures::future::try_join_all((0..50_u32).map(|v| r0.send(HelperAddr::Right, v)))
+            .await
+            .unwrap();
+        futures::future::try_join_all((0..50_u32).map(|v| r1.send(HelperAddr::Right, v)))
+            .await
+            .unwrap();
+        // frames of a batch arrive in the order they were sent
+        for v in 0..50_u32 {
+            assert_eq!(v, r1.receive::<u32>(HelperAddr::Left).await.unwrap());
+        }
+
+        let writes = recorder.0.lock().unwrap().clone();
+        assert!(writes.len() <= 50, "{writes:?}");
+        assert_eq!(
+            r0.progress().snapshot("q").right.bytes_sent,
+            writes.iter().sum::<usize>() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_send() {
+        let [r0, r1, _r2] = make_three().await;
+        let big = vec![7_u8; 8 << 20];
+        // sender gives up while the frame is being written, which goes on without it
+        let send = r0.send(HelperAddr::Right, big.clone());
+        assert!(tokio::time::timeout(Duration::ZERO, send).await.is_err());
+        r0.send(HelperAddr::Right, 1_u32).await.unwrap();
+
+        assert_eq!(big, r1.receive::<Vec<u8>>(HelperAddr::Left).await.unwrap());
+        assert_eq!(1, r1.receive::<u32>(HelperAddr::Left).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn pace() {
+        let [r0, r1, r2] = make_three().await.map(|r| r.with_max_lead(Some(10)));
+        futures::try_join!(
+            r0.pace(Stage::Sort, 10),
+            r1.pace(Stage::Sort, 10),
+            r2.pace(Stage::Sort, 10),
+        )
+        .unwrap();
+
+        // first helper gets ahead of the others, until both of them catch up
+        let ahead = r0.pace(Stage::Sort, 30);
+        tokio::pin!(ahead);
+        let wait = Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, &mut ahead).await.is_err());
+        r1.pace(Stage::Sort, 20).await.unwrap();
+        assert!(tokio::time::timeout(wait, &mut ahead).await.is_err());
+        r2.pace(Stage::Sort, 20).await.unwrap();
+        ahead.await.unwrap();
+        let pacer = r0.pacer();
+        assert_eq!(
+            Position::new(Stage::Sort, 20),
+            pacer.position(Some(HelperAddr::Left))
+        );
+        assert_eq!(10, pacer.lead());
+
+        // a helper that waits fails with the query
+        let ahead = r1.pace(Stage::Aggregation, 0);
+        let (waited, aborted) = tokio::join!(ahead, r2.abort("stop"));
+        aborted.unwrap();
+        assert!(matches!(waited, Err(Error::Aborted { .. })));
+    }
+
+    #[tokio::test]
+    async fn heartbeat() {
+        let config = HeartbeatConfig {
+            interval_ms: 10,
+            timeout_ms: 200,
+        };
+        let [r0, r1, r2] = make_three().await.map(|r| r.with_heartbeat(Some(config)));
+        // no query traffic for longer than the timeout, but peers are still there
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(r0.failure().is_none());
+        let health = r0.progress().snapshot("q").left_peer;
+        assert!(!health.gone && health.silent_ms < 200, "{health:?}");
+
+        // helper on the left goes away while the first one waits for its message
+        drop(r2);
+        let started = Instant::now();
+        let waited = r0.receive::<u8>(HelperAddr::Left).await;
+        assert!(
+            matches!(
+                waited,
+                Err(Error::PeerGone {
+                    peer: HelperAddr::Left,
+                    ..
+                })
+            ),
+            "{waited:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(r0.progress().snapshot("q").left_peer.gone);
+        assert!(matches!(r1.failure(), Some(Failure::PeerGone { .. })));
+    }
+
+    #[tokio::test]
+    async fn send_rate() {
+        let [r0, r1, r2] = make_three().await;
+        let r0 = r0.with_send_rate(Some(1000));
+        let values = [Fp31::ONE; 600];
+
+        // a second worth of data goes out at once
+        let start = Instant::now();
+        r0.send_fields(HelperAddr::Right, &values).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        // both peers share the rate
+        r0.send_fields(HelperAddr::Left, &values).await.unwrap();
+        let throttled = start.elapsed();
+        assert!(throttled >= Duration::from_millis(200), "{throttled:?}");
+
+        // control messages are not held back
+        let start = Instant::now();
+        r0.close::<FieldValues>(HelperAddr::Right).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let received = r1.receive_fields::<Fp31>(HelperAddr::Left).await.unwrap();
+        assert_eq!(&values[..], &received);
+        let received = r2.receive_fields::<Fp31>(HelperAddr::Right).await.unwrap();
+        assert_eq!(&values[..], &received);
+    }
+}
@@ -0,0 +1,256 @@
+//!
+//! Transports that connections between helpers in a [`TcpRing`] run over. Whatever the transport,
+//! the ring writes the same handshake and frames to a connection and reads them back the same
+//! way, so peers are authenticated and held to the same limits on every transport.
+//!
+//! [`Transport::Tcp`] carries connections on plain TCP. [`Transport::WebSocket`] carries them in
+//! binary WebSocket messages over TCP (see [`crate::helpers::websocket`]), for deployments that
+//! only let HTTP traffic through between helpers. Messages arrive in the order they were sent on
+//! both.
+//!
+//! [`Transport::Quic`] carries connections on QUIC (see [`crate::helpers::quic`]), which has
+//! [channels](Channels): the handshake and control messages go on a stream of their own, and
+//! messages of every name go on a stream of their own as well, along with the close of their
+//! channel. Messages of a name arrive in the order they were sent, while a message that is
+//! held up does not hold up messages of other names, the way it does on a single TCP connection.
+//!
+//! [`TcpRing`]: crate::helpers::tcp::TcpRing
+//!
+#[cfg(feature = "quic")]
+use crate::helpers::quic::{self, QuicStream};
+#[cfg(feature = "websocket")]
+use crate::helpers::websocket;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How helpers in a ring connect to each other. All helpers of a ring must use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "enable-serde", serde(rename_all = "lowercase"))]
+pub enum Transport {
+    #[default]
+    Tcp,
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+/// Byte stream between two helpers.
+pub trait Duplex: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Duplex for T {}
+
+/// Connection between two helpers, over any transport.
+pub type Connection = Box<dyn Duplex>;
+
+/// Channel that frames are written to, see [`Channels`].
+pub type ChannelWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Channel that frames are read from, see [`Channels`].
+pub type ChannelReader = Box<dyn AsyncRead + Send + Unpin>;
+
+impl Transport {
+    /// Starts listening for connections from peers on `addr`.
+    ///
+    /// ## Errors
+    /// If it fails to bind to `addr`.
+    pub async fn bind(self, addr: SocketAddr) -> io::Result<Listener> {
+        Ok(match self {
+            Self::Tcp => Listener::Tcp(TcpListener::bind(addr).await?),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket => Listener::WebSocket(TcpListener::bind(addr).await?),
+            #[cfg(feature = "quic")]
+            Self::Quic => Listener::Quic(quic::endpoint(addr)?),
+        })
+    }
+}
+
+/// Where a helper accepts connections from its peers, over the transport of the variant.
+/// Connections to peers start from there as well.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpListener),
+    #[cfg(feature = "quic")]
+    Quic(quinn::Endpoint),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl Listener {
+    #[must_use]
+    pub fn transport(&self) -> Transport {
+        match self {
+            Self::Tcp(_) => Transport::Tcp,
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(_) => Transport::WebSocket,
+            #[cfg(feature = "quic")]
+            Self::Quic(_) => Transport::Quic,
+        }
+    }
+
+    /// ## Errors
+    /// If the address cannot be determined.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(listener) => listener.local_addr(),
+            #[cfg(feature = "quic")]
+            Self::Quic(endpoint) => endpoint.local_addr(),
+        }
+    }
+
+    /// Connects to the peer listening on `addr`.
+    pub(crate) async fn dial(&self, addr: SocketAddr) -> io::Result<Link> {
+        let stream: Connection = match self {
+            Self::Tcp(_) => Box::new(dial_tcp(addr).await?),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(_) => Box::new(websocket::connect(dial_tcp(addr).await?, addr).await?),
+            #[cfg(feature = "quic")]
+            Self::Quic(endpoint) => {
+                let connection = quic::connect(endpoint, addr).await?;
+                let stream = QuicStream::new(connection.open_bi().await?);
+                return Link::over_quic(stream, connection);
+            }
+        };
+        Ok(Link::new(stream))
+    }
+
+    /// Waits for the next connection and returns it along with the address it came from. The
+    /// connection is [opened](Incoming::open) separately, so that one which is slow to open does
+    /// not hold up the next.
+    pub(crate) async fn accept(&self) -> io::Result<(Incoming, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::Tcp(stream), addr))
+            }
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::WebSocket(stream), addr))
+            }
+            #[cfg(feature = "quic")]
+            Self::Quic(endpoint) => {
+                let connecting = endpoint
+                    .accept()
+                    .await
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "closed"))?;
+                let addr = connecting.remote_address();
+                Ok((Incoming::Quic(connecting), addr))
+            }
+        }
+    }
+}
+
+async fn dial_tcp(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Connection that a peer started, but which is not ready to carry anything yet.
+pub(crate) enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpStream),
+    #[cfg(feature = "quic")]
+    Quic(quinn::Connecting),
+}
+
+impl Incoming {
+    /// Does what the transport needs before the connection can carry the handshake.
+    pub(crate) async fn open(self) -> io::Result<Link> {
+        let stream: Connection = match self {
+            Self::Tcp(stream) => {
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(stream) => {
+                stream.set_nodelay(true)?;
+                Box::new(websocket::accept(stream).await?)
+            }
+            #[cfg(feature = "quic")]
+            Self::Quic(connecting) => {
+                let connection = connecting.await?;
+                let stream = QuicStream::new(connection.accept_bi().await?);
+                return Link::over_quic(stream, connection);
+            }
+        };
+        Ok(Link::new(stream))
+    }
+}
+
+/// Connection to a peer, with what the transport offers besides the stream of bytes.
+pub(crate) struct Link {
+    /// Carries the handshake, and then all frames if there are no `channels`.
+    pub(crate) stream: Connection,
+    /// Secret that only this helper and the peer know, which authentication is bound to. Empty
+    /// if the transport has none.
+    pub(crate) binding: Vec<u8>,
+    pub(crate) channels: Option<Channels>,
+}
+
+impl Link {
+    fn new(stream: Connection) -> Self {
+        Self {
+            stream,
+            binding: Vec::new(),
+            channels: None,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    fn over_quic(stream: QuicStream, connection: quinn::Connection) -> io::Result<Self> {
+        Ok(Self {
+            stream: Box::new(stream),
+            binding: quic::binding(&connection)?,
+            channels: Some(Channels::Quic(connection)),
+        })
+    }
+}
+
+/// Streams of a connection besides the one the handshake goes on, one per channel of messages.
+/// The helper that opened the connection opens channels, and the other one accepts them.
+#[derive(Debug)]
+pub(crate) enum Channels {
+    #[cfg(feature = "quic")]
+    Quic(quinn::Connection),
+}
+
+impl Channels {
+    /// Opens a channel to write frames to.
+    pub(crate) async fn open(&self) -> io::Result<ChannelWriter> {
+        match *self {
+            #[cfg(feature = "quic")]
+            Self::Quic(ref connection) => Ok(Box::new(connection.open_uni().await?)),
+        }
+    }
+
+    /// Waits for the next channel the peer opens, or returns `None` once the peer is done.
+    pub(crate) async fn accept(&self) -> io::Result<Option<ChannelReader>> {
+        match *self {
+            #[cfg(feature = "quic")]
+            Self::Quic(ref connection) => match connection.accept_uni().await {
+                Ok(stream) => Ok(Some(Box::new(stream))),
+                Err(
+                    quinn::ConnectionError::ApplicationClosed(_)
+                    | quinn::ConnectionError::LocallyClosed,
+                ) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+}
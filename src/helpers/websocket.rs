@@ -0,0 +1,197 @@
+//!
+//! Byte streams over WebSocket connections, which the [`WebSocket`] transport carries
+//! connections between helpers on. Bytes written to a [`WsStream`] go out as a binary message per
+//! write, and bytes read from it are the contents of the binary messages the other side sent, in
+//! order. A close message reads as the end of the stream, and messages of other types are
+//! skipped, so the stream carries the same bytes as a TCP connection would.
+//!
+//! Helpers upgrade connections to their peers at [`PATH`]. Requests for anything else are turned
+//! away before the upgrade.
+//!
+//! [`WebSocket`]: crate::helpers::transport::Transport::WebSocket
+//!
+use bytes::{Buf, Bytes};
+use futures::{ready, SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Path helpers upgrade connections to their peers at.
+pub const PATH: &str = "/raw-ipa/ring";
+
+/// WebSocket connection read and written as a stream of bytes.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    /// Rest of the binary message read last.
+    input: Bytes,
+}
+
+/// Upgrades `stream`, a connection to the peer at `addr`, to a WebSocket connection.
+///
+/// ## Errors
+/// If the peer does not upgrade the connection.
+pub async fn connect<S>(stream: S, addr: SocketAddr) -> io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let url = format!("ws://{addr}{PATH}");
+    let (ws, _) = tokio_tungstenite::client_async_with_config(url, stream, Some(config()))
+        .await
+        .map_err(into_io)?;
+    Ok(WsStream::new(ws))
+}
+
+/// Upgrades `stream`, a connection from a peer, to a WebSocket connection.
+///
+/// ## Errors
+/// If the peer does not ask for an upgrade at [`PATH`].
+pub async fn accept<S>(stream: S) -> io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(stream, check_path, Some(config()))
+        .await
+        .map_err(into_io)?;
+    Ok(WsStream::new(ws))
+}
+
+#[allow(clippy::result_large_err)] // as tungstenite calls it
+fn check_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() == PATH {
+        Ok(response)
+    } else {
+        let mut response = ErrorResponse::new(None);
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        Err(response)
+    }
+}
+
+/// Frames carried in messages are limited by the ring, see
+/// [`ReceiveLimits`](crate::helpers::tcp::ReceiveLimits), and messages may hold any number of
+/// them, so messages are not limited here.
+fn config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: None,
+        max_frame_size: None,
+        ..WebSocketConfig::default()
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl<S> WsStream<S> {
+    fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            input: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.input.is_empty() {
+            match ready!(self.ws.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => self.input = Bytes::from(data),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // pings are answered by the connection itself, and nothing else is for the ring
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            }
+        }
+        let len = self.input.len().min(buf.remaining());
+        buf.put_slice(&self.input[..len]);
+        self.input.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    /// Queues `buf` as a binary message, which goes out on the next flush at the latest.
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.ws.poll_ready_unpin(cx)).map_err(into_io)?;
+        self.ws
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(into_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.ws.poll_flush_unpin(cx).map_err(into_io)
+    }
+
+    /// Sends a close message, which the other side reads as the end of the stream.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.ws.poll_close_unpin(cx).map_err(into_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::websocket::{accept, connect};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept(stream).await.unwrap();
+            let mut input = [0; 3];
+            ws.read_exact(&mut input).await.unwrap();
+            assert_eq!([1, 2, 3], input);
+            let mut rest = Vec::new();
+            ws.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(vec![4, 5], rest);
+        };
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut ws = connect(stream, addr).await.unwrap();
+            // messages do not line up with reads on the other side
+            ws.write_all(&[1, 2]).await.unwrap();
+            ws.write_all(&[3, 4, 5]).await.unwrap();
+            ws.flush().await.unwrap();
+            ws.shutdown().await.unwrap();
+        };
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn wrong_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(accept(stream).await.is_err());
+        };
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{addr}/elsewhere");
+            assert!(tokio_tungstenite::client_async(url, stream).await.is_err());
+        };
+        tokio::join!(server, client);
+    }
+}
@@ -0,0 +1,724 @@
+//!
+//! Conversion of the match keys of incoming reports into the shares the protocol starts from.
+//! Reports carry match keys as XOR shares of 40 bits, and every helper holds two of the three
+//! parts, the same way it does for shares in a field. The protocol needs shares of every bit in
+//! the field instead, which modulus conversion computes as `x1 ^ x2 ^ x3` for every bit, from
+//! three sharings that each hold one of the parts.
+//!
+//! A helper can build those sharings without talking to anyone: the part it shares with the
+//! helper on its left is the left half of one of them, the part it shares with the helper on its
+//! right the right half of another, and the third one is zero. This only works if both helpers
+//! that hold a part have the same one, so [`Ingest`] commits to the parts of all reports and
+//! helpers check the [`commitment`](crate::commitment) before the conversion. Reports that one
+//! helper is missing, or that have a different number of match keys at different helpers, fail
+//! that check too.
+//!
+//! A report that is malformed at one helper only, because its part of the shares was damaged on
+//! the way, would fail the query for all of them. Queries that can live without a few reports set
+//! a [`skip limit`](Ingest::with_skip_limit): helpers then tell each other which reports they
+//! rejected, all of them leave out every report that any of them rejected, and the query goes on
+//! with the rest as long as there are no more of them than the limit.
+//!
+//! Reports may be submitted more than once, by clients that retry or by whoever replays them to
+//! skew the result. Helpers that keep a [`ReplayFilter`] for as long as reports of an epoch may
+//! arrive, across queries, push reports with [`Ingest::push_report`], which rejects those that
+//! went through the filter before. The filter recognizes reports by their encoding, the same way
+//! it does for reports read from a [`report source`](crate::helpers::report_source), so that both
+//! can share one. A report submitted again reaches all helpers, which reject it at the same
+//! position.
+//!
+//! Every helper keeps a report of the [`Rejections`] it made, so that collectors can fix the
+//! clients that sent malformed reports without anybody reading the logs of helpers. Reports of
+//! rejections count them by reason and point at some of them by their position in the input, but
+//! never say anything about what the rejected reports hold.
+//!
+use crate::columnar::BitColumns;
+use crate::commitment::InputCommitment;
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::models::ReplicatedShare;
+use crate::helpers::report_source::ReplayFilter;
+use crate::helpers::ring::{message, HelperAddr, Identity, Ring};
+use crate::helpers::share_file::SharedEventRef;
+use crate::noise::xor;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::step;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+use thiserror::Error;
+
+/// Bits of a match key. Parts of match keys must not have any bits set above these.
+pub const MATCHKEY_BITS: u32 = 40;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("match key {key} of report {report} has bits set above bit {MATCHKEY_BITS}")]
+    Width { report: usize, key: usize },
+    #[error("report {report} has {actual} match keys, but earlier reports have {expected}")]
+    MatchKeyCount {
+        report: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("report {report} was submitted before")]
+    Replayed { report: usize },
+    #[error("helpers rejected {skipped} reports, but at most {limit} may be left out")]
+    TooManySkipped { skipped: usize, limit: usize },
+}
+
+impl Error {
+    /// Why the report this error is about was rejected, if it is about a single report.
+    #[must_use]
+    pub fn rejection(&self) -> Option<RejectionReason> {
+        match self {
+            Self::Width { .. } => Some(RejectionReason::Width),
+            Self::MatchKeyCount { .. } => Some(RejectionReason::MatchKeyCount),
+            Self::Replayed { .. } => Some(RejectionReason::Replayed),
+            Self::TooManySkipped { .. } => None,
+        }
+    }
+}
+
+/// Most positions of rejected reports that [`Rejections`] lists.
+pub const SAMPLED_REJECTIONS: usize = 16;
+
+/// Why a helper rejected a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// A part of a match key is wider than [`MATCHKEY_BITS`].
+    Width,
+    /// Report has a different number of match keys than the reports before it.
+    MatchKeyCount,
+    /// Report went through the [`ReplayFilter`] before.
+    Replayed,
+}
+
+/// Reports a single helper rejected from the input of a query.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rejections {
+    pub rejected: u64,
+    /// Number of reports rejected for every reason.
+    pub reasons: BTreeMap<RejectionReason, u64>,
+    /// Positions in the input of the first [`SAMPLED_REJECTIONS`] reports rejected, in order.
+    pub sample: Vec<usize>,
+}
+
+impl Rejections {
+    fn add(&mut self, position: usize, reason: RejectionReason) {
+        self.rejected += 1;
+        *self.reasons.entry(reason).or_default() += 1;
+        if self.sample.len() < SAMPLED_REJECTIONS {
+            self.sample.push(position);
+        }
+    }
+}
+
+/// Positions of the reports a helper rejected, sent to both peers.
+#[derive(Debug, Serialize, Deserialize)]
+struct Skipped(Vec<usize>);
+
+message!(Skipped => "ingest.skipped");
+
+/// Match keys of the reports of a query, as one helper receives them.
+#[derive(Debug)]
+pub struct Ingest<F> {
+    identity: Identity,
+    /// Parts of all match keys, report by report.
+    keys: Vec<ReplicatedShare<u64>>,
+    /// Match keys of every report, set by the first one.
+    per_report: Option<usize>,
+    /// Position in the input of every report that was added, in order.
+    positions: Vec<usize>,
+    /// Positions of the reports that were rejected.
+    skipped: BTreeSet<usize>,
+    /// Reports this helper rejected, and why.
+    rejections: Rejections,
+    skip_limit: usize,
+    /// Reports seen before, if reports are checked for replays.
+    replays: Option<ReplayFilter>,
+    field: PhantomData<F>,
+}
+
+/// Match keys of a query, converted, and the reports that were left out of them.
+#[derive(Debug)]
+pub struct Ingested<F> {
+    /// A row for every report that was kept, in the order of the input.
+    pub columns: BitColumns<F>,
+    /// Positions in the input of the reports that any helper rejected, sorted. Other data of
+    /// the reports must leave these out as well.
+    pub skipped: Vec<usize>,
+}
+
+impl<F: Field> Ingest<F> {
+    #[must_use]
+    pub fn new(identity: Identity) -> Self {
+        Self {
+            identity,
+            keys: Vec::new(),
+            per_report: None,
+            positions: Vec::new(),
+            skipped: BTreeSet::new(),
+            rejections: Rejections::default(),
+            skip_limit: 0,
+            replays: None,
+            field: PhantomData,
+        }
+    }
+
+    /// Lets the query go on without up to `limit` reports that helpers reject, see
+    /// [`Ingest::finish`]. All helpers must use the same limit, and with none, which is the
+    /// default, a single rejected report fails the query.
+    #[must_use]
+    pub fn with_skip_limit(mut self, limit: usize) -> Self {
+        self.skip_limit = limit;
+        self
+    }
+
+    /// Rejects reports pushed with [`push_report`](Self::push_report) that went through
+    /// `filter` before, and adds the others to it.
+    #[must_use]
+    pub fn with_replay_filter(mut self, filter: ReplayFilter) -> Self {
+        self.replays = Some(filter);
+        self
+    }
+
+    /// Filter of the reports seen so far, to keep for the next query of the same epoch.
+    #[must_use]
+    pub fn replay_filter(&self) -> Option<&ReplayFilter> {
+        self.replays.as_ref()
+    }
+
+    /// Adds the match keys of the next report, as [`SharedEventRef::matchkeys`](crate::helpers::share_file::SharedEventRef::matchkeys) reads them from
+    /// a share file. All reports of a query must have the same number of match keys.
+    ///
+    /// ## Errors
+    /// If a part of a match key is wider than [`MATCHKEY_BITS`], or the report has a different
+    /// number of match keys than the ones before it. The report is not added in either case,
+    /// but it still takes up a position in the input, and is skipped by all helpers if the skip
+    /// limit allows it.
+    pub fn push<I>(&mut self, matchkeys: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = ReplicatedShare<u64>>,
+    {
+        let position = self.next_position();
+        let start = self.keys.len();
+        self.keys.extend(matchkeys);
+        if let Err(e) = self.check_last(start, position) {
+            self.keys.truncate(start);
+            return Err(self.reject(position, e));
+        }
+        self.positions.push(position);
+        Ok(())
+    }
+
+    /// Adds the match keys of `report`, the same as [`push`](Self::push), after checking that
+    /// it did not go through the [replay filter](Self::with_replay_filter) before.
+    ///
+    /// ## Errors
+    /// If the report was seen before, or [`push`](Self::push) rejects it. The report takes up a
+    /// position in the input either way.
+    pub fn push_report(&mut self, report: SharedEventRef<'_>) -> Result<(), Error> {
+        let position = self.next_position();
+        if let Some(replays) = &self.replays {
+            if replays.contains(report.as_bytes()) {
+                return Err(self.reject(position, Error::Replayed { report: position }));
+            }
+        }
+        self.push(report.matchkeys())?;
+        if let Some(replays) = &mut self.replays {
+            replays.insert(report.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn next_position(&self) -> usize {
+        self.positions.len() + self.skipped.len()
+    }
+
+    /// Leaves out the report at `position`, which was rejected with `e`.
+    fn reject(&mut self, position: usize, e: Error) -> Error {
+        self.skipped.insert(position);
+        if let Some(reason) = e.rejection() {
+            self.rejections.add(position, reason);
+        }
+        e
+    }
+
+    fn check_last(&mut self, start: usize, report: usize) -> Result<(), Error> {
+        let added = &self.keys[start..];
+        if let Some(key) = added
+            .iter()
+            .position(|&ReplicatedShare(left, right)| (left | right) >> MATCHKEY_BITS != 0)
+        {
+            return Err(Error::Width { report, key });
+        }
+        match self.per_report {
+            Some(expected) if expected != added.len() => Err(Error::MatchKeyCount {
+                report,
+                expected,
+                actual: added.len(),
+            }),
+            _ => {
+                self.per_report = Some(added.len());
+                Ok(())
+            }
+        }
+    }
+
+    /// Number of reports that were added.
+    #[must_use]
+    pub fn reports(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Reports this helper rejected so far, not counting those its peers rejected. Code that
+    /// runs the query passes them on to the collector with
+    /// [`QueryProgress::set_rejections`](crate::telemetry::status::QueryProgress::set_rejections)
+    /// before it [finishes](Self::finish) the ingest, which fails if there are too many.
+    #[must_use]
+    pub fn rejections(&self) -> &Rejections {
+        &self.rejections
+    }
+
+    /// Sharings of the parts of every bit of every match key, in the same order as the keys
+    /// were added and least significant bit first. Every bit is the XOR of the three values.
+    #[must_use]
+    pub fn parts(&self) -> Vec<[ReplicatedSecretSharing<F>; 3]> {
+        let zero = ReplicatedSecretSharing::new(F::ZERO, F::ZERO);
+        self.keys
+            .iter()
+            .flat_map(|&ReplicatedShare(left, right)| {
+                (0..MATCHKEY_BITS).map(move |b| {
+                    let left = ReplicatedSecretSharing::new(bit(left, b), F::ZERO);
+                    let right = ReplicatedSecretSharing::new(F::ZERO, bit(right, b));
+                    // the left part of every helper is the right part of the helper on its left
+                    match self.identity {
+                        Identity::H1 => [left, right, zero],
+                        Identity::H2 => [zero, left, right],
+                        Identity::H3 => [right, zero, left],
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Leaves out the reports that any helper rejected, checks that all helpers received the
+    /// same reports otherwise, and converts their match keys into shares of their bits in `F`.
+    /// Every report that is kept is a row, with [`MATCHKEY_BITS`] bits for each of its match
+    /// keys, least significant first. All three helpers must call this at the same point of the
+    /// query, and the conversion uses PRSS indices from `next_index` on.
+    ///
+    /// Helpers only tell each other which reports they rejected if they may skip any, which
+    /// takes another round.
+    ///
+    /// ## Errors
+    /// If helpers rejected more reports than the skip limit, the commitments of helpers do not
+    /// match, or communication with them fails.
+    pub async fn finish<R: Ring>(
+        mut self,
+        ctx: &ProtocolContext<'_, R>,
+        next_index: &mut u128,
+    ) -> Res<Ingested<F>> {
+        if self.skip_limit > 0 {
+            self.agree_skipped(ctx).await?;
+        }
+        if self.skipped.len() > self.skip_limit {
+            return Err(Error::TooManySkipped {
+                skipped: self.skipped.len(),
+                limit: self.skip_limit,
+            }
+            .into());
+        }
+
+        let per_report = self.per_report.unwrap_or(0);
+        let mut commitment = InputCommitment::default();
+        let committed = self
+            .keys
+            .iter()
+            .flat_map(|&ReplicatedShare(left, right)| {
+                (0..MATCHKEY_BITS)
+                    .map(move |b| ReplicatedSecretSharing::new(bit::<F>(left, b), bit(right, b)))
+            })
+            .collect::<Vec<_>>();
+        commitment.add(&committed);
+        // the same parts split into reports differently would commit the same otherwise
+        let count = F::from(per_report as u128);
+        commitment.add(&[ReplicatedSecretSharing::new(count, count)]);
+        commitment.check(ctx).await?;
+
+        let parts = self.parts();
+        let [x1, x2, x3] = [0, 1, 2].map(|i| parts.iter().map(|p| p[i]).collect::<Vec<_>>());
+        let x12 = xor(ctx, next_index, &x1, &x2).await?;
+        let bits = xor(ctx, next_index, &x12, &x3).await?;
+
+        let width = per_report * MATCHKEY_BITS as usize;
+        let reports = self.reports();
+        let mut columns = BitColumns::with_capacity(width, reports);
+        for r in 0..reports {
+            columns.push_row(&bits[r * width..(r + 1) * width])?;
+        }
+        Ok(Ingested {
+            columns,
+            skipped: self.skipped.into_iter().collect(),
+        })
+    }
+
+    /// Tells both peers which reports this helper rejected, and leaves out the ones they did.
+    async fn agree_skipped<R: Ring>(&mut self, ctx: &ProtocolContext<'_, R>) -> Res<()> {
+        let mine = self.skipped.iter().copied().collect::<Vec<_>>();
+        let _round = ctx.rounds.map(|rounds| rounds.wait(step::SKIPPED_REPORTS));
+        let ((), (), Skipped(left), Skipped(right)) = futures::try_join!(
+            ctx.helper_ring
+                .send(HelperAddr::Left, Skipped(mine.clone())),
+            ctx.helper_ring.send(HelperAddr::Right, Skipped(mine)),
+            ctx.helper_ring.receive::<Skipped>(HelperAddr::Left),
+            ctx.helper_ring.receive::<Skipped>(HelperAddr::Right),
+        )?;
+        self.skipped.extend(left.into_iter().chain(right));
+
+        let per_report = self.per_report.unwrap_or(0);
+        let mut kept = 0;
+        for r in 0..self.positions.len() {
+            if !self.skipped.contains(&self.positions[r]) {
+                self.positions[kept] = self.positions[r];
+                self.keys
+                    .copy_within(r * per_report..(r + 1) * per_report, kept * per_report);
+                kept += 1;
+            }
+        }
+        self.positions.truncate(kept);
+        self.keys.truncate(kept * per_report);
+        Ok(())
+    }
+}
+
+/// Bit `b` of `x`, in the field.
+fn bit<F: Field>(x: u64, b: u32) -> F {
+    F::from(u128::from((x >> b) & 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commitment::Error as CommitmentError;
+    use crate::error::{Error, Res};
+    use crate::field::Fp31;
+    use crate::helpers::models::{ReplicatedShare, SharedEvent, SharedEventKind};
+    use crate::helpers::report_source::ReplayFilter;
+    use crate::helpers::ring::{Identity, Ring};
+    use crate::helpers::share_file::Records;
+    use crate::ingest::{
+        Error as IngestError, Ingest, Ingested, RejectionReason, Rejections, MATCHKEY_BITS,
+        SAMPLED_REJECTIONS,
+    };
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::securemul::ProtocolContext;
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use futures::future::FutureExt;
+    use rand::{thread_rng, Rng};
+
+    const MASK: u64 = (1 << MATCHKEY_BITS) - 1;
+
+    /// Shares of reports with the given match keys, for every helper.
+    fn share(reports: &[&[u64]]) -> [Vec<Vec<ReplicatedShare<u64>>>; 3] {
+        let mut rng = thread_rng();
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for keys in reports {
+            let mut report = [Vec::new(), Vec::new(), Vec::new()];
+            for &key in *keys {
+                let parts = ReplicatedShare::share(key, &mut rng);
+                for (helper, ReplicatedShare(left, right)) in report.iter_mut().zip(parts) {
+                    helper.push(ReplicatedShare(left & MASK, right & MASK));
+                }
+            }
+            for (helper, report) in shares.iter_mut().zip(report) {
+                helper.push(report);
+            }
+        }
+        shares
+    }
+
+    /// Adds the reports of a helper in `input` and converts them, returning the rows.
+    async fn convert<R: Ring>(
+        ctx: &ProtocolContext<'_, R>,
+        input: Vec<Vec<ReplicatedShare<u64>>>,
+    ) -> Res<Vec<Vec<ReplicatedSecretSharing<Fp31>>>> {
+        let mut ingest = Ingest::new(ctx.identity);
+        for report in input {
+            ingest.push(report).unwrap();
+        }
+        let mut next_index = 1;
+        let columns = ingest.finish(ctx, &mut next_index).await?.columns;
+        Ok((0..columns.rows()).map(|r| columns.row(r)).collect())
+    }
+
+    /// Same as [`convert`], but reports that are rejected are skipped if there are at most
+    /// `limit` of them.
+    async fn convert_skipping<R: Ring>(
+        ctx: &ProtocolContext<'_, R>,
+        input: Vec<Vec<ReplicatedShare<u64>>>,
+        limit: usize,
+    ) -> Res<Ingested<Fp31>> {
+        let mut ingest = Ingest::new(ctx.identity).with_skip_limit(limit);
+        for report in input {
+            let _ = ingest.push(report);
+        }
+        ingest.finish(ctx, &mut 1).await
+    }
+
+    async fn ingest(
+        input: [Vec<Vec<ReplicatedShare<u64>>>; 3],
+    ) -> [Res<Vec<Vec<ReplicatedSecretSharing<Fp31>>>>; 3] {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let [i0, i1, i2] = input;
+        let (r0, r1, r2) = futures::join!(
+            convert(&ctx[0], i0).boxed(),
+            convert(&ctx[1], i1).boxed(),
+            convert(&ctx[2], i2).boxed(),
+        );
+        [r0, r1, r2]
+    }
+
+    /// Helper that finishes first and its result. Helpers that get past the commitment do not
+    /// finish when another one stops there, so this is the one that noticed.
+    async fn first_error(
+        input: [Vec<Vec<ReplicatedShare<u64>>>; 3],
+    ) -> (usize, Res<Vec<Vec<ReplicatedSecretSharing<Fp31>>>>) {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let [i0, i1, i2] = input;
+        let (result, helper, _) = futures::future::select_all([
+            convert(&ctx[0], i0).boxed(),
+            convert(&ctx[1], i1).boxed(),
+            convert(&ctx[2], i2).boxed(),
+        ])
+        .await;
+        (helper, result)
+    }
+
+    #[tokio::test]
+    async fn converts() {
+        let mut rng = thread_rng();
+        let keys = (0..3)
+            .map(|_| [rng.gen::<u64>() & MASK, rng.gen::<u64>() & MASK])
+            .collect::<Vec<_>>();
+        let reports = keys.iter().map(|k| &k[..]).collect::<Vec<_>>();
+        let [r0, r1, r2] = ingest(share(&reports)).await;
+        let rows = [r0.unwrap(), r1.unwrap(), r2.unwrap()];
+
+        assert_eq!(keys.len(), rows[0].len());
+        for (r, keys) in keys.iter().enumerate() {
+            let row = reconstruct(&[0, 1, 2].map(|i| rows[i][r].clone()));
+            assert_eq!(2 * MATCHKEY_BITS as usize, row.len());
+            for (k, key) in keys.iter().enumerate() {
+                for b in 0..MATCHKEY_BITS {
+                    let expected = Fp31::from(u128::from((key >> b) & 1));
+                    assert_eq!(expected, row[k * MATCHKEY_BITS as usize + b as usize]);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inconsistent() {
+        let mut input = share(&[&[1], &[2]]);
+        // helper 2 has a different part than helper 1, on its left, has in common with it
+        input[1][1][0].0 ^= 1 << 7;
+        let (helper, result) = first_error(input).await;
+        assert_eq!(1, helper);
+        assert!(matches!(
+            result,
+            Err(Error::Commitment(CommitmentError::Mismatch))
+        ));
+
+        let mut input = share(&[&[1], &[2]]);
+        input[2].pop();
+        let (helper, result) = first_error(input).await;
+        assert_eq!(0, helper);
+        assert!(matches!(
+            result,
+            Err(Error::Commitment(CommitmentError::Truncated { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn split_differently() {
+        let mut input = share(&[&[1, 2]]);
+        // the same match keys, in two reports of one
+        let keys = input[0].pop().unwrap();
+        input[0] = keys.into_iter().map(|k| vec![k]).collect();
+        // both neighbours of helper 1 notice
+        let (helper, result) = first_error(input).await;
+        assert_ne!(2, helper);
+        assert!(matches!(
+            result,
+            Err(Error::Commitment(CommitmentError::Mismatch))
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_rejected() {
+        let keys = [1, 2, 3, 4];
+        let reports = keys.iter().map(std::slice::from_ref).collect::<Vec<_>>();
+        let mut input = share(&reports);
+        // a part of report 1 is damaged at helper 1 only, and report 3 at helper 3
+        input[0][1][0].1 |= 1 << MATCHKEY_BITS;
+        input[2][3][0].0 |= 1 << MATCHKEY_BITS;
+
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let [i0, i1, i2] = input.clone();
+        let (r0, r1, r2) = futures::join!(
+            convert_skipping(&ctx[0], i0, 2).boxed(),
+            convert_skipping(&ctx[1], i1, 2).boxed(),
+            convert_skipping(&ctx[2], i2, 2).boxed(),
+        );
+        let results = [r0.unwrap(), r1.unwrap(), r2.unwrap()];
+        for r in &results {
+            assert_eq!(vec![1, 3], r.skipped);
+            assert_eq!(2, r.columns.rows());
+        }
+        for (row, key) in [0, 1].into_iter().zip([1_u64, 3]) {
+            let bits = reconstruct(&[0, 1, 2].map(|i| results[i].columns.row(row)));
+            for (b, bit) in bits.iter().enumerate() {
+                assert_eq!(Fp31::from(u128::from((key >> b) & 1)), *bit);
+            }
+        }
+
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let [i0, i1, i2] = input;
+        let (r0, r1, r2) = futures::join!(
+            convert_skipping(&ctx[0], i0, 1).boxed(),
+            convert_skipping(&ctx[1], i1, 1).boxed(),
+            convert_skipping(&ctx[2], i2, 1).boxed(),
+        );
+        for r in [r0, r1, r2] {
+            assert!(matches!(
+                r,
+                Err(Error::Ingest(IngestError::TooManySkipped {
+                    skipped: 2,
+                    limit: 1
+                }))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let mut ingest = Ingest::<Fp31>::new(Identity::H1);
+        let key = ReplicatedShare(MASK, MASK);
+        ingest.push([key, key]).unwrap();
+        assert!(matches!(
+            ingest.push([key, ReplicatedShare(MASK + 1, 0)]),
+            Err(IngestError::Width { report: 1, key: 1 })
+        ));
+        assert!(matches!(
+            ingest.push([key]),
+            Err(IngestError::MatchKeyCount {
+                report: 2,
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert_eq!(1, ingest.reports());
+        assert_eq!(2 * MATCHKEY_BITS as usize, ingest.parts().len());
+    }
+
+    #[test]
+    fn rejects_replayed() {
+        let event = |matchkey| SharedEvent {
+            matchkeys: vec![ReplicatedShare(matchkey, 0)],
+            epoch: 1,
+            timestamp: ReplicatedShare(2, 3),
+            kind: SharedEventKind::Trigger {
+                value: ReplicatedShare(4, 5),
+            },
+        };
+        let encoded = |events: &[SharedEvent]| {
+            let mut bytes = Vec::new();
+            for e in events {
+                bincode::serialize_into(&mut bytes, e).unwrap();
+            }
+            bytes
+        };
+
+        let first = encoded(&[event(1), event(2), event(1)]);
+        let mut ingest =
+            Ingest::<Fp31>::new(Identity::H1).with_replay_filter(ReplayFilter::default());
+        let mut results = Records::new(&first).map(|r| ingest.push_report(r.unwrap()));
+        assert!(results.next().unwrap().is_ok());
+        assert!(results.next().unwrap().is_ok());
+        assert!(matches!(
+            results.next().unwrap(),
+            Err(IngestError::Replayed { report: 2 })
+        ));
+        drop(results);
+        assert_eq!(2, ingest.reports());
+        assert_eq!(
+            Some(&1),
+            ingest.rejections().reasons.get(&RejectionReason::Replayed)
+        );
+
+        // the filter carries over to the next query of the epoch
+        let filter = ingest.replay_filter().unwrap().clone();
+        assert_eq!(2, filter.len());
+        let second = encoded(&[event(2), event(3)]);
+        let mut ingest = Ingest::<Fp31>::new(Identity::H1).with_replay_filter(filter);
+        let results = Records::new(&second)
+            .map(|r| ingest.push_report(r.unwrap()).is_ok())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![false, true], results);
+
+        // and without a filter, nothing is a replay
+        let mut ingest = Ingest::<Fp31>::new(Identity::H1);
+        for report in Records::new(&first) {
+            ingest.push_report(report.unwrap()).unwrap();
+        }
+        assert_eq!(3, ingest.reports());
+        assert!(ingest.replay_filter().is_none());
+    }
+
+    #[test]
+    fn rejections() {
+        let mut ingest = Ingest::<Fp31>::new(Identity::H1);
+        let key = ReplicatedShare(MASK, MASK);
+        ingest.push([key]).unwrap();
+        for _ in 0..SAMPLED_REJECTIONS {
+            ingest.push([key, key]).unwrap_err();
+            ingest.push([key]).unwrap();
+        }
+        ingest.push([ReplicatedShare(0, MASK + 1)]).unwrap_err();
+
+        let rejections = ingest.rejections();
+        assert_eq!(SAMPLED_REJECTIONS as u64 + 1, rejections.rejected);
+        assert_eq!(
+            vec![
+                (RejectionReason::Width, 1),
+                (RejectionReason::MatchKeyCount, SAMPLED_REJECTIONS as u64),
+            ],
+            rejections.reasons.clone().into_iter().collect::<Vec<_>>()
+        );
+        // every other report, from the second one on
+        assert_eq!(
+            (0..SAMPLED_REJECTIONS)
+                .map(|i| 2 * i + 1)
+                .collect::<Vec<_>>(),
+            rejections.sample
+        );
+
+        // positions and counts only
+        let json = serde_json::to_string(rejections).unwrap();
+        assert!(
+            json.contains(r#""reasons":{"width":1,"match_key_count":16}"#),
+            "{json}"
+        );
+        assert_eq!(
+            *rejections,
+            serde_json::from_str::<Rejections>(&json).unwrap()
+        );
+    }
+}
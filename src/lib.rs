@@ -1,16 +1,101 @@
+//!
+//! Interoperable Private Attribution: the helpers that run queries, with the `helper` feature,
+//! and what clients need to produce their input, with the `client-core` feature, see
+//! [`client_core`]. Default features include both.
+//!
 #![deny(clippy::clone_on_ref_ptr)]
 
+#[cfg(feature = "helper")]
+pub mod accuracy;
+#[cfg(feature = "helper")]
+pub mod arena;
+#[cfg(feature = "helper")]
+pub mod attribution;
+#[cfg(all(feature = "helper", feature = "enable-serde"))]
+pub mod capabilities;
+#[cfg(feature = "helper")]
 mod chunkscan;
 #[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(feature = "client-core")]
+pub mod client_core;
+#[cfg(feature = "helper")]
+pub mod clock;
+#[cfg(all(feature = "helper", feature = "enable-serde"))]
+pub mod collector;
+#[cfg(feature = "helper")]
+pub mod columnar;
+#[cfg(feature = "helper")]
+pub mod commitment;
+#[cfg(feature = "helper")]
+pub mod conformance;
+#[cfg(feature = "helper")]
+pub mod context;
+#[cfg(feature = "helper")]
+pub mod dedup;
+#[cfg(feature = "client-core")]
+pub mod encoding;
+#[cfg(feature = "client-core")]
+pub mod entropy;
+#[cfg(feature = "helper")]
 pub mod error;
+#[cfg(feature = "helper")]
+pub mod estimate;
+#[cfg(all(feature = "helper", feature = "enable-serde"))]
+pub mod export;
+#[cfg(feature = "client-core")]
 pub mod field;
+#[cfg(feature = "helper")]
 pub mod helpers;
+#[cfg(feature = "helper")]
+pub mod ingest;
+#[cfg(feature = "helper")]
 pub mod net;
+#[cfg(feature = "helper")]
+pub mod noise;
+#[cfg(feature = "helper")]
+pub mod parallelism;
+#[cfg(feature = "helper")]
 pub mod prss;
-mod replicated_secret_sharing;
+#[cfg(feature = "helper")]
+pub mod query;
+#[cfg(feature = "helper")]
+pub mod reach;
+#[cfg(feature = "client-core")]
+pub mod replicated_secret_sharing;
+#[cfg(feature = "client-core")]
 pub mod report;
+#[cfg(feature = "helper")]
+pub mod reveal;
+#[cfg(feature = "helper")]
+pub mod roles;
+#[cfg(feature = "scenarios")]
+pub mod scenarios;
+#[cfg(feature = "helper")]
 pub mod securemul;
+#[cfg(feature = "helper")]
 pub mod shamir;
+#[cfg(feature = "helper")]
+pub mod shuffle;
+#[cfg(feature = "helper")]
+pub mod sorting_network;
+#[cfg(feature = "helper")]
+pub mod step;
+#[cfg(feature = "helper")]
+pub mod storage;
+#[cfg(feature = "helper")]
+pub mod telemetry;
+#[cfg(all(test, feature = "helper"))]
+pub mod test_fixture;
+#[cfg(feature = "helper")]
+pub mod test_vectors;
+#[cfg(feature = "client-core")]
 pub mod threshold;
+#[cfg(feature = "helper")]
+pub mod top_k;
+#[cfg(feature = "helper")]
+pub mod transcript;
+#[cfg(feature = "client-core")]
 pub mod user;
+#[cfg(feature = "helper")]
+pub mod verify;
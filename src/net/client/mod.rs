@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 use hyper::client::HttpConnector;
-use hyper::{Body, Client, Uri};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Request, StatusCode, Uri};
 use hyper_tls::HttpsConnector;
 use thiserror::Error as ThisError;
 
@@ -13,6 +14,9 @@ pub enum MpcClientError {
 
     #[error("network connection error")]
     NetworkConnection(#[from] hyper::Error),
+
+    #[error("query {0} is not running")]
+    NotRunning(String),
 }
 
 #[async_trait]
@@ -23,6 +27,8 @@ pub trait MpcHandle {
 pub struct MpcHttpConnection {
     client: Client<HttpsConnector<HttpConnector>>,
     addr: Uri,
+    /// Bearer token the helper knows this client by, see [`crate::net::auth`].
+    token: Option<String>,
 }
 
 #[async_trait]
@@ -30,6 +36,7 @@ impl MpcHandle for MpcHttpConnection {
     async fn execute(&self, command: Command) -> Result<Vec<u8>, MpcClientError> {
         match command {
             Command::Echo(s) => self.echo(&s).await,
+            Command::Pause(id) => self.pause(id).await,
         }
     }
 }
@@ -44,9 +51,17 @@ impl MpcHttpConnection {
         Self {
             client,
             addr: addr.parse().expect("Cannot parse the URI"),
+            token: None,
         }
     }
 
+    /// Authenticates requests with `token`, which endpoints that manage queries take.
+    #[must_use]
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
     async fn echo(&self, s: &str) -> Result<Vec<u8>, MpcClientError> {
         let uri: Uri = format!("{}echo?foo={}", self.addr, s)
             .parse()
@@ -57,4 +72,22 @@ impl MpcHttpConnection {
 
         Ok(result.to_vec())
     }
+
+    async fn pause(&self, id: String) -> Result<Vec<u8>, MpcClientError> {
+        let mut request = Request::post(format!("{}pause/{}", self.addr, id));
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = request
+            .body(Body::empty())
+            .expect("Failed to build a request for \"pause\" command");
+
+        let response = self.client.request(request).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(MpcClientError::NotRunning(id));
+        }
+        let result = hyper::body::to_bytes(response.into_body()).await?;
+
+        Ok(result.to_vec())
+    }
 }
@@ -5,6 +5,8 @@ use std::fmt::{Debug, Formatter};
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum Command {
     Echo(String),
+    /// Asks the helper to checkpoint and stop the query with the given id.
+    Pause(String),
 }
 
 #[cfg(feature = "debug")]
@@ -13,6 +15,7 @@ impl Debug for Command {
         f.write_str("Command::")?;
         match self {
             Self::Echo(_) => f.write_str("Echo"),
+            Self::Pause(_) => f.write_str("Pause"),
         }
     }
 }
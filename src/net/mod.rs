@@ -3,10 +3,18 @@ pub use thread::{Message, Pool};
 
 mod client;
 mod data;
+pub mod object_store;
 mod server;
 mod thread;
 
-pub use server::{bind as bind_mpc_helper_server, router as mpc_helper_router, BindTarget};
+pub use server::{
+    auth, bind as bind_mpc_helper_server, capabilities_router, router as mpc_helper_router,
+    serve as serve_mpc_helper, serve_until as serve_mpc_helper_until, status_router, BindTarget,
+    SHUTDOWN_GRACE_PERIOD,
+};
+
+#[cfg(feature = "prometheus")]
+pub use server::metrics_router;
 
 #[cfg(feature = "self-signed-certs")]
 pub use server::tls_config_from_self_signed_cert;
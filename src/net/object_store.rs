@@ -0,0 +1,394 @@
+//!
+//! Input and output of helpers through an object store, such as S3, GCS or anything compatible
+//! with them. Report collectors upload share files ahead of time and give helpers pre-signed URLs
+//! to read them from and to write result shares to, so bulk data does not go through the query
+//! API. Pre-signed URLs carry their own authorization, so helpers need no credentials for the
+//! store.
+//!
+//! Share objects hold shares encoded with [`write_shares`], in blocks of at most [`BLOCK_SHARES`]
+//! shares that each end with the SHA-256 digest of the shares in them:
+//!
+//! ```text
+//! | block length (u32 LE) | shares | digest (32 bytes) |
+//! ```
+//!
+//! where the block length is that of the shares. A block that was corrupted in the store or on
+//! the way fails with [`Error::Corrupted`], which says which block it was, instead of feeding the
+//! protocol shares that do not add up.
+//!
+//! URLs are never logged or put in errors with their query string, because that is where the
+//! signature is.
+//!
+use crate::error::BoxError;
+use crate::field::Field;
+use crate::helpers::codec::{read_shares, write_shares};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_LENGTH;
+use hyper::http::uri::InvalidUri;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use sha2::{Digest, Sha256};
+use std::io;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::debug;
+
+/// Size of the chunks [`ObjectStore::put_reader`] reads its input in.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+/// Number of shares in every block of a share object, except the last one.
+pub const BLOCK_SHARES: usize = 64 * 1024;
+const DIGEST_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid object URL")]
+    InvalidUrl(#[from] InvalidUri),
+    #[error("request to the object store failed")]
+    Network(#[from] hyper::Error),
+    #[error("object store responded with {status} to {method} {object}")]
+    Status {
+        method: Method,
+        object: String,
+        status: StatusCode,
+    },
+    #[error("object {object} is larger than the limit of {limit} bytes")]
+    TooLarge { object: String, limit: usize },
+    #[error("failed to read the data to upload")]
+    Input(#[from] io::Error),
+    #[error("object {object} does not hold valid shares")]
+    Malformed {
+        object: String,
+        #[source]
+        inner: BoxError,
+    },
+    #[error("block {block} of object {object} does not match its digest")]
+    Corrupted { object: String, block: usize },
+}
+
+/// Reads and writes objects by their pre-signed URLs, over HTTP or HTTPS.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    client: Client<HttpsConnector<HttpConnector>>,
+    max_size: Option<usize>,
+}
+
+impl Default for ObjectStore {
+    fn default() -> Self {
+        Self {
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            max_size: None,
+        }
+    }
+}
+
+impl ObjectStore {
+    /// Fails reads of objects that are larger than `limit` bytes, before reading more than that.
+    #[must_use]
+    pub fn with_max_size(mut self, limit: usize) -> Self {
+        self.max_size = Some(limit);
+        self
+    }
+
+    /// Reads the whole object at `url`.
+    ///
+    /// ## Errors
+    /// If the request fails, the store responds with anything but success or the object is too
+    /// large.
+    pub async fn get(&self, url: &str) -> Result<Bytes, Error> {
+        let uri: Uri = url.parse()?;
+        let object = redact(&uri);
+        debug!("reading {object}");
+        let mut response = self.client.get(uri).await?;
+        check_status(Method::GET, &object, response.status())?;
+
+        let too_large = |limit| Error::TooLarge {
+            object: object.clone(),
+            limit,
+        };
+        let body = response.body_mut();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if let Some(limit) = self.max_size.filter(|&l| data.len() + chunk.len() > l) {
+                return Err(too_large(limit));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data.freeze())
+    }
+
+    /// Writes `data` to the object at `url`, replacing it if it exists.
+    ///
+    /// ## Errors
+    /// If the request fails or the store responds with anything but success.
+    ///
+    /// ## Panics
+    /// Never: the request is built from a URL that parsed already.
+    pub async fn put(&self, url: &str, data: Bytes) -> Result<(), Error> {
+        let uri: Uri = url.parse()?;
+        let object = redact(&uri);
+        debug!("writing {} bytes to {object}", data.len());
+        let request = Request::put(uri).body(Body::from(data)).unwrap();
+        let response = self.client.request(request).await?;
+        check_status(Method::PUT, &object, response.status())
+    }
+
+    /// Writes everything `input` produces to the object at `url`, replacing it if it exists.
+    /// Input is streamed in chunks, so only a few of them are held in memory at a time. Stores
+    /// that need the size of an object up front, like S3 does for pre-signed uploads, need `len`.
+    /// Without it, the object is sent with chunked transfer encoding.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// ## Errors
+    /// If `input` cannot be read, the request fails or the store responds with anything but
+    /// success.
+    ///
+    /// ## Panics
+    /// Never: the request is built from a URL that parsed already.
+    pub async fn put_reader<R: AsyncRead + Unpin>(
+        &self,
+        url: &str,
+        len: Option<u64>,
+        mut input: R,
+    ) -> Result<u64, Error> {
+        let uri: Uri = url.parse()?;
+        let object = redact(&uri);
+        debug!("streaming to {object}");
+        let (mut sender, body) = Body::channel();
+        let mut request = Request::put(uri);
+        if let Some(len) = len {
+            request = request.header(CONTENT_LENGTH, len);
+        }
+        let request = request.body(body).unwrap();
+
+        let send = async move {
+            let mut written = 0;
+            loop {
+                let mut chunk = BytesMut::with_capacity(UPLOAD_CHUNK);
+                match input.read_buf(&mut chunk).await {
+                    Ok(0) => return Ok(written),
+                    Ok(n) => written += n as u64,
+                    Err(e) => {
+                        // the store must not take what was sent so far for the whole object
+                        sender.abort();
+                        return Err(e);
+                    }
+                }
+                // waits for the connection to take the chunk before the next one is read
+                if sender.send_data(chunk.freeze()).await.is_err() {
+                    // request failed, the response says why
+                    return Ok(written);
+                }
+            }
+        };
+        let (written, response) = futures::join!(send, self.client.request(request));
+        let written = written?;
+        check_status(Method::PUT, &object, response?.status())?;
+        Ok(written)
+    }
+
+    /// Reads shares from the object at `url`, checking every block against its digest.
+    ///
+    /// ## Errors
+    /// If the object cannot be read, it does not hold whole shares of field `F` or a block does
+    /// not match its digest.
+    pub async fn get_shares<F: Field>(
+        &self,
+        url: &str,
+    ) -> Result<Vec<ReplicatedSecretSharing<F>>, Error> {
+        let object = redact(&url.parse()?);
+        let mut data = self.get(url).await?;
+        let malformed = |inner: BoxError| Error::Malformed {
+            object: object.clone(),
+            inner,
+        };
+        let mut shares = Vec::new();
+        let mut block = 0;
+        while data.has_remaining() {
+            if data.remaining() < 4 {
+                return Err(malformed("object ends in the middle of a block".into()));
+            }
+            let len = data.get_u32_le() as usize;
+            if data.remaining() < len + DIGEST_LEN {
+                return Err(malformed(format!("block {block} is incomplete").into()));
+            }
+            let body = data.split_to(len);
+            if Sha256::digest(&body)[..] != data.split_to(DIGEST_LEN)[..] {
+                return Err(Error::Corrupted { object, block });
+            }
+            shares.extend(read_shares(&body).map_err(malformed)?);
+            block += 1;
+        }
+        Ok(shares)
+    }
+
+    /// Writes `shares` to the object at `url`, in blocks with their digests.
+    ///
+    /// ## Errors
+    /// If the object cannot be written.
+    ///
+    /// ## Panics
+    /// Never: blocks of [`BLOCK_SHARES`] shares are far smaller than 4 GiB.
+    pub async fn put_shares<F: Field>(
+        &self,
+        url: &str,
+        shares: &[ReplicatedSecretSharing<F>],
+    ) -> Result<(), Error> {
+        let mut data = BytesMut::new();
+        let mut body = BytesMut::new();
+        for block in shares.chunks(BLOCK_SHARES) {
+            write_shares(block, &mut body);
+            data.put_u32_le(u32::try_from(body.len()).unwrap());
+            data.extend_from_slice(&body);
+            data.put_slice(&Sha256::digest(&body));
+            body.clear();
+        }
+        self.put(url, data.freeze()).await
+    }
+}
+
+fn check_status(method: Method, object: &str, status: StatusCode) -> Result<(), Error> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(Error::Status {
+            method,
+            object: object.to_owned(),
+            status,
+        })
+    }
+}
+
+/// URL without the query string, which holds the signature of pre-signed URLs.
+fn redact(uri: &Uri) -> String {
+    format!(
+        "{}://{}{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.authority().map_or("", |a| a.as_str()),
+        uri.path()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::net::object_store::{Error, ObjectStore, BLOCK_SHARES};
+    use crate::net::server::{serve, BindTarget};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use axum::extract::Path;
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use bytes::Bytes;
+    use hyper::StatusCode;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    type Objects = Arc<Mutex<HashMap<String, Bytes>>>;
+
+    /// Bucket that keeps objects in memory and accepts any signature.
+    fn bucket(objects: Objects) -> Router {
+        async fn get_object(
+            Path(name): Path<String>,
+            Extension(objects): Extension<Objects>,
+        ) -> Result<Bytes, StatusCode> {
+            objects
+                .lock()
+                .unwrap()
+                .get(&name)
+                .cloned()
+                .ok_or(StatusCode::NOT_FOUND)
+        }
+        async fn put_object(
+            Path(name): Path<String>,
+            Extension(objects): Extension<Objects>,
+            body: Bytes,
+        ) {
+            objects.lock().unwrap().insert(name, body);
+        }
+        Router::new()
+            .route("/bucket/:name", get(get_object).put(put_object))
+            .layer(Extension(objects))
+    }
+
+    #[tokio::test]
+    async fn shares_round_trip() {
+        let objects = Objects::default();
+        let (addr, _) = serve(
+            BindTarget::Http("127.0.0.1:0".parse().unwrap()),
+            bucket(Arc::clone(&objects)),
+        )
+        .await;
+        let url = |name: &str| format!("http://{addr}/bucket/{name}?X-Amz-Signature=secret");
+        let store = ObjectStore::default();
+
+        let shares = [(1_u128, 2_u128), (30, 0)]
+            .map(|(a, b)| ReplicatedSecretSharing::new(Fp31::from(a), Fp31::from(b)));
+        store.put_shares(&url("result"), &shares).await.unwrap();
+        assert_eq!(
+            &[4, 0, 0, 0, 1, 2, 30, 0],
+            &objects.lock().unwrap()["result"][..8]
+        );
+        assert_eq!(
+            shares.to_vec(),
+            store.get_shares::<Fp31>(&url("result")).await.unwrap()
+        );
+
+        // every block is checked on its own
+        let many = vec![shares[0]; BLOCK_SHARES + 1];
+        store.put_shares(&url("blocks"), &many).await.unwrap();
+        assert_eq!(
+            many,
+            store.get_shares::<Fp31>(&url("blocks")).await.unwrap()
+        );
+        let mut corrupted = objects.lock().unwrap()["blocks"].to_vec();
+        let last = corrupted.len() - 33;
+        corrupted[last] ^= 1;
+        objects
+            .lock()
+            .unwrap()
+            .insert("blocks".into(), Bytes::from(corrupted));
+        assert!(matches!(
+            store.get_shares::<Fp31>(&url("blocks")).await,
+            Err(Error::Corrupted { block: 1, .. })
+        ));
+
+        let data = (0..=255).cycle().take(200_000).collect::<Vec<u8>>();
+        for len in [None, Some(data.len() as u64)] {
+            let written = store
+                .put_reader(&url("streamed"), len, &data[..])
+                .await
+                .unwrap();
+            assert_eq!(data.len() as u64, written);
+            assert_eq!(data, objects.lock().unwrap()["streamed"]);
+        }
+
+        // signature does not end up in errors
+        let err = store.get(&url("missing")).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }
+        ));
+        assert!(!err.to_string().contains("secret"), "{err}");
+
+        objects
+            .lock()
+            .unwrap()
+            .insert("odd".into(), Bytes::from_static(&[1, 2, 3]));
+        assert!(matches!(
+            store.get_shares::<Fp31>(&url("odd")).await,
+            Err(Error::Malformed { .. })
+        ));
+        assert!(matches!(
+            store.with_max_size(2).get(&url("odd")).await,
+            Err(Error::TooLarge { limit: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+//!
+//! Authentication of callers of the query-management endpoints of a helper. Callers present a
+//! bearer token in the `Authorization` header, which the helper knows the [`Caller`] of: a
+//! report collector, a peer helper or an operator. Handlers take the caller as an argument, so a
+//! request without a known token never reaches them, and [`Caller::require`] checks that its
+//! [`Role`] may do what it asks for. Collectors only manage the queries they submitted, see
+//! [`Caller::collector`].
+//!
+//! Helpers keep only SHA-256 digests of tokens, so that their configuration does not hold
+//! anything a caller could present. Requests that are turned away are written to the audit log,
+//! along with the name of the caller if it is known.
+//!
+use crate::helpers::quota::AUDIT_TARGET;
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use axum::response::{IntoResponse, Response};
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+/// What a caller is to the helper, which decides what it may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Report collector, which submits queries and follows and cancels them.
+    Collector,
+    /// Helper of the same ring, which pauses queries when it drains.
+    Peer,
+    /// Whoever runs the helper, who may do anything.
+    Operator,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Collector => "collector",
+            Self::Peer => "peer",
+            Self::Operator => "operator",
+        })
+    }
+}
+
+/// Authenticated caller of an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub name: String,
+    pub role: Role,
+}
+
+impl Caller {
+    #[must_use]
+    pub fn new(name: impl Into<String>, role: Role) -> Self {
+        Self {
+            name: name.into(),
+            role,
+        }
+    }
+
+    /// Name of the caller if it is a collector, which may only manage the queries it submitted.
+    /// Other callers may manage all of them.
+    #[must_use]
+    pub fn collector(&self) -> Option<&str> {
+        (self.role == Role::Collector).then_some(self.name.as_str())
+    }
+
+    /// Checks that the caller has one of `roles`. Operators have all of them.
+    ///
+    /// ## Errors
+    /// If it does not, which is written to the audit log.
+    pub fn require(&self, roles: &[Role]) -> Result<(), AuthError> {
+        if self.role == Role::Operator || roles.contains(&self.role) {
+            Ok(())
+        } else {
+            warn!(
+                target: AUDIT_TARGET,
+                caller = self.name.as_str(),
+                role = %self.role,
+                "request denied"
+            );
+            Err(AuthError::Forbidden)
+        }
+    }
+}
+
+/// Token of a single caller, as the helper is configured with it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenEntry {
+    pub name: String,
+    pub role: Role,
+    /// SHA-256 digest of the token, hex-encoded.
+    #[serde(with = "hex::serde")]
+    pub sha256: [u8; 32],
+}
+
+/// Callers a helper knows, by the digests of their tokens. A helper without any turns away all
+/// requests to the endpoints that need authentication.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    callers: HashMap<[u8; 32], Caller>,
+}
+
+impl Tokens {
+    /// Knows the caller that presents `token` as `caller`.
+    #[must_use]
+    pub fn with_token(self, token: &str, caller: Caller) -> Self {
+        self.with_digest(digest(token), caller)
+    }
+
+    /// Knows the caller that presents a token with the SHA-256 `digest` as `caller`.
+    #[must_use]
+    pub fn with_digest(mut self, digest: [u8; 32], caller: Caller) -> Self {
+        self.callers.insert(digest, caller);
+        self
+    }
+
+    /// Reads tokens from the JSON list of [`TokenEntry`]s in `json`.
+    ///
+    /// ## Errors
+    /// If `json` is not such a list.
+    pub fn from_json(json: &[u8]) -> Result<Self, serde_json::Error> {
+        let entries: Vec<TokenEntry> = serde_json::from_slice(json)?;
+        Ok(entries.into_iter().fold(Self::default(), |tokens, entry| {
+            tokens.with_digest(entry.sha256, Caller::new(entry.name, entry.role))
+        }))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.callers.is_empty()
+    }
+
+    /// Caller that presents `token`, if it is known.
+    #[must_use]
+    pub fn authenticate(&self, token: &str) -> Option<&Caller> {
+        self.callers.get(&digest(token))
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("missing or unknown bearer token")]
+    Unauthenticated,
+    #[error("caller may not do this")]
+    Forbidden,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Unauthenticated => (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer")],
+                self.to_string(),
+            )
+                .into_response(),
+            Self::Forbidden => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+        }
+    }
+}
+
+/// Takes the caller from the bearer token of the request, against the [`Tokens`] the router was
+/// given as an extension.
+#[async_trait]
+impl<B: Send> FromRequest<B> for Caller {
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let caller = token.and_then(|token| {
+            req.extensions()
+                .get::<Arc<Tokens>>()?
+                .authenticate(token.trim())
+                .cloned()
+        });
+        caller.ok_or_else(|| {
+            warn!(
+                target: AUDIT_TARGET,
+                path = req.uri().path(),
+                "request with missing or unknown token denied"
+            );
+            AuthError::Unauthenticated
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::server::auth::{digest, AuthError, Caller, Role, Tokens};
+
+    #[test]
+    fn authenticate() {
+        let digest = hex::encode(digest("secret-2"));
+        let json = format!(r#"[{{"name": "acme", "role": "collector", "sha256": "{digest}"}}]"#);
+        let tokens = Tokens::from_json(json.as_bytes())
+            .unwrap()
+            .with_token("secret-1", Caller::new("helper-2", Role::Peer));
+
+        assert_eq!(
+            Some(&Caller::new("acme", Role::Collector)),
+            tokens.authenticate("secret-2")
+        );
+        assert_eq!(Role::Peer, tokens.authenticate("secret-1").unwrap().role);
+        assert_eq!(None, tokens.authenticate("secret-3"));
+        assert!(Tokens::default().is_empty());
+        assert!(Tokens::from_json(b"[{}]").is_err());
+    }
+
+    #[test]
+    fn require() {
+        let collector = Caller::new("acme", Role::Collector);
+        assert_eq!(Ok(()), collector.require(&[Role::Collector]));
+        assert_eq!(Err(AuthError::Forbidden), collector.require(&[Role::Peer]));
+        assert_eq!(
+            Ok(()),
+            Caller::new("ops", Role::Operator).require(&[Role::Peer])
+        );
+        assert_eq!(Some("acme"), collector.collector());
+        assert_eq!(None, Caller::new("ops", Role::Operator).collector());
+    }
+}
@@ -0,0 +1,8 @@
+use crate::capabilities::Capabilities;
+use axum::{Extension, Json};
+use std::sync::Arc;
+
+/// Describes what this helper can run, for report collectors to choose query parameters.
+pub async fn handler(Extension(capabilities): Extension<Arc<Capabilities>>) -> Json<Capabilities> {
+    Json(Capabilities::clone(&capabilities))
+}
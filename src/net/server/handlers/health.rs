@@ -0,0 +1,20 @@
+use crate::telemetry::status::Status;
+use axum::Extension;
+use hyper::StatusCode;
+use std::sync::Arc;
+
+/// Succeeds for as long as the helper serves requests.
+pub async fn health_handler() -> &'static str {
+    "ok"
+}
+
+/// Succeeds if the helper takes new queries, and fails once it starts shutting down.
+pub async fn ready_handler(
+    Extension(status): Extension<Arc<Status>>,
+) -> (StatusCode, &'static str) {
+    if status.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
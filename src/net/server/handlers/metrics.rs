@@ -0,0 +1,7 @@
+use axum::Extension;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+/// Renders all metrics recorded by this helper in Prometheus text format.
+pub async fn handler(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
@@ -1,3 +1,13 @@
+mod capabilities;
 mod echo;
+mod health;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod status;
 
+pub use capabilities::handler as capabilities_handler;
 pub use echo::{handler as echo_handler, Payload as EchoData};
+pub use health::{health_handler, ready_handler};
+#[cfg(feature = "prometheus")]
+pub use metrics::handler as metrics_handler;
+pub use status::{cancel_handler, events_handler, handler as status_handler, pause_handler};
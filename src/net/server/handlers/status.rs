@@ -0,0 +1,98 @@
+use crate::helpers::quota::AUDIT_TARGET;
+use crate::net::server::auth::{AuthError, Caller, Role};
+use crate::telemetry::status::{Snapshot, Status};
+use axum::extract::Path;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use futures::stream::{self, Stream, StreamExt};
+use hyper::StatusCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// How often records processed by a query are reported to those following it.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Describes the queries running on this helper: those the caller submitted if it is a
+/// collector, all of them otherwise.
+pub async fn handler(caller: Caller, Extension(status): Extension<Arc<Status>>) -> Json<Snapshot> {
+    Json(status.snapshot_for(caller.collector()))
+}
+
+/// Asks a running query to checkpoint and stop, because a peer is about to restart.
+///
+/// ## Errors
+/// If the caller is not a peer.
+pub async fn pause_handler(
+    caller: Caller,
+    Path(id): Path<String>,
+    Extension(status): Extension<Arc<Status>>,
+) -> Result<(StatusCode, &'static str), AuthError> {
+    caller.require(&[Role::Peer])?;
+    Ok(if status.pause(&id) {
+        (StatusCode::OK, "paused")
+    } else {
+        (StatusCode::NOT_FOUND, "not running")
+    })
+}
+
+/// Asks a running query to abort on all helpers, because the collector that submitted it does
+/// not want it anymore. A query that another collector submitted is not running as far as the
+/// caller is concerned. Cancellations are written to the audit log along with the caller,
+/// whether the query was running or not.
+///
+/// ## Errors
+/// If the caller is not a collector.
+pub async fn cancel_handler(
+    caller: Caller,
+    Path(id): Path<String>,
+    Extension(status): Extension<Arc<Status>>,
+) -> Result<(StatusCode, &'static str), AuthError> {
+    caller.require(&[Role::Collector])?;
+    let collector = caller.name.as_str();
+    Ok(if status.cancel(&id, caller.collector()) {
+        info!(target: AUDIT_TARGET, collector, query = id.as_str(), "query cancelled");
+        (StatusCode::OK, "cancelled")
+    } else {
+        info!(
+            target: AUDIT_TARGET,
+            collector,
+            query = id.as_str(),
+            "query not running, nothing to cancel"
+        );
+        (StatusCode::NOT_FOUND, "not running")
+    })
+}
+
+/// Streams progress events of a running query as server-sent events, until it stops running.
+///
+/// ## Errors
+/// If the caller is not a collector, or no query with the given id that it submitted is
+/// running.
+pub async fn events_handler(
+    caller: Caller,
+    Path(id): Path<String>,
+    Extension(status): Extension<Arc<Status>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, Response> {
+    caller
+        .require(&[Role::Collector])
+        .map_err(IntoResponse::into_response)?;
+    let feed = status
+        .follow(&id, caller.collector())
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "not running").into_response())?;
+    let events = stream::unfold(Some(feed), |feed| async move {
+        let mut feed = feed?;
+        let events = feed.next(PROGRESS_INTERVAL).await;
+        let feed = if feed.is_finished() { None } else { Some(feed) };
+        Some((stream::iter(events), feed))
+    })
+    .flatten()
+    .map(|event| {
+        Event::default()
+            .event(event.name())
+            .json_data(event)
+            .map_err(axum::Error::new)
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -1,19 +1,31 @@
+use std::future::Future;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use axum::{
     extract::rejection::QueryRejection,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Router,
 };
 use axum_server::{tls_rustls::RustlsConfig, Handle};
 use hyper::StatusCode;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::task::JoinHandle;
 use tower_http::trace::TraceLayer;
 
+use crate::capabilities::Capabilities;
+use crate::telemetry::status::Status;
+
+pub mod auth;
 mod handlers;
 
+use auth::Tokens;
+
+/// How long requests that are in progress when the server shuts down have to finish.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 #[derive(Error, Debug)]
 pub enum MpcServerError {
     #[error(transparent)]
@@ -36,6 +48,52 @@ pub fn router() -> Router {
     Router::new().route("/echo", get(handlers::echo_handler))
 }
 
+/// Router that serves metrics rendered by `handle` on `/metrics`. Can be merged with the main
+/// router to expose helper metrics to a Prometheus scraper.
+#[cfg(feature = "prometheus")]
+#[must_use]
+pub fn metrics_router(handle: metrics_exporter_prometheus::PrometheusHandle) -> Router {
+    Router::new()
+        .route("/metrics", get(handlers::metrics_handler))
+        .layer(axum::Extension(handle))
+}
+
+/// Router that serves a JSON snapshot of the queries tracked by `status` on `/status`, liveness
+/// on `/healthz` and readiness to take new queries on `/readyz`. Can be merged with the main
+/// router to give operators and orchestrators a view of what the helper is doing. Peers that
+/// drain pause queries with a `POST` to `/pause/{id}`. Collectors follow a long query on
+/// `/events/{id}`, which streams every stage it enters and its progress as server-sent events,
+/// and cancel it with a `POST` to `/cancel/{id}`.
+///
+/// Queries are only there once the code that runs them puts them into `status`, with
+/// [`Status::track`], or [`Status::admit`] for a query a collector submitted. The server has no
+/// endpoint to submit queries, so it adds none itself. The `helper` binary has no such code yet,
+/// so there these endpoints stay empty and collector quotas are not applied.
+///
+/// All but `/healthz` and `/readyz` take only callers with a bearer token in `tokens`, see
+/// [`auth`]. Collectors only see, follow and cancel the queries admitted for them.
+#[must_use]
+pub fn status_router(status: Arc<Status>, tokens: Tokens) -> Router {
+    Router::new()
+        .route("/status", get(handlers::status_handler))
+        .route("/events/:id", get(handlers::events_handler))
+        .route("/pause/:id", post(handlers::pause_handler))
+        .route("/cancel/:id", post(handlers::cancel_handler))
+        .route("/healthz", get(handlers::health_handler))
+        .route("/readyz", get(handlers::ready_handler))
+        .layer(axum::Extension(status))
+        .layer(axum::Extension(Arc::new(tokens)))
+}
+
+/// Router that serves `capabilities` of the helper as JSON on `/capabilities`, without
+/// authentication, so that report collectors can choose query parameters all helpers support.
+#[must_use]
+pub fn capabilities_router(capabilities: Capabilities) -> Router {
+    Router::new()
+        .route("/capabilities", get(handlers::capabilities_handler))
+        .layer(axum::Extension(Arc::new(capabilities)))
+}
+
 /// MPC helper supports HTTP and HTTPS protocols. Only the latter is suitable for production,
 /// http mode may be useful to debug network communication on dev machines
 pub enum BindTarget {
@@ -46,10 +104,40 @@ pub enum BindTarget {
 /// Starts a new instance of MPC helper and binds it to a given target.
 /// Returns a socket it is listening to and the join handle of the web server running.
 pub async fn bind(target: BindTarget) -> (SocketAddr, JoinHandle<()>) {
-    let svc = router()
-        .layer(TraceLayer::new_for_http())
-        .into_make_service();
+    serve(target, router()).await
+}
+
+/// Same as `bind`, but serves the given router instead of the default one.
+///
+/// ## Panics
+/// If the server fails to bind to the target address.
+pub async fn serve(target: BindTarget, router: Router) -> (SocketAddr, JoinHandle<()>) {
+    serve_until(target, router, std::future::pending()).await
+}
+
+/// Same as `serve`, but stops taking new connections once `shutdown` completes. Requests that
+/// are in progress by then get [`SHUTDOWN_GRACE_PERIOD`] to finish, after which the server
+/// stops and the returned task completes.
+///
+/// ## Panics
+/// If the server fails to bind to the target address.
+pub async fn serve_until<S>(
+    target: BindTarget,
+    router: Router,
+    shutdown: S,
+) -> (SocketAddr, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let svc = router.layer(TraceLayer::new_for_http()).into_make_service();
     let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(Some(SHUTDOWN_GRACE_PERIOD));
+        }
+    });
 
     let task_handle = match target {
         BindTarget::Http(addr) => tokio::spawn({
@@ -147,19 +235,40 @@ ShF2TD9MWOlghJSEC6+W3nModkc=
 
 #[cfg(test)]
 mod e2e_tests {
+    use crate::net::server::auth::{Caller, Role, Tokens};
     use crate::net::server::handlers::EchoData;
     use crate::net::server::{bind, BindTarget};
     use hyper::{
         body,
         client::HttpConnector,
-        header::{HeaderName, HeaderValue},
+        header::{HeaderName, HeaderValue, AUTHORIZATION},
         http::uri::Scheme,
-        Body, Request, Response, StatusCode,
+        Body, Method, Request, Response, StatusCode,
     };
     use hyper_tls::{native_tls::TlsConnector, HttpsConnector};
     use std::collections::HashMap;
     use std::str::FromStr;
 
+    const COLLECTOR_TOKEN: &str = "collector-token";
+    const OTHER_COLLECTOR_TOKEN: &str = "other-collector-token";
+    const PEER_TOKEN: &str = "peer-token";
+
+    fn tokens() -> Tokens {
+        Tokens::default()
+            .with_token(COLLECTOR_TOKEN, Caller::new("acme", Role::Collector))
+            .with_token(OTHER_COLLECTOR_TOKEN, Caller::new("other", Role::Collector))
+            .with_token(PEER_TOKEN, Caller::new("helper-2", Role::Peer))
+    }
+
+    fn request(method: Method, uri: String, token: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
     impl EchoData {
         fn to_request(&self, scheme: &Scheme) -> Request<Body> {
             let mut request = Request::builder();
@@ -245,4 +354,284 @@ mod e2e_tests {
         assert_eq!(StatusCode::OK, response.status());
         assert_eq!(expected, EchoData::from_response(&mut response).await);
     }
+
+    #[tokio::test]
+    async fn serves_status() {
+        use crate::net::server::{router, serve, status_router};
+        use crate::telemetry::status::{QueryProgress, Status};
+        use std::sync::Arc;
+
+        let status = Arc::new(Status::default());
+        let progress = Arc::new(QueryProgress::default());
+        let _tracked = status
+            .admit("acme", "query-1", 1, 0, Arc::clone(&progress))
+            .unwrap();
+        progress.set_stage("aggregate");
+        progress.records_processed(12);
+
+        let router = router().merge(status_router(status, tokens()));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+
+        let mut response = hyper::Client::new()
+            .request(request(
+                Method::GET,
+                format!("http://{addr}/status"),
+                COLLECTOR_TOKEN,
+            ))
+            .await
+            .unwrap();
+        let body = body::to_bytes(response.body_mut()).await.unwrap();
+        let snapshot: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        let query = &snapshot["queries"][0];
+        assert_eq!("query-1", query["id"]);
+        assert_eq!("aggregate", query["stage"]);
+        assert_eq!(12, query["records_processed"]);
+        assert_eq!(0, query["left"]["bytes_sent"]);
+    }
+
+    #[tokio::test]
+    async fn streams_events() {
+        use crate::net::server::{router, serve, status_router};
+        use crate::telemetry::status::{QueryProgress, Status};
+        use std::sync::Arc;
+
+        let status = Arc::new(Status::default());
+        let progress = Arc::new(QueryProgress::default());
+        let tracked = status
+            .admit("acme", "query-1", 1, 0, Arc::clone(&progress))
+            .unwrap();
+        progress.set_stage("sort");
+        progress.set_expected_records(20);
+        progress.records_processed(5);
+
+        let router = router().merge(status_router(status, tokens()));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+        let client = hyper::Client::new();
+        let events = |id: &str| {
+            request(
+                Method::GET,
+                format!("http://{addr}/events/{id}"),
+                COLLECTOR_TOKEN,
+            )
+        };
+
+        let missing = client.request(events("query-2")).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, missing.status());
+
+        let mut response = client.request(events("query-1")).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        progress.set_stage("aggregate");
+        drop(tracked);
+
+        // the stream ends once the query is gone
+        let body = body::to_bytes(response.body_mut()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event:"))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        assert_eq!(events.first(), Some(&"stage"));
+        assert!(events.contains(&"progress"));
+        assert_eq!(events.last(), Some(&"finished"));
+        assert!(body.contains(r#""stage":"aggregate""#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn serves_capabilities() {
+        use crate::capabilities::Capabilities;
+        use crate::net::server::{capabilities_router, router, serve};
+
+        let capabilities = Capabilities::new(1).with_max_records(Some(1000));
+        let router = router().merge(capabilities_router(capabilities));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+
+        let mut response = hyper::Client::new()
+            .get(format!("http://{addr}/capabilities").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = body::to_bytes(response.body_mut()).await.unwrap();
+        let capabilities: Capabilities = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, capabilities.protocol_version);
+        assert_eq!(Some(1000), capabilities.max_records);
+        assert!(!capabilities.security_modes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn health_and_shutdown() {
+        use crate::net::server::{router, serve_until, status_router};
+        use crate::telemetry::status::Status;
+        use std::sync::Arc;
+        use tokio::sync::oneshot;
+
+        let status = Arc::new(Status::default());
+        let (stop, stopped) = oneshot::channel();
+        let router = router().merge(status_router(Arc::clone(&status), Tokens::default()));
+        let (addr, server) = serve_until(
+            BindTarget::Http("127.0.0.1:0".parse().unwrap()),
+            router,
+            async move {
+                stopped.await.unwrap();
+            },
+        )
+        .await;
+
+        let client = hyper::Client::new();
+        let get = |path: &str| client.get(format!("http://{addr}{path}").parse().unwrap());
+        assert_eq!(StatusCode::OK, get("/healthz").await.unwrap().status());
+        assert_eq!(StatusCode::OK, get("/readyz").await.unwrap().status());
+
+        // still alive while draining, but no longer ready
+        status.start_draining();
+        assert_eq!(StatusCode::OK, get("/healthz").await.unwrap().status());
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            get("/readyz").await.unwrap().status()
+        );
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_query() {
+        use crate::net::server::{router, serve, status_router};
+        use crate::net::{Client, Command, MpcHandle};
+        use crate::telemetry::status::{QueryProgress, Status};
+        use std::sync::Arc;
+
+        let status = Arc::new(Status::default());
+        let progress = Arc::new(QueryProgress::default());
+        let _tracked = status
+            .admit("acme", "query-1", 1, 0, Arc::clone(&progress))
+            .unwrap();
+
+        let router = router().merge(status_router(status, tokens()));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+
+        let client = Client::new(&format!("http://{addr}/")).with_token(PEER_TOKEN);
+        client
+            .execute(Command::Pause("query-1".into()))
+            .await
+            .unwrap();
+        assert!(progress.pause_requested());
+
+        let response = hyper::Client::new()
+            .request(request(
+                Method::POST,
+                format!("http://{addr}/pause/query-2"),
+                PEER_TOKEN,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    async fn authenticates_callers() {
+        use crate::net::server::{router, serve, status_router};
+        use crate::telemetry::status::{QueryProgress, Status};
+        use std::sync::Arc;
+
+        let status = Arc::new(Status::default());
+        let progress = Arc::new(QueryProgress::default());
+        let _tracked = status
+            .admit("acme", "query-1", 1, 0, Arc::clone(&progress))
+            .unwrap();
+
+        let router = router().merge(status_router(status, tokens()));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+        let client = hyper::Client::new();
+        let status_of = |method: Method, path: &str, token: &str| {
+            let request = request(method, format!("http://{addr}{path}"), token);
+            let response = client.request(request);
+            async move { response.await.unwrap().status() }
+        };
+
+        // no token, or one the helper does not know
+        let response = client
+            .get(format!("http://{addr}/status").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!("Bearer", response.headers()["www-authenticate"]);
+        for (method, path) in [
+            (Method::GET, "/status"),
+            (Method::GET, "/events/query-1"),
+            (Method::POST, "/pause/query-1"),
+            (Method::POST, "/cancel/query-1"),
+        ] {
+            assert_eq!(
+                StatusCode::UNAUTHORIZED,
+                status_of(method, path, "wrong").await
+            );
+        }
+
+        // peers pause, collectors cancel, and neither does what the other does
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status_of(Method::POST, "/cancel/query-1", PEER_TOKEN).await
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status_of(Method::POST, "/pause/query-1", COLLECTOR_TOKEN).await
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status_of(Method::GET, "/events/query-1", PEER_TOKEN).await
+        );
+        assert!(!progress.pause_requested() && !progress.cancel_requested());
+
+        // collectors do not see, follow or cancel queries of other collectors
+        let mut response = client
+            .request(request(
+                Method::GET,
+                format!("http://{addr}/status"),
+                OTHER_COLLECTOR_TOKEN,
+            ))
+            .await
+            .unwrap();
+        let body = body::to_bytes(response.body_mut()).await.unwrap();
+        let snapshot: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(0, snapshot["queries"].as_array().unwrap().len());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(Method::GET, "/events/query-1", OTHER_COLLECTOR_TOKEN).await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(Method::POST, "/cancel/query-1", OTHER_COLLECTOR_TOKEN).await
+        );
+        assert!(!progress.cancel_requested());
+        assert_eq!(
+            StatusCode::OK,
+            status_of(Method::POST, "/cancel/query-1", COLLECTOR_TOKEN).await
+        );
+        assert!(progress.cancel_requested());
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn serves_metrics() {
+        use crate::helpers::ring::HelperAddr;
+        use crate::net::server::{metrics_router, router, serve};
+
+        let handle = crate::telemetry::install_prometheus_recorder().unwrap();
+        crate::telemetry::bytes_sent(HelperAddr::Left, 42);
+
+        let router = router().merge(metrics_router(handle));
+        let (addr, _) = serve(BindTarget::Http("127.0.0.1:0".parse().unwrap()), router).await;
+
+        let mut response = hyper::Client::new()
+            .get(format!("http://{addr}/metrics").parse().unwrap())
+            .await
+            .unwrap();
+        let body = body::to_bytes(response.body_mut()).await.unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(String::from_utf8_lossy(&body).contains(r#"helper_bytes_sent{peer="left"} 42"#));
+    }
 }
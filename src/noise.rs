@@ -0,0 +1,264 @@
+//!
+//! Differentially private noise that helpers sample together, so that no single one of them
+//! knows the noise or can pick it. Noise added to the result after it is revealed is known to
+//! whoever adds it, who can then remove it again, and a helper that skips it or adds too little
+//! goes unnoticed.
+//!
+//! Every random bit is the XOR of three PRSS bits, each of which is known to two helpers only.
+//! Each helper misses one of them, so the XOR is uniform and unknown to it, whatever the other
+//! two bits are. The sum of `trials` such bits, centered around zero, is binomial noise, which
+//! approximates a discrete Gaussian with variance `trials / 4`.
+//!
+//! A helper can still tamper with the multiplications that compute the XOR, which this does not
+//! detect.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::{Identity, Ring};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::step;
+use crate::verify::NoiseParams;
+
+/// Binomial noise, with mean zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binomial {
+    /// Number of random bits that are added up. Always even, so that the noise is centered.
+    trials: u32,
+}
+
+impl Binomial {
+    /// Noise from the sum of `trials` random bits, rounded up to a positive even number.
+    #[must_use]
+    pub fn new(trials: u32) -> Self {
+        let trials = trials.max(1);
+        Self {
+            trials: trials + trials % 2,
+        }
+    }
+
+    /// Noise with the standard deviation of the Gaussian mechanism for `(epsilon, delta)`
+    /// differential privacy when a single user changes a bucket by `sensitivity` at most.
+    ///
+    /// The binomial distribution only approaches the Gaussian as the number of trials grows, so
+    /// small values of `sensitivity / epsilon` get somewhat weaker guarantees than the Gaussian
+    /// mechanism would.
+    #[must_use]
+    pub fn gaussian(epsilon: f64, delta: f64, sensitivity: f64) -> Self {
+        let std_dev = sensitivity * (2.0 * (1.25 / delta).ln()).sqrt() / epsilon;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let trials = (4.0 * std_dev * std_dev).ceil() as u32;
+        Self::new(trials)
+    }
+
+    #[must_use]
+    pub fn trials(self) -> u32 {
+        self.trials
+    }
+
+    /// Standard deviation of the noise.
+    #[must_use]
+    pub fn std_dev(self) -> f64 {
+        f64::from(self.trials).sqrt() / 2.0
+    }
+
+    /// The largest error expected in a single bucket. Binomial noise exceeds `t` in absolute
+    /// value with probability `2 exp(-2t²/trials)` at most.
+    #[must_use]
+    pub fn tolerance(self, confidence: f64) -> f64 {
+        (f64::from(self.trials) / 2.0 * (2.0 / (1.0 - confidence)).ln()).sqrt()
+    }
+
+    /// Shares of `count` independent noise values. Uses `trials` PRSS indices for every value
+    /// and twice as many for multiplication, all reserved from `next_index`.
+    ///
+    /// ## Errors
+    /// If multiplication fails.
+    pub async fn sample<F: Field, R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+        next_index: &mut u128,
+        count: usize,
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let trials = self.trials as usize;
+        let bits = random_bits(ctx, next_index, count * trials).await?;
+        let half = ctx.share_known(F::from(u128::from(self.trials / 2)));
+        let zero = ReplicatedSecretSharing::new(F::ZERO, F::ZERO);
+        Ok(bits
+            .chunks(trials)
+            .map(|c| c.iter().fold(zero, |acc, b| acc + *b) - half)
+            .collect())
+    }
+
+    /// Adds independent noise to every one of `values`, see [`Self::sample`].
+    ///
+    /// ## Errors
+    /// If multiplication fails.
+    pub async fn add<F: Field, R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+        next_index: &mut u128,
+        values: &[ReplicatedSecretSharing<F>],
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let noise = self.sample(ctx, next_index, values.len()).await?;
+        Ok(values.iter().zip(noise).map(|(v, n)| *v + n).collect())
+    }
+}
+
+/// How noise is added to the result of a query.
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+    /// Laplace noise added to the revealed result.
+    Laplace(NoiseParams),
+    /// Binomial noise sampled by helpers together and added to the shares of the result before
+    /// they are revealed.
+    Binomial { noise: Binomial, confidence: f64 },
+}
+
+impl Strategy {
+    /// The largest error expected in a single bucket, with the confidence of the strategy.
+    #[must_use]
+    pub fn tolerance(&self) -> f64 {
+        match self {
+            Self::Laplace(params) => params.tolerance(),
+            Self::Binomial { noise, confidence } => noise.tolerance(*confidence),
+        }
+    }
+
+    /// Shares of the result to reveal. Only [`Self::Binomial`] changes them, Laplace noise is
+    /// added after they are revealed.
+    ///
+    /// ## Errors
+    /// If sampling noise fails.
+    pub async fn apply<F: Field, R: Ring>(
+        &self,
+        ctx: &ProtocolContext<'_, R>,
+        next_index: &mut u128,
+        values: &[ReplicatedSecretSharing<F>],
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        match self {
+            Self::Laplace(_) => Ok(values.to_vec()),
+            Self::Binomial { noise, .. } => noise.add(ctx, next_index, values).await,
+        }
+    }
+}
+
+/// Shares of `count` uniformly random bits that no helper knows. Takes two rounds.
+async fn random_bits<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    count: usize,
+) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+    let (a, (b, c)): (Vec<_>, (Vec<_>, Vec<_>)) = (0..count)
+        .map(|i| {
+            let [a, b, c] = bit_parts(ctx, *next_index + i as u128);
+            (a, (b, c))
+        })
+        .unzip();
+    *next_index += count as u128;
+
+    let ab = xor(ctx, next_index, &a, &b).await?;
+    xor(ctx, next_index, &ab, &c).await
+}
+
+/// Replicated sharings of three random bits. Every one of them is a single PRSS value, which is
+/// known to the two helpers that hold it, and is zero in the sharing of the other helper.
+fn bit_parts<F: Field, R>(
+    ctx: &ProtocolContext<'_, R>,
+    index: u128,
+) -> [ReplicatedSecretSharing<F>; 3] {
+    let (left, right) = ctx.bind(step::RANDOM_BITS, index).prss_values();
+    let zero = ReplicatedSecretSharing::new(F::ZERO, F::ZERO);
+    let left = ReplicatedSecretSharing::new(F::from(left & 1), F::ZERO);
+    let right = ReplicatedSecretSharing::new(F::ZERO, F::from(right & 1));
+    // the left part of every helper is the right part of the helper on its left
+    match ctx.identity {
+        Identity::H1 => [left, right, zero],
+        Identity::H2 => [zero, left, right],
+        Identity::H3 => [right, zero, left],
+    }
+}
+
+/// `a ^ b = a + b - 2ab` for bits.
+pub(crate) async fn xor<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    a: &[ReplicatedSecretSharing<F>],
+    b: &[ReplicatedSecretSharing<F>],
+) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+    let index = *next_index;
+    *next_index += a.len() as u128;
+    let ab = ctx.multiply_batch(index, a, b).await?;
+    let two = F::ONE + F::ONE;
+    Ok(a.iter()
+        .zip(b)
+        .zip(ab)
+        .map(|((a, b), ab)| *a + *b - ab * two)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::{Field, Fp31};
+    use crate::noise::{random_bits, Binomial};
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use futures::future::FutureExt;
+
+    #[tokio::test]
+    async fn random_bits_are_bits() {
+        let world = TestWorld::new();
+        let shares = world
+            .run([(); 3], |ctx, ()| {
+                async move {
+                    let mut next_index = 1;
+                    random_bits::<Fp31, _>(&ctx, &mut next_index, 64)
+                        .await
+                        .unwrap()
+                }
+                .boxed()
+            })
+            .await;
+
+        let bits = reconstruct(&shares);
+        assert!(bits.iter().all(|b| *b == Fp31::ZERO || *b == Fp31::ONE));
+        // not all random bits can be the same
+        assert!(bits.iter().any(|b| *b != bits[0]));
+    }
+
+    #[tokio::test]
+    async fn binomial_noise() {
+        let noise = Binomial::new(7);
+        assert_eq!(8, noise.trials());
+
+        let world = TestWorld::new();
+        let shares = world
+            .run([(); 3], |ctx, ()| {
+                async move {
+                    let mut next_index = 1;
+                    noise
+                        .sample::<Fp31, _>(&ctx, &mut next_index, 20)
+                        .await
+                        .unwrap()
+                }
+                .boxed()
+            })
+            .await;
+
+        let values = reconstruct(&shares);
+        assert_eq!(20, values.len());
+        // centered noise is within -4..=4, negative values wrap around
+        let prime = Fp31::PRIME;
+        assert!(values
+            .iter()
+            .map(|v| u8::from(*v))
+            .all(|v| v <= 4 || v >= prime - 4));
+    }
+
+    #[test]
+    fn gaussian_trials() {
+        // standard deviation of 2 * sqrt(2 ln 1.25e5) / 1 is about 9.7
+        let noise = Binomial::gaussian(1.0, 1e-5, 2.0);
+        assert!((noise.std_dev() - 9.7).abs() < 0.1, "{}", noise.std_dev());
+        assert_eq!(0, noise.trials() % 2);
+    }
+}
@@ -0,0 +1,184 @@
+//!
+//! How much of a query a helper works on at once. By default every step takes all of its records
+//! in one go and nothing limits how many steps run concurrently, which is the fastest way to run
+//! a query if the helper has the memory and the bandwidth for it. Operators of helpers that do
+//! not can trade some of the speed for less of both with a [`ParallelismConfig`].
+//!
+//! Protocols see the limits through [`ProtocolContext::parallelism`]:
+//!
+//! * Batched steps split their records into chunks of at most
+//!   [`ParallelismConfig::max_records_per_step`] and exchange one chunk at a time, so messages
+//!   and the memory to build them stay bounded whatever the size of the input.
+//! * Steps wait for a slot before they exchange anything with peers, of which there are
+//!   [`ParallelismConfig::max_concurrent_steps`]. Slots are handed out in the order steps ask for
+//!   them, which is the same on all three helpers as long as they run the same protocol, so
+//!   peers do not end up waiting for steps that cannot get one.
+//! * Tasks spawned for a query with [`Parallelism::spawn`] wait until fewer than
+//!   [`ParallelismConfig::task_budget`] of them run.
+//!
+//! [`ProtocolContext::parallelism`]: crate::securemul::ProtocolContext::parallelism
+//!
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+
+/// Limits on the work a helper does at once for a single query. Nothing is limited if not set,
+/// and limits of zero are taken as one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct ParallelismConfig {
+    /// Records a step works on at once.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_records_per_step: Option<usize>,
+    /// Steps that exchange messages with peers at the same time.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub max_concurrent_steps: Option<usize>,
+    /// Tasks spawned for the query that run at the same time.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub task_budget: Option<usize>,
+}
+
+impl ParallelismConfig {
+    /// Ranges of `records` records a step works on one after another.
+    pub fn chunks(self, records: usize) -> impl Iterator<Item = Range<usize>> {
+        let size = self.max_records_per_step.unwrap_or(records).max(1);
+        (0..records)
+            .step_by(size)
+            .map(move |start| start..records.min(start + size))
+    }
+}
+
+/// Limits of a [`ParallelismConfig`], enforced for one query.
+#[derive(Debug)]
+pub struct Parallelism {
+    config: ParallelismConfig,
+    steps: Option<Semaphore>,
+    tasks: Option<Arc<Semaphore>>,
+}
+
+impl Parallelism {
+    #[must_use]
+    pub fn new(config: ParallelismConfig) -> Self {
+        Self {
+            config,
+            steps: config
+                .max_concurrent_steps
+                .map(|n| Semaphore::new(n.max(1))),
+            tasks: config
+                .task_budget
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+        }
+    }
+
+    #[must_use]
+    pub fn config(&self) -> ParallelismConfig {
+        self.config
+    }
+
+    /// Waits until the step may exchange messages with peers, which it may for as long as it
+    /// holds on to the permit. There is no permit if steps are not limited.
+    ///
+    /// ## Panics
+    /// Never, the semaphore is not closed while `self` lives.
+    pub async fn step(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.steps {
+            Some(steps) => Some(steps.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        }
+    }
+
+    /// Spawns `future` on the runtime once the query has room in its task budget for it. The
+    /// task counts towards the budget until it finishes.
+    ///
+    /// ## Panics
+    /// If called outside of a Tokio runtime.
+    pub async fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let permit = match &self.tasks {
+            Some(tasks) => Some(
+                Arc::clone(tasks)
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        };
+        tokio::spawn(async move {
+            let output = future.await;
+            drop(permit);
+            output
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parallelism::{Parallelism, ParallelismConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn chunks() {
+        let chunked = ParallelismConfig {
+            max_records_per_step: Some(4),
+            ..ParallelismConfig::default()
+        };
+        assert_eq!(
+            vec![0..4, 4..8, 8..10],
+            chunked.chunks(10).collect::<Vec<_>>()
+        );
+        assert_eq!(0, chunked.chunks(0).count());
+
+        let unlimited = ParallelismConfig::default();
+        assert_eq!(vec![0..10], unlimited.chunks(10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn task_budget() {
+        let parallelism = Parallelism::new(ParallelismConfig {
+            task_budget: Some(2),
+            ..ParallelismConfig::default()
+        });
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        for _ in 0..6 {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            let task = async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            };
+            handles.push(parallelism.spawn(task).await);
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(2, most.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn steps() {
+        let parallelism = Parallelism::new(ParallelismConfig {
+            max_concurrent_steps: Some(1),
+            ..ParallelismConfig::default()
+        });
+        let first = parallelism.step().await;
+        assert!(first.is_some());
+        assert!(futures::FutureExt::now_or_never(parallelism.step()).is_none());
+        drop(first);
+        assert!(parallelism.step().await.is_some());
+
+        let unlimited = Parallelism::new(ParallelismConfig::default());
+        assert!(unlimited.step().await.is_none());
+    }
+}
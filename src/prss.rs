@@ -6,16 +6,26 @@ use aes::{
 use byteorder::{ByteOrder, LittleEndian};
 use hkdf::Hkdf;
 use rand::{CryptoRng, RngCore};
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+#[cfg(debug_assertions)]
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+#[cfg(debug_assertions)]
+use std::sync::Mutex;
 use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroize;
 
 /// A participant in a 2-of-3 replicated secret sharing.
-#[derive(Debug)] // TODO custom debug implementation
 pub struct Participant {
     left: Generator,
     left_bits: BitGenerator,
     right: Generator,
     right_bits: BitGenerator,
+    /// Steps that claimed PRSS indices, see [`Participant::claim`].
+    #[cfg(debug_assertions)]
+    claimed: Mutex<HashMap<u128, &'static str>>,
 }
 
 impl Participant {
@@ -63,6 +73,28 @@ impl Participant {
         l.wrapping_add(r)
     }
 
+    /// Records that protocol step `step` draws randomness for `index`, and returns the step that
+    /// claimed it before, if any. Randomness drawn twice for the same index is the same, which
+    /// breaks both correctness and security of protocols and is hard to spot in their output.
+    ///
+    /// Claims are only kept in debug builds, release builds always return `None`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    #[allow(clippy::unused_self)]
+    pub fn claim(&self, step: &'static str, index: u128) -> Option<&'static str> {
+        #[cfg(debug_assertions)]
+        {
+            self.claimed.lock().unwrap().insert(index, step)
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            let _ = (step, index);
+            None
+        }
+    }
+
     /// Generate the next share in `ZZ_2`
     #[must_use]
     pub fn next_zero_bit_share(&mut self) -> bool {
@@ -84,6 +116,12 @@ impl Participant {
     }
 }
 
+impl Debug for Participant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Participant { .. }")
+    }
+}
+
 /// Use this to setup a three-party PRSS configuration.
 pub struct ParticipantSetup {
     left: KeyExchange,
@@ -115,13 +153,55 @@ impl ParticipantSetup {
     /// participant instance.
     #[must_use]
     pub fn setup(self, left_pk: &PublicKey, right_pk: &PublicKey) -> Participant {
-        let fl = self.left.key_exchange(left_pk);
-        let fr = self.right.key_exchange(right_pk);
+        Participant::from(&self.setup_seeds(left_pk, right_pk))
+    }
+
+    /// Same as `setup`, but returns the secrets shared with the left and right participants
+    /// instead. Keeping them allows the participant to be reconstructed later, for instance to
+    /// replay a protocol execution.
+    #[must_use]
+    pub fn setup_seeds(self, left_pk: &PublicKey, right_pk: &PublicKey) -> Seeds {
+        Seeds {
+            left: self.left.shared_secret(left_pk),
+            right: self.right.shared_secret(right_pk),
+        }
+    }
+}
+
+/// Secrets a participant shares with the participants on its left and right. Anyone who has
+/// these can generate the same randomness as the participant, so they must be handled with the
+/// same care as private keys.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct Seeds {
+    left: [u8; 32],
+    right: [u8; 32],
+}
+
+impl Debug for Seeds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Seeds { .. }")
+    }
+}
+
+impl Drop for Seeds {
+    fn drop(&mut self) {
+        self.left.zeroize();
+        self.right.zeroize();
+    }
+}
+
+impl From<&Seeds> for Participant {
+    fn from(seeds: &Seeds) -> Self {
+        let fl = GeneratorFactory::new(&seeds.left);
+        let fr = GeneratorFactory::new(&seeds.right);
         Participant {
-            left: fl.generator(Self::CONTEXT_VALUES),
-            left_bits: BitGenerator::from(fl.generator(Self::CONTEXT_BITS)),
-            right: fr.generator(Self::CONTEXT_VALUES),
-            right_bits: BitGenerator::from(fr.generator(Self::CONTEXT_BITS)),
+            left: fl.generator(ParticipantSetup::CONTEXT_VALUES),
+            left_bits: BitGenerator::from(fl.generator(ParticipantSetup::CONTEXT_BITS)),
+            right: fr.generator(ParticipantSetup::CONTEXT_VALUES),
+            right_bits: BitGenerator::from(fr.generator(ParticipantSetup::CONTEXT_BITS)),
+            #[cfg(debug_assertions)]
+            claimed: Mutex::default(),
         }
     }
 }
@@ -145,10 +225,15 @@ impl KeyExchange {
 
     #[must_use]
     pub fn key_exchange(self, pk: &PublicKey) -> GeneratorFactory {
+        let mut secret = self.shared_secret(pk);
+        let factory = GeneratorFactory::new(&secret);
+        secret.zeroize();
+        factory
+    }
+
+    fn shared_secret(self, pk: &PublicKey) -> [u8; 32] {
         debug_assert_ne!(pk, &self.public_key(), "self key exchange detected");
-        let secret = self.sk.diffie_hellman(pk);
-        let kdf = Hkdf::<Sha256>::new(None, secret.as_bytes());
-        GeneratorFactory { kdf }
+        self.sk.diffie_hellman(pk).to_bytes()
     }
 }
 
@@ -159,24 +244,36 @@ pub struct GeneratorFactory {
 }
 
 impl GeneratorFactory {
+    fn new(secret: &[u8]) -> Self {
+        Self {
+            kdf: Hkdf::<Sha256>::new(None, secret),
+        }
+    }
+
     /// Create a new generator using the provided context string.
     #[allow(clippy::missing_panics_doc)] // Panic should be impossible.
     #[must_use]
     pub fn generator(&self, context: &[u8]) -> Generator {
         let mut k = GenericArray::default();
         self.kdf.expand(context, &mut k).unwrap();
-        Generator {
-            cipher: Aes256::new(&k),
-        }
+        let cipher = Aes256::new(&k);
+        k.zeroize();
+        Generator { cipher }
     }
 }
 
-/// The basic generator.  This generates values based on an arbitrary index.
-#[derive(Debug)]
+/// The basic generator.  This generates values based on an arbitrary index. Its key is erased
+/// from memory when it is dropped.
 pub struct Generator {
     cipher: Aes256,
 }
 
+impl Debug for Generator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Generator { .. }")
+    }
+}
+
 impl Generator {
     /// Generate the value at the given index.
     #[must_use]
@@ -190,7 +287,6 @@ impl Generator {
 }
 
 /// A generator for a single bit.  Unlike the base generator, this is a stateful object.
-#[derive(Debug)]
 pub struct BitGenerator {
     /// The underlying generator.
     g: Generator,
@@ -227,6 +323,20 @@ impl BitGenerator {
     }
 }
 
+impl Debug for BitGenerator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BitGenerator")
+            .field("index", &(self.i >> 7))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for BitGenerator {
+    fn drop(&mut self) {
+        self.v.zeroize();
+    }
+}
+
 impl From<Generator> for BitGenerator {
     fn from(g: Generator) -> Self {
         Self { g, i: 0, v: 0 }
@@ -237,7 +347,7 @@ impl From<Generator> for BitGenerator {
 pub mod test {
     use aes::{cipher::KeyInit, Aes256};
     use digest::generic_array::GenericArray;
-    use rand::thread_rng;
+    use rand::{thread_rng, CryptoRng, RngCore};
 
     use crate::field::Fp31;
 
@@ -323,6 +433,17 @@ pub mod test {
         }
     }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    fn claim_reports_reuse() {
+        let (p1, p2, _) = make_three();
+        assert_eq!(None, p1.claim("securemul", 1));
+        assert_eq!(None, p1.claim("securemul", 2));
+        assert_eq!(Some("securemul"), p1.claim("reveal", 1));
+        // every helper keeps its own claims
+        assert_eq!(None, p2.claim("reveal", 1));
+    }
+
     /// Creating generators with different contexts means different output.
     #[test]
     fn mismatched_context() {
@@ -339,10 +460,17 @@ pub mod test {
     /// p1 is left of p2, p2 is left of p3, p3 is left of p1...
     #[must_use]
     pub fn make_three() -> (Participant, Participant, Participant) {
-        let mut r = thread_rng();
-        let setup1 = ParticipantSetup::new(&mut r);
-        let setup2 = ParticipantSetup::new(&mut r);
-        let setup3 = ParticipantSetup::new(&mut r);
+        make_three_with_rng(&mut thread_rng())
+    }
+
+    /// Same as [`make_three`], with keys generated by `r`.
+    #[must_use]
+    pub fn make_three_with_rng<R: RngCore + CryptoRng>(
+        r: &mut R,
+    ) -> (Participant, Participant, Participant) {
+        let setup1 = ParticipantSetup::new(r);
+        let setup2 = ParticipantSetup::new(r);
+        let setup3 = ParticipantSetup::new(r);
         let (pk1_l, pk1_r) = setup1.public_keys();
         let (pk2_l, pk2_r) = setup2.public_keys();
         let (pk3_l, pk3_r) = setup3.public_keys();
@@ -354,6 +482,29 @@ pub mod test {
         (p1, p2, p3)
     }
 
+    #[test]
+    fn participant_from_seeds() {
+        const IDX: u128 = 7;
+        let mut r = thread_rng();
+        let setup1 = ParticipantSetup::new(&mut r);
+        let setup2 = ParticipantSetup::new(&mut r);
+        let setup3 = ParticipantSetup::new(&mut r);
+        let (_, pk1_r) = setup1.public_keys();
+        let (pk2_l, _) = setup2.public_keys();
+        let (pk3_l, pk3_r) = setup3.public_keys();
+
+        let seeds = setup1.setup_seeds(&pk3_r, &pk2_l);
+        let p1 = Participant::from(&seeds);
+        let p2 = setup2.setup(&pk1_r, &pk3_l);
+
+        assert_eq!(p1.generate_values(IDX).1, p2.generate_values(IDX).0);
+        assert_eq!(
+            p1.generate_values(IDX),
+            Participant::from(&seeds).generate_values(IDX)
+        );
+        assert_eq!("Seeds { .. }", format!("{seeds:?}"));
+    }
+
     #[test]
     fn three_party_values() {
         const IDX: u128 = 7;
@@ -0,0 +1,866 @@
+//!
+//! Parameters of an IPA query. Every stage of the protocol takes them from [`IpaQueryConfig`]
+//! rather than from constants of its own, and they are checked together with
+//! [`IpaQueryConfig::validate`] before the query starts, so stages can rely on them being
+//! consistent with each other.
+//!
+//! A query may run on a [`Sampling`] of its input only, as a dry run that tells the collector
+//! what to expect of the real one: approximate results, and how long it takes and how much
+//! helpers send to each other. It spends only the part of the budget that the sample is worth.
+//!
+//! Every stage may also have a deadline, see [`StageDeadlines`]. A stage that runs past it
+//! aborts the query on all three helpers, so that a peer that hangs does not keep the query and
+//! everything it buffered around forever. A query the collector cancels is aborted the same way.
+//!
+use crate::attribution::Model;
+use crate::error::Res;
+use crate::field::{Field, Fp31, Fp32BitPrime};
+use crate::helpers::codec::field_size;
+use crate::helpers::models::Aggregate;
+use crate::helpers::ring::Ring;
+use crate::telemetry::status::QueryProgress;
+use crate::verify::NoiseParams;
+use futures::future::{self, Either};
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("query must have at least one breakdown key")]
+    NoBreakdownKeys,
+    #[error("{max_breakdown_key} breakdown keys do not fit into {field:?}")]
+    TooManyBreakdownKeys {
+        max_breakdown_key: u32,
+        field: FieldType,
+    },
+    #[error("attribution window must be positive")]
+    EmptyAttributionWindow,
+    #[error("per user cap must be positive")]
+    ZeroCap,
+    #[error("per user cap of {cap} does not fit into {field:?}")]
+    CapTooLarge { cap: u8, field: FieldType },
+    #[error("half life of time-decay attribution must be positive")]
+    EmptyHalfLife,
+    #[error("trigger values can have {max} decimals at most, not {0}", max = ValueScale::MAX_DECIMALS)]
+    TooManyDecimals(u8),
+    #[error("largest trigger value must be positive and finite, not {0}")]
+    MaxValue(f64),
+    #[error("per user cap of values up to {max_value} does not fit into {field:?}")]
+    ValueCapTooLarge { max_value: f64, field: FieldType },
+    #[error("epsilon must be positive and finite, not {0}")]
+    Epsilon(f64),
+    #[error("sampling rate must be in (0, 1], not {0}")]
+    SamplingRate(f64),
+    #[error("deadline of the {0} stage must be positive")]
+    EmptyDeadline(Stage),
+    #[error("{stage} stage did not finish in {limit:?}")]
+    DeadlineExceeded { stage: Stage, limit: Duration },
+    #[error("query was cancelled in the {0} stage")]
+    Cancelled(Stage),
+}
+
+/// Stage of the protocol that has a deadline of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Conversion,
+    Sort,
+    Attribution,
+    Aggregation,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [
+        Self::Conversion,
+        Self::Sort,
+        Self::Attribution,
+        Self::Aggregation,
+    ];
+
+    /// Name of the stage, as reported in [`QueryProgress`].
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Conversion => "conversion",
+            Self::Sort => "sort",
+            Self::Attribution => "attribution",
+            Self::Aggregation => "aggregation",
+        }
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Uniform sample of the input that a dry run of a query processes instead of all of it. Every
+/// record is in the sample with probability `rate`, independently of the others. Helpers must
+/// agree on which records those are, so whether one is depends only on the `seed` and the index
+/// of the record in the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct Sampling {
+    pub rate: f64,
+    pub seed: u64,
+}
+
+/// What it took to run a query, or what it is expected to take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunCost {
+    pub records: u64,
+    pub elapsed: Duration,
+    /// Bytes sent by all helpers to each other.
+    pub bytes: u64,
+}
+
+impl Sampling {
+    /// Whether the record at `index` of the input is in the sample.
+    #[must_use]
+    pub fn includes(self, index: u64) -> bool {
+        let mut hash = Sha256::new();
+        hash.update(self.seed.to_le_bytes());
+        hash.update(index.to_le_bytes());
+        let digest = hash.finalize();
+        let value = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let threshold = (self.rate * u64::MAX as f64) as u64;
+        value <= threshold
+    }
+
+    /// Privacy budget a query that spends `epsilon` on all of its input spends on this sample.
+    /// Users are in the sample with probability `rate` only, so their privacy loss is amplified
+    /// down to `ln(1 + rate (e^epsilon - 1))`.
+    #[must_use]
+    pub fn amplify(self, epsilon: f64) -> f64 {
+        self.rate.mul_add(epsilon.exp_m1(), 1.0).ln()
+    }
+
+    /// Cost of running the query on all of its input, extrapolated linearly from `sampled`, the
+    /// cost of running it on this sample. Sorting grows faster than that, so this is a lower
+    /// bound for large inputs.
+    #[must_use]
+    pub fn extrapolate(self, sampled: &RunCost) -> RunCost {
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let scale = |v: u64| (v as f64 / self.rate).round() as u64;
+        RunCost {
+            records: scale(sampled.records),
+            elapsed: sampled.elapsed.div_f64(self.rate),
+            bytes: scale(sampled.bytes),
+        }
+    }
+}
+
+/// Time, in milliseconds, every stage of a query may take at most. Stages without a deadline
+/// take as long as they need.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "enable-serde", serde(default))]
+pub struct StageDeadlines {
+    pub conversion_ms: Option<u64>,
+    pub sort_ms: Option<u64>,
+    pub attribution_ms: Option<u64>,
+    pub aggregation_ms: Option<u64>,
+}
+
+impl StageDeadlines {
+    /// Deadline of `stage`, if it has one.
+    #[must_use]
+    pub fn get(&self, stage: Stage) -> Option<Duration> {
+        let ms = match stage {
+            Stage::Conversion => self.conversion_ms,
+            Stage::Sort => self.sort_ms,
+            Stage::Attribution => self.attribution_ms,
+            Stage::Aggregation => self.aggregation_ms,
+        };
+        ms.map(Duration::from_millis)
+    }
+
+    /// Runs `stage` of the query on this helper, and reports it in `progress` while it runs.
+    /// If the stage does not finish in time, or the query is cancelled through `progress`, it
+    /// is dropped and the query is aborted through `ring`, which fails whatever the peers are
+    /// still waiting for. Every helper enforces the deadlines on its own, so whichever of them
+    /// runs out of time first aborts the query for all of them. Messages the helper held or
+    /// waited for at that point are logged.
+    ///
+    /// ## Errors
+    /// If the stage fails, does not finish before its deadline or is cancelled.
+    pub async fn run<R, T, S>(
+        &self,
+        stage: Stage,
+        ring: &R,
+        progress: Option<&QueryProgress>,
+        future: S,
+    ) -> Res<T>
+    where
+        R: Ring,
+        S: Future<Output = Res<T>>,
+    {
+        if let Some(progress) = progress {
+            progress.set_stage(stage.name());
+        }
+        let timed = async {
+            match self.get(stage) {
+                Some(limit) => tokio::time::timeout(limit, future)
+                    .await
+                    .map_err(|_| Error::DeadlineExceeded { stage, limit }),
+                None => Ok(future.await),
+            }
+        };
+        let cancelled = async {
+            match progress {
+                Some(progress) => progress.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        let error = match future::select(Box::pin(timed), Box::pin(cancelled)).await {
+            Either::Left((Ok(result), _)) => return result,
+            Either::Left((Err(e), _)) => e,
+            Either::Right(((), _)) => Error::Cancelled(stage),
+        };
+
+        // what the helper waited for when it stopped, before abort drops it
+        let pending = ring.pending();
+        warn!("{error}, with {} messages pending", pending.len());
+        for p in pending {
+            warn!("pending: {p}");
+        }
+        // the error of the stage is more useful to the caller than that of the abort
+        if let Err(e) = ring.abort(&error.to_string()).await {
+            warn!("failed to abort the query after the {stage} stage stopped: {e}");
+        }
+        Err(error.into())
+    }
+}
+
+/// Fixed-point representation of trigger values. Values are shared as integers in units of
+/// `10^-decimals` of the currency of the query, so that currencies with different minor units
+/// and fractional values are represented exactly: 1234 is 12.34 dollars with two decimals, and
+/// 1234 yen with none.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "enable-serde", serde(default))]
+pub struct ValueScale {
+    pub decimals: u8,
+    /// Largest value of a single conversion, in units of the currency. Larger values are capped
+    /// to it before they are shared. Values are not capped if not set.
+    pub max_value: Option<f64>,
+}
+
+impl ValueScale {
+    /// Fixed-point values are `u32`, which leaves room for nine decimals.
+    pub const MAX_DECIMALS: u8 = 9;
+
+    /// Number of fixed-point units in one unit of the currency.
+    #[must_use]
+    pub fn unit(self) -> u32 {
+        10_u32.pow(u32::from(self.decimals.min(Self::MAX_DECIMALS)))
+    }
+
+    /// Largest fixed-point value of a single conversion, if values are capped. Rounded down, so
+    /// that capped values never exceed `max_value`.
+    #[must_use]
+    pub fn max_fixed(self) -> Option<u32> {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        self.max_value.map(|max| {
+            (max * f64::from(self.unit()))
+                .floor()
+                .min(f64::from(u32::MAX)) as u32
+        })
+    }
+
+    /// Caps a fixed-point value of a single conversion.
+    #[must_use]
+    pub fn cap(self, fixed: u32) -> u32 {
+        self.max_fixed().map_or(fixed, |max| fixed.min(max))
+    }
+
+    /// Fixed-point value of a conversion worth `value` units of the currency, rounded to the
+    /// nearest unit and capped. Negative values are worth nothing.
+    #[must_use]
+    pub fn encode(self, value: f64) -> u32 {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let fixed = (value * f64::from(self.unit()))
+            .round()
+            .clamp(0.0, f64::from(u32::MAX)) as u32;
+        self.cap(fixed)
+    }
+
+    /// Value in units of the currency of a fixed-point value or of an aggregate of them.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn decode(self, fixed: u128) -> f64 {
+        fixed as f64 / f64::from(self.unit())
+    }
+}
+
+/// Field shares of the query are in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum FieldType {
+    #[default]
+    Fp31,
+    /// For trigger values, and sums of them, that do not fit into [`Fp31`].
+    Fp32BitPrime,
+}
+
+impl FieldType {
+    /// Number of elements of the field.
+    #[must_use]
+    pub fn prime(self) -> u128 {
+        match self {
+            Self::Fp31 => u128::from(Fp31::PRIME),
+            Self::Fp32BitPrime => u128::from(Fp32BitPrime::PRIME),
+        }
+    }
+
+    /// Number of bytes a value of the field takes on the wire.
+    #[must_use]
+    pub fn size(self) -> usize {
+        match self {
+            Self::Fp31 => field_size::<Fp31>(),
+            Self::Fp32BitPrime => field_size::<Fp32BitPrime>(),
+        }
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    /// Parses `fp31` or `fp32-bit-prime`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fp31" => Ok(Self::Fp31),
+            "fp32-bit-prime" => Ok(Self::Fp32BitPrime),
+            _ => Err(format!("unknown field: {s}")),
+        }
+    }
+}
+
+/// Security model the query runs in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum SecurityMode {
+    /// Helpers follow the protocol, see [`crate::context::SemiHonestContext`].
+    #[default]
+    SemiHonest,
+    /// A helper that deviates from the protocol is caught, see
+    /// [`crate::context::MaliciousContext`].
+    Malicious,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct IpaQueryConfig {
+    /// Breakdown keys of source events are in `0..max_breakdown_key`.
+    pub max_breakdown_key: u32,
+    /// Seconds after a source event in which a trigger event may be attributed to it.
+    pub attribution_window_seconds: u32,
+    /// Number of conversions of a single user that are attributed at most.
+    pub per_user_cap: u8,
+    /// How conversions credit the source events they are attributed to.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub attribution: Model,
+    /// How trigger values are represented, and what they are capped to.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub value_scale: ValueScale,
+    /// Privacy budget the query spends. No noise is added if not set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub epsilon: Option<f64>,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub field: FieldType,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub security: SecurityMode,
+    /// Query is a dry run on a sample of the input, if set.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub sampling: Option<Sampling>,
+    /// Deadlines do not change the result, so they are not part of the fingerprint of the query.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub deadlines: StageDeadlines,
+}
+
+impl Default for IpaQueryConfig {
+    fn default() -> Self {
+        Self {
+            max_breakdown_key: 4,
+            attribution_window_seconds: 7 * 24 * 60 * 60,
+            per_user_cap: 1,
+            attribution: Model::default(),
+            value_scale: ValueScale::default(),
+            epsilon: None,
+            field: FieldType::default(),
+            security: SecurityMode::default(),
+            sampling: None,
+            deadlines: StageDeadlines::default(),
+        }
+    }
+}
+
+impl IpaQueryConfig {
+    /// Checks that the parameters make sense on their own and together.
+    ///
+    /// ## Errors
+    /// If there are no breakdown keys, the attribution window is empty, the cap is zero, epsilon
+    /// is not positive, the sampling rate is out of range, a deadline is zero, the half life of
+    /// time-decay attribution is zero, trigger values have too many decimals or a largest value
+    /// that is not positive, or breakdown keys, the credit of the capped conversions or the sum
+    /// of their capped values do not fit into the field of the query.
+    pub fn validate(&self) -> Result<(), Error> {
+        let prime = self.field.prime();
+        if self.max_breakdown_key == 0 {
+            return Err(Error::NoBreakdownKeys);
+        }
+        // breakdown keys are shared as field values
+        if u128::from(self.max_breakdown_key) > prime {
+            return Err(Error::TooManyBreakdownKeys {
+                max_breakdown_key: self.max_breakdown_key,
+                field: self.field,
+            });
+        }
+        if self.attribution_window_seconds == 0 {
+            return Err(Error::EmptyAttributionWindow);
+        }
+        if self.per_user_cap == 0 {
+            return Err(Error::ZeroCap);
+        }
+        if matches!(
+            self.attribution,
+            Model::TimeDecay {
+                half_life_seconds: 0
+            }
+        ) {
+            return Err(Error::EmptyHalfLife);
+        }
+        // so that the count of a single user does not wrap around
+        if u128::from(self.per_user_cap) * u128::from(self.attribution.scale()) >= prime {
+            return Err(Error::CapTooLarge {
+                cap: self.per_user_cap,
+                field: self.field,
+            });
+        }
+        self.validate_values(prime)?;
+        if let Some(Sampling { rate, .. }) = self.sampling {
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err(Error::SamplingRate(rate));
+            }
+        }
+        if let Some(stage) = Stage::ALL
+            .into_iter()
+            .find(|s| self.deadlines.get(*s) == Some(Duration::ZERO))
+        {
+            return Err(Error::EmptyDeadline(stage));
+        }
+        match self.epsilon {
+            Some(epsilon) if !(epsilon > 0.0 && epsilon.is_finite()) => {
+                Err(Error::Epsilon(epsilon))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_values(&self, prime: u128) -> Result<(), Error> {
+        if self.value_scale.decimals > ValueScale::MAX_DECIMALS {
+            return Err(Error::TooManyDecimals(self.value_scale.decimals));
+        }
+        if let Some(max_value) = self.value_scale.max_value {
+            if !(max_value > 0.0 && max_value.is_finite()) {
+                return Err(Error::MaxValue(max_value));
+            }
+            // so that the sum of a single user does not wrap around either
+            let max_fixed = self.value_scale.max_fixed().unwrap_or_default();
+            if u128::from(self.per_user_cap)
+                * u128::from(self.attribution.scale())
+                * u128::from(max_fixed)
+                >= prime
+            {
+                return Err(Error::ValueCapTooLarge {
+                    max_value,
+                    field: self.field,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Privacy budget the query spends, if it adds noise: all of epsilon, or the part of it that
+    /// the sample is worth for a dry run.
+    #[must_use]
+    pub fn budget(&self) -> Option<f64> {
+        self.epsilon
+            .map(|epsilon| self.sampling.map_or(epsilon, |s| s.amplify(epsilon)))
+    }
+
+    /// Noise added to every bucket of `aggregate`, when the query computes `count` aggregates
+    /// with fixed-point trigger values in `value_range`, or up to the largest value of the scale
+    /// if that is smaller. Fractional attribution credit scales aggregates up, and their
+    /// sensitivity with them.
+    #[must_use]
+    pub fn noise_params(
+        &self,
+        aggregate: Aggregate,
+        count: usize,
+        value_range: &Range<u32>,
+        confidence: f64,
+    ) -> NoiseParams {
+        self.epsilon
+            .map_or(NoiseParams::NONE, |epsilon| NoiseParams {
+                epsilon: Aggregate::epsilon(epsilon, count),
+                sensitivity: aggregate.sensitivity(self.per_user_cap, &self.capped(value_range))
+                    * f64::from(self.attribution.scale()),
+                confidence,
+            })
+    }
+
+    /// Fixed-point trigger values in `value_range` that are left after capping.
+    fn capped(&self, value_range: &Range<u32>) -> Range<u32> {
+        match self.value_scale.max_fixed() {
+            Some(max) => {
+                let end = value_range.end.min(max.saturating_add(1));
+                value_range.start.min(end)..end
+            }
+            None => value_range.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribution::Model;
+    use crate::error::Error as IpaError;
+    use crate::helpers::error::Error as HelperError;
+    use crate::helpers::models::Aggregate;
+    use crate::helpers::ring::{HelperAddr, Ring};
+    use crate::query::{
+        Error, FieldType, IpaQueryConfig, RunCost, Sampling, Stage, StageDeadlines, ValueScale,
+    };
+    use crate::telemetry::status::QueryProgress;
+    use crate::test_fixture::TestWorld;
+    use std::time::Duration;
+
+    #[test]
+    #[allow(clippy::too_many_lines)]
+    fn validate() {
+        let config = IpaQueryConfig::default();
+        assert_eq!(Ok(()), config.validate());
+
+        let invalid = [
+            (
+                IpaQueryConfig {
+                    max_breakdown_key: 0,
+                    ..config
+                },
+                Error::NoBreakdownKeys,
+            ),
+            (
+                IpaQueryConfig {
+                    max_breakdown_key: 32,
+                    ..config
+                },
+                Error::TooManyBreakdownKeys {
+                    max_breakdown_key: 32,
+                    field: FieldType::Fp31,
+                },
+            ),
+            (
+                IpaQueryConfig {
+                    attribution_window_seconds: 0,
+                    ..config
+                },
+                Error::EmptyAttributionWindow,
+            ),
+            (
+                IpaQueryConfig {
+                    per_user_cap: 31,
+                    ..config
+                },
+                Error::CapTooLarge {
+                    cap: 31,
+                    field: FieldType::Fp31,
+                },
+            ),
+            (
+                // four conversions get 32 units of credit
+                IpaQueryConfig {
+                    per_user_cap: 4,
+                    attribution: Model::EqualCredit,
+                    ..config
+                },
+                Error::CapTooLarge {
+                    cap: 4,
+                    field: FieldType::Fp31,
+                },
+            ),
+            (
+                IpaQueryConfig {
+                    value_scale: ValueScale {
+                        decimals: 10,
+                        max_value: None,
+                    },
+                    ..config
+                },
+                Error::TooManyDecimals(10),
+            ),
+            (
+                IpaQueryConfig {
+                    value_scale: ValueScale {
+                        decimals: 0,
+                        max_value: Some(-1.0),
+                    },
+                    ..config
+                },
+                Error::MaxValue(-1.0),
+            ),
+            (
+                // 3.1 is 31 tenths
+                IpaQueryConfig {
+                    value_scale: ValueScale {
+                        decimals: 1,
+                        max_value: Some(3.1),
+                    },
+                    ..config
+                },
+                Error::ValueCapTooLarge {
+                    max_value: 3.1,
+                    field: FieldType::Fp31,
+                },
+            ),
+            (
+                IpaQueryConfig {
+                    attribution: Model::TimeDecay {
+                        half_life_seconds: 0,
+                    },
+                    ..config
+                },
+                Error::EmptyHalfLife,
+            ),
+            (
+                IpaQueryConfig {
+                    epsilon: Some(0.0),
+                    ..config
+                },
+                Error::Epsilon(0.0),
+            ),
+            (
+                IpaQueryConfig {
+                    sampling: Some(Sampling { rate: 1.5, seed: 0 }),
+                    ..config
+                },
+                Error::SamplingRate(1.5),
+            ),
+            (
+                IpaQueryConfig {
+                    deadlines: StageDeadlines {
+                        sort_ms: Some(0),
+                        ..StageDeadlines::default()
+                    },
+                    ..config
+                },
+                Error::EmptyDeadline(Stage::Sort),
+            ),
+        ];
+        for (config, error) in invalid {
+            assert_eq!(Err(error), config.validate());
+        }
+
+        // a wider field takes what does not fit into the default one
+        let wide = IpaQueryConfig {
+            max_breakdown_key: 1000,
+            per_user_cap: 31,
+            field: FieldType::Fp32BitPrime,
+            ..config
+        };
+        assert_eq!(Ok(()), wide.validate());
+        assert_eq!(Ok(FieldType::Fp32BitPrime), "fp32-bit-prime".parse());
+    }
+
+    #[test]
+    fn noise_params() {
+        let config = IpaQueryConfig {
+            per_user_cap: 3,
+            epsilon: Some(1.0),
+            ..IpaQueryConfig::default()
+        };
+        let params = config.noise_params(Aggregate::Sum, 2, &(1..5), 0.9);
+        assert!((0.5 - params.epsilon).abs() < f64::EPSILON);
+        assert!((12.0 - params.sensitivity).abs() < f64::EPSILON);
+
+        // credit of a conversion is split into eighths
+        let config = IpaQueryConfig {
+            attribution: Model::EqualCredit,
+            ..config
+        };
+        let params = config.noise_params(Aggregate::Count, 1, &(1..5), 0.9);
+        assert!((24.0 - params.sensitivity).abs() < f64::EPSILON);
+
+        // values are capped at 2.5 dollars, 250 cents
+        let config = IpaQueryConfig {
+            attribution: Model::LastTouch,
+            value_scale: ValueScale {
+                decimals: 2,
+                max_value: Some(2.5),
+            },
+            ..config
+        };
+        let params = config.noise_params(Aggregate::Sum, 1, &(1..1000), 0.9);
+        assert!((750.0 - params.sensitivity).abs() < f64::EPSILON);
+        let params = config.noise_params(Aggregate::Sum, 1, &(1..100), 0.9);
+        assert!((297.0 - params.sensitivity).abs() < f64::EPSILON);
+
+        let params = IpaQueryConfig::default().noise_params(Aggregate::Count, 1, &(1..5), 0.9);
+        assert!(params.epsilon.is_infinite());
+    }
+
+    #[test]
+    fn value_scale() {
+        let cents = ValueScale {
+            decimals: 2,
+            max_value: Some(100.0),
+        };
+        assert_eq!(100, cents.unit());
+        assert_eq!(1234, cents.encode(12.34));
+        assert_eq!(1, cents.encode(0.006));
+        assert_eq!(10_000, cents.encode(250.0));
+        assert_eq!(0, cents.encode(-1.0));
+        assert!((12.34 - cents.decode(1234)).abs() < f64::EPSILON);
+
+        // largest value is rounded down to what the scale can represent
+        let yen = ValueScale {
+            decimals: 0,
+            max_value: Some(99.9),
+        };
+        assert_eq!(Some(99), yen.max_fixed());
+        assert_eq!(99, yen.encode(99.6));
+        assert_eq!(1234, ValueScale::default().encode(1234.4));
+        assert_eq!(None, ValueScale::default().max_fixed());
+    }
+
+    #[test]
+    fn sampling() {
+        let sampling = Sampling { rate: 0.1, seed: 1 };
+        let sampled = (0..10_000).filter(|i| sampling.includes(*i)).count();
+        assert!((900..1100).contains(&sampled), "{sampled}");
+        // same records every time, different ones with a different seed
+        assert!((0..100).all(|i| sampling.includes(i) == sampling.includes(i)));
+        let other = Sampling {
+            seed: 2,
+            ..sampling
+        };
+        assert!((0..100).any(|i| sampling.includes(i) != other.includes(i)));
+
+        let config = IpaQueryConfig {
+            epsilon: Some(1.0),
+            sampling: Some(sampling),
+            ..IpaQueryConfig::default()
+        };
+        let budget = config.budget().unwrap();
+        assert!(budget > 0.0 && budget < 0.2, "{budget}");
+
+        let cost = sampling.extrapolate(&RunCost {
+            records: 100,
+            elapsed: Duration::from_secs(2),
+            bytes: 5000,
+        });
+        assert_eq!(
+            RunCost {
+                records: 1000,
+                elapsed: Duration::from_secs(20),
+                bytes: 50_000,
+            },
+            cost
+        );
+    }
+
+    #[tokio::test]
+    async fn stage_deadline() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let deadlines = StageDeadlines {
+            sort_ms: Some(10),
+            ..StageDeadlines::default()
+        };
+        let progress = QueryProgress::default();
+
+        // no deadline for this stage
+        let value = deadlines
+            .run(Stage::Conversion, ctx[0].helper_ring, None, async {
+                Ok::<_, IpaError>(1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, value);
+
+        // helper on the left never sends anything
+        let hung = ctx[0].helper_ring.receive::<u8>(HelperAddr::Left);
+        let result = deadlines
+            .run(Stage::Sort, ctx[0].helper_ring, Some(&progress), async {
+                hung.await.map_err(IpaError::from)
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(IpaError::Query(Error::DeadlineExceeded {
+                stage: Stage::Sort,
+                limit,
+            })) if limit == Duration::from_millis(10)
+        ));
+        assert_eq!("sort", progress.snapshot("query").stage);
+
+        // peers learn about it
+        assert!(matches!(
+            ctx[1].helper_ring.receive::<u8>(HelperAddr::Left).await,
+            Err(HelperError::Aborted {
+                by: Some(HelperAddr::Left),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancelled_stage() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+        let progress = QueryProgress::default();
+
+        let deadlines = StageDeadlines::default();
+        let hung = ctx[0].helper_ring.receive::<u8>(HelperAddr::Left);
+        let (result, ()) = tokio::join!(
+            deadlines.run(
+                Stage::Attribution,
+                ctx[0].helper_ring,
+                Some(&progress),
+                async { hung.await.map_err(IpaError::from) }
+            ),
+            async {
+                tokio::task::yield_now().await;
+                progress.request_cancel();
+            }
+        );
+        assert!(matches!(
+            result,
+            Err(IpaError::Query(Error::Cancelled(Stage::Attribution)))
+        ));
+        assert!(matches!(
+            ctx[2].helper_ring.receive::<u8>(HelperAddr::Right).await,
+            Err(HelperError::Aborted {
+                by: Some(HelperAddr::Right),
+                ..
+            })
+        ));
+    }
+}
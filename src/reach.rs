@@ -0,0 +1,245 @@
+//!
+//! Reach of ads: the number of distinct users exposed to every breakdown key, without any
+//! attribution to conversions. Impressions are sorted obliviously by breakdown key and match
+//! key, so that impressions of the same user with the same breakdown key end up next to each
+//! other, and only the first of every such run is counted. Helpers learn neither which
+//! impressions belong to the same user nor how many of them there are.
+//!
+//! However many impressions a user has, they add at most one to the reach of a breakdown key.
+//! That caps the contribution of a user without a separate capping stage, and noise is
+//! calibrated for it by [`noise_params`].
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::Ring;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::sorting_network::{sort, Row};
+use crate::verify::NoiseParams;
+use std::future::Future;
+use thiserror::Error;
+
+const BREAKDOWN_KEY: usize = 0;
+const MATCH_KEY: usize = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Expected {expected} equality bits, but got {actual}")]
+    EqualityBitCount { expected: usize, actual: usize },
+}
+
+/// Noise added to the reach of every breakdown key, which a single user changes by one at most.
+#[must_use]
+pub fn noise_params(epsilon: f64, confidence: f64) -> NoiseParams {
+    NoiseParams {
+        epsilon,
+        sensitivity: 1.0,
+        confidence,
+    }
+}
+
+/// Shares of the number of distinct match keys among `impressions` with every breakdown key in
+/// `0..buckets`. Every impression is a pair of shares of its breakdown key and its match key.
+///
+/// `compare` is used as the comparator of [`sort`] and must order rows by breakdown key and
+/// match key. Any order does, as long as rows with the same keys end up next to each other.
+/// `equal` gets pairs of shares and must return, for every pair, a share of one if they are
+/// equal and a share of zero otherwise. It reserves indices from `next_index` the same way as
+/// the comparator of [`sort`].
+///
+/// ## Errors
+/// If sorting, comparison or multiplication fails, or `equal` returns the wrong number of bits.
+pub async fn reach<F, R, C, CFut, E, EFut>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    impressions: &[(ReplicatedSecretSharing<F>, ReplicatedSecretSharing<F>)],
+    buckets: usize,
+    compare: C,
+    mut equal: E,
+) -> Res<Vec<ReplicatedSecretSharing<F>>>
+where
+    F: Field,
+    R: Ring,
+    C: FnMut(&mut u128, Vec<(Row<F>, Row<F>)>) -> CFut,
+    CFut: Future<Output = Res<Vec<ReplicatedSecretSharing<F>>>>,
+    E: FnMut(&mut u128, Vec<(ReplicatedSecretSharing<F>, ReplicatedSecretSharing<F>)>) -> EFut,
+    EFut: Future<Output = Res<Vec<ReplicatedSecretSharing<F>>>>,
+{
+    let zero = ReplicatedSecretSharing::new(F::ZERO, F::ZERO);
+    if impressions.is_empty() {
+        return Ok(vec![zero; buckets]);
+    }
+
+    let mut rows = impressions
+        .iter()
+        .map(|&(breakdown_key, match_key)| vec![breakdown_key, match_key])
+        .collect::<Vec<_>>();
+    sort(ctx, next_index, &mut rows, compare).await?;
+
+    // a row starts a run if either of its keys differs from the one of the row before it
+    let pairs = rows
+        .windows(2)
+        .flat_map(|w| [BREAKDOWN_KEY, MATCH_KEY].map(|c| (w[0][c], w[1][c])))
+        .collect::<Vec<_>>();
+    let same = checked(pairs.len(), equal(next_index, pairs).await?)?;
+    let (same_breakdown_key, same_match_key): (Vec<_>, Vec<_>) =
+        same.chunks(2).map(|s| (s[0], s[1])).unzip();
+    let index = *next_index;
+    *next_index += same_breakdown_key.len() as u128;
+    let same = ctx
+        .multiply_batch(index, &same_breakdown_key, &same_match_key)
+        .await?;
+    let one = ctx.share_known(F::ONE);
+    let first = std::iter::once(one)
+        .chain(same.into_iter().map(|s| one - s))
+        .collect::<Vec<_>>();
+
+    // all buckets are counted in the same round
+    let pairs = (0..buckets)
+        .flat_map(|b| {
+            let bucket = ctx.share_known(F::from(b as u128));
+            rows.iter().map(move |r| (r[BREAKDOWN_KEY], bucket))
+        })
+        .collect::<Vec<_>>();
+    let in_bucket = checked(pairs.len(), equal(next_index, pairs).await?)?;
+    let first = first
+        .iter()
+        .cycle()
+        .take(in_bucket.len())
+        .copied()
+        .collect::<Vec<_>>();
+    let index = *next_index;
+    *next_index += in_bucket.len() as u128;
+    let counted = ctx.multiply_batch(index, &first, &in_bucket).await?;
+
+    Ok(counted
+        .chunks(rows.len())
+        .map(|c| c.iter().fold(zero, |acc, s| acc + *s))
+        .collect())
+}
+
+fn checked<T>(expected: usize, bits: Vec<T>) -> Res<Vec<T>> {
+    if bits.len() == expected {
+        Ok(bits)
+    } else {
+        Err(Error::EqualityBitCount {
+            expected,
+            actual: bits.len(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Res;
+    use crate::field::Fp31;
+    use crate::helpers::ring::mock::TestHelper;
+    use crate::reach::reach;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::reveal::reveal_vec;
+    use crate::securemul::ProtocolContext;
+    use crate::sorting_network::Row;
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use rand::rngs::mock::StepRng;
+
+    type Share = ReplicatedSecretSharing<Fp31>;
+
+    /// Reveals both sides of every pair and compares them with `f`, which is only good enough to
+    /// test what is done with the result.
+    async fn in_the_clear(
+        ctx: &ProtocolContext<'_, TestHelper>,
+        index: u128,
+        pairs: Vec<(Row<Fp31>, Row<Fp31>)>,
+        f: fn(&[u8], &[u8]) -> bool,
+    ) -> Res<Vec<Share>> {
+        let width = pairs.first().map_or(0, |(a, _)| a.len());
+        let (a, b): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        let values = a.into_iter().chain(b).flatten().collect::<Vec<_>>();
+        let values = reveal_vec(ctx, index, &values)
+            .await?
+            .into_iter()
+            .map(u8::from)
+            .collect::<Vec<_>>();
+        let (a, b) = values.split_at(values.len() / 2);
+        Ok(a.chunks(width.max(1))
+            .zip(b.chunks(width.max(1)))
+            .map(|(a, b)| ctx.share_known(Fp31::from(u128::from(f(a, b)))))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn distinct_users() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+
+        // (breakdown key, match key)
+        let input: [(u128, u128); 8] = [
+            (0, 7),
+            (1, 7),
+            (0, 3),
+            (0, 7),
+            (2, 5),
+            (0, 3),
+            (1, 4),
+            (1, 7),
+        ];
+        let mut rand = StepRng::new(1, 7);
+        let mut impressions = [Vec::new(), Vec::new(), Vec::new()];
+        for (breakdown_key, match_key) in input {
+            let bk = Share::share(Fp31::from(breakdown_key), &mut rand);
+            let mk = Share::share(Fp31::from(match_key), &mut rand);
+            for (i, helper) in impressions.iter_mut().enumerate() {
+                helper.push((bk[i], mk[i]));
+            }
+        }
+
+        let compare = |ctx| {
+            move |next: &mut u128, pairs: Vec<(Row<Fp31>, Row<Fp31>)>| {
+                let index = *next;
+                *next += 1;
+                in_the_clear(ctx, index, pairs, |a, b| a > b)
+            }
+        };
+        let equal = |ctx| {
+            move |next: &mut u128, pairs: Vec<(Share, Share)>| {
+                let index = *next;
+                *next += 1;
+                let pairs = pairs.into_iter().map(|(a, b)| (vec![a], vec![b])).collect();
+                in_the_clear(ctx, index, pairs, |a, b| a == b)
+            }
+        };
+        let mut next_index = [1; 3];
+        let [n0, n1, n2] = &mut next_index;
+        let (r0, r1, r2) = futures::try_join!(
+            reach(
+                &ctx[0],
+                n0,
+                &impressions[0],
+                4,
+                compare(&ctx[0]),
+                equal(&ctx[0])
+            ),
+            reach(
+                &ctx[1],
+                n1,
+                &impressions[1],
+                4,
+                compare(&ctx[1]),
+                equal(&ctx[1])
+            ),
+            reach(
+                &ctx[2],
+                n2,
+                &impressions[2],
+                4,
+                compare(&ctx[2]),
+                equal(&ctx[2])
+            ),
+        )
+        .unwrap();
+
+        let expected = [2_u128, 2, 1, 0].map(Fp31::from);
+        assert_eq!(expected.to_vec(), reconstruct(&[r0, r1, r2]));
+    }
+}
@@ -5,6 +5,7 @@ use std::{
 };
 
 use crate::field::Field;
+use rand::{Rng, RngCore};
 
 #[derive(Clone, Copy, PartialEq)]
 pub struct ReplicatedSecretSharing<T>(T, T);
@@ -24,6 +25,29 @@ impl<T: Field> ReplicatedSecretSharing<T> {
     pub fn as_tuple(&self) -> (T, T) {
         (self.0, self.1)
     }
+
+    /// Splits `input` into three replicated secret shares, one for each helper. Helper `i` gets
+    /// `(x_i, x_i+1)` where `x_1 + x_2 + x_3 = input`.
+    pub fn share<R: RngCore>(input: T, rng: &mut R) -> [Self; 3] {
+        let x1 = T::from(rng.gen::<u128>());
+        let x2 = T::from(rng.gen::<u128>());
+        let x3 = input - (x1 + x2);
+
+        [Self(x1, x2), Self(x2, x3), Self(x3, x1)]
+    }
+
+    /// Reconstructs the secret value from the shares held by all three helpers. Returns `None`
+    /// if shares are not consistent with each other, i.e. some helper holds a different value
+    /// than its neighbour for the same part of the secret.
+    #[must_use]
+    pub fn reconstruct(shares: &[Self; 3]) -> Option<T> {
+        let [a, b, c] = shares;
+        if a.1 == b.0 && b.1 == c.0 && c.1 == a.0 {
+            Some(a.0 + b.0 + c.0)
+        } else {
+            None
+        }
+    }
 }
 
 impl<T: Field> Add for ReplicatedSecretSharing<T> {
@@ -62,7 +86,7 @@ impl<T: Field> Mul<T> for ReplicatedSecretSharing<T> {
 mod tests {
     use crate::replicated_secret_sharing::ReplicatedSecretSharing;
 
-    use crate::field::Fp31;
+    use crate::field::{Field, Fp31};
 
     fn secret_share(
         a: u8,
@@ -113,6 +137,19 @@ mod tests {
         assert_secret_shared_value(res1, res2, res3, expected_output);
     }
 
+    #[test]
+    fn share_reconstruct() {
+        let mut rng = rand::thread_rng();
+        for v in 0..Fp31::PRIME {
+            let input = Fp31::from(v);
+            let mut shares = ReplicatedSecretSharing::share(input, &mut rng);
+            assert_eq!(Some(input), ReplicatedSecretSharing::reconstruct(&shares));
+
+            shares[1].1 += Fp31::ONE;
+            assert_eq!(None, ReplicatedSecretSharing::reconstruct(&shares));
+        }
+    }
+
     #[test]
     fn test_simple_addition() {
         addition_test_case((1, 0, 0), (1, 0, 0), 2);
@@ -1,14 +1,55 @@
+use crate::threshold::BlindingKey;
 use crate::threshold::DecryptionKey as ThresholdDecryptionKey;
 use crate::threshold::{Ciphertext, RistrettoPoint};
 
+use rust_elgamal::CompressedRistretto;
 use std::collections::HashMap;
 use std::fmt;
+use subtle_ng::{Choice, ConstantTimeEq};
+use thiserror::Error;
+
+/// Size of an encrypted match key in the encoding of [`EncryptedMatchkeys::to_bytes`].
+const CIPHERTEXT_SIZE: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("encoding ends before the match key of provider {0}")]
+    Truncated(usize),
+    #[error("{0} bytes follow the last match key")]
+    TrailingBytes(usize),
+    #[error("provider {0} is not valid UTF-8")]
+    BadProvider(usize),
+    #[error("provider {0} is not listed after the provider before it")]
+    NotCanonical(usize),
+    #[error("match key of provider {0} is not a canonical encoding of two points")]
+    BadCiphertext(usize),
+    #[error("{0} match keys do not fit in the encoding")]
+    TooMany(usize),
+    #[error("provider name of {0} bytes does not fit in the encoding")]
+    ProviderTooLong(usize),
+}
 
-#[derive(Debug)]
 pub struct EncryptedMatchkeys {
     match_keys: HashMap<String, Ciphertext>,
 }
 
+/// Shows the providers only: ciphertexts of the same match keys can be linked until they are
+/// rerandomized, so they are kept out of logs.
+impl fmt::Debug for EncryptedMatchkeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedMatchkeys")
+            .field("providers", &providers(&self.match_keys))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Providers of `match_keys`, sorted.
+fn providers<V>(match_keys: &HashMap<String, V>) -> Vec<&str> {
+    let mut providers = match_keys.keys().map(String::as_str).collect::<Vec<_>>();
+    providers.sort_unstable();
+    providers
+}
+
 impl EncryptedMatchkeys {
     #[cfg(test)]
     #[must_use]
@@ -21,6 +62,70 @@ impl EncryptedMatchkeys {
         EncryptedMatchkeys { match_keys }
     }
 
+    /// Canonical encoding of the match keys, which every implementation must produce for the
+    /// same match keys:
+    ///
+    /// ```text
+    /// | count (u8) | provider length (u8) | provider | ciphertext (64 bytes) | ...
+    /// ```
+    ///
+    /// with providers sorted by their bytes and every ciphertext encoded as its two points,
+    /// compressed.
+    ///
+    /// ## Errors
+    /// If there are more than 255 match keys or a provider is longer than 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let count = self.match_keys.len();
+        let mut out = vec![u8::try_from(count).map_err(|_| Error::TooMany(count))?];
+        let mut match_keys = self.match_keys.iter().collect::<Vec<_>>();
+        match_keys.sort_unstable_by_key(|(provider, _)| provider.as_bytes());
+        for (provider, c) in match_keys {
+            let len = provider.len();
+            out.push(u8::try_from(len).map_err(|_| Error::ProviderTooLong(len))?);
+            out.extend_from_slice(provider.as_bytes());
+            let (c0, c1) = c.inner();
+            out.extend_from_slice(c0.compress().as_bytes());
+            out.extend_from_slice(c1.compress().as_bytes());
+        }
+        Ok(out)
+    }
+
+    /// Decodes match keys encoded with [`Self::to_bytes`]. Only the canonical encoding is
+    /// accepted, so no two byte strings decode to the same match keys.
+    ///
+    /// ## Errors
+    /// If `bytes` are not the canonical encoding of some match keys.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let (&count, mut rest) = bytes.split_first().ok_or(Error::Truncated(0))?;
+        let mut match_keys = HashMap::with_capacity(usize::from(count));
+        let mut previous: Option<&[u8]> = None;
+        for i in 0..usize::from(count) {
+            let (&len, tail) = rest.split_first().ok_or(Error::Truncated(i))?;
+            let len = usize::from(len);
+            if tail.len() < len + CIPHERTEXT_SIZE {
+                return Err(Error::Truncated(i));
+            }
+            let (provider, tail) = tail.split_at(len);
+            let (c, tail) = tail.split_at(CIPHERTEXT_SIZE);
+            rest = tail;
+
+            if previous.map_or(false, |p| p >= provider) {
+                return Err(Error::NotCanonical(i));
+            }
+            previous = Some(provider);
+            let provider = std::str::from_utf8(provider).map_err(|_| Error::BadProvider(i))?;
+            let point = |b: &[u8]| CompressedRistretto::from_slice(b).decompress();
+            let (c0, c1) = point(&c[..32])
+                .zip(point(&c[32..]))
+                .ok_or(Error::BadCiphertext(i))?;
+            match_keys.insert(provider.to_owned(), Ciphertext::from((c0, c1)));
+        }
+        if !rest.is_empty() {
+            return Err(Error::TrailingBytes(rest.len()));
+        }
+        Ok(Self::from(match_keys))
+    }
+
     #[must_use]
     pub fn threshold_decrypt(
         &self,
@@ -34,6 +139,21 @@ impl EncryptedMatchkeys {
         EncryptedMatchkeys::from(partially_decrypted_matchkeys)
     }
 
+    /// Applies the blinding exponent of a helper to every match key. Once all helpers have done
+    /// that, [`Self::decrypt`] produces blinded match keys that can be compared with
+    /// [`DecryptedMatchkeys::count_matches`] without revealing the points match keys map to.
+    #[must_use]
+    pub fn blind(&self, blinding: &BlindingKey) -> EncryptedMatchkeys {
+        let ciphertexts = self.match_keys.values().copied().collect::<Vec<_>>();
+        let blinded: HashMap<_, _> = self
+            .match_keys
+            .keys()
+            .cloned()
+            .zip(blinding.blind_batch(&ciphertexts))
+            .collect();
+        EncryptedMatchkeys::from(blinded)
+    }
+
     #[must_use]
     pub fn decrypt(&self, matchkey_decrypt: &ThresholdDecryptionKey) -> DecryptedMatchkeys {
         let decrypted_matchkeys: HashMap<_, _> = self
@@ -45,21 +165,75 @@ impl EncryptedMatchkeys {
     }
 }
 
-#[derive(Debug)]
 pub struct DecryptedMatchkeys {
     match_keys: HashMap<String, RistrettoPoint>,
 }
 
+/// Shows the providers only, never the points match keys decrypt to.
+impl fmt::Debug for DecryptedMatchkeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptedMatchkeys")
+            .field("providers", &providers(&self.match_keys))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Two sets of match keys are equal if any match key of one is equal to any match key of the
+/// other.
 impl PartialEq for DecryptedMatchkeys {
     fn eq(&self, other: &Self) -> bool {
-        any_matches(self.match_keys.values(), &other.match_keys.values())
+        self.count_matches(other) > 0
     }
 }
 
 impl DecryptedMatchkeys {
+    /// Number of pairs of equal match keys, one from each set.
+    ///
+    /// Every match key is compared with every other one in constant time, in the order of
+    /// providers, and no comparison is skipped. How long this takes depends on the number of
+    /// match keys only, not on which of them match or how many.
     #[must_use]
     pub fn count_matches(&self, other: &Self) -> usize {
-        n_matches(self.match_keys.values(), &other.match_keys.values())
+        let theirs = other.ordered();
+        self.ordered()
+            .into_iter()
+            .flat_map(|a| theirs.iter().map(move |b| a.ct_eq(b).unwrap_u8()))
+            .map(usize::from)
+            .sum()
+    }
+
+    /// Whether match keys of the first provider in `providers` that both sets have are equal,
+    /// and `false` if there is no such provider. Providers that come earlier are preferred, so a
+    /// provider is a fallback for the ones before it.
+    ///
+    /// Which providers a report has a match key of is public, whether the keys of a provider are
+    /// equal is not. Every provider that both sets have is compared in constant time, including
+    /// the ones after the provider that decides, so how long this takes does not reveal which
+    /// one that was.
+    #[must_use]
+    pub fn matches_by_priority(&self, other: &Self, providers: &[&str]) -> bool {
+        let mut decided = Choice::from(0);
+        let mut matched = Choice::from(0);
+        for provider in providers {
+            let (both, equal) = match (
+                self.match_keys.get(*provider),
+                other.match_keys.get(*provider),
+            ) {
+                (Some(a), Some(b)) => (Choice::from(1), a.ct_eq(b)),
+                _ => (Choice::from(0), Choice::from(0)),
+            };
+            matched |= both & !decided & equal;
+            decided |= both;
+        }
+        matched.into()
+    }
+
+    /// Points sorted by provider, which is public, so that iteration order does not depend on
+    /// how a `HashMap` happens to lay them out.
+    fn ordered(&self) -> Vec<&RistrettoPoint> {
+        let mut match_keys = self.match_keys.iter().collect::<Vec<_>>();
+        match_keys.sort_unstable_by_key(|(provider, _)| *provider);
+        match_keys.into_iter().map(|(_, point)| point).collect()
     }
 }
 
@@ -115,6 +289,7 @@ impl From<HashMap<String, Ciphertext>> for EncryptedMatchkeys {
     }
 }
 
+#[cfg(test)]
 fn n_matches<T>(
     a: impl Iterator<Item = impl PartialEq<T>>,
     b: &(impl Iterator<Item = T> + Clone),
@@ -124,9 +299,45 @@ fn n_matches<T>(
         .sum()
 }
 
-fn any_matches<T>(
-    a: impl Iterator<Item = impl PartialEq<T>>,
-    b: &(impl Iterator<Item = T> + Clone),
-) -> bool {
-    a.into_iter().any(|x| b.clone().any(|y| x.eq(&y)))
+#[cfg(test)]
+mod tests {
+    use crate::report::DecryptedMatchkeys;
+    use crate::threshold::hash_to_ristretto;
+    use std::collections::HashMap;
+
+    fn matchkeys(mks: &[(&str, &str)]) -> DecryptedMatchkeys {
+        mks.iter()
+            .map(|(provider, mk)| ((*provider).to_owned(), hash_to_ristretto(mk.as_bytes())))
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+
+    #[test]
+    fn count_every_pair() {
+        let a = matchkeys(&[("p1", "x"), ("p2", "y"), ("p3", "z")]);
+        let b = matchkeys(&[("p1", "x"), ("p2", "x"), ("p4", "z")]);
+        let c = matchkeys(&[("p1", "w")]);
+
+        // keys are compared across providers too
+        assert_eq!(3, a.count_matches(&b));
+        assert_eq!(3, b.count_matches(&a));
+        assert_eq!(0, a.count_matches(&c));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn fallback_providers() {
+        let a = matchkeys(&[("p1", "x"), ("p2", "y")]);
+        let b = matchkeys(&[("p1", "w"), ("p2", "y")]);
+        let c = matchkeys(&[("p2", "y"), ("p3", "z")]);
+
+        // first provider both have decides, even if a later one would match
+        assert!(!a.matches_by_priority(&b, &["p1", "p2"]));
+        assert!(a.matches_by_priority(&b, &["p2", "p1"]));
+        // p1 is missing from c, so p2 is used instead
+        assert!(a.matches_by_priority(&c, &["p1", "p2"]));
+        assert!(!a.matches_by_priority(&c, &["p3"]));
+        assert!(!a.matches_by_priority(&c, &[]));
+    }
 }
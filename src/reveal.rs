@@ -0,0 +1,175 @@
+//!
+//! Opens secret-shared values to all three helpers. Every helper holds two of the three parts of
+//! a value and is missing the one held by both of its neighbours, so a single exchange with the
+//! neighbours is enough to reveal any number of values at once.
+//!
+//! Helpers get the missing parts from the helper on the left and, to detect a helper that lies
+//! about its shares, a digest of the same parts from the helper on the right. Sending the digest
+//! instead of the values keeps the cost of the check constant no matter how many values are
+//! revealed, and both messages are exchanged in the same round.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::codec::write_fields;
+use crate::helpers::ring::{message, Ring};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::step;
+use crate::telemetry::StepTimer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::Instrument;
+
+const STEP: &str = step::REVEAL;
+
+/// Digest of the parts of revealed values that the helper on the left is missing.
+#[derive(Debug, Serialize, Deserialize)]
+struct RevealDigest([u8; 32]);
+
+message!(RevealDigest => "reveal.digest");
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Expected to reveal {expected} values, but peer sent {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("Peers disagree about the shares of revealed values")]
+    Inconsistent,
+}
+
+/// Reveals a single value. See [`reveal_vec`].
+///
+/// ## Errors
+/// Same as [`reveal_vec`].
+pub async fn reveal<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    index: u128,
+    share: ReplicatedSecretSharing<F>,
+) -> Res<F> {
+    Ok(reveal_vec(ctx, index, &[share]).await?[0])
+}
+
+/// Reveals all `shares` to every helper in a single round. `index` must be the same on all
+/// three helpers, it identifies the batch in traces and errors.
+///
+/// ## Errors
+/// If communication with peers fails, or shares held by peers are not consistent with the ones
+/// held by this helper, which means that some helper does not follow the protocol.
+pub async fn reveal_vec<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    index: u128,
+    shares: &[ReplicatedSecretSharing<F>],
+) -> Res<Vec<F>> {
+    reveal_inner(ctx, shares)
+        .instrument(tracing::debug_span!(
+            "step",
+            helper = %ctx.identity,
+            name = STEP,
+            record = index,
+            values = shares.len()
+        ))
+        .await
+        .map_err(|e| e.in_step(ctx.identity, STEP, index))
+}
+
+async fn reveal_inner<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    shares: &[ReplicatedSecretSharing<F>],
+) -> Res<Vec<F>> {
+    let _timer = StepTimer::start(STEP);
+    let (left, right): (Vec<F>, Vec<F>) =
+        shares.iter().map(ReplicatedSecretSharing::as_tuple).unzip();
+
+    // helper on the right is missing our left parts, helper on the left is missing the right
+    // ones and checks them against what it gets from its own left
+    let [left_peer, right_peer] = ctx.peers();
+    let _round = ctx.rounds.map(|rounds| rounds.wait(STEP));
+    let ((), (), missing, RevealDigest(expected)) = futures::try_join!(
+        right_peer.send_fields(&left),
+        left_peer.send(RevealDigest(digest(&right))),
+        left_peer.receive_fields::<F>(),
+        right_peer.receive::<RevealDigest>(),
+    )?;
+
+    if missing.len() != shares.len() {
+        return Err(Error::LengthMismatch {
+            expected: shares.len(),
+            actual: missing.len(),
+        }
+        .into());
+    }
+    if digest(&missing) != expected {
+        return Err(Error::Inconsistent.into());
+    }
+
+    Ok(left
+        .into_iter()
+        .zip(right)
+        .zip(missing)
+        .map(|((l, r), m)| l + r + m)
+        .collect())
+}
+
+fn digest<F: Field>(values: &[F]) -> [u8; 32] {
+    let mut bytes = Vec::new();
+    write_fields(values, &mut bytes);
+    Sha256::digest(&bytes).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::field::Fp31;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::reveal::{reveal, reveal_vec, Error as RevealError};
+    use crate::telemetry::rounds::RoundCounter;
+    use crate::test_fixture::TestWorld;
+    use rand::rngs::mock::StepRng;
+
+    #[tokio::test]
+    async fn reveal_batch() {
+        let world = TestWorld::new();
+        let counter = RoundCounter::default();
+        let ctx = world.contexts().map(|ctx| ctx.with_rounds(&counter));
+
+        let mut rand = StepRng::new(1, 7);
+        let values = (0..20_u128).map(Fp31::from).collect::<Vec<_>>();
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for v in &values {
+            for (helper, share) in shares
+                .iter_mut()
+                .zip(ReplicatedSecretSharing::share(*v, &mut rand))
+            {
+                helper.push(share);
+            }
+        }
+
+        let revealed = futures::try_join!(
+            reveal_vec(&ctx[0], 1, &shares[0]),
+            reveal_vec(&ctx[1], 1, &shares[1]),
+            reveal_vec(&ctx[2], 1, &shares[2]),
+        )
+        .unwrap();
+        assert_eq!(
+            (&values, &values, &values),
+            (&revealed.0, &revealed.1, &revealed.2)
+        );
+        // helpers run concurrently here, so they share the round
+        assert_eq!(1, counter.report().step("reveal").rounds);
+
+        // first helper lies about the second part of the first value, the helper on its left
+        // is the one missing that part and detects it
+        let (a, b) = shares[0][0].as_tuple();
+        let lie = ReplicatedSecretSharing::new(a, b + Fp31::from(1_u128));
+        let (_, unaffected, cheated) = futures::join!(
+            reveal(&ctx[0], 2, lie),
+            reveal(&ctx[1], 2, shares[1][0]),
+            reveal(&ctx[2], 2, shares[2][0]),
+        );
+        assert_eq!(values[0], unaffected.unwrap());
+        assert!(matches!(
+            cheated,
+            Err(Error::Step { inner, .. }) if matches!(*inner, Error::Reveal(RevealError::Inconsistent))
+        ));
+    }
+}
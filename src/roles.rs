@@ -0,0 +1,171 @@
+//!
+//! Check that every helper plays the role its peers expect of it, before a stage of a query
+//! starts. Some protocols are not symmetric: which of its PRSS values a helper uses for what,
+//! which pair of helpers shuffles rows in which pass, which part of a random bit a helper holds,
+//! all depend on its [`Identity`]. A helper that was started with the wrong identity, or that
+//! connected to its peers the wrong way round, does its part of those protocols as if it were
+//! another helper, and the query produces garbage shares without anything failing.
+//!
+//! Every helper sends its identity and the stage it is about to start to both peers, in a single
+//! round. The helper on its left must say it is the helper on the left of this one, and so on, and
+//! all of them must be at the same stage.
+//!
+use crate::error::Res;
+use crate::helpers::ring::{message, HelperAddr, Identity, Ring};
+use crate::securemul::ProtocolContext;
+use crate::step;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const STEP: &str = step::ROLE_CHECK;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("helper on the {peer:?} of {ours} should be {expected}, but says it is {actual}")]
+    RoleMismatch {
+        peer: HelperAddr,
+        ours: Identity,
+        expected: Identity,
+        actual: Identity,
+    },
+    #[error("helper on the {peer:?} is about to start stage {theirs}, but this helper {ours}")]
+    StageMismatch {
+        peer: HelperAddr,
+        ours: &'static str,
+        theirs: String,
+    },
+}
+
+/// What a helper tells its peers before a stage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoleMessage {
+    identity: Identity,
+    stage: String,
+}
+
+message!(RoleMessage => "roles.role");
+
+/// Checks that both peers are the helpers [`ctx.identity`] expects on its left and right, and
+/// that they are about to start `stage` as well. All three helpers must call this at the same
+/// point of the query.
+///
+/// [`ctx.identity`]: ProtocolContext::identity
+///
+/// ## Errors
+/// If communication with peers fails, or either of them is not the helper it should be or at
+/// another stage.
+pub async fn check_roles<R: Ring>(ctx: &ProtocolContext<'_, R>, stage: &'static str) -> Res<()> {
+    let mine = RoleMessage {
+        identity: ctx.identity,
+        stage: stage.to_owned(),
+    };
+
+    let _round = ctx.rounds.map(|rounds| rounds.wait(STEP));
+    let ((), (left, right)) = futures::try_join!(
+        ctx.helper_ring.broadcast(mine),
+        ctx.helper_ring.receive_from_both::<RoleMessage>(),
+    )?;
+
+    for (peer, theirs) in [(HelperAddr::Left, left), (HelperAddr::Right, right)] {
+        let expected = ctx.identity.peer(peer);
+        if theirs.identity != expected {
+            return Err(Error::RoleMismatch {
+                peer,
+                ours: ctx.identity,
+                expected,
+                actual: theirs.identity,
+            }
+            .into());
+        }
+        if theirs.stage != stage {
+            return Err(Error::StageMismatch {
+                peer,
+                ours: stage,
+                theirs: theirs.stage,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::helpers::ring::{HelperAddr, Identity};
+    use crate::roles::{check_roles, Error as RoleError};
+    use crate::securemul::ProtocolContext;
+    use crate::test_fixture::TestWorld;
+
+    #[tokio::test]
+    async fn agree() {
+        let world = TestWorld::new();
+        let [c0, c1, c2] = world.contexts();
+        let res = futures::join!(
+            check_roles(&c0, "sort"),
+            check_roles(&c1, "sort"),
+            check_roles(&c2, "sort"),
+        );
+        assert!(matches!(res, (Ok(()), Ok(()), Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn swapped_identities() {
+        // first two helpers were started with each other's identity
+        let world = TestWorld::new();
+        let [c0, c1, c2] = world.contexts();
+        let c0 = ProtocolContext {
+            identity: Identity::H2,
+            ..c0
+        };
+        let c1 = ProtocolContext {
+            identity: Identity::H1,
+            ..c1
+        };
+        let (r0, _, r2) = futures::join!(
+            check_roles(&c0, "sort"),
+            check_roles(&c1, "sort"),
+            check_roles(&c2, "sort"),
+        );
+        // third helper sits between the first two, which it expects to be on its other sides
+        assert!(matches!(
+            r2,
+            Err(Error::Roles(RoleError::RoleMismatch {
+                peer: HelperAddr::Left,
+                ours: Identity::H3,
+                expected: Identity::H2,
+                actual: Identity::H1,
+            }))
+        ));
+        assert!(matches!(
+            r0,
+            Err(Error::Roles(RoleError::RoleMismatch { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stage_mismatch() {
+        let world = TestWorld::new();
+        let [c0, c1, c2] = world.contexts();
+        let (r0, r1, _) = futures::join!(
+            check_roles(&c0, "sort"),
+            check_roles(&c1, "sort"),
+            check_roles(&c2, "attribution"),
+        );
+        assert!(matches!(
+            r0,
+            Err(Error::Roles(RoleError::StageMismatch {
+                peer: HelperAddr::Left,
+                ours: "sort",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            r1,
+            Err(Error::Roles(RoleError::StageMismatch {
+                peer: HelperAddr::Right,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,442 @@
+//!
+//! Named end-to-end benchmark scenarios, for tracking how fast helpers are from one release to
+//! the next. Scenarios are defined in TOML, one `[[scenario]]` table each:
+//!
+//! ```toml
+//! [[scenario]]
+//! name = "100k-malicious"
+//! records = 100000
+//! field = "Fp31"
+//! security = "Malicious"
+//! transport = "tcp"
+//! repeat = 3
+//!
+//! [scenario.parallelism]
+//! max_records_per_step = 4096
+//! ```
+//!
+//! Everything but the name and the number of records is optional, see [`Scenario`] for the
+//! defaults. Every scenario runs the same workload: helpers get `records` shared values, each
+//! with a bit that says whether it counts, multiply every value by its bit and reveal the sum of
+//! the products, which must match the sum computed in the clear. Malicious scenarios upgrade the
+//! inputs and validate every product before the sum is revealed, see
+//! [`crate::context::MaliciousContext`].
+//!
+//! Helpers run inside one process, over either the in-memory ring unit tests use or TCP
+//! connections on localhost. [`run`] measures every repetition of a scenario on a fresh set of
+//! helpers, setup excluded, and returns a [`Report`] that serializes to JSON for whatever tracks
+//! the numbers over time.
+//!
+use crate::context::{Context, MaliciousContext};
+use crate::entropy::Entropy;
+use crate::error::Res;
+use crate::field::{Field, Fp31, Fp32BitPrime};
+use crate::helpers::codec::Bincode;
+use crate::helpers::ring::mock::make_three_with_codec;
+use crate::helpers::ring::{Identity, Ring};
+use crate::helpers::tcp::{TcpRing, TcpRingConfig};
+use crate::parallelism::{Parallelism, ParallelismConfig};
+use crate::prss::{Participant, ParticipantSetup};
+use crate::query::{FieldType, SecurityMode};
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::reveal::reveal;
+use crate::securemul::ProtocolContext;
+use crate::telemetry::rounds::RoundCounter;
+use crate::telemetry::status::QueryProgress;
+use rand::{CryptoRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to parse scenarios: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("scenario {0} is defined more than once")]
+    Duplicate(String),
+    #[error("scenario {name} is invalid: {reason}")]
+    Invalid { name: String, reason: &'static str },
+    #[error("scenario {name} revealed {actual} instead of {expected}")]
+    Mismatch {
+        name: String,
+        actual: u128,
+        expected: u128,
+    },
+}
+
+/// How helpers of a scenario talk to each other.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// In-memory ring, which measures the protocol without the cost of the network.
+    #[default]
+    InMemory,
+    /// TCP connections on localhost.
+    Tcp,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scenario {
+    /// Name the scenario is reported under, unique within a file.
+    pub name: String,
+    /// Number of records of the input.
+    pub records: usize,
+    #[serde(default)]
+    pub field: FieldType,
+    #[serde(default)]
+    pub security: SecurityMode,
+    /// Limits every helper runs with, none by default.
+    #[serde(default)]
+    pub parallelism: ParallelismConfig,
+    #[serde(default)]
+    pub transport: Transport,
+    /// Number of times the scenario is run, every one of which is measured.
+    #[serde(default = "one")]
+    pub repeat: usize,
+    /// Seed of the input and of PRSS, so runs can be reproduced. Drawn from the OS if not set.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ScenarioFile {
+    #[serde(default)]
+    scenario: Vec<Scenario>,
+}
+
+/// Parses the scenarios defined in `toml`, in the order they are defined.
+///
+/// ## Errors
+/// If `toml` is not a valid scenario file, two scenarios have the same name, or a scenario has
+/// no records or repetitions.
+pub fn parse(toml: &str) -> Result<Vec<Scenario>, Error> {
+    let file: ScenarioFile = toml::from_str(toml)?;
+    let mut names = BTreeSet::new();
+    for s in &file.scenario {
+        let invalid = |reason| Error::Invalid {
+            name: s.name.clone(),
+            reason,
+        };
+        if !names.insert(s.name.as_str()) {
+            return Err(Error::Duplicate(s.name.clone()));
+        }
+        if s.records == 0 {
+            return Err(invalid("it must have some records"));
+        }
+        if s.repeat == 0 {
+            return Err(invalid("it must run at least once"));
+        }
+    }
+    Ok(file.scenario)
+}
+
+/// What a single run of a scenario took.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Measurement {
+    /// Time from the moment helpers got their input until all of them had the result.
+    pub elapsed_secs: f64,
+    /// Rounds of the first helper, others take as many.
+    pub rounds: u64,
+    /// Multiplications of the first helper, others do as many.
+    pub multiplications: u64,
+    /// Bytes sent by all helpers.
+    pub bytes_sent: u64,
+}
+
+/// Measurements of every run of a scenario, in the order they were run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub scenario: Scenario,
+    /// Version of this crate, so that reports of different releases can be told apart.
+    pub version: &'static str,
+    pub runs: Vec<Measurement>,
+}
+
+/// Runs `scenario` as many times as it asks for.
+///
+/// ## Errors
+/// If helpers fail to connect or to run the protocol, or reveal a result that is not the one
+/// expected.
+///
+/// ## Panics
+/// If called outside of a Tokio runtime.
+pub async fn run(scenario: &Scenario) -> Res<Report> {
+    let entropy = scenario.seed.map_or_else(Entropy::os, Entropy::seeded);
+    let mut rng = entropy.rng();
+    let mut runs = Vec::with_capacity(scenario.repeat);
+    for _ in 0..scenario.repeat {
+        let participants = Box::new(make_participants(&mut rng));
+        let measurement = match scenario.transport {
+            Transport::InMemory => {
+                let helpers = make_three_with_codec::<Bincode>();
+                let progress = [0, 1, 2].map(|i| helpers[i].progress());
+                run_once(scenario, &helpers, &participants, &progress, &mut rng).await?
+            }
+            Transport::Tcp => {
+                let helpers = make_tcp_ring().await?;
+                let progress = [0, 1, 2].map(|i| helpers[i].progress());
+                run_once(scenario, &helpers, &participants, &progress, &mut rng).await?
+            }
+        };
+        runs.push(measurement);
+    }
+    Ok(Report {
+        scenario: scenario.clone(),
+        version: env!("CARGO_PKG_VERSION"),
+        runs,
+    })
+}
+
+async fn run_once<R: Ring, G: Rng>(
+    scenario: &Scenario,
+    helpers: &[R; 3],
+    participants: &[Participant; 3],
+    progress: &[Arc<QueryProgress>; 3],
+    rng: &mut G,
+) -> Res<Measurement> {
+    let counters = [(); 3].map(|()| RoundCounter::default());
+    let parallelism = [(); 3].map(|()| Parallelism::new(scenario.parallelism));
+    let ctx = [0, 1, 2].map(|i| {
+        ProtocolContext::new(Identity::ALL[i], &participants[i], &helpers[i])
+            .with_rounds(&counters[i])
+            .with_parallelism(&parallelism[i])
+    });
+
+    let started = Instant::now();
+    let (actual, expected) = match scenario.field {
+        FieldType::Fp31 => run_field::<Fp31, _, _>(scenario, &ctx, rng).await?,
+        FieldType::Fp32BitPrime => run_field::<Fp32BitPrime, _, _>(scenario, &ctx, rng).await?,
+    };
+    let elapsed = started.elapsed();
+    if actual != expected {
+        return Err(Error::Mismatch {
+            name: scenario.name.clone(),
+            actual,
+            expected,
+        }
+        .into());
+    }
+
+    let rounds = counters[0].report().total();
+    let bytes_sent = progress
+        .iter()
+        .map(|p| {
+            let snapshot = p.snapshot(&scenario.name);
+            snapshot.left.bytes_sent + snapshot.right.bytes_sent
+        })
+        .sum();
+    Ok(Measurement {
+        elapsed_secs: elapsed.as_secs_f64(),
+        rounds: rounds.rounds,
+        multiplications: rounds.multiplications,
+        bytes_sent,
+    })
+}
+
+/// Shares the input of the scenario in field `F` and runs the workload on it. Returns the sum
+/// the helpers revealed and the one computed in the clear.
+async fn run_field<F: Field, R: Ring, G: Rng>(
+    scenario: &Scenario,
+    ctx: &[ProtocolContext<'_, R>; 3],
+    rng: &mut G,
+) -> Res<(u128, u128)> {
+    let mut expected = F::ZERO;
+    let mut bits = [(); 3].map(|()| Vec::with_capacity(scenario.records));
+    let mut values = [(); 3].map(|()| Vec::with_capacity(scenario.records));
+    for _ in 0..scenario.records {
+        let (bit, value) = (rng.gen_bool(0.5), F::from(rng.gen_range(0..8_u128)));
+        if bit {
+            expected += value;
+        }
+        let bit = if bit { F::ONE } else { F::ZERO };
+        for (i, (b, v)) in ReplicatedSecretSharing::share(bit, rng)
+            .into_iter()
+            .zip(ReplicatedSecretSharing::share(value, rng))
+            .enumerate()
+        {
+            bits[i].push(b);
+            values[i].push(v);
+        }
+    }
+
+    let [b0, b1, b2] = bits;
+    let [v0, v1, v2] = values;
+    // reveal fails unless all helpers hold consistent shares, so they all reveal the same sum
+    let (actual, _, _) = match scenario.security {
+        SecurityMode::SemiHonest => futures::try_join!(
+            semi_honest(&ctx[0], &b0, &v0),
+            semi_honest(&ctx[1], &b1, &v1),
+            semi_honest(&ctx[2], &b2, &v2),
+        )?,
+        SecurityMode::Malicious => futures::try_join!(
+            malicious(&ctx[0], &b0, &v0),
+            malicious(&ctx[1], &b1, &v1),
+            malicious(&ctx[2], &b2, &v2),
+        )?,
+    };
+    Ok((to_u128(actual), to_u128(expected)))
+}
+
+fn to_u128<F: Field>(value: F) -> u128 {
+    let value: F::Integer = value.into();
+    value.into()
+}
+
+/// Sum of `bits[i] * values[i]`. Takes indices from `index` up to `index + bits.len()`.
+async fn sum_of_products<F: Field, C: Context<F>>(
+    ctx: &C,
+    index: u128,
+    bits: &[C::Share],
+    values: &[C::Share],
+) -> Res<C::Share> {
+    let products = ctx.multiply_batch(index, bits, values).await?;
+    let zero = ctx.share_known(F::ZERO);
+    Ok(products.into_iter().fold(zero, |acc, p| acc + p))
+}
+
+async fn semi_honest<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    bits: &[ReplicatedSecretSharing<F>],
+    values: &[ReplicatedSecretSharing<F>],
+) -> Res<F> {
+    let sum = sum_of_products(ctx, 0, bits, values).await?;
+    reveal(ctx, 0, sum).await
+}
+
+async fn malicious<'a, F: Field, R: Ring>(
+    ctx: &'a ProtocolContext<'a, R>,
+    bits: &[ReplicatedSecretSharing<F>],
+    values: &[ReplicatedSecretSharing<F>],
+) -> Res<F> {
+    let n = bits.len() as u128;
+    let m = MaliciousContext::new(ctx, 0);
+    let bits = m.upgrade(0, bits).await?;
+    let values = m.upgrade(n, values).await?;
+    let sum = sum_of_products(&m, 2 * n, &bits, &values).await?;
+    let sum = m.validate(3 * n, &[sum]).await?;
+    reveal(ctx, 0, sum[0]).await
+}
+
+fn make_participants<G: Rng + CryptoRng>(rng: &mut G) -> [Participant; 3] {
+    let setup = [(); 3].map(|()| ParticipantSetup::new(rng));
+    let pk = [0, 1, 2].map(|i| setup[i].public_keys());
+    let mut i = 0;
+    setup.map(|s| {
+        let (left, right) = ((i + 2) % 3, (i + 1) % 3);
+        i += 1;
+        s.setup(&pk[left].1, &pk[right].0)
+    })
+}
+
+/// Three helpers connected over TCP on localhost.
+async fn make_tcp_ring() -> Res<[TcpRing; 3]> {
+    let mut listeners = Vec::with_capacity(3);
+    let mut addrs = Vec::with_capacity(3);
+    for _ in 0..3 {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        addrs.push(listener.local_addr()?);
+        listeners.push(listener);
+    }
+    let [l0, l1, l2]: [TcpListener; 3] = listeners.try_into().unwrap();
+
+    // every helper is on this host, so peers are not authenticated
+    let config = |listen: usize, left: usize, right: usize| TcpRingConfig {
+        allow_unauthenticated: true,
+        ..TcpRingConfig::new(addrs[listen], addrs[left], addrs[right])
+    };
+    let configs = [config(0, 2, 1), config(1, 0, 2), config(2, 1, 0)];
+
+    let (h0, h1, h2) = futures::try_join!(
+        TcpRing::connect(l0, &configs[0]),
+        TcpRing::connect(l1, &configs[1]),
+        TcpRing::connect(l2, &configs[2]),
+    )?;
+    Ok([h0, h1, h2])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parallelism::ParallelismConfig;
+    use crate::query::SecurityMode;
+    use crate::scenarios::{parse, run, Error, Scenario, Transport};
+
+    const SCENARIOS: &str = r#"
+        [[scenario]]
+        name = "small"
+        records = 10
+
+        [[scenario]]
+        name = "chunked-malicious"
+        records = 20
+        security = "Malicious"
+        transport = "tcp"
+        repeat = 2
+        seed = 7
+
+        [scenario.parallelism]
+        max_records_per_step = 8
+    "#;
+
+    #[test]
+    fn parses() {
+        let scenarios = parse(SCENARIOS).unwrap();
+        assert_eq!(2, scenarios.len());
+        assert_eq!(Transport::InMemory, scenarios[0].transport);
+        assert_eq!(1, scenarios[0].repeat);
+        assert_eq!(
+            Scenario {
+                name: "chunked-malicious".into(),
+                records: 20,
+                field: crate::query::FieldType::Fp31,
+                security: SecurityMode::Malicious,
+                parallelism: ParallelismConfig {
+                    max_records_per_step: Some(8),
+                    ..ParallelismConfig::default()
+                },
+                transport: Transport::Tcp,
+                repeat: 2,
+                seed: Some(7),
+            },
+            scenarios[1]
+        );
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let duplicate = "[[scenario]]\nname = \"a\"\nrecords = 1\n".repeat(2);
+        assert!(matches!(parse(&duplicate), Err(Error::Duplicate(name)) if name == "a"));
+        assert!(matches!(
+            parse("[[scenario]]\nname = \"a\"\nrecords = 0\n"),
+            Err(Error::Invalid { .. })
+        ));
+        assert!(matches!(
+            parse("[[scenario]]\nname = \"a\"\nrecords = 1\nfield = \"Fp32\"\n"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn runs() {
+        for scenario in parse(SCENARIOS).unwrap() {
+            let report = run(&scenario).await.unwrap();
+            assert_eq!(scenario.repeat, report.runs.len());
+            for measurement in &report.runs {
+                assert!(measurement.bytes_sent > 0);
+                assert!(measurement.rounds > 0);
+                assert!(measurement.multiplications >= scenario.records as u64);
+            }
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(scenario.name, json["scenario"]["name"]);
+            assert_eq!(env!("CARGO_PKG_VERSION"), json["version"]);
+        }
+    }
+}
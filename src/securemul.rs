@@ -1,11 +1,22 @@
-use crate::error::BoxError;
+use crate::arena::{Arena, Scratch};
+use crate::entropy::Entropy;
+use crate::error::Res;
 use crate::field::Field;
-use crate::helpers::ring::{HelperAddr, Ring};
+use crate::helpers::ring::{message, HelperAddr, Identity, Message, PeerChannel, Ring};
+use crate::parallelism::Parallelism;
 use crate::prss::Participant;
 use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::step;
+use crate::telemetry::rounds::RoundCounter;
+use crate::telemetry::StepTimer;
+use crate::transcript::Transcript;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use thiserror::Error;
+use tokio::sync::SemaphorePermit;
+use tracing::Instrument;
 
 /// IKHC multiplication protocol
 /// for use with replicated secret sharing over some field F.
@@ -24,13 +35,28 @@ pub struct DValue {
     d: u128,
 }
 
+message!(DValue => "securemul.d-value");
+
 /// Context used by each helper to perform computation. Currently they need access to shared
 /// randomness generator (PRSS) and communication trait to send messages to each other.
 /// Eventually when we have more than one protocol, this should be lifted to its own module
 #[derive(Debug)]
 pub struct ProtocolContext<'a, R> {
+    /// Helper that executes the protocol.
+    pub identity: Identity,
     pub participant: &'a Participant,
     pub helper_ring: &'a R,
+    /// If set, rounds and multiplications done by every step are counted there.
+    pub rounds: Option<&'a RoundCounter>,
+    /// Randomness the helper draws on its own. Taken from the operating system if not set.
+    pub entropy: Option<&'a Entropy>,
+    /// Limits on the work done at once for the query. Nothing is limited if not set.
+    pub parallelism: Option<&'a Parallelism>,
+    /// If set, PRSS indices drawn by every step are added there.
+    pub transcript: Option<&'a Transcript>,
+    /// Scratch buffers of the query, reused by steps until it is reset. Steps allocate their
+    /// own if not set.
+    pub arena: Option<&'a Arena>,
 }
 
 #[derive(Error, Debug)]
@@ -39,64 +65,576 @@ pub enum Error {
         "Shares calculated by peer used different index {their_index} than expected {my_index}"
     )]
     IndexMismatch { my_index: u128, their_index: u128 },
+    #[error("Cannot multiply {a} values by {b} values")]
+    LengthMismatch { a: usize, b: usize },
+    #[error("Expected {expected} products from peer, but it sent {actual}")]
+    BatchMismatch { expected: usize, actual: usize },
 }
 
 impl<F: Field> SecureMul<F> {
+    const STEP: &'static str = step::SECURE_MUL;
+
+    /// Prepares multiplication of `a_share` and `b_share`. `index` must be the same on all
+    /// three helpers and unique for every multiplication that uses the same PRSS.
+    #[must_use]
+    pub fn new(
+        index: u128,
+        a_share: ReplicatedSecretSharing<F>,
+        b_share: ReplicatedSecretSharing<F>,
+    ) -> Self {
+        Self {
+            index,
+            a_share,
+            b_share,
+        }
+    }
+
     /// Executes the secure multiplication on the MPC helper side. Each helper will proceed with
     /// their part, eventually producing 2/3 shares of the product and that is what this function
     /// returns.
     ///
     /// ## Errors
     /// Lots of things may go wrong here, from timeouts to bad output. They will be signalled
-    /// back via the error response
+    /// back via the error response, annotated with the step name and the index of this
+    /// multiplication.
     pub async fn execute<R: Ring>(
         self,
         ctx: &ProtocolContext<'_, R>,
-    ) -> Result<ReplicatedSecretSharing<F>, BoxError> {
-        // generate shared randomness.
-        let (s0, s1) = ctx.participant.generate_fields(self.index);
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        let record = ctx.bind(Self::STEP, self.index);
+        self.execute_inner(ctx)
+            .instrument(tracing::debug_span!(
+                "step",
+                helper = %ctx.identity,
+                name = Self::STEP,
+                record = record.record()
+            ))
+            .await
+            .map_err(|e| record.error(e))
+    }
 
-        // compute the value (d_i) we want to send to the right helper (i+1)
+    async fn execute_inner<R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        let _timer = StepTimer::start(Self::STEP);
+        if let Some(rounds) = ctx.rounds {
+            rounds.multiplication(Self::STEP);
+        }
         let (a0, a1) = self.a_share.as_tuple();
         let (b0, b1) = self.b_share.as_tuple();
-        let right_d: F = a0 * b1 + a1 * b0 - s0;
-
-        // this ugliness is needed just to convert Field to u128. There are better ways to do it
-        // and there is a PR open to make it easier
-        let right_d: <F as Field>::Integer = right_d.into();
-        let right_d: u128 = right_d.into();
-
-        // notify helper on the right that we've computed our value
-        ctx.helper_ring
-            .send(
-                HelperAddr::Right,
-                DValue {
-                    d: right_d,
-                    index: self.index,
-                },
-            )
-            .await?;
-
-        // Sleep until helper on the left sends us their (d_i-1) value
-        let DValue {
-            d: left_d,
-            index: left_index,
-        } = ctx.helper_ring.receive(HelperAddr::Left).await?;
-
-        // sanity check to make sure they've computed it using the same seed
-        if left_index == self.index {
-            // now we are ready to construct the result - 2/3 secret shares of a * b.
-            let lhs = a0 * b0 + F::from(left_d) + s0;
-            let rhs = a1 * b1 + F::from(right_d) + s1;
-
-            Ok(ReplicatedSecretSharing::new(lhs, rhs))
-        } else {
-            Err(Box::new(Error::IndexMismatch {
-                my_index: self.index,
-                their_index: left_index,
-            }))
+        exchange(
+            ctx,
+            Self::STEP,
+            self.index,
+            (a0 * b0, a0 * b1 + a1 * b0, a1 * b1),
+        )
+        .await
+    }
+}
+
+/// Computes shares of `sum(a[i] * b[i])`. Unlike a sequence of [`SecureMul`]s, every helper
+/// masks and sends only the sum of its products, so it takes a single message and round no
+/// matter how many products are summed.
+#[derive(Debug)]
+pub struct SumOfProducts<'a, F> {
+    index: u128,
+    a: &'a [ReplicatedSecretSharing<F>],
+    b: &'a [ReplicatedSecretSharing<F>],
+}
+
+impl<'a, F: Field> SumOfProducts<'a, F> {
+    const STEP: &'static str = step::SUM_OF_PRODUCTS;
+
+    /// Prepares the sum of products of `a` and `b`. `index` has the same requirements as the
+    /// one of [`SecureMul`] and must not be reused by either of them.
+    #[must_use]
+    pub fn new(
+        index: u128,
+        a: &'a [ReplicatedSecretSharing<F>],
+        b: &'a [ReplicatedSecretSharing<F>],
+    ) -> Self {
+        Self { index, a, b }
+    }
+
+    /// Executes the protocol on the MPC helper side and returns its share of the sum.
+    ///
+    /// ## Errors
+    /// If `a` and `b` have different lengths, and otherwise same as [`SecureMul::execute`].
+    pub async fn execute<R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        let record = ctx.bind(Self::STEP, self.index);
+        self.execute_inner(ctx)
+            .instrument(tracing::debug_span!(
+                "step",
+                helper = %ctx.identity,
+                name = Self::STEP,
+                record = record.record()
+            ))
+            .await
+            .map_err(|e| record.error(e))
+    }
+
+    async fn execute_inner<R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        let _timer = StepTimer::start(Self::STEP);
+        if self.a.len() != self.b.len() {
+            return Err(Error::LengthMismatch {
+                a: self.a.len(),
+                b: self.b.len(),
+            }
+            .into());
+        }
+        if let Some(rounds) = ctx.rounds {
+            for _ in self.a {
+                rounds.multiplication(Self::STEP);
+            }
+        }
+
+        let zero = (F::ZERO, F::ZERO, F::ZERO);
+        let products = self.a.iter().zip(self.b).fold(zero, |(l, c, r), (a, b)| {
+            let (a0, a1) = a.as_tuple();
+            let (b0, b1) = b.as_tuple();
+            (l + a0 * b0, c + a0 * b1 + a1 * b0, r + a1 * b1)
+        });
+        exchange(ctx, Self::STEP, self.index, products).await
+    }
+}
+
+/// Computes shares of `a[i] * b[i]` for every `i`. Masked cross products of the whole batch
+/// go to the helper on the right in one message, so the batch takes a single round. Product `i`
+/// uses PRSS index `index + i`, none of these may be reused.
+#[derive(Debug)]
+pub struct MultiplyBatch<'a, F> {
+    index: u128,
+    a: &'a [ReplicatedSecretSharing<F>],
+    b: &'a [ReplicatedSecretSharing<F>],
+}
+
+impl<'a, F: Field> MultiplyBatch<'a, F> {
+    const STEP: &'static str = step::MULTIPLY_BATCH;
+
+    #[must_use]
+    pub fn new(
+        index: u128,
+        a: &'a [ReplicatedSecretSharing<F>],
+        b: &'a [ReplicatedSecretSharing<F>],
+    ) -> Self {
+        Self { index, a, b }
+    }
+
+    /// Executes the protocol on the MPC helper side and returns its shares of the products.
+    ///
+    /// ## Errors
+    /// If `a` and `b` have different lengths, or the helper on the left sent a different number
+    /// of products. Otherwise same as [`SecureMul::execute`].
+    pub async fn execute<R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let record = ctx.bind(Self::STEP, self.index);
+        let records = self.a.len();
+        self.execute_inner(ctx)
+            .instrument(tracing::debug_span!(
+                "step",
+                helper = %ctx.identity,
+                name = Self::STEP,
+                record = record.record(),
+                records
+            ))
+            .await
+            .map_err(|e| record.error(e))
+    }
+
+    async fn execute_inner<R: Ring>(
+        self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let _timer = StepTimer::start(Self::STEP);
+        if self.a.len() != self.b.len() {
+            return Err(Error::LengthMismatch {
+                a: self.a.len(),
+                b: self.b.len(),
+            }
+            .into());
+        }
+        if let Some(rounds) = ctx.rounds {
+            for _ in self.a {
+                rounds.multiplication(Self::STEP);
+            }
+        }
+
+        // batches too big to work on at once are exchanged in chunks, one after another
+        let config = ctx.parallelism.map(Parallelism::config).unwrap_or_default();
+        let mut products = Vec::with_capacity(self.a.len());
+        for chunk in config.chunks(self.a.len()) {
+            let index = self.index + chunk.start as u128;
+            let (a, b) = (&self.a[chunk.clone()], &self.b[chunk]);
+            products.extend(Self::multiply_chunk(ctx, index, a, b).await?);
+        }
+        Ok(products)
+    }
+
+    /// Same as `exchange`, but for every product of the chunk.
+    async fn multiply_chunk<R: Ring>(
+        ctx: &ProtocolContext<'_, R>,
+        index: u128,
+        a: &[ReplicatedSecretSharing<F>],
+        b: &[ReplicatedSecretSharing<F>],
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        let mut lhs = ctx.scratch(a.len());
+        let mut rhs = ctx.scratch(a.len());
+        let mut right_d = ctx.scratch(a.len());
+        for (index, (a, b)) in (index..).zip(a.iter().zip(b)) {
+            let (s0, s1) = ctx.bind(Self::STEP, index).prss_fields::<F>();
+            let (a0, a1) = a.as_tuple();
+            let (b0, b1) = b.as_tuple();
+            let d = a0 * b1 + a1 * b0 - s0;
+            lhs.push(a0 * b0 + s0);
+            rhs.push(a1 * b1 + d + s1);
+            right_d.push(d);
+        }
+
+        let _permit = ctx.step_permit().await;
+        let _round = ctx.rounds.map(|rounds| rounds.wait(Self::STEP));
+        let ((), left_d) = futures::try_join!(
+            ctx.helper_ring.send_fields(HelperAddr::Right, &right_d),
+            ctx.helper_ring.receive_fields::<F>(HelperAddr::Left),
+        )?;
+        if left_d.len() != lhs.len() {
+            return Err(Error::BatchMismatch {
+                expected: lhs.len(),
+                actual: left_d.len(),
+            }
+            .into());
+        }
+
+        Ok(lhs
+            .iter()
+            .zip(left_d)
+            .zip(rhs.iter())
+            .map(|((&l, d), &r)| ReplicatedSecretSharing::new(l + d, r))
+            .collect())
+    }
+}
+
+/// Finishes the multiplication given the local products of this helper's shares: the product
+/// of left shares, the sum of cross products and the product of right shares. The cross
+/// products are masked and sent to the helper on the right, which needs them to complete its
+/// shares.
+async fn exchange<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    step: &'static str,
+    index: u128,
+    (left, cross, right): (F, F, F),
+) -> Res<ReplicatedSecretSharing<F>> {
+    // generate shared randomness.
+    let (s0, s1) = ctx.bind(step, index).prss_fields();
+
+    // compute the value (d_i) we want to send to the right helper (i+1)
+    let right_d: F = cross - s0;
+
+    // this ugliness is needed just to convert Field to u128. There are better ways to do it
+    // and there is a PR open to make it easier
+    let right_d: <F as Field>::Integer = right_d.into();
+    let right_d: u128 = right_d.into();
+
+    // the round lasts until the value from the left helper arrives
+    let _permit = ctx.step_permit().await;
+    let _round = ctx.rounds.map(|rounds| rounds.wait(step));
+
+    // notify helper on the right that we've computed our value
+    ctx.helper_ring
+        .send(HelperAddr::Right, DValue { d: right_d, index })
+        .await?;
+
+    // Sleep until helper on the left sends us their (d_i-1) value
+    let DValue {
+        d: left_d,
+        index: left_index,
+    } = ctx.helper_ring.receive(HelperAddr::Left).await?;
+
+    // sanity check to make sure they've computed it using the same seed
+    if left_index == index {
+        // now we are ready to construct the result - 2/3 secret shares of a * b.
+        let lhs = left + F::from(left_d) + s0;
+        let rhs = right + F::from(right_d) + s1;
+
+        Ok(ReplicatedSecretSharing::new(lhs, rhs))
+    } else {
+        Err(Error::IndexMismatch {
+            my_index: index,
+            their_index: left_index,
+        }
+        .into())
+    }
+}
+
+/// View of a [`ProtocolContext`] for protocol step `step` working on a single record. It is
+/// cheap to create, so protocols bind one for every record instead of passing the step and the
+/// record to everything they call.
+#[derive(Debug)]
+pub struct Record<'a, 'c, R> {
+    ctx: &'a ProtocolContext<'c, R>,
+    step: &'static str,
+    record: u128,
+}
+
+impl<R> Record<'_, '_, R> {
+    #[must_use]
+    pub fn record(&self) -> u128 {
+        self.record
+    }
+
+    /// Two random values for this record, one that is known to the left helper and one that is
+    /// known to the right helper. In debug builds, a record that drew randomness for any step of
+    /// this helper before is logged as an error, along with both steps, see
+    /// [`Participant::claim`].
+    #[must_use]
+    pub fn prss_values(&self) -> (u128, u128) {
+        if let Some(previous) = self.ctx.participant.claim(self.step, self.record) {
+            tracing::error!(
+                helper = %self.ctx.identity,
+                index = self.record,
+                "PRSS index used by {previous} is used again by {}",
+                self.step
+            );
+        }
+        if let Some(transcript) = self.ctx.transcript {
+            transcript.prss(self.step, self.record);
+        }
+        self.ctx.participant.generate_values(self.record)
+    }
+
+    /// Same as [`Self::prss_values`], but as field values.
+    #[must_use]
+    pub fn prss_fields<F: Field>(&self) -> (F, F) {
+        let (l, r) = self.prss_values();
+        (F::from(l), F::from(r))
+    }
+
+    /// Attaches the helper, the step and the record to `error`, see [`crate::error::Error::in_step`].
+    #[must_use]
+    pub fn error(&self, error: crate::error::Error) -> crate::error::Error {
+        error.in_step(self.ctx.identity, self.step, self.record)
+    }
+}
+
+impl<'c, R> ProtocolContext<'c, R> {
+    /// Context of helper `identity` that draws PRSS randomness from `participant` and talks to
+    /// its peers over `helper_ring`. Nothing else is set; the `with_` methods below add the rest.
+    #[must_use]
+    pub fn new(identity: Identity, participant: &'c Participant, helper_ring: &'c R) -> Self {
+        Self {
+            identity,
+            participant,
+            helper_ring,
+            rounds: None,
+            entropy: None,
+            parallelism: None,
+            transcript: None,
+            arena: None,
+        }
+    }
+
+    /// Counts rounds and multiplications of every step in `rounds`.
+    #[must_use]
+    pub fn with_rounds(mut self, rounds: &'c RoundCounter) -> Self {
+        self.rounds = Some(rounds);
+        self
+    }
+
+    /// Draws randomness of the helper from `entropy` instead of the operating system.
+    #[must_use]
+    pub fn with_entropy(mut self, entropy: &'c Entropy) -> Self {
+        self.entropy = Some(entropy);
+        self
+    }
+
+    /// Limits the work done at once to what `parallelism` allows.
+    #[must_use]
+    pub fn with_parallelism(mut self, parallelism: &'c Parallelism) -> Self {
+        self.parallelism = Some(parallelism);
+        self
+    }
+
+    /// Adds PRSS indices drawn by every step to `transcript`.
+    #[must_use]
+    pub fn with_transcript(mut self, transcript: &'c Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+
+    /// Takes scratch buffers of steps from `arena`.
+    #[must_use]
+    pub fn with_arena(mut self, arena: &'c Arena) -> Self {
+        self.arena = Some(arena);
+        self
+    }
+
+    /// Binds this context to `record` of protocol step `step`, which must be one of the names in
+    /// [`crate::step`]. Names that are not are rejected in debug builds, as nothing would keep
+    /// them from colliding with other steps.
+    #[must_use]
+    pub fn bind(&self, step: &'static str, record: u128) -> Record<'_, 'c, R> {
+        debug_assert!(step::is_known(step), "step {step} is not registered");
+        Record {
+            ctx: self,
+            step,
+            record,
+        }
+    }
+
+    /// Waits until a step of the query may exchange messages with peers, see
+    /// [`Parallelism::step`]. Steps hold on to the permit until they have received what they
+    /// wait for.
+    pub async fn step_permit(&self) -> Option<SemaphorePermit<'c>> {
+        match self.parallelism {
+            Some(parallelism) => parallelism.step().await,
+            None => None,
+        }
+    }
+
+    /// Generator for randomness this helper draws on its own, rather than from PRSS. See
+    /// [`crate::entropy`].
+    #[must_use]
+    pub fn rng(&self) -> StdRng {
+        self.entropy.map_or_else(StdRng::from_entropy, Entropy::rng)
+    }
+
+    /// Empty buffer for `capacity` values a step works on, taken from the arena of the query if
+    /// it has one. See [`crate::arena`].
+    #[must_use]
+    pub fn scratch<T: Send + 'static>(&self, capacity: usize) -> Scratch<'c, T> {
+        match self.arena {
+            Some(arena) => arena.vec(capacity),
+            None => Scratch::unpooled(capacity),
+        }
+    }
+
+    /// Replicated sharing of a random value that no helper knows. Helpers get correlated parts
+    /// from PRSS without talking to each other. `index` must be the same on all three helpers and
+    /// must not be used for anything else that draws from the same PRSS.
+    #[must_use]
+    pub fn prss_random_share<F: Field>(&self, index: u128) -> ReplicatedSecretSharing<F> {
+        let (left, right) = self.bind(step::PRSS_RANDOM_SHARE, index).prss_fields();
+        ReplicatedSecretSharing::new(left, right)
+    }
+
+    /// Replicated sharing of `value` that is known to every helper, such as a constant or a
+    /// position, so it can be used together with secret values. `H1` holds all of it.
+    #[must_use]
+    pub fn share_known<F: Field>(&self, value: F) -> ReplicatedSecretSharing<F> {
+        match self.identity {
+            Identity::H1 => ReplicatedSecretSharing::new(value, F::ZERO),
+            Identity::H2 => ReplicatedSecretSharing::new(F::ZERO, F::ZERO),
+            Identity::H3 => ReplicatedSecretSharing::new(F::ZERO, value),
         }
     }
+
+    /// Share of zero, for blinding values before they are sent to a peer. Parts held by the
+    /// three helpers add up to zero, but each one is known only to its holder.
+    ///
+    /// The share is additive rather than replicated: a replicated sharing of a known value lets
+    /// every helper compute all three parts, so it could not hide anything. Same requirements on
+    /// `index` as for [`Self::prss_random_share`].
+    #[must_use]
+    pub fn prss_zero_share<F: Field>(&self, index: u128) -> F {
+        let (left, right): (F, F) = self.bind(step::PRSS_ZERO_SHARE, index).prss_fields();
+        left - right
+    }
+}
+
+impl<R: Ring> ProtocolContext<'_, R> {
+    /// Channel to the helper with identity `peer`. Protocols that talk to a specific helper
+    /// rather than a neighbour use this instead of working out its position in the ring.
+    ///
+    /// ## Panics
+    /// If `peer` is this helper.
+    #[must_use]
+    pub fn peer(&self, peer: Identity) -> PeerChannel<'_, R> {
+        PeerChannel::new(self.helper_ring, self.identity, peer)
+            .unwrap_or_else(|| panic!("{peer} cannot exchange messages with itself"))
+    }
+
+    /// Channels to both other helpers, the one on the left first.
+    #[must_use]
+    pub fn peers(&self) -> [PeerChannel<'_, R>; 2] {
+        PeerChannel::both(self.helper_ring, self.identity)
+    }
+
+    /// Sends `msg` to the helper with identity `to`.
+    ///
+    /// ## Errors
+    /// Same as [`Ring::send`].
+    ///
+    /// ## Panics
+    /// If `to` is this helper.
+    pub async fn send_to<T: Message>(&self, to: Identity, msg: T) -> Res<()> {
+        Ok(self.peer(to).send(msg).await?)
+    }
+
+    /// Receives a message of type `T` from the helper with identity `from`.
+    ///
+    /// ## Errors
+    /// Same as [`Ring::receive`].
+    ///
+    /// ## Panics
+    /// If `from` is this helper.
+    pub async fn receive_from<T: Message>(&self, from: Identity) -> Res<T> {
+        Ok(self.peer(from).receive().await?)
+    }
+
+    /// Sends `msg` to both other helpers.
+    ///
+    /// ## Errors
+    /// Same as [`Ring::broadcast`].
+    pub async fn broadcast<T: Message + Clone>(&self, msg: T) -> Res<()> {
+        Ok(self.helper_ring.broadcast(msg).await?)
+    }
+
+    /// Multiplies `a` and `b`, see [`SecureMul`].
+    ///
+    /// ## Errors
+    /// Same as [`SecureMul::execute`].
+    pub async fn multiply<F: Field>(
+        &self,
+        index: u128,
+        a: ReplicatedSecretSharing<F>,
+        b: ReplicatedSecretSharing<F>,
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        SecureMul::new(index, a, b).execute(self).await
+    }
+
+    /// Multiplies every `a[i]` by `b[i]` in one round, see [`MultiplyBatch`].
+    ///
+    /// ## Errors
+    /// Same as [`MultiplyBatch::execute`].
+    pub async fn multiply_batch<F: Field>(
+        &self,
+        index: u128,
+        a: &[ReplicatedSecretSharing<F>],
+        b: &[ReplicatedSecretSharing<F>],
+    ) -> Res<Vec<ReplicatedSecretSharing<F>>> {
+        MultiplyBatch::new(index, a, b).execute(self).await
+    }
+
+    /// Computes `sum(a[i] * b[i])`, see [`SumOfProducts`].
+    ///
+    /// ## Errors
+    /// Same as [`SumOfProducts::execute`].
+    pub async fn sum_of_products<F: Field>(
+        &self,
+        index: u128,
+        a: &[ReplicatedSecretSharing<F>],
+        b: &[ReplicatedSecretSharing<F>],
+    ) -> Res<ReplicatedSecretSharing<F>> {
+        SumOfProducts::new(index, a, b).execute(self).await
+    }
 }
 
 /// Module to support streaming interface for secure multiplication
@@ -120,9 +658,9 @@ pub mod stream {
         input_stream: S,
         ctx: &'a ProtocolContext<'a, R>,
         index: u128,
-    ) -> impl Stream<Item = ReplicatedSecretSharing<F>> + 'a
+    ) -> impl Stream<Item = ReplicatedSecretSharing<F>> + Send + 'a
     where
-        S: Stream<Item = ReplicatedSecretSharing<F>> + 'a,
+        S: Stream<Item = ReplicatedSecretSharing<F>> + Send + 'a,
         F: Field + 'static,
         R: Ring,
     {
@@ -157,7 +695,6 @@ mod tests {
 
     use crate::field::{Field, Fp31};
     use rand::rngs::mock::StepRng;
-    use rand::Rng;
     use rand_core::RngCore;
 
     use crate::replicated_secret_sharing::ReplicatedSecretSharing;
@@ -165,19 +702,20 @@ mod tests {
     use futures::{stream, StreamExt};
     use futures_util::future::join_all;
 
-    use crate::prss::Participant;
-
-    use crate::error::BoxError;
+    use crate::error::{Error, Res};
     use crate::helpers;
     use crate::helpers::ring::mock::TestHelper;
+    use crate::helpers::ring::Identity;
+    use crate::parallelism::{Parallelism, ParallelismConfig};
     use crate::securemul::stream::secure_multiply;
-    use crate::securemul::{ProtocolContext, SecureMul};
+    use crate::securemul::{Error as SecureMulError, ProtocolContext, SecureMul};
+    use crate::telemetry::rounds::RoundCounter;
+    use crate::test_fixture::TestWorld;
 
     #[tokio::test]
-    async fn basic() -> Result<(), BoxError> {
-        let ring = helpers::ring::mock::make_three();
-        let participants = crate::prss::test::make_three();
-        let context = make_context(&ring, &participants);
+    async fn basic() -> Res<()> {
+        let world = TestWorld::new();
+        let context = world.contexts();
         let mut rand = StepRng::new(1, 1);
 
         assert_eq!(30, multiply_sync(&context, 6, 5, &mut rand).await?);
@@ -197,9 +735,9 @@ mod tests {
     async fn supports_stream_of_secret_shares() {
         // we compute a*b*c in this test. 4*3*2 = 24
         let mut rand = StepRng::new(1, 1);
-        let a = share(Fp31::from(4_u128), &mut rand);
-        let b = share(Fp31::from(3_u128), &mut rand);
-        let c = share(Fp31::from(2_u128), &mut rand);
+        let a = ReplicatedSecretSharing::share(Fp31::from(4_u128), &mut rand);
+        let b = ReplicatedSecretSharing::share(Fp31::from(3_u128), &mut rand);
+        let c = ReplicatedSecretSharing::share(Fp31::from(2_u128), &mut rand);
         let start_index = 1024_u128;
 
         // setup helpers
@@ -215,13 +753,14 @@ mod tests {
         ];
 
         // create 3 tasks (1 per helper) that will execute secure multiplication
-        let handles = input.into_iter().zip(participants).zip(ring).map(
-            |((input, participant), helper_ring)| {
+        let handles = input
+            .into_iter()
+            .zip(participants)
+            .zip(ring)
+            .zip(Identity::ALL)
+            .map(|(((input, participant), helper_ring), identity)| {
                 tokio::spawn(async move {
-                    let ctx = ProtocolContext {
-                        participant: &participant,
-                        helper_ring: &helper_ring,
-                    };
+                    let ctx = ProtocolContext::new(identity, &participant, &helper_ring);
                     let mut stream = secure_multiply(input, &ctx, start_index);
 
                     // compute a*b
@@ -230,8 +769,7 @@ mod tests {
                     // compute (a*b)*c and return it
                     stream.next().await.expect("Failed to compute a*b*c")
                 })
-            },
-        );
+            });
 
         let result_shares: [ReplicatedSecretSharing<Fp31>; 3] =
             join_all(handles.map(|handle| async { handle.await.unwrap() }))
@@ -243,12 +781,54 @@ mod tests {
         assert_eq!(Fp31::from(24_u128), validate_and_reconstruct(result_shares));
     }
 
+    /// Every helper may run its part of the protocol on a different thread.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 3)]
+    async fn multi_threaded() {
+        let mut rand = StepRng::new(1, 1);
+        let a = ReplicatedSecretSharing::share(Fp31::from(5_u128), &mut rand);
+        let b = ReplicatedSecretSharing::share(Fp31::from(6_u128), &mut rand);
+
+        let ring = helpers::ring::mock::make_three();
+        let participants = crate::prss::test::make_three();
+        let participants = [participants.0, participants.1, participants.2];
+
+        let handles = ring
+            .into_iter()
+            .zip(participants)
+            .zip(Identity::ALL)
+            .zip(a.into_iter().zip(b))
+            .map(
+                |(((helper_ring, participant), identity), (a_share, b_share))| {
+                    tokio::spawn(async move {
+                        let ctx = ProtocolContext::new(identity, &participant, &helper_ring);
+                        SecureMul {
+                            index: 1,
+                            a_share,
+                            b_share,
+                        }
+                        .execute(&ctx)
+                        .await
+                        .unwrap()
+                    })
+                },
+            );
+
+        let result_shares: [ReplicatedSecretSharing<Fp31>; 3] =
+            join_all(handles.map(|handle| async { handle.await.unwrap() }))
+                .await
+                .try_into()
+                .unwrap();
+        let result_shares = (result_shares[0], result_shares[1], result_shares[2]);
+
+        assert_eq!(Fp31::from(30_u128), validate_and_reconstruct(result_shares));
+    }
+
     async fn multiply_sync<R: RngCore>(
         context: &[ProtocolContext<'_, TestHelper>; 3],
         a: u8,
         b: u8,
         rng: &mut R,
-    ) -> Result<u8, BoxError> {
+    ) -> Res<u8> {
         assert!(a < Fp31::PRIME);
         assert!(b < Fp31::PRIME);
 
@@ -261,8 +841,8 @@ mod tests {
 
         let index = u128::from(INDEX.with(|i| i.fetch_add(1, Ordering::Release)));
 
-        let a = share(a, rng);
-        let b = share(b, rng);
+        let a = ReplicatedSecretSharing::share(a, rng);
+        let b = ReplicatedSecretSharing::share(b, rng);
 
         let result_shares = tokio::try_join!(
             SecureMul {
@@ -288,32 +868,174 @@ mod tests {
         Ok(validate_and_reconstruct(result_shares).into())
     }
 
-    fn make_context<'a>(
-        ring: &'a [TestHelper; 3],
-        participants: &'a (Participant, Participant, Participant),
-    ) -> [ProtocolContext<'a, TestHelper>; 3] {
-        ring.iter()
-            .zip([&participants.0, &participants.1, &participants.2])
-            .map(|(helper_ring, participant)| ProtocolContext {
-                participant,
-                helper_ring,
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap()
-    }
-
-    /// Shares `input` into 3 replicated secret shares using the provided `rng` implementation
-    fn share<R: RngCore>(input: Fp31, rng: &mut R) -> [ReplicatedSecretSharing<Fp31>; 3] {
-        let x1 = Fp31::from(rng.gen_range(0..Fp31::PRIME));
-        let x2 = Fp31::from(rng.gen_range(0..Fp31::PRIME));
-        let x3 = input - (x1 + x2);
-
-        [
-            ReplicatedSecretSharing::new(x1, x2),
-            ReplicatedSecretSharing::new(x2, x3),
-            ReplicatedSecretSharing::new(x3, x1),
-        ]
+    #[tokio::test]
+    async fn sum_of_products() {
+        let world = TestWorld::new();
+        let counter = RoundCounter::default();
+        let context = world.contexts().map(|ctx| ctx.with_rounds(&counter));
+        let mut rand = StepRng::new(1, 1);
+
+        // 3*4 + 5*6 + 7*8 = 98 = 5 mod 31
+        let share =
+            |v: u128, rand: &mut StepRng| ReplicatedSecretSharing::share(Fp31::from(v), rand);
+        let mut a = [Vec::new(), Vec::new(), Vec::new()];
+        let mut b = [Vec::new(), Vec::new(), Vec::new()];
+        for (x, y) in [(3, 4), (5, 6), (7, 8)] {
+            let (x, y) = (share(x, &mut rand), share(y, &mut rand));
+            for i in 0..3 {
+                a[i].push(x[i]);
+                b[i].push(y[i]);
+            }
+        }
+
+        let result_shares = tokio::try_join!(
+            context[0].sum_of_products(1, &a[0], &b[0]),
+            context[1].sum_of_products(1, &a[1], &b[1]),
+            context[2].sum_of_products(1, &a[2], &b[2]),
+        )
+        .unwrap();
+        assert_eq!(Fp31::from(5_u128), validate_and_reconstruct(result_shares));
+
+        let stats = counter.report().step("sum_of_products");
+        assert_eq!(1, stats.rounds);
+        assert_eq!(9, stats.multiplications);
+
+        let err = context[0]
+            .sum_of_products(2, &a[0], &b[0][1..])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Step { inner, .. } if matches!(*inner, Error::SecureMul(SecureMulError::LengthMismatch { a: 3, b: 2 }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn multiply_batch() {
+        let world = TestWorld::new();
+        let counter = RoundCounter::default();
+        let context = world.contexts().map(|ctx| ctx.with_rounds(&counter));
+        let mut rand = StepRng::new(1, 1);
+
+        let mut a = [Vec::new(), Vec::new(), Vec::new()];
+        let mut b = [Vec::new(), Vec::new(), Vec::new()];
+        for v in 0..10_u128 {
+            let x = ReplicatedSecretSharing::share(Fp31::from(v), &mut rand);
+            let y = ReplicatedSecretSharing::share(Fp31::from(v + 1), &mut rand);
+            for i in 0..3 {
+                a[i].push(x[i]);
+                b[i].push(y[i]);
+            }
+        }
+
+        let (r0, r1, r2) = tokio::try_join!(
+            context[0].multiply_batch(1, &a[0], &b[0]),
+            context[1].multiply_batch(1, &a[1], &b[1]),
+            context[2].multiply_batch(1, &a[2], &b[2]),
+        )
+        .unwrap();
+        for (v, shares) in (0..10_u128).zip(r0.into_iter().zip(r1).zip(r2)) {
+            let ((s0, s1), s2) = shares;
+            assert_eq!(
+                Fp31::from(v * (v + 1)),
+                validate_and_reconstruct((s0, s1, s2))
+            );
+        }
+
+        let stats = counter.report().step("multiply_batch");
+        assert_eq!(1, stats.rounds);
+        assert_eq!(30, stats.multiplications);
+    }
+
+    #[tokio::test]
+    async fn multiply_batch_in_chunks() {
+        let world = TestWorld::new();
+        let counters = [(); 3].map(|()| RoundCounter::default());
+        let config = ParallelismConfig {
+            max_records_per_step: Some(4),
+            max_concurrent_steps: Some(1),
+            task_budget: None,
+        };
+        let parallelism = [(); 3].map(|()| Parallelism::new(config));
+        let [c0, c1, c2] = world.contexts();
+        let context = [
+            c0.with_rounds(&counters[0])
+                .with_parallelism(&parallelism[0]),
+            c1.with_rounds(&counters[1])
+                .with_parallelism(&parallelism[1]),
+            c2.with_rounds(&counters[2])
+                .with_parallelism(&parallelism[2]),
+        ];
+        let mut rand = StepRng::new(1, 1);
+
+        let mut a = [Vec::new(), Vec::new(), Vec::new()];
+        for v in 0..10_u128 {
+            let x = ReplicatedSecretSharing::share(Fp31::from(v), &mut rand);
+            for i in 0..3 {
+                a[i].push(x[i]);
+            }
+        }
+
+        let (r0, r1, r2) = tokio::try_join!(
+            context[0].multiply_batch(1, &a[0], &a[0]),
+            context[1].multiply_batch(1, &a[1], &a[1]),
+            context[2].multiply_batch(1, &a[2], &a[2]),
+        )
+        .unwrap();
+        for (v, shares) in (0..10_u128).zip(r0.into_iter().zip(r1).zip(r2)) {
+            let ((s0, s1), s2) = shares;
+            assert_eq!(Fp31::from(v * v), validate_and_reconstruct((s0, s1, s2)));
+        }
+
+        // one round for every chunk of 4 records
+        assert_eq!(3, counters[0].report().step("multiply_batch").rounds);
+    }
+
+    #[tokio::test]
+    async fn send_to_identity() {
+        let world = TestWorld::new();
+        let context = world.contexts();
+
+        context[0].send_to(Identity::H3, 1_u8).await.unwrap();
+        context[2].send_to(Identity::H1, 3_u8).await.unwrap();
+        context[1].broadcast(2_u8).await.unwrap();
+
+        assert_eq!(
+            1,
+            context[2].receive_from::<u8>(Identity::H1).await.unwrap()
+        );
+        assert_eq!(
+            3,
+            context[0].receive_from::<u8>(Identity::H3).await.unwrap()
+        );
+        assert_eq!(
+            2,
+            context[0].receive_from::<u8>(Identity::H2).await.unwrap()
+        );
+        assert_eq!(
+            2,
+            context[2].receive_from::<u8>(Identity::H2).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn prss_shares() {
+        let world = TestWorld::new();
+        let context = world.contexts();
+
+        let mut values = Vec::new();
+        for index in 0..10 {
+            let shares = [0, 1, 2].map(|i| context[i].prss_random_share::<Fp31>(index));
+            values.push(ReplicatedSecretSharing::reconstruct(&shares).unwrap());
+
+            let zero: Fp31 = context
+                .iter()
+                .map(|ctx| ctx.prss_zero_share::<Fp31>(index))
+                .fold(Fp31::ZERO, |acc, v| acc + v);
+            assert_eq!(Fp31::ZERO, zero);
+        }
+        // not all random values can be the same
+        assert!(values.iter().any(|v| *v != values[0]));
     }
 
     fn validate_and_reconstruct<T: Field>(
@@ -0,0 +1,151 @@
+//!
+//! Permutations that shuffle secret-shared rows, derived from PRSS. Every pass of a shuffle is
+//! done by two of the helpers, which permute the rows in the same way and reshare them to the
+//! third, so the pair needs a permutation that both of them know and the third does not. Both
+//! of them hold the PRSS key they share, so they derive the permutation from it on their own,
+//! instead of one of them drawing it and sending it to the other.
+//!
+//! [`permutations`] gives every helper the permutation it shares with the helper on its left and
+//! the one it shares with the helper on its right. Each is a Fisher–Yates shuffle that draws the
+//! position to swap with at step `i` from PRSS index `index + i`, with the key shared with that
+//! helper. The third helper has neither key, so it cannot tell the permutation from any other.
+//!
+//! Swap positions are PRSS values reduced modulo the number of positions left, which biases
+//! permutations of `n` rows by less than `n / 2^128`.
+//!
+use crate::helpers::ring::Ring;
+use crate::securemul::ProtocolContext;
+
+/// Reordering of `len` items: item `i` of the output is item `self[i]` of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permutation(Vec<usize>);
+
+impl Permutation {
+    /// Permutation that leaves everything in place.
+    #[must_use]
+    pub fn identity(len: usize) -> Self {
+        Self((0..len).collect())
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Positions of the input items in the output.
+    #[must_use]
+    pub fn as_slice(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// Permutation that puts items back where they were before this one moved them.
+    #[must_use]
+    pub fn inverse(&self) -> Self {
+        let mut inverse = vec![0; self.0.len()];
+        for (to, &from) in self.0.iter().enumerate() {
+            inverse[from] = to;
+        }
+        Self(inverse)
+    }
+
+    /// Reorders `items`.
+    ///
+    /// ## Panics
+    /// If there are not as many items as the permutation has positions.
+    #[must_use]
+    pub fn apply<T: Clone>(&self, items: &[T]) -> Vec<T> {
+        assert_eq!(
+            self.0.len(),
+            items.len(),
+            "permutation of {} items applied to {} items",
+            self.0.len(),
+            items.len()
+        );
+        self.0.iter().map(|&i| items[i].clone()).collect()
+    }
+}
+
+/// Permutations of `len` items that this helper shares with the helper on its left and with the
+/// helper on its right, in that order. Steps of the shuffles use PRSS indices from `index` up
+/// to `index + len`, exclusive, which are claimed for `step` and must not be used by anything
+/// else, so every pass of a shuffle needs indices of its own.
+///
+/// ## Panics
+/// If `len` does not fit into the PRSS index space after `index`.
+#[must_use]
+pub fn permutations<R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    step: &'static str,
+    index: u128,
+    len: usize,
+) -> (Permutation, Permutation) {
+    let mut left = Permutation::identity(len);
+    let mut right = Permutation::identity(len);
+    for i in (1..len).rev() {
+        let record = index
+            .checked_add(i as u128)
+            .expect("PRSS indices of the shuffle overflow");
+        let (l, r) = ctx.bind(step, record).prss_values();
+        let positions = i as u128 + 1;
+        left.0.swap(i, usize::try_from(l % positions).unwrap());
+        right.0.swap(i, usize::try_from(r % positions).unwrap());
+    }
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shuffle::{permutations, Permutation};
+    use crate::step;
+    use crate::test_fixture::TestWorld;
+
+    const ROWS: usize = 100;
+
+    #[tokio::test]
+    async fn pairs_agree() {
+        let world = TestWorld::new();
+        let [c1, c2, c3] = world.contexts();
+        let (l1, r1) = permutations(&c1, step::SHUFFLE, 1, ROWS);
+        let (l2, r2) = permutations(&c2, step::SHUFFLE, 1, ROWS);
+        let (l3, r3) = permutations(&c3, step::SHUFFLE, 1, ROWS);
+
+        // every helper shares its right key with the helper on its right
+        assert_eq!(r1, l2);
+        assert_eq!(r2, l3);
+        assert_eq!(r3, l1);
+
+        // and has nothing in common with the permutation of the other two
+        for (pair, third) in [(&r1, [&l3, &r3]), (&r2, [&l1, &r1]), (&r3, [&l2, &r2])] {
+            assert!(third.iter().all(|p| *p != pair));
+            assert_ne!(&Permutation::identity(ROWS), pair);
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_differ() {
+        let world = TestWorld::new();
+        let [c1, _, _] = world.contexts();
+        let (first, _) = permutations(&c1, step::bit(0), 1, ROWS);
+        let (second, _) = permutations(&c1, step::bit(1), 1 + ROWS as u128, ROWS);
+        assert_ne!(first, second);
+
+        // every pass is a permutation
+        let mut sorted = first.as_slice().to_vec();
+        sorted.sort_unstable();
+        assert_eq!(Permutation::identity(ROWS).as_slice(), sorted);
+    }
+
+    #[test]
+    fn apply_and_inverse() {
+        let p = Permutation(vec![2, 0, 1]);
+        let shuffled = p.apply(&["a", "b", "c"]);
+        assert_eq!(["c", "a", "b"], shuffled[..]);
+        assert_eq!(["a", "b", "c"], p.inverse().apply(&shuffled)[..]);
+        assert_eq!(vec![0, 1, 2], p.inverse().apply(p.as_slice()));
+    }
+}
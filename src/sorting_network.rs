@@ -0,0 +1,276 @@
+//!
+//! Oblivious sorting of secret-shared rows with a sorting network. The sequence of compared
+//! positions of a network does not depend on the data, so helpers learn nothing about the order
+//! of the rows from running it. The only gate it needs is a conditional swap of two rows, driven
+//! by a secret-shared bit.
+//!
+//! Comparisons are done by the caller, who is free to use any protocol that produces shares of
+//! the swap bit, for example one that compares secret values directly, without decomposing them
+//! into bits first. The network used is Batcher's odd-even merge sort, with `O(log^2 n)` layers
+//! of comparators that are independent of each other within a layer. Every layer costs one
+//! call to the comparator and one round for the swaps, so this is a good fit for small inputs.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::Ring;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use std::future::Future;
+use thiserror::Error;
+
+/// Shares of a single row, one per column.
+pub type Row<F> = Vec<ReplicatedSecretSharing<F>>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Rows {first} and {second} have different number of columns")]
+    RowLengthMismatch { first: usize, second: usize },
+    #[error("Expected {expected} swap bits, but got {actual}")]
+    SwapBitCount { expected: usize, actual: usize },
+}
+
+/// Comparators of the odd-even merge sort network for `n` rows. Every layer lists pairs of
+/// positions `(i, j)` with `i < j`, and no position appears twice in a layer. Positions are
+/// sorted once every pair of every layer is put in order, in sequence.
+#[must_use]
+pub fn odd_even_merge_layers(n: usize) -> Vec<Vec<(usize, usize)>> {
+    // network for the next power of two, without the comparators that involve positions past
+    // the end. Those would compare rows with padding that sorts last and never swap.
+    let mut layers = Vec::new();
+    let mut p = 1;
+    while p < n {
+        let mut k = p;
+        while k >= 1 {
+            let mut layer = Vec::new();
+            let mut j = k % p;
+            while j + k < n {
+                for i in j..j + k.min(n - j - k) {
+                    if i / (2 * p) == (i + k) / (2 * p) {
+                        layer.push((i, i + k));
+                    }
+                }
+                j += 2 * k;
+            }
+            if !layer.is_empty() {
+                layers.push(layer);
+            }
+            k /= 2;
+        }
+        p *= 2;
+    }
+    layers
+}
+
+/// Swaps rows `i` and `j` of every pair in `pairs` if the corresponding share in `swap` is a
+/// share of one, and leaves them as they are if it is a share of zero. All swaps are done in
+/// a single round and use PRSS indices from `index` to `index + pairs.len() * columns`.
+///
+/// ## Errors
+/// If the number of swap bits does not match the number of pairs, rows that are swapped have
+/// different number of columns, or multiplication fails.
+///
+/// ## Panics
+/// If a pair refers to a position outside of `rows`.
+pub async fn conditional_swap<F: Field, R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    index: u128,
+    rows: &mut [Row<F>],
+    pairs: &[(usize, usize)],
+    swap: &[ReplicatedSecretSharing<F>],
+) -> Res<()> {
+    if swap.len() != pairs.len() {
+        return Err(Error::SwapBitCount {
+            expected: pairs.len(),
+            actual: swap.len(),
+        }
+        .into());
+    }
+
+    // rows[i] + bit * (rows[j] - rows[i]) ends up in i and rows[j] - the same in j
+    let mut bits = ctx.scratch(pairs.len());
+    let mut diffs = ctx.scratch(pairs.len());
+    for (&(i, j), &bit) in pairs.iter().zip(swap) {
+        if rows[i].len() != rows[j].len() {
+            return Err(Error::RowLengthMismatch {
+                first: i,
+                second: j,
+            }
+            .into());
+        }
+        for (a, b) in rows[i].iter().zip(&rows[j]) {
+            bits.push(bit);
+            diffs.push(*b - *a);
+        }
+    }
+    let mut deltas = ctx.multiply_batch(index, &bits, &diffs).await?.into_iter();
+
+    for &(i, j) in pairs {
+        for c in 0..rows[i].len() {
+            let delta = deltas.next().unwrap();
+            rows[i][c] = rows[i][c] + delta;
+            rows[j][c] = rows[j][c] - delta;
+        }
+    }
+    Ok(())
+}
+
+/// Sorts `rows` with the odd-even merge sort network. For every layer of the network,
+/// `compare` gets the pairs of rows compared by it and must return, for every pair, a share of
+/// one if the rows are out of order and a share of zero otherwise. All helpers must use the
+/// same comparator.
+///
+/// `next_index` is the first PRSS index that is not used yet and is advanced past every index
+/// used by the sort. `compare` gets it too and must advance it past the indices it uses before
+/// returning the future.
+///
+/// ## Errors
+/// If comparison fails, it returns the wrong number of swap bits, rows have different number
+/// of columns, or multiplication fails.
+pub async fn sort<F, R, C, Fut>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    rows: &mut [Row<F>],
+    mut compare: C,
+) -> Res<()>
+where
+    F: Field,
+    R: Ring,
+    C: FnMut(&mut u128, Vec<(Row<F>, Row<F>)>) -> Fut,
+    Fut: Future<Output = Res<Vec<ReplicatedSecretSharing<F>>>>,
+{
+    for layer in odd_even_merge_layers(rows.len()) {
+        let pairs = layer
+            .iter()
+            .map(|&(i, j)| (rows[i].clone(), rows[j].clone()))
+            .collect();
+        let swap = compare(next_index, pairs).await?;
+
+        let index = *next_index;
+        *next_index += layer
+            .iter()
+            .map(|&(i, _)| rows[i].len() as u128)
+            .sum::<u128>();
+        conditional_swap(ctx, index, rows, &layer, &swap).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Res;
+    use crate::field::Fp31;
+    use crate::helpers::ring::mock::TestHelper;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::securemul::ProtocolContext;
+    use crate::sorting_network::{odd_even_merge_layers, sort, Row};
+    use crate::telemetry::rounds::RoundCounter;
+    use crate::test_fixture::TestWorld;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    fn network_sorts() {
+        // a comparator network sorts everything if it sorts all sequences of zeros and ones
+        for n in 0..=12 {
+            let layers = odd_even_merge_layers(n);
+            for input in 0..1_u32 << n {
+                let mut bits = (0..n).map(|i| (input >> i) & 1).collect::<Vec<_>>();
+                for layer in &layers {
+                    for &(i, j) in layer {
+                        assert!(i < j && j < n);
+                        if bits[i] > bits[j] {
+                            bits.swap(i, j);
+                        }
+                    }
+                }
+                assert!(
+                    bits.windows(2).all(|w| w[0] <= w[1]),
+                    "n={n} input={input:b}"
+                );
+            }
+        }
+        assert_eq!(6, odd_even_merge_layers(8).len());
+    }
+
+    /// Shares of one if the key in the first column of the first row is greater than the key of
+    /// the second one. Keys must be zero or one, and then that is `a * (1 - b)`.
+    async fn compare_bits(
+        ctx: &ProtocolContext<'_, TestHelper>,
+        index: u128,
+        pairs: Vec<(Row<Fp31>, Row<Fp31>)>,
+    ) -> Res<Vec<ReplicatedSecretSharing<Fp31>>> {
+        let (a, b): (Vec<_>, Vec<_>) = pairs.iter().map(|(a, b)| (a[0], b[0])).unzip();
+        let ab = ctx.multiply_batch(index, &a, &b).await?;
+        Ok(a.into_iter().zip(ab).map(|(a, ab)| a - ab).collect())
+    }
+
+    #[tokio::test]
+    async fn sort_by_secret_bit() {
+        let world = TestWorld::new();
+        let counters = [(); 3].map(|()| RoundCounter::default());
+        let [c0, c1, c2] = world.contexts();
+        let ctx = [
+            c0.with_rounds(&counters[0]),
+            c1.with_rounds(&counters[1]),
+            c2.with_rounds(&counters[2]),
+        ];
+
+        let input: [(u128, u128); 7] = [
+            (1, 10),
+            (0, 11),
+            (1, 12),
+            (1, 13),
+            (0, 14),
+            (0, 15),
+            (1, 16),
+        ];
+        let mut rand = StepRng::new(1, 3);
+        let mut rows = [Vec::new(), Vec::new(), Vec::new()];
+        for (key, value) in input {
+            let key = ReplicatedSecretSharing::share(Fp31::from(key), &mut rand);
+            let value = ReplicatedSecretSharing::share(Fp31::from(value), &mut rand);
+            for (i, helper) in rows.iter_mut().enumerate() {
+                helper.push(vec![key[i], value[i]]);
+            }
+        }
+
+        let [mut r0, mut r1, mut r2] = rows;
+        let mut next_index = [1; 3];
+        let [n0, n1, n2] = &mut next_index;
+        let compare = |ctx| {
+            move |next: &mut u128, pairs: Vec<(Row<Fp31>, Row<Fp31>)>| {
+                let index = *next;
+                *next += pairs.len() as u128;
+                compare_bits(ctx, index, pairs)
+            }
+        };
+        futures::try_join!(
+            sort(&ctx[0], n0, &mut r0, compare(&ctx[0])),
+            sort(&ctx[1], n1, &mut r1, compare(&ctx[1])),
+            sort(&ctx[2], n2, &mut r2, compare(&ctx[2])),
+        )
+        .unwrap();
+        assert!(next_index.iter().all(|n| *n == next_index[0]));
+
+        let sorted = (0..input.len())
+            .map(|row| {
+                [0, 1].map(|c| {
+                    let shares = [r0[row][c], r1[row][c], r2[row][c]];
+                    u8::from(ReplicatedSecretSharing::reconstruct(&shares).unwrap())
+                })
+            })
+            .collect::<Vec<_>>();
+        let keys = sorted.iter().map(|[k, _]| *k).collect::<Vec<_>>();
+        assert_eq!(vec![0, 0, 0, 1, 1, 1, 1], keys);
+        let mut values = sorted.iter().map(|[_, v]| *v).collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!((10..=16).collect::<Vec<_>>(), values);
+
+        // a round to compare and a round to swap for every layer. Helpers do not move from one
+        // layer to the next in lockstep, so rounds are counted for one of them only
+        let layers = odd_even_merge_layers(input.len()).len() as u64;
+        assert_eq!(
+            2 * layers,
+            counters[0].report().step("multiply_batch").rounds
+        );
+    }
+}
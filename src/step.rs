@@ -0,0 +1,187 @@
+//!
+//! Names of protocol steps. They label metrics, round counts and errors, and claim PRSS indices
+//! (see [`crate::prss::Participant::claim`]), so two steps with the same name would be mixed up
+//! everywhere without anything failing. Every name is listed in [`ALL`], which is checked for
+//! duplicates when the crate is compiled.
+//!
+//! Loops that run a number of times only known at runtime, such as one iteration for every bit
+//! of a value, need a step for every iteration. Their names come from [`bit`] and
+//! [`iteration`], which hand out a fixed set of names that is checked along with the others, so
+//! nothing needs to be allocated and every name remains `&'static str`.
+//!
+
+pub const SECURE_MUL: &str = "securemul";
+pub const SUM_OF_PRODUCTS: &str = "sum_of_products";
+pub const MULTIPLY_BATCH: &str = "multiply_batch";
+pub const REVEAL: &str = "reveal";
+pub const PRSS_RANDOM_SHARE: &str = "prss_random_share";
+pub const PRSS_ZERO_SHARE: &str = "prss_zero_share";
+pub const RANDOM_BITS: &str = "random_bits";
+pub const INPUT_COMMITMENT: &str = "input_commitment";
+pub const CLOCK_CHECK: &str = "clock_check";
+pub const SKIPPED_REPORTS: &str = "skipped_reports";
+pub const SHUFFLE: &str = "shuffle";
+pub const ROLE_CHECK: &str = "role_check";
+pub const TRANSCRIPT_CHECK: &str = "transcript_check";
+
+/// All step names. A name that is added above must be added here as well.
+pub const ALL: &[&str] = &[
+    SECURE_MUL,
+    SUM_OF_PRODUCTS,
+    MULTIPLY_BATCH,
+    REVEAL,
+    PRSS_RANDOM_SHARE,
+    PRSS_ZERO_SHARE,
+    RANDOM_BITS,
+    INPUT_COMMITMENT,
+    CLOCK_CHECK,
+    SKIPPED_REPORTS,
+    SHUFFLE,
+    ROLE_CHECK,
+    TRANSCRIPT_CHECK,
+];
+
+/// Number of distinct [`bit`] steps.
+pub const MAX_BITS: usize = 128;
+/// Number of distinct [`iteration`] steps.
+pub const MAX_ITERATIONS: usize = 256;
+
+/// Array of names made of `$prefix` followed by every one of the numbers.
+macro_rules! numbered {
+    ($prefix:literal; $($n:literal)*) => {
+        [$(concat!($prefix, $n)),*]
+    };
+}
+
+const BIT: [&str; MAX_BITS] = numbered!("bit";
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34
+    35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66
+    67 68 69 70 71 72 73 74 75 76 77 78 79 80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95 96 97 98
+    99 100 101 102 103 104 105 106 107 108 109 110 111 112 113 114 115 116 117 118 119 120 121 122
+    123 124 125 126 127
+);
+
+const ITERATION: [&str; MAX_ITERATIONS] = numbered!("iteration";
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34
+    35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66
+    67 68 69 70 71 72 73 74 75 76 77 78 79 80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95 96 97 98
+    99 100 101 102 103 104 105 106 107 108 109 110 111 112 113 114 115 116 117 118 119 120 121 122
+    123 124 125 126 127 128 129 130 131 132 133 134 135 136 137 138 139 140 141 142 143 144 145 146
+    147 148 149 150 151 152 153 154 155 156 157 158 159 160 161 162 163 164 165 166 167 168 169 170
+    171 172 173 174 175 176 177 178 179 180 181 182 183 184 185 186 187 188 189 190 191 192 193 194
+    195 196 197 198 199 200 201 202 203 204 205 206 207 208 209 210 211 212 213 214 215 216 217 218
+    219 220 221 222 223 224 225 226 227 228 229 230 231 232 233 234 235 236 237 238 239 240 241 242
+    243 244 245 246 247 248 249 250 251 252 253 254 255
+);
+
+const _: () = assert!(distinct(ALL), "step names must be unique");
+// numbered names are distinct from each other by construction
+const _: () = assert!(disjoint(ALL, &BIT) && disjoint(ALL, &ITERATION));
+
+/// Step of the iteration of a loop over bits that works on bit `i`.
+///
+/// ## Panics
+/// If `i` is not less than [`MAX_BITS`].
+#[must_use]
+pub fn bit(i: usize) -> &'static str {
+    assert!(i < MAX_BITS, "bit {i} is out of range of bit steps");
+    BIT[i]
+}
+
+/// Step of iteration `i` of a loop that runs a number of times only known at runtime, such as
+/// once for every bucket.
+///
+/// ## Panics
+/// If `i` is not less than [`MAX_ITERATIONS`].
+#[must_use]
+pub fn iteration(i: usize) -> &'static str {
+    assert!(
+        i < MAX_ITERATIONS,
+        "iteration {i} is out of range of iteration steps"
+    );
+    ITERATION[i]
+}
+
+/// Whether `name` is one of the steps of this module.
+#[must_use]
+pub fn is_known(name: &str) -> bool {
+    ALL.iter()
+        .chain(BIT.iter())
+        .chain(ITERATION.iter())
+        .any(|&s| s == name)
+}
+
+const fn equal(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether none of `a` is the same as any of `b`.
+const fn disjoint(a: &[&str], b: &[&str]) -> bool {
+    let mut i = 0;
+    while i < a.len() {
+        let mut j = 0;
+        while j < b.len() {
+            if equal(a[i], b[j]) {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether no two of `names` are the same. Usable in constants, so duplicates fail the build.
+#[must_use]
+pub const fn distinct(names: &[&str]) -> bool {
+    let mut i = 0;
+    while i < names.len() {
+        let mut j = i + 1;
+        while j < names.len() {
+            if equal(names[i], names[j]) {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::step::{bit, distinct, is_known, iteration, MAX_BITS, SECURE_MUL};
+
+    #[test]
+    fn duplicates() {
+        assert!(distinct(&[]));
+        assert!(distinct(&["reveal", "securemul", "secure"]));
+        assert!(!distinct(&["reveal", "securemul", "reveal"]));
+    }
+
+    #[test]
+    fn dynamic_steps() {
+        assert_eq!("bit0", bit(0));
+        assert_eq!("iteration17", iteration(17));
+        assert!(is_known(bit(MAX_BITS - 1)));
+        assert!(is_known(SECURE_MUL));
+        assert!(!is_known("bit128"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn bit_out_of_range() {
+        let _ = bit(MAX_BITS);
+    }
+}
@@ -0,0 +1,458 @@
+//!
+//! Encrypted storage for data a helper spills to disk while it runs a query, such as input
+//! shares that do not fit in memory or checkpoints.
+//!
+//! Every query gets its own data key, which encrypts everything stored for that query. The data
+//! key itself is stored next to the data, wrapped (encrypted) by the long-term storage key of the
+//! helper, so a copy of the disk reveals nothing without the storage key. Once the query
+//! completes, [`BlobStore::destroy`] overwrites and removes everything stored for it.
+//!
+//! Blobs are sealed with AES-256-GCM under a random nonce, with the name of the blob as associated
+//! data:
+//!
+//! ```text
+//! | nonce (12 bytes) | ciphertext | tag (16 bytes) |
+//! ```
+//!
+//! A blob that was modified or moved to a different name fails to open.
+//!
+//! Data that does not outlive the helper process, such as messages it spills to disk while it
+//! runs a query, goes to an [ephemeral](BlobStore::ephemeral) store, whose data key never leaves
+//! memory.
+//!
+//! What the query subsystem keeps about queries beyond a single run of a helper, their metadata,
+//! checkpoints, the audit log and the privacy-budget ledger, goes through the [`QueryStore`]
+//! trait instead. Tests use the [`MemoryStore`], helpers the [`DiskStore`], which keeps it in a
+//! [`BlobStore`] of its own.
+//!
+mod query_store;
+
+#[cfg(feature = "enable-serde")]
+pub use query_store::DiskStore;
+pub use query_store::{AuditEntry, MemoryStore, QueryMetadata, QueryState, QueryStore};
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use rand::{CryptoRng, RngCore};
+use std::fmt::{Debug, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Largest blob that can be sealed, which is what GCM encrypts under a single nonce.
+const MAX_BLOB_SIZE: u64 = (1 << 36) - 32;
+/// Size of the chunks of zeros that files are overwritten with, see [`wipe`].
+const WIPE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Name of the file that holds the wrapped data key of a query.
+const DATA_KEY_FILE: &str = "data.key";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("blob {0} is corrupted or was sealed with a different key")]
+    Corrupted(String),
+    #[error("blob of {0} bytes is too large to seal")]
+    TooLarge(usize),
+    #[error("{0} is not a valid blob name")]
+    BadName(String),
+    #[error("record {0} is malformed")]
+    Malformed(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Symmetric key that seals blobs. Its bytes are wiped from memory when it is dropped.
+struct Key([u8; KEY_SIZE]);
+
+impl Key {
+    fn random<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut key = [0; KEY_SIZE];
+        rng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Encrypts `data` and binds it to `name`.
+    fn seal<R: RngCore + CryptoRng>(
+        &self,
+        name: &str,
+        data: &[u8],
+        rng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        if data.len() as u64 > MAX_BLOB_SIZE {
+            return Err(Error::TooLarge(data.len()));
+        }
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: data,
+            aad: name.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::TooLarge(data.len()))?;
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts a blob sealed with [`Key::seal`] under the same `name`.
+    fn open(&self, name: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(Error::Corrupted(name.to_owned()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: name.as_bytes(),
+        };
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Corrupted(name.to_owned()))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.0).unwrap() // the key has the size AES-256 takes
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Long-term key of a helper that wraps data keys of its queries.
+pub struct StorageKey(Key);
+
+impl StorageKey {
+    #[must_use]
+    pub fn new<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Self(Key::random(rng))
+    }
+
+    #[must_use]
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(Key(bytes))
+    }
+}
+
+impl Debug for StorageKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StorageKey")
+    }
+}
+
+/// Blobs stored for a single query, in a directory of their own.
+pub struct BlobStore {
+    dir: PathBuf,
+    data_key: Key,
+    /// Whether the store is destroyed when it is dropped, see [`BlobStore::ephemeral`].
+    ephemeral: bool,
+}
+
+impl BlobStore {
+    /// Creates an empty store for query `id` under `root`, with a fresh data key.
+    ///
+    /// ## Errors
+    /// If the store exists already or cannot be created.
+    pub fn create<R: RngCore + CryptoRng>(
+        root: &Path,
+        id: &str,
+        storage_key: &StorageKey,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let dir = root.join(check_name(id)?);
+        fs::create_dir_all(root)?;
+        fs::create_dir(&dir)?;
+
+        let data_key = Key::random(rng);
+        let wrapped = storage_key.0.seal(id, &data_key.0, rng)?;
+        write_new(&dir.join(DATA_KEY_FILE), &wrapped, true)?;
+        Ok(Self {
+            dir,
+            data_key,
+            ephemeral: false,
+        })
+    }
+
+    /// Creates an empty store under `root` for data that does not outlive this process. Its data
+    /// key is never written anywhere, so blobs are neither synced to disk nor overwritten when
+    /// they are removed: nothing can open them once the store is gone. The store is destroyed
+    /// when it is dropped.
+    ///
+    /// ## Errors
+    /// If the store cannot be created.
+    pub fn ephemeral<R: RngCore + CryptoRng>(root: &Path, rng: &mut R) -> Result<Self, Error> {
+        let dir = root.join(format!("tmp-{:016x}", rng.next_u64()));
+        fs::create_dir_all(root)?;
+        fs::create_dir(&dir)?;
+        Ok(Self {
+            dir,
+            data_key: Key::random(rng),
+            ephemeral: true,
+        })
+    }
+
+    /// Opens the store of query `id` under `root`, created by [`BlobStore::create`] before,
+    /// possibly by a helper process that has stopped since.
+    ///
+    /// ## Errors
+    /// If the store does not exist or its data key was not wrapped with `storage_key`.
+    pub fn open(root: &Path, id: &str, storage_key: &StorageKey) -> Result<Self, Error> {
+        let dir = root.join(check_name(id)?);
+        let mut unwrapped = storage_key
+            .0
+            .open(id, &fs::read(dir.join(DATA_KEY_FILE))?)?;
+        let data_key = <[u8; KEY_SIZE]>::try_from(&unwrapped[..])
+            .map(Key)
+            .map_err(|_| Error::Corrupted(DATA_KEY_FILE.to_owned()));
+        unwrapped.zeroize();
+        Ok(Self {
+            dir,
+            data_key: data_key?,
+            ephemeral: false,
+        })
+    }
+
+    /// Seals `data` and stores it as `name`, replacing whatever was stored under that name.
+    ///
+    /// ## Errors
+    /// If `name` is not a plain file name, `data` is too large, or it cannot be written.
+    pub fn put<R: RngCore + CryptoRng>(
+        &self,
+        name: &str,
+        data: &[u8],
+        rng: &mut R,
+    ) -> Result<(), Error> {
+        let path = self.path(name)?;
+        let sealed = self.data_key.seal(name, data, rng)?;
+        // written next to the blob and moved over it, so a crash never leaves half of it behind
+        let tmp = self.dir.join(format!(".{name}"));
+        write_new(&tmp, &sealed, !self.ephemeral)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Reads and opens the blob stored as `name`.
+    ///
+    /// ## Errors
+    /// If there is no such blob, or it was modified since it was stored.
+    pub fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let sealed = fs::read(self.path(name)?)?;
+        self.data_key.open(name, &sealed)
+    }
+
+    /// Names of all blobs in the store, sorted.
+    ///
+    /// ## Errors
+    /// If the directory of the store cannot be read.
+    pub fn names(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name != DATA_KEY_FILE && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Overwrites the blob stored as `name` and removes it. Does nothing if there is none.
+    ///
+    /// ## Errors
+    /// If `name` is not a plain file name, or the blob cannot be overwritten or removed.
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let path = self.path(name)?;
+        if !self.ephemeral {
+            return Ok(wipe(&path)?);
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Overwrites every file of the store, including the wrapped data key, with zeros and
+    /// removes them along with the directory. Storage that keeps old copies of data (journaling
+    /// or copy-on-write file systems, SSD wear leveling) may still hold sealed blobs, but without
+    /// the wrapped data key they cannot be opened.
+    ///
+    /// ## Errors
+    /// If files cannot be overwritten or removed.
+    pub fn destroy(self) -> Result<(), Error> {
+        // the data key goes first, which makes everything else unreadable even if this fails
+        // half-way
+        wipe(&self.dir.join(DATA_KEY_FILE))?;
+        for entry in fs::read_dir(&self.dir)? {
+            wipe(&entry?.path())?;
+        }
+        fs::remove_dir(&self.dir)?;
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        // names starting with a dot are kept for files that are being written
+        if name == DATA_KEY_FILE || name.starts_with('.') {
+            return Err(Error::BadName(name.to_owned()));
+        }
+        Ok(self.dir.join(check_name(name)?))
+    }
+}
+
+impl Drop for BlobStore {
+    fn drop(&mut self) {
+        if self.ephemeral {
+            // nothing to do about it, and nothing can open what is left anyway
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+impl Debug for BlobStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BlobStore({})", self.dir.display())
+    }
+}
+
+/// Names of queries and blobs become file names, so they must not point anywhere else.
+fn check_name(name: &str) -> Result<&str, Error> {
+    let valid =
+        !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0']);
+    if valid {
+        Ok(name)
+    } else {
+        Err(Error::BadName(name.to_owned()))
+    }
+}
+
+/// Writes `data` to the file at `path`, and makes sure it reaches the disk if `sync` is set.
+fn write_new(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    f.write_all(data)?;
+    if sync {
+        f.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Overwrites the file with zeros, makes sure that reaches the disk, and removes it. Zeros are
+/// written [`WIPE_CHUNK_SIZE`] bytes at a time, so wiping a large file does not take as much
+/// memory.
+fn wipe(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut remaining = fs::metadata(path)?.len();
+    let mut f = OpenOptions::new().write(true).open(path)?;
+    let zeros = vec![0; WIPE_CHUNK_SIZE];
+    while remaining > 0 {
+        let len = usize::try_from(remaining).map_or(WIPE_CHUNK_SIZE, |r| r.min(WIPE_CHUNK_SIZE));
+        f.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    f.sync_all()?;
+    drop(f);
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        wipe, BlobStore, Error, StorageKey, DATA_KEY_FILE, NONCE_SIZE, TAG_SIZE, WIPE_CHUNK_SIZE,
+    };
+    use rand::thread_rng;
+    use std::fs;
+
+    #[test]
+    fn round_trip_and_destroy() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-storage-{}", std::process::id()));
+        let mut rng = thread_rng();
+        let key = StorageKey::new(&mut rng);
+
+        let store = BlobStore::create(&root, "q1", &key, &mut rng).unwrap();
+        store.put("shares-0", b"secret shares", &mut rng).unwrap();
+        store.put("empty", b"", &mut rng).unwrap();
+        assert_eq!(b"secret shares".to_vec(), store.get("shares-0").unwrap());
+        assert_eq!(Vec::<u8>::new(), store.get("empty").unwrap());
+
+        // nothing is stored in the clear
+        let on_disk = fs::read(root.join("q1").join("shares-0")).unwrap();
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+
+        // blobs are bound to their names
+        fs::copy(root.join("q1/shares-0"), root.join("q1/shares-1")).unwrap();
+        assert!(matches!(store.get("shares-1"), Err(Error::Corrupted(_))));
+        assert!(matches!(store.get("../q1"), Err(Error::BadName(_))));
+        assert!(matches!(store.get(DATA_KEY_FILE), Err(Error::BadName(_))));
+
+        // reopened with the storage key, but not with any other key
+        let reopened = BlobStore::open(&root, "q1", &key).unwrap();
+        assert_eq!(b"secret shares".to_vec(), reopened.get("shares-0").unwrap());
+        let other = StorageKey::new(&mut rng);
+        assert!(matches!(
+            BlobStore::open(&root, "q1", &other),
+            Err(Error::Corrupted(_))
+        ));
+
+        store.destroy().unwrap();
+        assert!(!root.join("q1").exists());
+        fs::remove_dir(root).unwrap();
+    }
+
+    #[test]
+    fn ephemeral() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-ephemeral-{}", std::process::id()));
+        let mut rng = thread_rng();
+
+        let store = BlobStore::ephemeral(&root, &mut rng).unwrap();
+        store.put("spill-0", b"secret message", &mut rng).unwrap();
+        assert_eq!(b"secret message".to_vec(), store.get("spill-0").unwrap());
+        let dir = fs::read_dir(&root).unwrap().next().unwrap().unwrap().path();
+        let on_disk = fs::read(dir.join("spill-0")).unwrap();
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+        assert!(!dir.join(DATA_KEY_FILE).exists());
+
+        store.remove("spill-0").unwrap();
+        store.remove("spill-0").unwrap();
+        drop(store);
+        assert!(!dir.exists());
+        fs::remove_dir(root).unwrap();
+    }
+
+    #[test]
+    fn tampering() {
+        let mut rng = thread_rng();
+        let key = StorageKey::new(&mut rng).0;
+        let mut sealed = key.seal("blob", &[7; 100], &mut rng).unwrap();
+        assert_eq!(NONCE_SIZE + 100 + TAG_SIZE, sealed.len());
+        assert_eq!(vec![7; 100], key.open("blob", &sealed).unwrap());
+        assert!(key.open("other", &sealed).is_err());
+
+        sealed[20] ^= 1;
+        assert!(key.open("blob", &sealed).is_err());
+        assert!(key.open("blob", &sealed[..10]).is_err());
+    }
+
+    #[test]
+    fn wipe_in_chunks() {
+        let path = std::env::temp_dir().join(format!("raw-ipa-wipe-{}", std::process::id()));
+        fs::write(&path, vec![1; 2 * WIPE_CHUNK_SIZE + 5]).unwrap();
+        wipe(&path).unwrap();
+        assert!(!path.exists());
+        // nothing to wipe is fine
+        wipe(&path).unwrap();
+    }
+}
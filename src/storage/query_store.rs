@@ -0,0 +1,552 @@
+//!
+//! Records the query subsystem keeps across runs of a helper: metadata of every query, the
+//! checkpoints queries resume from, the audit log and the ledger of privacy budgets spent. Code
+//! that keeps any of them takes a [`QueryStore`] and does not care where they go, so tests run
+//! it against a [`MemoryStore`] and helpers against a [`DiskStore`], which survives restarts.
+//!
+//! A [`DiskStore`] keeps every record in a blob of its own, sealed the same way as any other
+//! blob of a [`BlobStore`], so it holds nothing in the clear either. Records are encoded with
+//! bincode.
+//!
+use crate::storage::Error;
+#[cfg(feature = "enable-serde")]
+use crate::storage::{BlobStore, StorageKey};
+#[cfg(feature = "enable-serde")]
+use rand::rngs::OsRng;
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+#[cfg(feature = "enable-serde")]
+use std::fmt::Write;
+#[cfg(feature = "enable-serde")]
+use std::io;
+#[cfg(feature = "enable-serde")]
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Where a query is in its life.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum QueryState {
+    Running,
+    /// Query stopped at a checkpoint, which it resumes from.
+    Suspended,
+    Completed,
+    Failed {
+        reason: String,
+    },
+}
+
+/// What a helper knows about a query it accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct QueryMetadata {
+    pub id: String,
+    /// Authenticated identity of the report collector that submitted the query.
+    pub collector: String,
+    pub epoch: u8,
+    /// Number of input rows.
+    pub rows: u64,
+    /// Seconds since the Unix epoch the query was submitted at.
+    pub submitted: u64,
+    pub state: QueryState,
+}
+
+/// Decision or event that operators and auditors must be able to look up later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    /// Collector the entry is about, empty if none.
+    pub collector: String,
+    pub event: String,
+}
+
+/// Durable records of the query subsystem. Implementations are shared by everything a helper
+/// runs, so every method takes `&self` and is atomic on its own.
+pub trait QueryStore: Debug + Send + Sync {
+    /// Stores the metadata of a query, replacing what was stored for the same id.
+    ///
+    /// ## Errors
+    /// If the metadata cannot be stored.
+    fn put_query(&self, query: &QueryMetadata) -> Result<(), Error>;
+
+    /// Metadata of query `id`, if there is any.
+    ///
+    /// ## Errors
+    /// If the metadata cannot be read.
+    fn query(&self, id: &str) -> Result<Option<QueryMetadata>, Error>;
+
+    /// Metadata of all queries, sorted by id.
+    ///
+    /// ## Errors
+    /// If the metadata cannot be read.
+    fn queries(&self) -> Result<Vec<QueryMetadata>, Error>;
+
+    /// Forgets query `id`, along with its checkpoint.
+    ///
+    /// ## Errors
+    /// If either cannot be removed.
+    fn remove_query(&self, id: &str) -> Result<(), Error>;
+
+    /// Stores the checkpoint of query `id`, replacing the one before.
+    ///
+    /// ## Errors
+    /// If the checkpoint cannot be stored.
+    fn put_checkpoint(&self, id: &str, checkpoint: &[u8]) -> Result<(), Error>;
+
+    /// Latest checkpoint of query `id`, if there is one.
+    ///
+    /// ## Errors
+    /// If the checkpoint cannot be read.
+    fn checkpoint(&self, id: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Appends `entry` to the audit log.
+    ///
+    /// ## Errors
+    /// If the entry cannot be stored.
+    fn audit(&self, entry: AuditEntry) -> Result<(), Error>;
+
+    /// All entries of the audit log, in the order they were appended.
+    ///
+    /// ## Errors
+    /// If the log cannot be read.
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, Error>;
+
+    /// Spends `amount` of the privacy budget of `key`, unless that makes it spend more than
+    /// `limit` in total. Returns whether it was spent.
+    ///
+    /// ## Errors
+    /// If the ledger cannot be read or updated.
+    fn charge_budget(&self, key: &[u8], amount: u32, limit: u32) -> Result<bool, Error>;
+
+    /// Gives back `amount` of the privacy budget `key` spent, for a query that was charged for
+    /// it but stopped before it revealed anything, such as one that was cancelled. Budget that
+    /// was not spent is not given back.
+    ///
+    /// ## Errors
+    /// If the ledger cannot be read or updated.
+    fn refund_budget(&self, key: &[u8], amount: u32) -> Result<(), Error>;
+
+    /// Privacy budget `key` has spent so far.
+    ///
+    /// ## Errors
+    /// If the ledger cannot be read.
+    fn budget_spent(&self, key: &[u8]) -> Result<u32, Error>;
+}
+
+/// Whether `spent` leaves room for `amount` more within `limit`, and the new total if so.
+fn charge(spent: u32, amount: u32, limit: u32) -> Option<u32> {
+    spent.checked_add(amount).filter(|&total| total <= limit)
+}
+
+/// Store that keeps everything in memory, for tests and helpers that do not need to survive a
+/// restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+}
+
+#[derive(Debug, Default)]
+struct Records {
+    queries: BTreeMap<String, QueryMetadata>,
+    checkpoints: HashMap<String, Vec<u8>>,
+    audit_log: Vec<AuditEntry>,
+    budgets: HashMap<Vec<u8>, u32>,
+}
+
+impl MemoryStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl QueryStore for MemoryStore {
+    fn put_query(&self, query: &QueryMetadata) -> Result<(), Error> {
+        let mut records = self.records.lock().unwrap();
+        records.queries.insert(query.id.clone(), query.clone());
+        Ok(())
+    }
+
+    fn query(&self, id: &str) -> Result<Option<QueryMetadata>, Error> {
+        Ok(self.records.lock().unwrap().queries.get(id).cloned())
+    }
+
+    fn queries(&self) -> Result<Vec<QueryMetadata>, Error> {
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .queries
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    fn remove_query(&self, id: &str) -> Result<(), Error> {
+        let mut records = self.records.lock().unwrap();
+        records.queries.remove(id);
+        records.checkpoints.remove(id);
+        Ok(())
+    }
+
+    fn put_checkpoint(&self, id: &str, checkpoint: &[u8]) -> Result<(), Error> {
+        let mut records = self.records.lock().unwrap();
+        records
+            .checkpoints
+            .insert(id.to_owned(), checkpoint.to_vec());
+        Ok(())
+    }
+
+    fn checkpoint(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.records.lock().unwrap().checkpoints.get(id).cloned())
+    }
+
+    fn audit(&self, entry: AuditEntry) -> Result<(), Error> {
+        self.records.lock().unwrap().audit_log.push(entry);
+        Ok(())
+    }
+
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, Error> {
+        Ok(self.records.lock().unwrap().audit_log.clone())
+    }
+
+    fn charge_budget(&self, key: &[u8], amount: u32, limit: u32) -> Result<bool, Error> {
+        let mut records = self.records.lock().unwrap();
+        let spent = records.budgets.entry(key.to_vec()).or_default();
+        Ok(match charge(*spent, amount, limit) {
+            Some(total) => {
+                *spent = total;
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn refund_budget(&self, key: &[u8], amount: u32) -> Result<(), Error> {
+        if let Some(spent) = self.records.lock().unwrap().budgets.get_mut(key) {
+            *spent = spent.saturating_sub(amount);
+        }
+        Ok(())
+    }
+
+    fn budget_spent(&self, key: &[u8]) -> Result<u32, Error> {
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .budgets
+            .get(key)
+            .copied()
+            .unwrap_or_default())
+    }
+}
+
+/// Id of the [`BlobStore`] a [`DiskStore`] keeps its records in, which no query may use.
+#[cfg(feature = "enable-serde")]
+pub const STATE_STORE_ID: &str = "state";
+
+#[cfg(feature = "enable-serde")]
+const QUERY: &str = "query-";
+#[cfg(feature = "enable-serde")]
+const CHECKPOINT: &str = "checkpoint-";
+#[cfg(feature = "enable-serde")]
+const AUDIT: &str = "audit-";
+#[cfg(feature = "enable-serde")]
+const BUDGET: &str = "budget-";
+
+/// Store that keeps records on disk, sealed with the storage key of the helper. Query ids become
+/// blob names, so they must be valid ones.
+#[cfg(feature = "enable-serde")]
+#[derive(Debug)]
+pub struct DiskStore {
+    blobs: BlobStore,
+    /// Number of the next audit entry.
+    next_audit: Mutex<u64>,
+    /// Held while the ledger is updated, so that two charges do not both see the budget as it
+    /// was before the other.
+    ledger: Mutex<()>,
+}
+
+#[cfg(feature = "enable-serde")]
+impl DiskStore {
+    /// Opens the store kept under `root`, or creates an empty one if there is none.
+    ///
+    /// ## Errors
+    /// If the store cannot be created, or was created with a different storage key.
+    pub fn open(root: &Path, storage_key: &StorageKey) -> Result<Self, Error> {
+        if root.join(STATE_STORE_ID).exists() {
+            Self::open_existing(root, storage_key)
+        } else {
+            Self::with_blobs(BlobStore::create(
+                root,
+                STATE_STORE_ID,
+                storage_key,
+                &mut OsRng,
+            )?)
+        }
+    }
+
+    /// Opens the store kept under `root`, without creating one if there is none, for tools that
+    /// only read what a helper stored.
+    ///
+    /// ## Errors
+    /// If there is no store, or it was created with a different storage key.
+    pub fn open_existing(root: &Path, storage_key: &StorageKey) -> Result<Self, Error> {
+        Self::with_blobs(BlobStore::open(root, STATE_STORE_ID, storage_key)?)
+    }
+
+    fn with_blobs(blobs: BlobStore) -> Result<Self, Error> {
+        // names are zero-padded, so the last one is the latest entry
+        let next_audit = match blobs.names()?.iter().rev().find(|n| n.starts_with(AUDIT)) {
+            Some(name) => name[AUDIT.len()..]
+                .parse::<u64>()
+                .map_err(|_| Error::Malformed(name.clone()))?
+                .saturating_add(1),
+            None => 0,
+        };
+        Ok(Self {
+            blobs,
+            next_audit: Mutex::new(next_audit),
+            ledger: Mutex::default(),
+        })
+    }
+
+    /// Overwrites and removes all records, see [`BlobStore::destroy`].
+    ///
+    /// ## Errors
+    /// If they cannot be overwritten or removed.
+    pub fn destroy(self) -> Result<(), Error> {
+        self.blobs.destroy()
+    }
+
+    /// Blob stored as `name`, or `None` if there is none.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.blobs.get(name) {
+            Ok(data) => Ok(Some(data)),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put_record<T: Serialize>(&self, name: &str, record: &T) -> Result<(), Error> {
+        let data = bincode::serialize(record).map_err(|_| Error::Malformed(name.to_owned()))?;
+        self.blobs.put(name, &data, &mut OsRng)
+    }
+
+    fn record<T: for<'de> Deserialize<'de>>(&self, name: &str) -> Result<Option<T>, Error> {
+        self.read(name)?
+            .map(|data| bincode::deserialize(&data).map_err(|_| Error::Malformed(name.to_owned())))
+            .transpose()
+    }
+
+    fn budget_name(key: &[u8]) -> String {
+        key.iter().fold(String::from(BUDGET), |mut name, b| {
+            write!(name, "{b:02x}").unwrap();
+            name
+        })
+    }
+}
+
+#[cfg(feature = "enable-serde")]
+impl QueryStore for DiskStore {
+    fn put_query(&self, query: &QueryMetadata) -> Result<(), Error> {
+        self.put_record(&format!("{QUERY}{}", query.id), query)
+    }
+
+    fn query(&self, id: &str) -> Result<Option<QueryMetadata>, Error> {
+        self.record(&format!("{QUERY}{id}"))
+    }
+
+    fn queries(&self) -> Result<Vec<QueryMetadata>, Error> {
+        let mut queries = Vec::new();
+        for name in self.blobs.names()? {
+            // names share the prefix, so they are sorted by id
+            if name.starts_with(QUERY) {
+                // unless it was removed in the meantime
+                queries.extend(self.record(&name)?);
+            }
+        }
+        Ok(queries)
+    }
+
+    fn remove_query(&self, id: &str) -> Result<(), Error> {
+        self.blobs.remove(&format!("{CHECKPOINT}{id}"))?;
+        self.blobs.remove(&format!("{QUERY}{id}"))
+    }
+
+    fn put_checkpoint(&self, id: &str, checkpoint: &[u8]) -> Result<(), Error> {
+        self.blobs
+            .put(&format!("{CHECKPOINT}{id}"), checkpoint, &mut OsRng)
+    }
+
+    fn checkpoint(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.read(&format!("{CHECKPOINT}{id}"))
+    }
+
+    fn audit(&self, entry: AuditEntry) -> Result<(), Error> {
+        let mut next = self.next_audit.lock().unwrap();
+        self.put_record(&format!("{AUDIT}{:020}", *next), &entry)?;
+        *next += 1;
+        Ok(())
+    }
+
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, Error> {
+        let mut log = Vec::new();
+        for name in self.blobs.names()? {
+            if name.starts_with(AUDIT) {
+                log.extend(self.record(&name)?);
+            }
+        }
+        Ok(log)
+    }
+
+    fn charge_budget(&self, key: &[u8], amount: u32, limit: u32) -> Result<bool, Error> {
+        let _ledger = self.ledger.lock().unwrap();
+        let name = Self::budget_name(key);
+        let spent = self.record::<u32>(&name)?.unwrap_or_default();
+        match charge(spent, amount, limit) {
+            Some(total) => {
+                self.put_record(&name, &total)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn refund_budget(&self, key: &[u8], amount: u32) -> Result<(), Error> {
+        let _ledger = self.ledger.lock().unwrap();
+        let name = Self::budget_name(key);
+        if let Some(spent) = self.record::<u32>(&name)? {
+            self.put_record(&name, &spent.saturating_sub(amount))?;
+        }
+        Ok(())
+    }
+
+    fn budget_spent(&self, key: &[u8]) -> Result<u32, Error> {
+        Ok(self.record(&Self::budget_name(key))?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{AuditEntry, MemoryStore, QueryMetadata, QueryState, QueryStore};
+    #[cfg(feature = "enable-serde")]
+    use crate::storage::{DiskStore, StorageKey};
+    #[cfg(feature = "enable-serde")]
+    use rand::thread_rng;
+
+    fn metadata(id: &str) -> QueryMetadata {
+        QueryMetadata {
+            id: id.to_owned(),
+            collector: "news.example".to_owned(),
+            epoch: 3,
+            rows: 1000,
+            submitted: 1_660_000_000,
+            state: QueryState::Running,
+        }
+    }
+
+    fn audit_entry(event: &str) -> AuditEntry {
+        AuditEntry {
+            at: 1_660_000_000,
+            collector: "news.example".to_owned(),
+            event: event.to_owned(),
+        }
+    }
+
+    /// Behavior every store must have.
+    fn records(store: &dyn QueryStore) {
+        assert_eq!(None, store.query("q2").unwrap());
+        store.put_query(&metadata("q2")).unwrap();
+        store.put_query(&metadata("q1")).unwrap();
+        let failed = QueryMetadata {
+            state: QueryState::Failed {
+                reason: "peer is gone".to_owned(),
+            },
+            ..metadata("q2")
+        };
+        store.put_query(&failed).unwrap();
+        assert_eq!(Some(failed.clone()), store.query("q2").unwrap());
+        assert_eq!(vec![metadata("q1"), failed], store.queries().unwrap());
+
+        assert_eq!(None, store.checkpoint("q1").unwrap());
+        store.put_checkpoint("q1", b"stage 1").unwrap();
+        store.put_checkpoint("q1", b"stage 2").unwrap();
+        assert_eq!(Some(b"stage 2".to_vec()), store.checkpoint("q1").unwrap());
+        store.remove_query("q1").unwrap();
+        assert_eq!(None, store.query("q1").unwrap());
+        assert_eq!(None, store.checkpoint("q1").unwrap());
+        // removing it again is fine
+        store.remove_query("q1").unwrap();
+
+        store.audit(audit_entry("query admitted")).unwrap();
+        store.audit(audit_entry("query rejected")).unwrap();
+        assert_eq!(
+            vec![audit_entry("query admitted"), audit_entry("query rejected")],
+            store.audit_log().unwrap()
+        );
+
+        assert_eq!(0, store.budget_spent(b"key").unwrap());
+        assert!(store.charge_budget(b"key", 60, 100).unwrap());
+        assert!(!store.charge_budget(b"key", 60, 100).unwrap());
+        assert!(store.charge_budget(b"key", 40, 100).unwrap());
+        assert!(!store.charge_budget(b"key", u32::MAX, u32::MAX).unwrap());
+        assert_eq!(100, store.budget_spent(b"key").unwrap());
+        assert_eq!(0, store.budget_spent(b"other key").unwrap());
+
+        assert!(store.charge_budget(b"refunded", 60, 100).unwrap());
+        store.refund_budget(b"refunded", 40).unwrap();
+        assert_eq!(20, store.budget_spent(b"refunded").unwrap());
+        store.refund_budget(b"refunded", 100).unwrap();
+        assert_eq!(0, store.budget_spent(b"refunded").unwrap());
+        store.refund_budget(b"other key", 1).unwrap();
+        assert_eq!(0, store.budget_spent(b"other key").unwrap());
+    }
+
+    #[test]
+    fn memory() {
+        records(&MemoryStore::new());
+    }
+
+    #[test]
+    #[cfg(feature = "enable-serde")]
+    fn disk() {
+        let root = std::env::temp_dir().join(format!("raw-ipa-state-{}", std::process::id()));
+        let key = StorageKey::new(&mut thread_rng());
+        let store = DiskStore::open(&root, &key).unwrap();
+        records(&store);
+        drop(store);
+
+        // everything is still there after a restart, and the audit log carries on
+        let store = DiskStore::open(&root, &key).unwrap();
+        let queries = store.queries().unwrap();
+        assert_eq!(
+            vec!["q2"],
+            queries.iter().map(|q| &q.id).collect::<Vec<_>>()
+        );
+        assert_eq!(100, store.budget_spent(b"key").unwrap());
+        store.audit(audit_entry("helper restarted")).unwrap();
+        assert_eq!(3, store.audit_log().unwrap().len());
+        assert_eq!(
+            audit_entry("helper restarted"),
+            store.audit_log().unwrap()[2]
+        );
+
+        assert!(DiskStore::open(&root, &StorageKey::new(&mut thread_rng())).is_err());
+        assert_eq!(
+            queries,
+            DiskStore::open_existing(&root, &key)
+                .unwrap()
+                .queries()
+                .unwrap()
+        );
+        store.destroy().unwrap();
+        // tools that only read do not create a store
+        assert!(DiskStore::open_existing(&root, &key).is_err());
+        std::fs::remove_dir(root).unwrap();
+    }
+}
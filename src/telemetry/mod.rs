@@ -0,0 +1,164 @@
+//!
+//! Telemetry reported by helpers while they execute protocols: metrics defined in this module,
+//! trace context propagation in [`trace`], per-step round accounting in [`rounds`] and a live
+//! view of running queries in [`status`], with an opt-in [`summary`] of those that ran recently.
+//! The [`timeline`] of a query that stopped running is put together from what was kept of it.
+//!
+//! Metrics are recorded via the [`metrics`](https://docs.rs/metrics) facade when the
+//! `enable-metrics` feature is on, otherwise recording compiles to nothing. Recording is cheap
+//! when no recorder is installed, so it is up to the binary (helper server, benchmarks) to
+//! install the exporter it wants to report with. The `prometheus` feature provides one such
+//! exporter.
+//!
+use crate::helpers::ring::HelperAddr;
+
+pub mod rounds;
+pub mod status;
+pub mod summary;
+pub mod timeline;
+pub mod trace;
+
+/// Names of the metrics and labels recorded by helpers.
+pub mod metrics {
+    /// Histogram of the time, in seconds, it took to execute a single protocol step. This and
+    /// `STEP_RECORDS` are labelled with the name of the step (`step`).
+    pub const STEP_LATENCY: &str = "step.latency";
+    /// Counter of records processed by a protocol step. Exporters can derive the throughput
+    /// (records/second) from it.
+    pub const STEP_RECORDS: &str = "step.records";
+    /// Gauge of the number of messages that arrived to a helper but have not been received yet.
+    pub const BUFFER_DEPTH: &str = "buffer.depth";
+    /// Counter of bytes sent to other helpers. This and `BYTES_RECEIVED` are labelled with the
+    /// position of the peer (`peer`).
+    pub const BYTES_SENT: &str = "helper.bytes_sent";
+    /// Counter of bytes received from other helpers.
+    pub const BYTES_RECEIVED: &str = "helper.bytes_received";
+    /// Counter of bytes of payloads sent to other helpers that were compressed. This and
+    /// `COMPRESSED_OUTPUT` are labelled with the position of the peer (`peer`) and the
+    /// compression algorithm (`algorithm`). Exporters can derive the compression ratio from them.
+    pub const COMPRESSED_INPUT: &str = "helper.compression.input_bytes";
+    /// Counter of bytes those payloads were compressed to.
+    pub const COMPRESSED_OUTPUT: &str = "helper.compression.output_bytes";
+
+    /// Registers descriptions for all metrics above with the currently installed recorder.
+    /// Must be called after the recorder is installed.
+    #[cfg(feature = "enable-metrics")]
+    pub fn describe() {
+        use ::metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+        describe_histogram!(STEP_LATENCY, Unit::Seconds, "protocol step execution time");
+        describe_counter!(
+            STEP_RECORDS,
+            Unit::Count,
+            "records processed by protocol step"
+        );
+        describe_gauge!(BUFFER_DEPTH, Unit::Count, "messages waiting to be received");
+        describe_counter!(BYTES_SENT, Unit::Bytes, "bytes sent to other helpers");
+        describe_counter!(
+            BYTES_RECEIVED,
+            Unit::Bytes,
+            "bytes received from other helpers"
+        );
+        describe_counter!(
+            COMPRESSED_INPUT,
+            Unit::Bytes,
+            "bytes of payloads compressed for other helpers"
+        );
+        describe_counter!(
+            COMPRESSED_OUTPUT,
+            Unit::Bytes,
+            "bytes payloads for other helpers were compressed to"
+        );
+    }
+}
+
+/// Measures the execution of a single record by a protocol step. Time is recorded when this
+/// value is dropped, so steps that fail half-way are accounted for as well.
+#[derive(Debug)]
+#[must_use]
+pub struct StepTimer {
+    #[cfg(feature = "enable-metrics")]
+    step: &'static str,
+    #[cfg(feature = "enable-metrics")]
+    start: std::time::Instant,
+}
+
+impl StepTimer {
+    #[cfg_attr(not(feature = "enable-metrics"), allow(unused_variables))]
+    pub fn start(step: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "enable-metrics")]
+            step,
+            #[cfg(feature = "enable-metrics")]
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "enable-metrics")]
+impl Drop for StepTimer {
+    fn drop(&mut self) {
+        ::metrics::histogram!(metrics::STEP_LATENCY, self.start.elapsed(), "step" => self.step);
+        ::metrics::counter!(metrics::STEP_RECORDS, 1, "step" => self.step);
+    }
+}
+
+/// Reports the number of messages sitting in the message buffer.
+#[cfg_attr(not(feature = "enable-metrics"), allow(unused_variables))]
+pub fn buffer_depth(depth: u64) {
+    #[cfg(feature = "enable-metrics")]
+    {
+        #[allow(clippy::cast_precision_loss)]
+        let depth = depth as f64;
+        ::metrics::gauge!(metrics::BUFFER_DEPTH, depth);
+    }
+}
+
+/// Reports bytes sent to the helper at `peer` position.
+#[cfg_attr(not(feature = "enable-metrics"), allow(unused_variables))]
+pub fn bytes_sent(peer: HelperAddr, len: usize) {
+    #[cfg(feature = "enable-metrics")]
+    ::metrics::counter!(metrics::BYTES_SENT, len as u64, "peer" => peer_label(peer));
+}
+
+/// Reports bytes received from the helper at `peer` position.
+#[cfg_attr(not(feature = "enable-metrics"), allow(unused_variables))]
+pub fn bytes_received(peer: HelperAddr, len: usize) {
+    #[cfg(feature = "enable-metrics")]
+    ::metrics::counter!(metrics::BYTES_RECEIVED, len as u64, "peer" => peer_label(peer));
+}
+
+/// Reports a payload of `input` bytes sent to the helper at `peer` position, which `algorithm`
+/// compressed to `output` bytes. Payloads that did not get any smaller are reported as well.
+#[cfg_attr(not(feature = "enable-metrics"), allow(unused_variables))]
+pub fn compressed(peer: HelperAddr, algorithm: &'static str, input: usize, output: usize) {
+    #[cfg(feature = "enable-metrics")]
+    {
+        let peer = peer_label(peer);
+        ::metrics::counter!(metrics::COMPRESSED_INPUT, input as u64, "peer" => peer, "algorithm" => algorithm);
+        ::metrics::counter!(metrics::COMPRESSED_OUTPUT, output as u64, "peer" => peer, "algorithm" => algorithm);
+    }
+}
+
+#[cfg(feature = "enable-metrics")]
+fn peer_label(peer: HelperAddr) -> &'static str {
+    match peer {
+        HelperAddr::Left => "left",
+        HelperAddr::Right => "right",
+    }
+}
+
+/// Installs the Prometheus recorder as the global metrics recorder. The returned handle renders
+/// all metrics recorded so far in Prometheus text format.
+///
+/// ## Errors
+/// If a global recorder is already installed.
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus_recorder(
+) -> Result<metrics_exporter_prometheus::PrometheusHandle, metrics_exporter_prometheus::BuildError>
+{
+    let handle = metrics_exporter_prometheus::PrometheusBuilder::new().install_recorder()?;
+    metrics::describe();
+
+    Ok(handle)
+}
@@ -0,0 +1,311 @@
+//! Accounting of communication rounds and multiplications done by every protocol step.
+//!
+//! A round starts when some multiplication begins waiting for a message from a peer while no
+//! other one is waiting, and ends when none of them are waiting anymore. Multiplications that
+//! are executed concurrently therefore share a round, while ones that depend on each other's
+//! output need a round each. This is what protocol authors need to confirm that a batched
+//! implementation actually takes a single round.
+//!
+//! Steps are named by paths separated by `/`, for example `sort/shuffle/securemul`, so counts
+//! can be aggregated over any subtree of the protocol. [`Report::tree`] nests them that way, to
+//! be exported as JSON or drawn with Graphviz from [`StepTree::to_dot`].
+#[cfg(feature = "enable-serde")]
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use std::sync::Mutex;
+
+/// Counts for a single step.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct StepStats {
+    pub rounds: u64,
+    pub multiplications: u64,
+}
+
+#[derive(Debug, Default)]
+struct StepState {
+    stats: StepStats,
+    waiting: usize,
+}
+
+/// Collects [`StepStats`] for one protocol execution. It is shared by all tasks that work on
+/// behalf of the same helper.
+#[derive(Debug, Default)]
+pub struct RoundCounter {
+    steps: Mutex<BTreeMap<String, StepState>>,
+}
+
+/// Keeps the round open until dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct Round<'a> {
+    counter: &'a RoundCounter,
+    step: &'a str,
+}
+
+impl RoundCounter {
+    /// Records a multiplication executed by `step`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn multiplication(&self, step: &str) {
+        self.with_step(step, |s| s.stats.multiplications += 1);
+    }
+
+    /// Marks that `step` waits for a message from a peer until the returned value is dropped.
+    /// If it is the only wait in progress, a new round is counted.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn wait<'a>(&'a self, step: &'a str) -> Round<'a> {
+        self.with_step(step, |s| {
+            if s.waiting == 0 {
+                s.stats.rounds += 1;
+            }
+            s.waiting += 1;
+        });
+        Round {
+            counter: self,
+            step,
+        }
+    }
+
+    /// Returns counts for every step that did any work so far.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn report(&self) -> Report {
+        let steps = self.steps.lock().unwrap();
+        Report(
+            steps
+                .iter()
+                .map(|(name, state)| (name.clone(), state.stats))
+                .collect(),
+        )
+    }
+
+    fn with_step<F: FnOnce(&mut StepState)>(&self, step: &str, f: F) {
+        let mut steps = self.steps.lock().unwrap();
+        match steps.get_mut(step) {
+            Some(state) => f(state),
+            None => f(steps.entry(step.to_owned()).or_default()),
+        }
+    }
+}
+
+impl Drop for Round<'_> {
+    fn drop(&mut self) {
+        self.counter.with_step(self.step, |s| s.waiting -= 1);
+    }
+}
+
+/// Counts collected by [`RoundCounter`], keyed by step.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report(BTreeMap<String, StepStats>);
+
+impl Report {
+    #[must_use]
+    pub fn step(&self, step: &str) -> StepStats {
+        self.0.get(step).copied().unwrap_or_default()
+    }
+
+    /// Sums counts of `step` and all steps nested under it. Rounds of nested steps are added
+    /// up, so this is an upper bound if some of them ran concurrently.
+    #[must_use]
+    pub fn subtree(&self, step: &str) -> StepStats {
+        self.0
+            .iter()
+            .filter(|(name, _)| {
+                name.strip_prefix(step)
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .fold(StepStats::default(), |acc, (_, s)| StepStats {
+                rounds: acc.rounds + s.rounds,
+                multiplications: acc.multiplications + s.multiplications,
+            })
+    }
+
+    /// Sums counts of all steps, with the same caveat about rounds as [`Report::subtree`].
+    #[must_use]
+    pub fn total(&self) -> StepStats {
+        self.0
+            .values()
+            .fold(StepStats::default(), |acc, s| StepStats {
+                rounds: acc.rounds + s.rounds,
+                multiplications: acc.multiplications + s.multiplications,
+            })
+    }
+
+    /// Steps nested by the components of their paths, under a root without a name. Steps that
+    /// only have others nested under them, such as `sort` of `sort/securemul`, are in the tree
+    /// with no counts of their own. Every multiplication sends a value of `field_size` bytes to
+    /// the helper on the right, which is what [`StepTree::bytes`] counts.
+    #[must_use]
+    pub fn tree(&self, field_size: usize) -> StepTree {
+        let mut root = StepTree::default();
+        for (path, stats) in &self.0 {
+            let mut node = &mut root;
+            for name in path.split('/') {
+                let i = if let Some(i) = node.children.iter().position(|c| c.name == name) {
+                    i
+                } else {
+                    node.children.push(StepTree {
+                        name: name.to_owned(),
+                        ..StepTree::default()
+                    });
+                    node.children.len() - 1
+                };
+                node = &mut node.children[i];
+            }
+            node.stats = *stats;
+        }
+        root.add_totals(field_size as u64);
+        root
+    }
+}
+
+/// A step of a [`Report::tree`] and the steps nested under it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct StepTree {
+    /// Last component of the path of the step.
+    pub name: String,
+    /// Counts of the step itself.
+    pub stats: StepStats,
+    /// Counts of the step and all steps nested under it, the same as [`Report::subtree`].
+    pub total: StepStats,
+    /// Bytes sent by the step and all steps nested under it.
+    pub bytes: u64,
+    pub children: Vec<StepTree>,
+}
+
+impl StepTree {
+    fn add_totals(&mut self, field_size: u64) {
+        self.total = self.stats;
+        for child in &mut self.children {
+            child.add_totals(field_size);
+            self.total.rounds += child.total.rounds;
+            self.total.multiplications += child.total.multiplications;
+        }
+        self.bytes = self.total.multiplications * field_size;
+    }
+
+    /// Tree in the DOT language of Graphviz, with a node for every step that shows the counts of
+    /// its subtree. Nodes are identified by the full paths of their steps.
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph steps {\n    node [shape=box];\n");
+        self.write_dot(&mut dot, "");
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_dot(&self, dot: &mut String, path: &str) {
+        let label = if path.is_empty() { "query" } else { &self.name };
+        // writing to a String does not fail
+        let _ = writeln!(
+            dot,
+            "    {path:?} [label=\"{label}\\nrounds: {}\\nmultiplications: {}\\nbytes: {}\"];",
+            self.total.rounds, self.total.multiplications, self.bytes
+        );
+        for child in &self.children {
+            let child_path = if path.is_empty() {
+                child.name.clone()
+            } else {
+                format!("{path}/{}", child.name)
+            };
+            let _ = writeln!(dot, "    {path:?} -> {child_path:?};");
+            child.write_dot(dot, &child_path);
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<32} {:>8} {:>16}",
+            "step", "rounds", "multiplications"
+        )?;
+        for (name, s) in &self.0 {
+            writeln!(f, "{name:<32} {:>8} {:>16}", s.rounds, s.multiplications)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry::rounds::{RoundCounter, StepStats};
+
+    #[test]
+    fn concurrent_waits_share_round() {
+        let counter = RoundCounter::default();
+        {
+            let _a = counter.wait("mul");
+            let _b = counter.wait("mul");
+        }
+        let _c = counter.wait("mul");
+        for _ in 0..3 {
+            counter.multiplication("mul");
+        }
+
+        assert_eq!(
+            StepStats {
+                rounds: 2,
+                multiplications: 3
+            },
+            counter.report().step("mul")
+        );
+    }
+
+    #[test]
+    fn subtree() {
+        let counter = RoundCounter::default();
+        for step in ["sort/mul", "sort/shuffle/mul", "sorted/mul", "mul"] {
+            drop(counter.wait(step));
+            counter.multiplication(step);
+        }
+
+        let report = counter.report();
+        assert_eq!(2, report.subtree("sort").rounds);
+        assert_eq!(1, report.subtree("sort/shuffle").multiplications);
+        assert_eq!(StepStats::default(), report.step("sort"));
+        assert_eq!(4, report.total().multiplications);
+    }
+
+    #[test]
+    fn tree() {
+        let counter = RoundCounter::default();
+        for step in [
+            "sort/mul",
+            "sort/shuffle/mul",
+            "sort-x/mul",
+            "mul",
+            "sort/shuffle/mul",
+        ] {
+            drop(counter.wait(step));
+            counter.multiplication(step);
+        }
+
+        let tree = counter.report().tree(4);
+        assert_eq!(
+            vec!["mul", "sort-x", "sort"],
+            tree.children.iter().map(|c| &c.name).collect::<Vec<_>>()
+        );
+        assert_eq!(5, tree.total.multiplications);
+        assert_eq!(20, tree.bytes);
+        let sort = &tree.children[2];
+        assert_eq!(StepStats::default(), sort.stats);
+        assert_eq!(counter.report().subtree("sort"), sort.total);
+        assert_eq!(12, sort.bytes);
+        assert_eq!(2, sort.children[1].children[0].stats.multiplications);
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph steps {"));
+        assert!(dot.contains("\"sort\" -> \"sort/shuffle\";"));
+        assert!(dot.contains("\"sort/shuffle\" -> \"sort/shuffle/mul\";"));
+    }
+}
@@ -0,0 +1,996 @@
+//! Live view of the queries a helper is running, for dashboards and operators.
+//!
+//! Every query has a [`QueryProgress`] that the code running it updates as it goes: the stage
+//! of the protocol it is in, records processed, and bytes exchanged with every peer. The
+//! transport updates the latter on its own, see [`TcpRing::progress`]. Queries are tracked in
+//! a [`Status`] for as long as they run, and [`Status::snapshot`] describes all of them at once.
+//!
+//! A helper that is about to stop drains its status: it takes no new queries from then on, but
+//! lets the ones that are running finish. Queries that would not finish in time can be paused
+//! instead, which asks the code running them to checkpoint its state to the [`QueryStore`] of the
+//! query and stop. Peers pause their part of the query when asked to by the helper that drains,
+//! and the query resumes from the checkpoint once it is tracked again, by the same helper after it
+//! restarts.
+//!
+//! Report collectors may cancel a query they submitted. That asks the code running it to abort
+//! the query on all helpers, see [`QueryProgress::cancelled`], and it stops for good. Queries a
+//! collector submits are [admitted](Status::admit) against its quota, and are only cancelled,
+//! followed and described for that collector; to any other, they are not running.
+//!
+//! Collectors that follow a query for hours get more out of a stream of [`ProgressEvent`]s than
+//! out of snapshots: an [`EventFeed`] of the query reports every stage it enters, records
+//! processed and the estimated time until all are, and when it stops running.
+//!
+//! Helpers that opt in also keep a [`Summary`] of the queries that stopped running recently,
+//! which is part of the snapshot.
+//!
+//! Snapshots of a query tell the collector that submitted it which of its reports the helper
+//! rejected, see [`QueryProgress::set_rejections`], so that it can fix its clients while the
+//! query still runs.
+//!
+//! [`QueryStore`]: crate::storage::QueryStore
+//! [`TcpRing::progress`]: crate::helpers::tcp::TcpRing::progress
+use crate::helpers::quota::{self, QuotaTracker, Quotas};
+use crate::helpers::ring::HelperAddr;
+use crate::ingest::Rejections;
+use crate::telemetry::summary::{Outcome, Summary, SummarySnapshot};
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// Stage of a query that did not report any yet.
+pub const NOT_STARTED: &str = "not started";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("helper is shutting down and does not take new queries")]
+    Draining,
+    #[error(transparent)]
+    Quota(#[from] quota::Error),
+}
+
+/// Progress of a single query. All updates are cheap enough to be made for every record.
+#[derive(Debug)]
+pub struct QueryProgress {
+    started: Instant,
+    stage: Mutex<&'static str>,
+    /// Every stage the query entered, with the time since it started.
+    stages: Mutex<Vec<(&'static str, Duration)>>,
+    /// Notified when the query enters a stage or stops running.
+    changed: Notify,
+    records: AtomicU64,
+    /// Number of records the query processes in total, zero if not known.
+    expected_records: AtomicU64,
+    sent: [AtomicU64; 2],
+    received: [AtomicU64; 2],
+    buffer_depth: AtomicU64,
+    dead_letters: AtomicU64,
+    health: Mutex<[PeerHealth; 2]>,
+    rejections: Mutex<Option<Rejections>>,
+    pause: AtomicBool,
+    cancel: AtomicBool,
+}
+
+impl Default for QueryProgress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            stage: Mutex::new(NOT_STARTED),
+            stages: Mutex::default(),
+            changed: Notify::new(),
+            records: AtomicU64::default(),
+            expected_records: AtomicU64::default(),
+            sent: Default::default(),
+            received: Default::default(),
+            buffer_depth: AtomicU64::default(),
+            dead_letters: AtomicU64::default(),
+            health: Mutex::default(),
+            rejections: Mutex::default(),
+            pause: AtomicBool::default(),
+            cancel: AtomicBool::default(),
+        }
+    }
+}
+
+impl QueryProgress {
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn set_stage(&self, stage: &'static str) {
+        let mut current = self.stage.lock().unwrap();
+        if *current != stage {
+            *current = stage;
+            let elapsed = self.started.elapsed();
+            self.stages.lock().unwrap().push((stage, elapsed));
+            self.changed.notify_waiters();
+        }
+    }
+
+    pub fn records_processed(&self, records: u64) {
+        self.records.fetch_add(records, Ordering::Relaxed);
+    }
+
+    /// Number of records the query processes in total, which lets it estimate how long it
+    /// takes to finish.
+    pub fn set_expected_records(&self, records: u64) {
+        self.expected_records.store(records, Ordering::Relaxed);
+    }
+
+    /// Time until all expected records are processed, at the rate they were so far. Not known
+    /// until the query says how many records it expects, and processes some of them.
+    #[must_use]
+    pub fn eta(&self) -> Option<Duration> {
+        let expected = self.expected_records.load(Ordering::Relaxed);
+        let processed = self.records.load(Ordering::Relaxed);
+        if expected == 0 || processed == 0 {
+            return None;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let remaining = expected.saturating_sub(processed) as f64 / processed as f64;
+        Some(self.started.elapsed().mul_f64(remaining))
+    }
+
+    pub fn bytes_sent(&self, peer: HelperAddr, len: usize) {
+        self.sent[index(peer)].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self, peer: HelperAddr, len: usize) {
+        self.received[index(peer)].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Number of messages that arrived from peers but have not been received yet.
+    pub fn set_buffer_depth(&self, depth: u64) {
+        self.buffer_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Number of messages that arrived from peers and were given up on, because nobody received
+    /// them in time or the query failed.
+    pub fn set_dead_letters(&self, count: u64) {
+        self.dead_letters.store(count, Ordering::Relaxed);
+    }
+
+    /// What this helper heard from `peer` lately, see [`crate::helpers::liveness`].
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn set_peer_health(&self, peer: HelperAddr, health: PeerHealth) {
+        self.health.lock().unwrap()[index(peer)] = health;
+    }
+
+    /// Reports of the input of the query this helper rejected, see
+    /// [`Ingest::rejections`](crate::ingest::Ingest::rejections).
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn set_rejections(&self, rejections: Rejections) {
+        *self.rejections.lock().unwrap() = Some(rejections);
+    }
+
+    /// Number of records the query has in total: those it expects, or processed so far if it
+    /// did not say.
+    #[must_use]
+    pub fn input_size(&self) -> u64 {
+        match self.expected_records.load(Ordering::Relaxed) {
+            0 => self.records.load(Ordering::Relaxed),
+            expected => expected,
+        }
+    }
+
+    /// Time the query spent in every stage it entered, in order. The last one counts until now.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn stage_durations(&self) -> Vec<(&'static str, Duration)> {
+        let stages = self.stages.lock().unwrap();
+        let elapsed = self.started.elapsed();
+        stages
+            .iter()
+            .enumerate()
+            .map(|(i, &(stage, entered))| {
+                let left = stages.get(i + 1).map_or(elapsed, |&(_, next)| next);
+                (stage, left.saturating_sub(entered))
+            })
+            .collect()
+    }
+
+    /// Asks the query to checkpoint and stop at the next opportunity.
+    pub fn request_pause(&self) {
+        self.pause.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the query should checkpoint and stop, see [`Tracked::suspend`]. Code running the
+    /// query checks this between steps, where its state is easy to save.
+    #[must_use]
+    pub fn pause_requested(&self) -> bool {
+        self.pause.load(Ordering::SeqCst)
+    }
+
+    /// Asks the query to abort, because the collector that submitted it does not want it
+    /// anymore.
+    pub fn request_cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    #[must_use]
+    pub fn cancel_requested(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the query is asked to cancel. Code running the query waits for this next
+    /// to every stage, which [`StageDeadlines::run`] does.
+    ///
+    /// [`StageDeadlines::run`]: crate::query::StageDeadlines::run
+    pub async fn cancelled(&self) {
+        loop {
+            // registered before the check, so that a request in between is not missed
+            let changed = self.changed.notified();
+            if self.cancel_requested() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn snapshot(&self, id: &str) -> QuerySnapshot {
+        let traffic = |peer| Traffic {
+            bytes_sent: self.sent[index(peer)].load(Ordering::Relaxed),
+            bytes_received: self.received[index(peer)].load(Ordering::Relaxed),
+        };
+        let [left_peer, right_peer] = *self.health.lock().unwrap();
+        QuerySnapshot {
+            id: id.to_owned(),
+            stage: (*self.stage.lock().unwrap()).to_owned(),
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+            records_processed: self.records.load(Ordering::Relaxed),
+            left: traffic(HelperAddr::Left),
+            right: traffic(HelperAddr::Right),
+            buffer_depth: self.buffer_depth.load(Ordering::Relaxed),
+            dead_letters: self.dead_letters.load(Ordering::Relaxed),
+            left_peer,
+            right_peer,
+            rejections: self.rejections.lock().unwrap().clone(),
+            eta_secs: self.eta().map(|eta| eta.as_secs_f64()),
+        }
+    }
+}
+
+fn index(peer: HelperAddr) -> usize {
+    match peer {
+        HelperAddr::Left => 0,
+        HelperAddr::Right => 1,
+    }
+}
+
+/// Bytes exchanged with a single peer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct Traffic {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// What a helper heard from a single peer lately. Only kept up to date by rings that send
+/// heartbeats.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct PeerHealth {
+    /// Milliseconds since anything arrived from the peer. A peer that is silent for a while
+    /// but not gone is slow.
+    pub silent_ms: u64,
+    /// Peer was silent for too long, and the query failed because of it.
+    pub gone: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct QuerySnapshot {
+    pub id: String,
+    pub stage: String,
+    pub elapsed_secs: f64,
+    pub records_processed: u64,
+    /// Traffic with the peer on the left.
+    pub left: Traffic,
+    /// Traffic with the peer on the right.
+    pub right: Traffic,
+    pub buffer_depth: u64,
+    pub dead_letters: u64,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub left_peer: PeerHealth,
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub right_peer: PeerHealth,
+    /// Reports of the input this helper rejected, if the query got that far.
+    #[cfg_attr(
+        feature = "enable-serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub rejections: Option<Rejections>,
+    /// Seconds until the query is expected to process all of its records, if known.
+    pub eta_secs: Option<f64>,
+}
+
+/// Something that happened to a running query, see [`EventFeed`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+#[cfg_attr(
+    feature = "enable-serde",
+    serde(tag = "event", rename_all = "snake_case")
+)]
+pub enum ProgressEvent {
+    /// Query entered `stage`, `elapsed_secs` after it started.
+    StageEntered { stage: String, elapsed_secs: f64 },
+    /// Query processed more records since the last event.
+    Progress {
+        records_processed: u64,
+        eta_secs: Option<f64>,
+    },
+    /// Query is no longer running: it completed, failed or was suspended.
+    Finished,
+}
+
+impl ProgressEvent {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::StageEntered { .. } => "stage",
+            Self::Progress { .. } => "progress",
+            Self::Finished => "finished",
+        }
+    }
+}
+
+/// Turns what a running query reports in its [`QueryProgress`] into [`ProgressEvent`]s. Stages
+/// are reported as soon as the query enters them, records processed at most once per interval
+/// the feed is polled with, since counting them is not worth waking anybody up for.
+#[derive(Debug)]
+pub struct EventFeed {
+    status: Arc<Status>,
+    id: String,
+    progress: Arc<QueryProgress>,
+    stages_seen: usize,
+    records_seen: u64,
+    finished: bool,
+}
+
+impl EventFeed {
+    /// Events since the last call, which are all stages entered so far on the first one.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn poll(&mut self) -> Vec<ProgressEvent> {
+        if self.finished {
+            return Vec::new();
+        }
+        let mut events = self.progress.stages.lock().unwrap()[self.stages_seen..]
+            .iter()
+            .map(|(stage, elapsed)| ProgressEvent::StageEntered {
+                stage: (*stage).to_owned(),
+                elapsed_secs: elapsed.as_secs_f64(),
+            })
+            .collect::<Vec<_>>();
+        self.stages_seen += events.len();
+
+        let records = self.progress.records.load(Ordering::Relaxed);
+        if records != self.records_seen {
+            self.records_seen = records;
+            events.push(ProgressEvent::Progress {
+                records_processed: records,
+                eta_secs: self.progress.eta().map(|eta| eta.as_secs_f64()),
+            });
+        }
+
+        if !self.status.is_tracked(&self.id, &self.progress) {
+            self.finished = true;
+            events.push(ProgressEvent::Finished);
+        }
+        events
+    }
+
+    /// Waits for the next events, but no longer than `interval` at a time for records
+    /// processed. Returns nothing once the query has finished.
+    pub async fn next(&mut self, interval: Duration) -> Vec<ProgressEvent> {
+        while !self.finished {
+            // registered before polling, so that a change in between is not missed
+            let progress = Arc::clone(&self.progress);
+            let changed = progress.changed.notified();
+            let events = self.poll();
+            if !events.is_empty() {
+                return events;
+            }
+            let _ = tokio::time::timeout(interval, changed).await;
+        }
+        Vec::new()
+    }
+
+    /// Whether the query stopped running, after which the feed has no more events.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// All queries running on a helper, sorted by id.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct Snapshot {
+    pub queries: Vec<QuerySnapshot>,
+    /// Queries that were checkpointed and wait to be resumed, sorted.
+    pub suspended: Vec<String>,
+    /// Queries that stopped running recently, if the status keeps a summary of them.
+    #[cfg_attr(
+        feature = "enable-serde",
+        serde(skip_serializing_if = "Option::is_none")
+    )]
+    pub summary: Option<SummarySnapshot>,
+}
+
+/// Queries that are running on this helper.
+#[derive(Debug, Default)]
+pub struct Status {
+    queries: Mutex<BTreeMap<String, Running>>,
+    /// Queries that were suspended, along with the collector that submitted them.
+    suspended: Mutex<BTreeMap<String, Option<String>>>,
+    draining: AtomicBool,
+    summary: Option<Summary>,
+    /// Quotas of collectors, which are not limited if there are none.
+    quotas: Option<QuotaTracker>,
+}
+
+/// Query that is running, along with the collector that submitted it, if it is known.
+#[derive(Debug)]
+struct Running {
+    progress: Arc<QueryProgress>,
+    collector: Option<String>,
+}
+
+impl Running {
+    /// Whether the query is visible to `collector`, or to anybody if `None`.
+    fn is_visible_to(&self, collector: Option<&str>) -> bool {
+        is_visible(self.collector.as_deref(), collector)
+    }
+}
+
+fn is_visible(owner: Option<&str>, collector: Option<&str>) -> bool {
+    collector.map_or(true, |collector| owner == Some(collector))
+}
+
+/// Keeps a query in the [`Status`] it was added to until dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct Tracked {
+    status: Arc<Status>,
+    id: String,
+    progress: Arc<QueryProgress>,
+    collector: Option<String>,
+    outcome: Option<Outcome>,
+}
+
+impl Status {
+    /// Status that keeps a [`Summary`] of the queries that stopped running within `window`.
+    #[must_use]
+    pub fn with_summary(window: Duration) -> Self {
+        Self {
+            summary: Some(Summary::new(window)),
+            ..Self::default()
+        }
+    }
+
+    /// Enforces `quotas` on the queries collectors submit, see [`admit`](Self::admit).
+    #[must_use]
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(QuotaTracker::new(quotas));
+        self
+    }
+
+    /// Adds the query to the status until the returned value is dropped. A query tracked with
+    /// the same id before is replaced, and a suspended one is resumed.
+    ///
+    /// ## Errors
+    /// If the status is draining, in which case the query must not be started.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn track(
+        self: &Arc<Self>,
+        id: impl Into<String>,
+        progress: Arc<QueryProgress>,
+    ) -> Result<Tracked, Error> {
+        self.insert(id.into(), progress, None)
+    }
+
+    /// Same as [`track`](Self::track), for a query with `rows` input rows for `epoch` that
+    /// `collector` submitted. `collector` must be the authenticated identity of the caller. The
+    /// query is counted against the quota of the collector, which is written to the audit log,
+    /// before anything is allocated for it. Only that collector may cancel and follow it, and
+    /// see it in snapshots.
+    ///
+    /// ## Errors
+    /// If the status is draining, or the query would exceed the quota of the collector. The
+    /// query must not be started in either case.
+    pub fn admit(
+        self: &Arc<Self>,
+        collector: &str,
+        id: impl Into<String>,
+        epoch: u8,
+        rows: u64,
+        progress: Arc<QueryProgress>,
+    ) -> Result<Tracked, Error> {
+        self.insert(id.into(), progress, Some((collector, epoch, rows)))
+    }
+
+    fn insert(
+        self: &Arc<Self>,
+        id: String,
+        progress: Arc<QueryProgress>,
+        submitted: Option<(&str, u8, u64)>,
+    ) -> Result<Tracked, Error> {
+        let mut queries = self.queries.lock().unwrap();
+        // checked under the lock, so no query slips in after `drained` saw none running
+        if self.is_draining() {
+            return Err(Error::Draining);
+        }
+        // and before the quota, so that a query that does not start is not counted
+        if let (Some(quotas), Some((collector, epoch, rows))) = (&self.quotas, submitted) {
+            quotas.admit(collector, epoch, rows, SystemTime::now())?;
+        }
+        let collector = submitted.map(|(collector, ..)| collector.to_owned());
+        self.suspended.lock().unwrap().remove(&id);
+        queries.insert(
+            id.clone(),
+            Running {
+                progress: Arc::clone(&progress),
+                collector: collector.clone(),
+            },
+        );
+        Ok(Tracked {
+            status: Arc::clone(self),
+            id,
+            progress,
+            collector,
+            outcome: None,
+        })
+    }
+
+    /// Stops taking new queries. Queries that are running already are not affected.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn start_draining(&self) {
+        let _queries = self.queries.lock().unwrap();
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Asks the query with the given id to checkpoint and stop, see
+    /// [`QueryProgress::request_pause`]. Returns whether such a query is running.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn pause(&self, id: &str) -> bool {
+        if let Some(running) = self.queries.lock().unwrap().get(id) {
+            running.progress.request_pause();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Asks the query with the given id to abort, see [`QueryProgress::request_cancel`]. Returns
+    /// whether such a query is running and `collector` submitted it, or any query if it is
+    /// `None`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn cancel(&self, id: &str, collector: Option<&str>) -> bool {
+        let queries = self.queries.lock().unwrap();
+        if let Some(running) = queries.get(id).filter(|r| r.is_visible_to(collector)) {
+            running.progress.request_cancel();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Asks every running query to checkpoint and stop.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn pause_all(&self) {
+        for running in self.queries.lock().unwrap().values() {
+            running.progress.request_pause();
+        }
+    }
+
+    #[must_use]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Whether the status is draining and all queries have finished.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn drained(&self) -> bool {
+        let queries = self.queries.lock().unwrap();
+        self.is_draining() && queries.is_empty()
+    }
+
+    /// Feed of events of the running query with the given id, if there is one and `collector`
+    /// submitted it, or any query if it is `None`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn follow(self: &Arc<Self>, id: &str, collector: Option<&str>) -> Option<EventFeed> {
+        let progress = self
+            .queries
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|r| r.is_visible_to(collector))
+            .map(|r| Arc::clone(&r.progress))?;
+        Some(EventFeed {
+            status: Arc::clone(self),
+            id: id.to_owned(),
+            progress,
+            stages_seen: 0,
+            records_seen: 0,
+            finished: false,
+        })
+    }
+
+    fn is_tracked(&self, id: &str, progress: &Arc<QueryProgress>) -> bool {
+        self.queries
+            .lock()
+            .unwrap()
+            .get(id)
+            .map_or(false, |r| Arc::ptr_eq(&r.progress, progress))
+    }
+
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot_for(None)
+    }
+
+    /// Snapshot of the queries `collector` submitted only, or of all of them if it is `None`.
+    /// The summary covers all queries either way, and tells nothing about any one of them.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn snapshot_for(&self, collector: Option<&str>) -> Snapshot {
+        Snapshot {
+            queries: self
+                .queries
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, running)| running.is_visible_to(collector))
+                .map(|(id, running)| running.progress.snapshot(id))
+                .collect(),
+            suspended: self
+                .suspended
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, owner)| is_visible(owner.as_deref(), collector))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            summary: self.summary.as_ref().map(Summary::snapshot),
+        }
+    }
+}
+
+impl Tracked {
+    /// Stops tracking the query, which ended with the given outcome. Queries that are dropped
+    /// without finishing are summarized with an unknown outcome.
+    pub fn finish(mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+    }
+
+    /// Stops tracking the query as running and records it as suspended, after the code running
+    /// it has saved a checkpoint to resume from. Suspended queries do not keep the status from
+    /// being [`Status::drained`].
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn suspend(mut self) {
+        self.outcome = Some(Outcome::Suspended);
+        // recorded as suspended before it stops running, so it never goes missing from both
+        self.status
+            .suspended
+            .lock()
+            .unwrap()
+            .insert(self.id.clone(), self.collector.clone());
+        drop(self);
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut queries = self.status.queries.lock().unwrap();
+        // unless it was replaced by another query with the same id
+        if queries
+            .get(&self.id)
+            .map_or(false, |r| Arc::ptr_eq(&r.progress, &self.progress))
+        {
+            queries.remove(&self.id);
+        }
+        drop(queries);
+        if let Some(summary) = &self.status.summary {
+            summary.record(&self.progress, self.outcome.unwrap_or(Outcome::Unknown));
+        }
+        // feeds of the query learn that it is gone
+        self.progress.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::quota::{self, Quota, Quotas};
+    use crate::helpers::ring::HelperAddr;
+    use crate::telemetry::status::{
+        Error, ProgressEvent, QueryProgress, Status, Traffic, NOT_STARTED,
+    };
+    use crate::telemetry::summary::{FailureCategory, Outcome};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn tracks_running_queries() {
+        let status = Arc::new(Status::default());
+        let (p1, p2) = (Arc::default(), Arc::<QueryProgress>::default());
+        let t1 = status.track("q1", Arc::clone(&p1)).unwrap();
+        let _t2 = status.track("q2", Arc::clone(&p2)).unwrap();
+
+        p1.set_stage("sort");
+        p1.records_processed(10);
+        p1.records_processed(5);
+        p1.bytes_sent(HelperAddr::Right, 100);
+        p1.bytes_received(HelperAddr::Left, 7);
+        p1.set_buffer_depth(3);
+
+        let snapshot = status.snapshot();
+        let q1 = &snapshot.queries[0];
+        assert_eq!(
+            ("q1", "sort", 15, 3),
+            (&*q1.id, &*q1.stage, q1.records_processed, q1.buffer_depth)
+        );
+        assert_eq!(
+            (
+                Traffic {
+                    bytes_sent: 0,
+                    bytes_received: 7
+                },
+                Traffic {
+                    bytes_sent: 100,
+                    bytes_received: 0
+                }
+            ),
+            (q1.left, q1.right)
+        );
+        assert_eq!(NOT_STARTED, snapshot.queries[1].stage);
+
+        drop(t1);
+        let ids = status
+            .snapshot()
+            .queries
+            .into_iter()
+            .map(|q| q.id)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["q2"], ids);
+    }
+
+    #[test]
+    fn drain() {
+        let status = Arc::new(Status::default());
+        let running = status.track("q1", Arc::default()).unwrap();
+        assert!(!status.drained());
+
+        status.start_draining();
+        assert_eq!(
+            Error::Draining,
+            status.track("q2", Arc::default()).unwrap_err()
+        );
+        assert!(!status.drained());
+
+        drop(running);
+        assert!(status.drained());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let status = Arc::new(Status::default());
+        let progress = Arc::<QueryProgress>::default();
+        let running = status.track("q1", Arc::clone(&progress)).unwrap();
+        let _other = status.track("q2", Arc::default()).unwrap();
+
+        assert!(!status.pause("q3"));
+        assert!(status.pause("q1"));
+        assert!(progress.pause_requested());
+
+        running.suspend();
+        let snapshot = status.snapshot();
+        assert_eq!(vec!["q1"], snapshot.suspended);
+        assert_eq!(1, snapshot.queries.len());
+
+        let _resumed = status.track("q1", Arc::default()).unwrap();
+        assert!(status.snapshot().suspended.is_empty());
+        assert_eq!(2, status.snapshot().queries.len());
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let status = Arc::new(Status::default());
+        let progress = Arc::<QueryProgress>::default();
+        let _running = status.track("q1", Arc::clone(&progress)).unwrap();
+        assert!(!status.cancel("q2", None));
+
+        let (cancelled, ()) = tokio::join!(progress.cancelled(), async {
+            tokio::task::yield_now().await;
+            assert!(status.cancel("q1", None));
+        });
+        assert_eq!((), cancelled);
+        assert!(progress.cancel_requested());
+        // and it stays that way
+        progress.cancelled().await;
+    }
+
+    #[test]
+    fn collectors() {
+        let status = Arc::new(Status::default());
+        let progress = Arc::<QueryProgress>::default();
+        let _acme = status
+            .admit("acme", "q1", 1, 10, Arc::clone(&progress))
+            .unwrap();
+        let _untracked = status.track("q2", Arc::default()).unwrap();
+        status
+            .admit("other", "q3", 1, 10, Arc::default())
+            .unwrap()
+            .suspend();
+        status
+            .admit("acme", "q4", 1, 10, Arc::default())
+            .unwrap()
+            .suspend();
+
+        // collectors only see their own queries
+        let snapshot = status.snapshot_for(Some("acme"));
+        assert_eq!(
+            vec!["q1"],
+            snapshot.queries.iter().map(|q| &q.id).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["q4"], snapshot.suspended);
+        assert_eq!(2, status.snapshot().queries.len());
+        assert_eq!(vec!["q3", "q4"], status.snapshot().suspended);
+
+        // and only follow and cancel those
+        assert!(status.follow("q1", Some("other")).is_none());
+        assert!(status.follow("q2", Some("acme")).is_none());
+        assert!(status.follow("q1", Some("acme")).is_some());
+        assert!(!status.cancel("q1", Some("other")));
+        assert!(!progress.cancel_requested());
+        assert!(status.cancel("q1", Some("acme")));
+        assert!(progress.cancel_requested());
+    }
+
+    #[test]
+    fn quotas() {
+        let status = Arc::new(Status::default().with_quotas(Quotas {
+            default: Quota {
+                queries_per_day: Some(2),
+                rows_per_epoch: Some(100),
+            },
+            collectors: HashMap::default(),
+        }));
+        let _q1 = status.admit("acme", "q1", 1, 60, Arc::default()).unwrap();
+        assert!(matches!(
+            status.admit("acme", "q2", 1, 50, Arc::default()),
+            Err(Error::Quota(quota::Error::RowsPerEpoch { epoch: 1, .. }))
+        ));
+        // the rejected query neither runs nor counts
+        assert_eq!(1, status.snapshot().queries.len());
+        let _q2 = status.admit("acme", "q2", 1, 40, Arc::default()).unwrap();
+        assert!(matches!(
+            status.admit("acme", "q3", 2, 1, Arc::default()),
+            Err(Error::Quota(quota::Error::QueriesPerDay { .. }))
+        ));
+        // other collectors have quotas of their own, and queries the helper tracks itself
+        // have none
+        let _q4 = status.admit("other", "q4", 1, 100, Arc::default()).unwrap();
+        let _q5 = status.track("q5", Arc::default()).unwrap();
+
+        // and nobody gets in while draining
+        status.start_draining();
+        assert_eq!(
+            Error::Draining,
+            status
+                .admit("new", "q6", 1, 100, Arc::default())
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let status = Arc::new(Status::default());
+        status
+            .track("q1", Arc::default())
+            .unwrap()
+            .finish(Outcome::Completed);
+        assert_eq!(None, status.snapshot().summary);
+
+        let status = Arc::new(Status::with_summary(Duration::from_secs(60)));
+        status
+            .track("q1", Arc::default())
+            .unwrap()
+            .finish(Outcome::Completed);
+        status
+            .track("q2", Arc::default())
+            .unwrap()
+            .finish(Outcome::Failed(FailureCategory::Aborted));
+        status.track("q3", Arc::default()).unwrap().suspend();
+        drop(status.track("q4", Arc::default()).unwrap());
+        let _running = status.track("q5", Arc::default()).unwrap();
+
+        let summary = status.snapshot().summary.unwrap();
+        assert_eq!(4, summary.queries);
+        assert_eq!(
+            vec![
+                ("completed", 1),
+                ("failed_aborted", 1),
+                ("suspended", 1),
+                ("unknown", 1)
+            ],
+            summary.outcomes.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let status = Arc::new(Status::default());
+        let progress = Arc::<QueryProgress>::default();
+        let tracked = status.track("q1", Arc::clone(&progress)).unwrap();
+        assert!(status.follow("q2", None).is_none());
+
+        progress.set_stage("sort");
+        let mut feed = status.follow("q1", None).unwrap();
+        assert!(matches!(
+            &feed.poll()[..],
+            [ProgressEvent::StageEntered { stage, .. }] if stage == "sort"
+        ));
+        assert!(feed.poll().is_empty());
+
+        progress.set_expected_records(100);
+        progress.records_processed(25);
+        progress.set_stage("aggregation");
+        let events = feed.next(Duration::from_secs(60)).await;
+        assert_eq!(2, events.len());
+        assert_eq!("stage", events[0].name());
+        assert!(matches!(
+            events[1],
+            ProgressEvent::Progress {
+                records_processed: 25,
+                eta_secs: Some(_),
+            }
+        ));
+
+        // wakes up as soon as the query is gone
+        let (events, ()) = tokio::join!(feed.next(Duration::from_secs(60)), async {
+            drop(tracked);
+        });
+        assert_eq!(vec![ProgressEvent::Finished], events);
+        assert!(feed.is_finished());
+        assert!(feed.next(Duration::from_secs(60)).await.is_empty());
+    }
+}
@@ -0,0 +1,311 @@
+//! Coarse statistics of the queries a helper ran recently, for operators to plan capacity with.
+//!
+//! A [`Summary`] aggregates every query that stops running on the helper into a rolling window:
+//! how it ended, how many records it had, rounded up to a power of ten, and how long it spent in
+//! every stage. Nothing that identifies a query or any of its records is kept, not even its id,
+//! so the summary can be shown to anybody who can see the status of the helper. Summaries are
+//! opt-in, see [`Status::with_summary`].
+//!
+//! The window is split into [`SLOTS`] slots, and queries are added to the most recent one. Slots
+//! that fall out of the window are dropped with everything in them, so the summary covers between
+//! the window and one slot less of it.
+//!
+//! [`Status::with_summary`]: crate::telemetry::status::Status::with_summary
+//!
+use crate::helpers::error::Error;
+use crate::telemetry::status::QueryProgress;
+#[cfg(feature = "enable-serde")]
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of slots the window of a summary is split into.
+pub const SLOTS: u32 = 12;
+
+/// Why a query failed, without any detail that could tell queries apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureCategory {
+    /// Exchanging messages with a peer failed.
+    Network,
+    /// Query ran out of memory, or a peer sent more than it was allowed to.
+    ResourceLimit,
+    /// This helper or a peer aborted the query.
+    Aborted,
+    /// Anything outside of the helper infrastructure, like invalid input.
+    Other,
+}
+
+impl From<&Error> for FailureCategory {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::SendError { .. }
+            | Error::ReceiveError { .. }
+            | Error::Closed { .. }
+            | Error::Peer { .. }
+            | Error::PeerGone { .. } => Self::Network,
+            Error::MemoryLimitExceeded(_) | Error::MessageTooBig { .. } | Error::Spill(_) => {
+                Self::ResourceLimit
+            }
+            Error::Aborted { .. } => Self::Aborted,
+        }
+    }
+}
+
+/// How a query stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Completed,
+    /// Query was checkpointed to be resumed later.
+    Suspended,
+    /// Collector that submitted the query cancelled it.
+    Cancelled,
+    Failed(FailureCategory),
+    /// Query stopped running without saying how, which is what happens when the code running it
+    /// panics.
+    Unknown,
+}
+
+impl Outcome {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Suspended => "suspended",
+            Self::Cancelled => "cancelled",
+            Self::Failed(FailureCategory::Network) => "failed_network",
+            Self::Failed(FailureCategory::ResourceLimit) => "failed_resource_limit",
+            Self::Failed(FailureCategory::Aborted) => "failed_aborted",
+            Self::Failed(FailureCategory::Other) => "failed_other",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Time spent in a single stage, by all queries that entered it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct StageStats {
+    pub queries: u64,
+    pub total_secs: f64,
+    pub max_secs: f64,
+}
+
+impl StageStats {
+    fn add(&mut self, other: &Self) {
+        self.queries += other.queries;
+        self.total_secs += other.total_secs;
+        self.max_secs = self.max_secs.max(other.max_secs);
+    }
+}
+
+/// Queries that stopped running within the window of a [`Summary`].
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct SummarySnapshot {
+    pub window_secs: f64,
+    pub queries: u64,
+    /// Number of queries by how they ended, see [`Outcome::name`].
+    pub outcomes: BTreeMap<&'static str, u64>,
+    /// Number of queries by the power of ten their records were rounded up to, with queries that
+    /// had no records under zero.
+    pub input_sizes: BTreeMap<u64, u64>,
+    pub stages: BTreeMap<&'static str, StageStats>,
+}
+
+impl SummarySnapshot {
+    fn add(&mut self, other: &Self) {
+        self.queries += other.queries;
+        for (outcome, count) in &other.outcomes {
+            *self.outcomes.entry(outcome).or_default() += count;
+        }
+        for (size, count) in &other.input_sizes {
+            *self.input_sizes.entry(*size).or_default() += count;
+        }
+        for (stage, stats) in &other.stages {
+            self.stages.entry(stage).or_default().add(stats);
+        }
+    }
+}
+
+/// Smallest power of ten that is not less than `records`, or zero if there are none.
+#[must_use]
+pub fn size_bucket(records: u64) -> u64 {
+    if records == 0 {
+        return 0;
+    }
+    let mut bucket = 1_u64;
+    while bucket < records {
+        match bucket.checked_mul(10) {
+            Some(next) => bucket = next,
+            None => return u64::MAX,
+        }
+    }
+    bucket
+}
+
+#[derive(Debug)]
+struct Slot {
+    start: Instant,
+    queries: SummarySnapshot,
+}
+
+/// Rolling summary of the queries that stopped running on a helper within a window of time.
+#[derive(Debug)]
+pub struct Summary {
+    window: Duration,
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+impl Summary {
+    #[must_use]
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            slots: Mutex::default(),
+        }
+    }
+
+    /// Adds a query that stopped running with the given outcome. Its input size is the number of
+    /// records it expected, or processed if it did not say.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn record(&self, progress: &QueryProgress, outcome: Outcome) {
+        let now = Instant::now();
+        let mut slots = self.slots.lock().unwrap();
+        self.expire(&mut slots, now);
+        let slot_len = self.window / SLOTS;
+        if slots
+            .back()
+            .map_or(true, |slot| now.duration_since(slot.start) >= slot_len)
+        {
+            slots.push_back(Slot {
+                start: now,
+                queries: SummarySnapshot::default(),
+            });
+        }
+        let queries = &mut slots.back_mut().unwrap().queries;
+
+        queries.queries += 1;
+        *queries.outcomes.entry(outcome.name()).or_default() += 1;
+        *queries
+            .input_sizes
+            .entry(size_bucket(progress.input_size()))
+            .or_default() += 1;
+        for (stage, time) in progress.stage_durations() {
+            let secs = time.as_secs_f64();
+            queries.stages.entry(stage).or_default().add(&StageStats {
+                queries: 1,
+                total_secs: secs,
+                max_secs: secs,
+            });
+        }
+    }
+
+    fn expire(&self, slots: &mut VecDeque<Slot>, now: Instant) {
+        while slots
+            .front()
+            .map_or(false, |slot| now.duration_since(slot.start) >= self.window)
+        {
+            slots.pop_front();
+        }
+    }
+
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn snapshot(&self) -> SummarySnapshot {
+        let mut slots = self.slots.lock().unwrap();
+        self.expire(&mut slots, Instant::now());
+        slots.iter().fold(
+            SummarySnapshot {
+                window_secs: self.window.as_secs_f64(),
+                ..SummarySnapshot::default()
+            },
+            |mut summary, slot| {
+                summary.add(&slot.queries);
+                summary
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::error::Error;
+    use crate::helpers::ring::HelperAddr;
+    use crate::telemetry::status::QueryProgress;
+    use crate::telemetry::summary::{size_bucket, FailureCategory, Outcome, Summary};
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(
+            vec![0, 1, 10, 10, 100, 1_000_000, u64::MAX],
+            [0, 1, 2, 10, 11, 999_999, u64::MAX]
+                .into_iter()
+                .map(size_bucket)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn categories() {
+        let closed = Error::Closed {
+            by: HelperAddr::Left,
+            message: "m",
+        };
+        assert_eq!(FailureCategory::Network, (&closed).into());
+        let peer = closed.with_peer("127.0.0.1:1".parse().unwrap());
+        assert_eq!(FailureCategory::Network, (&peer).into());
+        assert_eq!(
+            FailureCategory::ResourceLimit,
+            (&Error::Spill("disk full".to_owned())).into()
+        );
+    }
+
+    #[test]
+    fn aggregates_queries() {
+        let summary = Summary::new(Duration::from_secs(3600));
+        let p1 = QueryProgress::default();
+        p1.set_expected_records(250);
+        p1.records_processed(20);
+        p1.set_stage("sort");
+        p1.set_stage("aggregate");
+        summary.record(&p1, Outcome::Completed);
+
+        let p2 = QueryProgress::default();
+        p2.records_processed(7);
+        p2.set_stage("sort");
+        summary.record(&p2, Outcome::Failed(FailureCategory::Network));
+        summary.record(&QueryProgress::default(), Outcome::Completed);
+
+        let snapshot = summary.snapshot();
+        assert_eq!(3, snapshot.queries);
+        assert!((snapshot.window_secs - 3600.0).abs() < f64::EPSILON);
+        assert_eq!(
+            vec![("completed", 2), ("failed_network", 1)],
+            snapshot.outcomes.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(0, 1), (10, 1), (1000, 1)],
+            snapshot.input_sizes.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(2, snapshot.stages["sort"].queries);
+        assert_eq!(1, snapshot.stages["aggregate"].queries);
+        assert!(snapshot.stages["sort"].max_secs <= snapshot.stages["sort"].total_secs);
+    }
+
+    #[test]
+    fn rolls_over() {
+        let summary = Summary::new(Duration::from_millis(60));
+        summary.record(&QueryProgress::default(), Outcome::Unknown);
+        assert_eq!(1, summary.snapshot().queries);
+
+        std::thread::sleep(Duration::from_millis(80));
+        let snapshot = summary.snapshot();
+        assert_eq!(0, snapshot.queries);
+        assert!(snapshot.outcomes.is_empty());
+    }
+}
@@ -0,0 +1,284 @@
+//!
+//! Timeline of a query that stopped running, put together after the fact from what helpers keep
+//! anyway: its metadata and checkpoint in the [`QueryStore`], the entries of the audit log about
+//! it, and snapshots of its progress taken from the status of the helper while it ran, see
+//! [`Status::snapshot`]. Postmortems of queries that were slow or failed start from the timeline
+//! instead of from a helper that runs the query again.
+//!
+//! Snapshots are samples. As far as the timeline knows, a stage starts with the first snapshot
+//! that shows it and ends with the first that shows the next one, so durations are only as
+//! precise as the interval snapshots were taken at, and a stage that was over before the next
+//! snapshot does not show up at all. Audit entries do not name queries, so the ones about the
+//! query are those that mention its id.
+//!
+//! [`QueryStore`]: crate::storage::QueryStore
+//! [`Status::snapshot`]: crate::telemetry::status::Status::snapshot
+//!
+use crate::storage::{AuditEntry, QueryMetadata, QueryState};
+use crate::telemetry::status::QuerySnapshot;
+#[cfg(feature = "enable-serde")]
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+/// Part of a query spent in a single stage.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct StageSpan {
+    pub stage: String,
+    /// Seconds since the query started.
+    pub started_secs: f64,
+    pub duration_secs: f64,
+    /// Records processed in the stage.
+    pub records: u64,
+    /// Bytes sent to both peers in the stage.
+    pub bytes_sent: u64,
+    /// Bytes received from both peers in the stage.
+    pub bytes_received: u64,
+}
+
+impl StageSpan {
+    /// Span of the stage `first` shows, up to `end`.
+    fn new(first: &QuerySnapshot, end: &QuerySnapshot) -> Self {
+        let sent = |s: &QuerySnapshot| s.left.bytes_sent + s.right.bytes_sent;
+        let received = |s: &QuerySnapshot| s.left.bytes_received + s.right.bytes_received;
+        Self {
+            stage: first.stage.clone(),
+            started_secs: first.elapsed_secs,
+            duration_secs: end.elapsed_secs - first.elapsed_secs,
+            records: end
+                .records_processed
+                .saturating_sub(first.records_processed),
+            bytes_sent: sent(end).saturating_sub(sent(first)),
+            bytes_received: received(end).saturating_sub(received(first)),
+        }
+    }
+}
+
+/// How a single query went, from start to end.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize))]
+pub struct Timeline {
+    pub query: QueryMetadata,
+    /// Stages in the order the query went through them.
+    pub stages: Vec<StageSpan>,
+    /// Entries of the audit log about the query.
+    pub audit: Vec<AuditEntry>,
+    /// Why the query failed, and anything the snapshots show went wrong.
+    pub errors: Vec<String>,
+    /// Size of the checkpoint the query can resume from, if it has one.
+    pub checkpoint_bytes: Option<usize>,
+}
+
+impl Timeline {
+    /// Puts together the timeline of `query`. Snapshots and audit entries of other queries are
+    /// ignored, so helpers can pass everything they have.
+    #[must_use]
+    pub fn new(
+        query: QueryMetadata,
+        audit: &[AuditEntry],
+        snapshots: &[QuerySnapshot],
+        checkpoint: Option<&[u8]>,
+    ) -> Self {
+        let mut samples = snapshots
+            .iter()
+            .filter(|s| s.id == query.id)
+            .collect::<Vec<_>>();
+        samples.sort_by(|a, b| a.elapsed_secs.total_cmp(&b.elapsed_secs));
+
+        let mut stages = Vec::new();
+        let mut first: Option<&QuerySnapshot> = None;
+        for &sample in &samples {
+            match first {
+                Some(f) if f.stage == sample.stage => {}
+                _ => {
+                    if let Some(f) = first {
+                        stages.push(StageSpan::new(f, sample));
+                    }
+                    first = Some(sample);
+                }
+            }
+        }
+        if let (Some(f), Some(last)) = (first, samples.last()) {
+            stages.push(StageSpan::new(f, last));
+        }
+
+        let mut errors = Vec::new();
+        if let QueryState::Failed { reason } = &query.state {
+            errors.push(reason.clone());
+        }
+        if let Some(last) = samples.last().filter(|s| s.dead_letters > 0) {
+            errors.push(format!(
+                "{} messages were dead letters by {:.1}s",
+                last.dead_letters, last.elapsed_secs
+            ));
+        }
+
+        Self {
+            audit: audit
+                .iter()
+                .filter(|entry| entry.event.contains(&query.id))
+                .cloned()
+                .collect(),
+            query,
+            stages,
+            errors,
+            checkpoint_bytes: checkpoint.map(<[u8]>::len),
+        }
+    }
+}
+
+impl Display for Timeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let query = &self.query;
+        writeln!(
+            f,
+            "query {} of {}, epoch {}, {} rows: {:?}",
+            query.id, query.collector, query.epoch, query.rows, query.state
+        )?;
+        writeln!(
+            f,
+            "{:<16} {:>10} {:>10} {:>12} {:>14} {:>14}",
+            "stage", "start", "duration", "records", "bytes sent", "bytes received"
+        )?;
+        for span in &self.stages {
+            writeln!(
+                f,
+                "{:<16} {:>9.1}s {:>9.1}s {:>12} {:>14} {:>14}",
+                span.stage,
+                span.started_secs,
+                span.duration_secs,
+                span.records,
+                span.bytes_sent,
+                span.bytes_received
+            )?;
+        }
+        for entry in &self.audit {
+            writeln!(
+                f,
+                "audit at +{}s: {}",
+                entry.at.saturating_sub(query.submitted),
+                entry.event
+            )?;
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+        if let Some(bytes) = self.checkpoint_bytes {
+            writeln!(f, "checkpoint of {bytes} bytes")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{AuditEntry, QueryMetadata, QueryState};
+    use crate::telemetry::status::{PeerHealth, QuerySnapshot, Traffic};
+    use crate::telemetry::timeline::{StageSpan, Timeline};
+
+    fn snapshot(id: &str, stage: &str, elapsed_secs: f64, records: u64) -> QuerySnapshot {
+        QuerySnapshot {
+            id: id.to_owned(),
+            stage: stage.to_owned(),
+            elapsed_secs,
+            records_processed: records,
+            left: Traffic {
+                bytes_sent: records * 10,
+                bytes_received: records * 20,
+            },
+            right: Traffic::default(),
+            buffer_depth: 0,
+            dead_letters: 0,
+            left_peer: PeerHealth::default(),
+            right_peer: PeerHealth::default(),
+            rejections: None,
+            eta_secs: None,
+        }
+    }
+
+    #[test]
+    fn timeline() {
+        let query = QueryMetadata {
+            id: "q1".to_owned(),
+            collector: "c".to_owned(),
+            epoch: 3,
+            rows: 100,
+            submitted: 1000,
+            state: QueryState::Failed {
+                reason: "peer closed the connection".to_owned(),
+            },
+        };
+        let audit = [
+            AuditEntry {
+                at: 1002,
+                collector: "c".to_owned(),
+                event: "budget charged for q1".to_owned(),
+            },
+            AuditEntry {
+                at: 1003,
+                collector: "c".to_owned(),
+                event: "budget charged for q2".to_owned(),
+            },
+        ];
+        let mut last = snapshot("q1", "attribution", 9.0, 90);
+        last.dead_letters = 2;
+        // out of order, and with snapshots of another query in between
+        let snapshots = [
+            snapshot("q1", "sort", 1.0, 0),
+            snapshot("q1", "attribution", 5.0, 50),
+            snapshot("q2", "sort", 2.0, 0),
+            snapshot("q1", "sort", 3.0, 30),
+            last,
+        ];
+
+        let timeline = Timeline::new(query, &audit, &snapshots, Some(&[0; 16]));
+        assert_eq!(
+            vec![
+                StageSpan {
+                    stage: "sort".to_owned(),
+                    started_secs: 1.0,
+                    duration_secs: 4.0,
+                    records: 50,
+                    bytes_sent: 500,
+                    bytes_received: 1000,
+                },
+                StageSpan {
+                    stage: "attribution".to_owned(),
+                    started_secs: 5.0,
+                    duration_secs: 4.0,
+                    records: 40,
+                    bytes_sent: 400,
+                    bytes_received: 800,
+                },
+            ],
+            timeline.stages
+        );
+        assert_eq!(&audit[..1], &timeline.audit[..]);
+        assert_eq!(
+            vec![
+                "peer closed the connection".to_owned(),
+                "2 messages were dead letters by 9.0s".to_owned(),
+            ],
+            timeline.errors
+        );
+        assert_eq!(Some(16), timeline.checkpoint_bytes);
+        assert!(timeline
+            .to_string()
+            .contains("audit at +2s: budget charged for q1"));
+    }
+
+    #[test]
+    fn no_snapshots() {
+        let query = QueryMetadata {
+            id: "q1".to_owned(),
+            collector: "c".to_owned(),
+            epoch: 0,
+            rows: 0,
+            submitted: 0,
+            state: QueryState::Completed,
+        };
+        let timeline = Timeline::new(query, &[], &[], None);
+        assert!(timeline.stages.is_empty());
+        assert!(timeline.errors.is_empty());
+    }
+}
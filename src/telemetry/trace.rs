@@ -0,0 +1,81 @@
+//!
+//! Trace context propagation between helpers. When the `otlp` feature is on, every message sent
+//! to another helper carries the W3C `traceparent` of the span it was sent from, and the span
+//! that receives it on the other side becomes its child. That way a single query can be followed
+//! across all three helpers in a distributed tracing UI. Without the feature the context is
+//! always empty and attaching it does nothing.
+//!
+use tracing::Span;
+
+/// W3C `traceparent` of the current span, or an empty string if there is none.
+#[cfg(feature = "otlp")]
+#[must_use]
+pub fn current_context() -> String {
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+
+    carrier.remove("traceparent").unwrap_or_default()
+}
+
+#[cfg(not(feature = "otlp"))]
+#[must_use]
+pub fn current_context() -> String {
+    String::new()
+}
+
+/// Makes `span` a child of the remote span described by `traceparent`.
+#[cfg(feature = "otlp")]
+pub fn set_remote_parent(span: &Span, traceparent: &str) {
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    if traceparent.is_empty() {
+        return;
+    }
+    let carrier = HashMap::from([("traceparent".to_owned(), traceparent.to_owned())]);
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn set_remote_parent(_span: &Span, _traceparent: &str) {}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use crate::telemetry::trace::{current_context, set_remote_parent};
+    use opentelemetry::sdk::trace::TracerProvider;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn propagates_trace_id() {
+        // tracer keeps a weak reference to the provider, so it must outlive the test
+        let provider = TracerProvider::default();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!("", current_context());
+
+            let sender = tracing::info_span!("send");
+            let context = sender.in_scope(current_context);
+            let trace_id = sender.context().span().span_context().trace_id();
+            assert!(context.contains(&trace_id.to_string()));
+
+            let receiver = tracing::info_span!("receive");
+            set_remote_parent(&receiver, &context);
+            assert_eq!(
+                trace_id,
+                receiver.context().span().span_context().trace_id()
+            );
+        });
+    }
+}
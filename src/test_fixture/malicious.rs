@@ -0,0 +1,228 @@
+//!
+//! Helpers that cheat, to test that protocols which are meant to catch cheating actually do.
+//! A [`TestWorld::malicious`] has one helper whose ring tampers with the field values it sends,
+//! which is how shares travel between helpers, while the other two follow the protocol.
+//!
+use crate::entropy::Entropy;
+use crate::field::Field;
+use crate::helpers::buffer::Pending;
+use crate::helpers::codec::{read_fields, write_fields};
+use crate::helpers::error::Error;
+use crate::helpers::ring::mock::{make_three, TestHelper};
+use crate::helpers::ring::{HelperAddr, Identity, Message, Ring};
+use crate::test_fixture::TestWorld;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// What the cheating helper does to field values it sends to the peer at the given address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tamper {
+    /// Follows the protocol.
+    None,
+    /// Adds one to the first value of every batch.
+    Corrupt(HelperAddr),
+    /// Sends the batch it sent the time before instead of the current one. The first batch is
+    /// sent as it is.
+    Replay(HelperAddr),
+}
+
+/// Ring that tampers with field values on the way out, and leaves everything else alone.
+#[derive(Debug)]
+pub struct TamperingRing<R = TestHelper> {
+    inner: R,
+    tamper: Tamper,
+    /// Last batch sent, encoded with [`write_fields`].
+    last_sent: Mutex<Option<Vec<u8>>>,
+}
+
+impl<R: Ring> TamperingRing<R> {
+    pub fn new(inner: R, tamper: Tamper) -> Self {
+        Self {
+            inner,
+            tamper,
+            last_sent: Mutex::default(),
+        }
+    }
+
+    fn tamper_with<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Vec<F> {
+        let mut values = values.to_vec();
+        match self.tamper {
+            Tamper::Corrupt(addr) if addr == dest => {
+                if let Some(v) = values.first_mut() {
+                    *v += F::ONE;
+                }
+            }
+            Tamper::Replay(addr) if addr == dest => {
+                let mut encoded = Vec::new();
+                write_fields(&values, &mut encoded);
+                let last = self.last_sent.lock().unwrap().replace(encoded);
+                if let Some(last) = last {
+                    values = read_fields(&last).unwrap();
+                }
+            }
+            _ => {}
+        }
+        values
+    }
+}
+
+#[async_trait]
+impl<R: Ring + Send> Ring for TamperingRing<R> {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        self.inner.send(dest, msg).await
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
+        self.inner.receive(source).await
+    }
+
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, Error> {
+        self.inner.receive_shared(source, consumers).await
+    }
+
+    async fn abort(&self, reason: &str) -> Result<(), Error> {
+        self.inner.abort(reason).await
+    }
+
+    async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), Error> {
+        self.inner.close::<T>(dest).await
+    }
+
+    fn pending(&self) -> Vec<Pending> {
+        self.inner.pending()
+    }
+
+    async fn send_fields<F: Field>(&self, dest: HelperAddr, values: &[F]) -> Result<(), Error> {
+        let values = self.tamper_with(dest, values);
+        self.inner.send_fields(dest, &values).await
+    }
+
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, Error> {
+        self.inner.receive_fields(source).await
+    }
+}
+
+impl TestWorld<TamperingRing> {
+    /// Same as [`TestWorld::new`], but the helper with identity `cheater` does what `tamper`
+    /// says.
+    ///
+    /// ## Panics
+    /// If called outside of a Tokio runtime, which the in-memory ring needs.
+    #[must_use]
+    pub fn malicious(cheater: Identity, tamper: Tamper) -> Self {
+        let (p1, p2, p3) = crate::prss::test::make_three();
+        let mut tampers = [Tamper::None; 3];
+        tampers[Identity::ALL.iter().position(|&h| h == cheater).unwrap()] = tamper;
+        let [r1, r2, r3] = make_three();
+        let [t1, t2, t3] = tampers;
+        Self {
+            ring: [
+                TamperingRing::new(r1, t1),
+                TamperingRing::new(r2, t2),
+                TamperingRing::new(r3, t3),
+            ],
+            participants: [p1, p2, p3],
+            entropy: Entropy::os(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::field::Fp31;
+    use crate::helpers::error::Error as HelperError;
+    use crate::helpers::ring::{HelperAddr, Identity, Ring};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::reveal::{reveal_vec, Error as RevealError};
+    use crate::test_fixture::malicious::Tamper;
+    use crate::test_fixture::TestWorld;
+    use futures::FutureExt;
+    use rand::rngs::mock::StepRng;
+
+    fn shares(values: &[u128]) -> [Vec<ReplicatedSecretSharing<Fp31>>; 3] {
+        let mut rng = StepRng::new(1, 7);
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for &v in values {
+            let [s1, s2, s3] = ReplicatedSecretSharing::share(Fp31::from(v), &mut rng);
+            shares[0].push(s1);
+            shares[1].push(s2);
+            shares[2].push(s3);
+        }
+        shares
+    }
+
+    fn is_inconsistent(result: &Result<Vec<Fp31>, Error>) -> bool {
+        matches!(
+            result,
+            Err(Error::Step { inner, .. }) if matches!(**inner, Error::Reveal(RevealError::Inconsistent))
+        )
+    }
+
+    #[tokio::test]
+    async fn honest() {
+        let world = TestWorld::malicious(Identity::H2, Tamper::None);
+        let revealed = world
+            .run(shares(&[1, 2, 3]), |ctx, shares| {
+                async move { reveal_vec(&ctx, 0, &shares).await.unwrap() }.boxed()
+            })
+            .await;
+        let expected = [1_u128, 2, 3].map(Fp31::from).to_vec();
+        assert_eq!([expected.clone(), expected.clone(), expected], revealed);
+    }
+
+    /// Helper that reveals wrong values to its right is caught by that helper, which aborts the
+    /// query for everybody.
+    #[tokio::test]
+    async fn reveal_catches_lies() {
+        let world = TestWorld::malicious(Identity::H2, Tamper::Corrupt(HelperAddr::Right));
+        let [h1, h2, h3] = world
+            .run(shares(&[1, 2, 3]), |ctx, shares| {
+                async move {
+                    let revealed = reveal_vec(&ctx, 0, &shares).await;
+                    if is_inconsistent(&revealed) {
+                        ctx.helper_ring.abort("peer lied").await.unwrap();
+                        return revealed;
+                    }
+                    // helpers that did not catch it learn about it from the one that did
+                    ctx.helper_ring.receive::<u8>(HelperAddr::Left).await?;
+                    revealed
+                }
+                .boxed()
+            })
+            .await;
+
+        assert!(is_inconsistent(&h3), "{h3:?}");
+        for other in [h1, h2] {
+            assert!(
+                matches!(
+                    other,
+                    Err(Error::Helper(HelperError::Aborted { ref reason, .. })) if reason == "peer lied"
+                ),
+                "{other:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reveal_catches_replays() {
+        let world = TestWorld::malicious(Identity::H1, Tamper::Replay(HelperAddr::Right));
+        let [_, h2, _] = world
+            .run(shares(&[1, 2, 3]), |ctx, shares| {
+                async move {
+                    let first = reveal_vec(&ctx, 0, &shares).await;
+                    let rest = shares.iter().map(|&s| s + s).collect::<Vec<_>>();
+                    (first, reveal_vec(&ctx, 1, &rest).await)
+                }
+                .boxed()
+            })
+            .await;
+
+        assert!(h2.0.is_ok());
+        assert!(is_inconsistent(&h2.1), "{:?}", h2.1);
+    }
+}
@@ -0,0 +1,110 @@
+//!
+//! Building blocks for tests of protocols: three helpers running in one process, one of which
+//! may cheat (see [`malicious`]), strategies for property tests of protocols in [`proptest`],
+//! and a deterministic simulation of helpers that explores different orderings of messages in
+//! [`sim`].
+//!
+pub mod malicious;
+pub mod proptest;
+pub mod sim;
+
+use crate::entropy::Entropy;
+use crate::field::Field;
+use crate::helpers::ring::mock::{make_three, TestHelper};
+use crate::helpers::ring::{Identity, Ring};
+use crate::prss::Participant;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use futures::future::BoxFuture;
+
+/// Three helpers connected by an in-memory ring, with PRSS set up between them.
+#[derive(Debug)]
+pub struct TestWorld<R = TestHelper> {
+    ring: [R; 3],
+    participants: [Participant; 3],
+    entropy: Entropy,
+}
+
+impl Default for TestWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestWorld {
+    /// ## Panics
+    /// If called outside of a Tokio runtime, which the in-memory ring needs.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_entropy(Entropy::os())
+    }
+
+    /// World that is the same every time it is created with the same `seed`: PRSS and
+    /// everything helpers draw from [`ProtocolContext::rng`] are derived from it.
+    ///
+    /// ## Panics
+    /// If called outside of a Tokio runtime, which the in-memory ring needs.
+    #[must_use]
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_entropy(Entropy::seeded(seed))
+    }
+
+    fn with_entropy(entropy: Entropy) -> Self {
+        let (p1, p2, p3) = crate::prss::test::make_three_with_rng(&mut entropy.rng());
+        Self {
+            ring: make_three(),
+            participants: [p1, p2, p3],
+            entropy,
+        }
+    }
+}
+
+impl<R: Ring> TestWorld<R> {
+    /// Contexts of the three helpers, in the order of [`Identity::ALL`].
+    #[must_use]
+    pub fn contexts(&self) -> [ProtocolContext<'_, R>; 3] {
+        let context = |i: usize| {
+            ProtocolContext::new(Identity::ALL[i], &self.participants[i], &self.ring[i])
+                .with_entropy(&self.entropy)
+        };
+        [context(0), context(1), context(2)]
+    }
+
+    /// Runs `protocol` on all three helpers at once, each with its own input, and returns what
+    /// every helper ended up with.
+    pub async fn run<I, O, P>(&self, inputs: [I; 3], protocol: P) -> [O; 3]
+    where
+        P: for<'a> Fn(ProtocolContext<'a, R>, I) -> BoxFuture<'a, O>,
+    {
+        let [c1, c2, c3] = self.contexts();
+        let [i1, i2, i3] = inputs;
+        let (o1, o2, o3) = futures::join!(protocol(c1, i1), protocol(c2, i2), protocol(c3, i3));
+        [o1, o2, o3]
+    }
+}
+
+/// Values shared as `shares`, one list of shares for every helper.
+///
+/// ## Panics
+/// If helpers hold a different number of shares, or shares that are not consistent with each
+/// other.
+#[must_use]
+pub fn reconstruct<F: Field>(shares: &[Vec<ReplicatedSecretSharing<F>>; 3]) -> Vec<F> {
+    let [s1, s2, s3] = shares;
+    assert!(
+        s1.len() == s2.len() && s2.len() == s3.len(),
+        "helpers hold {}, {} and {} shares",
+        s1.len(),
+        s2.len(),
+        s3.len()
+    );
+    s1.iter()
+        .zip(s2)
+        .zip(s3)
+        .enumerate()
+        .map(|(i, ((&a, &b), &c))| {
+            ReplicatedSecretSharing::reconstruct(&[a, b, c])
+                .unwrap_or_else(|| panic!("share {i} is not consistent: {:?}", [a, b, c]))
+        })
+        .collect()
+}
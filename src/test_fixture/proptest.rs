@@ -0,0 +1,139 @@
+//!
+//! Strategies for property tests of protocols, and [`check_protocol`] to compare what a protocol
+//! computes on shares with a reference function that computes the same on clear text.
+//!
+//! Shares are drawn by the strategies rather than from an RNG, so a failing case shrinks and
+//! reproduces with the same shares.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::mock::TestHelper;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::securemul::ProtocolContext;
+use crate::test_fixture::{reconstruct, TestWorld};
+use ::proptest::collection::vec;
+use ::proptest::prelude::*;
+use ::proptest::test_runner::TestCaseError;
+use futures::future::BoxFuture;
+use std::fmt::Debug;
+use std::ops::Range;
+
+/// Shares of a list of values, one list for every helper.
+pub type Shares<F> = [Vec<ReplicatedSecretSharing<F>>; 3];
+
+/// Any value of field `F`.
+pub fn field<F: Field>() -> impl Strategy<Value = F> {
+    (0..F::PRIME.into()).prop_map(F::from)
+}
+
+/// Any value of field `F` and the shares of it that the three helpers hold.
+pub fn shared<F: Field>() -> impl Strategy<Value = (F, [ReplicatedSecretSharing<F>; 3])> {
+    (field(), field(), field()).prop_map(|(value, x1, x2)| {
+        let x3 = value - (x1 + x2);
+        let shares = [
+            ReplicatedSecretSharing::new(x1, x2),
+            ReplicatedSecretSharing::new(x2, x3),
+            ReplicatedSecretSharing::new(x3, x1),
+        ];
+        (value, shares)
+    })
+}
+
+/// Batch of `len` records of field `F`, and the shares of it that the three helpers hold.
+pub fn batch<F: Field>(len: Range<usize>) -> impl Strategy<Value = (Vec<F>, Shares<F>)> {
+    vec(shared(), len).prop_map(|records| {
+        let mut shares: Shares<F> = Default::default();
+        let values = records
+            .into_iter()
+            .map(|(value, [s1, s2, s3])| {
+                shares[0].push(s1);
+                shares[1].push(s2);
+                shares[2].push(s3);
+                value
+            })
+            .collect();
+        (values, shares)
+    })
+}
+
+/// Runs `protocol` on `shares` in a fresh [`TestWorld`] and checks that its output reconstructs
+/// to `reference(values)`.
+///
+/// ## Errors
+/// If the protocol fails on any helper, or its output is not what `reference` computes.
+///
+/// ## Panics
+/// If a Tokio runtime cannot be created, or the protocol returns inconsistent shares.
+pub fn check_protocol<F, T, P>(
+    (values, shares): (Vec<F>, Shares<F>),
+    reference: impl Fn(&[F]) -> Vec<T>,
+    protocol: P,
+) -> Result<(), TestCaseError>
+where
+    F: Field,
+    T: Field,
+    P: for<'a> Fn(
+        ProtocolContext<'a, TestHelper>,
+        Vec<ReplicatedSecretSharing<F>>,
+    ) -> BoxFuture<'a, Res<Vec<ReplicatedSecretSharing<T>>>>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let [o1, o2, o3] = runtime.block_on(async {
+        let world = TestWorld::new();
+        world.run(shares, protocol).await
+    });
+    let outputs = [fail_on_error(o1)?, fail_on_error(o2)?, fail_on_error(o3)?];
+
+    prop_assert_eq!(reference(&values), reconstruct(&outputs));
+    Ok(())
+}
+
+fn fail_on_error<T, E: Debug>(result: Result<T, E>) -> Result<T, TestCaseError> {
+    result.map_err(|e| TestCaseError::fail(format!("protocol failed: {e:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::{Field, Fp31};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::test_fixture::proptest::{batch, check_protocol, shared};
+    use crate::test_fixture::reconstruct;
+    use ::proptest::prelude::*;
+    use futures::FutureExt;
+
+    proptest! {
+        #[test]
+        fn shares_reconstruct((value, shares) in shared::<Fp31>()) {
+            prop_assert_eq!(Some(value), ReplicatedSecretSharing::reconstruct(&shares));
+        }
+
+        #[test]
+        fn batches_reconstruct((values, shares) in batch::<Fp31>(0..10)) {
+            prop_assert_eq!(values, reconstruct(&shares));
+        }
+
+        #[test]
+        fn multiply_batch(input in batch::<Fp31>(0..10)) {
+            check_protocol(
+                input,
+                |values| values.iter().map(|&v| v * v).collect(),
+                |ctx, shares| async move { ctx.multiply_batch(0, &shares, &shares).await }.boxed(),
+            )?;
+        }
+
+        #[test]
+        fn sum_of_products(input in batch::<Fp31>(1..10)) {
+            check_protocol(
+                input,
+                |values| vec![values.iter().fold(Fp31::ZERO, |acc, &v| acc + v * v)],
+                |ctx, shares| {
+                    async move { Ok(vec![ctx.sum_of_products(0, &shares, &shares).await?]) }
+                        .boxed()
+                },
+            )?;
+        }
+    }
+}
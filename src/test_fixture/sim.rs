@@ -0,0 +1,436 @@
+//!
+//! Deterministic simulation of three helpers running a protocol. All helpers and the network
+//! between them run on the current thread, and a scheduler seeded with a number decides what
+//! happens next: one of the helpers that can make progress runs until it waits for something,
+//! or the network delivers the oldest message on one of the connections. Messages on the same
+//! connection arrive in order, as they do over TCP, but anything else may happen in any order.
+//!
+//! Running the same protocol with many seeds exercises interleavings of sends and receives that
+//! tests on a real runtime almost never hit. A schedule where every helper waits for a message
+//! that nobody is going to send is reported as a [`Deadlock`], and a seed that fails reproduces
+//! the same failure every time.
+//!
+use crate::helpers::buffer::{MessageBuffer, Take};
+use crate::helpers::codec::{Codec, Json};
+use crate::helpers::error::Error;
+use crate::helpers::ring::{Abort, HelperAddr, Identity, Message, Ring};
+use crate::prss::Participant;
+use crate::securemul::ProtocolContext;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::task::{waker, ArcWake};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::any::TypeId;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+/// Every helper waits for something, and there are no messages left to deliver.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("helpers {waiting:?} wait for messages that nobody sends, after {steps} steps")]
+pub struct Deadlock {
+    pub waiting: Vec<Identity>,
+    pub steps: usize,
+}
+
+/// What the scheduler decided to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Helper ran until it had to wait.
+    Run(Identity),
+    /// Oldest message sent by `from` to `to` arrived.
+    Deliver { from: Identity, to: Identity },
+}
+
+#[derive(Debug)]
+struct Envelope {
+    type_id: TypeId,
+    payload: Bytes,
+    /// Envelope carries no message, but closes the channel of `type_id`.
+    close: bool,
+}
+
+/// Messages in flight on every connection, and messages that arrived but were not received yet.
+#[derive(Debug, Default)]
+struct Network {
+    /// Indexed by sender, then by receiver.
+    links: [[VecDeque<Envelope>; 3]; 3],
+    buffers: [MessageBuffer<TypeId>; 3],
+}
+
+impl Network {
+    fn busy_links(&self) -> impl Iterator<Item = (Identity, Identity)> + '_ {
+        Identity::ALL.into_iter().flat_map(move |from| {
+            Identity::ALL
+                .into_iter()
+                .filter(move |&to| !self.links[index(from)][index(to)].is_empty())
+                .map(move |to| (from, to))
+        })
+    }
+
+    fn deliver(&mut self, from: Identity, to: Identity) {
+        let Envelope {
+            type_id,
+            payload,
+            close,
+        } = self.links[index(from)][index(to)].pop_front().unwrap();
+        let source = to.addr_of(from).unwrap();
+        let buf = &mut self.buffers[index(to)];
+        if type_id == TypeId::of::<Abort>() {
+            let reason = Json::decode::<Abort>(&payload)
+                .map_or_else(|e| format!("malformed abort message: {e}"), |a| a.0);
+            buf.abort(Some(source), reason);
+        } else if close {
+            buf.close(source, type_id);
+        } else {
+            // a failed buffer drops whatever arrives after the failure
+            let _ = buf.put(source, type_id, payload);
+        }
+    }
+}
+
+/// Ring of a single helper in the simulation. Sending never blocks: messages wait on the
+/// connection until the scheduler delivers them.
+#[derive(Debug)]
+pub struct SimRing {
+    identity: Identity,
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimRing {
+    fn post(&self, dest: HelperAddr, envelope: Envelope) {
+        let to = self.identity.peer(dest);
+        self.network.lock().unwrap().links[index(self.identity)][index(to)].push_back(envelope);
+    }
+}
+
+#[async_trait]
+impl Ring for SimRing {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), Error> {
+        let payload = Json::encode(&msg).map_err(|inner| Error::SendError { dest, inner })?;
+        self.post(
+            dest,
+            Envelope {
+                type_id: TypeId::of::<T>(),
+                payload: payload.into(),
+                close: false,
+            },
+        );
+        Ok(())
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, Error> {
+        self.receive_shared(source, 1).await
+    }
+
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, Error> {
+        let take = self.network.lock().unwrap().buffers[index(self.identity)].take_shared(
+            source,
+            TypeId::of::<T>(),
+            consumers,
+        )?;
+        let payload = match take {
+            Take::Ready(payload) => Some(payload),
+            Take::Closed => None,
+            Take::Spilled(_) => unreachable!("buffers without a window never spill"),
+            Take::Wait(rx) => rx.await.map_err(|e| Error::ReceiveError {
+                source,
+                inner: Box::new(e) as _,
+            })??,
+        }
+        .ok_or(Error::Closed {
+            by: source,
+            message: T::NAME,
+        })?;
+        Json::decode(&payload).map_err(|inner| Error::ReceiveError { source, inner })
+    }
+
+    async fn abort(&self, reason: &str) -> Result<(), Error> {
+        self.network.lock().unwrap().buffers[index(self.identity)].abort(None, reason.to_owned());
+        self.broadcast(Abort(reason.to_owned())).await
+    }
+
+    async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), Error> {
+        self.post(
+            dest,
+            Envelope {
+                type_id: TypeId::of::<T>(),
+                payload: Bytes::new(),
+                close: true,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Woken(AtomicBool);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Three helpers with PRSS set up between them, connected by a simulated network.
+#[derive(Debug)]
+pub struct Simulation {
+    rng: StdRng,
+    participants: [Participant; 3],
+    trace: Vec<Step>,
+}
+
+impl Simulation {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        let (p1, p2, p3) = crate::prss::test::make_three();
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            participants: [p1, p2, p3],
+            trace: Vec::new(),
+        }
+    }
+
+    /// Steps the scheduler took during the last run.
+    #[must_use]
+    pub fn trace(&self) -> &[Step] {
+        &self.trace
+    }
+
+    /// Runs `protocol` on all three helpers, each with its own input, until all of them finish.
+    ///
+    /// ## Errors
+    /// If helpers deadlock.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn run<I, O, P>(&mut self, inputs: [I; 3], protocol: P) -> Result<[O; 3], Deadlock>
+    where
+        P: for<'a> Fn(ProtocolContext<'a, SimRing>, I) -> BoxFuture<'a, O>,
+    {
+        let network = Arc::new(Mutex::new(Network::default()));
+        let rings = Identity::ALL.map(|identity| SimRing {
+            identity,
+            network: Arc::clone(&network),
+        });
+        let mut inputs = inputs.map(Some);
+        let mut helpers = [0, 1, 2].map(|i| {
+            let ctx = ProtocolContext::new(Identity::ALL[i], &self.participants[i], &rings[i]);
+            Some(protocol(ctx, inputs[i].take().unwrap()))
+        });
+        let woken = [(); 3].map(|()| Arc::new(Woken(AtomicBool::new(true))));
+        let mut outputs = [None, None, None];
+
+        self.trace.clear();
+        loop {
+            let mut steps = Identity::ALL
+                .into_iter()
+                .filter(|&h| {
+                    helpers[index(h)].is_some() && woken[index(h)].0.load(Ordering::SeqCst)
+                })
+                .map(Step::Run)
+                .collect::<Vec<_>>();
+            steps.extend(
+                network
+                    .lock()
+                    .unwrap()
+                    .busy_links()
+                    .map(|(from, to)| Step::Deliver { from, to }),
+            );
+            if steps.is_empty() {
+                break;
+            }
+
+            let step = steps[self.rng.gen_range(0..steps.len())];
+            self.trace.push(step);
+            match step {
+                Step::Run(h) => {
+                    let i = index(h);
+                    woken[i].0.store(false, Ordering::SeqCst);
+                    let waker = waker(Arc::clone(&woken[i]));
+                    let helper = helpers[i].as_mut().unwrap();
+                    if let Poll::Ready(output) =
+                        helper.as_mut().poll(&mut Context::from_waker(&waker))
+                    {
+                        outputs[i] = Some(output);
+                        helpers[i] = None;
+                    }
+                }
+                Step::Deliver { from, to } => network.lock().unwrap().deliver(from, to),
+            }
+        }
+
+        if outputs.iter().all(Option::is_some) {
+            Ok(outputs.map(Option::unwrap))
+        } else {
+            Err(Deadlock {
+                waiting: Identity::ALL
+                    .into_iter()
+                    .filter(|&h| outputs[index(h)].is_none())
+                    .collect(),
+                steps: self.trace.len(),
+            })
+        }
+    }
+}
+
+fn index(identity: Identity) -> usize {
+    match identity {
+        Identity::H1 => 0,
+        Identity::H2 => 1,
+        Identity::H3 => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::helpers::error::Error;
+    use crate::helpers::ring::{HelperAddr, Identity, Ring};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::test_fixture::reconstruct;
+    use crate::test_fixture::sim::{Deadlock, Simulation, Step};
+    use futures::FutureExt;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const SEEDS: u64 = 200;
+
+    fn shared_inputs(values: &[Fp31]) -> [Vec<ReplicatedSecretSharing<Fp31>>; 3] {
+        let mut rng = StdRng::seed_from_u64(0);
+        let shares = values
+            .iter()
+            .map(|&v| ReplicatedSecretSharing::share(v, &mut rng))
+            .collect::<Vec<_>>();
+        [0, 1, 2].map(|h| shares.iter().map(|s| s[h]).collect())
+    }
+
+    #[test]
+    fn multiply_in_any_order() {
+        let values = [3_u128, 7, 30, 0].map(Fp31::from);
+        for seed in 0..SEEDS {
+            let outputs = Simulation::new(seed)
+                .run(shared_inputs(&values), |ctx, input| {
+                    async move {
+                        let squares = ctx.multiply_batch(0, &input, &input).await.unwrap();
+                        vec![ctx.multiply(1, squares[1], input[1]).await.unwrap()]
+                    }
+                    .boxed()
+                })
+                .unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+
+            let cube = values[1] * values[1] * values[1];
+            assert_eq!(vec![cube], reconstruct(&outputs), "seed {seed}");
+        }
+    }
+
+    /// Messages are told apart by their type only, so a helper that is a step ahead of its peer
+    /// may send it a message before the peer received the one of the same type from the step
+    /// before. The peer receives them in the order they were sent.
+    #[test]
+    fn consecutive_steps() {
+        let values = [3_u128, 7].map(Fp31::from);
+        for seed in 0..SEEDS {
+            let outputs = Simulation::new(seed)
+                .run(shared_inputs(&values), |ctx, input| {
+                    async move {
+                        let mut product = input.clone();
+                        for step in 0..3 {
+                            product = ctx
+                                .multiply_batch(2 * step, &product, &input)
+                                .await
+                                .unwrap();
+                        }
+                        product
+                    }
+                    .boxed()
+                })
+                .unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+
+            let expected = values.map(|v| v * v * v * v);
+            assert_eq!(expected.to_vec(), reconstruct(&outputs), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn same_seed_same_schedule() {
+        let run = |seed| {
+            let mut sim = Simulation::new(seed);
+            sim.run([(); 3], |ctx, ()| {
+                async move {
+                    ctx.helper_ring.broadcast(1_u32).await.unwrap();
+                    ctx.helper_ring.receive_from_both::<u32>().await.unwrap()
+                }
+                .boxed()
+            })
+            .unwrap();
+            sim.trace().to_vec()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert!(run(7).contains(&Step::Deliver {
+            from: Identity::H1,
+            to: Identity::H2
+        }));
+    }
+
+    #[test]
+    fn deadlock() {
+        for seed in 0..SEEDS {
+            // everybody waits for the helper on the left to speak first
+            let err = Simulation::new(seed)
+                .run([(); 3], |ctx, ()| {
+                    async move {
+                        let v = ctx.helper_ring.receive::<u32>(HelperAddr::Left).await;
+                        ctx.helper_ring
+                            .send(HelperAddr::Right, 1_u32)
+                            .await
+                            .unwrap();
+                        v.unwrap()
+                    }
+                    .boxed()
+                })
+                .unwrap_err();
+            assert_eq!(
+                Deadlock {
+                    waiting: Identity::ALL.to_vec(),
+                    steps: 3
+                },
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn abort_in_any_order() {
+        for seed in 0..SEEDS {
+            let outputs = Simulation::new(seed)
+                .run([(); 3], |ctx, ()| {
+                    async move {
+                        if ctx.identity == Identity::H2 {
+                            ctx.helper_ring.abort("bad input").await.unwrap();
+                        }
+                        ctx.helper_ring.receive::<u32>(HelperAddr::Left).await
+                    }
+                    .boxed()
+                })
+                .unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+
+            for output in outputs {
+                assert!(
+                    matches!(output, Err(Error::Aborted { ref reason, .. }) if reason == "bad input"),
+                    "seed {seed}: {output:?}"
+                );
+            }
+        }
+    }
+}
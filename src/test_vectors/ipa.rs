@@ -0,0 +1,395 @@
+//!
+//! Vectors of whole IPA queries, small enough to follow by hand. Every vector has the records
+//! in the clear, the shares of them each of the three helpers gets, what every stage of the
+//! query computes from them and the histogram the helpers reveal at the end. An implementation
+//! that gets the histogram wrong can tell from the stages where it went wrong.
+//!
+//! Stages are checked by the values their shares reconstruct to, which do not depend on the
+//! randomness helpers use: an implementation with a different PRSS or multiplication protocol
+//! computes the same ones. Values are in [`Fp31`](crate::field::Fp31), and every stage is recorded as the SHA-256
+//! [`digest`] of its values, encoded by [`write_fields`] in the order [`Stages`] lists them in.
+//!
+//! Records are conversions that are already joined with the source events they are attributed
+//! to, the same as `ipa_local` generates them. Their match keys go through [`crate::ingest`],
+//! but matching them is not part of the query yet. Vectors are JSON files in the `ipa`
+//! directory next to this module, one [`Vector`] each.
+//!
+//! [`VectorQuery`] runs the query of a vector the way this crate does, stage by stage, which is
+//! what [`crate::conformance`] checks other implementations against.
+//!
+use crate::attribution::{self, Model};
+use crate::error::Res;
+use crate::field::{Field, Fp31};
+use crate::helpers::codec::write_fields;
+use crate::helpers::models::ReplicatedShare;
+use crate::helpers::ring::{Identity, Ring};
+use crate::ingest::Ingest;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::reveal::reveal_vec;
+use crate::securemul::ProtocolContext;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Contents of the files of all vectors, by name.
+pub const VECTORS: &[(&str, &str)] = &[
+    ("last_touch", include_str!("ipa/last_touch.json")),
+    ("equal_credit", include_str!("ipa/equal_credit.json")),
+];
+
+/// Source event a conversion is attributed to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Touch {
+    pub breakdown_key: usize,
+    /// Time between the source event and the conversion, which is public.
+    pub elapsed_seconds: u32,
+}
+
+/// Record in the clear: a conversion, the source events it is attributed to and its match key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Record {
+    /// Match key of 40 bits, see [`crate::ingest::MATCHKEY_BITS`].
+    pub matchkey: u64,
+    pub touches: Vec<Touch>,
+    /// Whether the value counts at all.
+    pub attributed: bool,
+    pub value: u32,
+}
+
+/// Shares of a record that one helper gets. Field values are shared as pairs of their integer
+/// representation, the left and right part of the helper, and the match key as a pair of XOR
+/// parts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SharedRecord {
+    pub matchkey: (u64, u64),
+    /// Breakdown key of every touch, as one bit for every breakdown key that is set only for
+    /// the one of the touch.
+    pub breakdown_keys: Vec<Vec<(u8, u8)>>,
+    /// Same as in the [`Record`], public.
+    pub elapsed_seconds: Vec<u32>,
+    pub attributed: (u8, u8),
+    pub value: (u8, u8),
+}
+
+/// What every stage of the query computes, in the order they run in.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Stages {
+    /// Digest of the bits of the match keys, record by record and least significant bit first,
+    /// as ingest converts them.
+    pub ingest: String,
+    /// Credit of every touch of every record, which helpers compute in the clear.
+    pub credits: Vec<Vec<u32>>,
+    /// Digest of `attributed * value * credit` of every touch, record by record.
+    pub contributions: String,
+    /// Digest of the sum of the contributions of the touches with every breakdown key, before
+    /// it is revealed.
+    pub histogram: String,
+}
+
+/// A query, its input and everything it computes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Vector {
+    pub description: String,
+    pub attribution: Model,
+    /// Breakdown keys are below this.
+    pub breakdown_keys: usize,
+    pub records: Vec<Record>,
+    /// Shares of the records, for each of the three helpers in turn.
+    pub shares: [Vec<SharedRecord>; 3],
+    pub stages: Stages,
+    /// Revealed histogram, one value for every breakdown key.
+    pub histogram: Vec<u8>,
+}
+
+impl Vector {
+    /// Parses all vectors in [`VECTORS`].
+    ///
+    /// ## Panics
+    /// If one of them does not parse, which the tests of this module make sure it does.
+    #[must_use]
+    pub fn all() -> Vec<(&'static str, Self)> {
+        VECTORS
+            .iter()
+            .map(|&(name, json)| (name, serde_json::from_str(json).unwrap()))
+            .collect()
+    }
+}
+
+/// Share of a value of a stage that one helper holds.
+pub type Share = ReplicatedSecretSharing<Fp31>;
+
+/// Share of a field value of a [`SharedRecord`].
+#[must_use]
+pub fn share((left, right): (u8, u8)) -> Share {
+    Share::new(Fp31::from(left), Fp31::from(right))
+}
+
+/// Query of a vector as a single helper runs it, one stage at a time. All three helpers must
+/// run the same stages in the same order, as every stage draws PRSS indices from where the one
+/// before it stopped, starting at 1.
+#[derive(Debug)]
+pub struct VectorQuery<'a> {
+    vector: &'a Vector,
+    records: &'a [SharedRecord],
+    next_index: u128,
+    contributions: Vec<Share>,
+    histogram: Vec<Share>,
+}
+
+impl<'a> VectorQuery<'a> {
+    /// Query of `vector` on the shares that `identity` gets.
+    #[must_use]
+    pub fn new(vector: &'a Vector, identity: Identity) -> Self {
+        let helper = match identity {
+            Identity::H1 => 0,
+            Identity::H2 => 1,
+            Identity::H3 => 2,
+        };
+        Self {
+            vector,
+            records: &vector.shares[helper],
+            next_index: 1,
+            contributions: Vec::new(),
+            histogram: Vec::new(),
+        }
+    }
+
+    /// Converts the match keys to bits, and returns them record by record.
+    ///
+    /// ## Errors
+    /// If communication with peers fails.
+    pub async fn ingest<R: Ring>(&mut self, ctx: &ProtocolContext<'_, R>) -> Res<Vec<Share>> {
+        let mut ingest = Ingest::new(ctx.identity);
+        for r in self.records {
+            ingest.push([ReplicatedShare(r.matchkey.0, r.matchkey.1)])?;
+        }
+        let columns = ingest.finish(ctx, &mut self.next_index).await?.columns;
+        Ok((0..columns.rows()).flat_map(|r| columns.row(r)).collect())
+    }
+
+    /// Computes the contribution of every touch, record by record.
+    ///
+    /// ## Errors
+    /// If communication with peers fails.
+    pub async fn contributions<R: Ring>(
+        &mut self,
+        ctx: &ProtocolContext<'_, R>,
+    ) -> Res<Vec<Share>> {
+        let attributed = self
+            .records
+            .iter()
+            .map(|r| share(r.attributed))
+            .collect::<Vec<_>>();
+        let values = self
+            .records
+            .iter()
+            .map(|r| share(r.value))
+            .collect::<Vec<_>>();
+        let products = ctx
+            .multiply_batch(self.next_index, &attributed, &values)
+            .await?;
+        self.next_index += self.records.len() as u128;
+        let mut contributions = Vec::new();
+        for (r, product) in self.records.iter().zip(products) {
+            let credits = self.vector.attribution.credits(&r.elapsed_seconds);
+            contributions.extend(attribution::weigh(&vec![product; credits.len()], &credits));
+        }
+        self.contributions.clone_from(&contributions);
+        Ok(contributions)
+    }
+
+    /// Sums the contributions of the touches with every breakdown key. Runs after
+    /// [`Self::contributions`].
+    ///
+    /// ## Errors
+    /// If communication with peers fails.
+    pub async fn histogram<R: Ring>(&mut self, ctx: &ProtocolContext<'_, R>) -> Res<Vec<Share>> {
+        let mut histogram = Vec::with_capacity(self.vector.breakdown_keys);
+        for key in 0..self.vector.breakdown_keys {
+            let bits = self
+                .records
+                .iter()
+                .flat_map(|r| &r.breakdown_keys)
+                .map(|bits| share(bits[key]))
+                .collect::<Vec<_>>();
+            histogram.push(
+                ctx.sum_of_products(self.next_index, &bits, &self.contributions)
+                    .await?,
+            );
+            self.next_index += 1;
+        }
+        self.histogram.clone_from(&histogram);
+        Ok(histogram)
+    }
+
+    /// Reveals the histogram. Runs after [`Self::histogram`].
+    ///
+    /// ## Errors
+    /// If communication with peers fails, or they do not agree on the histogram.
+    pub async fn reveal<R: Ring>(&mut self, ctx: &ProtocolContext<'_, R>) -> Res<Vec<Fp31>> {
+        reveal_vec(ctx, self.next_index, &self.histogram).await
+    }
+}
+
+/// Digest of `values`, in hex, as it is recorded for stages.
+#[must_use]
+pub fn digest<F: Field>(values: &[F]) -> String {
+    let mut bytes = Vec::new();
+    write_fields(values, &mut bytes);
+    Sha256::digest(&bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, b| {
+            write!(hex, "{b:02x}").unwrap();
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Res;
+    use crate::field::{Field, Fp31};
+    use crate::helpers::models::ReplicatedShare;
+    use crate::helpers::ring::Ring;
+    use crate::ingest::MATCHKEY_BITS;
+    use crate::securemul::ProtocolContext;
+    use crate::test_fixture::{reconstruct, TestWorld};
+    use crate::test_vectors::ipa::{
+        digest, share, Share, SharedRecord, Stages, Vector, VectorQuery, VECTORS,
+    };
+    use futures::future::FutureExt;
+
+    /// Shares of every stage that one helper ends up with.
+    struct Outputs {
+        bits: Vec<Share>,
+        contributions: Vec<Share>,
+        histogram: Vec<Share>,
+        revealed: Vec<Fp31>,
+    }
+
+    /// Runs the query of `vector` on the shares of one helper.
+    async fn query<R: Ring>(ctx: &ProtocolContext<'_, R>, vector: &Vector) -> Res<Outputs> {
+        let mut query = VectorQuery::new(vector, ctx.identity);
+        Ok(Outputs {
+            bits: query.ingest(ctx).await?,
+            contributions: query.contributions(ctx).await?,
+            histogram: query.histogram(ctx).await?,
+            revealed: query.reveal(ctx).await?,
+        })
+    }
+
+    /// Stages and the histogram of `vector`, computed in the clear from its records.
+    fn expected(vector: &Vector) -> (Stages, Vec<u8>) {
+        let records = &vector.records;
+        let bits = records
+            .iter()
+            .flat_map(|r| {
+                (0..MATCHKEY_BITS).map(move |b| Fp31::from(u128::from((r.matchkey >> b) & 1)))
+            })
+            .collect::<Vec<_>>();
+        let credits = records
+            .iter()
+            .map(|r| {
+                let elapsed = r
+                    .touches
+                    .iter()
+                    .map(|t| t.elapsed_seconds)
+                    .collect::<Vec<_>>();
+                vector.attribution.credits(&elapsed)
+            })
+            .collect::<Vec<_>>();
+        let mut contributions = Vec::new();
+        let mut histogram = vec![Fp31::ZERO; vector.breakdown_keys];
+        for (r, credits) in records.iter().zip(&credits) {
+            for (touch, credit) in r.touches.iter().zip(credits) {
+                let c = Fp31::from(u128::from(r.attributed) * u128::from(r.value * credit));
+                contributions.push(c);
+                histogram[touch.breakdown_key] += c;
+            }
+        }
+        let stages = Stages {
+            ingest: digest(&bits),
+            credits,
+            contributions: digest(&contributions),
+            histogram: digest(&histogram),
+        };
+        (stages, histogram.into_iter().map(u8::from).collect())
+    }
+
+    #[test]
+    fn shares_reconstruct_to_records() {
+        for (name, vector) in Vector::all() {
+            assert_eq!(vector.records.len(), vector.shares[0].len(), "{name}");
+            for (j, record) in vector.records.iter().enumerate() {
+                let shares = [0, 1, 2].map(|i| &vector.shares[i][j]);
+                let open = |f: fn(&SharedRecord) -> (u8, u8)| {
+                    Share::reconstruct(&shares.map(|s| share(f(s)))).unwrap()
+                };
+                let matchkey = shares.map(|s| ReplicatedShare(s.matchkey.0, s.matchkey.1));
+                assert_eq!(
+                    record.matchkey,
+                    ReplicatedShare::reconstruct(&matchkey).unwrap()
+                );
+                assert_eq!(
+                    Fp31::from(u128::from(record.attributed)),
+                    open(|s| s.attributed)
+                );
+                assert_eq!(Fp31::from(u128::from(record.value)), open(|s| s.value));
+                for (t, touch) in record.touches.iter().enumerate() {
+                    for key in 0..vector.breakdown_keys {
+                        let bit =
+                            Share::reconstruct(&shares.map(|s| share(s.breakdown_keys[t][key])))
+                                .unwrap();
+                        assert_eq!(Fp31::from(u128::from(key == touch.breakdown_key)), bit);
+                    }
+                    assert!(shares
+                        .iter()
+                        .all(|s| s.elapsed_seconds[t] == touch.elapsed_seconds));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stages_in_the_clear() {
+        for (name, vector) in Vector::all() {
+            let (stages, histogram) = expected(&vector);
+            assert_eq!(vector.stages, stages, "{name}");
+            assert_eq!(vector.histogram, histogram, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn stages_of_the_query() {
+        assert_eq!(VECTORS.len(), Vector::all().len());
+        for (name, vector) in Vector::all() {
+            let world = TestWorld::new();
+            let [c0, c1, c2] = world.contexts();
+            let (o0, o1, o2) = futures::try_join!(
+                query(&c0, &vector).boxed(),
+                query(&c1, &vector).boxed(),
+                query(&c2, &vector).boxed(),
+            )
+            .unwrap();
+            let outputs = [o0, o1, o2];
+            let open = |f: fn(&Outputs) -> &Vec<Share>| {
+                digest(&reconstruct(&[0, 1, 2].map(|i| f(&outputs[i]).clone())))
+            };
+
+            assert_eq!(vector.stages.ingest, open(|o| &o.bits), "{name}");
+            assert_eq!(
+                vector.stages.contributions,
+                open(|o| &o.contributions),
+                "{name}"
+            );
+            assert_eq!(vector.stages.histogram, open(|o| &o.histogram), "{name}");
+            let histogram = vector
+                .histogram
+                .iter()
+                .map(|&v| Fp31::from(v))
+                .collect::<Vec<_>>();
+            for o in &outputs {
+                assert_eq!(histogram, o.revealed, "{name}");
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//!
+//! Fixed inputs and the bytes this crate encodes them to. Helpers and clients written
+//! independently of this crate can check that they produce and accept exactly the same bytes.
+//! Every encoding is canonical: integers are little-endian, field values are always reduced
+//! and take the same number of bytes, and a decoder rejects anything this crate would not
+//! produce.
+//!
+//! Encodings are described next to the code that produces them:
+//! [`write_fields`](crate::helpers::codec::write_fields) for field values,
+//! [`write_shares`](crate::helpers::codec::write_shares) for replicated shares and
+//! [`EncryptedMatchkeys::to_bytes`](crate::report::EncryptedMatchkeys::to_bytes) for encrypted
+//! match keys. Bytes are listed in hex.
+//!
+//! Vectors of whole queries, from the records to the histogram, are in [`ipa`].
+//!
+#[cfg(feature = "enable-serde")]
+pub mod ipa;
+
+/// `Fp31` values and their encoding.
+pub const FP31: &[(u8, &str)] = &[(0, "00"), (1, "01"), (30, "1e")];
+
+/// Replicated shares of `Fp31` values, as the pair of values held by a helper, and their
+/// encoding.
+pub const REPLICATED_FP31: &[((u8, u8), &str)] = &[((3, 29), "031d"), ((30, 0), "1e00")];
+
+/// Match keys of a provider and the points they map to, compressed.
+pub const MATCHKEY_POINTS: &[(&str, &str, &str)] = &[
+    (
+        "a.example",
+        "12345678",
+        "9847aa2d2daead246c1dd4020a2e054c1b34eacf00e3ac1cb080592d858a525c",
+    ),
+    (
+        "b.example",
+        "",
+        "5cd01a61a1515645f77aa2c1b113a37cf21508a64c3221ba16a1724223e0a45b",
+    ),
+];
+
+/// Secret key whose public key `x * G` encrypts [`ENCRYPTED_MATCHKEYS`].
+pub const MATCHKEY_SECRET: u64 = 0x1234_5678;
+
+/// Encrypted match keys, as pairs of the provider and the match key, and their encoding. Match
+/// keys are mapped to points as in [`MATCHKEY_POINTS`] and encrypted under [`MATCHKEY_SECRET`],
+/// each with randomness `r = i + 1` where `i` is its position in the list.
+pub const ENCRYPTED_MATCHKEYS: &[(&[(&str, &str)], &str)] = &[
+    (&[], "00"),
+    (&[("a.example", "12345678"), ("b.example", "")], "0209612e6578616d706c65e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76f8836416b4a37f8003ca90cf3ca06edc0f8d1a8732a282afe3e39ab15d47617109622e6578616d706c656a493210f7499cd17fecb510ae0cea23a110e8d5b901f8acadd3095c73a3b9192e2249d439ecb9b246aebf12065e4d1d4e5aa0e01670c13b60631e9d53b3e467"),
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::field::Fp31;
+    use crate::helpers::codec::{read_fields, read_shares, write_fields, write_shares};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::report::{EncryptedMatchkeys, Error};
+    use crate::test_vectors::{
+        ENCRYPTED_MATCHKEYS, FP31, MATCHKEY_POINTS, MATCHKEY_SECRET, REPLICATED_FP31,
+    };
+    use crate::threshold::{DKey, EKey, RistrettoPoint};
+    use crate::user::User;
+    use rust_elgamal::Scalar;
+    use std::collections::HashMap;
+
+    fn point(provider: &str, mk: &str) -> RistrettoPoint {
+        User::point_from_matchkey(provider, mk.as_bytes())
+    }
+
+    #[test]
+    fn fields() {
+        for &(v, encoded) in FP31 {
+            let mut bytes = Vec::new();
+            write_fields(&[Fp31::from(v)], &mut bytes);
+            assert_eq!(encoded, hex::encode(&bytes));
+            assert_eq!(vec![Fp31::from(v)], read_fields::<Fp31>(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn shares() {
+        for &((a, b), encoded) in REPLICATED_FP31 {
+            let share = ReplicatedSecretSharing::new(Fp31::from(a), Fp31::from(b));
+            let mut bytes = Vec::new();
+            write_shares(&[share], &mut bytes);
+            assert_eq!(encoded, hex::encode(&bytes));
+            assert_eq!(vec![share], read_shares::<Fp31>(&bytes).unwrap());
+        }
+        assert!(read_shares::<Fp31>(&[1]).is_err());
+    }
+
+    #[test]
+    fn matchkey_points() {
+        for &(provider, mk, encoded) in MATCHKEY_POINTS {
+            let p = point(provider, mk);
+            assert_eq!(encoded, hex::encode(p.compress().as_bytes()));
+        }
+    }
+
+    #[test]
+    fn encrypted_matchkeys() {
+        let ek = EKey::from(DKey::from(Scalar::from(MATCHKEY_SECRET)));
+        for &(mks, encoded) in ENCRYPTED_MATCHKEYS {
+            let match_keys = mks
+                .iter()
+                .zip(1_u64..)
+                .map(|(&(provider, mk), r)| {
+                    let c = ek.encrypt_with(point(provider, mk), Scalar::from(r));
+                    (provider.to_owned(), c)
+                })
+                .collect::<HashMap<_, _>>();
+            let bytes = EncryptedMatchkeys::from(match_keys).to_bytes().unwrap();
+            assert_eq!(encoded, hex::encode(&bytes));
+
+            let decoded = EncryptedMatchkeys::from_bytes(&bytes).unwrap();
+            assert_eq!(bytes, decoded.to_bytes().unwrap());
+        }
+    }
+
+    #[test]
+    fn non_canonical_matchkeys() {
+        let (_, encoded) = ENCRYPTED_MATCHKEYS[1];
+        let bytes = hex::decode(encoded).unwrap();
+
+        // providers out of order
+        let entry = 1 + 9 + 64;
+        let mut swapped = vec![2];
+        swapped.extend_from_slice(&bytes[1 + entry..]);
+        swapped.extend_from_slice(&bytes[1..=entry]);
+        assert_eq!(
+            Err(Error::NotCanonical(1)),
+            EncryptedMatchkeys::from_bytes(&swapped).map(|_| ())
+        );
+
+        // point that is not reduced
+        let mut unreduced = bytes.clone();
+        unreduced[1 + 10..1 + 10 + 32].fill(0xff);
+        assert_eq!(
+            Err(Error::BadCiphertext(0)),
+            EncryptedMatchkeys::from_bytes(&unreduced).map(|_| ())
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Err(Error::TrailingBytes(1)),
+            EncryptedMatchkeys::from_bytes(&trailing).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Truncated(1)),
+            EncryptedMatchkeys::from_bytes(&bytes[..bytes.len() - 1]).map(|_| ())
+        );
+    }
+}
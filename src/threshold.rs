@@ -1,12 +1,59 @@
 #[cfg(feature = "debug")]
 use hex::encode as hex;
 use rand_core::{CryptoRng, RngCore};
+use rust_elgamal::Scalar;
 pub use rust_elgamal::{Ciphertext, DecryptionKey as DKey, EncryptionKey as EKey, RistrettoPoint};
 #[cfg(feature = "enable-serde")]
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha512};
 #[cfg(feature = "debug")]
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
+use std::sync::atomic::{compiler_fence, Ordering};
+use zeroize::Zeroize;
+
+/// Domain separation tag for match keys, following the format suggested by RFC 9380.
+pub const MATCHKEY_DST: &[u8] = b"IPA-V00-CS01-ristretto255_XMD:SHA-512_R255MAP_RO_";
+
+/// Maps `msg` to a Ristretto point with the `ristretto255_XMD:SHA-512_R255MAP_RO_` suite of
+/// RFC 9380, using [`MATCHKEY_DST`]. Clients and helpers that map the same bytes get the same
+/// point, and nobody knows the discrete log of it.
+#[must_use]
+pub fn hash_to_ristretto(msg: &[u8]) -> RistrettoPoint {
+    let mut uniform = [0; 64];
+    uniform.copy_from_slice(&expand_message_xmd(msg, MATCHKEY_DST, 64));
+    RistrettoPoint::from_uniform_bytes(&uniform)
+}
+
+/// `expand_message_xmd` of RFC 9380, with SHA-512. Only a single block of output is needed here,
+/// so that is all this supports.
+///
+/// ## Panics
+/// If `dst` is longer than 255 bytes or more than 64 bytes of output are requested.
+fn expand_message_xmd(msg: &[u8], dst: &[u8], len: usize) -> Vec<u8> {
+    const R_IN_BYTES: usize = 128;
+
+    assert!(len <= 64, "at most one block of output");
+    let dst_len = u8::try_from(dst.len()).expect("domain separation tag is at most 255 bytes");
+    let len_bytes = u16::try_from(len).unwrap().to_be_bytes();
+
+    let b_0 = Sha512::new()
+        .chain([0; R_IN_BYTES])
+        .chain(msg)
+        .chain(len_bytes)
+        .chain([0])
+        .chain(dst)
+        .chain([dst_len])
+        .finalize();
+
+    let b_1 = Sha512::new()
+        .chain(b_0)
+        .chain([1])
+        .chain(dst)
+        .chain([dst_len])
+        .finalize();
+    b_1[..len].to_vec()
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
@@ -38,8 +85,9 @@ impl Debug for EncryptionKey {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+/// Key of a helper to decrypt match keys with. It is erased from memory when dropped, so it is
+/// not `Copy`.
+#[derive(Clone, PartialEq, Eq)]
 pub struct DecryptionKey(DKey);
 
 impl DecryptionKey {
@@ -72,17 +120,92 @@ impl From<DKey> for DecryptionKey {
     }
 }
 
+/// Only the secret scalar is written. `rust_elgamal` serializes its key as a struct but can only
+/// read it back from a sequence, so its own implementation does not survive a round trip through
+/// JSON.
+#[cfg(feature = "enable-serde")]
+impl Serialize for DecryptionKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.as_ref().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "enable-serde")]
+impl<'de> Deserialize<'de> for DecryptionKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut secret = Scalar::deserialize(deserializer)?;
+        let key = Self(DKey::from(secret));
+        secret.zeroize();
+        Ok(key)
+    }
+}
+
+impl Drop for DecryptionKey {
+    fn drop(&mut self) {
+        // `rust_elgamal` has no way to erase the secret in place, so the whole key is overwritten
+        // with a zero one, the same way `zeroize` does it
+        // SAFETY: the pointer comes from a reference, and the key has no drop glue to skip
+        unsafe { std::ptr::write_volatile(&mut self.0, DKey::from(Scalar::zero())) };
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Shows the encryption key only, never the secret.
 #[cfg(feature = "debug")]
 impl Debug for DecryptionKey {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        f.write_str("DecryptionKey ")?;
-        f.write_str(&hex(self.0.as_ref().to_bytes()))
+        f.write_str("DecryptionKey for ")?;
+        f.write_str(&hex(self.encryption_key().as_ref().compress().as_bytes()))
+    }
+}
+
+/// Secret exponent that a helper applies to encrypted match keys. Once every helper has applied
+/// its own, decryption produces the match key multiplied by all of them instead of the match key
+/// itself. Equal match keys still decrypt to equal points, so they can be compared, but nobody
+/// learns the points the match keys map to.
+///
+/// Blinded points of different queries can be linked if the same key is used for both, so every
+/// query needs a fresh one. The key is erased from memory when dropped.
+#[derive(Clone)]
+pub struct BlindingKey(Scalar);
+
+impl BlindingKey {
+    #[must_use]
+    pub fn new<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Self(Scalar::random(rng))
+    }
+
+    /// Blinds the encrypted point. The result is a valid encryption of the blinded point under
+    /// the same key, so blinding and decryption can be done by helpers in any order.
+    #[must_use]
+    pub fn blind(&self, c: Ciphertext) -> Ciphertext {
+        let (c0, c1) = c.inner();
+        Ciphertext::from((c0 * self.0, c1 * self.0))
+    }
+
+    /// Same as [`Self::blind`] for every ciphertext in `cs`, in order.
+    #[must_use]
+    pub fn blind_batch(&self, cs: &[Ciphertext]) -> Vec<Ciphertext> {
+        cs.iter().map(|c| self.blind(*c)).collect()
+    }
+}
+
+impl Drop for BlindingKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(feature = "debug")]
+impl Debug for BlindingKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("BlindingKey { .. }")
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DecryptionKey, EncryptionKey};
+    use super::{expand_message_xmd, hash_to_ristretto, DecryptionKey, EncryptionKey};
     use hex::encode as hex;
     use rand::thread_rng;
     use rust_elgamal::{Ciphertext, RistrettoPoint, Scalar};
@@ -149,4 +272,43 @@ mod tests {
         dump_p("out", &m_out);
         assert_eq!(m.compress(), m_out.compress());
     }
+
+    #[cfg(feature = "enable-serde")]
+    #[test]
+    fn serde_decryption_key() {
+        let k = DecryptionKey::new(&mut thread_rng());
+        let json = serde_json::to_string(&k).unwrap();
+        assert_eq!(k, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn expand_message() {
+        // RFC 9380, appendix K.3
+        const DST: &[u8] = b"QUUX-V01-CS02-with-expander-SHA512-256";
+        assert_eq!(
+            "6b9a7312411d92f921c6f68ca0b6380730a1a4d982c507211a90964c394179ba",
+            hex(expand_message_xmd(b"", DST, 0x20))
+        );
+        assert_eq!(
+            "0da749f12fbe5483eb066a5f595055679b976e93abe9be6f0f6318bce7aca8dc",
+            hex(expand_message_xmd(b"abc", DST, 0x20))
+        );
+    }
+
+    #[test]
+    fn hash_to_ristretto_vectors() {
+        // these pin the mapping, so that other implementations can check theirs against it
+        for (msg, point) in [
+            (
+                &b""[..],
+                "007d075347ea50569eb6fd697da805e2d098e1627fcf79e0b092778ee0642367",
+            ),
+            (
+                b"12345678",
+                "fe8a61c3aea722dc069b9d364c53aae9616b0dd81adb09b796e003c7ea499641",
+            ),
+        ] {
+            assert_eq!(point, hex(hash_to_ristretto(msg).compress().as_bytes()));
+        }
+    }
 }
@@ -0,0 +1,168 @@
+//!
+//! Selection of the largest aggregates of a query. Queries that only want the `K` biggest
+//! breakdown buckets get those and nothing else: aggregates are sorted obliviously together with
+//! their bucket numbers, and only the first `K` rows are revealed. Helpers learn neither the
+//! values nor the positions of the other buckets.
+//!
+//! Which buckets make it to the top is revealed as well, so differential privacy noise must be
+//! added to the aggregates before they are selected, not to the revealed values.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::ring::Ring;
+use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+use crate::reveal::reveal_vec;
+use crate::securemul::ProtocolContext;
+use crate::sorting_network::sort;
+use std::future::Future;
+use thiserror::Error;
+
+const BUCKET: usize = 0;
+const VALUE: usize = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot number {buckets} buckets in a field of {prime} elements")]
+    TooManyBuckets { buckets: usize, prime: u128 },
+}
+
+/// Reveals the `k` largest of `aggregates`, largest first, as pairs of the bucket number, which
+/// is the position in `aggregates`, and its value. Returns all buckets if there are fewer than
+/// `k`.
+///
+/// `compare` gets pairs of aggregates and must return, for every pair, a share of one if the
+/// first aggregate is smaller than the second one and a share of zero otherwise. It is used the
+/// same way as the comparator of [`sort`], including how it reserves indices from `next_index`.
+///
+/// ## Errors
+/// If there are more buckets than the field has elements, or any step of the sort or reveal
+/// fails.
+///
+/// ## Panics
+/// If a revealed bucket number does not fit in `usize`, which only a field larger than the
+/// address space could produce.
+pub async fn top_k<F, R, C, Fut>(
+    ctx: &ProtocolContext<'_, R>,
+    next_index: &mut u128,
+    aggregates: &[ReplicatedSecretSharing<F>],
+    k: usize,
+    mut compare: C,
+) -> Res<Vec<(usize, F)>>
+where
+    F: Field,
+    R: Ring,
+    C: FnMut(&mut u128, Vec<(ReplicatedSecretSharing<F>, ReplicatedSecretSharing<F>)>) -> Fut,
+    Fut: Future<Output = Res<Vec<ReplicatedSecretSharing<F>>>>,
+{
+    let prime: u128 = F::PRIME.into();
+    if aggregates.len() as u128 > prime {
+        return Err(Error::TooManyBuckets {
+            buckets: aggregates.len(),
+            prime,
+        }
+        .into());
+    }
+
+    let mut rows = aggregates
+        .iter()
+        .enumerate()
+        .map(|(bucket, value)| vec![ctx.share_known(F::from(bucket as u128)), *value])
+        .collect::<Vec<_>>();
+    sort(ctx, next_index, &mut rows, |next, pairs| {
+        let values = pairs
+            .into_iter()
+            .map(|(a, b)| (a[VALUE], b[VALUE]))
+            .collect();
+        compare(next, values)
+    })
+    .await?;
+
+    let selected = rows.iter().take(k).flatten().copied().collect::<Vec<_>>();
+    let revealed = reveal_vec(ctx, *next_index, &selected).await?;
+    Ok(revealed
+        .chunks(2)
+        .map(|row| {
+            let bucket: <F as Field>::Integer = row[BUCKET].into();
+            let bucket: u128 = bucket.into();
+            (usize::try_from(bucket).unwrap(), row[VALUE])
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Res;
+    use crate::field::Fp31;
+    use crate::helpers::ring::mock::TestHelper;
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::reveal::reveal_vec;
+    use crate::securemul::ProtocolContext;
+    use crate::test_fixture::TestWorld;
+    use crate::top_k::top_k;
+    use rand::rngs::mock::StepRng;
+
+    type Share = ReplicatedSecretSharing<Fp31>;
+
+    /// Compares aggregates by revealing them, which is only good enough to test the selection.
+    async fn compare_in_the_clear(
+        ctx: &ProtocolContext<'_, TestHelper>,
+        index: u128,
+        pairs: Vec<(Share, Share)>,
+    ) -> Res<Vec<Share>> {
+        let (mut values, b): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        values.extend(b);
+        let values = reveal_vec(ctx, index, &values).await?;
+        let (a, b) = values.split_at(values.len() / 2);
+        Ok(a.iter()
+            .zip(b)
+            .map(|(a, b)| {
+                let less = u8::from(*a) < u8::from(*b);
+                ctx.share_known(Fp31::from(u128::from(less)))
+            })
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn largest_buckets() {
+        let world = TestWorld::new();
+        let ctx = world.contexts();
+
+        let mut rand = StepRng::new(1, 5);
+        let mut aggregates = [Vec::new(), Vec::new(), Vec::new()];
+        for v in [5_u128, 20, 3, 11, 28, 9] {
+            let shares = ReplicatedSecretSharing::share(Fp31::from(v), &mut rand);
+            for (helper, share) in aggregates.iter_mut().zip(shares) {
+                helper.push(share);
+            }
+        }
+
+        let compare = |ctx| {
+            move |next: &mut u128, pairs: Vec<(Share, Share)>| {
+                let index = *next;
+                *next += 1;
+                compare_in_the_clear(ctx, index, pairs)
+            }
+        };
+        let mut next_index = [1; 3];
+        let [n0, n1, n2] = &mut next_index;
+        let (t0, t1, t2) = futures::try_join!(
+            top_k(&ctx[0], n0, &aggregates[0], 3, compare(&ctx[0])),
+            top_k(&ctx[1], n1, &aggregates[1], 3, compare(&ctx[1])),
+            top_k(&ctx[2], n2, &aggregates[2], 3, compare(&ctx[2])),
+        )
+        .unwrap();
+
+        let expected = [(4, 28_u128), (1, 20), (3, 11)].map(|(b, v)| (b, Fp31::from(v)));
+        assert_eq!(expected.to_vec(), t0);
+        assert_eq!((&t0, &t0), (&t1, &t2));
+
+        // fewer buckets than requested
+        let (t0, _, _) = futures::try_join!(
+            top_k(&ctx[0], n0, &aggregates[0][..2], 3, compare(&ctx[0])),
+            top_k(&ctx[1], n1, &aggregates[1][..2], 3, compare(&ctx[1])),
+            top_k(&ctx[2], n2, &aggregates[2][..2], 3, compare(&ctx[2])),
+        )
+        .unwrap();
+        assert_eq!(vec![(1, Fp31::from(20_u128)), (0, Fp31::from(5_u128))], t0);
+    }
+}
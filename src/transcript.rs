@@ -0,0 +1,415 @@
+//!
+//! Transcripts of how a helper executed a query, to tell whether all three executed it the same
+//! way. A helper that skips or repeats a step, draws PRSS randomness for other records than its
+//! peers, or whose messages are changed on the way, can still end up with aggregates that
+//! reconstruct, and nothing in the output would show it. Bugs like that, or tampering, show up in
+//! the transcripts instead.
+//!
+//! A [`Transcript`] hashes every PRSS index a step draws randomness for, by step, and, when the
+//! ring of the helper is wrapped in a [`TranscriptRing`], every message the helper sends and
+//! receives, by peer and message name. Hashes are sums of the SHA-256 digests of every item, so
+//! they do not depend on the order in which concurrent steps got to them, which differs between
+//! helpers.
+//!
+//! Once the query is done, [`check_transcript`] exchanges transcripts with both peers in a single
+//! round. Every helper draws randomness for the same indices of the same steps, so PRSS hashes
+//! must be the same on all three, and what a helper sent to a peer must be what the peer
+//! received from it. [`Transcript::digest`] sums up the transcript in a single value, which can
+//! be kept with the results of the query for audits later on.
+//!
+use crate::error::Res;
+use crate::field::Field;
+use crate::helpers::buffer::Pending;
+use crate::helpers::codec::{write_fields, Bincode, Codec};
+use crate::helpers::error::Error as HelperError;
+use crate::helpers::ring::{message, FieldValues, HelperAddr, Message, Ring};
+use crate::securemul::ProtocolContext;
+use crate::step;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::digest::Output;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use thiserror::Error;
+
+const STEP: &str = step::TRANSCRIPT_CHECK;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("helper on the {peer:?} drew PRSS randomness for other records of step {step}")]
+    PrssMismatch { peer: HelperAddr, step: String },
+    #[error("helper on the {peer:?} sent other {name} messages than this helper received")]
+    MessageMismatch { peer: HelperAddr, name: String },
+}
+
+/// Hash of a set of items that does not depend on the order they were added in: the sum of
+/// their SHA-256 digests, taken as 256-bit integers, and how many there were.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetHash {
+    count: u64,
+    sum: [u64; 4],
+}
+
+impl SetHash {
+    fn add(&mut self, item: &[u8]) {
+        let digest = Sha256::digest(item);
+        let mut carry = false;
+        for (word, bytes) in self.sum.iter_mut().zip(digest.chunks_exact(8)) {
+            let (sum, c1) = word.overflowing_add(u64::from_le_bytes(bytes.try_into().unwrap()));
+            let (sum, c2) = sum.overflowing_add(u64::from(carry));
+            *word = sum;
+            carry = c1 || c2;
+        }
+        self.count += 1;
+    }
+}
+
+type Hashes = BTreeMap<String, SetHash>;
+
+/// Running hashes of what a helper did in a query.
+#[derive(Debug, Default)]
+pub struct Transcript {
+    /// PRSS indices, by step.
+    prss: Mutex<Hashes>,
+    /// Messages sent to the peer on the left and on the right, by name.
+    sent: [Mutex<Hashes>; 2],
+    /// Messages received from the peer on the left and on the right, by name.
+    received: [Mutex<Hashes>; 2],
+}
+
+fn index(peer: HelperAddr) -> usize {
+    match peer {
+        HelperAddr::Left => 0,
+        HelperAddr::Right => 1,
+    }
+}
+
+fn add(hashes: &Mutex<Hashes>, key: &str, item: &[u8]) {
+    let mut hashes = hashes.lock().unwrap();
+    match hashes.get_mut(key) {
+        Some(hash) => hash.add(item),
+        None => hashes.entry(key.to_owned()).or_default().add(item),
+    }
+}
+
+impl Transcript {
+    /// Records that `step` drew PRSS randomness for `index`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn prss(&self, step: &str, index: u128) {
+        add(&self.prss, step, &index.to_le_bytes());
+    }
+
+    /// Records a message called `name` with `payload` sent to `dest`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn sent(&self, dest: HelperAddr, name: &str, payload: &[u8]) {
+        add(&self.sent[index(dest)], name, payload);
+    }
+
+    /// Records a message called `name` with `payload` received from `source`.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    pub fn received(&self, source: HelperAddr, name: &str, payload: &[u8]) {
+        add(&self.received[index(source)], name, payload);
+    }
+
+    /// Digest of everything in the transcript.
+    ///
+    /// ## Panics
+    /// Panics if Mutex used internally for synchronization is poisoned.
+    #[must_use]
+    pub fn digest(&self) -> Output<Sha256> {
+        let mut digest = Sha256::new();
+        let maps = [&self.prss, &self.sent[0], &self.sent[1]];
+        for hashes in maps.into_iter().chain(&self.received) {
+            let hashes = hashes.lock().unwrap();
+            digest.update((hashes.len() as u64).to_le_bytes());
+            for (key, hash) in hashes.iter() {
+                digest.update((key.len() as u64).to_le_bytes());
+                digest.update(key.as_bytes());
+                digest.update(hash.count.to_le_bytes());
+                for word in hash.sum {
+                    digest.update(word.to_le_bytes());
+                }
+            }
+        }
+        digest.finalize()
+    }
+
+    /// What a peer needs to check the transcript of this helper against its own.
+    fn for_peer(&self, peer: HelperAddr) -> PeerTranscript {
+        PeerTranscript {
+            prss: self.prss.lock().unwrap().clone(),
+            sent: self.sent[index(peer)].lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Part of the transcript of a helper that a peer checks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PeerTranscript {
+    prss: Hashes,
+    /// Messages sent to the peer this goes to.
+    sent: Hashes,
+}
+
+message!(PeerTranscript => "transcript.peer");
+
+/// First key whose hash is not the same in `a` and `b`.
+fn first_difference(a: &Hashes, b: &Hashes) -> Option<String> {
+    a.keys()
+        .chain(b.keys())
+        .find(|key| a.get(*key) != b.get(*key))
+        .cloned()
+}
+
+/// Checks the transcript of this helper against those of both peers. All three helpers must call
+/// this once they are done with the query, after every message sent was received.
+///
+/// ## Errors
+/// If communication with peers fails, or either of them drew other PRSS randomness, or sent
+/// other messages than this helper received.
+///
+/// ## Panics
+/// Panics if Mutex used internally for synchronization is poisoned.
+pub async fn check_transcript<R: Ring>(
+    ctx: &ProtocolContext<'_, R>,
+    transcript: &Transcript,
+) -> Res<()> {
+    let [to_left, to_right] =
+        [HelperAddr::Left, HelperAddr::Right].map(|peer| transcript.for_peer(peer));
+    let received = [HelperAddr::Left, HelperAddr::Right]
+        .map(|peer| transcript.received[index(peer)].lock().unwrap().clone());
+    let prss = to_left.prss.clone();
+
+    let _round = ctx.rounds.map(|rounds| rounds.wait(STEP));
+    let ((), (), (left, right)) = futures::try_join!(
+        ctx.helper_ring.send(HelperAddr::Left, to_left),
+        ctx.helper_ring.send(HelperAddr::Right, to_right),
+        ctx.helper_ring.receive_from_both::<PeerTranscript>(),
+    )?;
+
+    for ((peer, theirs), received) in [(HelperAddr::Left, left), (HelperAddr::Right, right)]
+        .into_iter()
+        .zip(received)
+    {
+        if let Some(step) = first_difference(&prss, &theirs.prss) {
+            return Err(Error::PrssMismatch { peer, step }.into());
+        }
+        if let Some(name) = first_difference(&received, &theirs.sent) {
+            return Err(Error::MessageMismatch { peer, name }.into());
+        }
+    }
+    Ok(())
+}
+
+type SharedKey = (HelperAddr, &'static str, Output<Sha256>);
+
+/// Ring that adds every message that goes through the ring `R` to a [`Transcript`]. Messages are
+/// hashed as encoded with [`Bincode`], or with [`write_fields`] for field values.
+#[derive(Debug)]
+pub struct TranscriptRing<'a, R> {
+    inner: &'a R,
+    transcript: Transcript,
+    /// Consumers that got a message received with [`Ring::receive_shared`] so far, by peer,
+    /// message name and digest of the message.
+    shared: Mutex<HashMap<SharedKey, usize>>,
+}
+
+impl<'a, R> TranscriptRing<'a, R> {
+    #[must_use]
+    pub fn new(inner: &'a R) -> Self {
+        Self {
+            inner,
+            transcript: Transcript::default(),
+            shared: Mutex::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    /// Whether the last of `consumers` consumers of the message just got it. Every consumer gets
+    /// the message, but it was sent only once, so only the last one adds it to the transcript.
+    fn last_consumer<T: Message>(
+        &self,
+        source: HelperAddr,
+        payload: &[u8],
+        consumers: usize,
+    ) -> bool {
+        if consumers <= 1 {
+            return true;
+        }
+        let key = (source, T::NAME, Sha256::digest(payload));
+        let mut shared = self.shared.lock().unwrap();
+        let got = shared.entry(key).or_default();
+        *got += 1;
+        if *got < consumers {
+            return false;
+        }
+        shared.remove(&key);
+        true
+    }
+}
+
+fn fields_payload<F: Field>(values: &[F]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_fields(values, &mut payload);
+    payload
+}
+
+#[async_trait]
+impl<R: Ring> Ring for TranscriptRing<'_, R> {
+    async fn send<T: Message>(&self, dest: HelperAddr, msg: T) -> Result<(), HelperError> {
+        let payload =
+            Bincode::encode(&msg).map_err(|inner| HelperError::SendError { dest, inner })?;
+        self.inner.send(dest, msg).await?;
+        self.transcript.sent(dest, T::NAME, &payload);
+        Ok(())
+    }
+
+    async fn receive<T: Message>(&self, source: HelperAddr) -> Result<T, HelperError> {
+        self.receive_shared(source, 1).await
+    }
+
+    async fn receive_shared<T: Message>(
+        &self,
+        source: HelperAddr,
+        consumers: usize,
+    ) -> Result<T, HelperError> {
+        let msg = self.inner.receive_shared::<T>(source, consumers).await?;
+        let payload =
+            Bincode::encode(&msg).map_err(|inner| HelperError::ReceiveError { source, inner })?;
+        if self.last_consumer::<T>(source, &payload, consumers) {
+            self.transcript.received(source, T::NAME, &payload);
+        }
+        Ok(msg)
+    }
+
+    async fn send_fields<F: Field>(
+        &self,
+        dest: HelperAddr,
+        values: &[F],
+    ) -> Result<(), HelperError> {
+        self.inner.send_fields(dest, values).await?;
+        self.transcript
+            .sent(dest, FieldValues::NAME, &fields_payload(values));
+        Ok(())
+    }
+
+    async fn receive_fields<F: Field>(&self, source: HelperAddr) -> Result<Vec<F>, HelperError> {
+        let values = self.inner.receive_fields(source).await?;
+        self.transcript
+            .received(source, FieldValues::NAME, &fields_payload(&values));
+        Ok(values)
+    }
+
+    async fn abort(&self, reason: &str) -> Result<(), HelperError> {
+        self.inner.abort(reason).await
+    }
+
+    fn pending(&self) -> Vec<Pending> {
+        self.inner.pending()
+    }
+
+    async fn close<T: Message>(&self, dest: HelperAddr) -> Result<(), HelperError> {
+        self.inner.close::<T>(dest).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::field::Fp31;
+    use crate::helpers::ring::{FieldValues, HelperAddr, Identity, Message, Ring};
+    use crate::replicated_secret_sharing::ReplicatedSecretSharing;
+    use crate::securemul::ProtocolContext;
+    use crate::step;
+    use crate::test_fixture::malicious::Tamper;
+    use crate::test_fixture::TestWorld;
+    use crate::transcript::{check_transcript, Error as TranscriptError, TranscriptRing};
+    use rand::thread_rng;
+
+    /// Every helper multiplies a few values with themselves and checks its transcript, after
+    /// `before` did whatever else it wants to.
+    async fn multiply_and_check<R, B>(world: &TestWorld<R>, before: B) -> [Result<(), Error>; 3]
+    where
+        R: Ring,
+        B: Fn(&ProtocolContext<'_, TranscriptRing<'_, R>>),
+    {
+        let base = world.contexts();
+        let rings = [0, 1, 2].map(|i| TranscriptRing::new(base[i].helper_ring));
+        let ctx = [0, 1, 2].map(|i| {
+            ProtocolContext::new(base[i].identity, base[i].participant, &rings[i])
+                .with_transcript(rings[i].transcript())
+        });
+        let mut shares = [Vec::new(), Vec::new(), Vec::new()];
+        for v in 1..5_u128 {
+            let parts = ReplicatedSecretSharing::share(Fp31::from(v), &mut thread_rng());
+            for i in 0..3 {
+                shares[i].push(parts[i]);
+            }
+        }
+
+        let run = |i: usize| {
+            let (ctx, shares) = (&ctx[i], &shares[i]);
+            before(ctx);
+            async move {
+                ctx.multiply_batch(1, shares, shares).await?;
+                check_transcript(ctx, ctx.transcript.unwrap()).await
+            }
+        };
+        let (r0, r1, r2) = futures::join!(run(0), run(1), run(2));
+        [r0, r1, r2]
+    }
+
+    #[tokio::test]
+    async fn agree() {
+        let world = TestWorld::new();
+        let results = multiply_and_check(&world, |_| {}).await;
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+    }
+
+    #[tokio::test]
+    async fn tampered_messages() {
+        let world = TestWorld::malicious(Identity::H2, Tamper::Corrupt(HelperAddr::Right));
+        let [r1, _, r3] = multiply_and_check(&world, |_| {}).await;
+        assert!(r1.is_ok(), "{r1:?}");
+        assert!(matches!(
+            r3,
+            Err(Error::Transcript(TranscriptError::MessageMismatch {
+                peer: HelperAddr::Left,
+                ref name,
+            })) if name == FieldValues::NAME
+        ));
+    }
+
+    #[tokio::test]
+    async fn extra_prss() {
+        let world = TestWorld::new();
+        let [r1, r2, r3] = multiply_and_check(&world, |ctx| {
+            if ctx.identity == Identity::H1 {
+                let _ = ctx.bind(step::SECURE_MUL, 100).prss_values();
+            }
+        })
+        .await;
+        // the helper that drew it tells both of its peers apart
+        assert!(matches!(
+            r1,
+            Err(Error::Transcript(TranscriptError::PrssMismatch { ref step, .. })) if step == "securemul"
+        ));
+        for peer in [r2, r3] {
+            assert!(matches!(
+                peer,
+                Err(Error::Transcript(TranscriptError::PrssMismatch { .. }))
+            ));
+        }
+    }
+}
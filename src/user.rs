@@ -1,18 +1,22 @@
-#[cfg(feature = "enable-serde")]
+use crate::entropy::Entropy;
+#[cfg(all(feature = "helper", feature = "enable-serde"))]
 use crate::error::{Error, Res};
 use crate::report::{EncryptedMatchkeys, EventReport};
-use crate::threshold::{Ciphertext, EncryptionKey as ThresholdEncryptionKey, RistrettoPoint};
+use crate::threshold::{
+    hash_to_ristretto, Ciphertext, EncryptionKey as ThresholdEncryptionKey, RistrettoPoint,
+};
 use hkdf::Hkdf;
-use rand::{thread_rng, RngCore};
+use rand::RngCore;
 #[cfg(feature = "enable-serde")]
 use serde::{Deserialize, Serialize};
 use sha2::Sha512;
 use std::collections::HashMap;
-#[cfg(feature = "enable-serde")]
+#[cfg(all(feature = "helper", feature = "enable-serde"))]
 use std::fs;
 #[cfg(feature = "enable-serde")]
 use std::path::{Path, PathBuf};
 use tracing::trace;
+use zeroize::Zeroize;
 
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct User {
@@ -20,6 +24,9 @@ pub struct User {
     threshold_key: ThresholdEncryptionKey,
     encrypted_match_keys: HashMap<String, Ciphertext>,
     fallback_prk: Vec<u8>,
+    /// Randomness for encryption. Users that are loaded get it from the operating system.
+    #[cfg_attr(feature = "enable-serde", serde(skip))]
+    entropy: Entropy,
 }
 
 impl User {
@@ -40,20 +47,35 @@ impl User {
     /// When a file for the given ID already exists.
     #[must_use]
     pub fn new(id: usize, threshold_key: ThresholdEncryptionKey) -> Self {
+        Self::with_entropy(id, threshold_key, Entropy::os())
+    }
+
+    /// Same as [`new`](Self::new), but the fallback secret and all encryption use randomness
+    /// from `entropy`.
+    #[must_use]
+    pub fn with_entropy(
+        id: usize,
+        threshold_key: ThresholdEncryptionKey,
+        entropy: Entropy,
+    ) -> Self {
         let mut ikm = [0; 64];
-        thread_rng().fill_bytes(&mut ikm);
-        let (prk, _) = Hkdf::<Sha512>::extract(None, &ikm);
+        entropy.rng().fill_bytes(&mut ikm);
+        let (mut prk, _) = Hkdf::<Sha512>::extract(None, &ikm);
+        ikm.zeroize();
+        let fallback_prk = prk.to_vec();
+        prk.zeroize();
         Self {
             id,
             threshold_key,
             encrypted_match_keys: HashMap::default(),
-            fallback_prk: prk.to_vec(),
+            fallback_prk,
+            entropy,
         }
     }
 
     /// # Errors
     /// When the file is invalid JSON, or when it contains a bad ID.
-    #[cfg(feature = "enable-serde")]
+    #[cfg(all(feature = "helper", feature = "enable-serde"))]
     pub fn load(dir: &Path, uid: usize) -> Res<Self> {
         let f = Self::filename_for(dir, uid);
         let s = fs::read_to_string(f)?;
@@ -66,27 +88,29 @@ impl User {
 
     /// # Errors
     /// When the file cannot be written.
-    #[cfg(feature = "enable-serde")]
+    #[cfg(all(feature = "helper", feature = "enable-serde"))]
     pub fn save(&self, dir: &Path) -> Res<()> {
         let f = self.filename(dir);
         fs::write(f, serde_json::to_string_pretty(self)?.as_bytes())?;
         Ok(())
     }
 
-    /// This part ultimately needs to be standardized.
-    /// This should do for now in terms of getting a good input.
-    fn point_from_matchkey(provider: &str, mk: &[u8]) -> RistrettoPoint {
+    /// Match keys of different providers never map to the same point, because the provider is
+    /// part of the input to [`hash_to_ristretto`].
+    pub(crate) fn point_from_matchkey(provider: &str, mk: &[u8]) -> RistrettoPoint {
         let mut input = Vec::with_capacity(2 + provider.len() + mk.len());
         input.push(u8::try_from(provider.len()).unwrap());
         input.extend_from_slice(provider.as_bytes());
         input.push(u8::try_from(mk.len()).unwrap());
         input.extend_from_slice(mk);
-        RistrettoPoint::hash_from_bytes::<Sha512>(&input)
+        let point = hash_to_ristretto(&input);
+        input.zeroize();
+        point
     }
 
     pub fn set_matchkey(&mut self, provider: impl AsRef<str>, mk: impl AsRef<str>) {
         let m = Self::point_from_matchkey(provider.as_ref(), mk.as_ref().as_bytes());
-        let emk = self.threshold_key.encrypt(m, &mut thread_rng());
+        let emk = self.threshold_key.encrypt(m, &mut self.entropy.rng());
         trace!(
             "User {}: set matchkey for '{}' to '{:?}'",
             self.id,
@@ -122,7 +146,8 @@ impl User {
             .expand(&info, &mut mk)
             .unwrap(); // length is valid
         let m = Self::point_from_matchkey(provider, &mk);
-        self.threshold_key.encrypt(m, &mut thread_rng())
+        mk.zeroize();
+        self.threshold_key.encrypt(m, &mut self.entropy.rng())
     }
 
     /// Create an encrypted matchkey for the identified provider.
@@ -130,7 +155,7 @@ impl User {
     /// If the provider name is >= 256 bytes.
     #[must_use]
     pub fn encrypt_matchkey(&self, provider: &str) -> Ciphertext {
-        let mut rng = thread_rng();
+        let mut rng = self.entropy.rng();
         // TODO: determine if we need to hide the timing sidechannel here.
         let emk = self
             .encrypted_match_keys
@@ -152,6 +177,12 @@ impl User {
     }
 }
 
+impl Drop for User {
+    fn drop(&mut self) {
+        self.fallback_prk.zeroize();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::User;
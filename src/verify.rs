@@ -0,0 +1,206 @@
+//! Checks the histogram revealed by MPC helpers against the one computed in the clear.
+//!
+//! Helpers add differentially private noise to the result, so it is not expected to match the
+//! reference exactly. Instead, every bucket is checked to be within the distance from the
+//! reference that Laplace noise with the configured parameters would not exceed with the given
+//! confidence.
+use crate::error::{Error, Res};
+use crate::helpers::models::ReplicatedShare;
+use std::fmt::{Display, Formatter};
+
+/// Parameters of the Laplace noise added to each bucket of the result.
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseParams {
+    /// Privacy budget spent on the query. Infinite epsilon means there is no noise.
+    pub epsilon: f64,
+    /// How much a single user can change the value of any bucket.
+    pub sensitivity: f64,
+    /// Probability with which the noise in a bucket stays within the tolerance.
+    pub confidence: f64,
+}
+
+impl NoiseParams {
+    /// No noise is added, results must match exactly.
+    pub const NONE: Self = Self {
+        epsilon: f64::INFINITY,
+        sensitivity: 1.0,
+        confidence: 1.0,
+    };
+
+    /// The largest error expected in a single bucket. Laplace noise with scale `b` exceeds
+    /// `t` in absolute value with probability `exp(-t/b)`.
+    #[must_use]
+    pub fn tolerance(&self) -> f64 {
+        if self.epsilon.is_infinite() {
+            0.0
+        } else {
+            -self.sensitivity / self.epsilon * (1.0 - self.confidence).ln()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl Bucket {
+    #[must_use]
+    pub fn error(&self) -> i64 {
+        i64::from(self.actual) - i64::from(self.expected)
+    }
+}
+
+/// Result of the comparison, one entry per breakdown key.
+#[derive(Debug)]
+pub struct Report {
+    pub buckets: Vec<Bucket>,
+    pub tolerance: f64,
+}
+
+impl Report {
+    /// Whether the error in every bucket is within the tolerance.
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.buckets.iter().all(|b| {
+            #[allow(clippy::cast_precision_loss)]
+            let error = b.error().abs() as f64;
+            error <= self.tolerance
+        })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:>6} {:>10} {:>10} {:>8}",
+            "bucket", "expected", "actual", "error"
+        )?;
+        for (i, b) in self.buckets.iter().enumerate() {
+            writeln!(
+                f,
+                "{i:>6} {:>10} {:>10} {:>8}",
+                b.expected,
+                b.actual,
+                b.error()
+            )?;
+        }
+        write!(
+            f,
+            "tolerance: {:.2}, consistent: {}",
+            self.tolerance,
+            self.is_consistent()
+        )
+    }
+}
+
+/// Reconstructs the result from the `shares` revealed by every helper and compares it to the
+/// `expected` histogram.
+///
+/// ## Errors
+/// If helpers returned a different number of buckets or their shares are not consistent.
+pub fn verify(
+    expected: &[u32],
+    shares: &[Vec<ReplicatedShare<u32>>; 3],
+    noise: &NoiseParams,
+) -> Res<Report> {
+    if let Some(s) = shares.iter().find(|s| s.len() != expected.len()) {
+        return Err(Error::BucketMismatch {
+            expected: expected.len(),
+            actual: s.len(),
+        });
+    }
+
+    let buckets = expected
+        .iter()
+        .zip(reconstruct(shares)?)
+        .map(|(&expected, actual)| Bucket { expected, actual })
+        .collect();
+
+    Ok(Report {
+        buckets,
+        tolerance: noise.tolerance(),
+    })
+}
+
+/// Reconstructs the value of every bucket from the `shares` revealed by every helper.
+///
+/// ## Errors
+/// If helpers returned different numbers of buckets or their shares are not consistent.
+pub fn reconstruct(shares: &[Vec<ReplicatedShare<u32>>; 3]) -> Res<Vec<u32>> {
+    if let Some(s) = shares.iter().find(|s| s.len() != shares[0].len()) {
+        return Err(Error::BucketMismatch {
+            expected: shares[0].len(),
+            actual: s.len(),
+        });
+    }
+
+    (0..shares[0].len())
+        .map(|i| {
+            let shares = [shares[0][i], shares[1][i], shares[2][i]];
+            Ok(ReplicatedShare::reconstruct(&shares)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::helpers::models::ReplicatedShare;
+    use crate::verify::{verify, NoiseParams};
+
+    fn share(values: &[u32]) -> [Vec<ReplicatedShare<u32>>; 3] {
+        let mut rng = rand::thread_rng();
+        let mut res = [Vec::new(), Vec::new(), Vec::new()];
+        for v in values {
+            for (helper, share) in res.iter_mut().zip(ReplicatedShare::share(*v, &mut rng)) {
+                helper.push(share);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn exact() {
+        let report = verify(&[1, 2, 3], &share(&[1, 2, 3]), &NoiseParams::NONE).unwrap();
+        assert!(report.is_consistent());
+
+        let report = verify(&[1, 2, 3], &share(&[1, 2, 4]), &NoiseParams::NONE).unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(1, report.buckets[2].error());
+    }
+
+    #[test]
+    fn noisy() {
+        let noise = NoiseParams {
+            epsilon: 1.0,
+            sensitivity: 1.0,
+            confidence: 0.99,
+        };
+        // ln(100) = 4.6
+        let report = verify(&[10, 10], &share(&[14, 6]), &noise).unwrap();
+        assert!(report.is_consistent());
+        let report = verify(&[10, 10], &share(&[10, 15]), &noise).unwrap();
+        assert!(!report.is_consistent());
+    }
+
+    #[test]
+    fn bad_shares() {
+        let mut shares = share(&[1, 2]);
+        assert!(matches!(
+            verify(&[1], &shares, &NoiseParams::NONE),
+            Err(Error::BucketMismatch {
+                expected: 1,
+                actual: 2
+            })
+        ));
+
+        shares[1][0].0 ^= 1;
+        assert!(matches!(
+            verify(&[1, 2], &shares, &NoiseParams::NONE),
+            Err(Error::Io(_))
+        ));
+    }
+}